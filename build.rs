@@ -0,0 +1,11 @@
+// build.rs
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/qks.proto")?;
+    tonic_build::compile_protos("proto/fleet.proto")?;
+    // containerd is only ever a server we call
+    tonic_build::configure().build_server(false).compile(
+        &["proto/containerd/events.proto", "proto/containerd/containers.proto", "proto/containerd/tasks.proto"],
+        &["proto"],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+// src/alert_dedup.rs
+// Sits between the event bus and the notifiers. A process that stays
+// anomalous is re-scored, and re-alerted, every pipeline tick; here alerts
+// with the same fingerprint (kind, PID, executable, and for responses the
+// action and outcome) go out at most once per `suppress_secs`, the repeat
+// carrying how many were held back. One that keeps recurring for
+// `escalate_after_secs` is sent once more a severity higher. An alert not
+// seen for a whole window is forgotten, so its next occurrence is new.
+// Whatever survives that shares one global token bucket; alerts over the
+// limit are dropped and their count reported with the next one through.
+use crate::config::DedupSection;
+use crate::events::SecurityEvent;
+use crate::metrics;
+use crate::notify::{Alert, AlertSeverity};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Forgotten fingerprints are swept out this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Seen {
+    first: Instant,
+    last: Instant,
+    // None until one gets past the rate limit
+    last_sent: Option<Instant>,
+    suppressed: u64,
+    escalated: bool,
+}
+
+pub struct AlertGate {
+    suppress: Duration,
+    escalate_after: Duration,
+    rate_per_sec: f64,
+    burst: f64,
+    seen: HashMap<String, Seen>,
+    tokens: f64,
+    refilled: Instant,
+    rate_limited: u64,
+    swept: Instant,
+}
+
+pub fn fingerprint(event: &SecurityEvent) -> String {
+    match event {
+        SecurityEvent::Anomaly(e) => format!("anomaly:{}:{}", e.pid, e.exe.as_deref().unwrap_or("-")),
+        SecurityEvent::Response(e) => format!("response:{}:{}:{}", e.action, e.pid.map_or("-".to_string(), |pid| pid.to_string()), e.outcome),
+        SecurityEvent::Integrity(e) => format!("integrity:{}:{}:{}", e.source, e.finding, e.path.as_deref().unwrap_or("-")),
+        SecurityEvent::Intel(e) => format!("intel:{}:{}:{}", e.indicator, e.matched, e.pid.map_or("-".to_string(), |pid| pid.to_string())),
+        other => format!("{}:{}", other.kind().as_str(), other.pid().map_or("-".to_string(), |pid| pid.to_string())),
+    }
+}
+
+impl AlertGate {
+    pub fn new(config: &DedupSection) -> Self {
+        let now = Instant::now();
+        Self {
+            suppress: Duration::from_secs(config.suppress_secs),
+            escalate_after: Duration::from_secs(config.escalate_after_secs),
+            rate_per_sec: config.rate_per_min as f64 / 60.0,
+            burst: config.burst as f64,
+            seen: HashMap::new(),
+            tokens: config.burst as f64,
+            refilled: now,
+            rate_limited: 0,
+            swept: now,
+        }
+    }
+
+    // Whether `alert` goes out; if it does, it is annotated with what was
+    // held back and whether it is an escalation
+    pub fn admit(&mut self, alert: &mut Alert, now: Instant) -> bool {
+        if now.duration_since(self.swept) >= SWEEP_INTERVAL {
+            let suppress = self.suppress;
+            self.seen.retain(|_, seen| now.duration_since(seen.last) < suppress);
+            self.swept = now;
+        }
+
+        let kind = alert.kind.as_str();
+        let suppress = self.suppress;
+        let recurring = self.seen.get(&alert.fingerprint).is_some_and(|seen| now.duration_since(seen.last) < suppress);
+        if !recurring {
+            let seen = Seen { first: now, last: now, last_sent: None, suppressed: 0, escalated: false };
+            self.seen.insert(alert.fingerprint.clone(), seen);
+        }
+        let seen = self.seen.get_mut(&alert.fingerprint).expect("inserted above");
+        seen.last = now;
+
+        let escalate = recurring && !seen.escalated && now.duration_since(seen.first) >= self.escalate_after;
+        if !escalate && seen.last_sent.is_some_and(|sent| now.duration_since(sent) < suppress) {
+            seen.suppressed += 1;
+            metrics::global().incr("qks_alerts_suppressed_total", &[("kind", kind)]);
+            return false;
+        }
+
+        if !self.take_token(now) {
+            self.rate_limited += 1;
+            metrics::global().incr("qks_alerts_rate_limited_total", &[("kind", kind)]);
+            return false;
+        }
+
+        let seen = self.seen.get_mut(&alert.fingerprint).expect("inserted above");
+        alert.suppressed = std::mem::take(&mut seen.suppressed);
+        alert.persisting_secs = now.duration_since(seen.first).as_secs();
+        alert.rate_limited = std::mem::take(&mut self.rate_limited);
+        seen.last_sent = Some(now);
+        if escalate {
+            seen.escalated = true;
+            alert.escalated = true;
+            alert.severity = match alert.severity {
+                AlertSeverity::Info => AlertSeverity::Warning,
+                AlertSeverity::Warning | AlertSeverity::Critical => AlertSeverity::Critical,
+            };
+            alert.summary = format!("{} (persisting for {}s)", alert.summary, alert.persisting_secs);
+            metrics::global().incr("qks_alerts_escalated_total", &[("kind", kind)]);
+        }
+        if alert.suppressed > 0 {
+            alert.summary = format!("{}; {} repeats suppressed", alert.summary, alert.suppressed);
+        }
+        if alert.rate_limited > 0 {
+            alert.summary = format!("{}; {} other alerts dropped by the rate limit", alert.summary, alert.rate_limited);
+        }
+        true
+    }
+
+    fn take_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_per_sec).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
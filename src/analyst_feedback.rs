@@ -0,0 +1,174 @@
+// src/analyst_feedback.rs
+// Triage verdicts fed back into detection. A false positive raises the
+// alert threshold for the process's profile (its executable, or image or
+// namespace outside the host's network namespace) just above the score
+// that fired; a true positive pulls a raised threshold back down. Verdicts also
+// label the row in the training export. Overrides persist as JSON.
+use crate::behavior_profiles::ProfileKey;
+use crate::feature_pipeline::ScoredProcess;
+use crate::training_export::{Label, TrainingExporter};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Raised thresholds sit this far above the false positive's score
+const FP_MARGIN: f32 = 0.01;
+// Never suppress a profile entirely
+const MAX_THRESHOLD: f32 = 0.99;
+// Alerts awaiting a verdict; the oldest are forgotten first
+const MAX_OPEN_ALERTS: usize = 10_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FeedbackError {
+    #[error("unknown alert {0}")]
+    UnknownAlert(String),
+    #[error("feedback store I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("feedback store format: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    FalsePositive,
+    TruePositive,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRecord {
+    pub pid: u32,
+    pub timestamp: u64,
+    pub exe: Option<String>,
+    pub score: f32,
+    // Taken while the process is still there to say which namespace it is in
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl AlertRecord {
+    fn profile(&self) -> Option<String> {
+        self.profile.clone().or_else(|| self.exe.as_ref().map(|exe| ProfileKey::Executable(PathBuf::from(exe)).to_string()))
+    }
+}
+
+pub fn alert_id(pid: u32, timestamp: u64) -> String {
+    format!("{}-{}", pid, timestamp)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerdictCounts {
+    pub false_positives: u64,
+    pub true_positives: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct FeedbackLoop {
+    open: Arc<DashMap<String, AlertRecord>>,
+    // Per-profile alert thresholds raised by false positives
+    overrides: Arc<DashMap<String, f32>>,
+    counts: Arc<DashMap<String, VerdictCounts>>,
+    exporter: Option<TrainingExporter>,
+}
+
+impl FeedbackLoop {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_exporter(mut self, exporter: TrainingExporter) -> Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    // Register a raised alert so a verdict can find it; returns its ID
+    pub fn track_alert(&self, scored: &ScoredProcess) -> String {
+        if self.open.len() >= MAX_OPEN_ALERTS {
+            let oldest = self.open.iter()
+                .min_by_key(|entry| entry.value().timestamp)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                self.open.remove(&oldest);
+            }
+        }
+
+        let id = alert_id(scored.pid, scored.timestamp);
+        self.open.insert(id.clone(), AlertRecord {
+            pid: scored.pid,
+            timestamp: scored.timestamp,
+            exe: scored.exe.clone(),
+            score: scored.score,
+            profile: ProfileKey::for_pid(scored.pid).map(|key| key.to_string()),
+        });
+        id
+    }
+
+    pub fn record_verdict(&self, alert_id: &str, verdict: Verdict) -> Result<(), FeedbackError> {
+        let (_, alert) = self.open.remove(alert_id)
+            .ok_or_else(|| FeedbackError::UnknownAlert(alert_id.to_string()))?;
+
+        if let Some(exporter) = &self.exporter {
+            let label = match verdict {
+                Verdict::FalsePositive => Label::Benign,
+                Verdict::TruePositive => Label::Confirmed,
+            };
+            exporter.label(alert.pid, alert.timestamp, label);
+        }
+
+        let Some(profile) = alert.profile() else {
+            return Ok(());
+        };
+        let mut counts = self.counts.entry(profile.clone()).or_default();
+        match verdict {
+            Verdict::FalsePositive => {
+                counts.false_positives += 1;
+                let raised = (alert.score + FP_MARGIN).min(MAX_THRESHOLD);
+                let mut threshold = self.overrides.entry(profile.clone()).or_insert(raised);
+                *threshold = threshold.max(raised);
+                tracing::info!("False positive on {}: alert threshold now {:.3}", profile, *threshold);
+            }
+            Verdict::TruePositive => {
+                counts.true_positives += 1;
+                // A raised threshold that would have hidden this alert was wrong
+                if let Some(mut threshold) = self.overrides.get_mut(&profile) {
+                    if alert.score < *threshold + FP_MARGIN {
+                        *threshold = (alert.score - FP_MARGIN).max(0.0);
+                        tracing::info!("True positive on {}: alert threshold lowered to {:.3}", profile, *threshold);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Effective alert threshold for an executable given the calibrated one
+    pub fn threshold_for(&self, key: &ProfileKey, calibrated: f32) -> f32 {
+        self.overrides.get(&key.to_string()).map_or(calibrated, |raised| raised.max(calibrated))
+    }
+
+    pub fn verdict_counts(&self, key: &ProfileKey) -> VerdictCounts {
+        self.counts.get(&key.to_string()).map(|c| c.clone()).unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), FeedbackError> {
+        let overrides: HashMap<String, f32> = self.overrides.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&overrides)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load_overrides(&self, path: &Path) -> Result<(), FeedbackError> {
+        let overrides: HashMap<String, f32> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for (profile, threshold) in overrides {
+            self.overrides.insert(profile, threshold.min(MAX_THRESHOLD));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+// src/anomaly_events.rs
+// One self-contained record per detection. A scored row that crosses its
+// alert threshold is enriched with everything a responder would otherwise
+// have to go and collect while the process is still alive: the score
+// explanation, the ancestry up to init, the capabilities its ProcessToken
+// grants, the newest syscalls and the hash of its randomized layout.
+use crate::analyst_feedback::FeedbackLoop;
+use crate::behavior_profiles::ProfileKey;
+use crate::crypto_identifiers::Capability;
+use crate::feature_pipeline::ScoredProcess;
+use crate::kubernetes;
+use crate::memory_randomizer::LayoutChangeEvent;
+use crate::ml_detector::{AnomalyExplanation, MLAnomalyDetector, ScoringMode};
+use crate::privsep;
+use crate::token_keyring::TokenKeyring;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+// Used when the model has no calibrated thresholds
+const DEFAULT_ALERT_THRESHOLD: f32 = 0.8;
+// Ancestors walked before giving up (pid namespaces, races with exit)
+const MAX_TREE_DEPTH: usize = 16;
+const TOP_CONTRIBUTIONS: usize = 3;
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct ProcessAncestor {
+    pub pid: u32,
+    pub comm: String,
+    pub exe: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct AnomalyEvent {
+    pub pid: u32,
+    pub exe: Option<String>,
+    pub score: f32,
+    pub threshold: f32,
+    pub mode: ScoringMode,
+    pub model_version: u64,
+    pub timestamp: u64,
+    // None when re-scoring for the explanation failed
+    pub explanation: Option<AnomalyExplanation>,
+    // The process itself first, then its parent, up towards init
+    pub process_tree: Vec<ProcessAncestor>,
+    // None when the process holds no token
+    pub capabilities: Option<Vec<Capability>>,
+    pub recent_syscalls: Vec<u32>,
+    pub layout_hash: Option<[u8; 32]>,
+}
+
+pub struct AnomalyEnricher {
+    detector: Arc<Mutex<MLAnomalyDetector>>,
+    keyring: Option<Arc<TokenKeyring>>,
+    feedback: Option<FeedbackLoop>,
+    // Latest layout hash per PID, from the randomizer's layout events
+    layouts: Arc<DashMap<u32, [u8; 32]>>,
+}
+
+impl AnomalyEnricher {
+    pub fn new(detector: Arc<Mutex<MLAnomalyDetector>>) -> Self {
+        Self { detector, keyring: None, feedback: None, layouts: Arc::new(DashMap::new()) }
+    }
+
+    pub fn with_keyring(mut self, keyring: TokenKeyring) -> Self {
+        self.keyring = Some(Arc::new(keyring));
+        self
+    }
+
+    // Per-profile thresholds raised by analyst verdicts apply to alerting
+    pub fn with_feedback(mut self, feedback: FeedbackLoop) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    pub fn threshold_for(&self, scored: &ScoredProcess) -> f32 {
+        let calibrated = self.detector.lock().unwrap().thresholds().map_or(DEFAULT_ALERT_THRESHOLD, |t| t.alert);
+        match (&self.feedback, ProfileKey::for_pid(scored.pid)) {
+            (Some(feedback), Some(key)) => feedback.threshold_for(&key, calibrated),
+            _ => calibrated,
+        }
+    }
+
+    // Blocking: re-scores for the explanation and reads procfs and keyrings
+    pub fn enrich(&self, scored: &ScoredProcess, threshold: f32) -> AnomalyEvent {
+        let explanation = match self.detector.lock().unwrap().explain_anomaly(&scored.features, TOP_CONTRIBUTIONS) {
+            Ok(explanation) => Some(explanation),
+            Err(e) => {
+                tracing::warn!(pid = scored.pid, "Could not explain anomaly in PID {}: {}", scored.pid, e);
+                None
+            }
+        };
+        let capabilities = self.keyring.as_ref()
+            .and_then(|keyring| keyring.load(scored.pid).ok().flatten())
+            .map(|token| token.capabilities);
+
+        AnomalyEvent {
+            pid: scored.pid,
+            exe: scored.exe.clone(),
+            score: scored.score,
+            threshold,
+            mode: scored.mode,
+            model_version: scored.model_version,
+            timestamp: scored.timestamp,
+            explanation,
+            process_tree: process_tree(scored.pid),
+            capabilities,
+            recent_syscalls: scored.recent_syscalls.clone(),
+            layout_hash: self.layouts.get(&scored.pid).map(|hash| *hash),
+        }
+    }
+
+    pub fn start(
+        self,
+        mut scored: mpsc::UnboundedReceiver<ScoredProcess>,
+        mut layout_events: broadcast::Receiver<LayoutChangeEvent>,
+    ) -> (tokio::task::JoinHandle<()>, mpsc::UnboundedReceiver<AnomalyEvent>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let enricher = Arc::new(self);
+
+        let handle = tokio::spawn(async move {
+            let mut layouts_open = true;
+            loop {
+                tokio::select! {
+                    event = layout_events.recv(), if layouts_open => match event {
+                        Ok(event) => {
+                            enricher.layouts.insert(event.pid, event.layout_hash);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            tracing::debug!("Anomaly enricher missed {} layout events", missed);
+                        }
+                        // Randomizer gone; keep enriching without layouts
+                        Err(broadcast::error::RecvError::Closed) => layouts_open = false,
+                    },
+                    row = scored.recv() => {
+                        let Some(row) = row else {
+                            return;
+                        };
+                        if !std::path::Path::new(&format!("/proc/{}", row.pid)).exists() {
+                            enricher.layouts.remove(&row.pid);
+                        }
+
+                        let worker = enricher.clone();
+                        let event = tokio::task::spawn_blocking(move || {
+                            let threshold = kubernetes::global().monitoring_for(row.pid).threshold(worker.threshold_for(&row))?;
+                            (row.score > threshold).then(|| worker.enrich(&row, threshold))
+                        }).await;
+
+                        match event {
+                            Ok(Some(event)) => {
+                                tracing::warn!(
+                                    pid = event.pid,
+                                    score = event.score as f64,
+                                    threshold = event.threshold as f64,
+                                    exe = event.exe.as_deref().unwrap_or("?"),
+                                    "Anomaly in PID {} ({}): score {:.3} over {:.3}",
+                                    event.pid, event.exe.as_deref().unwrap_or("?"), event.score, event.threshold
+                                );
+                                if tx.send(event).is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => tracing::warn!("Anomaly enrichment task failed: {}", e),
+                        }
+                    }
+                }
+            }
+        });
+
+        (handle, rx)
+    }
+}
+
+// PID, then parent, then grandparent... stopping at init or a vanished PID
+pub fn process_tree(pid: u32) -> Vec<ProcessAncestor> {
+    let mut tree = Vec::new();
+    let mut current = pid;
+    while current > 0 && tree.len() < MAX_TREE_DEPTH {
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", current)) else {
+            break;
+        };
+        // comm is parenthesised and may itself contain ") "
+        let (Some(open), Some(close)) = (stat.find('('), stat.rfind(')')) else {
+            break;
+        };
+        let ppid = stat[close + 1..].split_whitespace().nth(1).and_then(|p| p.parse::<u32>().ok()).unwrap_or(0);
+
+        tree.push(ProcessAncestor {
+            pid: current,
+            comm: stat[open + 1..close].to_string(),
+            exe: privsep::read_exe(current).ok().map(|p| p.display().to_string()),
+        });
+        if current == 1 {
+            break;
+        }
+        current = ppid;
+    }
+    tree
+}
@@ -0,0 +1,135 @@
+// src/arch_profile.rs
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    // 32-bit process on a 64-bit kernel (ia32 / arm32 compat)
+    Compat32,
+}
+
+// Address windows (start, end inclusive) and paging rules for one ABI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchProfile {
+    pub arch: Arch,
+    pub page_shift: u32,
+    pub user_space_end: u64,
+    pub stack_window: (u64, u64),
+    pub heap_window: (u64, u64),
+    pub mmap_window: (u64, u64),
+    // vDSO offset bits, counted in pages
+    pub vdso_max_bits: u32,
+}
+
+impl ArchProfile {
+    pub fn x86_64() -> Self {
+        Self {
+            arch: Arch::X86_64,
+            page_shift: 12,
+            // 47-bit user space (4-level paging)
+            user_space_end: 0x0000_7FFF_FFFF_F000,
+            stack_window: (0x00007_000_0000, 0x00007_FFF_FFFF),
+            heap_window: (0x00001_000_0000, 0x00001_FFF_FFFF),
+            mmap_window: (0x00002_000_0000, 0x00002_FFF_FFFF),
+            vdso_max_bits: 4,
+        }
+    }
+
+    pub fn aarch64() -> Self {
+        // Windows stay below 2^39 so they are valid with 3-level 39-bit VA
+        // kernels as well as the default 48-bit configuration
+        Self {
+            arch: Arch::Aarch64,
+            page_shift: Self::runtime_page_shift().unwrap_or(12),
+            user_space_end: 0x0000_007F_FFFF_F000,
+            stack_window: (0x0000_0070_0000_0000, 0x0000_007E_FFFF_FFFF),
+            heap_window: (0x0000_0010_0000_0000, 0x0000_001F_FFFF_FFFF),
+            mmap_window: (0x0000_0040_0000_0000, 0x0000_004F_FFFF_FFFF),
+            vdso_max_bits: 4,
+        }
+    }
+
+    pub fn compat32() -> Self {
+        Self {
+            arch: Arch::Compat32,
+            page_shift: 12,
+            user_space_end: 0xFFFF_E000,
+            stack_window: (0xE000_0000, 0xEFFF_FFFF),
+            heap_window: (0x1000_0000, 0x1FFF_FFFF),
+            mmap_window: (0x4000_0000, 0x4FFF_FFFF),
+            vdso_max_bits: 2,
+        }
+    }
+
+    // Profile for the running kernel's native ABI
+    pub fn detect() -> Self {
+        match std::env::consts::ARCH {
+            "aarch64" => Self::aarch64(),
+            "x86" | "arm" => Self::compat32(),
+            _ => Self::x86_64(),
+        }
+    }
+
+    // 32-bit binaries on a 64-bit host get the compat windows
+    pub fn for_pid(pid: u32) -> Self {
+        let native = Self::detect();
+
+        match Self::elf_class(pid) {
+            Some(1) if native.arch != Arch::Compat32 => Self::compat32(),
+            _ => native,
+        }
+    }
+
+    pub fn page_size(&self) -> u64 {
+        1 << self.page_shift
+    }
+
+    pub fn page_mask(&self) -> u64 {
+        !(self.page_size() - 1)
+    }
+
+    pub fn align(&self, addr: u64) -> u64 {
+        addr & self.page_mask()
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr < self.user_space_end
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, window) in [
+            ("stack", self.stack_window),
+            ("heap", self.heap_window),
+            ("mmap", self.mmap_window),
+        ] {
+            if window.0 >= window.1 || window.1 >= self.user_space_end {
+                return Err(format!("{:?} {} window {:#x}-{:#x} is outside user space", self.arch, name, window.0, window.1));
+            }
+            if window.0 & !self.page_mask() != 0 {
+                return Err(format!("{:?} {} window start {:#x} is not page aligned", self.arch, name, window.0));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn elf_class(pid: u32) -> Option<u8> {
+        let mut header = [0u8; 5];
+        File::open(format!("/proc/{}/exe", pid)).ok()?.read_exact(&mut header).ok()?;
+
+        if &header[..4] != b"\x7fELF" {
+            return None;
+        }
+        Some(header[4])
+    }
+
+    fn runtime_page_shift() -> Option<u32> {
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size <= 0 {
+            return None;
+        }
+        Some((size as u64).trailing_zeros())
+    }
+}
@@ -0,0 +1,200 @@
+// src/arena_broker.rs
+// Allocator-cooperative heap shuffling. Instead of rebasing a live heap
+// blindly, cooperating processes ask for fresh, randomized arena bases and
+// map new arenas there themselves. The broker runs when
+// `randomizer.arena_socket` is set; the client side, an LD_PRELOAD shim or
+// allocator hook shipped with the allocator rather than in this tree,
+// speaks newline-delimited JSON over that Unix socket:
+//
+//   -> {"op":"allocate","size":67108864,"align":2097152}
+//   <- {"status":"granted","base":140234871816192,"size":67108864}
+//   (shim: mmap(base, size, ..., MAP_FIXED_NOREPLACE) and retires the old
+//    arena once it drains)
+//   -> {"op":"release","base":140234871816192}
+//   -> {"op":"register_table","addr":94812345678848}
+//
+// The caller's PID comes from SO_PEERCRED, never from the request.
+// `register_table` publishes the allocator's relocation table so the heap
+// itself may also be moved (see heap_fixup).
+use crate::arch_profile::ArchProfile;
+use crate::heap_fixup::HeapFixups;
+use crate::metrics;
+use crate::process_maps::{self, ProcessMaps};
+use crate::secure_random::{SecureRandomSource, SystemRandomSource};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+const MAX_PLACEMENT_ATTEMPTS: u32 = 64;
+// Arenas larger than this are almost certainly a confused client
+const MAX_ARENA_SIZE: u64 = 1 << 36;
+// The socket is world-writable, so one process can't hoard address space
+// bookkeeping or spin the placement loop without bound
+const MAX_GRANTS_PER_PID: usize = 64;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ArenaRequest {
+    Allocate { size: u64, align: Option<u64> },
+    Release { base: u64 },
+    RegisterTable { addr: u64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ArenaResponse {
+    Granted { base: u64, size: u64 },
+    Ok,
+    Error { message: String },
+}
+
+pub struct ArenaBroker {
+    socket_path: PathBuf,
+    // Outstanding grants per PID, so two requests in flight never overlap
+    // before the shim has mapped the first
+    grants: Arc<DashMap<u32, Vec<(u64, u64)>>>,
+    heap_fixups: HeapFixups,
+}
+
+impl ArenaBroker {
+    pub fn new(socket_path: &Path, heap_fixups: HeapFixups) -> Self {
+        Self {
+            socket_path: socket_path.to_path_buf(),
+            grants: Arc::new(DashMap::new()),
+            heap_fixups,
+        }
+    }
+
+    pub fn start(&self) -> std::io::Result<tokio::task::JoinHandle<()>> {
+        let _ = std::fs::remove_file(&self.socket_path);
+        let listener = UnixListener::bind(&self.socket_path)?;
+        // Any process may ask; a grant only ever affects the asking process
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o666))?;
+
+        let grants = self.grants.clone();
+        let heap_fixups = self.heap_fixups.clone();
+
+        Ok(tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Arena broker accept failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let grants = grants.clone();
+                let heap_fixups = heap_fixups.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = Self::serve(stream, &grants, &heap_fixups).await {
+                        tracing::debug!("Arena broker client error: {}", e);
+                    }
+                });
+            }
+        }))
+    }
+
+    async fn serve(
+        stream: UnixStream,
+        grants: &DashMap<u32, Vec<(u64, u64)>>,
+        heap_fixups: &HeapFixups,
+    ) -> std::io::Result<()> {
+        let pid = match stream.peer_cred()?.pid() {
+            Some(pid) if pid > 0 => pid as u32,
+            _ => return Ok(()),
+        };
+        Self::prune(grants);
+
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<ArenaRequest>(&line) {
+                Ok(request) => Self::handle(pid, request, grants, heap_fixups),
+                Err(e) => ArenaResponse::Error { message: format!("bad request: {}", e) },
+            };
+
+            let mut reply = serde_json::to_vec(&response).unwrap_or_default();
+            reply.push(b'\n');
+            writer.write_all(&reply).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle(
+        pid: u32,
+        request: ArenaRequest,
+        grants: &DashMap<u32, Vec<(u64, u64)>>,
+        heap_fixups: &HeapFixups,
+    ) -> ArenaResponse {
+        match request {
+            ArenaRequest::Allocate { .. } if grants.get(&pid).map_or(0, |g| g.len()) >= MAX_GRANTS_PER_PID => {
+                metrics::global().incr("qks_arena_grants_refused_total", &[]);
+                ArenaResponse::Error { message: format!("at most {} outstanding arenas per process", MAX_GRANTS_PER_PID) }
+            }
+            ArenaRequest::Allocate { size, align } => match Self::place(pid, size, align, grants) {
+                Ok(base) => {
+                    grants.entry(pid).or_default().push((base, size));
+                    metrics::global().incr("qks_arena_grants_total", &[]);
+                    tracing::debug!(pid, "PID {}: granted arena {:#x} (+{} bytes)", pid, base, size);
+                    ArenaResponse::Granted { base, size }
+                }
+                Err(message) => ArenaResponse::Error { message },
+            },
+            ArenaRequest::Release { base } => {
+                if let Some(mut held) = grants.get_mut(&pid) {
+                    held.retain(|(start, _)| *start != base);
+                }
+                ArenaResponse::Ok
+            }
+            ArenaRequest::RegisterTable { addr } => {
+                heap_fixups.register(pid, addr);
+                tracing::info!(pid, "PID {} registered heap relocation table at {:#x}", pid, addr);
+                ArenaResponse::Ok
+            }
+        }
+    }
+
+    fn place(pid: u32, size: u64, align: Option<u64>, grants: &DashMap<u32, Vec<(u64, u64)>>) -> Result<u64, String> {
+        let arch = ArchProfile::for_pid(pid);
+        let align = align.unwrap_or(arch.page_size()).max(arch.page_size());
+        if size == 0 || size > MAX_ARENA_SIZE || !align.is_power_of_two() {
+            return Err(format!("unsupported arena size {} / alignment {}", size, align));
+        }
+        let size = (size + arch.page_size() - 1) & arch.page_mask();
+
+        let maps = ProcessMaps::read(pid).map_err(|e| format!("cannot read mappings: {}", e))?;
+        let (low, high) = arch.mmap_window;
+        let low = low.max(process_maps::mmap_min_addr());
+        if high <= low + size {
+            return Err("arena does not fit the mmap window".into());
+        }
+
+        let held = grants.get(&pid).map(|g| g.clone()).unwrap_or_default();
+        let rng = SystemRandomSource::new();
+        for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+            let base = (low + rng.next_below(high - size - low)) & !(align - 1);
+            let end = base + size;
+
+            let clashes = base < low
+                || !maps.overlapping(base, end).is_empty()
+                || held.iter().any(|(start, len)| base < start + len && *start < end);
+            if !clashes {
+                return Ok(base);
+            }
+        }
+
+        Err("no free arena base found".into())
+    }
+
+    // Grants of exited processes
+    fn prune(grants: &DashMap<u32, Vec<(u64, u64)>>) {
+        grants.retain(|pid, _| Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
@@ -0,0 +1,439 @@
+// src/audit_log.rs
+// The response audit log, kept as segments. Records are appended to the
+// active file (response-audit.jsonl); once it reaches `rotate_size_mb` or
+// its first record is `rotate_secs` old it is sealed: renamed to
+// <stem>-<first>-<last>.jsonl after the timestamps of its first and last
+// records and, with `compress`, zstd-compressed to .jsonl.zst. A sealed
+// segment is never overwritten: when that name is taken (two rotations
+// within the same second) the next free <stem>-<first>-<last>-<seq>.jsonl
+// is used instead. Sealed
+// segments older than `retention_days`, and the oldest beyond
+// `retention_max_mb` in total, are deleted. Age is checked every minute as
+// well as on each append, so a quiet log still rotates on time.
+//
+// Export seals the active segment and writes the segments covering a time
+// range into a tar bundle for compliance archives: manifest.json lists each
+// segment with its time range and SHA-256, manifest.sig is the host
+// identity's Ed25519 signature over the manifest, and the segments are
+// under segments/. The manifest carries the signing public key; verifying
+// against a key the auditor trusts is what makes the bundle evidence.
+use crate::config::AuditSection;
+use crate::crypto_identifiers::CryptoIdentifier;
+use crate::metrics;
+use crate::model_signing::decode_hex;
+use crate::syslog_sink;
+use crate::systemd;
+use ring::{digest, signature};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const BUNDLE_FORMAT: &str = "qks-audit-bundle/1";
+const MANIFEST_NAME: &str = "manifest.json";
+const SIGNATURE_NAME: &str = "manifest.sig";
+const SEGMENT_DIR: &str = "segments";
+const MB: u64 = 1024 * 1024;
+// Segments sealed with the same first and last timestamps
+const MAX_SEAL_SEQ: u32 = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("no audit records between {since} and {until}")]
+    NothingToExport { since: u64, until: u64 },
+    #[error("not an audit bundle: {0}")]
+    Bundle(String),
+    #[error("manifest signature does not verify")]
+    BadSignature,
+    #[error("bundle is signed by a key that is not trusted")]
+    UntrustedKey,
+    #[error("segment {0} is missing or does not match the manifest")]
+    Tampered(String),
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> AuditError + '_ {
+    move |source| AuditError::Io { path: path.to_path_buf(), source }
+}
+
+// A sealed segment on disk
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    pub name: String,
+    pub first: u64,
+    pub last: u64,
+    // 0 unless another segment already had the same range
+    pub seq: u32,
+    pub bytes: u64,
+    pub compressed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub name: String,
+    pub first: u64,
+    pub last: u64,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub format: String,
+    pub host: String,
+    pub created_at: u64,
+    pub since: u64,
+    pub until: u64,
+    // Hex Ed25519 key that signed manifest.sig
+    pub public_key: String,
+    pub segments: Vec<BundleEntry>,
+}
+
+// What export reports back
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportSummary {
+    pub bundle: PathBuf,
+    pub segments: usize,
+    pub bytes: u64,
+    pub first: u64,
+    pub last: u64,
+}
+
+#[derive(Default)]
+struct Active {
+    first: Option<u64>,
+    last: u64,
+    bytes: u64,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    section: AuditSection,
+    // Also serializes appends, sealing and export, so lines never
+    // interleave and a segment is never read half-compressed
+    active: Mutex<Active>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, section: AuditSection) -> Self {
+        let log = Self { active: Mutex::new(scan_active(&path)), path, section };
+        // Sealed but not compressed when the last run stopped
+        if log.section.compress {
+            for segment in log.segments().into_iter().filter(|s| !s.compressed) {
+                log.compress(&log.dir().join(&segment.name));
+            }
+        }
+        log
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    fn stem(&self) -> &str {
+        self.path.file_stem().and_then(|s| s.to_str()).unwrap_or("audit")
+    }
+
+    pub fn append(&self, timestamp: u64, line: &str) -> Result<(), AuditError> {
+        let mut active = self.active.lock().unwrap();
+        let incoming = line.len() as u64 + 1;
+        if self.due(&active, timestamp, incoming) {
+            self.seal(&mut active);
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(io_error(&self.path))?;
+        active.first.get_or_insert(timestamp);
+        active.last = timestamp;
+        active.bytes += incoming;
+        Ok(())
+    }
+
+    fn due(&self, active: &Active, now: u64, incoming: u64) -> bool {
+        let Some(first) = active.first else {
+            return false;
+        };
+        active.bytes + incoming > self.section.rotate_size_mb * MB || now.saturating_sub(first) >= self.section.rotate_secs
+    }
+
+    fn seal(&self, active: &mut Active) {
+        let Some(first) = active.first else {
+            return;
+        };
+        let sealed = match self.seal_as(first, active.last) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                tracing::error!("Audit segment {} not sealed: {}", self.path.display(), e);
+                return;
+            }
+        };
+        *active = Active::default();
+        metrics::global().incr("qks_audit_rotations_total", &[]);
+        tracing::info!("Audit segment sealed as {}", sealed.display());
+        if self.section.compress {
+            self.compress(&sealed);
+        }
+        self.enforce_retention();
+    }
+
+    // Renames the active file to the first free name for its range; the
+    // rename itself refuses to replace, so a name taken in between is
+    // skipped rather than lost
+    fn seal_as(&self, first: u64, last: u64) -> std::io::Result<PathBuf> {
+        for seq in 0..MAX_SEAL_SEQ {
+            let base = match seq {
+                0 => format!("{}-{}-{}", self.stem(), first, last),
+                seq => format!("{}-{}-{}-{}", self.stem(), first, last, seq),
+            };
+            let sealed = self.dir().join(format!("{}.jsonl", base));
+            // The compressed form holds the name too
+            if self.dir().join(format!("{}.jsonl.zst", base)).exists() {
+                continue;
+            }
+            match rename_noreplace(&self.path, &sealed) {
+                Ok(()) => return Ok(sealed),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} segments already cover {}-{}", MAX_SEAL_SEQ, first, last)))
+    }
+
+    // Written aside and renamed, so a crash leaves either form whole
+    fn compress(&self, sealed: &Path) {
+        let compressed = sealed.with_extension("jsonl.zst");
+        let partial = sealed.with_extension("jsonl.zst.partial");
+        let result = fs::read(sealed)
+            .and_then(|plain| zstd::bulk::compress(&plain, self.section.zstd_level))
+            .and_then(|packed| fs::write(&partial, packed))
+            .and_then(|()| rename_noreplace(&partial, &compressed))
+            .and_then(|()| fs::remove_file(sealed));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&partial);
+            tracing::warn!("Audit segment {} left uncompressed: {}", sealed.display(), e);
+        }
+    }
+
+    // Sealed segments, oldest first
+    pub fn segments(&self) -> Vec<Segment> {
+        let prefix = format!("{}-", self.stem());
+        let mut segments: Vec<Segment> = fs::read_dir(self.dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let (range, compressed) = match name.strip_suffix(".jsonl.zst") {
+                    Some(range) => (range, true),
+                    None => (name.strip_suffix(".jsonl")?, false),
+                };
+                let mut parts = range.strip_prefix(&prefix)?.split('-');
+                let (first, last) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+                let seq = match parts.next() {
+                    Some(seq) => seq.parse().ok()?,
+                    None => 0,
+                };
+                if parts.next().is_some() {
+                    return None;
+                }
+                let bytes = entry.metadata().ok()?.len();
+                Some(Segment { name, first, last, seq, bytes, compressed })
+            })
+            .collect();
+        segments.sort_by_key(|s| (s.first, s.last, s.seq));
+        segments
+    }
+
+    fn enforce_retention(&self) {
+        let now = systemd::now_secs();
+        let oldest_kept = now.saturating_sub(self.section.retention_days * 86_400);
+        let mut segments = self.segments();
+        let mut total: u64 = segments.iter().map(|s| s.bytes).sum();
+        segments.retain(|segment| {
+            let expired = segment.last < oldest_kept;
+            let over_budget = total > self.section.retention_max_mb * MB;
+            if !expired && !over_budget {
+                return true;
+            }
+            let path = self.dir().join(&segment.name);
+            if let Err(e) = fs::remove_file(&path) {
+                tracing::warn!("Audit segment {} past retention not deleted: {}", path.display(), e);
+                return true;
+            }
+            total -= segment.bytes;
+            metrics::global().incr("qks_audit_segments_deleted_total", &[("reason", if expired { "age" } else { "size" })]);
+            tracing::info!("Audit segment {} deleted ({})", segment.name, if expired { "past retention_days" } else { "over retention_max_mb" });
+            false
+        });
+    }
+
+    // Blocking: rotates on age and enforces retention
+    pub fn maintain(&self) {
+        let mut active = self.active.lock().unwrap();
+        if self.due(&active, systemd::now_secs(), 0) {
+            self.seal(&mut active);
+        }
+        self.enforce_retention();
+    }
+
+    // Blocking: seals the active segment if it holds records in range, then
+    // bundles every segment overlapping since..=until
+    pub fn export(&self, since: u64, until: u64, output: &Path, identity: &CryptoIdentifier) -> Result<ExportSummary, AuditError> {
+        let mut active = self.active.lock().unwrap();
+        if active.first.is_some_and(|first| first <= until) && active.last >= since {
+            self.seal(&mut active);
+        }
+        let segments: Vec<Segment> = self.segments().into_iter().filter(|s| s.last >= since && s.first <= until).collect();
+        if segments.is_empty() {
+            return Err(AuditError::NothingToExport { since, until });
+        }
+
+        let mut entries = Vec::with_capacity(segments.len());
+        for segment in &segments {
+            let path = self.dir().join(&segment.name);
+            let bytes = fs::read(&path).map_err(io_error(&path))?;
+            entries.push(BundleEntry {
+                name: segment.name.clone(),
+                first: segment.first,
+                last: segment.last,
+                bytes: bytes.len() as u64,
+                sha256: hex(digest::digest(&digest::SHA256, &bytes).as_ref()),
+            });
+        }
+        let manifest = BundleManifest {
+            format: BUNDLE_FORMAT.to_string(),
+            host: syslog_sink::hostname(),
+            created_at: systemd::now_secs(),
+            since,
+            until,
+            public_key: hex(&identity.public_key_bytes()),
+            segments: entries,
+        };
+        let manifest_bytes = serde_json::to_vec_pretty(&manifest).map_err(|e| AuditError::Bundle(e.to_string()))?;
+        let signature = identity.sign(&manifest_bytes);
+
+        let partial = output.with_extension("partial");
+        let written = (|| -> std::io::Result<()> {
+            let mut bundle = tar::Builder::new(File::create(&partial)?);
+            for (name, data) in [(MANIFEST_NAME, &manifest_bytes), (SIGNATURE_NAME, &signature)] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(manifest.created_at);
+                header.set_cksum();
+                bundle.append_data(&mut header, name, data.as_slice())?;
+            }
+            for segment in &segments {
+                bundle.append_path_with_name(self.dir().join(&segment.name), format!("{}/{}", SEGMENT_DIR, segment.name))?;
+            }
+            bundle.into_inner()?.sync_all()?;
+            fs::rename(&partial, output)
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(AuditError::Io { path: output.to_path_buf(), source: e });
+        }
+
+        let summary = ExportSummary {
+            bundle: output.to_path_buf(),
+            segments: segments.len(),
+            bytes: manifest.segments.iter().map(|s| s.bytes).sum(),
+            first: segments.iter().map(|s| s.first).min().unwrap_or_default(),
+            last: segments.iter().map(|s| s.last).max().unwrap_or_default(),
+        };
+        tracing::info!("Audit bundle {} exported: {} segments, {}..{}", output.display(), summary.segments, summary.first, summary.last);
+        Ok(summary)
+    }
+}
+
+// Resume the active segment where the previous run left it
+// rename(2) that fails with EEXIST instead of replacing `to`
+fn rename_noreplace(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let from = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+    let to = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are NUL-terminated and outlive the call
+    let ret = unsafe { libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::RENAME_NOREPLACE) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+fn scan_active(path: &Path) -> Active {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Active::default();
+    };
+    let timestamp = |line: &str| serde_json::from_str::<serde_json::Value>(line).ok()?["timestamp"].as_u64();
+    let mut lines = contents.lines().filter(|l| !l.is_empty());
+    let first = lines.next().and_then(timestamp);
+    let last = contents.lines().rev().find_map(timestamp).or(first).unwrap_or_default();
+    Active { first: first.or_else(|| (!contents.is_empty()).then(systemd::now_secs)), last, bytes: contents.len() as u64 }
+}
+
+pub fn start_maintenance(log: Arc<AuditLog>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let log = log.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || log.maintain()).await {
+                tracing::error!("Audit log maintenance failed: {}", e);
+            }
+        }
+    })
+}
+
+// Offline check of a bundle: the manifest signature against its own key,
+// that key against `trusted_keys` when any are given, and every segment
+// against its digest
+pub fn verify_bundle(path: &Path, trusted_keys: &[Vec<u8>]) -> Result<BundleManifest, AuditError> {
+    let mut files = HashMap::new();
+    let mut bundle = tar::Archive::new(File::open(path).map_err(io_error(path))?);
+    for entry in bundle.entries().map_err(io_error(path))? {
+        let mut entry = entry.map_err(io_error(path))?;
+        let name = entry.path().map_err(io_error(path))?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(io_error(path))?;
+        files.insert(name, data);
+    }
+
+    let manifest_bytes = files.get(MANIFEST_NAME).ok_or_else(|| AuditError::Bundle(format!("no {}", MANIFEST_NAME)))?;
+    let signature = files.get(SIGNATURE_NAME).ok_or_else(|| AuditError::Bundle(format!("no {}", SIGNATURE_NAME)))?;
+    let manifest: BundleManifest = serde_json::from_slice(manifest_bytes).map_err(|e| AuditError::Bundle(e.to_string()))?;
+    if manifest.format != BUNDLE_FORMAT {
+        return Err(AuditError::Bundle(format!("unknown format {:?}", manifest.format)));
+    }
+    let public_key = decode_hex(&manifest.public_key).ok_or_else(|| AuditError::Bundle("public_key is not hex".to_string()))?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, &public_key)
+        .verify(manifest_bytes, signature)
+        .map_err(|_| AuditError::BadSignature)?;
+    if !trusted_keys.is_empty() && !trusted_keys.contains(&public_key) {
+        return Err(AuditError::UntrustedKey);
+    }
+
+    for entry in &manifest.segments {
+        let data = files.get(&format!("{}/{}", SEGMENT_DIR, entry.name));
+        if data.map(|data| hex(digest::digest(&digest::SHA256, data).as_ref())).as_ref() != Some(&entry.sha256) {
+            return Err(AuditError::Tampered(entry.name.clone()));
+        }
+    }
+    Ok(manifest)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,171 @@
+// src/baseline_model.rs
+// Host baseline learned online, used for scoring until a trained model is
+// installed. Features get exponentially weighted mean/variance; syscall
+// streams get a trigram frequency table.
+use std::collections::HashMap;
+
+// Weight of each new sample; ~1000-sample memory
+const EWMA_ALPHA: f64 = 0.001;
+// No verdicts until the statistics have seen this much traffic
+const WARMUP_SAMPLES: u64 = 500;
+// |z| at which the feature score reaches ~0.63
+pub(crate) const Z_SCALE: f64 = 4.0;
+// Trigrams seen fewer times than this count as novel
+const RARE_NGRAM_COUNT: u64 = 2;
+// Bound the table on hosts with very diverse workloads
+const MAX_NGRAMS: usize = 1 << 18;
+// Share of a warm host's statistics replaced by the fleet's on adoption
+const FLEET_BLEND: f64 = 0.5;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct RunningStat {
+    pub(crate) mean: f64,
+    var: f64,
+}
+
+impl RunningStat {
+    // `n` counts samples including this one. A plain running average until
+    // it reaches the EWMA's memory, so early samples aren't stuck at the
+    // first value's weight; n == 1 just takes x.
+    pub(crate) fn update(&mut self, x: f64, n: u64) {
+        let alpha = (1.0 / n.max(1) as f64).max(EWMA_ALPHA);
+        let delta = x - self.mean;
+        self.mean += alpha * delta;
+        self.var = (1.0 - alpha) * (self.var + alpha * delta * delta);
+    }
+
+    pub(crate) fn z(&self, x: f64) -> f64 {
+        // Floor the deviation: a feature that never varied is not infinitely
+        // surprising the first time it moves
+        let std = self.var.sqrt().max(1e-3 * self.mean.abs()).max(1e-6);
+        (x - self.mean) / std
+    }
+}
+
+// Feature statistics without any of the traffic behind them; what a host
+// shares with the fleet
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BaselineStats {
+    pub means: Vec<f64>,
+    pub vars: Vec<f64>,
+    pub samples: u64,
+}
+
+impl BaselineStats {
+    pub fn width(&self) -> usize {
+        self.means.len()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OnlineBaseline {
+    stats: Vec<RunningStat>,
+    samples: u64,
+    ngrams: HashMap<[u32; 3], u64>,
+    sequences: u64,
+}
+
+impl OnlineBaseline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_warm(&self) -> bool {
+        self.samples >= WARMUP_SAMPLES
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn update(&mut self, features: &[f32]) {
+        if self.stats.len() != features.len() {
+            // Feature layout changed: start over rather than mix schemas
+            self.stats = vec![RunningStat::default(); features.len()];
+            self.samples = 0;
+        }
+        self.samples += 1;
+        for (stat, &x) in self.stats.iter_mut().zip(features) {
+            stat.update(x as f64, self.samples);
+        }
+    }
+
+    // Same shape as a model result: score in [0, 1) and the "expected"
+    // feature vector (current means) standing in for the reconstruction
+    pub fn score(&self, features: &[f32]) -> (f32, Vec<f32>) {
+        let expected: Vec<f32> = self.stats.iter().map(|s| s.mean as f32).collect();
+        if !self.is_warm() || self.stats.len() != features.len() {
+            return (0.0, expected);
+        }
+
+        let worst = self.stats.iter()
+            .zip(features)
+            .map(|(stat, &x)| stat.z(x as f64).abs())
+            .fold(0.0, f64::max);
+
+        ((1.0 - (-worst / Z_SCALE).exp()) as f32, expected)
+    }
+
+    // Per-feature |z| against the running statistics; empty until warm
+    pub fn deviations(&self, features: &[f32]) -> Vec<f32> {
+        if !self.is_warm() || self.stats.len() != features.len() {
+            return Vec::new();
+        }
+        self.stats.iter()
+            .zip(features)
+            .map(|(stat, &x)| stat.z(x as f64).abs() as f32)
+            .collect()
+    }
+
+    pub fn export(&self) -> BaselineStats {
+        BaselineStats {
+            means: self.stats.iter().map(|s| s.mean).collect(),
+            vars: self.stats.iter().map(|s| s.var).collect(),
+            samples: self.samples,
+        }
+    }
+
+    // Take on fleet statistics: a cold host starts from them outright, a
+    // warm one blends them with what it has learned locally
+    pub fn adopt(&mut self, fleet: &BaselineStats) {
+        if fleet.vars.len() != fleet.means.len() {
+            return;
+        }
+        if !self.is_warm() || self.stats.len() != fleet.width() {
+            self.stats = fleet.means.iter()
+                .zip(&fleet.vars)
+                .map(|(&mean, &var)| RunningStat { mean, var })
+                .collect();
+            self.samples = fleet.samples.min(WARMUP_SAMPLES);
+            return;
+        }
+        for (stat, (&mean, &var)) in self.stats.iter_mut().zip(fleet.means.iter().zip(&fleet.vars)) {
+            stat.mean += FLEET_BLEND * (mean - stat.mean);
+            stat.var += FLEET_BLEND * (var - stat.var);
+        }
+    }
+
+    pub fn update_sequence(&mut self, sequence: &[u32]) {
+        self.sequences += 1;
+        for window in sequence.windows(3) {
+            let key = [window[0], window[1], window[2]];
+            if self.ngrams.len() >= MAX_NGRAMS && !self.ngrams.contains_key(&key) {
+                continue;
+            }
+            *self.ngrams.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    // Fraction of the sequence's trigrams that are new or rare on this host
+    pub fn sequence_novelty(&self, sequence: &[u32]) -> f32 {
+        let total = sequence.len().saturating_sub(2);
+        if total == 0 || self.sequences < WARMUP_SAMPLES {
+            return 0.0;
+        }
+
+        let novel = sequence.windows(3)
+            .filter(|w| self.ngrams.get(&[w[0], w[1], w[2]]).copied().unwrap_or(0) < RARE_NGRAM_COUNT)
+            .count();
+        novel as f32 / total as f32
+    }
+}
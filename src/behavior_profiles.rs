@@ -0,0 +1,274 @@
+// src/behavior_profiles.rs
+// Learned behaviour per executable or container image. A process is judged
+// against what its own binary normally does (syscall mix, timing, children)
+// rather than against the host-wide model; a web server forking workers is
+// normal, a shell doing so at the same rate may not be. Outside the host's
+// network namespace a process is keyed by its container's image, or by the
+// namespace and binary when it is in no known container, so the same nginx
+// on the host and in a sandbox learn separate baselines. Profiles persist
+// across restarts as one JSON file.
+use crate::baseline_model::{RunningStat, Z_SCALE};
+use crate::container_events;
+use crate::ml_detector::ProcessMetadata;
+use crate::netns;
+use crate::privsep;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Observations before a profile is trusted over the global model
+const WARMUP_OBSERVATIONS: u64 = 200;
+// Halve syscall counts past this so the mix keeps tracking upgrades
+const MAX_SYSCALL_TOTAL: u64 = 1 << 24;
+// Bound memory on hosts that run many distinct short-lived binaries
+const MAX_PROFILES: usize = 4096;
+// Profiles unseen for this long are dropped on save
+const PROFILE_TTL_SECS: u64 = 30 * 24 * 3600;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("profile store I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("profile store format: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProfileKey {
+    Executable(PathBuf),
+    Image(String),
+    // A binary in a network namespace no known container owns
+    Namespace { netns: u64, exe: PathBuf },
+}
+
+impl ProfileKey {
+    pub fn for_pid(pid: u32) -> Option<Self> {
+        let exe = privsep::read_exe(pid).ok()?;
+        match netns::global().netns_of(pid) {
+            Some(ns) if Some(ns) != netns::host() => match container_events::global().for_pid(pid) {
+                Some(container) => Some(ProfileKey::Image(container.image.clone())),
+                None => Some(ProfileKey::Namespace { netns: ns, exe }),
+            },
+            _ => Some(ProfileKey::Executable(exe)),
+        }
+    }
+}
+
+impl fmt::Display for ProfileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileKey::Executable(path) => write!(f, "exe:{}", path.display()),
+            ProfileKey::Image(image) => write!(f, "image:{}", image),
+            ProfileKey::Namespace { netns, exe } => write!(f, "netns:{}:{}", netns, exe.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BehaviorProfile {
+    syscall_counts: HashMap<u32, u64>,
+    syscall_total: u64,
+    sequence_len: RunningStat,
+    mean_latency: RunningStat,
+    children: RunningStat,
+    observations: u64,
+    last_seen: u64,
+}
+
+impl BehaviorProfile {
+    pub fn is_warm(&self) -> bool {
+        self.observations >= WARMUP_OBSERVATIONS
+    }
+
+    pub fn observations(&self) -> u64 {
+        self.observations
+    }
+
+    // Every syscall number seen, while it still carries any weight
+    pub fn syscalls(&self) -> Vec<u32> {
+        self.syscall_counts.keys().copied().collect()
+    }
+
+    fn update(&mut self, syscalls: &[u32], timing: &[u64], metadata: &ProcessMetadata) {
+        for &syscall in syscalls {
+            *self.syscall_counts.entry(syscall).or_insert(0) += 1;
+        }
+        self.syscall_total += syscalls.len() as u64;
+        if self.syscall_total > MAX_SYSCALL_TOTAL {
+            self.syscall_counts.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+            self.syscall_total = self.syscall_counts.values().sum();
+        }
+
+        self.observations += 1;
+        self.sequence_len.update(syscalls.len() as f64, self.observations);
+        self.mean_latency.update(mean(timing), self.observations);
+        self.children.update(metadata.children_count as f64, self.observations);
+        self.last_seen = now_secs();
+    }
+
+    // Jensen-Shannon divergence (base 2, so in [0, 1]) between this
+    // observation's syscall mix and the profile's
+    fn divergence(&self, syscalls: &[u32]) -> f64 {
+        if syscalls.is_empty() || self.syscall_total == 0 {
+            return 0.0;
+        }
+
+        let mut observed: HashMap<u32, f64> = HashMap::new();
+        for &syscall in syscalls {
+            *observed.entry(syscall).or_insert(0.0) += 1.0 / syscalls.len() as f64;
+        }
+
+        let expected = |syscall: &u32| {
+            self.syscall_counts.get(syscall).copied().unwrap_or(0) as f64 / self.syscall_total as f64
+        };
+        let term = |p: f64, m: f64| if p > 0.0 { p * (p / m).log2() } else { 0.0 };
+
+        let mut divergence = 0.0;
+        for (syscall, &p) in &observed {
+            let q = expected(syscall);
+            let m = (p + q) / 2.0;
+            divergence += 0.5 * term(p, m) + 0.5 * term(q, m);
+        }
+        // Profile mass on syscalls this observation never made
+        for (syscall, &count) in &self.syscall_counts {
+            if !observed.contains_key(syscall) {
+                let q = count as f64 / self.syscall_total as f64;
+                divergence += 0.5 * term(q, q / 2.0);
+            }
+        }
+        divergence.clamp(0.0, 1.0)
+    }
+
+    fn score(&self, syscalls: &[u32], timing: &[u64], metadata: &ProcessMetadata) -> ProfileScore {
+        let max_z = [
+            self.sequence_len.z(syscalls.len() as f64),
+            self.mean_latency.z(mean(timing)),
+            self.children.z(metadata.children_count as f64),
+        ]
+        .iter()
+        .fold(0.0f64, |worst, z| worst.max(z.abs()));
+        let divergence = self.divergence(syscalls);
+
+        ProfileScore {
+            score: (1.0 - (-max_z / Z_SCALE).exp()).max(divergence) as f32,
+            divergence: divergence as f32,
+            max_z: max_z as f32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ProfileScore {
+    // In [0, 1): the worse of the syscall-mix divergence and the timing /
+    // sequence-length / children deviation
+    pub score: f32,
+    pub divergence: f32,
+    pub max_z: f32,
+}
+
+#[derive(Clone, Default)]
+pub struct BehaviorProfiles {
+    profiles: Arc<DashMap<String, BehaviorProfile>>,
+}
+
+impl BehaviorProfiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ProfileError> {
+        let stored: HashMap<String, BehaviorProfile> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        tracing::info!("Loaded {} behavior profiles from {}", stored.len(), path.display());
+        Ok(Self { profiles: Arc::new(stored.into_iter().collect()) })
+    }
+
+    // Write-then-rename so a crash never leaves a truncated store
+    pub fn save(&self, path: &Path) -> Result<(), ProfileError> {
+        let cutoff = now_secs().saturating_sub(PROFILE_TTL_SECS);
+        self.profiles.retain(|_, profile| profile.last_seen >= cutoff);
+
+        let snapshot: HashMap<String, BehaviorProfile> = self.profiles.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    pub fn profile(&self, key: &ProfileKey) -> Option<BehaviorProfile> {
+        self.profiles.get(&key.to_string()).map(|p| p.clone())
+    }
+
+    // None while the profile is missing or still warming up; the caller
+    // falls back to the global model
+    pub fn score(&self, key: &ProfileKey, syscalls: &[u32], timing: &[u64], metadata: &ProcessMetadata) -> Option<ProfileScore> {
+        let profile = self.profiles.get(&key.to_string())?;
+        profile.is_warm().then(|| profile.score(syscalls, timing, metadata))
+    }
+
+    pub fn update(&self, key: &ProfileKey, syscalls: &[u32], timing: &[u64], metadata: &ProcessMetadata) {
+        let key = key.to_string();
+        if !self.profiles.contains_key(&key) && self.profiles.len() >= MAX_PROFILES {
+            return;
+        }
+        self.profiles.entry(key).or_default().update(syscalls, timing, metadata);
+    }
+
+    // Score before learning so an observation never vouches for itself
+    pub fn observe(&self, key: &ProfileKey, syscalls: &[u32], timing: &[u64], metadata: &ProcessMetadata) -> Option<ProfileScore> {
+        let score = self.score(key, syscalls, timing, metadata);
+        self.update(key, syscalls, timing, metadata);
+        score
+    }
+
+    pub fn start_persist(&self, path: &Path, interval_secs: u64) -> tokio::task::JoinHandle<()> {
+        let profiles = self.clone();
+        let path = path.to_path_buf();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let profiles = profiles.clone();
+                let path = path.clone();
+                match tokio::task::spawn_blocking(move || profiles.save(&path)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::warn!("Saving behavior profiles failed: {}", e),
+                    Err(e) => tracing::warn!("Behavior profile save task failed: {}", e),
+                }
+            }
+        })
+    }
+}
+
+fn mean(values: &[u64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<u64>() as f64 / values.len() as f64
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,70 @@
+// src/bin/qks-helper.rs
+// The privileged half of qksd under [privsep]: runs as root and carries out
+// the narrow requests the unprivileged daemon sends over its socket. Reads
+// the daemon's own configuration for the user to accept and the cgroups it
+// may manage.
+use quantum_kernel_security::config::{self, QksConfig};
+use quantum_kernel_security::logging;
+use quantum_kernel_security::privsep::Helper;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: qks-helper [--config FILE]";
+
+fn parse_args() -> Result<PathBuf, String> {
+    let mut config = PathBuf::from(config::DEFAULT_CONFIG_PATH);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config = PathBuf::from(args.next().ok_or("--config needs a value")?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("unknown argument {}\n{}", other, USAGE)),
+        }
+    }
+    Ok(config)
+}
+
+fn main() -> ExitCode {
+    let path = match parse_args() {
+        Ok(path) => path,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(2);
+        }
+    };
+    let loaded = if path == std::path::Path::new(config::DEFAULT_CONFIG_PATH) {
+        QksConfig::load_default()
+    } else {
+        QksConfig::load(&path)
+    };
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("qks-helper: {}", e);
+            return ExitCode::from(78);
+        }
+    };
+    if !config.privsep.enabled {
+        eprintln!("qks-helper: privsep is not enabled in {}", path.display());
+        return ExitCode::from(78);
+    }
+    if let Err(e) = logging::init(&config.logging) {
+        eprintln!("qks-helper: {}", e);
+        return ExitCode::from(78);
+    }
+
+    let helper = match Helper::new(&config) {
+        Ok(helper) => helper,
+        Err(e) => {
+            tracing::error!("qks-helper: {}", e);
+            return ExitCode::from(78);
+        }
+    };
+    match helper.serve(&config.privsep.helper_socket) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("qks-helper: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
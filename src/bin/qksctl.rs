@@ -0,0 +1,492 @@
+// src/bin/qksctl.rs
+// Command-line client for qksd's control socket. Prints the daemon's JSON
+// reply; exits 1 when the daemon reports an error (or, for diagnostics, a
+// failed check), 2 when it can't be reached.
+use clap::{Parser, Subcommand};
+use quantum_kernel_security::audit_log;
+use quantum_kernel_security::control::{ControlClient, ControlError, ControlRequest, APT_TAG_PREFIX, DEFAULT_SOCKET_PATH};
+use quantum_kernel_security::crypto_identifiers::{Capability, ProcessToken};
+use quantum_kernel_security::landlock;
+use quantum_kernel_security::mac_profiles::ProfileFormat;
+use quantum_kernel_security::model_signing::ModelTrust;
+use quantum_kernel_security::response::ResponseAction;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "qksctl", about = "Control the quantum kernel security daemon")]
+struct Cli {
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    #[arg(long, help = "Single-line JSON instead of pretty-printed")]
+    compact: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Subsystem health")]
+    Health,
+    #[command(about = "Check eBPF, the model, the snapshot store, the TPM and the clock")]
+    Diagnostics,
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    #[command(subcommand)]
+    Token(TokenCommand),
+    #[command(subcommand)]
+    Monitor(MonitorCommand),
+    #[command(subcommand)]
+    Randomizer(RandomizerCommand),
+    #[command(subcommand)]
+    Detector(DetectorCommand),
+    #[command(subcommand)]
+    Quarantine(QuarantineCommand),
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    #[command(about = "Secure Boot, kernel lockdown and module signature enforcement")]
+    Posture,
+    #[command(about = "Loaded kernel modules: file, package, signature and taint")]
+    Modules,
+    #[command(about = "Progress of the installed-package sweep against dpkg's md5sums")]
+    Packages,
+    #[command(subcommand)]
+    Ima(ImaCommand),
+    #[command(subcommand)]
+    Verity(VerityCommand),
+    #[command(subcommand)]
+    Tpm(TpmCommand),
+    #[command(about = "Fleet role, and the controller's agents or the agent's link to its controller")]
+    Fleet,
+    #[command(about = "Threat intelligence feeds: when each was fetched and how many indicators it gave")]
+    Intel,
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+    #[command(about = "Restore the newest snapshot taken before a package operation")]
+    Rollback {
+        #[arg(long, required = true, help = "The newest snapshot the APT hook took")]
+        last_before_upgrade: bool,
+        #[arg(long, help = "Draw new layouts instead of the recorded ones")]
+        fresh: bool,
+    },
+    #[command(about = "Run a command under Landlock, confined to what a valid token grants")]
+    Run {
+        #[arg(long, help = "Token JSON from `token issue`: FILE, or - for stdin")]
+        token: PathBuf,
+        #[arg(required = true, last = true)]
+        command: Vec<String>,
+    },
+    #[command(about = "Frozen processes, who holds them and when they thaw")]
+    Frozen,
+    #[command(about = "Take a response action against a process; audited like automatic ones")]
+    Respond {
+        #[arg(value_parser = parse_action, help = "kill, stop, thaw, rerandomize, revoke_token, snapshot, block_egress, quarantine or release")]
+        action: ResponseAction,
+        #[arg(help = "Target PID; not needed for snapshot")]
+        pid: Option<u32>,
+        #[arg(long, help = "Recorded in the audit log")]
+        reason: Option<String>,
+        #[arg(long, help = "Run the safety checks and record the request without acting")]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    Take {
+        #[arg(long, help = "a-z, 0-9 and -, e.g. pre-maintenance")]
+        tag: Option<String>,
+        #[arg(long, help = "Kept with the snapshot")]
+        note: Option<String>,
+    },
+    List,
+    Diff { from: String, to: String },
+    #[command(about = "Re-apply a snapshot's layouts to the processes still running")]
+    Restore {
+        id: String,
+        #[arg(long, help = "Draw new layouts instead of the recorded ones")]
+        fresh: bool,
+    },
+    Verify { id: String },
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    #[command(about = "Issue a token bound to PID; printed as JSON for verify and revoke")]
+    Issue {
+        pid: u32,
+        #[arg(long = "cap", value_parser = parse_capability, help = "network, fs:PATH, syscall:NR or memory:BYTES; repeatable")]
+        capabilities: Vec<Capability>,
+    },
+    Verify {
+        #[arg(help = "Token JSON from `token issue`: FILE, or - for stdin")]
+        token: PathBuf,
+    },
+    Revoke {
+        #[arg(help = "Token JSON from `token issue`: FILE, or - for stdin")]
+        token: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum MonitorCommand {
+    Stats {
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+    },
+    Enable { group: String },
+    Disable { group: String },
+    #[command(about = "Network namespaces: processes, containers and host-side veths")]
+    Netns,
+    #[command(about = "Recent events of every kind but syscall and network")]
+    Events {
+        #[arg(long, default_value_t = 0, help = "Only events after this sequence number")]
+        after: u64,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum RandomizerCommand {
+    #[command(about = "What applying a new layout would do, without doing it")]
+    Plan { pid: u32 },
+    Apply { pid: u32 },
+}
+
+#[derive(Subcommand)]
+enum DetectorCommand {
+    #[command(about = "The PID's latest score and its top contributing features")]
+    Score { pid: u32 },
+    #[command(about = "The most suspicious processes by latest score")]
+    Top {
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+    },
+    #[command(about = "Reload the configured model, or the one at PATH")]
+    Reload { path: Option<String> },
+}
+
+#[derive(Subcommand)]
+enum QuarantineCommand {
+    #[command(about = "Move PID into the quarantine cgroup: capped, no network, still alive")]
+    Add { pid: u32 },
+    #[command(about = "Return PID to the cgroup it was quarantined from")]
+    Release {
+        pid: u32,
+        #[arg(long, help = "Recorded in the audit log")]
+        reason: Option<String>,
+    },
+    List,
+}
+
+#[derive(Subcommand)]
+enum AuditCommand {
+    #[command(about = "Sealed segments of the response audit log")]
+    List,
+    #[command(about = "Write a signed bundle of the audit records in a time range")]
+    Export {
+        #[arg(long, help = "Epoch seconds; from the oldest record when unset")]
+        since: Option<u64>,
+        #[arg(long, help = "Epoch seconds; up to now when unset")]
+        until: Option<u64>,
+        #[arg(long, help = "Bundle path, written by qksd")]
+        output: PathBuf,
+    },
+    #[command(about = "Check a bundle's signature and segment digests; runs without the daemon")]
+    Verify {
+        bundle: PathBuf,
+        #[arg(long = "key", help = "Ed25519 public key file (raw or hex) the bundle must be signed with; repeatable")]
+        keys: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImaCommand {
+    #[command(about = "Appraisal mode, EVM state and what the measurement checks found")]
+    Status,
+}
+
+#[derive(Subcommand)]
+enum VerityCommand {
+    #[command(about = "Protected volumes and verity devices: verified, corrupted or unprotected")]
+    Status,
+}
+
+#[derive(Subcommand)]
+enum TpmCommand {
+    #[command(about = "Attestation key, measurement PCR and what was measured this boot")]
+    Status,
+    #[command(about = "A signed quote of the measurement PCR with this boot's event log")]
+    Quote {
+        #[arg(long, help = "Hex, at most 32 bytes; random when unset")]
+        nonce: Option<String>,
+    },
+    #[command(about = "Check a peer's quote against the nonce it was asked for; controller mode only")]
+    Verify {
+        #[arg(help = "Quote JSON from `tpm quote`: FILE, or - for stdin")]
+        quote: PathBuf,
+        #[arg(long)]
+        nonce: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    #[command(about = "Profiled binaries: learning, awaiting review, approved or enforced")]
+    List,
+    #[command(about = "The drafted policy for BINARY")]
+    Show {
+        binary: PathBuf,
+        #[arg(long, value_parser = parse_format, default_value = "apparmor", help = "apparmor or selinux")]
+        format: ProfileFormat,
+    },
+    #[command(about = "Write the drafted policy to a file for editing elsewhere")]
+    Export {
+        binary: PathBuf,
+        #[arg(long, value_parser = parse_format, default_value = "apparmor", help = "apparmor or selinux")]
+        format: ProfileFormat,
+        #[arg(long, help = "Written by qksd")]
+        output: PathBuf,
+    },
+    #[command(about = "Approve the AppArmor draft as it reads now")]
+    Approve {
+        binary: PathBuf,
+        #[arg(long, help = "Enforce in complain mode: log denials without blocking")]
+        complain: bool,
+    },
+    #[command(about = "Install and load the approved AppArmor profile")]
+    Enforce { binary: PathBuf },
+}
+
+fn parse_format(arg: &str) -> Result<ProfileFormat, String> {
+    match arg {
+        "apparmor" => Ok(ProfileFormat::Apparmor),
+        "selinux" => Ok(ProfileFormat::Selinux),
+        _ => Err(format!("unknown format {:?} (apparmor, selinux)", arg)),
+    }
+}
+
+fn parse_action(arg: &str) -> Result<ResponseAction, String> {
+    ResponseAction::ALL.iter().copied().find(|action| action.as_str() == arg).ok_or_else(|| {
+        let known: Vec<&str> = ResponseAction::ALL.iter().map(|a| a.as_str()).collect();
+        format!("unknown action {:?} ({})", arg, known.join(", "))
+    })
+}
+
+fn parse_capability(arg: &str) -> Result<Capability, String> {
+    let (kind, value) = arg.split_once(':').unwrap_or((arg, ""));
+    match (kind, value) {
+        ("network", "") => Ok(Capability::NetworkAccess),
+        ("fs", path) if !path.is_empty() => Ok(Capability::FilesystemAccess(path.to_string())),
+        ("syscall", nr) => nr.parse().map(Capability::Syscall).map_err(|e| format!("syscall number: {}", e)),
+        ("memory", bytes) => bytes.parse().map(Capability::MemoryAllocation).map_err(|e| format!("memory limit: {}", e)),
+        _ => Err(format!("unknown capability {:?} (network, fs:PATH, syscall:NR, memory:BYTES)", arg)),
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &PathBuf, what: &str) -> Result<T, String> {
+    let json = if path.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin()).map_err(|e| format!("stdin: {}", e))?
+    } else {
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?
+    };
+    serde_json::from_str(&json).map_err(|e| format!("{}: not a {}: {}", path.display(), what, e))
+}
+
+fn read_token(path: &PathBuf) -> Result<ProcessToken, String> {
+    read_json(path, "token")
+}
+
+fn request(command: Command) -> Result<ControlRequest, String> {
+    Ok(match command {
+        Command::Health => ControlRequest::Health,
+        Command::Diagnostics => ControlRequest::Diagnostics,
+        Command::Snapshot(command) => match command {
+            SnapshotCommand::Take { tag, note } => ControlRequest::SnapshotTake { tag, note },
+            SnapshotCommand::List => ControlRequest::SnapshotList,
+            SnapshotCommand::Diff { from, to } => ControlRequest::SnapshotDiff { from, to },
+            SnapshotCommand::Restore { id, fresh } => ControlRequest::SnapshotRestore { id, fresh },
+            SnapshotCommand::Verify { id } => ControlRequest::SnapshotVerify { id },
+        },
+        Command::Token(command) => match command {
+            TokenCommand::Issue { pid, capabilities } => ControlRequest::TokenIssue { pid, capabilities },
+            TokenCommand::Verify { token } => ControlRequest::TokenVerify { token: read_token(&token)? },
+            TokenCommand::Revoke { token } => ControlRequest::TokenRevoke { token: read_token(&token)? },
+        },
+        Command::Monitor(command) => match command {
+            MonitorCommand::Stats { top } => ControlRequest::MonitorStats { top },
+            MonitorCommand::Enable { group } => ControlRequest::ProbeGroup { group, enabled: true },
+            MonitorCommand::Disable { group } => ControlRequest::ProbeGroup { group, enabled: false },
+            MonitorCommand::Netns => ControlRequest::NetnsList,
+            MonitorCommand::Events { after, limit } => ControlRequest::RecentEvents { after, limit },
+        },
+        Command::Randomizer(command) => match command {
+            RandomizerCommand::Plan { pid } => ControlRequest::RandomizerPlan { pid },
+            RandomizerCommand::Apply { pid } => ControlRequest::RandomizerApply { pid },
+        },
+        Command::Detector(command) => match command {
+            DetectorCommand::Score { pid } => ControlRequest::DetectorScore { pid },
+            DetectorCommand::Top { top } => ControlRequest::DetectorTop { top },
+            DetectorCommand::Reload { path } => ControlRequest::DetectorReload { path },
+        },
+        Command::Quarantine(command) => match command {
+            QuarantineCommand::Add { pid } => ControlRequest::Quarantine { pid },
+            QuarantineCommand::Release { pid, reason } => ControlRequest::Respond { action: ResponseAction::Release, pid: Some(pid), reason, dry_run: false },
+            QuarantineCommand::List => ControlRequest::QuarantineList,
+        },
+        Command::Audit(command) => match command {
+            AuditCommand::List => ControlRequest::AuditList,
+            AuditCommand::Export { since, until, output } => {
+                // qksd resolves paths from its own working directory
+                let output = std::path::absolute(&output).map_err(|e| format!("{}: {}", output.display(), e))?;
+                ControlRequest::AuditExport { since, until, output }
+            }
+            AuditCommand::Verify { .. } => unreachable!("verified locally in main"),
+        },
+        Command::Posture => ControlRequest::Posture,
+        Command::Modules => ControlRequest::KernelModules,
+        Command::Packages => ControlRequest::PackageSweep,
+        Command::Fleet => ControlRequest::FleetStatus,
+        Command::Intel => ControlRequest::IntelStatus,
+        Command::Ima(command) => match command {
+            ImaCommand::Status => ControlRequest::ImaStatus,
+        },
+        Command::Verity(command) => match command {
+            VerityCommand::Status => ControlRequest::VerityStatus,
+        },
+        Command::Tpm(command) => match command {
+            TpmCommand::Status => ControlRequest::TpmStatus,
+            TpmCommand::Quote { nonce } => ControlRequest::TpmQuote { nonce },
+            TpmCommand::Verify { quote, nonce } => ControlRequest::TpmVerify { quote: read_json(&quote, "quote")?, nonce },
+        },
+        Command::Profile(command) => match command {
+            ProfileCommand::List => ControlRequest::ProfileList,
+            ProfileCommand::Show { binary, format } => ControlRequest::ProfileShow { binary, format },
+            ProfileCommand::Export { binary, format, output } => {
+                let output = std::path::absolute(&output).map_err(|e| format!("{}: {}", output.display(), e))?;
+                ControlRequest::ProfileExport { binary, format, output }
+            }
+            ProfileCommand::Approve { binary, complain } => ControlRequest::ProfileApprove { binary, complain },
+            ProfileCommand::Enforce { binary } => ControlRequest::ProfileEnforce { binary },
+        },
+        Command::Rollback { fresh, .. } => ControlRequest::SnapshotRollback { tag: APT_TAG_PREFIX.to_string(), fresh },
+        Command::Run { .. } => unreachable!("spawned locally in main"),
+        Command::Frozen => ControlRequest::FreezeList,
+        Command::Respond { action, pid, reason, dry_run } => ControlRequest::Respond { action, pid, reason, dry_run },
+    })
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Command::Audit(AuditCommand::Verify { bundle, keys }) = &cli.command {
+        return verify_bundle(bundle, keys, cli.compact);
+    }
+    if let Command::Run { token, command } = &cli.command {
+        return run_confined(&cli.socket, token, command).await;
+    }
+    // Policy text reads better as itself than as a JSON string
+    let raw = matches!(cli.command, Command::Profile(ProfileCommand::Show { .. }));
+    // For exec probes
+    let probe = matches!(cli.command, Command::Diagnostics);
+    let request = match request(cli.command) {
+        Ok(request) => request,
+        Err(message) => {
+            eprintln!("qksctl: {}", message);
+            return ExitCode::from(2);
+        }
+    };
+
+    let reply = match ControlClient::connect(&cli.socket).await {
+        Ok(mut client) => client.request(&request).await,
+        Err(e) => Err(e),
+    };
+    match reply {
+        Ok(serde_json::Value::String(text)) if raw => {
+            print!("{}", text);
+            ExitCode::SUCCESS
+        }
+        Ok(value) => {
+            let printed = if cli.compact { serde_json::to_string(&value) } else { serde_json::to_string_pretty(&value) };
+            println!("{}", printed.unwrap_or_default());
+            if probe && value["status"] == "failed" {
+                return ExitCode::FAILURE;
+            }
+            ExitCode::SUCCESS
+        }
+        Err(ControlError::Daemon(message)) => {
+            eprintln!("qksctl: {}", message);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("qksctl: {}: {}", cli.socket.display(), e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn verify_bundle(bundle: &PathBuf, keys: &[PathBuf], compact: bool) -> ExitCode {
+    let verified = ModelTrust::from_key_files(keys).map_err(|e| e.to_string()).and_then(|trust| {
+        let trusted_keys = match trust {
+            ModelTrust::Verify { trusted_keys } => trusted_keys,
+            ModelTrust::Insecure => Vec::new(),
+        };
+        audit_log::verify_bundle(bundle, &trusted_keys).map_err(|e| format!("{}: {}", bundle.display(), e))
+    });
+    match verified {
+        Ok(manifest) => {
+            let printed = if compact { serde_json::to_string(&manifest) } else { serde_json::to_string_pretty(&manifest) };
+            println!("{}", printed.unwrap_or_default());
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            eprintln!("qksctl: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The daemon vouches for the token; the command runs here, as the caller
+async fn run_confined(socket: &PathBuf, token: &PathBuf, command: &[String]) -> ExitCode {
+    let token = match read_token(token) {
+        Ok(token) => token,
+        Err(message) => {
+            eprintln!("qksctl: {}", message);
+            return ExitCode::from(2);
+        }
+    };
+    let status = match ControlClient::connect(socket).await {
+        Ok(mut client) => client.request(&ControlRequest::TokenVerify { token: token.clone() }).await,
+        Err(e) => Err(e),
+    };
+    match status {
+        Ok(status) if status["valid"].as_bool() == Some(true) => {}
+        Ok(status) => {
+            eprintln!("qksctl: token rejected: {}", status["failures"]);
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            eprintln!("qksctl: {}: {}", socket.display(), e);
+            return ExitCode::from(2);
+        }
+    }
+
+    let mut child = std::process::Command::new(&command[0]);
+    child.args(&command[1..]);
+    let mut child = match landlock::spawn_confined(&mut child, &token) {
+        Ok((child, _)) => child,
+        Err(e) => {
+            eprintln!("qksctl: {}: {}", command[0], e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match child.wait() {
+        // Shells report death by signal as 128 + signal
+        Ok(status) => ExitCode::from(status.code().or_else(|| std::os::unix::process::ExitStatusExt::signal(&status).map(|signal| 128 + signal)).unwrap_or(1) as u8),
+        Err(e) => {
+            eprintln!("qksctl: {}: {}", command[0], e);
+            ExitCode::FAILURE
+        }
+    }
+}
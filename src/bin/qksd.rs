@@ -0,0 +1,102 @@
+// src/bin/qksd.rs
+// The quantum kernel security daemon: every subsystem under one runtime.
+use quantum_kernel_security::config::{self, QksConfig};
+use quantum_kernel_security::daemon::Daemon;
+use quantum_kernel_security::logging;
+use quantum_kernel_security::self_sandbox;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: qksd [--config FILE] [--check-config]";
+
+struct Args {
+    config: PathBuf,
+    check_only: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut parsed = Args { config: PathBuf::from(config::DEFAULT_CONFIG_PATH), check_only: false };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => parsed.config = PathBuf::from(args.next().ok_or("--config needs a value")?),
+            "--check-config" => parsed.check_only = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("unknown argument {}\n{}", other, USAGE)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(2);
+        }
+    };
+
+    // Only the packaged path may be absent; an explicit --config must exist
+    let loaded = if args.config == std::path::Path::new(config::DEFAULT_CONFIG_PATH) {
+        QksConfig::load_default()
+    } else {
+        QksConfig::load(&args.config)
+    };
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("qksd: {}", e);
+            return ExitCode::from(78);
+        }
+    };
+    if args.check_only {
+        println!("{}: ok", args.config.display());
+        return ExitCode::SUCCESS;
+    }
+    // While this is the only thread: capabilities and Landlock bind just
+    // the caller and the threads it creates afterwards
+    if let Err(e) = self_sandbox::restrict(&config) {
+        eprintln!("qksd: sandbox: {}", e);
+        return ExitCode::FAILURE;
+    }
+    // Everything before this point reports on stderr directly
+    if let Err(e) = logging::init(&config.logging) {
+        eprintln!("qksd: {}", e);
+        return ExitCode::from(78);
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            tracing::error!("qksd: tokio runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    runtime.block_on(run(args.config, config))
+}
+
+async fn run(path: PathBuf, config: QksConfig) -> ExitCode {
+    let daemon = match Daemon::start(config.clone()).await {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            tracing::error!("qksd failed to start: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match config::start_reload(path, config) {
+        Ok((_, updates)) => daemon.follow_config(updates),
+        Err(e) => tracing::warn!("SIGHUP reload unavailable: {}", e),
+    }
+    for (subsystem, health) in daemon.health() {
+        tracing::info!("{}: {:?}", subsystem, health);
+    }
+
+    match daemon.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("qksd: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,441 @@
+// src/bin/qkstop.rs
+// Live view of a running qksd for incident response on a headless server:
+// syscall rates, the most suspicious processes, recent alerts, snapshots
+// and randomizer activity, refreshed from the control socket. Read access
+// is enough. Syscall rates are the change in the eBPF per-syscall counts
+// between refreshes, so they stay empty under the proc connector or /proc
+// polling. Alerts, layout changes and snapshot events come from the
+// daemon's recent-event history: whatever it still held at startup, then
+// everything after.
+//
+// Keys: q, Esc or Ctrl-C quits; p pauses refreshing.
+use clap::Parser;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use quantum_kernel_security::control::{ControlClient, ControlError, ControlRequest, DEFAULT_SOCKET_PATH};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, List, ListItem, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Stdout};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TOP_ROWS: usize = 15;
+// Per refresh; a larger burst is caught up over the next ones
+const EVENTS_PER_REFRESH: usize = 500;
+// Lines kept per panel
+const MAX_LINES: usize = 200;
+const MIN_INTERVAL_MS: u64 = 100;
+const SNAPSHOTS_SHOWN: usize = 3;
+
+#[derive(Parser)]
+#[command(name = "qkstop", about = "Live dashboard for the quantum kernel security daemon")]
+struct Cli {
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    #[arg(long, default_value_t = 1000, help = "Refresh interval in milliseconds")]
+    interval_ms: u64,
+}
+
+struct SyscallRate {
+    syscall: u64,
+    per_sec: f64,
+    avg_duration_ns: u64,
+    error_rate: f64,
+}
+
+#[derive(Default)]
+struct Dashboard {
+    // Subsystem, state and the reason for anything not running
+    health: Vec<(String, String, Option<String>)>,
+    telemetry: Option<String>,
+    // Per-syscall counts at the last refresh, for the rates
+    counts: HashMap<u64, u64>,
+    polled: Option<Instant>,
+    rates: Vec<SyscallRate>,
+    suspicious: Vec<Value>,
+    // From the latest anomaly; scores above it are highlighted
+    threshold: Option<f64>,
+    // Newest first
+    alerts: VecDeque<(Style, String)>,
+    layouts: VecDeque<(Style, String)>,
+    layout_times: VecDeque<u64>,
+    snapshots: Vec<Value>,
+    snapshot_events: VecDeque<(Style, String)>,
+    // Sequence number of the last event seen
+    after: u64,
+    updated: Option<u64>,
+    error: Option<String>,
+    paused: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// HH:MM:SS in UTC, which is what the daemon's timestamps are
+fn clock(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+fn push(lines: &mut VecDeque<(Style, String)>, style: Style, line: String) {
+    lines.push_front((style, line));
+    lines.truncate(MAX_LINES);
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl Dashboard {
+    async fn refresh(&mut self, client: &mut Option<ControlClient>, socket: &Path) {
+        if client.is_none() {
+            match ControlClient::connect(socket).await {
+                Ok(connected) => *client = Some(connected),
+                Err(e) => {
+                    self.error = Some(format!("{}: {}", socket.display(), e));
+                    return;
+                }
+            }
+        }
+        let Some(connection) = client.as_mut() else {
+            return;
+        };
+        match self.poll(connection).await {
+            Ok(()) => {
+                self.error = None;
+                self.updated = Some(now_secs());
+            }
+            // The daemon went away or restarted: reconnect next time
+            Err(e @ (ControlError::Io(_) | ControlError::Closed)) => {
+                *client = None;
+                self.error = Some(format!("{}: {}", socket.display(), e));
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    async fn poll(&mut self, client: &mut ControlClient) -> Result<(), ControlError> {
+        let health = client.request(&ControlRequest::Health).await?;
+        self.health = health
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(name, health)| (name.clone(), text(&health["state"]), health["reason"].as_str().map(str::to_string)))
+            .collect();
+
+        let stats = client.request(&ControlRequest::MonitorStats { top: TOP_ROWS }).await?;
+        self.update_rates(&stats);
+        self.suspicious = client.request(&ControlRequest::DetectorTop { top: TOP_ROWS }).await?.as_array().cloned().unwrap_or_default();
+        self.snapshots = client.request(&ControlRequest::SnapshotList).await?.as_array().cloned().unwrap_or_default();
+
+        let page = client.request(&ControlRequest::RecentEvents { after: self.after, limit: EVENTS_PER_REFRESH }).await?;
+        for item in page["items"].as_array().into_iter().flatten() {
+            self.record(&item["event"]);
+        }
+        self.after = page["next_after"].as_u64().unwrap_or(self.after);
+        let minute_ago = now_secs().saturating_sub(60);
+        while self.layout_times.back().is_some_and(|at| *at < minute_ago) {
+            self.layout_times.pop_back();
+        }
+        Ok(())
+    }
+
+    fn update_rates(&mut self, stats: &Value) {
+        let now = Instant::now();
+        let elapsed = self.polled.map(|at| now.duration_since(at).as_secs_f64());
+        self.polled = Some(now);
+        self.telemetry = stats["coverage"]["source"].as_str().map(str::to_string);
+
+        let mut counts = HashMap::new();
+        let mut rates = Vec::new();
+        for row in stats["syscalls"].as_array().into_iter().flatten() {
+            let (Some(syscall), Some(count)) = (row["syscall"].as_u64(), row["stats"]["count"].as_u64()) else {
+                continue;
+            };
+            counts.insert(syscall, count);
+            let per_sec = match (elapsed, self.counts.get(&syscall)) {
+                (Some(elapsed), Some(previous)) if elapsed > 0.0 => count.saturating_sub(*previous) as f64 / elapsed,
+                _ => 0.0,
+            };
+            rates.push(SyscallRate {
+                syscall,
+                per_sec,
+                avg_duration_ns: row["stats"]["avg_duration_ns"].as_u64().unwrap_or(0),
+                error_rate: row["stats"]["error_rate"].as_f64().unwrap_or(0.0),
+            });
+        }
+        rates.sort_by(|a, b| b.per_sec.total_cmp(&a.per_sec));
+        self.counts = counts;
+        self.rates = rates;
+    }
+
+    // One event from the history, as SecurityEvent serializes it
+    fn record(&mut self, event: &Value) {
+        let body = &event["event"];
+        let at = body["timestamp"].as_u64().unwrap_or_else(now_secs);
+        let time = clock(at);
+        let pid = text(&body["pid"]);
+        let red = Style::default().fg(Color::Red);
+        let yellow = Style::default().fg(Color::Yellow);
+        let plain = Style::default();
+        match event["kind"].as_str().unwrap_or_default() {
+            "anomaly" => {
+                self.threshold = body["threshold"].as_f64();
+                let line = format!(
+                    "{} anomaly   pid {} score {:.3} > {:.3} {}",
+                    time,
+                    pid,
+                    body["score"].as_f64().unwrap_or_default(),
+                    body["threshold"].as_f64().unwrap_or_default(),
+                    text(&body["exe"])
+                );
+                push(&mut self.alerts, red, line);
+            }
+            "response" => {
+                let style = if body["succeeded"].as_bool() == Some(true) { Style::default().fg(Color::Green) } else { yellow };
+                push(&mut self.alerts, style, format!("{} response  {} pid {}: {}", time, text(&body["action"]), pid, text(&body["outcome"])));
+            }
+            "integrity" => {
+                let style = if body["critical"].as_bool() == Some(true) { red } else { yellow };
+                push(&mut self.alerts, style, format!("{} integrity {} {} {}", time, text(&body["source"]), text(&body["finding"]), text(&body["path"])));
+            }
+            "intel" => {
+                let line = format!("{} intel     pid {} {} {} ({})", time, pid, text(&body["observed"]), text(&body["matched"]), text(&body["feed"]));
+                push(&mut self.alerts, red, line);
+            }
+            "drift" => {
+                let drifted = body["drifted"].as_array().map_or(0, Vec::len);
+                push(&mut self.alerts, yellow, format!("{} drift     model v{} {} features drifted", time, text(&body["model_version"]), drifted));
+            }
+            "token" => match body["event"].as_str() {
+                Some("revoked") => push(&mut self.alerts, yellow, format!("{} token     pid {} revoked", time, pid)),
+                Some("budget_exceeded") => push(&mut self.alerts, yellow, format!("{} token     pid {} over budget: {}", time, pid, text(&body["breach"]))),
+                _ => {}
+            },
+            "layout" => {
+                let regions: Vec<String> = body["regions"].as_array().into_iter().flatten().map(text).collect();
+                let line = format!("{} pid {} {} [{}] #{}", time, pid, text(&body["trigger"]).to_lowercase(), regions.join(","), text(&body["regeneration_count"]));
+                push(&mut self.layouts, plain, line);
+                self.layout_times.push_front(at);
+            }
+            "snapshot" => {
+                let id = text(&body["snapshot_id"]);
+                match body["event"].as_str() {
+                    Some("taken") => push(&mut self.snapshot_events, plain, format!("{} taken {}", time, id)),
+                    Some("restored") => {
+                        let restored = body["pids"].as_array().map_or(0, Vec::len);
+                        push(&mut self.snapshot_events, yellow, format!("{} restored {} ({} processes)", time, id, restored));
+                    }
+                    Some("verified") if body["valid"].as_bool() == Some(true) => push(&mut self.snapshot_events, plain, format!("{} verified {}", time, id)),
+                    Some("verified") => push(&mut self.snapshot_events, red, format!("{} INVALID {}", time, id)),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard, socket: &Path) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Percentage(45), Constraint::Min(8), Constraint::Length(1)])
+        .split(frame.size());
+    let top = Layout::default().direction(Direction::Horizontal).constraints([Constraint::Percentage(40), Constraint::Percentage(60)]).split(rows[1]);
+    let bottom = Layout::default().direction(Direction::Horizontal).constraints([Constraint::Percentage(60), Constraint::Percentage(40)]).split(rows[2]);
+    let side = Layout::default().direction(Direction::Vertical).constraints([Constraint::Percentage(50), Constraint::Percentage(50)]).split(bottom[1]);
+
+    frame.render_widget(header(dashboard, socket), rows[0]);
+    if dashboard.rates.is_empty() {
+        let telemetry = dashboard.telemetry.as_deref().unwrap_or("unknown");
+        let note = Paragraph::new(format!("Per-syscall counts need the eBPF monitor (telemetry: {})", telemetry)).block(panel("Syscall rates"));
+        frame.render_widget(note, top[0]);
+    } else {
+        frame.render_widget(syscall_table(dashboard), top[0]);
+    }
+    frame.render_widget(suspicious_table(dashboard), top[1]);
+    frame.render_widget(lines("Recent alerts", None, &dashboard.alerts), bottom[0]);
+    frame.render_widget(snapshot_list(dashboard), side[0]);
+    let randomizer = format!("{} layout changes in the last minute", dashboard.layout_times.len());
+    frame.render_widget(lines("Randomizer", Some(randomizer), &dashboard.layouts), side[1]);
+    frame.render_widget(footer(dashboard), rows[3]);
+}
+
+fn panel(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD)))
+}
+
+fn header<'a>(dashboard: &'a Dashboard, socket: &'a Path) -> Paragraph<'a> {
+    let running = dashboard.health.iter().filter(|(_, state, _)| state == "running").count();
+    let mut spans = vec![
+        Span::styled("qkstop ", Style::default().add_modifier(Modifier::BOLD)),
+        Span::raw(socket.display().to_string()),
+        Span::raw(format!("  telemetry: {}", dashboard.telemetry.as_deref().unwrap_or("-"))),
+        Span::raw(format!("  updated: {} UTC", dashboard.updated.map_or("-".to_string(), clock))),
+        Span::raw(format!("  {}/{} subsystems running", running, dashboard.health.len())),
+    ];
+    for (name, state, reason) in dashboard.health.iter().filter(|(_, state, _)| state != "running") {
+        let color = if state == "failed" { Color::Red } else { Color::Yellow };
+        let detail = reason.as_deref().map_or(String::new(), |reason| format!(": {}", reason));
+        spans.push(Span::styled(format!("  {} {}{}", name, state, detail), Style::default().fg(color)));
+    }
+    Paragraph::new(Line::from(spans))
+}
+
+fn syscall_table(dashboard: &Dashboard) -> Table<'_> {
+    let rows = dashboard.rates.iter().map(|rate| {
+        Row::new(vec![
+            Cell::from(rate.syscall.to_string()),
+            Cell::from(format!("{:.1}", rate.per_sec)),
+            Cell::from(rate.avg_duration_ns.to_string()),
+            Cell::from(format!("{:.1}%", rate.error_rate * 100.0)),
+        ])
+    });
+    let widths = [Constraint::Length(8), Constraint::Length(10), Constraint::Length(10), Constraint::Length(8)];
+    Table::new(rows, widths)
+        .header(Row::new(vec!["syscall", "/s", "avg ns", "errors"]).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(panel("Syscall rates"))
+}
+
+fn suspicious_table(dashboard: &Dashboard) -> Table<'_> {
+    let rows = dashboard.suspicious.iter().map(|row| {
+        let score = row["score"].as_f64().unwrap_or_default();
+        let style = match dashboard.threshold {
+            Some(threshold) if score > threshold => Style::default().fg(Color::Red),
+            _ => Style::default(),
+        };
+        Row::new(vec![Cell::from(text(&row["pid"])), Cell::from(format!("{:.3}", score)), Cell::from(text(&row["mode"])), Cell::from(text(&row["exe"]))]).style(style)
+    });
+    let widths = [Constraint::Length(8), Constraint::Length(8), Constraint::Length(10), Constraint::Min(10)];
+    Table::new(rows, widths)
+        .header(Row::new(vec!["pid", "score", "mode", "exe"]).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(panel("Most suspicious processes"))
+}
+
+fn lines<'a>(title: &'a str, summary: Option<String>, lines: &'a VecDeque<(Style, String)>) -> List<'a> {
+    let summary = summary.map(|summary| ListItem::new(Span::styled(summary, Style::default().add_modifier(Modifier::ITALIC))));
+    let items: Vec<ListItem> = summary.into_iter().chain(lines.iter().map(|(style, line)| ListItem::new(Span::styled(line.as_str(), *style)))).collect();
+    List::new(items).block(panel(title))
+}
+
+fn snapshot_list(dashboard: &Dashboard) -> List<'_> {
+    let now = now_secs();
+    let mut items = vec![ListItem::new(Span::styled(format!("{} snapshots", dashboard.snapshots.len()), Style::default().add_modifier(Modifier::ITALIC)))];
+    let mut newest: Vec<&Value> = dashboard.snapshots.iter().collect();
+    newest.sort_by_key(|snapshot| std::cmp::Reverse(snapshot["timestamp"].as_u64().unwrap_or_default()));
+    for snapshot in newest.into_iter().take(SNAPSHOTS_SHOWN) {
+        let age = now.saturating_sub(snapshot["timestamp"].as_u64().unwrap_or(now));
+        let tag = snapshot["tag"].as_str().map_or(String::new(), |tag| format!(" [{}]", tag));
+        items.push(ListItem::new(format!("{}{} {} processes, {}m ago", text(&snapshot["snapshot_id"]), tag, text(&snapshot["processes"]), age / 60)));
+    }
+    items.extend(dashboard.snapshot_events.iter().map(|(style, line)| ListItem::new(Span::styled(line.as_str(), *style))));
+    List::new(items).block(panel("Snapshots"))
+}
+
+fn footer(dashboard: &Dashboard) -> Paragraph<'_> {
+    let mut spans = vec![Span::raw("q quit  p pause")];
+    if dashboard.paused {
+        spans.push(Span::styled("  PAUSED", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)));
+    }
+    if let Some(error) = &dashboard.error {
+        spans.push(Span::styled(format!("  {}", error), Style::default().fg(Color::Red)));
+    }
+    Paragraph::new(Line::from(spans))
+}
+
+// Raw mode and the alternate screen, undone however qkstop exits
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        if let Err(e) = execute!(stdout, EnterAlternateScreen) {
+            let _ = disable_raw_mode();
+            return Err(e);
+        }
+        let terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        Ok(Self { terminal })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+fn run(screen: &mut Screen, runtime: &tokio::runtime::Runtime, cli: &Cli) -> io::Result<()> {
+    let interval = Duration::from_millis(cli.interval_ms.max(MIN_INTERVAL_MS));
+    let mut dashboard = Dashboard::default();
+    let mut client = None;
+    let mut next = Instant::now();
+    loop {
+        if Instant::now() >= next {
+            if !dashboard.paused {
+                runtime.block_on(dashboard.refresh(&mut client, &cli.socket));
+            }
+            next = Instant::now() + interval;
+        }
+        screen.terminal.draw(|frame| draw(frame, &dashboard, &cli.socket))?;
+        if !event::poll(next.saturating_duration_since(Instant::now()))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Char('p') => dashboard.paused = !dashboard.paused,
+            _ => {}
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("qkstop: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut screen = match Screen::enter() {
+        Ok(screen) => screen,
+        Err(e) => {
+            eprintln!("qkstop: terminal: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let result = run(&mut screen, &runtime, &cli);
+    // Restore the terminal before anything is printed
+    drop(screen);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("qkstop: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,362 @@
+// src/capability_metering.rs
+// Token holders' memory against their MemoryAllocation grant; enforced
+// holders get their own cgroup with memory.max set to the grant.
+use crate::crypto_identifiers::{Capability, ProcessToken};
+use crate::privsep;
+use dashmap::DashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+pub struct CapabilityMeter {
+    budgets: Arc<DashMap<u32, MeteredBudget>>,
+    violations: mpsc::UnboundedSender<MeteringViolation>,
+    cgroup_root: PathBuf,
+    // Parent of the per-holder cgroups, relative to the root; None only meters
+    enforce_under: Option<String>,
+    // The parent has its memory controller enabled on first use
+    prepared: Arc<Mutex<bool>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeteredBudget {
+    pub pid: u32,
+    pub token_signature: Vec<u8>,
+    pub memory_limit: Option<u64>,
+    pub memory_used: u64,
+    pub peak_memory: u64,
+    pub violated: bool,
+    // The dedicated cgroup with memory.max, and where the process came from
+    pub cgroup: Option<PathBuf>,
+    pub original_cgroup: Option<String>,
+    // memory.events counters last seen
+    pub limit_hits: u64,
+    pub oom_kills: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MeteringViolation {
+    pub pid: u32,
+    pub capability: Capability,
+    pub used: u64,
+    pub limit: u64,
+    pub source: UsageSource,
+    pub detected_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub enum UsageSource {
+    // memory.current of a cgroup holding only the holder
+    Cgroup,
+    // The holder's own RSS, when it shares its cgroup
+    Rss,
+    // The enforced limit was hit; `used` is the count of new hits
+    LimitHit,
+    // The kernel OOM-killed in the holder's cgroup
+    OomKill,
+}
+
+impl UsageSource {
+    // The breach as TokenEvent::BudgetExceeded names it
+    pub fn breach(self) -> &'static str {
+        match self {
+            UsageSource::Cgroup | UsageSource::Rss => "over_budget",
+            UsageSource::LimitHit => "limit_hit",
+            UsageSource::OomKill => "oom_kill",
+        }
+    }
+}
+
+impl CapabilityMeter {
+    pub fn new(cgroup_root: &str) -> (Self, mpsc::UnboundedReceiver<MeteringViolation>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let meter = Self {
+            budgets: Arc::new(DashMap::new()),
+            violations: tx,
+            cgroup_root: PathBuf::from(cgroup_root),
+            enforce_under: None,
+            prepared: Arc::new(Mutex::new(false)),
+        };
+
+        (meter, rx)
+    }
+
+    // Enforce budgets in children of `parent`, e.g. "qks.tokens"
+    pub fn with_enforcement(mut self, parent: &str) -> Self {
+        self.enforce_under = Some(parent.trim_matches('/').to_string());
+        self
+    }
+
+    pub fn enroll(&self, token: &ProcessToken) {
+        // Several MemoryAllocation grants collapse to the tightest one
+        let memory_limit = token.capabilities.iter()
+            .filter_map(|cap| match cap {
+                Capability::MemoryAllocation(max) => Some(*max),
+                _ => None,
+            })
+            .min();
+
+        let previous = self.budgets.remove(&token.pid).map(|(_, budget)| budget);
+        let (cgroup, original_cgroup, limit_hits, oom_kills) = match (memory_limit, previous) {
+            // A reissued token keeps the cgroup, with the new limit
+            (Some(limit), Some(MeteredBudget { cgroup: Some(cgroup), original_cgroup, limit_hits, oom_kills, .. })) => {
+                if let Err(e) = write(&cgroup.join("memory.max"), &limit.to_string()) {
+                    tracing::warn!(pid = token.pid, "Memory budget of PID {} not updated: {}", token.pid, e);
+                }
+                (Some(cgroup), original_cgroup, limit_hits, oom_kills)
+            }
+            (limit, previous) => {
+                if let Some(previous) = previous {
+                    Self::release(&self.cgroup_root, &previous);
+                }
+                match (limit, &self.enforce_under) {
+                    (Some(limit), Some(parent)) => match self.confine(parent, token.pid, limit) {
+                        Ok((cgroup, original)) => (Some(cgroup), Some(original), 0, 0),
+                        Err(e) => {
+                            tracing::warn!(pid = token.pid, "Memory budget of PID {} only metered, not enforced: {}", token.pid, e);
+                            (None, None, 0, 0)
+                        }
+                    },
+                    _ => (None, None, 0, 0),
+                }
+            }
+        };
+
+        self.budgets.insert(token.pid, MeteredBudget {
+            pid: token.pid,
+            token_signature: token.signature.clone(),
+            memory_limit,
+            memory_used: 0,
+            peak_memory: 0,
+            violated: false,
+            cgroup,
+            original_cgroup,
+            limit_hits,
+            oom_kills,
+        });
+    }
+
+    pub fn unenroll(&self, pid: u32) -> Option<MeteredBudget> {
+        let (_, budget) = self.budgets.remove(&pid)?;
+        Self::release(&self.cgroup_root, &budget);
+        Some(budget)
+    }
+
+    // Moves the process into a fresh child capped at `limit` bytes
+    fn confine(&self, parent: &str, pid: u32, limit: u64) -> Result<(PathBuf, String), std::io::Error> {
+        self.prepare(parent)?;
+        let original = Self::cgroup_dir_for_pid(&self.cgroup_root, pid)?;
+        let original = format!("/{}", original.strip_prefix(&self.cgroup_root).unwrap_or(&original).display());
+        let dir = self.cgroup_root.join(parent).join(format!("pid-{}", pid));
+        create_dir(&dir)?;
+        write(&dir.join("memory.max"), &limit.to_string())?;
+        // Swap would otherwise let it keep twice its budget
+        match write(&dir.join("memory.swap.max"), "0") {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        if let Err(e) = write(&dir.join("cgroup.procs"), &pid.to_string()) {
+            let _ = privsep::cgroup_remove(&dir);
+            return Err(e);
+        }
+        tracing::info!(pid, "PID {} limited to {} bytes in {}", pid, limit, dir.display());
+        Ok((dir, original))
+    }
+
+    // Back where it came from if still alive, then the child goes
+    fn release(cgroup_root: &Path, budget: &MeteredBudget) {
+        let (Some(cgroup), Some(original)) = (&budget.cgroup, &budget.original_cgroup) else {
+            return;
+        };
+        if Path::new(&format!("/proc/{}", budget.pid)).exists() {
+            let original = cgroup_root.join(original.trim_start_matches('/'));
+            if let Err(e) = write(&original.join("cgroup.procs"), &budget.pid.to_string()) {
+                tracing::warn!(pid = budget.pid, "PID {} left in {}: {}", budget.pid, cgroup.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = privsep::cgroup_remove(cgroup) {
+            tracing::warn!(pid = budget.pid, "Budget cgroup {} not removed: {}", cgroup.display(), e);
+        }
+    }
+
+    fn prepare(&self, parent: &str) -> Result<(), std::io::Error> {
+        let mut prepared = self.prepared.lock().unwrap();
+        if *prepared {
+            return Ok(());
+        }
+        let mut level = self.cgroup_root.clone();
+        write(&level.join("cgroup.subtree_control"), "+memory")?;
+        for part in parent.split('/') {
+            level.push(part);
+            create_dir(&level)?;
+            write(&level.join("cgroup.subtree_control"), "+memory")?;
+        }
+        *prepared = true;
+        Ok(())
+    }
+
+    pub fn budget(&self, pid: u32) -> Option<MeteredBudget> {
+        self.budgets.get(&pid).map(|b| b.clone())
+    }
+
+    // A shared cgroup's memory.current belongs to every process in it, so
+    // it only counts when the holder is alone there
+    fn read_usage(cgroup_root: &Path, pid: u32) -> Result<(u64, UsageSource), std::io::Error> {
+        let cgroup_dir = Self::cgroup_dir_for_pid(cgroup_root, pid)?;
+        let procs = std::fs::read_to_string(cgroup_dir.join("cgroup.procs"))?;
+        let invalid = |e: std::num::ParseIntError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        if procs.split_whitespace().eq([pid.to_string().as_str()]) {
+            let current = std::fs::read_to_string(cgroup_dir.join("memory.current"))?;
+            return Ok((current.trim().parse::<u64>().map_err(invalid)?, UsageSource::Cgroup));
+        }
+
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+        let kb = status.lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.trim().strip_suffix("kB"))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("PID {} has no VmRSS", pid)))?;
+        Ok((kb.trim().parse::<u64>().map_err(invalid)? * 1024, UsageSource::Rss))
+    }
+
+    pub fn start_metering(&self, interval_ms: u64) -> tokio::task::JoinHandle<()> {
+        let budgets = self.budgets.clone();
+        let violations = self.violations.clone();
+        let cgroup_root = self.cgroup_root.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let pids: Vec<u32> = budgets.iter().map(|b| *b.key()).collect();
+
+                for pid in pids {
+                    if !Path::new(&format!("/proc/{}", pid)).exists() {
+                        if let Some((_, budget)) = budgets.remove(&pid) {
+                            Self::release(&cgroup_root, &budget);
+                        }
+                        continue;
+                    }
+                    if let Some(mut budget) = budgets.get_mut(&pid) {
+                        Self::check_events(&violations, &mut budget);
+                    }
+                    // Process may have left its cgroup
+                    let (used, source) = match Self::read_usage(&cgroup_root, pid) {
+                        Ok(usage) => usage,
+                        Err(_) => continue,
+                    };
+
+                    if let Some(mut budget) = budgets.get_mut(&pid) {
+                        Self::account(&mut budget, used);
+                        Self::check_budget(&violations, &mut budget, source);
+                    }
+                }
+
+                tokio::time::sleep(tokio::time::Duration::from_millis(interval_ms)).await;
+            }
+        })
+    }
+
+    fn account(budget: &mut MeteredBudget, used: u64) {
+        budget.memory_used = used;
+        budget.peak_memory = budget.peak_memory.max(used);
+    }
+
+    fn check_budget(
+        violations: &mpsc::UnboundedSender<MeteringViolation>,
+        budget: &mut MeteredBudget,
+        source: UsageSource,
+    ) {
+        let limit = match budget.memory_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        if budget.memory_used <= limit {
+            budget.violated = false;
+            return;
+        }
+
+        // Only report the transition into violation, not every sample
+        if budget.violated {
+            return;
+        }
+        budget.violated = true;
+
+        tracing::warn!(
+            pid = budget.pid,
+            "PID {} exceeded memory budget: {} > {} bytes",
+            budget.pid, budget.memory_used, limit
+        );
+
+        let _ = violations.send(MeteringViolation {
+            pid: budget.pid,
+            capability: Capability::MemoryAllocation(limit),
+            used: budget.memory_used,
+            limit,
+            source,
+            detected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        });
+    }
+
+    // New `max` and `oom_kill` counts in the holder's own cgroup
+    fn check_events(violations: &mpsc::UnboundedSender<MeteringViolation>, budget: &mut MeteredBudget) {
+        let (Some(cgroup), Some(limit)) = (&budget.cgroup, budget.memory_limit) else {
+            return;
+        };
+        let Ok(events) = std::fs::read_to_string(cgroup.join("memory.events")) else {
+            return;
+        };
+        let counter = |name: &str| {
+            events.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        let (hits, kills) = (counter("max"), counter("oom_kill"));
+        let detected_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        for (source, seen, now) in [(UsageSource::LimitHit, budget.limit_hits, hits), (UsageSource::OomKill, budget.oom_kills, kills)] {
+            if now > seen {
+                tracing::warn!(pid = budget.pid, "PID {} hit its {} byte budget ({:?}, {} new)", budget.pid, limit, source, now - seen);
+                let _ = violations.send(MeteringViolation {
+                    pid: budget.pid,
+                    capability: Capability::MemoryAllocation(limit),
+                    used: now - seen,
+                    limit,
+                    source,
+                    detected_at,
+                });
+            }
+        }
+        budget.limit_hits = hits;
+        budget.oom_kills = kills;
+    }
+
+    fn cgroup_dir_for_pid(cgroup_root: &Path, pid: u32) -> Result<PathBuf, std::io::Error> {
+        // cgroup v2 unified hierarchy: single "0::/path" line
+        let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+
+        let relative = cgroup.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("PID {} has no cgroup v2 membership", pid),
+            ))?;
+
+        Ok(cgroup_root.join(relative.trim_start_matches('/')))
+    }
+}
+
+fn write(path: &Path, value: &str) -> Result<(), std::io::Error> {
+    privsep::cgroup_write(path, value).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn create_dir(path: &Path) -> Result<(), std::io::Error> {
+    match privsep::cgroup_create(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,1732 @@
+// src/config.rs
+// Typed configuration for every subsystem, read from /etc/qks/config.toml.
+// Every section and field has a default, so an empty file is a valid
+// config. Validation collects every problem with its dotted field path
+// rather than stopping at the first. On SIGHUP the file is re-read; changes
+// that are safe to apply live are published, the rest are reported as
+// needing a restart and keep their running values.
+use crate::container_events::ContainerRuntime;
+use crate::control::AccessLevel;
+use crate::crypto_identifiers::Capability;
+use crate::drift_monitor::DriftThresholds;
+use crate::events::EventKind;
+use crate::feature_pipeline::PipelineConfig;
+use crate::inference_backend::{Device, InferenceOptions, Precision};
+use crate::kafka_sink::Compression;
+use crate::kubernetes::{MonitoringLevel, ResponseMode};
+use crate::ml_detector::FeatureSet;
+use crate::intel::{FeedFormat, IntelFeed};
+use crate::notify::{EmailNotifier, SlackNotifier, Template, WebhookNotifier};
+use crate::policy::{PolicyRule, PolicyTransform};
+use crate::randomization_policy::RandomizationProfile;
+use crate::response::ResponseAction;
+use crate::seccomp::DenyAction;
+use crate::siem_format::EventFormat;
+use crate::syslog_sink::{Facility, Severity, SyslogAddress, SyslogRoute};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/qks/config.toml";
+
+// Probe groups the eBPF monitor can attach
+pub const PROBE_GROUPS: &[&str] = &["syscalls", "mprotect", "network"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("{}: {source}", .path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QksConfig {
+    pub daemon: DaemonSection,
+    pub detector: DetectorSection,
+    pub inference: InferenceSection,
+    pub pipeline: PipelineSection,
+    pub monitor: MonitorSection,
+    pub randomizer: RandomizerSection,
+    pub snapshots: SnapshotSection,
+    pub tokens: TokenSection,
+    pub grpc: GrpcSection,
+    pub rest: RestSection,
+    pub dbus: DbusSection,
+    pub metrics: MetricsSection,
+    pub policy: PolicySection,
+    pub response: ResponseSection,
+    pub quarantine: QuarantineSection,
+    pub notify: NotifySection,
+    pub forward: ForwardSection,
+    pub integrity: IntegritySection,
+    pub tpm: TpmSection,
+    pub profiles: ProfilesSection,
+    pub containers: ContainersSection,
+    pub kubernetes: KubernetesSection,
+    pub watchdog: WatchdogSection,
+    pub fleet: FleetSection,
+    pub intel: IntelSection,
+    pub privsep: PrivsepSection,
+    pub sandbox: SandboxSection,
+    pub logging: LoggingSection,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonSection {
+    pub state_dir: PathBuf,
+    pub control_socket: PathBuf,
+    // Members may use the control socket at operator level; root is admin
+    pub operators_group: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectorSection {
+    pub model_path: Option<String>,
+    pub trusted_keys: Vec<PathBuf>,
+    pub insecure_models: bool,
+    pub feature_set: FeatureSet,
+    pub model_watch_secs: u64,
+    pub selftest_interval_secs: u64,
+    // Per-executable profiles (behavior_profiles.rs) that judge a process
+    // against its own history once warm; kept in the state directory and
+    // saved every `profile_save_secs`
+    pub behavior_profiles: bool,
+    pub profile_save_secs: u64,
+    pub drift: DriftSection,
+}
+
+// Model inputs compared against the training distribution of models that
+// ship a drift reference (drift_monitor.rs); ModelDrift goes on the bus
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriftSection {
+    pub enabled: bool,
+    pub psi: f64,
+    pub kl: f64,
+    pub window: usize,
+    pub request_retraining: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InferenceSection {
+    pub device: Device,
+    pub precision: Precision,
+    pub cpu_threads: Option<usize>,
+    pub latency_budget_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineSection {
+    pub cadence_secs: u64,
+    pub min_events: usize,
+    pub max_sequence: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorSection {
+    pub probe_groups: Vec<String>,
+    // Without eBPF, score fork/exec/exit from the netlink proc connector
+    // instead (proc_connector.rs). Those windows fill slowly: only processes
+    // forking or exec'ing at least pipeline.min_events times a tick are
+    // scored.
+    pub proc_connector: bool,
+    // Without either, infer process, descriptor and mapping changes from
+    // /proc every poll_interval_ms (proc_poll.rs)
+    pub proc_poll: bool,
+    pub poll_interval_ms: u64,
+    // Trace the syscalls of one process in N, chosen in the kernel; 1
+    // traces every process
+    pub syscall_sample_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RandomizerSection {
+    pub max_cpu_percent: f32,
+    // Generate layouts without remapping live processes. Heap and stack
+    // moves apply only to processes with CAP_SYS_RESOURCE (PR_SET_MM).
+    pub plan_only: bool,
+    // Where cooperating allocators ask for arena bases (arena_broker.rs);
+    // unset leaves the broker off
+    pub arena_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotSection {
+    // Defaults to <state_dir>/snapshots
+    pub dir: Option<PathBuf>,
+    pub retention_count: usize,
+}
+
+// With `enforce_memory`, a process whose token grants MemoryAllocation is
+// moved into its own child of `memory_cgroup` (relative to /sys/fs/cgroup)
+// with memory.max set to the grant; usage and breaches are read every
+// `meter_interval_ms` (capability_metering.rs). `clock_skew_secs` is how
+// far an issuer's clock may disagree with ours before a token counts as
+// expired or not yet valid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenSection {
+    pub lifetime_secs: u64,
+    pub status_validity_secs: u64,
+    pub clock_skew_secs: u64,
+    pub enforce_memory: bool,
+    pub memory_cgroup: String,
+    pub meter_interval_ms: u64,
+}
+
+// The remote API is off unless `listen` is set. It always uses TLS with
+// client certificates signed by `client_ca`, loopback included; `clients`
+// gives each certificate, by SHA-256 (hex) of its DER, the access level of
+// a control socket caller (read, operate, admin). Others are refused.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcSection {
+    pub listen: Option<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub clients: BTreeMap<String, AccessLevel>,
+}
+
+// The HTTP/JSON API is off unless `listen` is set. Every request needs a
+// bearer token whose SHA-256 (hex) is listed in `tokens_file` with the
+// scopes the route needs (read, snapshot, operate, admin); anything
+// but loopback must also use TLS.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestSection {
+    pub listen: Option<SocketAddr>,
+    pub tokens_file: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+// Registers org.qks.Daemon1 on the system bus. Needs the bus policy and
+// polkit actions from etc/dbus-1 and etc/polkit-1 installed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusSection {
+    pub enabled: bool,
+}
+
+// Prometheus scrapes /metrics on `listen`; off unless set. There is no
+// authentication, so keep it on loopback or a management network.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub listen: Option<SocketAddr>,
+}
+
+// Response rules evaluated against the event bus, and the scripted
+// transforms that derive fields for them; see policy.rs for the condition
+// language and scripts. Applied live on reload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySection {
+    pub rules: Vec<PolicyRule>,
+    pub transforms: Vec<PolicyTransform>,
+}
+
+// How responses are carried out. `cooldowns` overrides `cooldown_secs` per
+// action name; an action is not repeated on the same PID within it.
+// Processes running a `protected_exes` binary are never acted on. `stop`
+// thaws on its own after `freeze_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseSection {
+    pub dry_run: bool,
+    pub cooldown_secs: u64,
+    pub cooldowns: BTreeMap<String, u64>,
+    pub freeze_secs: u64,
+    pub protected_exes: Vec<PathBuf>,
+    // Defaults to response-audit.jsonl in daemon.state_dir
+    pub audit_log: Option<PathBuf>,
+    pub audit: AuditSection,
+}
+
+// The audit log rotates at `rotate_size_mb` or when its first record is
+// `rotate_secs` old; sealed segments are zstd-compressed at `zstd_level`
+// and deleted after `retention_days` or, oldest first, beyond
+// `retention_max_mb` in total (audit_log.rs)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditSection {
+    pub rotate_size_mb: u64,
+    pub rotate_secs: u64,
+    pub compress: bool,
+    pub zstd_level: i32,
+    pub retention_days: u64,
+    pub retention_max_mb: u64,
+}
+
+impl Default for AuditSection {
+    fn default() -> Self {
+        Self { rotate_size_mb: 16, rotate_secs: 86_400, compress: true, zstd_level: 3, retention_days: 365, retention_max_mb: 1024 }
+    }
+}
+
+impl Default for ResponseSection {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            cooldown_secs: 300,
+            cooldowns: BTreeMap::new(),
+            freeze_secs: 3600,
+            protected_exes: [
+                "/usr/lib/systemd/systemd",
+                "/usr/lib/systemd/systemd-journald",
+                "/usr/lib/systemd/systemd-logind",
+                "/usr/bin/dbus-daemon",
+                "/usr/sbin/qksd",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+            audit_log: None,
+            audit: AuditSection::default(),
+        }
+    }
+}
+
+// The quarantine cgroup, relative to /sys/fs/cgroup, and the caps each
+// quarantined process gets in its own child of it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineSection {
+    pub cgroup: String,
+    pub cpu_percent: u32,
+    pub memory_max_mb: u64,
+    pub pids_max: u32,
+}
+
+impl Default for QuarantineSection {
+    fn default() -> Self {
+        Self { cgroup: "qks.quarantine".to_string(), cpu_percent: 10, memory_max_mb: 256, pids_max: 64 }
+    }
+}
+
+// Where alerts go: any number of [[notify.webhook]], [[notify.slack]] and
+// [[notify.email]] sinks. Anomalies scoring `critical_score` or more are
+// critical rather than warnings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifySection {
+    pub critical_score: f32,
+    pub dedup: DedupSection,
+    pub webhook: Vec<WebhookNotifier>,
+    pub slack: Vec<SlackNotifier>,
+    pub email: Vec<EmailNotifier>,
+}
+
+impl Default for NotifySection {
+    fn default() -> Self {
+        Self { critical_score: 0.95, dedup: DedupSection::default(), webhook: Vec::new(), slack: Vec::new(), email: Vec::new() }
+    }
+}
+
+// An alert repeats at most once per `suppress_secs`, is escalated once
+// after recurring for `escalate_after_secs`, and all alerts together are
+// held to `rate_per_min` with bursts of `burst`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupSection {
+    pub suppress_secs: u64,
+    pub escalate_after_secs: u64,
+    pub rate_per_min: u32,
+    pub burst: u32,
+}
+
+impl Default for DedupSection {
+    fn default() -> Self {
+        Self { suppress_secs: 300, escalate_after_secs: 900, rate_per_min: 30, burst: 10 }
+    }
+}
+
+// Event streams to SIEM collectors (event_forward.rs)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardSection {
+    pub splunk: SplunkSection,
+    pub elasticsearch: ElasticsearchSection,
+    pub kafka: KafkaSection,
+}
+
+// Batches go out at `batch_size` events or every `flush_secs`. Failed ones
+// wait on disk, up to `buffer_max_mb`, and are retried with backoff
+// doubling up to `max_backoff_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSection {
+    pub batch_size: usize,
+    pub flush_secs: u64,
+    pub max_backoff_secs: u64,
+    pub buffer_max_mb: u64,
+}
+
+impl Default for BatchSection {
+    fn default() -> Self {
+        Self { batch_size: 100, flush_secs: 5, max_backoff_secs: 300, buffer_max_mb: 64 }
+    }
+}
+
+// `url` is the full endpoint, e.g.
+// https://splunk.example.org:8088/services/collector/event. The HEC token
+// is read from `token_file`. `sourcetype` defaults by format (qks:event,
+// qks:ecs, qks:cef, qks:leef); `index` to the token's default index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SplunkSection {
+    pub enabled: bool,
+    pub url: String,
+    pub token_file: Option<PathBuf>,
+    pub index: Option<String>,
+    pub source: String,
+    pub sourcetype: Option<String>,
+    pub format: EventFormat,
+    pub event_kinds: Vec<EventKind>,
+    pub verify_tls: bool,
+    pub batch: BatchSection,
+}
+
+impl Default for SplunkSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            token_file: None,
+            index: None,
+            source: "qksd".to_string(),
+            sourcetype: None,
+            format: EventFormat::Json,
+            event_kinds: forwarded_kinds(),
+            verify_tls: true,
+            batch: BatchSection::default(),
+        }
+    }
+}
+
+// `url` is the cluster's base URL. `index` is a template over the event's
+// time and kind (%Y, %m, %d, {kind}). Authenticates with an API key from
+// `api_key_file` or as `username` with the password in `password_file`.
+// Only the json and ecs formats make documents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ElasticsearchSection {
+    pub enabled: bool,
+    pub url: String,
+    pub index: String,
+    pub format: EventFormat,
+    pub username: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub api_key_file: Option<PathBuf>,
+    pub verify_tls: bool,
+    // Install the mappings as an index template before the first batch
+    pub install_template: bool,
+    pub template_name: String,
+    pub event_kinds: Vec<EventKind>,
+    pub batch: BatchSection,
+}
+
+impl Default for ElasticsearchSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "http://127.0.0.1:9200".to_string(),
+            index: "qks-events-%Y.%m.%d".to_string(),
+            format: EventFormat::Ecs,
+            username: None,
+            password_file: None,
+            api_key_file: None,
+            verify_tls: true,
+            install_template: true,
+            template_name: "qks-events".to_string(),
+            event_kinds: forwarded_kinds(),
+            batch: BatchSection::default(),
+        }
+    }
+}
+
+// `brokers` are host:port bootstrap servers. Events go to `topic` with
+// {kind} filled in, unless `topics` maps their kind to another. Needs the
+// `kafka` feature.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaSection {
+    pub enabled: bool,
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub topics: BTreeMap<String, String>,
+    pub format: EventFormat,
+    pub compression: Compression,
+    pub delivery_timeout_secs: u64,
+    // librdkafka settings, e.g. security.protocol = "sasl_ssl"
+    pub properties: BTreeMap<String, String>,
+    pub event_kinds: Vec<EventKind>,
+    pub batch: BatchSection,
+}
+
+impl Default for KafkaSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            brokers: Vec::new(),
+            topic: "qks.{kind}".to_string(),
+            topics: BTreeMap::new(),
+            format: EventFormat::Json,
+            compression: Compression::Zstd,
+            delivery_timeout_secs: 30,
+            properties: BTreeMap::new(),
+            event_kinds: forwarded_kinds(),
+            batch: BatchSection::default(),
+        }
+    }
+}
+
+// What event sinks carry unless told otherwise: syscalls and connects
+// would swamp any collector
+fn forwarded_kinds() -> Vec<EventKind> {
+    EventKind::ALL.iter().copied().filter(|&kind| !matches!(kind, EventKind::Syscall | EventKind::Network)).collect()
+}
+
+// Checks of the host's own integrity machinery: IMA, dm-verity, boot
+// posture, kernel modules and installed packages
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegritySection {
+    pub ima: ImaSection,
+    pub verity: VeritySection,
+    pub posture: PostureSection,
+    pub modules: ModulesSection,
+    pub packages: PackagesSection,
+}
+
+// The IMA measurement list under `securityfs` is read every
+// `interval_secs` and each newly measured file compared with `baseline`, a
+// sha256sum-style manifest. With `flag_unknown`, measured files under
+// `watch_paths` the baseline doesn't list are reported too.
+// `require_appraisal` reports IMA appraisal not enforcing or EVM not
+// initialized as critical.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImaSection {
+    pub enabled: bool,
+    pub securityfs: PathBuf,
+    pub baseline: Option<PathBuf>,
+    pub watch_paths: Vec<PathBuf>,
+    pub flag_unknown: bool,
+    pub require_appraisal: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for ImaSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            securityfs: PathBuf::from("/sys/kernel/security"),
+            baseline: None,
+            watch_paths: ["/usr/bin", "/usr/sbin", "/usr/lib", "/usr/libexec"].iter().map(PathBuf::from).collect(),
+            flag_unknown: true,
+            require_appraisal: false,
+            interval_secs: 10,
+        }
+    }
+}
+
+// Volumes that must be dm-verity protected, as mount points or
+// device-mapper names, checked at startup and every `interval_secs`
+// (dm_verity.rs). Snapshots record their state either way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VeritySection {
+    pub enabled: bool,
+    pub volumes: Vec<String>,
+    pub interval_secs: u64,
+}
+
+impl Default for VeritySection {
+    fn default() -> Self {
+        Self { enabled: false, volumes: vec!["/".to_string()], interval_secs: 300 }
+    }
+}
+
+// Secure Boot, lockdown and module signing, read every `interval_secs`
+// and compared with the last reading, kept across restarts
+// (host_posture.rs). Reading them needs nothing, so this is on by default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostureSection {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for PostureSection {
+    fn default() -> Self {
+        Self { enabled: true, interval_secs: 60 }
+    }
+}
+
+// Loaded kernel modules checked against their files, packages and
+// signatures every `interval_secs` and after each load the eBPF monitor
+// sees (kernel_modules.rs). Modules in `allowed`, by name as lsmod shows it,
+// may be out of tree or unpackaged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModulesSection {
+    pub enabled: bool,
+    pub allowed: Vec<String>,
+    pub interval_secs: u64,
+}
+
+impl Default for ModulesSection {
+    fn default() -> Self {
+        Self { enabled: true, allowed: Vec::new(), interval_secs: 600 }
+    }
+}
+
+// Installed files compared with dpkg's md5sums (package_sweep.rs): every
+// `tick_secs`, files are hashed until `batch_bytes` have been read, so a
+// full sweep is spread out; another starts `sweep_interval_secs` after one
+// finishes. `critical_paths` go first and a modification there is critical.
+// `conffiles` includes configuration files, which admins are expected to
+// edit, as warnings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackagesSection {
+    pub enabled: bool,
+    pub tick_secs: u64,
+    pub batch_bytes: u64,
+    pub sweep_interval_secs: u64,
+    pub critical_paths: Vec<PathBuf>,
+    pub skip_paths: Vec<PathBuf>,
+    pub conffiles: bool,
+}
+
+impl Default for PackagesSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tick_secs: 30,
+            batch_bytes: 32 << 20,
+            sweep_interval_secs: 86400,
+            critical_paths: [
+                "/usr/bin",
+                "/usr/sbin",
+                "/usr/lib/systemd",
+                "/usr/lib/openssh",
+                "/usr/lib/x86_64-linux-gnu/security",
+                "/usr/lib/x86_64-linux-gnu/ld-linux-x86-64.so.2",
+                "/usr/lib/x86_64-linux-gnu/libc.so.6",
+                "/etc/pam.d",
+                "/etc/sudoers",
+                "/etc/sudoers.d",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+            skip_paths: ["/usr/share/doc", "/usr/share/man", "/usr/share/info", "/usr/share/locale"].iter().map(PathBuf::from).collect(),
+            conffiles: true,
+        }
+    }
+}
+
+// AppArmor (and SELinux) drafts for `binaries` from what their processes
+// are seen doing every `sample_secs` (mac_profiles.rs). After
+// `learning_secs` a draft waits for review; approved AppArmor profiles are
+// written to `apparmor_dir` when enforced. A directory with more than
+// `glob_threshold` files seen becomes one dir/* rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfilesSection {
+    pub enabled: bool,
+    pub binaries: Vec<PathBuf>,
+    pub learning_secs: u64,
+    pub sample_secs: u64,
+    pub glob_threshold: usize,
+    pub apparmor_dir: PathBuf,
+}
+
+impl Default for ProfilesSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            binaries: Vec::new(),
+            learning_secs: 7 * 24 * 3600,
+            sample_secs: 60,
+            glob_threshold: 8,
+            apparmor_dir: PathBuf::from("/etc/apparmor.d"),
+        }
+    }
+}
+
+// Containers from containerd's event stream (container_events.rs), or with
+// `runtime = "docker"` from the Docker socket's (docker_events.rs). Tasks
+// starting in `namespaces` are attributed in every event about their
+// processes, given a token with `token_capabilities` when `issue_tokens`,
+// and re-randomized every `randomize_secs` (0 leaves them to the policy);
+// all of it is undone when they exit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContainersSection {
+    pub enabled: bool,
+    pub runtime: ContainerRuntime,
+    pub socket: PathBuf,
+    pub docker_socket: PathBuf,
+    pub namespaces: Vec<String>,
+    pub issue_tokens: bool,
+    pub token_capabilities: Vec<Capability>,
+    pub randomize_secs: u64,
+    pub randomize_jitter: f32,
+}
+
+impl Default for ContainersSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            runtime: ContainerRuntime::Containerd,
+            socket: PathBuf::from("/run/containerd/containerd.sock"),
+            docker_socket: PathBuf::from("/var/run/docker.sock"),
+            // Kubernetes, Docker and ctr's default
+            namespaces: vec!["k8s.io".to_string(), "moby".to_string(), "default".to_string()],
+            issue_tokens: false,
+            token_capabilities: Vec::new(),
+            randomize_secs: 0,
+            randomize_jitter: 0.2,
+        }
+    }
+}
+
+// DaemonSet mode (kubernetes.rs). Pods on this node are listed from the
+// kubelet at `kubelet_url` every `sync_secs`, authenticating with the
+// service account token in `token_file`. Their annotations pick each pod's
+// monitoring level, randomization profile and response mode; the fields
+// below apply to pods that set none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubernetesSection {
+    pub enabled: bool,
+    pub kubelet_url: String,
+    pub token_file: PathBuf,
+    pub verify_tls: bool,
+    pub sync_secs: u64,
+    pub monitoring: MonitoringLevel,
+    pub randomization: Option<RandomizationProfile>,
+    pub response: ResponseMode,
+}
+
+impl Default for KubernetesSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            kubelet_url: "https://127.0.0.1:10250".to_string(),
+            token_file: PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount/token"),
+            verify_tls: true,
+            sync_secs: 10,
+            monitoring: MonitoringLevel::Standard,
+            randomization: None,
+            response: ResponseMode::Enforce,
+        }
+    }
+}
+
+// The crash-loop watchdog (crash_watchdog.rs). Each systemd unit in
+// `services` is polled every `poll_secs`, and `crash_restarts` restarts or
+// failures within `crash_window_secs` make a crash loop. One that follows
+// a detection against the service's processes (an anomaly, an executed
+// kill, stop, quarantine or egress block, a critical integrity finding) by
+// less than `compromise_window_secs` is reported; with `rollback` the
+// service is also stopped and started again on a fresh layout, with the
+// randomization profile and token capabilities the newest snapshot from
+// before the detection that still verifies recorded for it, at most once
+// per `cooldown_secs`. So that there is such a snapshot, one tagged
+// "watchdog" is taken every `snapshot_secs` while no detection is open (0
+// leaves it to others). response.dry_run keeps it to reporting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogSection {
+    pub enabled: bool,
+    pub services: Vec<String>,
+    pub poll_secs: u64,
+    pub crash_restarts: u32,
+    pub crash_window_secs: u64,
+    pub compromise_window_secs: u64,
+    pub rollback: bool,
+    pub cooldown_secs: u64,
+    // How long the restarted service gets to report a main PID
+    pub start_timeout_secs: u64,
+    pub snapshot_secs: u64,
+}
+
+impl Default for WatchdogSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            services: Vec::new(),
+            poll_secs: 5,
+            crash_restarts: 3,
+            crash_window_secs: 300,
+            compromise_window_secs: 3600,
+            rollback: false,
+            cooldown_secs: 3600,
+            start_timeout_secs: 30,
+            snapshot_secs: 3600,
+        }
+    }
+}
+
+// Fleet mode (fleet.rs). A `controller` takes agents on `listen`, keeps the
+// events they send in fleet/events.jsonl under daemon.state_dir (rotated
+// at `events_max_mb`) and pushes to them the rules in `policy_file`
+// (rules and transforms as in [policy]), the signed model at `model_path`
+// and every token revocation in the fleet, checking both files every
+// `watch_secs`. An `agent` dials the `controller` URL and sends it
+// `event_kinds`, batched like a forwarding sink and spooled while the
+// controller can't be reached. Both ends use mutual TLS: `tls_cert` and
+// `tls_key` are this host's, `ca` signed the other end's. Fleet IDs derive
+// from each host's identity key, which fleet mode keeps in daemon.state_dir
+// across restarts; `agents` limits a controller to those IDs and
+// `controller_id` pins an agent to one controller. Token revocations from
+// other hosts apply only if they come from one of the `revokers` IDs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FleetRole {
+    #[default]
+    Off,
+    Agent,
+    Controller,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FleetSection {
+    pub role: FleetRole,
+    pub listen: Option<SocketAddr>,
+    pub controller: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub ca: Option<PathBuf>,
+    pub agents: Vec<String>,
+    pub controller_id: Option<String>,
+    pub revokers: Vec<String>,
+    pub policy_file: Option<PathBuf>,
+    pub model_path: Option<PathBuf>,
+    pub watch_secs: u64,
+    pub events_max_mb: u64,
+    pub event_kinds: Vec<EventKind>,
+    pub batch: BatchSection,
+}
+
+impl Default for FleetSection {
+    fn default() -> Self {
+        Self {
+            role: FleetRole::Off,
+            listen: None,
+            controller: None,
+            tls_cert: None,
+            tls_key: None,
+            ca: None,
+            agents: Vec::new(),
+            controller_id: None,
+            revokers: Vec::new(),
+            policy_file: None,
+            model_path: None,
+            watch_secs: 30,
+            events_max_mb: 256,
+            event_kinds: forwarded_kinds(),
+            batch: BatchSection::default(),
+        }
+    }
+}
+
+// Threat intelligence (intel.rs). Every [[intel.feeds]] is fetched each
+// `refresh_secs` and its indicators matched against outbound connections
+// (`match_network`), the executables processes start (`match_exec`; files
+// over `max_exec_mb` are not hashed) and the digests integrity findings
+// report (`match_integrity`). Domains are matched through the addresses
+// they resolve to at each refresh, up to `resolve_domains` of them (0
+// leaves domains unmatched). A match is reported once per process and indicator every `repeat_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntelSection {
+    pub enabled: bool,
+    pub feeds: Vec<IntelFeed>,
+    pub refresh_secs: u64,
+    pub match_network: bool,
+    pub match_exec: bool,
+    pub match_integrity: bool,
+    pub max_exec_mb: u64,
+    pub resolve_domains: usize,
+    pub repeat_secs: u64,
+}
+
+impl Default for IntelSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            feeds: Vec::new(),
+            refresh_secs: 3600,
+            match_network: true,
+            match_exec: true,
+            match_integrity: true,
+            max_exec_mb: 256,
+            resolve_domains: 1024,
+            repeat_secs: 600,
+        }
+    }
+}
+
+// Privilege separation (privsep.rs). When enabled, qksd starts as root,
+// connects to qks-helper on `helper_socket`, brings its subsystems up and
+// then drops to `user`; from there signals, cgroup changes, nftables, remaps
+// and /proc reads of other users' processes go through the helper. The
+// helper reads the same file, for the user it accepts, the cgroups it may
+// manage and the services the watchdog may restart. It remaps, and opens
+// the memory of, only processes running one of `remap_targets`; with none
+// listed the randomizer can plan but not apply under privsep.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivsepSection {
+    pub enabled: bool,
+    pub user: String,
+    pub helper_socket: PathBuf,
+    pub remap_targets: Vec<PathBuf>,
+}
+
+impl Default for PrivsepSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            user: "qks".to_string(),
+            helper_socket: PathBuf::from(crate::privsep::DEFAULT_HELPER_SOCKET),
+            remap_targets: Vec::new(),
+        }
+    }
+}
+
+// qksd's confinement of itself (self_sandbox.rs). Before any thread starts
+// it drops the capabilities it has no use for and sets no_new_privs; once
+// everything is running a seccomp filter goes on every thread, with
+// `seccomp_deny` for calls outside it. With `landlock`, the filesystem is
+// read-only beneath `read_paths` and writable beneath `write_paths` and the
+// daemon's own state, snapshot, audit and socket directories.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxSection {
+    pub enabled: bool,
+    pub seccomp: bool,
+    pub seccomp_deny: DenyAction,
+    pub landlock: bool,
+    pub read_paths: Vec<PathBuf>,
+    pub write_paths: Vec<PathBuf>,
+}
+
+impl Default for SandboxSection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seccomp: true,
+            seccomp_deny: DenyAction::Errno,
+            landlock: false,
+            // Executables and libraries of monitored processes live anywhere
+            read_paths: vec![PathBuf::from("/")],
+            write_paths: ["/proc", "/sys/fs/cgroup", "/sys/fs/bpf", "/sys/kernel/security", "/run", "/dev"].iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+// TPM 2.0 attestation (tpm.rs). Config loads and changes, model loads and
+// snapshot restores are extended into `pcr` through `tcti`. A `controller`
+// verifies peers' quotes signed by one of `trusted_aks`, which needs no
+// TPM of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TpmSection {
+    pub enabled: bool,
+    pub tcti: String,
+    pub pcr: u8,
+    pub controller: bool,
+    pub trusted_aks: Vec<PathBuf>,
+}
+
+impl Default for TpmSection {
+    fn default() -> Self {
+        Self { enabled: false, tcti: "device:/dev/tpmrm0".to_string(), pcr: 23, controller: false, trusted_aks: Vec::new() }
+    }
+}
+
+// Where qksd's own log records go. `auto` picks journald when systemd has
+// connected stderr to the journal. `level` is a tracing filter directive;
+// RUST_LOG overrides it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Auto,
+    Journald,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub target: LogTarget,
+    pub level: String,
+    pub syslog: SyslogSection,
+}
+
+// RFC 5424 copies of every record, in addition to `target`. `address` is
+// unix:PATH, udp:HOST:PORT or tcp:HOST:PORT. Each event type may send
+// `rate_per_sec` records a second on average, `burst` at once. Setting
+// `event_format` also forwards the bus events of `event_kinds` in that
+// format (syslog_sink::forward_events).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogSection {
+    pub enabled: bool,
+    pub address: String,
+    pub app_name: String,
+    pub facility: Facility,
+    pub rate_per_sec: u32,
+    pub burst: u32,
+    pub routes: Vec<SyslogRoute>,
+    pub event_format: Option<EventFormat>,
+    pub event_kinds: Vec<EventKind>,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        Self { target: LogTarget::Auto, level: "info".to_string(), syslog: SyslogSection::default() }
+    }
+}
+
+impl Default for SyslogSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "unix:/dev/log".to_string(),
+            app_name: "qksd".to_string(),
+            facility: Facility::Daemon,
+            rate_per_sec: 20,
+            burst: 200,
+            // Anomalies are security events; most rsyslog setups route
+            // authpriv to a restricted file
+            routes: vec![SyslogRoute {
+                target: "qks::anomaly".to_string(),
+                facility: Some(Facility::Authpriv),
+                severity: Some(Severity::Alert),
+            }],
+            event_format: None,
+            event_kinds: forwarded_kinds(),
+        }
+    }
+}
+
+impl Default for DaemonSection {
+    fn default() -> Self {
+        Self {
+            state_dir: PathBuf::from("/var/lib/qks"),
+            control_socket: PathBuf::from(crate::control::DEFAULT_SOCKET_PATH),
+            operators_group: crate::control::DEFAULT_OPERATORS_GROUP.to_string(),
+        }
+    }
+}
+
+impl Default for DetectorSection {
+    fn default() -> Self {
+        Self {
+            model_path: None,
+            trusted_keys: Vec::new(),
+            insecure_models: false,
+            feature_set: FeatureSet::Full,
+            model_watch_secs: 30,
+            selftest_interval_secs: 900,
+            behavior_profiles: false,
+            profile_save_secs: 300,
+            drift: DriftSection::default(),
+        }
+    }
+}
+
+impl Default for DriftSection {
+    fn default() -> Self {
+        let thresholds = DriftThresholds::default();
+        Self {
+            enabled: true,
+            psi: thresholds.psi,
+            kl: thresholds.kl,
+            window: thresholds.window,
+            request_retraining: thresholds.request_retraining,
+        }
+    }
+}
+
+impl Default for InferenceSection {
+    fn default() -> Self {
+        let options = InferenceOptions::default();
+        Self {
+            device: options.device,
+            precision: options.precision,
+            cpu_threads: options.cpu_threads,
+            latency_budget_ms: None,
+        }
+    }
+}
+
+impl Default for PipelineSection {
+    fn default() -> Self {
+        let pipeline = PipelineConfig::default();
+        Self { cadence_secs: pipeline.cadence_secs, min_events: pipeline.min_events, max_sequence: pipeline.max_sequence }
+    }
+}
+
+impl Default for MonitorSection {
+    fn default() -> Self {
+        Self { probe_groups: PROBE_GROUPS.iter().map(|g| g.to_string()).collect(), proc_connector: true, proc_poll: true, poll_interval_ms: 2000, syscall_sample_rate: 1 }
+    }
+}
+
+impl Default for RandomizerSection {
+    fn default() -> Self {
+        Self { max_cpu_percent: 80.0, plan_only: false, arena_socket: None }
+    }
+}
+
+impl Default for SnapshotSection {
+    fn default() -> Self {
+        Self { dir: None, retention_count: 10 }
+    }
+}
+
+impl Default for TokenSection {
+    fn default() -> Self {
+        Self {
+            lifetime_secs: 3600,
+            status_validity_secs: 300,
+            clock_skew_secs: 30,
+            enforce_memory: true,
+            memory_cgroup: "qks.tokens".to_string(),
+            meter_interval_ms: 1000,
+        }
+    }
+}
+
+impl InferenceSection {
+    pub fn options(&self) -> InferenceOptions {
+        InferenceOptions {
+            device: self.device,
+            precision: self.precision,
+            cpu_threads: self.cpu_threads,
+            latency_budget: self.latency_budget_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+impl DriftSection {
+    pub fn thresholds(&self) -> DriftThresholds {
+        DriftThresholds { psi: self.psi, kl: self.kl, window: self.window, request_retraining: self.request_retraining }
+    }
+}
+
+impl PipelineSection {
+    pub fn config(&self) -> PipelineConfig {
+        PipelineConfig { cadence_secs: self.cadence_secs, min_events: self.min_events, max_sequence: self.max_sequence }
+    }
+}
+
+impl MonitorSection {
+    pub fn enabled(&self, group: &str) -> bool {
+        self.probe_groups.iter().any(|g| g == group)
+    }
+}
+
+impl QksConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+        let config: Self = toml::from_str(&text)
+            .map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The packaged default path; a missing file means all defaults
+    pub fn load_default() -> Result<Self, ConfigError> {
+        match Self::load(Path::new(DEFAULT_CONFIG_PATH)) {
+            Err(ConfigError::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            other => other,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, field: &str, message: &str| {
+            if !ok {
+                problems.push(format!("{}: {}", field, message));
+            }
+        };
+
+        check(self.daemon.state_dir.is_absolute(), "daemon.state_dir", "must be an absolute path");
+        check(self.daemon.control_socket.is_absolute(), "daemon.control_socket", "must be an absolute path");
+        if let Some(model) = &self.detector.model_path {
+            check(Path::new(model).exists(), "detector.model_path", &format!("{} does not exist", model));
+            check(
+                self.detector.insecure_models || !self.detector.trusted_keys.is_empty(),
+                "detector.trusted_keys",
+                "a model is configured but no signing keys are trusted (set insecure_models = true only on development hosts)",
+            );
+        }
+        for (i, key) in self.detector.trusted_keys.iter().enumerate() {
+            check(key.exists(), &format!("detector.trusted_keys[{}]", i), &format!("{} does not exist", key.display()));
+        }
+        check(self.detector.model_watch_secs > 0, "detector.model_watch_secs", "must be at least 1");
+        check(self.detector.selftest_interval_secs >= 60, "detector.selftest_interval_secs", "must be at least 60");
+        check(self.detector.profile_save_secs >= 10, "detector.profile_save_secs", "must be at least 10");
+        if self.detector.drift.enabled {
+            check(self.detector.drift.psi > 0.0, "detector.drift.psi", "must be positive");
+            check(self.detector.drift.kl > 0.0, "detector.drift.kl", "must be positive");
+            check(self.detector.drift.window >= 100, "detector.drift.window", "must be at least 100");
+        }
+
+        check(self.inference.cpu_threads != Some(0), "inference.cpu_threads", "must be at least 1 (omit for the runtime default)");
+        check(self.inference.latency_budget_ms != Some(0), "inference.latency_budget_ms", "must be at least 1 (omit for no budget)");
+        check(
+            self.inference.precision == Precision::Float32 || self.inference.device == Device::Cpu,
+            "inference.precision",
+            "int8 models run on the CPU only",
+        );
+
+        check(self.pipeline.cadence_secs > 0, "pipeline.cadence_secs", "must be at least 1");
+        check(self.pipeline.min_events > 0, "pipeline.min_events", "must be at least 1");
+        check(
+            self.pipeline.max_sequence >= self.pipeline.min_events,
+            "pipeline.max_sequence",
+            "must be at least pipeline.min_events",
+        );
+
+        for group in &self.monitor.probe_groups {
+            check(
+                PROBE_GROUPS.contains(&group.as_str()),
+                "monitor.probe_groups",
+                &format!("unknown group {:?} (known: {})", group, PROBE_GROUPS.join(", ")),
+            );
+        }
+        // Every scan reads fd/ and maps of every process
+        check(self.monitor.poll_interval_ms >= 250, "monitor.poll_interval_ms", "must be at least 250");
+        check(self.monitor.syscall_sample_rate >= 1, "monitor.syscall_sample_rate", "must be at least 1");
+
+        check(
+            self.randomizer.max_cpu_percent > 0.0 && self.randomizer.max_cpu_percent <= 100.0,
+            "randomizer.max_cpu_percent",
+            "must be in (0, 100]",
+        );
+        if let Some(socket) = &self.randomizer.arena_socket {
+            check(socket.is_absolute(), "randomizer.arena_socket", "must be an absolute path");
+            check(*socket != self.daemon.control_socket, "randomizer.arena_socket", "must differ from daemon.control_socket");
+        }
+        check(self.snapshots.retention_count > 0, "snapshots.retention_count", "must be at least 1");
+        check(self.tokens.lifetime_secs > 0, "tokens.lifetime_secs", "must be at least 1");
+        check(self.tokens.clock_skew_secs < self.tokens.lifetime_secs, "tokens.clock_skew_secs", "must be less than tokens.lifetime_secs");
+        check(
+            self.tokens.status_validity_secs > 0 && self.tokens.status_validity_secs <= self.tokens.lifetime_secs,
+            "tokens.status_validity_secs",
+            "must be between 1 and tokens.lifetime_secs",
+        );
+
+        let grpc = &self.grpc;
+        check(grpc.tls_cert.is_some() == grpc.tls_key.is_some(), "grpc.tls_key", "tls_cert and tls_key go together");
+        check(grpc.client_ca.is_none() || grpc.tls_cert.is_some(), "grpc.client_ca", "client certificates need tls_cert and tls_key");
+        if grpc.listen.is_some() {
+            check(grpc.client_ca.is_some(), "grpc.client_ca", "required, loopback included; set tls_cert, tls_key and client_ca");
+            check(!grpc.clients.is_empty(), "grpc.clients", "no client certificate may call the API");
+        }
+        for digest in grpc.clients.keys() {
+            check(
+                digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()),
+                "grpc.clients",
+                &format!("{:?} is not a lowercase hex SHA-256", digest),
+            );
+        }
+        for (field, path) in [("grpc.tls_cert", &grpc.tls_cert), ("grpc.tls_key", &grpc.tls_key), ("grpc.client_ca", &grpc.client_ca)] {
+            if let Some(path) = path {
+                check(path.exists(), field, &format!("{} does not exist", path.display()));
+            }
+        }
+
+        let rest = &self.rest;
+        check(rest.tls_cert.is_some() == rest.tls_key.is_some(), "rest.tls_key", "tls_cert and tls_key go together");
+        if let Some(listen) = rest.listen {
+            check(rest.tokens_file.is_some(), "rest.tokens_file", "required when rest.listen is set");
+            check(
+                listen.ip().is_loopback() || rest.tls_cert.is_some(),
+                "rest.listen",
+                &format!("{} is not loopback; set tls_cert and tls_key", listen),
+            );
+        }
+        for (field, path) in [("rest.tokens_file", &rest.tokens_file), ("rest.tls_cert", &rest.tls_cert), ("rest.tls_key", &rest.tls_key)] {
+            if let Some(path) = path {
+                check(path.exists(), field, &format!("{} does not exist", path.display()));
+            }
+        }
+
+        check(
+            tracing_subscriber::EnvFilter::try_new(&self.logging.level).is_ok(),
+            "logging.level",
+            &format!("{:?} is not a valid filter directive", self.logging.level),
+        );
+        let policy = crate::policy::compile(&self.policy).err().map(|e| e.to_string());
+        check(policy.is_none(), "policy", policy.as_deref().unwrap_or_default());
+
+        for action in self.response.cooldowns.keys() {
+            check(
+                ResponseAction::ALL.iter().any(|a| a.as_str() == action),
+                "response.cooldowns",
+                &format!("unknown action {:?}", action),
+            );
+        }
+        check(self.response.freeze_secs > 0, "response.freeze_secs", "must be at least 1");
+        if let Some(audit_log) = &self.response.audit_log {
+            check(audit_log.is_absolute(), "response.audit_log", "must be an absolute path");
+        }
+        let audit = &self.response.audit;
+        check(audit.rotate_size_mb > 0, "response.audit.rotate_size_mb", "must be at least 1");
+        check(audit.rotate_secs > 0, "response.audit.rotate_secs", "must be at least 1");
+        check((1..=19).contains(&audit.zstd_level), "response.audit.zstd_level", "must be between 1 and 19");
+        check(audit.retention_days > 0, "response.audit.retention_days", "must be at least 1");
+        check(audit.retention_max_mb >= audit.rotate_size_mb, "response.audit.retention_max_mb", "must hold at least one segment (rotate_size_mb)");
+
+        let cgroup_path = |path: &str| {
+            !path.is_empty()
+                && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+                && path.chars().all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c))
+        };
+        let quarantine = &self.quarantine;
+        check(cgroup_path(&quarantine.cgroup), "quarantine.cgroup", "expected a relative cgroup path of letters, digits, '.', '_' and '-'");
+        if self.tokens.enforce_memory {
+            check(cgroup_path(&self.tokens.memory_cgroup), "tokens.memory_cgroup", "expected a relative cgroup path of letters, digits, '.', '_' and '-'");
+            check(self.tokens.memory_cgroup != quarantine.cgroup, "tokens.memory_cgroup", "must differ from quarantine.cgroup");
+        }
+        check(self.tokens.meter_interval_ms >= 100, "tokens.meter_interval_ms", "must be at least 100");
+        check((1..=100).contains(&quarantine.cpu_percent), "quarantine.cpu_percent", "must be between 1 and 100");
+        check(quarantine.memory_max_mb > 0, "quarantine.memory_max_mb", "must be at least 1");
+        check(quarantine.pids_max > 0, "quarantine.pids_max", "must be at least 1");
+
+        let notify = &self.notify;
+        check(notify.critical_score > 0.0 && notify.critical_score <= 1.0, "notify.critical_score", "must be in (0, 1]");
+        check(notify.dedup.suppress_secs > 0, "notify.dedup.suppress_secs", "must be at least 1");
+        check(notify.dedup.rate_per_min > 0, "notify.dedup.rate_per_min", "must be at least 1");
+        check(notify.dedup.burst > 0, "notify.dedup.burst", "must be at least 1");
+        let http_url = |url: &str| url.starts_with("https://") || url.starts_with("http://");
+        let template = |template: &Option<String>| template.as_deref().map_or(true, |t| Template::parse(t).is_ok());
+        for (i, webhook) in notify.webhook.iter().enumerate() {
+            check(http_url(&webhook.url), &format!("notify.webhook[{}].url", i), "expected an http:// or https:// URL");
+            check(template(&webhook.template), &format!("notify.webhook[{}].template", i), "unclosed {{ placeholder");
+            check(webhook.format.is_none() || webhook.template.is_none(), &format!("notify.webhook[{}].format", i), "a SIEM format replaces the template; set one or the other");
+        }
+        for (i, slack) in notify.slack.iter().enumerate() {
+            check(slack.webhook_url.starts_with("https://"), &format!("notify.slack[{}].webhook_url", i), "expected an https:// URL");
+            check(template(&slack.template), &format!("notify.slack[{}].template", i), "unclosed {{ placeholder");
+        }
+        for (i, email) in notify.email.iter().enumerate() {
+            let field = |name: &str| format!("notify.email[{}].{}", i, name);
+            let mailbox = |address: &str| address.parse::<lettre::message::Mailbox>().is_ok();
+            check(!email.smtp_host.is_empty(), &field("smtp_host"), "must not be empty");
+            check(mailbox(&email.from), &field("from"), "not an email address");
+            check(!email.to.is_empty() && email.to.iter().all(|to| mailbox(to)), &field("to"), "expected one or more email addresses");
+            check(email.username.is_some() == email.password_file.is_some(), &field("password_file"), "username and password_file go together");
+            check(template(&email.subject) && template(&email.template), &field("template"), "unclosed {{ placeholder");
+        }
+
+        let splunk = &self.forward.splunk;
+        if splunk.enabled {
+            check(http_url(&splunk.url), "forward.splunk.url", "expected an http:// or https:// URL");
+            check(splunk.token_file.is_some(), "forward.splunk.token_file", "required when forward.splunk.enabled is set");
+            check(!splunk.event_kinds.is_empty(), "forward.splunk.event_kinds", "must name at least one kind to forward");
+        }
+        let elasticsearch = &self.forward.elasticsearch;
+        if elasticsearch.enabled {
+            check(http_url(&elasticsearch.url), "forward.elasticsearch.url", "expected an http:// or https:// URL");
+            check(
+                matches!(elasticsearch.format, EventFormat::Json | EventFormat::Ecs),
+                "forward.elasticsearch.format",
+                "documents are json or ecs",
+            );
+            // Checked as it comes out for some day and kind
+            let index = elasticsearch.index.replace("%Y", "2024").replace("%m", "01").replace("%d", "01").replace("{kind}", "anomaly");
+            check(
+                !index.is_empty()
+                    && !index.starts_with(['-', '_', '+'])
+                    && index.chars().all(|c| !c.is_ascii_uppercase() && !" \\/*?\"<>|,#:{}".contains(c)),
+                "forward.elasticsearch.index",
+                "not a valid index name: lowercase, without spaces or any of \\/*?\"<>|,#:",
+            );
+            check(
+                elasticsearch.username.is_some() == elasticsearch.password_file.is_some(),
+                "forward.elasticsearch.password_file",
+                "username and password_file go together",
+            );
+            check(
+                elasticsearch.api_key_file.is_none() || elasticsearch.username.is_none(),
+                "forward.elasticsearch.api_key_file",
+                "an API key replaces username and password; set one or the other",
+            );
+            check(!elasticsearch.event_kinds.is_empty(), "forward.elasticsearch.event_kinds", "must name at least one kind to forward");
+        }
+        let kafka = &self.forward.kafka;
+        if kafka.enabled {
+            check(cfg!(feature = "kafka"), "forward.kafka.enabled", "kafka support not compiled in (enable the kafka feature)");
+            check(
+                !kafka.brokers.is_empty() && kafka.brokers.iter().all(|broker| broker.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())),
+                "forward.kafka.brokers",
+                "expected one or more HOST:PORT",
+            );
+            // Kafka topic names: letters, digits, '.', '_' and '-', at most 249
+            let topic = |topic: &str| !topic.is_empty() && topic.len() <= 249 && topic.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+            check(topic(&kafka.topic.replace("{kind}", "anomaly")), "forward.kafka.topic", "not a valid topic name");
+            for (kind, name) in &kafka.topics {
+                check(EventKind::ALL.iter().any(|k| k.as_str() == kind), "forward.kafka.topics", &format!("unknown event kind {:?}", kind));
+                check(topic(name), "forward.kafka.topics", &format!("{:?} is not a valid topic name", name));
+            }
+            check(kafka.delivery_timeout_secs > 0, "forward.kafka.delivery_timeout_secs", "must be at least 1");
+            check(!kafka.event_kinds.is_empty(), "forward.kafka.event_kinds", "must name at least one kind to forward");
+        }
+        for (sink, batch, enabled) in [
+            ("splunk", &splunk.batch, splunk.enabled),
+            ("elasticsearch", &elasticsearch.batch, elasticsearch.enabled),
+            ("kafka", &kafka.batch, kafka.enabled),
+        ] {
+            if enabled {
+                let field = |name: &str| format!("forward.{}.batch.{}", sink, name);
+                check(batch.batch_size > 0, &field("batch_size"), "must be at least 1");
+                check(batch.flush_secs > 0, &field("flush_secs"), "must be at least 1");
+                check(batch.max_backoff_secs > 0, &field("max_backoff_secs"), "must be at least 1");
+                check(batch.buffer_max_mb > 0, &field("buffer_max_mb"), "must be at least 1");
+            }
+        }
+
+        let ima = &self.integrity.ima;
+        if ima.enabled {
+            check(ima.securityfs.is_absolute(), "integrity.ima.securityfs", "must be an absolute path");
+            check(ima.baseline.as_ref().map_or(true, |path| path.is_absolute()), "integrity.ima.baseline", "must be an absolute path");
+            check(ima.watch_paths.iter().all(|path| path.is_absolute()), "integrity.ima.watch_paths", "must be absolute paths");
+            check(ima.interval_secs > 0, "integrity.ima.interval_secs", "must be at least 1");
+        }
+        let verity = &self.integrity.verity;
+        if verity.enabled {
+            check(!verity.volumes.is_empty(), "integrity.verity.volumes", "must name at least one mount point or device");
+            check(verity.volumes.iter().all(|volume| !volume.is_empty() && (volume.starts_with('/') || !volume.contains('/'))), "integrity.verity.volumes", "expected absolute mount points or device-mapper names");
+            check(verity.interval_secs > 0, "integrity.verity.interval_secs", "must be at least 1");
+        }
+        check(self.integrity.posture.interval_secs > 0, "integrity.posture.interval_secs", "must be at least 1");
+        let modules = &self.integrity.modules;
+        if modules.enabled {
+            check(modules.interval_secs > 0, "integrity.modules.interval_secs", "must be at least 1");
+            check(modules.allowed.iter().all(|name| !name.is_empty() && !name.contains('-')), "integrity.modules.allowed", "expected module names as lsmod shows them, with _ for -");
+        }
+        let packages = &self.integrity.packages;
+        if packages.enabled {
+            check(packages.tick_secs > 0, "integrity.packages.tick_secs", "must be at least 1");
+            check(packages.batch_bytes > 0, "integrity.packages.batch_bytes", "must be at least 1");
+            check(packages.critical_paths.iter().chain(&packages.skip_paths).all(|path| path.is_absolute()), "integrity.packages", "critical_paths and skip_paths must be absolute");
+        }
+
+        let profiles = &self.profiles;
+        if profiles.enabled {
+            check(!profiles.binaries.is_empty(), "profiles.binaries", "must list at least one binary to profile");
+            check(profiles.binaries.iter().chain([&profiles.apparmor_dir]).all(|path| path.is_absolute()), "profiles", "binaries and apparmor_dir must be absolute paths");
+            check(profiles.sample_secs > 0, "profiles.sample_secs", "must be at least 1");
+        }
+
+        let containers = &self.containers;
+        if containers.enabled {
+            match containers.runtime {
+                ContainerRuntime::Containerd => {
+                    check(containers.socket.is_absolute(), "containers.socket", "must be an absolute path");
+                    check(!containers.namespaces.is_empty(), "containers.namespaces", "must name at least one containerd namespace");
+                }
+                ContainerRuntime::Docker => check(containers.docker_socket.is_absolute(), "containers.docker_socket", "must be an absolute path"),
+            }
+            check((0.0..=1.0).contains(&containers.randomize_jitter), "containers.randomize_jitter", "must be between 0 and 1");
+        }
+        let kubernetes = &self.kubernetes;
+        if kubernetes.enabled {
+            check(http_url(&kubernetes.kubelet_url), "kubernetes.kubelet_url", "expected an http:// or https:// URL");
+            check(kubernetes.token_file.is_absolute(), "kubernetes.token_file", "must be an absolute path");
+            check(kubernetes.sync_secs > 0, "kubernetes.sync_secs", "must be at least 1");
+        }
+
+        let watchdog = &self.watchdog;
+        if watchdog.enabled {
+            check(!watchdog.services.is_empty(), "watchdog.services", "must name at least one systemd service");
+            for (i, service) in watchdog.services.iter().enumerate() {
+                check(crate::crash_watchdog::valid_unit(service), &format!("watchdog.services[{}]", i), "expected a unit name ending in .service");
+            }
+            check(watchdog.poll_secs > 0, "watchdog.poll_secs", "must be at least 1");
+            check(watchdog.crash_restarts > 0, "watchdog.crash_restarts", "must be at least 1");
+            check(watchdog.crash_window_secs >= watchdog.poll_secs, "watchdog.crash_window_secs", "must be at least poll_secs");
+            check(watchdog.start_timeout_secs > 0, "watchdog.start_timeout_secs", "must be at least 1");
+        }
+
+        let fleet = &self.fleet;
+        if fleet.role != FleetRole::Off {
+            for (field, path) in [("fleet.tls_cert", &fleet.tls_cert), ("fleet.tls_key", &fleet.tls_key), ("fleet.ca", &fleet.ca)] {
+                match path {
+                    Some(path) => check(path.exists(), field, &format!("{} does not exist", path.display())),
+                    None => check(false, field, "fleet mode needs mutual TLS"),
+                }
+            }
+        }
+        for (i, id) in fleet.revokers.iter().enumerate() {
+            check(crate::fleet::valid_id(id), &format!("fleet.revokers[{}]", i), "expected a fleet ID (32 hex digits)");
+        }
+        match fleet.role {
+            FleetRole::Off => {}
+            FleetRole::Controller => {
+                check(fleet.listen.is_some(), "fleet.listen", "a controller needs an address to take agents on");
+                for (i, id) in fleet.agents.iter().enumerate() {
+                    check(crate::fleet::valid_id(id), &format!("fleet.agents[{}]", i), "expected a fleet ID (32 hex digits)");
+                }
+                for (field, path) in [("fleet.policy_file", &fleet.policy_file), ("fleet.model_path", &fleet.model_path)] {
+                    if let Some(path) = path {
+                        check(path.is_absolute(), field, "must be an absolute path");
+                    }
+                }
+                check(fleet.watch_secs > 0, "fleet.watch_secs", "must be at least 1");
+                check(fleet.events_max_mb > 0, "fleet.events_max_mb", "must be at least 1");
+            }
+            FleetRole::Agent => {
+                check(fleet.controller.as_deref().is_some_and(|url| url.starts_with("https://")), "fleet.controller", "expected an https:// URL");
+                if let Some(id) = &fleet.controller_id {
+                    check(crate::fleet::valid_id(id), "fleet.controller_id", "expected a fleet ID (32 hex digits)");
+                }
+                check(!fleet.event_kinds.is_empty(), "fleet.event_kinds", "must name at least one kind to send");
+                check(fleet.batch.batch_size > 0, "fleet.batch.batch_size", "must be at least 1");
+                check(fleet.batch.flush_secs > 0, "fleet.batch.flush_secs", "must be at least 1");
+                check(fleet.batch.max_backoff_secs > 0, "fleet.batch.max_backoff_secs", "must be at least 1");
+                check(fleet.batch.buffer_max_mb > 0, "fleet.batch.buffer_max_mb", "must be at least 1");
+            }
+        }
+
+        let intel = &self.intel;
+        if intel.enabled {
+            check(!intel.feeds.is_empty(), "intel.feeds", "must name at least one feed");
+            check(intel.refresh_secs >= 60, "intel.refresh_secs", "must be at least 60");
+            check(intel.max_exec_mb > 0, "intel.max_exec_mb", "must be at least 1");
+            check(intel.repeat_secs > 0, "intel.repeat_secs", "must be at least 1");
+        }
+        for (i, feed) in intel.feeds.iter().enumerate() {
+            let field = |name: &str| format!("intel.feeds[{}].{}", i, name);
+            check(crate::intel::valid_name(&feed.name), &field("name"), "must be 1-64 of a-z, 0-9, - and _");
+            check(intel.feeds.iter().filter(|other| other.name == feed.name).count() == 1, &field("name"), "names another feed too");
+            match (&feed.url, &feed.path) {
+                (Some(url), None) => check(http_url(url), &field("url"), "expected an http:// or https:// URL"),
+                (None, Some(path)) => check(path.is_absolute(), &field("path"), "must be an absolute path"),
+                _ => check(false, &field("url"), "set either url or path"),
+            }
+            if feed.format == FeedFormat::Taxii {
+                check(feed.url.is_some(), &field("url"), "TAXII needs the API root's URL");
+                check(feed.collection.as_deref().is_some_and(|c| !c.is_empty()), &field("collection"), "TAXII needs a collection ID");
+            }
+            check(feed.token_file.is_none() || feed.username.is_none(), &field("token_file"), "set a token or a username, not both");
+            check(feed.severity <= 10, &field("severity"), "must be 0-10");
+        }
+
+        let privsep = &self.privsep;
+        if privsep.enabled {
+            check(!privsep.user.is_empty() && privsep.user != "root", "privsep.user", "must name an unprivileged user");
+            check(privsep.helper_socket.is_absolute(), "privsep.helper_socket", "must be an absolute path");
+            check(privsep.helper_socket != self.daemon.control_socket, "privsep.helper_socket", "must differ from daemon.control_socket");
+            check(privsep.remap_targets.iter().all(|path| path.is_absolute()), "privsep.remap_targets", "must be absolute paths");
+        }
+
+        let sandbox = &self.sandbox;
+        if sandbox.landlock {
+            check(sandbox.read_paths.iter().all(|path| path.is_absolute()), "sandbox.read_paths", "must be absolute paths");
+            check(sandbox.write_paths.iter().all(|path| path.is_absolute()), "sandbox.write_paths", "must be absolute paths");
+        }
+
+        let tpm = &self.tpm;
+        if tpm.enabled {
+            check(cfg!(feature = "tpm"), "tpm.enabled", "TPM support not compiled in (enable the tpm feature)");
+            // Replay starts from zeros: PCRs 8-16 and 23 start there at boot
+            check((8..=16).contains(&tpm.pcr) || tpm.pcr == 23, "tpm.pcr", "must be 8-16 or 23");
+            check(!tpm.tcti.is_empty(), "tpm.tcti", "must not be empty");
+        }
+        if tpm.controller {
+            check(!tpm.trusted_aks.is_empty(), "tpm.trusted_aks", "a controller needs at least one attestation key to trust");
+        }
+
+        let syslog = &self.logging.syslog;
+        if syslog.enabled {
+            check(syslog.address.parse::<SyslogAddress>().is_ok(), "logging.syslog.address", "expected unix:PATH, udp:HOST:PORT or tcp:HOST:PORT");
+            check(syslog.rate_per_sec > 0, "logging.syslog.rate_per_sec", "must be at least 1");
+            check(syslog.burst > 0, "logging.syslog.burst", "must be at least 1");
+            check(syslog.event_format.is_none() || !syslog.event_kinds.is_empty(), "logging.syslog.event_kinds", "must name at least one kind to forward");
+        }
+        for (i, route) in syslog.routes.iter().enumerate() {
+            check(!route.target.is_empty(), &format!("logging.syslog.routes[{}].target", i), "must not be empty");
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn response_audit_log(&self) -> PathBuf {
+        self.response.audit_log.clone().unwrap_or_else(|| self.daemon.state_dir.join("response-audit.jsonl"))
+    }
+
+    // What was extended into the TPM this boot
+    pub fn tpm_event_log(&self) -> PathBuf {
+        self.daemon.state_dir.join("tpm-events.jsonl")
+    }
+
+    // Confinement profile drafts and their review state
+    pub fn behavior_profiles(&self) -> PathBuf {
+        self.daemon.state_dir.join("behavior-profiles.json")
+    }
+
+    pub fn profile_drafts(&self) -> PathBuf {
+        self.daemon.state_dir.join("profile-drafts.json")
+    }
+
+    // The last host posture seen, to compare the next one with
+    pub fn posture_state(&self) -> PathBuf {
+        self.daemon.state_dir.join("posture.json")
+    }
+
+    // Where forwarding sinks spool batches while their collector is away,
+    // one subdirectory each
+    pub fn forward_spool_dir(&self) -> PathBuf {
+        self.daemon.state_dir.join("forward")
+    }
+
+    // The controller's journal and agent list, or an agent's fleet models
+    // and unsent revocations
+    pub fn fleet_dir(&self) -> PathBuf {
+        self.daemon.state_dir.join("fleet")
+    }
+
+    // Each intel feed's last good indicators
+    pub fn intel_dir(&self) -> PathBuf {
+        self.daemon.state_dir.join("intel")
+    }
+
+    // The persistent identity key (PKCS#8) fleet mode signs with
+    pub fn identity_key(&self) -> PathBuf {
+        self.daemon.state_dir.join("identity.pk8")
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.snapshots.dir.clone().unwrap_or_else(|| self.daemon.state_dir.join("snapshots"))
+    }
+
+    // Take the live-safe parts of `new`; everything else keeps its running
+    // value. Returns the merged config and the fields that need a restart.
+    pub fn merge_reload(&self, new: &QksConfig) -> (QksConfig, Vec<&'static str>) {
+        let mut merged = self.clone();
+        let mut restart = Vec::new();
+
+        // Applied live: the detector reloads its model with the new
+        // inference options and switches feature set if the model allows
+        merged.detector.feature_set = new.detector.feature_set;
+        merged.inference = new.inference.clone();
+        // Rules are recompiled and swapped in whole
+        merged.policy = new.policy.clone();
+
+        let mut differs = |changed: bool, field: &'static str| {
+            if changed {
+                restart.push(field);
+            }
+        };
+        differs(self.daemon != new.daemon, "daemon");
+        // The model watcher follows the path it was started with
+        differs(self.detector.model_path != new.detector.model_path, "detector.model_path");
+        // Trust is never widened without a restart
+        differs(self.detector.trusted_keys != new.detector.trusted_keys, "detector.trusted_keys");
+        differs(self.detector.insecure_models != new.detector.insecure_models, "detector.insecure_models");
+        differs(self.detector.model_watch_secs != new.detector.model_watch_secs, "detector.model_watch_secs");
+        differs(self.detector.selftest_interval_secs != new.detector.selftest_interval_secs, "detector.selftest_interval_secs");
+        differs(self.detector.behavior_profiles != new.detector.behavior_profiles, "detector.behavior_profiles");
+        differs(self.detector.profile_save_secs != new.detector.profile_save_secs, "detector.profile_save_secs");
+        differs(self.detector.drift != new.detector.drift, "detector.drift");
+        differs(self.pipeline != new.pipeline, "pipeline");
+        differs(self.monitor != new.monitor, "monitor");
+        differs(self.randomizer != new.randomizer, "randomizer");
+        differs(self.snapshots != new.snapshots, "snapshots");
+        differs(self.tokens != new.tokens, "tokens");
+        differs(self.grpc != new.grpc, "grpc");
+        differs(self.rest != new.rest, "rest");
+        differs(self.dbus != new.dbus, "dbus");
+        differs(self.metrics != new.metrics, "metrics");
+        differs(self.response != new.response, "response");
+        differs(self.quarantine != new.quarantine, "quarantine");
+        differs(self.notify != new.notify, "notify");
+        differs(self.forward != new.forward, "forward");
+        differs(self.integrity != new.integrity, "integrity");
+        differs(self.tpm != new.tpm, "tpm");
+        differs(self.profiles != new.profiles, "profiles");
+        differs(self.containers != new.containers, "containers");
+        differs(self.kubernetes != new.kubernetes, "kubernetes");
+        differs(self.watchdog != new.watchdog, "watchdog");
+        differs(self.fleet != new.fleet, "fleet");
+        differs(self.intel != new.intel, "intel");
+        differs(self.privsep != new.privsep, "privsep");
+        differs(self.sandbox != new.sandbox, "sandbox");
+        differs(self.logging != new.logging, "logging");
+
+        (merged, restart)
+    }
+}
+
+// Re-read the file on every SIGHUP. Invalid files are rejected whole and the
+// running config stays; subscribers see only merged, valid configs.
+pub fn start_reload(
+    path: PathBuf,
+    current: QksConfig,
+) -> std::io::Result<(tokio::task::JoinHandle<()>, watch::Receiver<Arc<QksConfig>>)> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())?;
+    let (tx, rx) = watch::channel(Arc::new(current));
+
+    let handle = tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let new = match QksConfig::load(&path) {
+                Ok(new) => new,
+                Err(e) => {
+                    tracing::error!("Config reload rejected, keeping the running config: {}", e);
+                    continue;
+                }
+            };
+            let (merged, restart) = tx.borrow().merge_reload(&new);
+            if !restart.is_empty() {
+                tracing::warn!("Config changes to {} take effect after a restart", restart.join(", "));
+            }
+            if *tx.borrow().as_ref() == merged {
+                tracing::info!("Config reloaded from {}; nothing to apply", path.display());
+                continue;
+            }
+            tracing::info!("Config reloaded from {}", path.display());
+            if tx.send(Arc::new(merged)).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok((handle, rx))
+}
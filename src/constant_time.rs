@@ -0,0 +1,51 @@
+// src/constant_time.rs
+use ring::digest;
+
+// Compare secret-dependent bytes without short-circuiting on the first
+// mismatch. Length is not treated as secret.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+
+    // Keep the optimizer from turning the fold back into an early exit
+    std::hint::black_box(diff) == 0
+}
+
+pub fn ct_eq_str(a: &str, b: &str) -> bool {
+    ct_eq(a.as_bytes(), b.as_bytes())
+}
+
+// Scan the whole set even after a hit so timing doesn't reveal the position
+pub fn ct_contains<'a, I>(haystack: I, needle: &[u8]) -> bool
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut found = false;
+    for candidate in haystack {
+        found |= ct_eq(candidate, needle);
+    }
+    found
+}
+
+// Fixed-size lookup key for secret byte strings, so hash map probing
+// depends on a digest rather than on the secret itself
+pub fn lookup_key(secret: &[u8]) -> [u8; 32] {
+    let hash = digest::digest(&digest::SHA256, secret);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(hash.as_ref());
+    key
+}
+
+// Best-effort wipe of key material before it is dropped
+pub fn zeroize(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
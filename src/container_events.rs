@@ -0,0 +1,325 @@
+// src/container_events.rs
+// Follows containerd's event stream so containers are enrolled as they
+// start rather than found by a later scan. When a task's init process
+// starts in one of [containers] namespaces, its container's image, labels
+// and pod (from the io.kubernetes.* labels the CRI plugin sets) go into the
+// registry, which the event renderers and the policy engine consult for
+// every event about a process in that container; the process is given a
+// token and scheduled for re-randomization if configured. When it exits
+// the token is revoked and the rest undone. Tasks already running when the
+// stream is (re)joined are enrolled from the task list first.
+//
+// containerd speaks gRPC on a unix socket and scopes most calls by the
+// containerd-namespace header; the event stream itself spans namespaces.
+use crate::config::ContainersSection;
+use crate::container_exclusions::ContainerExclusions;
+use crate::crypto_identifiers::ProcessToken;
+use crate::daemon::Control;
+use crate::events::{ContainerEvent, SecurityEvent};
+use crate::metrics;
+use crate::randomization_policy::RandomizationProfile;
+use crate::randomization_scheduler::{Cadence, RandomizationScheduler};
+use crate::systemd;
+use dashmap::DashMap;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+pub mod proto {
+    pub mod events {
+        tonic::include_proto!("containerd.services.events.v1");
+    }
+    pub mod containers {
+        tonic::include_proto!("containerd.services.containers.v1");
+    }
+    pub mod tasks {
+        tonic::include_proto!("containerd.services.tasks.v1");
+    }
+}
+
+use proto::containers::containers_client::ContainersClient;
+use proto::containers::GetContainerRequest;
+use proto::events::events_client::EventsClient;
+use proto::events::{Envelope, SubscribeRequest, TaskExit, TaskStart};
+use proto::tasks::tasks_client::TasksClient;
+use proto::tasks::ListTasksRequest;
+
+const NAMESPACE_HEADER: &str = "containerd-namespace";
+const TOPIC_START: &str = "/tasks/start";
+const TOPIC_EXIT: &str = "/tasks/exit";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Labels the CRI plugin puts on every container it creates
+pub(crate) const POD_NAME_LABEL: &str = "io.kubernetes.pod.name";
+pub(crate) const POD_NAMESPACE_LABEL: &str = "io.kubernetes.pod.namespace";
+pub(crate) const POD_UID_LABEL: &str = "io.kubernetes.pod.uid";
+pub(crate) const CONTAINER_NAME_LABEL: &str = "io.kubernetes.container.name";
+
+// Whose event stream to follow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerRuntime {
+    #[default]
+    Containerd,
+    Docker,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ContainerError {
+    #[error("containerd: {0}")]
+    Connect(#[from] tonic::transport::Error),
+    #[error("containerd: {0}")]
+    Rpc(#[from] tonic::Status),
+    #[error("{0:?} is not a valid containerd namespace")]
+    Namespace(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PodInfo {
+    pub name: String,
+    pub namespace: String,
+    pub uid: String,
+    // The container's name within the pod
+    pub container: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContainerInfo {
+    pub id: String,
+    // containerd's namespace, not the pod's
+    pub namespace: String,
+    pub image: String,
+    pub labels: BTreeMap<String, String>,
+    pub pod: Option<PodInfo>,
+}
+
+impl ContainerInfo {
+    pub(crate) fn new(id: String, namespace: String, image: String, labels: BTreeMap<String, String>) -> Self {
+        let pod = match (labels.get(POD_NAME_LABEL), labels.get(POD_NAMESPACE_LABEL)) {
+            (Some(name), Some(pod_namespace)) => Some(PodInfo {
+                name: name.clone(),
+                namespace: pod_namespace.clone(),
+                uid: labels.get(POD_UID_LABEL).cloned().unwrap_or_default(),
+                container: labels.get(CONTAINER_NAME_LABEL).cloned(),
+            }),
+            _ => None,
+        };
+        Self { id, namespace, image, labels, pod }
+    }
+}
+
+// Which container each enrolled process belongs to. Processes a container
+// forks later are found through their cgroup, which is also how processes
+// in containers only the kubelet told us about (kubernetes.rs) are found.
+#[derive(Default)]
+pub struct ContainerRegistry {
+    by_id: DashMap<String, Arc<ContainerInfo>>,
+    by_pid: DashMap<u32, String>,
+    // Replaced wholesale on each kubelet sync; enrolled containers win
+    known: DashMap<String, Arc<ContainerInfo>>,
+}
+
+static GLOBAL: OnceLock<ContainerRegistry> = OnceLock::new();
+
+pub fn global() -> &'static ContainerRegistry {
+    GLOBAL.get_or_init(ContainerRegistry::default)
+}
+
+impl ContainerRegistry {
+    fn register(&self, pid: u32, info: Arc<ContainerInfo>) {
+        self.by_pid.insert(pid, info.id.clone());
+        metrics::global().incr("qks_containers_enrolled_total", &[("namespace", &info.namespace)]);
+        self.by_id.insert(info.id.clone(), info);
+    }
+
+    fn remove(&self, pid: u32) -> Option<Arc<ContainerInfo>> {
+        let (_, id) = self.by_pid.remove(&pid)?;
+        let (_, info) = self.by_id.remove(&id)?;
+        metrics::global().incr("qks_containers_unenrolled_total", &[("namespace", &info.namespace)]);
+        Some(info)
+    }
+
+    pub fn for_pid(&self, pid: u32) -> Option<Arc<ContainerInfo>> {
+        if let Some(id) = self.by_pid.get(&pid) {
+            return self.by_id.get(id.value()).map(|info| info.clone());
+        }
+        // Nothing enrolled means nothing to find; skip reading /proc
+        if self.by_id.is_empty() && self.known.is_empty() {
+            return None;
+        }
+        let id = ContainerExclusions::container_id(pid)?;
+        self.by_id.get(&id).or_else(|| self.known.get(&id)).map(|info| info.clone())
+    }
+
+    pub(crate) fn replace_known(&self, containers: Vec<ContainerInfo>) {
+        let ids: std::collections::HashSet<String> = containers.iter().map(|info| info.id.clone()).collect();
+        self.known.retain(|id, _| ids.contains(id));
+        for info in containers {
+            self.known.insert(info.id.clone(), Arc::new(info));
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<ContainerInfo>> {
+        self.by_id.iter().map(|entry| entry.value().clone()).collect()
+    }
+}
+
+// The enrolled container the event's process runs in; container events
+// name their own, which has already left the registry when it stopped
+pub fn for_event(event: &SecurityEvent) -> Option<ContainerInfo> {
+    match event {
+        SecurityEvent::Container(ContainerEvent::Started { container, .. } | ContainerEvent::Stopped { container, .. }) => Some(container.clone()),
+        _ => event.pid().and_then(|pid| global().for_pid(pid)).map(|info| (*info).clone()),
+    }
+}
+
+pub(crate) struct ContainerWatcher {
+    section: ContainersSection,
+    control: Arc<Control>,
+    scheduler: Arc<RandomizationScheduler>,
+    // Tokens issued to init processes, to revoke when they exit
+    tokens: DashMap<u32, ProcessToken>,
+}
+
+impl ContainerWatcher {
+    pub(crate) fn new(section: &ContainersSection, control: Arc<Control>, scheduler: Arc<RandomizationScheduler>) -> Self {
+        Self { section: section.clone(), control, scheduler, tokens: DashMap::new() }
+    }
+
+    // Follows the stream, reconnecting for as long as the daemon runs
+    pub(crate) fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.follow().await {
+                    Ok(()) => tracing::warn!("containerd event stream ended; reconnecting"),
+                    Err(e) => tracing::warn!("{} ({}); retrying in {}s", e, self.section.socket.display(), RECONNECT_DELAY.as_secs()),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    async fn connect(&self) -> Result<Channel, ContainerError> {
+        let socket = self.section.socket.clone();
+        // The URI is required but never dialled
+        let channel = Endpoint::from_static("http://containerd")
+            .connect_with_connector(tower::service_fn(move |_: Uri| tokio::net::UnixStream::connect(socket.clone())))
+            .await?;
+        Ok(channel)
+    }
+
+    fn scoped<T>(namespace: &str, message: T) -> Result<tonic::Request<T>, ContainerError> {
+        let value: MetadataValue<_> = namespace.parse().map_err(|_| ContainerError::Namespace(namespace.to_string()))?;
+        let mut request = tonic::Request::new(message);
+        request.metadata_mut().insert(NAMESPACE_HEADER, value);
+        Ok(request)
+    }
+
+    async fn follow(&self) -> Result<(), ContainerError> {
+        let channel = self.connect().await?;
+        let mut containers = ContainersClient::new(channel.clone());
+        // Subscribe before listing, so nothing starts in between unseen
+        let filters = vec![format!("topic==\"{}\"", TOPIC_START), format!("topic==\"{}\"", TOPIC_EXIT)];
+        let mut stream = EventsClient::new(channel.clone()).subscribe(SubscribeRequest { filters }).await?.into_inner();
+        tracing::info!("Following containerd events on {}", self.section.socket.display());
+
+        let mut tasks = TasksClient::new(channel);
+        for namespace in &self.section.namespaces {
+            let running = tasks.list(Self::scoped(namespace, ListTasksRequest::default())?).await?.into_inner().tasks;
+            for task in running.into_iter().filter(|task| task.id == task.container_id || task.id.is_empty()) {
+                if global().by_pid.contains_key(&task.pid) {
+                    continue;
+                }
+                self.enroll(&mut containers, namespace, task.container_id, task.pid).await;
+            }
+        }
+
+        while let Some(envelope) = stream.message().await? {
+            self.handle(&mut containers, envelope).await;
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, containers: &mut ContainersClient<Channel>, envelope: Envelope) {
+        if !self.section.namespaces.contains(&envelope.namespace) {
+            return;
+        }
+        let Some(event) = envelope.event else {
+            return;
+        };
+        match envelope.topic.as_str() {
+            TOPIC_START => match TaskStart::decode(event.value.as_slice()) {
+                Ok(start) => self.enroll(containers, &envelope.namespace, start.container_id, start.pid).await,
+                Err(e) => tracing::warn!("Undecodable {} event: {}", TOPIC_START, e),
+            },
+            TOPIC_EXIT => match TaskExit::decode(event.value.as_slice()) {
+                // Exec'd processes exit too; only the init process ends the container
+                Ok(exit) if exit.id == exit.container_id => self.unenroll(exit.pid, exit.exit_status),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Undecodable {} event: {}", TOPIC_EXIT, e),
+            },
+            _ => {}
+        }
+    }
+
+    async fn enroll(&self, containers: &mut ContainersClient<Channel>, namespace: &str, id: String, pid: u32) {
+        let info = match Self::scoped(namespace, GetContainerRequest { id: id.clone() }) {
+            Ok(request) => match containers.get(request).await {
+                Ok(reply) => reply.into_inner().container.map(|c| ContainerInfo::new(c.id, namespace.to_string(), c.image, c.labels.into_iter().collect())),
+                Err(e) => {
+                    tracing::warn!("Container {} in {}: {}", id, namespace, e);
+                    None
+                }
+            },
+            Err(e) => {
+                tracing::warn!("{}", e);
+                None
+            }
+        };
+        // Still enrolled without metadata; the ID alone attributes events
+        self.admit(pid, info.unwrap_or_else(|| ContainerInfo::new(id, namespace.to_string(), String::new(), BTreeMap::new())));
+    }
+
+    // Registers a container's init process, gives it its token and
+    // schedules it; the Docker watcher (docker_events.rs) comes in here too
+    pub(crate) fn admit(&self, pid: u32, info: ContainerInfo) {
+        let info = Arc::new(info);
+        global().register(pid, info.clone());
+        tracing::info!(pid, container = %info.id, image = %info.image, "Container {} started as PID {}", info.id, pid);
+
+        if self.section.issue_tokens {
+            match self.control.issue_token(pid, &self.section.token_capabilities) {
+                Ok(token) => {
+                    self.tokens.insert(pid, token);
+                }
+                Err(e) => tracing::warn!(pid, "No token for container {}: {:?}", info.id, e),
+            }
+        }
+        let excluded = self.control.randomizer.lock().unwrap().profile_for(pid) == RandomizationProfile::Excluded;
+        if self.section.randomize_secs > 0 && !excluded {
+            self.scheduler.enroll(pid, Cadence { interval: Duration::from_secs(self.section.randomize_secs), jitter: self.section.randomize_jitter });
+        }
+        self.control.events.publish(SecurityEvent::Container(ContainerEvent::Started { pid, container: (*info).clone(), timestamp: systemd::now_secs() }));
+    }
+
+    pub(crate) fn unenroll(&self, pid: u32, exit_status: u32) {
+        self.scheduler.unenroll(pid);
+        if let Some((_, token)) = self.tokens.remove(&pid) {
+            self.control.revoke_token(&token, "container exit");
+        }
+        let Some(info) = global().remove(pid) else {
+            return;
+        };
+        tracing::info!(pid, container = %info.id, "Container {} exited with status {}", info.id, exit_status);
+        self.control.events.publish(SecurityEvent::Container(ContainerEvent::Stopped {
+            pid,
+            container: (*info).clone(),
+            exit_status,
+            timestamp: systemd::now_secs(),
+        }));
+    }
+}
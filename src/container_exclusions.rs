@@ -0,0 +1,157 @@
+// src/container_exclusions.rs
+// Per-container randomization overrides taken from OCI annotations, so pods
+// can opt out of (or into) layout regeneration declaratively:
+//
+//   metadata.annotations["randomization.qks.io/profile"] = "excluded"
+//
+// Sandbox ("pause") containers are always excluded.
+use crate::randomization_policy::RandomizationProfile;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PROFILE_ANNOTATION: &str = "randomization.qks.io/profile";
+
+const SANDBOX_ANNOTATIONS: [(&str, &str); 2] = [
+    ("io.kubernetes.cri.container-type", "sandbox"),
+    ("io.kubernetes.cri-o.ContainerType", "sandbox"),
+];
+
+// Where runtimes keep each container's OCI bundle config.json
+const DEFAULT_STATE_ROOTS: [&str; 2] = [
+    // containerd (and Docker through it): <root>/<namespace>/<id>/config.json
+    "/run/containerd/io.containerd.runtime.v2.task",
+    // CRI-O: <root>/<id>/userdata/config.json
+    "/run/containers/storage/overlay-containers",
+];
+
+// cgroup leaf names wrap the container ID, e.g. cri-containerd-<id>.scope
+const CGROUP_PREFIXES: [&str; 4] = ["docker-", "cri-containerd-", "crio-", "libpod-"];
+
+#[derive(Clone)]
+pub struct ContainerExclusions {
+    overrides: Arc<DashMap<String, RandomizationProfile>>,
+    state_roots: Vec<PathBuf>,
+}
+
+impl Default for ContainerExclusions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContainerExclusions {
+    pub fn new() -> Self {
+        Self::with_state_roots(DEFAULT_STATE_ROOTS.iter().map(PathBuf::from).collect())
+    }
+
+    pub fn with_state_roots(state_roots: Vec<PathBuf>) -> Self {
+        Self {
+            overrides: Arc::new(DashMap::new()),
+            state_roots,
+        }
+    }
+
+    // Container annotation beats any per-binary policy
+    pub fn profile_for_pid(&self, pid: u32) -> Option<RandomizationProfile> {
+        let id = Self::container_id(pid)?;
+        self.overrides.get(&id).map(|p| *p)
+    }
+
+    pub fn profile_for_container(&self, id: &str) -> Option<RandomizationProfile> {
+        self.overrides.get(id).map(|p| *p)
+    }
+
+    // Rescan runtime state; containers that are gone drop out of the map
+    pub fn refresh(&self) -> usize {
+        let mut found = Vec::new();
+        for root in &self.state_roots {
+            Self::collect_bundles(root, 0, &mut found);
+        }
+
+        let current: HashMap<String, RandomizationProfile> = found.into_iter()
+            .filter_map(|(id, config)| Self::profile_from_config(&id, &config).map(|p| (id, p)))
+            .collect();
+
+        // Update in place: clearing first would briefly un-exclude everything
+        self.overrides.retain(|id, _| current.contains_key(id));
+        for (id, profile) in current {
+            self.overrides.insert(id, profile);
+        }
+
+        self.overrides.len()
+    }
+
+    pub fn start_sync(&self, interval_secs: u64) -> tokio::task::JoinHandle<()> {
+        let sync = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let count = sync.refresh();
+                tracing::debug!("Container randomization overrides synced: {}", count);
+
+                tokio::time::sleep(tokio::time::Duration::from_secs(interval_secs)).await;
+            }
+        })
+    }
+
+    fn collect_bundles(dir: &Path, depth: u32, found: &mut Vec<(String, PathBuf)>) {
+        if depth > 3 {
+            return;
+        }
+        let config = dir.join("config.json");
+        if config.is_file() {
+            // CRI-O nests the bundle one level down in userdata/
+            let id_dir = if dir.ends_with("userdata") { dir.parent().unwrap_or(dir) } else { dir };
+            if let Some(id) = id_dir.file_name().and_then(|n| n.to_str()) {
+                found.push((id.to_string(), config));
+            }
+            return;
+        }
+
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if entry.file_type().map_or(false, |t| t.is_dir()) {
+                    Self::collect_bundles(&entry.path(), depth + 1, found);
+                }
+            }
+        }
+    }
+
+    fn profile_from_config(id: &str, config: &Path) -> Option<RandomizationProfile> {
+        let spec: serde_json::Value = serde_json::from_str(&fs::read_to_string(config).ok()?).ok()?;
+        let annotations = spec.get("annotations")?.as_object()?;
+
+        let is_sandbox = SANDBOX_ANNOTATIONS.iter()
+            .any(|(key, value)| annotations.get(*key).and_then(|v| v.as_str()) == Some(*value));
+        if is_sandbox {
+            return Some(RandomizationProfile::Excluded);
+        }
+
+        let requested = annotations.get(PROFILE_ANNOTATION)?;
+        match serde_json::from_value(requested.clone()) {
+            Ok(profile) => Some(profile),
+            Err(_) => {
+                tracing::warn!("Container {}: ignoring invalid {} value {}", id, PROFILE_ANNOTATION, requested);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn container_id(pid: u32) -> Option<String> {
+        let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+
+        cgroup.lines()
+            .filter_map(|line| line.rsplit('/').next())
+            .map(|leaf| {
+                let leaf = leaf.trim_end_matches(".scope");
+                CGROUP_PREFIXES.iter()
+                    .find_map(|prefix| leaf.strip_prefix(prefix))
+                    .unwrap_or(leaf)
+            })
+            .find(|id| id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+            .map(|id| id.to_string())
+    }
+}
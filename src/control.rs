@@ -0,0 +1,463 @@
+// src/control.rs
+// qksd's local control socket: a JSON line per request and per response,
+// with root admin, the operators group operate and anyone else read-only.
+use crate::crypto_identifiers::{Capability, ProcessToken};
+use crate::mac_profiles::ProfileFormat;
+use crate::response::ResponseAction;
+use crate::tpm::Quote;
+use serde::{Deserialize, Serialize};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/qks/control.sock";
+pub const DEFAULT_OPERATORS_GROUP: &str = "qks";
+// Tags the APT hook gives its snapshots: apt-kernel, apt-upgrade, apt-install
+pub const APT_TAG_PREFIX: &str = "apt-";
+const MAX_TAG_LEN: usize = 32;
+// A request line longer than this is not a control request
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ControlError {
+    #[error("control socket I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("control protocol: {0}")]
+    Protocol(#[from] serde_json::Error),
+    #[error("daemon closed the connection")]
+    Closed,
+    #[error("{0}")]
+    Daemon(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLevel {
+    Read,
+    // Changes what the daemon does, but not what happens to a process
+    Operate,
+    // Remaps, stops or revokes: acts on processes directly
+    Admin,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+    // Supplementary groups as of connect()
+    pub groups: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Health,
+    // Active checks of what the daemon depends on (diagnostics.rs)
+    Diagnostics,
+
+    // tag: [a-z0-9-], e.g. apt-upgrade; note: free text kept with it
+    SnapshotTake { tag: Option<String>, note: Option<String> },
+    SnapshotList,
+    SnapshotDiff { from: String, to: String },
+    // fresh: draw new layouts instead of re-applying the recorded ones
+    SnapshotRestore { id: String, fresh: bool },
+    SnapshotVerify { id: String },
+    // Restore the newest snapshot whose tag starts with `tag`
+    SnapshotRollback { tag: String, fresh: bool },
+
+    TokenIssue { pid: u32, capabilities: Vec<Capability> },
+    TokenVerify { token: ProcessToken },
+    TokenRevoke { token: ProcessToken },
+
+    MonitorStats { top: usize },
+    // Recent events of every kind but syscall and network, after a
+    // sequence number from an earlier reply
+    RecentEvents { after: u64, limit: usize },
+    ProbeGroup { group: String, enabled: bool },
+    // Network namespaces with their processes, containers and host veths
+    NetnsList,
+
+    RandomizerPlan { pid: u32 },
+    RandomizerApply { pid: u32 },
+
+    // Moved into the quarantine cgroup, left alive for inspection
+    Quarantine { pid: u32 },
+    QuarantineList,
+    // Who holds which processes frozen, and until when
+    FreezeList,
+    // Any response action, with the same checks and audit as automatic ones
+    Respond {
+        action: ResponseAction,
+        pid: Option<u32>,
+        reason: Option<String>,
+        #[serde(default)]
+        dry_run: bool,
+    },
+
+    // The attestation key and what was measured this boot
+    TpmStatus,
+    // A signed quote over the measurement PCR; nonce is hex, random when
+    // unset
+    TpmQuote { nonce: Option<String> },
+    // Controller mode: check a peer's quote against the nonce it was asked
+    // for
+    TpmVerify { quote: Quote, nonce: String },
+    // Loaded kernel modules with their files, packages and signatures
+    KernelModules,
+    // Progress of the installed-package sweep and what it found
+    PackageSweep,
+    // Secure Boot, lockdown and module signing as of the last check
+    Posture,
+    // IMA appraisal, EVM and measurement-list checks
+    ImaStatus,
+    // Protected volumes and verity devices as of the last check
+    VerityStatus,
+    // Fleet role, and the controller's agents or the agent's link
+    FleetStatus,
+    // Threat intelligence feeds and what was indexed from them
+    IntelStatus,
+
+    // Sealed audit segments
+    AuditList,
+    // Signed bundle of the audit records between since and until (epoch
+    // seconds, open-ended when unset), written by qksd to `output`
+    AuditExport { since: Option<u64>, until: Option<u64>, output: PathBuf },
+
+    // Confinement profile drafts and where each is in review
+    ProfileList,
+    // A draft as AppArmor or SELinux policy text
+    ProfileShow { binary: PathBuf, format: ProfileFormat },
+    // Written by qksd to `output`
+    ProfileExport { binary: PathBuf, format: ProfileFormat, output: PathBuf },
+    // complain: enforce in AppArmor's complain mode
+    ProfileApprove { binary: PathBuf, complain: bool },
+    // Load the approved AppArmor profile
+    ProfileEnforce { binary: PathBuf },
+
+    // Latest pipeline score for the PID, with its explanation
+    DetectorScore { pid: u32 },
+    // The highest latest scores, highest first
+    DetectorTop { top: usize },
+    // Reload the configured model (or another path) now
+    DetectorReload { path: Option<String> },
+}
+
+impl std::fmt::Display for AccessLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AccessLevel::Read => "read",
+            AccessLevel::Operate => "operate",
+            AccessLevel::Admin => "admin",
+        })
+    }
+}
+
+// Snapshot tags go into file listings and metric labels as they are
+pub fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.len() <= MAX_TAG_LEN && tag.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+impl ControlRequest {
+    // The wire name; what the audit log records instead of the body, which
+    // may carry tokens
+    pub fn op(&self) -> &'static str {
+        match self {
+            ControlRequest::Health => "health",
+            ControlRequest::Diagnostics => "diagnostics",
+            ControlRequest::SnapshotTake { .. } => "snapshot-take",
+            ControlRequest::SnapshotList => "snapshot-list",
+            ControlRequest::SnapshotDiff { .. } => "snapshot-diff",
+            ControlRequest::SnapshotRestore { .. } => "snapshot-restore",
+            ControlRequest::SnapshotRollback { .. } => "snapshot-rollback",
+            ControlRequest::SnapshotVerify { .. } => "snapshot-verify",
+            ControlRequest::TokenIssue { .. } => "token-issue",
+            ControlRequest::TokenVerify { .. } => "token-verify",
+            ControlRequest::TokenRevoke { .. } => "token-revoke",
+            ControlRequest::MonitorStats { .. } => "monitor-stats",
+            ControlRequest::RecentEvents { .. } => "recent-events",
+            ControlRequest::ProbeGroup { .. } => "probe-group",
+            ControlRequest::NetnsList => "netns-list",
+            ControlRequest::RandomizerPlan { .. } => "randomizer-plan",
+            ControlRequest::RandomizerApply { .. } => "randomizer-apply",
+            ControlRequest::Quarantine { .. } => "quarantine",
+            ControlRequest::QuarantineList => "quarantine-list",
+            ControlRequest::FreezeList => "freeze-list",
+            ControlRequest::Respond { .. } => "respond",
+            ControlRequest::TpmStatus => "tpm-status",
+            ControlRequest::TpmQuote { .. } => "tpm-quote",
+            ControlRequest::TpmVerify { .. } => "tpm-verify",
+            ControlRequest::KernelModules => "kernel-modules",
+            ControlRequest::PackageSweep => "package-sweep",
+            ControlRequest::Posture => "posture",
+            ControlRequest::ImaStatus => "ima-status",
+            ControlRequest::VerityStatus => "verity-status",
+            ControlRequest::FleetStatus => "fleet-status",
+            ControlRequest::IntelStatus => "intel-status",
+            ControlRequest::AuditList => "audit-list",
+            ControlRequest::AuditExport { .. } => "audit-export",
+            ControlRequest::ProfileList => "profile-list",
+            ControlRequest::ProfileShow { .. } => "profile-show",
+            ControlRequest::ProfileExport { .. } => "profile-export",
+            ControlRequest::ProfileApprove { .. } => "profile-approve",
+            ControlRequest::ProfileEnforce { .. } => "profile-enforce",
+            ControlRequest::DetectorScore { .. } => "detector-score",
+            ControlRequest::DetectorTop { .. } => "detector-top",
+            ControlRequest::DetectorReload { .. } => "detector-reload",
+        }
+    }
+
+    pub fn required_level(&self) -> AccessLevel {
+        match self {
+            ControlRequest::Health
+            | ControlRequest::Diagnostics
+            | ControlRequest::SnapshotList
+            | ControlRequest::SnapshotDiff { .. }
+            | ControlRequest::SnapshotVerify { .. }
+            | ControlRequest::TokenVerify { .. }
+            | ControlRequest::MonitorStats { .. }
+            | ControlRequest::RecentEvents { .. }
+            | ControlRequest::NetnsList
+            | ControlRequest::RandomizerPlan { .. }
+            | ControlRequest::QuarantineList
+            | ControlRequest::FreezeList
+            | ControlRequest::TpmStatus
+            | ControlRequest::TpmQuote { .. }
+            | ControlRequest::TpmVerify { .. }
+            | ControlRequest::KernelModules
+            | ControlRequest::PackageSweep
+            | ControlRequest::Posture
+            | ControlRequest::ImaStatus
+            | ControlRequest::VerityStatus
+            | ControlRequest::FleetStatus
+            | ControlRequest::IntelStatus
+            | ControlRequest::AuditList
+            | ControlRequest::ProfileList
+            | ControlRequest::ProfileShow { .. }
+            | ControlRequest::DetectorScore { .. }
+            | ControlRequest::DetectorTop { .. } => AccessLevel::Read,
+            ControlRequest::SnapshotTake { .. } | ControlRequest::ProbeGroup { .. } | ControlRequest::DetectorReload { .. } => AccessLevel::Operate,
+            // Issuing grants capabilities, so it ranks with revoking
+            ControlRequest::SnapshotRestore { .. }
+            | ControlRequest::SnapshotRollback { .. }
+            | ControlRequest::TokenIssue { .. }
+            | ControlRequest::TokenRevoke { .. }
+            | ControlRequest::RandomizerApply { .. }
+            | ControlRequest::Quarantine { .. }
+            | ControlRequest::Respond { .. }
+            | ControlRequest::ProfileApprove { .. }
+            | ControlRequest::ProfileEnforce { .. }
+            // Write wherever they are told, as root
+            | ControlRequest::AuditExport { .. }
+            | ControlRequest::ProfileExport { .. } => AccessLevel::Admin,
+        }
+    }
+}
+
+// Maps a peer's credentials to what it may do
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAuthorizer {
+    operators_gid: Option<u32>,
+}
+
+impl PeerAuthorizer {
+    // An unknown group leaves root as the only privileged caller
+    pub fn new(operators_group: &str) -> Self {
+        let operators_gid = group_id(operators_group);
+        if operators_gid.is_none() {
+            tracing::warn!("Operators group {:?} does not exist; only root may change anything over the control socket", operators_group);
+        }
+        Self { operators_gid }
+    }
+
+    pub fn operators_gid(&self) -> Option<u32> {
+        self.operators_gid
+    }
+
+    pub fn level_for(&self, peer: &PeerCredentials) -> AccessLevel {
+        if peer.uid == 0 {
+            return AccessLevel::Admin;
+        }
+        match self.operators_gid {
+            Some(gid) if peer.gid == gid || peer.groups.contains(&gid) => AccessLevel::Operate,
+            _ => AccessLevel::Read,
+        }
+    }
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // getgrnam's buffer is static; called once at startup
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!group.is_null()).then(|| unsafe { (*group).gr_gid })
+}
+
+// SO_PEERCRED carries only the primary group. SO_PEERGROUPS is captured by
+// the kernel at connect(), so unlike /proc/<pid>/status it can't be swapped
+// by a PID reuse or a setgroups() after the fact.
+fn peer_groups(stream: &UnixStream) -> std::io::Result<Vec<u32>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut len = (groups.len() * std::mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERGROUPS, groups.as_mut_ptr() as *mut libc::c_void, &mut len)
+        };
+        if rc == 0 {
+            groups.truncate(len as usize / std::mem::size_of::<libc::gid_t>());
+            return Ok(groups);
+        }
+        let err = std::io::Error::last_os_error();
+        // len now holds the size needed
+        if err.raw_os_error() == Some(libc::ERANGE) && len as usize > groups.len() * std::mem::size_of::<libc::gid_t>() {
+            groups.resize(len as usize / std::mem::size_of::<libc::gid_t>(), 0);
+            continue;
+        }
+        return Err(err);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", content = "body", rename_all = "lowercase")]
+pub enum ControlResponse {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+impl ControlResponse {
+    pub fn ok<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => ControlResponse::Ok(value),
+            Err(e) => ControlResponse::Error(format!("unserializable response: {}", e)),
+        }
+    }
+
+    pub fn error(message: impl std::fmt::Display) -> Self {
+        ControlResponse::Error(message.to_string())
+    }
+}
+
+pub type ControlHandler = Arc<dyn Fn(ControlRequest) -> ControlResponse + Send + Sync>;
+
+// Serve until the task is aborted. Handlers may block (ptrace, model
+// loads), so each request runs on the blocking pool.
+pub fn serve(path: &Path, handler: ControlHandler, authorizer: PeerAuthorizer) -> std::io::Result<tokio::task::JoinHandle<()>> {
+    let listener = bind(path, &authorizer)?;
+    Ok(serve_listener(listener, handler, authorizer))
+}
+
+pub fn bind(path: &Path, authorizer: &PeerAuthorizer) -> std::io::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // A stale socket from a previous run refuses the bind
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    match authorizer.operators_gid() {
+        Some(gid) => {
+            std::os::unix::fs::chown(path, Some(0), Some(gid))?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+        }
+        None => std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?,
+    }
+    Ok(listener)
+}
+
+// For a listener bound elsewhere, e.g. passed in by socket activation
+pub fn serve_listener(listener: UnixListener, handler: ControlHandler, authorizer: PeerAuthorizer) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Control socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(stream, handler, authorizer).await {
+                    tracing::debug!("Control connection ended: {}", e);
+                }
+            });
+        }
+    })
+}
+
+async fn serve_connection(stream: UnixStream, handler: ControlHandler, authorizer: PeerAuthorizer) -> Result<(), ControlError> {
+    let cred = stream.peer_cred()?;
+    let peer = PeerCredentials { uid: cred.uid(), gid: cred.gid(), pid: cred.pid(), groups: peer_groups(&stream)? };
+    let level = authorizer.level_for(&peer);
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if (&mut lines).take(MAX_REQUEST_BYTES as u64 + 1).read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        // The rest of an oversized line is still unread; drop the connection
+        if line.len() > MAX_REQUEST_BYTES {
+            let mut out = serde_json::to_vec(&ControlResponse::error("request too large"))?;
+            out.push(b'\n');
+            write.write_all(&out).await?;
+            return Ok(());
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) if request.required_level() > level => {
+                tracing::warn!(uid = peer.uid, pid = peer.pid, "Control {} from uid {} (pid {:?}) denied at {} level", request.op(), peer.uid, peer.pid, level);
+                ControlResponse::error(format!("permission denied: {} needs {} access, caller has {}", request.op(), request.required_level(), level))
+            }
+            Ok(request) => {
+                // Anything beyond a read is worth a record of who asked
+                if request.required_level() > AccessLevel::Read {
+                    tracing::info!(uid = peer.uid, pid = peer.pid, "Control {} from uid {} (pid {:?})", request.op(), peer.uid, peer.pid);
+                } else {
+                    tracing::debug!("Control {} from uid {}", request.op(), peer.uid);
+                }
+                let handler = handler.clone();
+                tokio::task::spawn_blocking(move || handler(request)).await
+                    .unwrap_or_else(|e| ControlResponse::error(format!("handler failed: {}", e)))
+            }
+            Err(e) => ControlResponse::error(format!("malformed request: {}", e)),
+        };
+
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+    }
+}
+
+pub struct ControlClient {
+    lines: BufReader<tokio::net::unix::OwnedReadHalf>,
+    write: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl ControlClient {
+    pub async fn connect(path: &Path) -> Result<Self, ControlError> {
+        let (read, write) = UnixStream::connect(path).await?.into_split();
+        Ok(Self { lines: BufReader::new(read), write })
+    }
+
+    // The daemon's error responses come back as ControlError::Daemon
+    pub async fn request(&mut self, request: &ControlRequest) -> Result<serde_json::Value, ControlError> {
+        let mut out = serde_json::to_vec(request)?;
+        out.push(b'\n');
+        self.write.write_all(&out).await?;
+
+        let mut line = String::new();
+        if self.lines.read_line(&mut line).await? == 0 {
+            return Err(ControlError::Closed);
+        }
+        match serde_json::from_str(&line)? {
+            ControlResponse::Ok(value) => Ok(value),
+            ControlResponse::Error(message) => Err(ControlError::Daemon(message)),
+        }
+    }
+}
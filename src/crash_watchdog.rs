@@ -0,0 +1,375 @@
+// src/crash_watchdog.rs
+// Restarts a critical service that crash-loops after a detection against
+// it, into what the last snapshot before the detection recorded for it.
+use crate::config::WatchdogSection;
+use crate::crypto_identifiers::Capability;
+use crate::daemon::Control;
+use crate::events::{EventKind, ResponseEvent, SecurityEvent};
+use crate::metrics;
+use crate::privsep::{self, UnitAction};
+use crate::process_maps;
+use crate::response::{ResponseAction, ResponseRequest};
+use crate::systemd;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Responses that mean a process was caught doing something
+const COMPROMISE_ACTIONS: &[&str] = &["kill", "stop", "quarantine", "block_egress"];
+const START_POLL: Duration = Duration::from_millis(500);
+// Tag of the snapshots taken to have one from before a detection
+const SNAPSHOT_TAG: &str = "watchdog";
+
+// What systemctl show says about a unit
+struct UnitState {
+    restarts: u64,
+    active: String,
+    main_pid: u32,
+}
+
+// Detections against one service's processes
+struct Compromise {
+    // Unix seconds; snapshots from before `first` are candidates
+    first: u64,
+    last: u64,
+    pids: BTreeSet<u32>,
+    reason: String,
+}
+
+#[derive(Default)]
+struct Service {
+    // At the last poll
+    restarts: Option<u64>,
+    failed: bool,
+    // When each crash was seen
+    crashes: VecDeque<u64>,
+    compromise: Option<Compromise>,
+    last_rollback: Option<u64>,
+}
+
+impl Service {
+    // True when the crashes inside the window make a loop
+    fn observe(&mut self, state: &UnitState, now: u64, section: &WatchdogSection) -> bool {
+        if let Some(previous) = self.restarts {
+            // NRestarts starts over when the unit is started by hand
+            let new = state.restarts.saturating_sub(previous).min(section.crash_restarts as u64);
+            self.crashes.extend(std::iter::repeat(now).take(new as usize));
+        }
+        let failed = state.active == "failed";
+        if failed && !self.failed {
+            self.crashes.push_back(now);
+        }
+        self.restarts = Some(state.restarts);
+        self.failed = failed;
+        while self.crashes.front().is_some_and(|at| now.saturating_sub(*at) > section.crash_window_secs) {
+            self.crashes.pop_front();
+        }
+        self.crashes.len() >= section.crash_restarts as usize
+    }
+}
+
+pub struct CrashWatchdog {
+    section: WatchdogSection,
+    control: Arc<Control>,
+    services: Mutex<HashMap<String, Service>>,
+    last_snapshot: Mutex<Option<u64>>,
+}
+
+impl CrashWatchdog {
+    pub(crate) fn new(section: &WatchdogSection, control: Arc<Control>) -> Self {
+        let services = section.services.iter().map(|unit| (unit.clone(), Service::default())).collect();
+        Self { section: section.clone(), control, services: Mutex::new(services), last_snapshot: Mutex::new(None) }
+    }
+
+    pub(crate) fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let mut events = self.control.events.subscribe_to(&[EventKind::Anomaly, EventKind::Response, EventKind::Integrity]).with_name("Crash-loop watchdog");
+        let mut poll = tokio::time::interval(Duration::from_secs(self.section.poll_secs));
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Some(event) => self.note(&event),
+                        None => return,
+                    },
+                    _ = poll.tick() => {
+                        // systemctl, and any rollback, block
+                        let watchdog = self.clone();
+                        if let Err(e) = tokio::task::spawn_blocking(move || watchdog.check()).await {
+                            tracing::error!("Crash-loop check failed: {}", e);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    // Records a detection against a watched service's process
+    fn note(&self, event: &SecurityEvent) {
+        let (pid, reason) = match event {
+            SecurityEvent::Anomaly(e) => (e.pid, format!("anomaly scoring {:.3}", e.score)),
+            SecurityEvent::Response(e) if e.succeeded && e.outcome == "executed" && COMPROMISE_ACTIONS.contains(&e.action.as_str()) => match e.pid {
+                Some(pid) => (pid, format!("response {}", e.action)),
+                None => return,
+            },
+            SecurityEvent::Integrity(e) if e.critical => match e.pid {
+                Some(pid) => (pid, format!("{} {}", e.source, e.finding)),
+                None => return,
+            },
+            _ => return,
+        };
+        let Some(unit) = self.unit_of(pid) else {
+            return;
+        };
+        let now = systemd::now_secs();
+        let mut services = self.services.lock().unwrap();
+        let Some(service) = services.get_mut(unit) else {
+            return;
+        };
+        tracing::info!(pid, service = unit, "Detection against {} (PID {}): {}", unit, pid, reason);
+        match service.compromise.as_mut() {
+            Some(compromise) if now.saturating_sub(compromise.last) <= self.section.compromise_window_secs => {
+                compromise.last = now;
+                compromise.pids.insert(pid);
+            }
+            _ => service.compromise = Some(Compromise { first: now, last: now, pids: BTreeSet::from([pid]), reason }),
+        }
+    }
+
+    // The watched service whose cgroup holds the process
+    fn unit_of(&self, pid: u32) -> Option<&str> {
+        let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+        let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+        path.split('/').find_map(|part| self.section.services.iter().find(|unit| unit.as_str() == part)).map(String::as_str)
+    }
+
+    fn check(&self) {
+        let now = systemd::now_secs();
+        let mut due = Vec::new();
+        for unit in &self.section.services {
+            let state = match show(unit) {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::debug!(service = unit.as_str(), "Watchdog could not read {}: {}", unit, e);
+                    continue;
+                }
+            };
+            let mut services = self.services.lock().unwrap();
+            let service = services.entry(unit.clone()).or_default();
+            if !service.observe(&state, now, &self.section) {
+                continue;
+            }
+            // A crash loop nothing was detected before is systemd's to handle
+            let after_detection = service.compromise.as_ref().is_some_and(|c| now.saturating_sub(c.last) <= self.section.compromise_window_secs);
+            let cooling_down = service.last_rollback.is_some_and(|at| now.saturating_sub(at) < self.section.cooldown_secs);
+            if !after_detection || cooling_down {
+                continue;
+            }
+            if let Some(compromise) = service.compromise.take() {
+                service.last_rollback = Some(now);
+                service.crashes.clear();
+                due.push((unit.clone(), compromise));
+            }
+        }
+        for (unit, compromise) in due {
+            self.roll_back(&unit, &compromise);
+        }
+        self.snapshot_if_due(now);
+    }
+
+    // Only while nothing is suspected, or the snapshot could record what
+    // an attacker changed
+    fn snapshot_if_due(&self, now: u64) {
+        if !self.section.rollback || self.section.snapshot_secs == 0 {
+            return;
+        }
+        let mut last = self.last_snapshot.lock().unwrap();
+        if last.is_some_and(|at| now.saturating_sub(at) < self.section.snapshot_secs) {
+            return;
+        }
+        let open = self.services.lock().unwrap().values()
+            .filter_map(|service| service.compromise.as_ref())
+            .any(|compromise| now.saturating_sub(compromise.last) <= self.section.compromise_window_secs);
+        if open {
+            return;
+        }
+        *last = Some(now);
+        match self.control.response.take_snapshot(Some(SNAPSHOT_TAG), None) {
+            Ok(summary) => tracing::debug!("Watchdog took snapshot {}", summary.snapshot_id),
+            Err(e) => tracing::warn!("Watchdog snapshot not taken: {}", e),
+        }
+    }
+
+    fn roll_back(&self, unit: &str, compromise: &Compromise) {
+        let now = systemd::now_secs();
+        tracing::warn!(
+            service = unit,
+            "{} is crash-looping {}s after a detection ({}; PIDs {:?})",
+            unit,
+            now.saturating_sub(compromise.first),
+            compromise.reason,
+            compromise.pids
+        );
+        let dry_run = !self.section.rollback || self.control.config.lock().unwrap().response.dry_run;
+        let snapshot = self.known_good(compromise.first);
+        let (outcome, detail) = if dry_run {
+            let plan = match snapshot.as_deref() {
+                Some(id) => format!("restart {} with a fresh layout and the state {} recorded for it", unit, id),
+                None => format!("restart {} with a fresh layout", unit),
+            };
+            ("dry_run", format!("would {}", plan))
+        } else {
+            match self.restore(unit, compromise, snapshot.as_deref()) {
+                Ok(detail) => ("executed", detail),
+                Err(e) => ("failed", e),
+            }
+        };
+        metrics::global().incr("qks_watchdog_rollbacks_total", &[("service", unit), ("outcome", outcome)]);
+        tracing::warn!(service = unit, outcome, "Rollback of {}: {}: {}", unit, outcome, detail);
+        self.control.events.publish(SecurityEvent::Response(ResponseEvent {
+            action: "rollback".to_string(),
+            pid: None,
+            outcome: outcome.to_string(),
+            succeeded: outcome != "failed",
+            detail: Some(format!("{}: {}", unit, detail)),
+            timestamp: now,
+        }));
+    }
+
+    // Newest first; verifying publishes its own events
+    fn known_good(&self, before_secs: u64) -> Option<String> {
+        // Snapshot timestamps are nanoseconds
+        let before = before_secs.saturating_mul(1_000_000_000);
+        let snapshots = match self.control.snapshots.list_snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                tracing::warn!("Snapshots unreadable for a rollback: {}", e);
+                return None;
+            }
+        };
+        snapshots.into_iter().rev().filter(|snapshot| snapshot.timestamp < before).map(|snapshot| snapshot.snapshot_id).find(|id| {
+            match self.control.verify_snapshot(id) {
+                Ok(valid) => valid,
+                Err(e) => {
+                    tracing::warn!(snapshot_id = id.as_str(), "Snapshot {} not verified: {}", id, e);
+                    false
+                }
+            }
+        })
+    }
+
+    // Ok carries what was done, for the event. The new main process always
+    // gets a fresh layout: a recorded one is known to whoever attacked the
+    // service. From the snapshot come its randomization profile and the
+    // capabilities its tokens held, in case either was changed since.
+    fn restore(&self, unit: &str, compromise: &Compromise, snapshot: Option<&str>) -> Result<String, String> {
+        let mut notes = Vec::new();
+        let mut capabilities: Vec<Capability> = Vec::new();
+        for pid in &compromise.pids {
+            if let Some(token) = self.control.response.retire_token(*pid) {
+                self.control.revoke_token(&token, "the crash-loop watchdog");
+                for capability in token.capabilities {
+                    if !capabilities.contains(&capability) {
+                        capabilities.push(capability);
+                    }
+                }
+            }
+        }
+
+        privsep::unit(unit, UnitAction::Stop).map_err(|e| format!("stop: {}", e))?;
+        // Clears the start limit a crash loop runs into
+        privsep::unit(unit, UnitAction::ResetFailed).map_err(|e| format!("reset-failed: {}", e))?;
+        let started = process_maps::boot_ticks();
+        privsep::unit(unit, UnitAction::Start).map_err(|e| format!("start: {}", e))?;
+
+        let pid = self.main_pid(unit)?;
+        // A main PID from before the start is a stale answer, not the new
+        // process
+        let Some(identity) = process_maps::identity(pid).filter(|identity| identity.start_time >= started) else {
+            return Err(format!("PID {} is not a process started by the restart", pid));
+        };
+        match snapshot.map(|id| (id, self.control.snapshots.service_state(id, &identity.exe))) {
+            Some((id, Ok(state))) if state.processes > 0 => {
+                let mut randomizer = self.control.randomizer.lock().unwrap();
+                if randomizer.policy().profile_for_exe(&identity.exe) != state.profile {
+                    let mut policy = randomizer.policy().clone();
+                    policy.binaries.insert(identity.exe.clone(), state.profile);
+                    randomizer.set_policy(policy);
+                    notes.push(format!("randomization profile of {} back to {:?} from {}", identity.exe, state.profile, id));
+                }
+                if !state.capabilities.is_empty() {
+                    capabilities = state.capabilities;
+                    notes.push(format!("token capabilities from {}", id));
+                }
+            }
+            Some((id, Ok(_))) => notes.push(format!("{} recorded no process of {}", id, identity.exe)),
+            Some((id, Err(e))) => notes.push(format!("{} unreadable: {}", id, e)),
+            None => notes.push("no snapshot from before the detection verifies".to_string()),
+        }
+
+        let record = self.control.response.execute(ResponseRequest {
+            action: ResponseAction::Rerandomize,
+            pid: Some(pid),
+            origin: format!("watchdog:{}", unit),
+            reason: "restarted after a crash loop".to_string(),
+            dry_run: false,
+        });
+        notes.push(format!("fresh layout for PID {}: {}", pid, record.outcome.as_str()));
+        if !capabilities.is_empty() {
+            match self.control.issue_token(pid, &capabilities) {
+                Ok(_) => notes.push(format!("fresh token for PID {}", pid)),
+                Err(e) => notes.push(format!("no fresh token for PID {}: {:?}", pid, e)),
+            }
+        }
+        Ok(notes.join("; "))
+    }
+
+    fn main_pid(&self, unit: &str) -> Result<u32, String> {
+        let deadline = Instant::now() + Duration::from_secs(self.section.start_timeout_secs);
+        loop {
+            match show(unit) {
+                Ok(state) if state.active == "active" && state.main_pid != 0 => return Ok(state.main_pid),
+                Ok(_) => {}
+                Err(e) => return Err(format!("show: {}", e)),
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("no main PID {}s after starting", self.section.start_timeout_secs));
+            }
+            std::thread::sleep(START_POLL);
+        }
+    }
+}
+
+// A name systemctl won't read as an option or a pattern
+pub fn valid_unit(name: &str) -> bool {
+    name.len() > ".service".len()
+        && name.ends_with(".service")
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || ":-_.@\\".contains(c))
+}
+
+// Stdout of a successful run
+pub(crate) fn systemctl(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("systemctl").args(args).stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        let verb = args.first().copied().unwrap_or_default();
+        return Err(io::Error::new(io::ErrorKind::Other, format!("systemctl {}: {}", verb, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn show(unit: &str) -> io::Result<UnitState> {
+    let output = systemctl(&["show", "--property=NRestarts,ActiveState,MainPID", "--", unit])?;
+    let mut state = UnitState { restarts: 0, active: String::new(), main_pid: 0 };
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("NRestarts", value)) => state.restarts = value.trim().parse().unwrap_or(0),
+            Some(("ActiveState", value)) => state.active = value.trim().to_string(),
+            Some(("MainPID", value)) => state.main_pid = value.trim().parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    Ok(state)
+}
@@ -0,0 +1,1487 @@
+// src/daemon.rs
+// qksd's runtime: brings the subsystems up in dependency order (identity
+// and token issuer, snapshots, randomizer, detector, then the eBPF monitor
+// that feeds it), supervises their tasks, and tears them down in reverse on
+// shutdown. A subsystem that cannot start on this host (no BCC, no model)
+// degrades the daemon rather than preventing it from running.
+use crate::anomaly_events::{AnomalyEnricher, AnomalyEvent};
+use crate::arena_broker::ArenaBroker;
+use crate::behavior_profiles::BehaviorProfiles;
+use crate::drift_monitor::ModelDrift;
+use crate::audit_log::{self, AuditLog};
+use crate::capability_metering::{CapabilityMeter, MeteringViolation, UsageSource};
+use crate::config::{FleetRole, QksConfig, PROBE_GROUPS};
+use crate::container_events::{ContainerRuntime, ContainerWatcher};
+use crate::control::{self, ControlRequest, ControlResponse};
+use crate::crash_watchdog::CrashWatchdog;
+use crate::crypto_identifiers::{Capability, CryptoIdentifier, ProcessToken, RevocationProof};
+use crate::dbus_api;
+use crate::diagnostics::{self, Diagnostics};
+use crate::detector_selftest::{self, DetectionHealth};
+use crate::dm_verity::VerityMonitor;
+use crate::docker_events::DockerWatcher;
+use crate::ebpf_monitor::{EBPFMonitor, NetworkAction, NetworkEvent, SyscallEvent, TelemetrySource, SYSCALL_QUEUE};
+use crate::elasticsearch::Elasticsearch;
+use crate::event_forward;
+use crate::events::{EventBus, EventHistory, EventKind, SecurityEvent, SnapshotEvent, TokenEvent};
+use crate::freezer;
+use crate::feature_pipeline::{FeaturePipeline, ScoredProcess};
+use crate::fleet::Fleet;
+use crate::intel::Intel;
+use crate::grpc_api;
+use crate::host_posture::PostureMonitor;
+use crate::ima::ImaMonitor;
+use crate::metrics;
+use crate::netns;
+use crate::notify::Notifications;
+use crate::package_sweep::PackageSweep;
+use crate::policy::{PolicyEngine, PolicyError};
+use crate::privsep::{self, PrivsepError};
+use crate::self_sandbox;
+use crate::proc_connector::ProcConnector;
+use crate::proc_poll::ProcPoller;
+use crate::prometheus;
+use crate::quarantine;
+use crate::response::{Responder, ResponseAction, ResponseRequest};
+use crate::rest_api;
+use crate::splunk_hec::SplunkHec;
+use crate::syslog_sink;
+use crate::systemd;
+use crate::tpm::{self, QuoteVerifier, Tpm};
+use crate::inference_backend::InferenceError;
+use crate::kernel_modules::{self, ModuleMonitor};
+use crate::kubernetes::PodWatcher;
+use crate::mac_profiles::ProfileGenerator;
+use crate::kafka_sink::Kafka;
+use crate::memory_randomizer::{LayoutChangeEvent, LayoutRestoreMode, MemoryRandomizer};
+use crate::ml_detector::MLAnomalyDetector;
+use crate::model_signing::{decode_hex, ModelTrust};
+use crate::randomization_scheduler::RandomizationScheduler;
+use crate::recovery_snapshot::SnapshotManager;
+use crate::secure_random::{SecureRandomSource, SystemRandomSource};
+use crate::token_status::{StatusRequest, StatusResponse, TokenStatusResponder};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio::task::JoinHandle;
+
+const SUPERVISE_INTERVAL_SECS: u64 = 5;
+// Restarts allowed per task before it is left failed
+const MAX_RESTARTS: u32 = 5;
+const TOKEN_STATUS_QUEUE: usize = 256;
+// Latest scores kept for `qksctl detector score`; exited PIDs are pruned
+// once the table reaches this size
+const MAX_LATEST_SCORES: usize = 4096;
+const TOP_CONTRIBUTIONS: usize = 3;
+const NETNS_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const PROFILES_DISABLED: &str = "profile drafting is not enabled ([profiles] enabled)";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error("host identity: {0}")]
+    Identity(String),
+    #[error("detector: {0}")]
+    Detector(#[from] InferenceError),
+    #[error("policy: {0}")]
+    Policy(#[from] PolicyError),
+    #[error("daemon I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("privilege separation: {0}")]
+    Privsep(#[from] PrivsepError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum SubsystemHealth {
+    Starting,
+    Running,
+    Degraded { reason: String },
+    Failed { reason: String },
+    Stopped,
+}
+
+struct SupervisedTask {
+    subsystem: &'static str,
+    name: &'static str,
+    handle: JoinHandle<()>,
+    // Re-spawns the task; None for tasks wired to channels that died with it
+    restart: Option<Box<dyn Fn() -> JoinHandle<()> + Send>>,
+    restarts: u32,
+    // Failure already reported
+    failed: bool,
+}
+
+pub struct Daemon {
+    identity: Arc<CryptoIdentifier>,
+    detector: Arc<Mutex<MLAnomalyDetector>>,
+    randomizer: Arc<Mutex<MemoryRandomizer>>,
+    snapshots: Arc<SnapshotManager>,
+    events: Arc<EventBus>,
+    policy: Arc<PolicyEngine>,
+    token_status: mpsc::Sender<(StatusRequest, oneshot::Sender<StatusResponse>)>,
+    health: Arc<DashMap<&'static str, SubsystemHealth>>,
+    // In startup order; stopped in reverse
+    tasks: Arc<Mutex<Vec<SupervisedTask>>>,
+    config: Arc<Mutex<Arc<QksConfig>>>,
+    // Unix time of the supervisor's last pass; the systemd watchdog checks it
+    heartbeat: Arc<AtomicU64>,
+}
+
+impl Daemon {
+    pub async fn start(config: QksConfig) -> Result<Self, DaemonError> {
+        diagnostics::record_clock();
+        let health = Arc::new(DashMap::new());
+        let tasks = Arc::new(Mutex::new(Vec::new()));
+        std::fs::create_dir_all(&config.daemon.state_dir)?;
+        // Before anything acts on a process, so every subsystem goes through
+        // the helper from the start; root is dropped once all is running
+        let privsep = config.privsep.clone();
+        let owned = [config.daemon.state_dir.clone(), config.snapshot_dir(), config.response_audit_log()];
+        if privsep.enabled {
+            health.insert("privsep", SubsystemHealth::Starting);
+            privsep::connect(&privsep.helper_socket)?;
+            tracing::info!("Privileged operations go through qks-helper on {}", privsep.helper_socket.display());
+        }
+        // Everything below publishes here rather than calling its consumers
+        let bus = Arc::new(EventBus::new());
+
+        // 1. Identity and token issuer: everything else signs with it
+        health.insert("tokens", SubsystemHealth::Starting);
+        // A fleet ID is derived from the key, so it must outlive restarts
+        let identity = if config.fleet.role == FleetRole::Off {
+            CryptoIdentifier::new().map_err(|e| DaemonError::Identity(format!("{:?}", e)))?
+        } else {
+            CryptoIdentifier::load_or_create(&config.identity_key())
+                .map_err(|e| DaemonError::Identity(format!("{}: {}", config.identity_key().display(), e)))?
+        };
+        let identity = Arc::new(
+            identity
+                .with_token_lifetime(config.tokens.lifetime_secs)
+                .with_skew_tolerance(config.tokens.clock_skew_secs),
+        );
+        let (token_status, requests) = mpsc::channel(TOKEN_STATUS_QUEUE);
+        let responder = TokenStatusResponder::new(identity.clone())
+            .with_response_validity(config.tokens.status_validity_secs);
+        let responder = Arc::new(responder);
+        Self::supervise(&tasks, "tokens", "token-status", responder.clone().start_serving(requests), None);
+        health.insert("tokens", SubsystemHealth::Running);
+
+        // The TPM next, so the model load below is measured too. A
+        // controller verifies peers' quotes whether or not it has one.
+        let tpm = if config.tpm.enabled {
+            health.insert("tpm", SubsystemHealth::Starting);
+            match Tpm::open(&config.tpm, config.tpm_event_log()) {
+                Ok(device) => {
+                    let device = Arc::new(device);
+                    Self::supervise(&tasks, "tpm", "tpm-measurements", tpm::start(device.clone()), None);
+                    tpm::measure_json("config_load", &config);
+                    health.insert("tpm", SubsystemHealth::Running);
+                    Some(device)
+                }
+                Err(e) => {
+                    tracing::error!("TPM attestation unavailable: {}", e);
+                    health.insert("tpm", SubsystemHealth::Failed { reason: e.to_string() });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let quote_verifier = if config.tpm.controller {
+            match QuoteVerifier::from_key_files(&config.tpm.trusted_aks) {
+                Ok(verifier) => Some(Arc::new(verifier)),
+                Err(e) => {
+                    tracing::error!("Quote verification unavailable: {}", e);
+                    health.insert("tpm", SubsystemHealth::Failed { reason: e.to_string() });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // 2. Snapshots, before anything that may need restoring
+        health.insert("snapshots", SubsystemHealth::Starting);
+        let snapshot_dir = config.snapshot_dir();
+        std::fs::create_dir_all(&snapshot_dir)?;
+        let snapshots = SnapshotManager::new(&snapshot_dir.to_string_lossy())
+            .with_max_snapshots(config.snapshots.retention_count)
+            .with_verity_volumes(config.integrity.verity.volumes.clone());
+        let snapshots = Arc::new(snapshots);
+        health.insert("snapshots", SubsystemHealth::Running);
+
+        // 3. Randomizer and its scheduler
+        health.insert("randomizer", SubsystemHealth::Starting);
+        let randomizer = Arc::new(Mutex::new(MemoryRandomizer::new()));
+        let scheduler = RandomizationScheduler::new(randomizer.clone())
+            .with_max_cpu_percent(config.randomizer.max_cpu_percent)
+            .with_apply(!config.randomizer.plan_only);
+        let scheduler = Arc::new(scheduler);
+        let restart = scheduler.clone();
+        Self::supervise(&tasks, "randomizer", "scheduler", scheduler.start(), Some(Box::new(move || restart.start())));
+        let layouts = randomizer.lock().unwrap().subscribe_layout_events();
+        Self::supervise(&tasks, "randomizer", "layout-events", Self::publish_layouts(layouts, bus.clone()), None);
+        // Relocation tables registered here are what let heap moves go ahead
+        if let Some(socket) = &config.randomizer.arena_socket {
+            let broker = ArenaBroker::new(socket, randomizer.lock().unwrap().heap_fixups().clone());
+            match broker.start() {
+                Ok(handle) => Self::supervise(&tasks, "randomizer", "arena-broker", handle, None),
+                Err(e) => tracing::error!("Arena broker on {} unavailable: {}", socket.display(), e),
+            }
+        }
+        health.insert("randomizer", SubsystemHealth::Running);
+
+        // 4. Detector; a bad model is fatal, no model is not
+        health.insert("detector", SubsystemHealth::Starting);
+        let trust = if config.detector.insecure_models {
+            tracing::warn!("Model signature verification disabled");
+            ModelTrust::Insecure
+        } else {
+            ModelTrust::from_key_files(&config.detector.trusted_keys)?
+        };
+        let mut detector = match &config.detector.model_path {
+            Some(path) => MLAnomalyDetector::new_with_options(path, trust, config.inference.options())?,
+            None => {
+                health.insert("detector", SubsystemHealth::Degraded { reason: "no model; scoring on the learned baseline".into() });
+                let detector = MLAnomalyDetector::learning(trust);
+                detector.set_inference_options(config.inference.options());
+                detector
+            }
+        };
+        detector.set_feature_set(config.detector.feature_set)?;
+        // A corrupt store costs the learned history, not the detector
+        if config.detector.behavior_profiles {
+            let path = config.behavior_profiles();
+            let profiles = BehaviorProfiles::load(&path).unwrap_or_else(|e| {
+                tracing::warn!("Behavior profiles in {} unreadable, starting over: {}", path.display(), e);
+                BehaviorProfiles::new()
+            });
+            Self::supervise(&tasks, "detector", "profile-persist", profiles.start_persist(&path, config.detector.profile_save_secs), None);
+            detector.set_behavior_profiles(profiles);
+        }
+        if config.detector.drift.enabled {
+            let drift = detector.enable_drift_monitor(config.detector.drift.thresholds());
+            Self::supervise(&tasks, "detector", "drift-events", Self::publish_drift(drift, bus.clone()), None);
+        }
+        let detector = Arc::new(Mutex::new(detector));
+        if let Some(path) = &config.detector.model_path {
+            let watch = detector.lock().unwrap().start_model_watch(path, config.detector.model_watch_secs);
+            Self::supervise(&tasks, "detector", "model-watch", watch, None);
+            health.insert("detector", SubsystemHealth::Running);
+        }
+        let (selftest, reports) = detector_selftest::start(detector.clone(), config.detector.selftest_interval_secs);
+        Self::supervise(&tasks, "detector", "self-test", selftest, None);
+        Self::supervise(&tasks, "detector", "self-test-health", Self::track_selftest(reports, health.clone()), None);
+
+        // 5. eBPF monitor last: it is the producer for everything above.
+        // Every probe group is attached with the monitor; a disabled group's
+        // events are dropped here, so qksctl can switch groups at runtime.
+        // Without eBPF, the proc connector or /proc polling stands in for
+        // the syscall stream ([monitor] proc_connector, proc_poll) and the
+        // monitor reports degraded, naming what can no longer be seen.
+        health.insert("monitor", SubsystemHealth::Starting);
+        let probe_groups: Arc<DashMap<&'static str, bool>> =
+            Arc::new(PROBE_GROUPS.iter().map(|group| (*group, config.monitor.enabled(group))).collect());
+        let latest = Arc::new(DashMap::new());
+        let module_loads = Arc::new(Notify::new());
+        let (monitor, syscalls, telemetry) = match EBPFMonitor::new() {
+            Ok(monitor) => {
+                let monitor = Arc::new(monitor);
+                let restart = monitor.clone();
+                Self::supervise(&tasks, "monitor", "syscall-stats", monitor.start_monitoring(), Some(Box::new(move || restart.start_monitoring())));
+
+                if let Err(e) = monitor.set_syscall_sampling(config.monitor.syscall_sample_rate) {
+                    tracing::warn!("Syscall sampling not applied, tracing every process: {}", e);
+                }
+                let (stream, events) = monitor.start_syscall_stream();
+                Self::supervise(&tasks, "monitor", "syscall-stream", stream, None);
+
+                let (mprotect, mprotects) = monitor.start_mprotect_watch();
+                Self::supervise(&tasks, "monitor", "mprotect-watch", mprotect, None);
+                let (gate, mprotects) = Self::gate(mprotects, probe_groups.clone(), "mprotect");
+                Self::supervise(&tasks, "monitor", "mprotect-gate", gate, None);
+                Self::supervise(&tasks, "randomizer", "wx-enforcement", Self::enforce_wx(mprotects, randomizer.clone()), None);
+
+                let (network, connects) = monitor.start_network_watch();
+                Self::supervise(&tasks, "monitor", "network-watch", network, None);
+                let (gate, connects) = Self::gate(connects, probe_groups.clone(), "network");
+                Self::supervise(&tasks, "monitor", "network-gate", gate, None);
+                Self::supervise(&tasks, "monitor", "network-events", Self::publish_network(connects, bus.clone()), None);
+                health.insert("monitor", SubsystemHealth::Running);
+                (Some(monitor), Some(events), TelemetrySource::Ebpf)
+            }
+            // Fork, exec and exit from the proc connector, else differences
+            // between /proc scans, feed the pipeline as a sparser stream
+            Err(e) => {
+                let connector = match config.monitor.proc_connector.then(ProcConnector::new) {
+                    Some(Ok(connector)) => Some(connector),
+                    Some(Err(fallback)) => {
+                        tracing::warn!("Proc connector unavailable: {}", fallback);
+                        None
+                    }
+                    None => None,
+                };
+                let (source, events) = if let Some(connector) = connector {
+                    let (stream, events) = connector.start();
+                    Self::supervise(&tasks, "monitor", "proc-connector", stream, None);
+                    (TelemetrySource::ProcConnector, Some(events))
+                } else if config.monitor.proc_poll {
+                    let poller = Arc::new(ProcPoller::new(Duration::from_millis(config.monitor.poll_interval_ms)));
+                    let (scan, events, mprotects) = poller.start();
+                    Self::supervise(&tasks, "monitor", "proc-poll", scan, None);
+                    let (gate, mprotects) = Self::gate(mprotects, probe_groups.clone(), "mprotect");
+                    Self::supervise(&tasks, "monitor", "mprotect-gate", gate, None);
+                    Self::supervise(&tasks, "randomizer", "wx-enforcement", Self::enforce_wx(mprotects, randomizer.clone()), None);
+                    (TelemetrySource::ProcPoll, Some(events))
+                } else {
+                    (TelemetrySource::None, None)
+                };
+                let degraded = source.coverage().degraded();
+                if source == TelemetrySource::None {
+                    tracing::error!("eBPF monitor unavailable, running without process telemetry: {}", e);
+                    health.insert("monitor", SubsystemHealth::Failed { reason: e.to_string() });
+                } else {
+                    tracing::warn!("eBPF monitor unavailable ({}); falling back to {}: {}", e, source.as_str(), degraded);
+                    health.insert("monitor", SubsystemHealth::Degraded { reason: format!("eBPF unavailable ({}); {}: {}", e, source.as_str(), degraded) });
+                }
+                (None, events, source)
+            }
+        };
+        if let Some(events) = syscalls {
+            let (tap, events) = Self::publish_syscalls(events, probe_groups.clone(), bus.clone(), module_loads.clone());
+            Self::supervise(&tasks, "monitor", "syscall-events", tap, None);
+            let (pipeline, scored) = FeaturePipeline::new(detector.clone(), config.pipeline.config());
+            Self::supervise(&tasks, "detector", "feature-pipeline", pipeline.start(events), None);
+            let (tap, scored) = Self::track_latest(scored, latest.clone());
+            Self::supervise(&tasks, "detector", "latest-scores", tap, None);
+            let layout_events = randomizer.lock().unwrap().subscribe_layout_events();
+            let (enricher, anomalies) = AnomalyEnricher::new(detector.clone()).start(scored, layout_events);
+            Self::supervise(&tasks, "detector", "anomaly-enricher", enricher, None);
+            Self::supervise(&tasks, "detector", "anomaly-log", Self::log_anomalies(anomalies, bus.clone()), None);
+        }
+
+        // 6. Host integrity: boot posture, IMA measurements against the
+        // baseline, dm-verity on the protected volumes, loaded kernel
+        // modules and installed packages
+        let posture = config.integrity.posture.enabled.then(|| {
+            let posture = Arc::new(PostureMonitor::new(config.integrity.posture.interval_secs, config.posture_state()));
+            let (restart, bus_restart) = (posture.clone(), bus.clone());
+            Self::supervise(&tasks, "integrity", "host-posture", posture.clone().start(bus.clone()), Some(Box::new(move || restart.clone().start(bus_restart.clone()))));
+            health.insert("integrity", SubsystemHealth::Running);
+            posture
+        });
+        let ima_section = config.integrity.ima.clone();
+        let ima = if ima_section.enabled {
+            health.insert("integrity", SubsystemHealth::Starting);
+            match ImaMonitor::new(&ima_section) {
+                Ok(ima) => {
+                    let ima = Arc::new(ima);
+                    let (restart, bus_restart) = (ima.clone(), bus.clone());
+                    Self::supervise(&tasks, "integrity", "ima-measurements", ima.clone().start(bus.clone()), Some(Box::new(move || restart.clone().start(bus_restart.clone()))));
+                    health.insert("integrity", SubsystemHealth::Running);
+                    Some(ima)
+                }
+                Err(e) => {
+                    tracing::error!("IMA checks unavailable: {}", e);
+                    health.insert("integrity", SubsystemHealth::Failed { reason: e.to_string() });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let verity_section = config.integrity.verity.clone();
+        let verity = verity_section.enabled.then(|| {
+            let verity = Arc::new(VerityMonitor::new(&verity_section));
+            let (restart, bus_restart) = (verity.clone(), bus.clone());
+            Self::supervise(&tasks, "integrity", "dm-verity", verity.clone().start(bus.clone()), Some(Box::new(move || restart.clone().start(bus_restart.clone()))));
+            health.entry("integrity").or_insert(SubsystemHealth::Running);
+            verity
+        });
+        let modules_section = config.integrity.modules.clone();
+        let modules = modules_section.enabled.then(|| {
+            let modules = Arc::new(ModuleMonitor::new(&modules_section, module_loads));
+            let (restart, bus_restart) = (modules.clone(), bus.clone());
+            Self::supervise(&tasks, "integrity", "kernel-modules", modules.clone().start(bus.clone()), Some(Box::new(move || restart.clone().start(bus_restart.clone()))));
+            health.entry("integrity").or_insert(SubsystemHealth::Running);
+            modules
+        });
+        let packages_section = config.integrity.packages.clone();
+        let packages = packages_section.enabled.then(|| {
+            let packages = Arc::new(PackageSweep::new(&packages_section));
+            let (restart, bus_restart) = (packages.clone(), bus.clone());
+            Self::supervise(&tasks, "integrity", "package-sweep", packages.clone().start(bus.clone()), Some(Box::new(move || restart.clone().start(bus_restart.clone()))));
+            health.entry("integrity").or_insert(SubsystemHealth::Running);
+            packages
+        });
+
+        // Confinement profile drafts, with syscalls from the detector's
+        // behaviour profiles when it keeps them
+        let profiles = config.profiles.enabled.then(|| {
+            let behavior = detector.lock().unwrap().behavior_profiles().cloned();
+            let profiles = Arc::new(ProfileGenerator::new(&config.profiles, config.profile_drafts(), behavior));
+            let restart = profiles.clone();
+            Self::supervise(&tasks, "profiles", "profile-sampling", profiles.clone().start(), Some(Box::new(move || restart.clone().start())));
+            health.insert("profiles", SubsystemHealth::Running);
+            profiles
+        });
+
+        // 7. Control socket for qksctl, once there is something to control
+        let audit = Arc::new(AuditLog::open(config.response_audit_log(), config.response.audit.clone()));
+        let maintenance = audit.clone();
+        Self::supervise(
+            &tasks,
+            "response",
+            "audit-maintenance",
+            audit_log::start_maintenance(audit.clone()),
+            Some(Box::new(move || audit_log::start_maintenance(maintenance.clone()))),
+        );
+        // Token holders' memory budgets, enforced through cgroups
+        let (meter, violations) = CapabilityMeter::new(quarantine::CGROUP_ROOT);
+        let meter = Arc::new(if config.tokens.enforce_memory { meter.with_enforcement(&config.tokens.memory_cgroup) } else { meter });
+        let interval_ms = config.tokens.meter_interval_ms;
+        let metering = meter.clone();
+        Self::supervise(&tasks, "tokens", "memory-metering", meter.start_metering(interval_ms), Some(Box::new(move || metering.start_metering(interval_ms))));
+        Self::supervise(&tasks, "tokens", "budget-events", Self::publish_violations(violations, bus.clone()), None);
+        health.insert("tokens", SubsystemHealth::Running);
+        let response = Arc::new(Responder::new(
+            config.response.clone(),
+            config.quarantine.clone(),
+            audit,
+            identity.clone(),
+            randomizer.clone(),
+            snapshots.clone(),
+            bus.clone(),
+        ));
+        Self::supervise(&tasks, "response", "freeze-reaper", freezer::start_reaper(), Some(Box::new(freezer::start_reaper)));
+        let sweep = response.clone();
+        Self::supervise(&tasks, "response", "token-sweep", response.clone().start_token_sweep(), Some(Box::new(move || sweep.clone().start_token_sweep())));
+        health.insert("response", SubsystemHealth::Running);
+        // Before Control, which passes revocations on to the fleet; its
+        // tasks start with the event forwarders
+        let fleet = match config.fleet.role {
+            FleetRole::Off => None,
+            _ => {
+                health.insert("fleet", SubsystemHealth::Starting);
+                match Fleet::new(&config.fleet, identity.clone(), config.fleet_dir()) {
+                    Ok(fleet) => Some(Arc::new(fleet)),
+                    Err(e) => {
+                        tracing::error!("Fleet mode unavailable: {}", e);
+                        health.insert("fleet", SubsystemHealth::Failed { reason: e.to_string() });
+                        None
+                    }
+                }
+            }
+        };
+        // Indexes the cached indicators now; fetching starts with the tasks
+        let intel = if config.intel.enabled {
+            health.insert("intel", SubsystemHealth::Starting);
+            match Intel::new(&config.intel, config.intel_dir()) {
+                Ok(intel) => Some(Arc::new(intel)),
+                Err(e) => {
+                    tracing::error!("Threat intelligence unavailable: {}", e);
+                    health.insert("intel", SubsystemHealth::Failed { reason: e.to_string() });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let socket = config.daemon.control_socket.clone();
+        let authorizer = control::PeerAuthorizer::new(&config.daemon.operators_group);
+        let config = Arc::new(Mutex::new(Arc::new(config)));
+        health.insert("control", SubsystemHealth::Starting);
+        let history = Arc::new(EventHistory::default());
+        Self::supervise(&tasks, "control", "event-history", history.clone().start(&bus), None);
+        let control = Arc::new(Control {
+            identity: identity.clone(),
+            response: response.clone(),
+            detector: detector.clone(),
+            randomizer: randomizer.clone(),
+            snapshots: snapshots.clone(),
+            monitor,
+            telemetry,
+            tpm,
+            quote_verifier,
+            posture,
+            ima,
+            verity,
+            modules,
+            packages,
+            profiles,
+            probe_groups,
+            latest,
+            meter,
+            events: bus.clone(),
+            history,
+            fleet: fleet.clone(),
+            intel: intel.clone(),
+            health: health.clone(),
+            config: config.clone(),
+        });
+        let handler = control.clone();
+        let handler: control::ControlHandler = Arc::new(move |request| handler.handle(request));
+        // qksd.socket owns the path and its permissions when it is in use
+        let server = match systemd::activated_control_listener() {
+            Some(listener) => {
+                tracing::info!("Control socket passed in by systemd");
+                tokio::net::UnixListener::from_std(listener).map(|listener| control::serve_listener(listener, handler, authorizer))
+            }
+            None => control::serve(&socket, handler, authorizer),
+        };
+        match server {
+            Ok(server) => {
+                Self::supervise(&tasks, "control", "control-socket", server, None);
+                health.insert("control", SubsystemHealth::Running);
+            }
+            Err(e) => {
+                tracing::error!("Control socket {} unavailable: {}", socket.display(), e);
+                health.insert("control", SubsystemHealth::Failed { reason: e.to_string() });
+            }
+        }
+
+        // Containers are enrolled through the same paths qksctl uses, so
+        // the watcher waits for the control side to exist
+        let containers = config.lock().unwrap().containers.clone();
+        if containers.enabled {
+            let watcher = Arc::new(ContainerWatcher::new(&containers, control.clone(), scheduler.clone()));
+            match containers.runtime {
+                ContainerRuntime::Containerd => {
+                    let restart = watcher.clone();
+                    Self::supervise(&tasks, "containers", "containerd-events", watcher.start(), Some(Box::new(move || restart.clone().start())));
+                }
+                ContainerRuntime::Docker => {
+                    let docker = Arc::new(DockerWatcher::new(containers.docker_socket.clone(), watcher));
+                    let restart = docker.clone();
+                    Self::supervise(&tasks, "containers", "docker-events", docker.start(), Some(Box::new(move || restart.clone().start())));
+                }
+            }
+            health.insert("containers", SubsystemHealth::Running);
+        }
+
+        let kubernetes = config.lock().unwrap().kubernetes.clone();
+        if kubernetes.enabled {
+            match PodWatcher::new(&kubernetes) {
+                Ok(watcher) => {
+                    let watcher = Arc::new(watcher);
+                    let restart = watcher.clone();
+                    Self::supervise(&tasks, "kubernetes", "kubelet-pods", watcher.start(), Some(Box::new(move || restart.clone().start())));
+                    health.insert("kubernetes", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("Kubernetes mode unavailable: {}", e);
+                    health.insert("kubernetes", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        // Restarts go through the control side's snapshots and tokens
+        let watchdog = config.lock().unwrap().watchdog.clone();
+        if watchdog.enabled {
+            let watchdog = Arc::new(CrashWatchdog::new(&watchdog, control.clone()));
+            let restart = watchdog.clone();
+            Self::supervise(&tasks, "watchdog", "crash-loop-watchdog", watchdog.start(), Some(Box::new(move || restart.clone().start())));
+            health.insert("watchdog", SubsystemHealth::Running);
+        }
+
+        // 8. Remote and system bus APIs and the metrics endpoint, only
+        // when configured
+        let (grpc, rest, dbus, metrics_section) = {
+            let config = config.lock().unwrap();
+            (config.grpc.clone(), config.rest.clone(), config.dbus.clone(), config.metrics.clone())
+        };
+        if let Some(listen) = grpc.listen {
+            health.insert("grpc", SubsystemHealth::Starting);
+            match grpc_api::serve(&grpc, control.clone()) {
+                Ok(server) => {
+                    tracing::info!("gRPC API listening on {}", listen);
+                    Self::supervise(&tasks, "grpc", "grpc-server", server, None);
+                    health.insert("grpc", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("gRPC API on {} unavailable: {}", listen, e);
+                    health.insert("grpc", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+        if let Some(listen) = rest.listen {
+            health.insert("rest", SubsystemHealth::Starting);
+            match rest_api::serve(&rest, control.clone()).await {
+                Ok(server) => {
+                    tracing::info!("REST API listening on {}", listen);
+                    Self::supervise(&tasks, "rest", "rest-server", server, None);
+                    health.insert("rest", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("REST API on {} unavailable: {}", listen, e);
+                    health.insert("rest", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+        if dbus.enabled {
+            health.insert("dbus", SubsystemHealth::Starting);
+            match dbus_api::serve(control.clone()).await {
+                Ok(bus) => {
+                    tracing::info!("Serving {} on the system bus", dbus_api::BUS_NAME);
+                    Self::supervise(&tasks, "dbus", "dbus-signals", bus, None);
+                    health.insert("dbus", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("System bus interface unavailable: {}", e);
+                    health.insert("dbus", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        if let Some(listen) = metrics_section.listen {
+            health.insert("metrics", SubsystemHealth::Starting);
+            match prometheus::serve(&metrics_section, control.clone()) {
+                Ok(server) => {
+                    tracing::info!("Prometheus metrics on http://{}/metrics", listen);
+                    Self::supervise(&tasks, "metrics", "metrics-server", server, None);
+                    health.insert("metrics", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("Metrics endpoint on {} unavailable: {}", listen, e);
+                    health.insert("metrics", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        // 9. Policy engine, last: its actions go through the responder
+        let rules = config.lock().unwrap().policy.clone();
+        let policy = Arc::new(PolicyEngine::new(&rules, response)?);
+        Self::supervise(&tasks, "policy", "policy-engine", policy.clone().start(bus.clone()), None);
+        health.insert("policy", SubsystemHealth::Running);
+
+        // 10. Alert notifiers and syslog event forwarding, fed from the bus
+        // like the policy engine
+        let notify = config.lock().unwrap().notify.clone();
+        match Notifications::new(&notify) {
+            Ok(notifications) if notifications.is_empty() => {}
+            Ok(notifications) => {
+                Self::supervise(&tasks, "notify", "notifier", Arc::new(notifications).start(bus.clone()), None);
+                health.insert("notify", SubsystemHealth::Running);
+            }
+            Err(e) => {
+                tracing::error!("Alert notifications unavailable: {}", e);
+                health.insert("notify", SubsystemHealth::Failed { reason: e.to_string() });
+            }
+        }
+        let syslog = config.lock().unwrap().logging.syslog.clone();
+        if syslog.enabled {
+            match syslog_sink::forward_events(&syslog, &bus) {
+                Ok(Some(forwarder)) => Self::supervise(&tasks, "notify", "syslog-events", forwarder, None),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("Syslog event forwarding unavailable: {}", e);
+                    health.insert("notify", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        // 11. Event streams to SIEM collectors
+        let (forward, spool_dir) = {
+            let config = config.lock().unwrap();
+            (config.forward.clone(), config.forward_spool_dir())
+        };
+        let mut forwarders = Vec::new();
+        if forward.splunk.enabled {
+            let splunk = &forward.splunk;
+            let started = SplunkHec::new(splunk).and_then(|hec| event_forward::start(hec, &splunk.batch, &splunk.event_kinds, &spool_dir.join("splunk"), &bus));
+            forwarders.push(("splunk-hec", splunk.url.as_str(), started));
+        }
+        if forward.elasticsearch.enabled {
+            let elasticsearch = &forward.elasticsearch;
+            let started = Elasticsearch::new(elasticsearch)
+                .and_then(|es| event_forward::start(es, &elasticsearch.batch, &elasticsearch.event_kinds, &spool_dir.join("elasticsearch"), &bus));
+            forwarders.push(("elasticsearch", elasticsearch.url.as_str(), started));
+        }
+        if forward.kafka.enabled {
+            let kafka = &forward.kafka;
+            let started = Kafka::new(kafka).and_then(|producer| event_forward::start(producer, &kafka.batch, &kafka.event_kinds, &spool_dir.join("kafka"), &bus));
+            forwarders.push(("kafka", kafka.brokers.first().map_or("", String::as_str), started));
+        }
+        for (name, url, started) in forwarders {
+            match started {
+                Ok(forwarder) => {
+                    tracing::info!("Forwarding events to {} at {}", name, url);
+                    Self::supervise(&tasks, "forward", name, forwarder, None);
+                    health.entry("forward").or_insert(SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("Event forwarding to {} unavailable: {}", name, e);
+                    health.insert("forward", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        // 12. Fleet mode: reporting to a controller, or being one
+        if let Some(fleet) = &fleet {
+            let section = config.lock().unwrap().fleet.clone();
+            match fleet.start(&section, &spool_dir.join("fleet"), detector.clone(), policy.clone(), &bus) {
+                Ok(started) => {
+                    for (name, handle) in started {
+                        Self::supervise(&tasks, "fleet", name, handle, None);
+                    }
+                    health.insert("fleet", SubsystemHealth::Running);
+                }
+                Err(e) => {
+                    tracing::error!("Fleet mode unavailable: {}", e);
+                    health.insert("fleet", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        // 13. Threat intelligence feeds
+        if let Some(intel) = &intel {
+            for (name, handle) in intel.clone().start(bus.clone()) {
+                Self::supervise(&tasks, "intel", name, handle, None);
+            }
+            health.insert("intel", SubsystemHealth::Running);
+        }
+
+        if privsep.enabled {
+            privsep::drop_privileges(&privsep.user, &owned)?;
+            health.insert("privsep", SubsystemHealth::Running);
+        }
+        // Last, so nothing above needs a syscall the filter leaves out
+        let sandbox = config.lock().unwrap().sandbox.clone();
+        if sandbox.enabled {
+            match self_sandbox::install_seccomp(&sandbox) {
+                Ok(report) if report.missing.is_empty() => {
+                    tracing::info!(capabilities = ?report.capabilities, syscalls = ?report.seccomp, landlock = report.landlock.is_some(), "qksd sandboxed");
+                    health.insert("sandbox", SubsystemHealth::Running);
+                }
+                Ok(report) => {
+                    tracing::warn!("Sandboxed without {}", report.missing.join(", "));
+                    health.insert("sandbox", SubsystemHealth::Degraded { reason: report.missing.join("; ") });
+                }
+                Err(e) => {
+                    tracing::error!("Seccomp filter not installed: {}", e);
+                    health.insert("sandbox", SubsystemHealth::Failed { reason: e.to_string() });
+                }
+            }
+        }
+
+        let heartbeat = Arc::new(AtomicU64::new(systemd::now_secs()));
+        let daemon = Self { identity, detector, randomizer, snapshots, events: bus, policy, token_status, health, tasks, config, heartbeat };
+        daemon.start_supervision();
+        if let Some(watchdog) = systemd::start_watchdog(daemon.health.clone(), daemon.heartbeat.clone()) {
+            Self::supervise(&daemon.tasks, "daemon", "watchdog", watchdog, None);
+        }
+        systemd::notify_ready(&daemon.health);
+        tracing::info!("qksd started");
+        Ok(daemon)
+    }
+
+    fn supervise(
+        tasks: &Mutex<Vec<SupervisedTask>>,
+        subsystem: &'static str,
+        name: &'static str,
+        handle: JoinHandle<()>,
+        restart: Option<Box<dyn Fn() -> JoinHandle<()> + Send>>,
+    ) {
+        tasks.lock().unwrap().push(SupervisedTask { subsystem, name, handle, restart, restarts: 0, failed: false });
+    }
+
+    // Notice exited tasks: restart what can be restarted, mark the rest
+    fn start_supervision(&self) {
+        let tasks = self.tasks.clone();
+        let health = self.health.clone();
+        let heartbeat = self.heartbeat.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(SUPERVISE_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                heartbeat.store(systemd::now_secs(), Ordering::Relaxed);
+                let mut tasks = tasks.lock().unwrap();
+                for task in tasks.iter_mut().filter(|t| t.handle.is_finished() && !t.failed) {
+                    match &task.restart {
+                        Some(restart) if task.restarts < MAX_RESTARTS => {
+                            task.restarts += 1;
+                            tracing::warn!("{} task {} exited; restarting ({}/{})", task.subsystem, task.name, task.restarts, MAX_RESTARTS);
+                            task.handle = restart();
+                        }
+                        _ => {
+                            task.failed = true;
+                            let reason = format!("task {} exited", task.name);
+                            tracing::error!("{} subsystem failed: {}", task.subsystem, reason);
+                            health.insert(task.subsystem, SubsystemHealth::Failed { reason });
+                        }
+                    }
+                }
+            }
+        });
+        // Not itself supervised; stopped first on shutdown
+        Self::supervise(&self.tasks, "daemon", "supervisor", handle, None);
+    }
+
+    fn track_selftest(
+        mut reports: mpsc::UnboundedReceiver<detector_selftest::SelfTestReport>,
+        health: Arc<DashMap<&'static str, SubsystemHealth>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(report) = reports.recv().await {
+                let detected = report.cases.iter().filter(|c| c.detected).count();
+                let state = match report.health {
+                    DetectionHealth::Healthy => SubsystemHealth::Running,
+                    DetectionHealth::Degraded => SubsystemHealth::Degraded {
+                        reason: format!("self-test detected {}/{} synthetic attacks", detected, report.cases.len()),
+                    },
+                    DetectionHealth::Failed => SubsystemHealth::Failed {
+                        reason: report.error.unwrap_or_else(|| "self-test failed".into()),
+                    },
+                };
+                health.insert("detector", state);
+            }
+        })
+    }
+
+    // Forward events while their probe group is enabled
+    fn gate<T: Send + 'static>(
+        mut events: mpsc::UnboundedReceiver<T>,
+        probe_groups: Arc<DashMap<&'static str, bool>>,
+        group: &'static str,
+    ) -> (JoinHandle<()>, mpsc::UnboundedReceiver<T>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if probe_groups.get(group).map_or(false, |enabled| *enabled) && tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+        (handle, rx)
+    }
+
+    fn track_latest(
+        mut scored: mpsc::UnboundedReceiver<ScoredProcess>,
+        latest: Arc<DashMap<u32, ScoredProcess>>,
+    ) -> (JoinHandle<()>, mpsc::UnboundedReceiver<ScoredProcess>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            while let Some(row) = scored.recv().await {
+                if latest.len() >= MAX_LATEST_SCORES {
+                    latest.retain(|pid, _| std::path::Path::new(&format!("/proc/{}", pid)).exists());
+                }
+                latest.insert(row.pid, row.clone());
+                if tx.send(row).is_err() {
+                    return;
+                }
+            }
+        });
+        (handle, rx)
+    }
+
+    // Gate the syscall group, copy syscalls onto the bus while anyone is
+    // listening for them, and wake the module check for each module load.
+    // Bounded throughout: a slow pipeline pushes back to the stream, which
+    // drops and counts.
+    fn publish_syscalls(
+        mut events: mpsc::Receiver<SyscallEvent>,
+        probe_groups: Arc<DashMap<&'static str, bool>>,
+        bus: Arc<EventBus>,
+        module_loads: Arc<Notify>,
+    ) -> (JoinHandle<()>, mpsc::Receiver<SyscallEvent>) {
+        let (tx, rx) = mpsc::channel(SYSCALL_QUEUE);
+        let handle = tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if !probe_groups.get("syscalls").map_or(false, |enabled| *enabled) {
+                    continue;
+                }
+                if kernel_modules::is_module_load(event.syscall, event.retval) {
+                    module_loads.notify_one();
+                }
+                if bus.has_subscribers(EventKind::Syscall) {
+                    bus.publish(SecurityEvent::Syscall(event.clone()));
+                }
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+        });
+        (handle, rx)
+    }
+
+    // Tag each network event with where the process was before a switch,
+    // drop switches of other namespace types, and publish the rest
+    fn publish_network(mut events: mpsc::UnboundedReceiver<NetworkEvent>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut prune = tokio::time::interval(NETNS_PRUNE_INTERVAL);
+            loop {
+                tokio::select! {
+                    event = events.recv() => {
+                        let Some(mut event) = event else {
+                            return;
+                        };
+                        if !netns::global().observe(&mut event) {
+                            continue;
+                        }
+                        if event.action == NetworkAction::NamespaceSwitch {
+                            tracing::info!(pid = event.pid, netns = event.netns, "PID {} moved to network namespace {}", event.pid, event.netns);
+                        }
+                        metrics::global().incr("qks_network_events_total", &[("action", event.action.as_str())]);
+                        bus.publish(SecurityEvent::Network(event));
+                    }
+                    _ = prune.tick() => netns::global().prune(),
+                }
+            }
+        })
+    }
+
+    fn publish_layouts(mut layouts: broadcast::Receiver<LayoutChangeEvent>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match layouts.recv().await {
+                    Ok(event) => bus.publish(SecurityEvent::Layout(event)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Layout event relay fell behind; {} layout changes not published", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    fn publish_drift(mut drift: mpsc::UnboundedReceiver<ModelDrift>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(event) = drift.recv().await {
+                bus.publish(SecurityEvent::Drift(event));
+            }
+        })
+    }
+
+    fn publish_violations(mut violations: mpsc::UnboundedReceiver<MeteringViolation>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(violation) = violations.recv().await {
+                let breach = violation.source.breach();
+                metrics::global().incr("qks_token_budget_breaches_total", &[("breach", breach)]);
+                let count = match violation.source {
+                    UsageSource::LimitHit | UsageSource::OomKill => violation.used,
+                    UsageSource::Cgroup | UsageSource::Rss => 1,
+                };
+                bus.publish(SecurityEvent::Token(TokenEvent::BudgetExceeded {
+                    pid: violation.pid,
+                    limit: violation.limit,
+                    breach: breach.to_string(),
+                    count,
+                    timestamp: violation.detected_at,
+                }));
+            }
+        })
+    }
+
+    // Log every anomaly, then publish it
+    fn log_anomalies(mut anomalies: mpsc::UnboundedReceiver<AnomalyEvent>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(event) = anomalies.recv().await {
+                metrics::global().incr("qks_anomalies_total", &[]);
+                match serde_json::to_string(&event) {
+                    Ok(json) => tracing::info!(
+                        target: "qks::anomaly",
+                        pid = event.pid,
+                        score = event.score as f64,
+                        threshold = event.threshold as f64,
+                        exe = event.exe.as_deref().unwrap_or("?"),
+                        "{}",
+                        json
+                    ),
+                    Err(e) => tracing::warn!("Could not serialize anomaly event: {}", e),
+                }
+                bus.publish(SecurityEvent::Anomaly(event));
+            }
+        })
+    }
+
+    fn enforce_wx(
+        mut events: mpsc::UnboundedReceiver<crate::ebpf_monitor::MprotectEvent>,
+        randomizer: Arc<Mutex<MemoryRandomizer>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                // Enforcement stops the target with ptrace; not on a runtime worker
+                let randomizer = randomizer.clone();
+                let pid = event.pid;
+                let enforced = tokio::task::spawn_blocking(move || randomizer.lock().unwrap().on_mprotect(&event)).await;
+                if let Err(e) = enforced.map_err(|e| e.to_string()).and_then(|result| result) {
+                    tracing::warn!(pid, error = %e, "W^X enforcement for PID {} failed: {}", pid, e);
+                }
+            }
+        })
+    }
+
+    // Apply configs published by config::start_reload. Only the live-safe
+    // fields differ between successive configs (see merge_reload).
+    pub fn follow_config(&self, mut updates: watch::Receiver<Arc<QksConfig>>) {
+        let detector = self.detector.clone();
+        let policy = self.policy.clone();
+        let current = self.config.clone();
+
+        let handle = tokio::spawn(async move {
+            while updates.changed().await.is_ok() {
+                let new = updates.borrow_and_update().clone();
+                let old = std::mem::replace(&mut *current.lock().unwrap(), new.clone());
+                tpm::measure_json("config_change", &*new);
+                if new.policy != old.policy {
+                    // Validated with the rest of the file, so this only fails on a bug
+                    if let Err(e) = policy.replace_rules(&new.policy) {
+                        tracing::error!("Reloaded policy not applied: {}", e);
+                    }
+                }
+                let detector = detector.clone();
+
+                let result = tokio::task::spawn_blocking(move || -> Result<(), InferenceError> {
+                    let mut detector = detector.lock().unwrap();
+                    if new.detector.feature_set != old.detector.feature_set {
+                        detector.set_feature_set(new.detector.feature_set)?;
+                    }
+                    if new.inference != old.inference {
+                        detector.set_inference_options(new.inference.options());
+                        if let Some(path) = &new.detector.model_path {
+                            detector.reload(path)?;
+                        }
+                    }
+                    Ok(())
+                }).await;
+
+                match result {
+                    Ok(Ok(())) => tracing::info!("Applied reloaded configuration"),
+                    Ok(Err(e)) => tracing::error!("Reloaded configuration not fully applied: {}", e),
+                    Err(e) => tracing::warn!("Configuration apply task failed: {}", e),
+                }
+            }
+        });
+        Self::supervise(&self.tasks, "daemon", "config-reload", handle, None);
+    }
+
+    pub fn config(&self) -> Arc<QksConfig> {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn health(&self) -> Vec<(&'static str, SubsystemHealth)> {
+        let mut health: Vec<_> = self.health.iter().map(|e| (*e.key(), e.value().clone())).collect();
+        health.sort_by_key(|(name, _)| *name);
+        health
+    }
+
+    pub fn identity(&self) -> &Arc<CryptoIdentifier> {
+        &self.identity
+    }
+
+    pub fn detector(&self) -> &Arc<Mutex<MLAnomalyDetector>> {
+        &self.detector
+    }
+
+    pub fn randomizer(&self) -> &Arc<Mutex<MemoryRandomizer>> {
+        &self.randomizer
+    }
+
+    pub fn snapshots(&self) -> &Arc<SnapshotManager> {
+        &self.snapshots
+    }
+
+    pub fn events(&self) -> &Arc<EventBus> {
+        &self.events
+    }
+
+    pub fn token_status(&self) -> mpsc::Sender<(StatusRequest, oneshot::Sender<StatusResponse>)> {
+        self.token_status.clone()
+    }
+
+    // Until SIGINT or SIGTERM, then shut down
+    pub async fn run(self) -> Result<(), DaemonError> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("SIGTERM received"),
+            _ = interrupt.recv() => tracing::info!("SIGINT received"),
+        }
+        self.shutdown().await;
+        Ok(())
+    }
+
+    // Supervisor first so nothing is restarted, then producers before
+    // consumers (reverse startup order)
+    pub async fn shutdown(&self) {
+        systemd::notify_stopping();
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        if let Some(position) = tasks.iter().position(|t| t.name == "supervisor") {
+            tasks.remove(position).handle.abort();
+        }
+
+        let mut stopped: Vec<&'static str> = Vec::new();
+        for task in tasks.into_iter().rev() {
+            task.handle.abort();
+            let _ = task.handle.await;
+            if !stopped.contains(&task.subsystem) {
+                stopped.push(task.subsystem);
+            }
+        }
+        for subsystem in self.health.iter().map(|e| *e.key()).collect::<Vec<_>>() {
+            self.health.insert(subsystem, SubsystemHealth::Stopped);
+        }
+        // What was learned since the last periodic save
+        if let Some(profiles) = self.detector.lock().unwrap().behavior_profiles().cloned() {
+            let path = self.config.lock().unwrap().behavior_profiles();
+            if let Err(e) = tokio::task::spawn_blocking(move || profiles.save(&path)).await.unwrap_or(Ok(())) {
+                tracing::warn!("Saving behavior profiles failed: {}", e);
+            }
+        }
+        // Nobody would be left to thaw them
+        let thawed = tokio::task::spawn_blocking(|| freezer::global().thaw_all()).await.unwrap_or_default();
+        if thawed > 0 {
+            tracing::warn!("Thawed {} processes still frozen at shutdown", thawed);
+        }
+        tracing::info!("qksd stopped ({})", stopped.join(", "));
+    }
+}
+
+// What the control socket and the remote API can reach. Handlers run on
+// the blocking pool.
+pub(crate) struct Control {
+    pub(crate) identity: Arc<CryptoIdentifier>,
+    // Kill, stop, block egress and the rest, with safety checks and audit
+    pub(crate) response: Arc<Responder>,
+    pub(crate) detector: Arc<Mutex<MLAnomalyDetector>>,
+    pub(crate) randomizer: Arc<Mutex<MemoryRandomizer>>,
+    pub(crate) snapshots: Arc<SnapshotManager>,
+    pub(crate) monitor: Option<Arc<EBPFMonitor>>,
+    // Which source stands in when eBPF could not be loaded
+    pub(crate) telemetry: TelemetrySource,
+    pub(crate) tpm: Option<Arc<Tpm>>,
+    // Controller mode only
+    pub(crate) quote_verifier: Option<Arc<QuoteVerifier>>,
+    pub(crate) posture: Option<Arc<PostureMonitor>>,
+    pub(crate) ima: Option<Arc<ImaMonitor>>,
+    pub(crate) verity: Option<Arc<VerityMonitor>>,
+    pub(crate) modules: Option<Arc<ModuleMonitor>>,
+    pub(crate) packages: Option<Arc<PackageSweep>>,
+    pub(crate) profiles: Option<Arc<ProfileGenerator>>,
+    pub(crate) probe_groups: Arc<DashMap<&'static str, bool>>,
+    pub(crate) latest: Arc<DashMap<u32, ScoredProcess>>,
+    pub(crate) meter: Arc<CapabilityMeter>,
+    pub(crate) events: Arc<EventBus>,
+    pub(crate) history: Arc<EventHistory>,
+    // Off unless fleet.role is set
+    pub(crate) fleet: Option<Arc<Fleet>>,
+    // Unless intel.enabled is off or it failed to start
+    pub(crate) intel: Option<Arc<Intel>>,
+    pub(crate) health: Arc<DashMap<&'static str, SubsystemHealth>>,
+    pub(crate) config: Arc<Mutex<Arc<QksConfig>>>,
+}
+
+impl Control {
+    pub(crate) fn handle(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Health => {
+                let health: std::collections::BTreeMap<_, _> = self.health.iter().map(|e| (*e.key(), e.value().clone())).collect();
+                ControlResponse::ok(&health)
+            }
+            ControlRequest::Diagnostics => ControlResponse::ok(&self.diagnostics()),
+            ControlRequest::FleetStatus => match &self.fleet {
+                Some(fleet) => ControlResponse::ok(&fleet.status()),
+                // Off, or failed to start; `health` says which
+                None => ControlResponse::ok(&serde_json::json!({ "role": self.config.lock().unwrap().fleet.role })),
+            },
+            ControlRequest::IntelStatus => match &self.intel {
+                Some(intel) => ControlResponse::ok(&intel.status()),
+                None => ControlResponse::error("threat intelligence is not running; see health"),
+            },
+
+            ControlRequest::SnapshotTake { tag, note } => {
+                if tag.as_deref().map_or(false, |tag| !control::valid_tag(tag)) {
+                    return ControlResponse::error("tag must be 1-32 of a-z, 0-9 and -");
+                }
+                Self::reply(self.response.take_snapshot(tag.as_deref(), note.as_deref()))
+            }
+            ControlRequest::SnapshotList => Self::reply(self.snapshots.list_snapshots()),
+            ControlRequest::SnapshotDiff { from, to } => Self::reply(self.snapshots.diff_snapshots(&from, &to)),
+            ControlRequest::SnapshotRestore { id, fresh } => {
+                let mode = if fresh { LayoutRestoreMode::Fresh } else { LayoutRestoreMode::Recorded };
+                Self::reply(self.restore_snapshot(&id, mode).map(|pids| serde_json::json!({ "restored": pids })))
+            }
+            ControlRequest::SnapshotVerify { id } => {
+                Self::reply(self.verify_snapshot(&id).map(|valid| serde_json::json!({ "snapshot_id": id, "valid": valid })))
+            }
+            ControlRequest::SnapshotRollback { tag, fresh } => {
+                let snapshot = match self.snapshots.latest_tagged(&tag) {
+                    Ok(Some(snapshot)) => snapshot,
+                    Ok(None) => return ControlResponse::error(format!("no snapshot tagged {}*", tag)),
+                    Err(e) => return ControlResponse::error(e),
+                };
+                let mode = if fresh { LayoutRestoreMode::Fresh } else { LayoutRestoreMode::Recorded };
+                Self::reply(self.restore_snapshot(&snapshot.snapshot_id, mode).map(|pids| {
+                    serde_json::json!({ "snapshot_id": snapshot.snapshot_id, "tag": snapshot.tag, "note": snapshot.note, "restored": pids })
+                }))
+            }
+
+            ControlRequest::TokenIssue { pid, capabilities } => match self.issue_token(pid, &capabilities) {
+                Ok(token) => ControlResponse::ok(&token),
+                Err(e) => ControlResponse::error(format!("token generation failed: {:?}", e)),
+            },
+            ControlRequest::TokenVerify { token } => ControlResponse::ok(&self.identity.introspect(&token)),
+            ControlRequest::TokenRevoke { token } => ControlResponse::ok(&self.revoke_token(&token, "the control interface")),
+
+            ControlRequest::MonitorStats { top } => {
+                // Per-syscall statistics only come from the eBPF programs
+                let stats: Vec<_> = self.monitor.iter().flat_map(|monitor| monitor.syscall_stats()).take(top)
+                    .map(|(syscall, stat)| serde_json::json!({ "syscall": syscall, "stats": stat }))
+                    .collect();
+                let groups: std::collections::BTreeMap<_, _> = self.probe_groups.iter().map(|e| (*e.key(), *e.value())).collect();
+                let dropped = self.monitor.as_ref().map(|monitor| monitor.dropped_syscall_events());
+                ControlResponse::ok(&serde_json::json!({ "coverage": self.telemetry.coverage(), "probe_groups": groups, "syscalls": stats, "dropped_events": dropped }))
+            }
+            ControlRequest::RecentEvents { after, limit } => {
+                let (items, next_after) = self.history.after(after, limit);
+                ControlResponse::ok(&serde_json::json!({ "items": items, "next_after": next_after }))
+            }
+            ControlRequest::ProbeGroup { group, enabled } => {
+                let Some(mut entry) = self.probe_groups.get_mut(group.as_str()) else {
+                    return ControlResponse::error(format!("unknown probe group {:?} (known: {})", group, PROBE_GROUPS.join(", ")));
+                };
+                *entry = enabled;
+                tracing::info!("Probe group {} {}", group, if enabled { "enabled" } else { "disabled" });
+                ControlResponse::ok(&serde_json::json!({ "group": group, "enabled": enabled }))
+            }
+            ControlRequest::NetnsList => ControlResponse::ok(&netns::list()),
+
+            ControlRequest::RandomizerPlan { pid } => match self.randomizer.lock().unwrap().plan_layout(pid) {
+                Ok(plan) => ControlResponse::ok(&serde_json::json!({
+                    "pid": plan.pid,
+                    "noop": plan.is_noop(),
+                    "moves": plan.moves.iter().map(|m| serde_json::json!({
+                        "region": m.region, "from": m.from, "to": m.to, "len": m.len,
+                    })).collect::<Vec<_>>(),
+                    "excluded": plan.excluded.iter().map(|e| serde_json::json!({
+                        "region": e.region, "reason": e.reason,
+                    })).collect::<Vec<_>>(),
+                    "pointer_patches": plan.pointer_patches,
+                    "estimated_pause_ms": plan.estimated_pause.as_secs_f64() * 1000.0,
+                    "observed_mean_pause_ms": plan.observed_mean_pause.map(|d| d.as_secs_f64() * 1000.0),
+                })),
+                Err(e) => ControlResponse::error(e),
+            },
+            ControlRequest::RandomizerApply { pid } => match self.randomizer.lock().unwrap().apply_layout_to_process(pid) {
+                Ok(()) => ControlResponse::ok(&serde_json::json!({ "pid": pid, "applied": true })),
+                Err(e) => ControlResponse::error(e),
+            },
+
+            ControlRequest::Quarantine { pid } => self.respond(ResponseAction::Quarantine, Some(pid), "quarantine requested".to_string(), false),
+            ControlRequest::QuarantineList => ControlResponse::ok(&self.response.quarantined()),
+            ControlRequest::FreezeList => ControlResponse::ok(&freezer::global().holds()),
+            ControlRequest::TpmStatus => match &self.tpm {
+                Some(tpm) => ControlResponse::ok(&tpm.status()),
+                None => ControlResponse::error("TPM attestation is not running"),
+            },
+            ControlRequest::TpmQuote { nonce } => {
+                let Some(tpm) = &self.tpm else {
+                    return ControlResponse::error("TPM attestation is not running");
+                };
+                let nonce = match nonce {
+                    Some(nonce) => match decode_hex(&nonce) {
+                        Some(nonce) => nonce,
+                        None => return ControlResponse::error("nonce must be hex"),
+                    },
+                    None => {
+                        let mut nonce = vec![0u8; tpm::MAX_NONCE_BYTES];
+                        SystemRandomSource::new().fill(&mut nonce);
+                        nonce
+                    }
+                };
+                match tpm.quote(&nonce) {
+                    Ok(quote) => ControlResponse::ok(&quote),
+                    Err(e) => ControlResponse::error(e),
+                }
+            }
+            ControlRequest::TpmVerify { quote, nonce } => {
+                let Some(verifier) = &self.quote_verifier else {
+                    return ControlResponse::error("quotes are verified in controller mode ([tpm] controller)");
+                };
+                let Some(nonce) = decode_hex(&nonce) else {
+                    return ControlResponse::error("nonce must be hex");
+                };
+                match verifier.verify(&quote, &nonce) {
+                    Ok(verified) => ControlResponse::ok(&verified),
+                    Err(e) => {
+                        tracing::warn!("Quote from {} rejected: {}", quote.host, e);
+                        ControlResponse::error(e)
+                    }
+                }
+            }
+            ControlRequest::Posture => match self.posture.as_ref().and_then(|posture| posture.posture()) {
+                Some(posture) => ControlResponse::ok(&posture),
+                None => ControlResponse::error("host posture has not been checked"),
+            },
+            ControlRequest::ImaStatus => match &self.ima {
+                Some(ima) => ControlResponse::ok(&ima.status()),
+                None => ControlResponse::error("IMA checks are not running"),
+            },
+            ControlRequest::VerityStatus => match &self.verity {
+                Some(verity) => ControlResponse::ok(&verity.volumes()),
+                None => ControlResponse::error("dm-verity checks are not running"),
+            },
+            ControlRequest::KernelModules => match &self.modules {
+                Some(modules) => ControlResponse::ok(&modules.modules()),
+                None => ControlResponse::error("kernel module checks are not running"),
+            },
+            ControlRequest::PackageSweep => match &self.packages {
+                Some(packages) => ControlResponse::ok(&packages.status()),
+                None => ControlResponse::error("the package sweep is not running"),
+            },
+            ControlRequest::AuditList => ControlResponse::ok(&self.response.audit().segments()),
+            ControlRequest::AuditExport { since, until, output } => {
+                if !output.is_absolute() {
+                    return ControlResponse::error("output must be an absolute path");
+                }
+                match self.response.audit().export(since.unwrap_or(0), until.unwrap_or(u64::MAX), &output, &self.identity) {
+                    Ok(summary) => ControlResponse::ok(&summary),
+                    Err(e) => ControlResponse::error(e),
+                }
+            }
+            ControlRequest::ProfileList => match &self.profiles {
+                Some(profiles) => ControlResponse::ok(&profiles.list()),
+                None => ControlResponse::error(PROFILES_DISABLED),
+            },
+            ControlRequest::ProfileShow { binary, format } => match &self.profiles {
+                Some(profiles) => Self::reply(profiles.render(&binary, format)),
+                None => ControlResponse::error(PROFILES_DISABLED),
+            },
+            ControlRequest::ProfileExport { binary, format, output } => {
+                let Some(profiles) = &self.profiles else {
+                    return ControlResponse::error(PROFILES_DISABLED);
+                };
+                if !output.is_absolute() {
+                    return ControlResponse::error("output must be an absolute path");
+                }
+                Self::reply(profiles.export(&binary, format, &output).map(|()| serde_json::json!({ "binary": binary, "output": output })))
+            }
+            ControlRequest::ProfileApprove { binary, complain } => match &self.profiles {
+                Some(profiles) => Self::reply(profiles.approve(&binary, complain).map(|()| serde_json::json!({ "binary": binary, "approved": true, "complain": complain }))),
+                None => ControlResponse::error(PROFILES_DISABLED),
+            },
+            ControlRequest::ProfileEnforce { binary } => match &self.profiles {
+                Some(profiles) => Self::reply(profiles.enforce(&binary).map(|path| serde_json::json!({ "binary": binary, "profile": path }))),
+                None => ControlResponse::error(PROFILES_DISABLED),
+            },
+            ControlRequest::Respond { action, pid, reason, dry_run } => {
+                let reason = reason.unwrap_or_else(|| "requested by operator".to_string());
+                self.respond(action, pid, reason, dry_run)
+            }
+
+            ControlRequest::DetectorScore { pid } => {
+                let Some(scored) = self.latest.get(&pid).map(|row| row.clone()) else {
+                    return ControlResponse::error(format!("PID {} has not been scored yet", pid));
+                };
+                let explanation = self.detector.lock().unwrap().explain_anomaly(&scored.features, TOP_CONTRIBUTIONS).ok();
+                ControlResponse::ok(&serde_json::json!({
+                    "pid": scored.pid,
+                    "exe": scored.exe,
+                    "score": scored.score,
+                    "mode": scored.mode,
+                    "model_version": scored.model_version,
+                    "timestamp": scored.timestamp,
+                    "explanation": explanation,
+                }))
+            }
+            ControlRequest::DetectorTop { top } => {
+                // Feature rows stay where they are; only what is shown is copied
+                let mut rows: Vec<_> = self
+                    .latest
+                    .iter()
+                    .map(|row| (row.score, serde_json::json!({ "pid": row.pid, "exe": row.exe, "score": row.score, "mode": row.mode, "timestamp": row.timestamp })))
+                    .collect();
+                rows.sort_by(|a, b| b.0.total_cmp(&a.0));
+                ControlResponse::ok(&rows.into_iter().take(top).map(|(_, row)| row).collect::<Vec<_>>())
+            }
+            ControlRequest::DetectorReload { path } => {
+                let Some(path) = path.or_else(|| self.config.lock().unwrap().detector.model_path.clone()) else {
+                    return ControlResponse::error("no model path given and none configured");
+                };
+                match self.detector.lock().unwrap().reload(&path) {
+                    Ok(version) => ControlResponse::ok(&serde_json::json!({ "model_path": path, "model_version": version })),
+                    Err(e) => ControlResponse::error(e),
+                }
+            }
+        }
+    }
+
+    // The operations below are shared by every interface and publish what
+    // they did on the event bus
+
+    pub(crate) fn restore_snapshot(&self, id: &str, mode: LayoutRestoreMode) -> Result<Vec<u32>, anyhow::Error> {
+        let mut randomizer = self.randomizer.lock().unwrap();
+        let pids = self.snapshots.restore_layouts(id, &mut randomizer, mode)?;
+        tpm::measure("snapshot_restore", format!("{} to {} processes", id, pids.len()));
+        self.events.publish(SecurityEvent::Snapshot(SnapshotEvent::Restored { snapshot_id: id.to_string(), pids: pids.clone() }));
+        Ok(pids)
+    }
+
+    pub(crate) fn verify_snapshot(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let valid = self.snapshots.verify_snapshot(id)?;
+        self.events.publish(SecurityEvent::Snapshot(SnapshotEvent::Verified { snapshot_id: id.to_string(), valid }));
+        Ok(valid)
+    }
+
+    pub(crate) fn issue_token(&self, pid: u32, capabilities: &[Capability]) -> Result<ProcessToken, ring::error::Unspecified> {
+        let token = self.identity.generate_process_token(pid, None, capabilities)?;
+        self.response.track_token(&token);
+        self.meter.enroll(&token);
+        self.events.publish(SecurityEvent::Token(TokenEvent::Issued {
+            pid,
+            capabilities: capabilities.to_vec(),
+            timestamp: token.timestamp,
+        }));
+        Ok(token)
+    }
+
+    // `via` names the interface for the log
+    pub(crate) fn revoke_token(&self, token: &ProcessToken, via: &str) -> RevocationProof {
+        let proof = self.identity.revoke_token(token);
+        if let Some(fleet) = &self.fleet {
+            fleet.revoked(proof.clone());
+        }
+        self.meter.unenroll(token.pid);
+        tracing::info!(pid = token.pid, "Token for PID {} revoked over {}", token.pid, via);
+        self.events.publish(SecurityEvent::Token(TokenEvent::Revoked { pid: token.pid, revoked_at: proof.revoked_at }));
+        proof
+    }
+
+    fn respond(&self, action: ResponseAction, pid: Option<u32>, reason: String, dry_run: bool) -> ControlResponse {
+        let record = self.response.execute(ResponseRequest { action, pid, origin: "operator".to_string(), reason, dry_run });
+        if record.outcome.is_success() {
+            ControlResponse::ok(&record)
+        } else {
+            ControlResponse::error(format!("{} {}: {}", action.as_str(), record.outcome.as_str(), record.detail.as_deref().unwrap_or("no detail")))
+        }
+    }
+
+    pub(crate) fn diagnostics(&self) -> Diagnostics {
+        let config = self.config.lock().unwrap().clone();
+        let (mode, version) = {
+            let detector = self.detector.lock().unwrap();
+            (detector.scoring_mode(), detector.model_version())
+        };
+        Diagnostics::new(vec![
+            diagnostics::subsystems(&self.health),
+            diagnostics::bpf(self.monitor.is_some(), self.telemetry, &self.probe_groups),
+            diagnostics::model(mode, version, &config.detector),
+            diagnostics::snapshot_store(&config.snapshot_dir()),
+            diagnostics::tpm(&config.tpm, self.tpm.as_deref()),
+            diagnostics::clock(),
+        ])
+    }
+
+    fn reply<T: Serialize, E: std::fmt::Display>(result: Result<T, E>) -> ControlResponse {
+        match result {
+            Ok(value) => ControlResponse::ok(&value),
+            Err(e) => ControlResponse::error(e),
+        }
+    }
+}
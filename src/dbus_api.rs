@@ -0,0 +1,146 @@
+// src/dbus_api.rs
+// org.qks.Daemon1 on the system bus, for desktop and other Debian system
+// components. Every method asks polkit first (actions in
+// etc/polkit-1/actions/org.qks.policy), so the bus policy can let anyone
+// call and leave the decision to the admin's polkit rules. Anomalies are
+// broadcast as the AnomalyDetected signal.
+// Signals reach every listener on the bus without a polkit check, so
+// AnomalyDetected carries only the PID and score; the details (exe,
+// ancestry, explanation) come from AnomalyScore, which is checked.
+use crate::control::{ControlRequest, ControlResponse};
+use crate::crypto_identifiers::ProcessToken;
+use crate::daemon::Control;
+use crate::events::{EventKind, SecurityEvent};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use zbus::message::Header;
+use zbus::{fdo, interface, Connection, SignalContext};
+use zbus_polkit::policykit1::{AuthorityProxy, CheckAuthorizationFlags, Subject};
+
+pub const BUS_NAME: &str = "org.qks.Daemon1";
+pub const OBJECT_PATH: &str = "/org/qks/Daemon1";
+
+const ACTION_READ: &str = "org.qks.read";
+const ACTION_SNAPSHOT: &str = "org.qks.snapshot";
+const ACTION_REVOKE_TOKEN: &str = "org.qks.revoke-token";
+const ACTION_QUARANTINE: &str = "org.qks.quarantine";
+
+struct DaemonInterface {
+    control: Arc<Control>,
+}
+
+impl DaemonInterface {
+    // Interactive: an admin at a desktop session gets an authentication
+    // prompt instead of a flat refusal
+    async fn authorize(&self, connection: &Connection, header: &Header<'_>, action: &str) -> fdo::Result<()> {
+        let subject = Subject::new_for_message_header(header)
+            .map_err(|e| fdo::Error::AccessDenied(format!("cannot identify caller: {}", e)))?;
+        let authority = AuthorityProxy::new(connection).await?;
+        let result = authority
+            .check_authorization(&subject, action, &HashMap::new(), CheckAuthorizationFlags::AllowUserInteraction.into(), "")
+            .await?;
+
+        if result.is_authorized {
+            Ok(())
+        } else {
+            let caller = header.sender().map(|s| s.to_string()).unwrap_or_default();
+            tracing::warn!("D-Bus caller {} denied {}", caller, action);
+            Err(fdo::Error::AccessDenied(format!("not authorized for {}", action)))
+        }
+    }
+
+    async fn call(&self, request: ControlRequest) -> fdo::Result<serde_json::Value> {
+        let control = self.control.clone();
+        match tokio::task::spawn_blocking(move || control.handle(request)).await {
+            Ok(ControlResponse::Ok(value)) => Ok(value),
+            Ok(ControlResponse::Error(message)) => Err(fdo::Error::Failed(message)),
+            Err(e) => Err(fdo::Error::Failed(format!("request task failed: {}", e))),
+        }
+    }
+}
+
+#[interface(name = "org.qks.Daemon1")]
+impl DaemonInterface {
+    // Returns the new snapshot's ID
+    async fn take_snapshot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<String> {
+        self.authorize(connection, &header, ACTION_SNAPSHOT).await?;
+        let snapshot = self.call(ControlRequest::SnapshotTake { tag: None, note: None }).await?;
+        snapshot["snapshot_id"].as_str()
+            .map(str::to_string)
+            .ok_or_else(|| fdo::Error::Failed("snapshot taken but its ID was not returned".to_string()))
+    }
+
+    // The PID's latest score, and the full result (explanation included) as JSON
+    async fn anomaly_score(
+        &self,
+        pid: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<(f64, String)> {
+        self.authorize(connection, &header, ACTION_READ).await?;
+        let scored = self.call(ControlRequest::DetectorScore { pid }).await?;
+        Ok((scored["score"].as_f64().unwrap_or_default(), scored.to_string()))
+    }
+
+    // Takes the token as JSON, as issued; returns the revocation proof as JSON
+    async fn revoke_token(
+        &self,
+        token: &str,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<String> {
+        self.authorize(connection, &header, ACTION_REVOKE_TOKEN).await?;
+        let token: ProcessToken = serde_json::from_str(token)
+            .map_err(|e| fdo::Error::InvalidArgs(format!("not a token: {}", e)))?;
+        Ok(self.call(ControlRequest::TokenRevoke { token }).await?.to_string())
+    }
+
+    async fn quarantine_pid(
+        &self,
+        pid: u32,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<()> {
+        self.authorize(connection, &header, ACTION_QUARANTINE).await?;
+        self.call(ControlRequest::Quarantine { pid }).await?;
+        Ok(())
+    }
+
+    // PID and score only; call AnomalyScore for the rest
+    #[zbus(signal)]
+    async fn anomaly_detected(ctxt: &SignalContext<'_>, pid: u32, score: f64) -> zbus::Result<()>;
+}
+
+// Owns the bus connection; the name is released when the task stops
+pub(crate) async fn serve(control: Arc<Control>) -> zbus::Result<tokio::task::JoinHandle<()>> {
+    let mut anomalies = control.events.subscribe(EventKind::Anomaly);
+    let connection = zbus::connection::Builder::system()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, DaemonInterface { control })?
+        .build()
+        .await?;
+    let ctxt = SignalContext::new(&connection, OBJECT_PATH)?.into_owned();
+
+    Ok(tokio::spawn(async move {
+        let _connection = connection;
+        loop {
+            match anomalies.recv().await {
+                Ok(SecurityEvent::Anomaly(event)) => {
+                    if let Err(e) = DaemonInterface::anomaly_detected(&ctxt, event.pid, event.score as f64).await {
+                        tracing::warn!(pid = event.pid, "AnomalyDetected signal for PID {} not sent: {}", event.pid, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("D-Bus alert signals fell behind; {} anomalies not signalled", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }))
+}
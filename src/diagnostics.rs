@@ -0,0 +1,192 @@
+// src/diagnostics.rs
+// Active self-checks behind the `diagnostics` request: where `health` says
+// whether each supervised task is running, these look at what the daemon
+// depends on right now. Is eBPF loaded, or which fallback stands in? Is
+// the model loaded and was its signature verified? Can the snapshot store
+// still be written? Does the TPM answer? Is the clock believable?
+//
+// Every check that is not ok carries a reason: a stable snake_case code
+// for liveness and readiness probes to match on, next to a human-readable
+// detail that may change between releases. Only `failed` means the daemon
+// is not doing its job; `degraded` is running with less coverage.
+//
+// The clock check can't ask the kernel whether NTP is synchronized:
+// adjtimex is one of the clock-setting calls the daemon's own seccomp
+// filter refuses (self_sandbox.rs). It checks that the time is past
+// CLOCK_FLOOR_SECS and that the wall clock has not been stepped against
+// boot time since the daemon started.
+use crate::config::{DetectorSection, TpmSection};
+use crate::daemon::SubsystemHealth;
+use crate::ebpf_monitor::TelemetrySource;
+use crate::ml_detector::ScoringMode;
+use crate::tpm::Tpm;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 2024-01-01T00:00:00Z; anything earlier is an RTC that was never set
+const CLOCK_FLOOR_SECS: u64 = 1_704_067_200;
+// More than NTP slewing can account for over any plausible uptime
+const MAX_CLOCK_STEP_SECS: i64 = 300;
+const PROBE_NAME: &str = ".diagnostics-probe";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Degraded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    // None when ok
+    pub reason: Option<&'static str>,
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Ok, reason: None, detail: Some(detail.into()) }
+    }
+
+    fn degraded(name: &'static str, reason: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Degraded, reason: Some(reason), detail: Some(detail.into()) }
+    }
+
+    fn failed(name: &'static str, reason: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Failed, reason: Some(reason), detail: Some(detail.into()) }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostics {
+    // The worst of the checks
+    pub status: CheckStatus,
+    // "<check>:<reason>" for every check that is not ok
+    pub reasons: Vec<String>,
+    pub checks: Vec<Check>,
+    pub timestamp: u64,
+}
+
+impl Diagnostics {
+    pub fn new(checks: Vec<Check>) -> Self {
+        let status = checks.iter().map(|check| check.status).max().unwrap_or(CheckStatus::Ok);
+        let reasons = checks.iter().filter_map(|check| check.reason.map(|reason| format!("{}:{}", check.name, reason))).collect();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        Self { status, reasons, checks, timestamp }
+    }
+}
+
+// Supervised tasks that stopped: the daemon runs on without them
+pub fn subsystems(health: &DashMap<&'static str, SubsystemHealth>) -> Check {
+    let mut failed: Vec<&str> = health.iter().filter(|e| matches!(e.value(), SubsystemHealth::Failed { .. })).map(|e| *e.key()).collect();
+    let mut degraded: Vec<&str> = health.iter().filter(|e| matches!(e.value(), SubsystemHealth::Degraded { .. })).map(|e| *e.key()).collect();
+    failed.sort_unstable();
+    degraded.sort_unstable();
+    if !failed.is_empty() {
+        Check::degraded("subsystems", "subsystem_failed", format!("failed: {}", failed.join(", ")))
+    } else if !degraded.is_empty() {
+        Check::degraded("subsystems", "subsystem_degraded", format!("degraded: {}", degraded.join(", ")))
+    } else {
+        Check::ok("subsystems", format!("{} running", health.len()))
+    }
+}
+
+// probe_groups: each group and whether it is switched on
+pub fn bpf(loaded: bool, telemetry: TelemetrySource, probe_groups: &DashMap<&'static str, bool>) -> Check {
+    if !loaded {
+        return match telemetry {
+            TelemetrySource::None => Check::failed("bpf", "no_telemetry", "eBPF could not be loaded and no fallback source is running"),
+            source => Check::degraded("bpf", "bpf_unavailable", format!("using {}: {}", source.as_str(), source.coverage().degraded())),
+        };
+    }
+    let mut disabled: Vec<&str> = probe_groups.iter().filter(|e| !*e.value()).map(|e| *e.key()).collect();
+    if disabled.is_empty() {
+        return Check::ok("bpf", format!("eBPF loaded, {} probe groups on", probe_groups.len()));
+    }
+    disabled.sort_unstable();
+    Check::degraded("bpf", "probe_groups_disabled", format!("eBPF loaded; switched off: {}", disabled.join(", ")))
+}
+
+// Loads are refused unless the signature verifies, so a model that is
+// loaded under trusted keys is a signed one
+pub fn model(mode: ScoringMode, version: u64, section: &DetectorSection) -> Check {
+    match (mode, &section.model_path) {
+        (ScoringMode::Model, _) if section.insecure_models => {
+            Check::degraded("model", "model_unsigned", format!("version {} loaded without signature verification (insecure_models)", version))
+        }
+        (ScoringMode::Model, _) => Check::ok("model", format!("version {} loaded, signature verified", version)),
+        (ScoringMode::Baseline, Some(path)) => Check::failed("model", "model_not_loaded", format!("{} is configured but not loaded; scoring against the baseline", path)),
+        (ScoringMode::Baseline, None) => Check::degraded("model", "no_model", "no model configured; scoring against the learned baseline"),
+    }
+}
+
+// Writes, syncs and removes a file, the way taking a snapshot would
+pub fn snapshot_store(dir: &Path) -> Check {
+    let path = dir.join(PROBE_NAME);
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .and_then(|mut file| {
+            file.write_all(b"ok")?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::remove_file(&path));
+    match written {
+        Ok(()) => Check::ok("snapshot_store", format!("{} writable", dir.display())),
+        Err(e) => Check::failed("snapshot_store", "snapshot_store_unwritable", format!("{}: {}", dir.display(), e)),
+    }
+}
+
+pub fn tpm(section: &TpmSection, tpm: Option<&Tpm>) -> Check {
+    if !section.enabled {
+        return Check::ok("tpm", "not enabled");
+    }
+    match tpm.map(Tpm::ping) {
+        None => Check::failed("tpm", "tpm_unavailable", format!("{} could not be opened at startup", section.tcti)),
+        Some(Err(e)) => Check::failed("tpm", "tpm_unreachable", e.to_string()),
+        Some(Ok(())) => Check::ok("tpm", format!("{} answering", section.tcti)),
+    }
+}
+
+fn clock_secs(clock: libc::clockid_t) -> i64 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `now` is a valid timespec for the kernel to fill
+    unsafe { libc::clock_gettime(clock, &mut now) };
+    now.tv_sec as i64
+}
+
+// Wall clock less boot time: constant unless someone sets the clock
+fn wall_offset() -> i64 {
+    clock_secs(libc::CLOCK_REALTIME) - clock_secs(libc::CLOCK_BOOTTIME)
+}
+
+fn start_offset() -> i64 {
+    static START: OnceLock<i64> = OnceLock::new();
+    *START.get_or_init(wall_offset)
+}
+
+// At daemon start, so later steps are measured from there
+pub fn record_clock() {
+    start_offset();
+}
+
+pub fn clock() -> Check {
+    let now = clock_secs(libc::CLOCK_REALTIME);
+    if now < CLOCK_FLOOR_SECS as i64 {
+        return Check::failed("clock", "clock_unset", format!("wall clock reads {} seconds since the epoch", now));
+    }
+    let step = wall_offset() - start_offset();
+    if step.abs() > MAX_CLOCK_STEP_SECS {
+        return Check::degraded("clock", "clock_stepped", format!("wall clock moved {}s against boot time since the daemon started", step));
+    }
+    Check::ok("clock", format!("stepped {}s since start", step))
+}
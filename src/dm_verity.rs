@@ -0,0 +1,219 @@
+// src/dm_verity.rs
+// Checks that the volumes meant to be verity-protected are, and that the
+// kernel hasn't found corrupted blocks on them. A volume is a mount point
+// (resolved through mountinfo to its device-mapper device) or a dm name.
+// dm-verity keeps its verdict in the target status, "V" until a block
+// fails its hash and "C" from then on, which dmsetup reads for us; the
+// table gives the root hash and what the target does on corruption. A
+// protected volume that isn't a verity device, is missing, or is set to
+// ignore_corruption counts as verification disabled. Both that and
+// corruption are critical, raised when they appear and again if they come
+// back after clearing. Snapshots record every verity device's state so a
+// later investigation can see what was verified when.
+use crate::config::VeritySection;
+use crate::events::{EventBus, IntegrityEvent, SecurityEvent};
+use crate::metrics;
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerityError {
+    #[error("dmsetup: {0}")]
+    Dmsetup(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerityState {
+    Verified,
+    Corrupted,
+    // Mounted or present, but not through a verity target
+    NotVerity,
+    // Not mounted, or no such device
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerityVolume {
+    // As configured: a mount point or dm name; unconfigured devices by dm name
+    pub volume: String,
+    pub device: Option<String>,
+    pub state: VerityState,
+    // algorithm:hex, from the table
+    pub root_hash: Option<String>,
+    // restart_on_corruption, panic_on_corruption, ignore_corruption, or
+    // None for the default of failing the read
+    pub on_corruption: Option<String>,
+    pub protected: bool,
+}
+
+impl VerityVolume {
+    // (finding, detail) when the volume is not as it should be
+    fn problem(&self) -> Option<(&'static str, String)> {
+        match self.state {
+            VerityState::Corrupted => Some(("verity_corrupted", "the kernel reported blocks failing verification".to_string())),
+            VerityState::NotVerity if self.protected => Some(("verity_disabled", "not backed by a dm-verity device".to_string())),
+            VerityState::Missing if self.protected => Some(("verity_disabled", "no such mount or device".to_string())),
+            VerityState::Verified if self.on_corruption.as_deref() == Some("ignore_corruption") => {
+                Some(("verity_disabled", "ignore_corruption is set; corrupted blocks are read anyway".to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn dmsetup(args: &[&str]) -> Result<String, VerityError> {
+    let output = Command::new("dmsetup")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| VerityError::Dmsetup(e.to_string()))?;
+    if !output.status.success() {
+        return Err(VerityError::Dmsetup(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// "name: <start> <length> verity <args...>", one line per target; the
+// device's last verity line wins
+fn verity_lines(output: &str) -> BTreeMap<String, Vec<String>> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, target) = line.split_once(": ")?;
+            let fields: Vec<String> = target.split_whitespace().map(str::to_string).collect();
+            (fields.get(2).map(String::as_str) == Some("verity")).then(|| (name.to_string(), fields[3..].to_vec()))
+        })
+        .collect()
+}
+
+// The dm name behind a mount point, if it is on a device-mapper device
+fn mount_device(mount_point: &str) -> Option<Option<String>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    // Later mounts hide earlier ones on the same point
+    let devnum = mountinfo.lines().rev().find_map(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        (fields.get(4) == Some(&mount_point)).then(|| fields[2].to_string())
+    })?;
+    let name = std::fs::read_to_string(format!("/sys/dev/block/{}/dm/name", devnum)).ok();
+    Some(name.map(|name| name.trim().to_string()))
+}
+
+// Every verity device, and every configured volume whether it is one or not
+pub fn inspect(volumes: &[String]) -> Result<Vec<VerityVolume>, VerityError> {
+    let status = verity_lines(&dmsetup(&["status", "--target", "verity"])?);
+    let tables = verity_lines(&dmsetup(&["table", "--target", "verity"])?);
+    let describe = |volume: &str, device: &str, protected: bool| {
+        let Some(status) = status.get(device) else {
+            return VerityVolume { volume: volume.to_string(), device: Some(device.to_string()), state: VerityState::NotVerity, root_hash: None, on_corruption: None, protected };
+        };
+        let state = if status.first().map(String::as_str) == Some("C") { VerityState::Corrupted } else { VerityState::Verified };
+        // <version> <data dev> <hash dev> <data block> <hash block> <blocks>
+        // <hash start> <algorithm> <root digest> <salt> [<#opts> <opts>...]
+        let table = tables.get(device).map(Vec::as_slice).unwrap_or_default();
+        let root_hash = table.get(7).zip(table.get(8)).map(|(algorithm, digest)| format!("{}:{}", algorithm, digest));
+        let on_corruption = table.iter().skip(10).find(|opt| opt.ends_with("_corruption")).cloned();
+        VerityVolume { volume: volume.to_string(), device: Some(device.to_string()), state, root_hash, on_corruption, protected }
+    };
+
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    for volume in volumes {
+        let device = if volume.starts_with('/') {
+            match mount_device(volume) {
+                None => None,
+                Some(None) => {
+                    found.push(VerityVolume { volume: volume.clone(), device: None, state: VerityState::NotVerity, root_hash: None, on_corruption: None, protected: true });
+                    continue;
+                }
+                Some(Some(device)) => Some(device),
+            }
+        } else {
+            Some(volume.clone()).filter(|device| std::path::Path::new("/dev/mapper").join(device).exists())
+        };
+        let Some(device) = device else {
+            found.push(VerityVolume { volume: volume.clone(), device: None, state: VerityState::Missing, root_hash: None, on_corruption: None, protected: true });
+            continue;
+        };
+        seen.insert(device.clone());
+        found.push(describe(volume, &device, true));
+    }
+    for device in status.keys().filter(|device| !seen.contains(*device)) {
+        found.push(describe(device, device, false));
+    }
+    Ok(found)
+}
+
+pub struct VerityMonitor {
+    section: VeritySection,
+    volumes: Mutex<Vec<VerityVolume>>,
+    // (volume, finding) reported and not cleared since
+    standing: Mutex<HashSet<(String, &'static str)>>,
+}
+
+impl VerityMonitor {
+    pub fn new(section: &VeritySection) -> Self {
+        Self { section: section.clone(), volumes: Mutex::new(Vec::new()), standing: Mutex::new(HashSet::new()) }
+    }
+
+    // As of the last check
+    pub fn volumes(&self) -> Vec<VerityVolume> {
+        self.volumes.lock().unwrap().clone()
+    }
+
+    // Inspects the volumes and returns what newly went wrong
+    pub fn check(&self) -> Result<Vec<IntegrityEvent>, VerityError> {
+        let volumes = inspect(&self.section.volumes)?;
+        let mut standing = self.standing.lock().unwrap();
+        let current: HashSet<(String, &'static str)> = volumes.iter().filter_map(|v| v.problem().map(|(finding, _)| (v.volume.clone(), finding))).collect();
+        standing.retain(|problem| current.contains(problem));
+
+        let mut findings = Vec::new();
+        for volume in &volumes {
+            let Some((finding, detail)) = volume.problem() else {
+                continue;
+            };
+            if !standing.insert((volume.volume.clone(), finding)) {
+                continue;
+            }
+            tracing::error!("dm-verity {} on {}: {}", finding.replace('_', " "), volume.volume, detail);
+            metrics::global().incr("qks_integrity_findings_total", &[("source", "dm-verity"), ("finding", finding)]);
+            findings.push(IntegrityEvent {
+                source: "dm-verity".to_string(),
+                finding: finding.to_string(),
+                critical: true,
+                path: Some(volume.volume.clone()),
+                pid: None,
+                expected: None,
+                actual: volume.root_hash.clone(),
+                detail: Some(detail),
+                timestamp: systemd::now_secs(),
+            });
+        }
+        *self.volumes.lock().unwrap() = volumes;
+        Ok(findings)
+    }
+
+    // Checks at once, then every interval_secs
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.section.interval_secs));
+            loop {
+                ticker.tick().await;
+                let monitor = self.clone();
+                match tokio::task::spawn_blocking(move || monitor.check()).await {
+                    Ok(Ok(findings)) => {
+                        for finding in findings {
+                            bus.publish(SecurityEvent::Integrity(finding));
+                        }
+                    }
+                    Ok(Err(e)) => tracing::warn!("dm-verity status not read: {}", e),
+                    Err(e) => tracing::error!("dm-verity check panicked: {}", e),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,186 @@
+// src/docker_events.rs
+// The containerd watcher's counterpart for plain Docker hosts, chosen with
+// [containers] runtime = "docker". Follows the Engine API's event stream
+// on the Docker socket for container start and die; each started container
+// is inspected for its init PID, image and labels and then enrolled
+// exactly as containerd tasks are (ContainerWatcher::admit), so tokens,
+// re-randomization, container exclusions and event attribution all behave
+// the same. Containers already running when the stream is (re)joined are
+// enrolled from the container list first. Processes the container forks
+// later are attributed through their cgroup (docker-<id>.scope).
+//
+// Requests are HTTP/1.0, which makes dockerd send bodies unchunked and
+// close the connection after them: the event stream is then plain JSON
+// lines, and nothing here needs an HTTP client that can dial a unix socket.
+use crate::container_events::{ContainerInfo, ContainerWatcher};
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Docker's containers live in this containerd namespace
+const DOCKER_NAMESPACE: &str = "moby";
+const EVENTS_PATH: &str = r#"/events?filters={"type":["container"],"event":["start","die"]}"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DockerError {
+    #[error("{}: {source}", .socket.display())]
+    Connect { socket: PathBuf, source: std::io::Error },
+    #[error("Docker API: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Docker API: GET {path} returned {status}")]
+    Status { path: String, status: u16 },
+    #[error("Docker API: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "Action", default)]
+    action: String,
+    #[serde(rename = "Actor")]
+    actor: Actor,
+}
+
+#[derive(Deserialize)]
+struct Actor {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "Attributes", default)]
+    attributes: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Summary {
+    #[serde(rename = "Id")]
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Inspect {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "State")]
+    state: State,
+    #[serde(rename = "Config")]
+    config: Config,
+}
+
+#[derive(Deserialize)]
+struct State {
+    #[serde(rename = "Pid", default)]
+    pid: u32,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(rename = "Image", default)]
+    image: String,
+    #[serde(rename = "Labels", default)]
+    labels: Option<BTreeMap<String, String>>,
+}
+
+pub(crate) struct DockerWatcher {
+    socket: PathBuf,
+    containers: Arc<ContainerWatcher>,
+    // Init PID of each enrolled container, for its die event
+    pids: DashMap<String, u32>,
+}
+
+impl DockerWatcher {
+    pub(crate) fn new(socket: PathBuf, containers: Arc<ContainerWatcher>) -> Self {
+        Self { socket, containers, pids: DashMap::new() }
+    }
+
+    pub(crate) fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.follow().await {
+                    Ok(()) => tracing::warn!("Docker event stream ended; reconnecting"),
+                    Err(e) => tracing::warn!("{}; retrying in {}s", e, RECONNECT_DELAY.as_secs()),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    // Sends the request and reads past the headers, leaving the body
+    async fn get(&self, path: &str) -> Result<BufReader<UnixStream>, DockerError> {
+        let mut stream = UnixStream::connect(&self.socket).await.map_err(|source| DockerError::Connect { socket: self.socket.clone(), source })?;
+        stream.write_all(format!("GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path).as_bytes()).await?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        // HTTP/1.x 200 OK
+        let status = line.split_whitespace().nth(1).and_then(|code| code.parse().ok()).unwrap_or(0);
+        if status != 200 {
+            return Err(DockerError::Status { path: path.to_string(), status });
+        }
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                return Ok(reader);
+            }
+        }
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, DockerError> {
+        let mut body = Vec::new();
+        self.get(path).await?.read_to_end(&mut body).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn follow(&self) -> Result<(), DockerError> {
+        // Subscribe before listing, so nothing starts in between unseen
+        let mut events = self.get(EVENTS_PATH).await?.lines();
+        tracing::info!("Following Docker events on {}", self.socket.display());
+
+        let running: Vec<Summary> = self.get_json("/containers/json").await?;
+        for summary in running.into_iter().filter(|summary| !self.pids.contains_key(&summary.id)) {
+            self.start_container(&summary.id).await;
+        }
+
+        while let Some(line) = events.next_line().await? {
+            let event: Event = match serde_json::from_str(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("Undecodable Docker event: {}", e);
+                    continue;
+                }
+            };
+            match event.action.as_str() {
+                "start" => self.start_container(&event.actor.id).await,
+                "die" => {
+                    let exit_status = event.actor.attributes.get("exitCode").and_then(|code| code.parse().ok()).unwrap_or(0);
+                    if let Some((_, pid)) = self.pids.remove(&event.actor.id) {
+                        self.containers.unenroll(pid, exit_status);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_container(&self, id: &str) {
+        let inspect: Inspect = match self.get_json(&format!("/containers/{}/json", id)).await {
+            Ok(inspect) => inspect,
+            Err(e) => {
+                tracing::warn!("Container {}: {}", id, e);
+                return;
+            }
+        };
+        // Already gone again
+        if inspect.state.pid == 0 {
+            return;
+        }
+        let info = ContainerInfo::new(inspect.id.clone(), DOCKER_NAMESPACE.to_string(), inspect.config.image, inspect.config.labels.unwrap_or_default());
+        self.pids.insert(inspect.id, inspect.state.pid);
+        self.containers.admit(inspect.state.pid, info);
+    }
+}
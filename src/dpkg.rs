@@ -0,0 +1,230 @@
+// src/dpkg.rs
+// What dpkg recorded about the files it installed. Each package's
+// /var/lib/dpkg/info/<package>[:<arch>].md5sums lists "<md5>  <path>" with
+// the path relative to /; conffiles carry their md5 in the status file
+// instead. Debian's merged /usr means a file may be listed under lib/ and
+// found under usr/lib/ or the other way round, so paths are compared after
+// merged_path.
+//
+// A few packages ship no md5sums. For those the .deb apt downloaded may
+// still be in its cache: once its SHA256 matches the one in apt's package
+// index (which apt checked against the signed Release file), the md5 of
+// every file in it is as good as the missing list.
+use crate::audit_log::hex;
+use md5::{Digest, Md5};
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const INFO_DIR: &str = "/var/lib/dpkg/info";
+const STATUS: &str = "/var/lib/dpkg/status";
+const DIVERSIONS: &str = "/var/lib/dpkg/diversions";
+const APT_ARCHIVES: &str = "/var/cache/apt/archives";
+const APT_LISTS: &str = "/var/lib/apt/lists";
+
+// The directories merged /usr folds into /usr
+const MERGED: [&str; 4] = ["/bin/", "/sbin/", "/lib/", "/lib64/"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owned {
+    pub package: String,
+    pub md5: String,
+}
+
+// The path as it is under /usr on a merged-/usr system
+pub fn merged_path(path: &str) -> String {
+    match MERGED.iter().any(|dir| path.starts_with(dir)) {
+        true => format!("/usr{}", path),
+        false => path.to_string(),
+    }
+}
+
+// "openssh-server:amd64.md5sums" -> "openssh-server"
+fn package_name(file_name: &str) -> Option<&str> {
+    let package = file_name.strip_suffix(".md5sums")?;
+    Some(package.split_once(':').map_or(package, |(name, _)| name))
+}
+
+// Every file dpkg installed whose merged path `wanted` accepts, by merged
+// path. Packages that can't be read are skipped.
+pub fn owned_files(wanted: impl Fn(&str) -> bool) -> std::io::Result<HashMap<String, Owned>> {
+    let mut owned = HashMap::new();
+    for entry in std::fs::read_dir(INFO_DIR)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(package) = file_name.to_str().and_then(package_name) else {
+            continue;
+        };
+        let Ok(md5sums) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        for line in md5sums.lines() {
+            let Some((md5, path)) = line.split_once("  ") else {
+                continue;
+            };
+            let path = merged_path(&format!("/{}", path));
+            if wanted(&path) {
+                owned.insert(path, Owned { package: package.to_string(), md5: md5.to_string() });
+            }
+        }
+    }
+    Ok(owned)
+}
+
+// An installed package, from the status file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Installed {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    // (merged path, md5); obsolete conffiles left out
+    pub conffiles: Vec<(String, String)>,
+}
+
+// Packages whose status is "install ok installed"
+pub fn installed() -> std::io::Result<Vec<Installed>> {
+    let status = std::fs::read_to_string(STATUS)?;
+    let mut packages = Vec::new();
+    for stanza in status.split("\n\n") {
+        let field = |name: &str| stanza.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':')).map(|value| value.trim().to_string());
+        if field("Status").map_or(true, |status| !status.ends_with(" installed")) {
+            continue;
+        }
+        let (Some(package), Some(version), Some(architecture)) = (field("Package"), field("Version"), field("Architecture")) else {
+            continue;
+        };
+        // " /etc/ssh/sshd_config <md5> [obsolete]" under "Conffiles:"
+        let conffiles = stanza
+            .lines()
+            .skip_while(|line| !line.starts_with("Conffiles:"))
+            .skip(1)
+            .take_while(|line| line.starts_with(' '))
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                match fields.as_slice() {
+                    [path, md5] if md5.len() == 32 => Some((merged_path(path), md5.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        packages.push(Installed { package, version, architecture, conffiles });
+    }
+    Ok(packages)
+}
+
+// The package's md5sums, by merged path; None when it ships none
+pub fn md5sums(package: &Installed) -> Option<Vec<(String, String)>> {
+    let info = Path::new(INFO_DIR);
+    let md5sums = std::fs::read_to_string(info.join(format!("{}:{}.md5sums", package.package, package.architecture)))
+        .or_else(|_| std::fs::read_to_string(info.join(format!("{}.md5sums", package.package))))
+        .ok()?;
+    Some(md5sums.lines().filter_map(|line| line.split_once("  ")).map(|(md5, path)| (merged_path(&format!("/{}", path)), md5.to_string())).collect())
+}
+
+// Paths another package's file has been diverted away from; what is there
+// now is not what the md5sums describe
+pub fn diversions() -> HashSet<String> {
+    let Ok(diversions) = std::fs::read_to_string(DIVERSIONS) else {
+        return HashSet::new();
+    };
+    // Three lines each: diverted path, where it went, diverting package
+    diversions.lines().step_by(3).map(merged_path).collect()
+}
+
+// The package's .deb in apt's cache, if it is still there
+pub fn cached_deb(package: &Installed) -> Option<PathBuf> {
+    let path = Path::new(APT_ARCHIVES).join(format!("{}_{}_{}.deb", package.package, package.version.replace(':', "%3a"), package.architecture));
+    path.exists().then_some(path)
+}
+
+// SHA256 of each wanted package's .deb, as apt's package indexes give it
+pub fn index_sha256(wanted: &[&Installed]) -> HashMap<String, String> {
+    let keys: HashSet<(&str, &str, &str)> = wanted.iter().map(|p| (p.package.as_str(), p.version.as_str(), p.architecture.as_str())).collect();
+    let mut found = HashMap::new();
+    let Ok(lists) = std::fs::read_dir(APT_LISTS) else {
+        return found;
+    };
+    for list in lists.flatten().filter(|entry| entry.file_name().to_string_lossy().ends_with("_Packages")) {
+        let Ok(index) = std::fs::read_to_string(list.path()) else {
+            continue;
+        };
+        for stanza in index.split("\n\n") {
+            let field = |name: &str| stanza.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(": "));
+            let (Some(package), Some(version), Some(architecture), Some(sha256)) = (field("Package"), field("Version"), field("Architecture"), field("SHA256")) else {
+                continue;
+            };
+            if keys.contains(&(package, version, architecture)) {
+                found.insert(package.to_string(), sha256.to_string());
+            }
+        }
+    }
+    found
+}
+
+// The md5 of every regular file in a .deb, by merged path, provided the
+// .deb's SHA256 is `sha256`
+pub fn deb_md5sums(deb: &Path, sha256: &str) -> std::io::Result<Vec<(String, String)>> {
+    let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", deb.display(), what));
+    let bytes = std::fs::read(deb)?;
+    if hex(digest::digest(&digest::SHA256, &bytes).as_ref()) != sha256 {
+        return Err(invalid("does not match apt's package index"));
+    }
+
+    // An ar archive: "!<arch>\n", then members with a 60-byte header
+    // (name 16, mtime 12, uid 6, gid 6, mode 8, size 10, magic 2), each
+    // padded to an even length
+    let mut offset = 8;
+    if !bytes.starts_with(b"!<arch>\n") {
+        return Err(invalid("not a .deb"));
+    }
+    while offset + 60 <= bytes.len() {
+        let header = &bytes[offset..offset + 60];
+        let name = String::from_utf8_lossy(&header[..16]).trim_end().trim_end_matches('/').to_string();
+        let size: usize = String::from_utf8_lossy(&header[48..58]).trim().parse().map_err(|_| invalid("bad member size"))?;
+        let data = bytes.get(offset + 60..offset + 60 + size).ok_or_else(|| invalid("truncated"))?;
+        offset += 60 + size + size % 2;
+        let tar: Box<dyn Read> = match name.as_str() {
+            "data.tar" => Box::new(data),
+            "data.tar.xz" => Box::new(xz2::read::XzDecoder::new(data)),
+            "data.tar.zst" => Box::new(zstd::stream::read::Decoder::new(data)?),
+            name if name.starts_with("data.tar") => return Err(invalid(&format!("{} is not supported", name))),
+            _ => continue,
+        };
+        let mut md5sums = Vec::new();
+        let mut archive = tar::Archive::new(tar);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            // "./usr/bin/ls"
+            let path = format!("/{}", entry.path()?.to_string_lossy().trim_start_matches("./"));
+            let mut hasher = Md5::new();
+            std::io::copy(&mut entry, &mut hasher)?;
+            md5sums.push((merged_path(&path), hex(&hasher.finalize())));
+        }
+        return Ok(md5sums);
+    }
+    Err(invalid("no data member"))
+}
+
+// When a package was last installed or removed; the info directory changes
+// with every one
+pub fn last_change() -> Option<SystemTime> {
+    std::fs::metadata(INFO_DIR).and_then(|meta| meta.modified()).ok()
+}
+
+pub fn file_md5(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hex(&hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
@@ -0,0 +1,230 @@
+// src/drift_monitor.rs
+// Feature drift against the training distribution. Training fits a
+// per-feature quantile histogram (saved next to the model as drift.json or
+// <model>.drift.json); at runtime incoming rows are binned on the same
+// edges and, once per window, compared with PSI and KL divergence. A model
+// scoring traffic it was never trained on degrades silently; this makes it
+// loud.
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// Keeps empty bins from making PSI/KL infinite
+const SMOOTHING: f64 = 1e-4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DriftError {
+    #[error("cannot fit a drift reference on an empty dataset")]
+    EmptyDataset,
+    #[error("ragged dataset: rows of {expected} and {found} features")]
+    Ragged { expected: usize, found: usize },
+    #[error("drift reference I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("drift reference format: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FeatureHistogram {
+    // Interior bin edges; bin i holds values below edges[i]
+    edges: Vec<f32>,
+    expected: Vec<f64>,
+}
+
+impl FeatureHistogram {
+    fn bin(&self, x: f32) -> usize {
+        self.edges.partition_point(|&edge| edge <= x)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriftReference {
+    features: Vec<FeatureHistogram>,
+}
+
+impl DriftReference {
+    pub fn fit(dataset: &[Vec<f32>], bins: usize) -> Result<Self, DriftError> {
+        let width = dataset.first().map_or(0, |row| row.len());
+        if width == 0 {
+            return Err(DriftError::EmptyDataset);
+        }
+        if let Some(row) = dataset.iter().find(|row| row.len() != width) {
+            return Err(DriftError::Ragged { expected: width, found: row.len() });
+        }
+
+        let bins = bins.max(2);
+        let features = (0..width)
+            .map(|i| {
+                let mut column: Vec<f32> = dataset.iter().map(|row| row[i]).collect();
+                column.sort_by(|a, b| a.total_cmp(b));
+
+                // Quantile edges; ties collapse, so heavy point masses get one bin
+                let mut edges: Vec<f32> = (1..bins)
+                    .map(|q| column[q * column.len() / bins])
+                    .collect();
+                edges.dedup();
+
+                let mut histogram = FeatureHistogram { expected: vec![0.0; edges.len() + 1], edges };
+                for &x in &column {
+                    let bin = histogram.bin(x);
+                    histogram.expected[bin] += 1.0 / column.len() as f64;
+                }
+                histogram
+            })
+            .collect();
+
+        Ok(Self { features })
+    }
+
+    pub fn width(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn sidecar_path(model_path: &Path) -> PathBuf {
+        if model_path.is_dir() {
+            model_path.join("drift.json")
+        } else {
+            model_path.with_extension("drift.json")
+        }
+    }
+
+    pub fn save(&self, model_path: &Path) -> Result<(), DriftError> {
+        std::fs::write(Self::sidecar_path(model_path), serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    // Missing file: the model was exported without a reference
+    pub fn load(model_path: &Path) -> Result<Option<Self>, DriftError> {
+        match std::fs::read(Self::sidecar_path(model_path)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DriftThresholds {
+    // PSI above 0.25 is the conventional "significant shift"
+    pub psi: f64,
+    pub kl: f64,
+    // Rows per comparison
+    pub window: usize,
+    // Ask the training-data export pipeline to start collecting when
+    // drift fires
+    pub request_retraining: bool,
+}
+
+impl Default for DriftThresholds {
+    fn default() -> Self {
+        Self { psi: 0.25, kl: 0.1, window: 5000, request_retraining: false }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeatureDrift {
+    pub feature: usize,
+    pub psi: f64,
+    pub kl: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelDrift {
+    pub model_version: u64,
+    pub samples: usize,
+    // Only the features over a threshold, worst PSI first
+    pub drifted: Vec<FeatureDrift>,
+    pub retrain_requested: bool,
+    pub timestamp: u64,
+}
+
+pub struct DriftMonitor {
+    thresholds: DriftThresholds,
+    counts: Vec<Vec<u64>>,
+    samples: usize,
+    model_version: u64,
+    events: mpsc::UnboundedSender<ModelDrift>,
+}
+
+impl DriftMonitor {
+    pub fn new(thresholds: DriftThresholds) -> (Self, mpsc::UnboundedReceiver<ModelDrift>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self { thresholds, counts: Vec::new(), samples: 0, model_version: 0, events: tx },
+            rx,
+        )
+    }
+
+    // Bin one raw (unscaled) row; every `window` rows compare and reset
+    pub fn observe(&mut self, reference: &DriftReference, model_version: u64, features: &[f32]) -> Option<ModelDrift> {
+        if features.len() != reference.width() {
+            return None;
+        }
+        if model_version != self.model_version || self.counts.len() != reference.width() {
+            // New model, new reference: don't mix windows across them
+            self.counts = reference.features.iter().map(|h| vec![0; h.expected.len()]).collect();
+            self.samples = 0;
+            self.model_version = model_version;
+        }
+
+        for ((counts, histogram), &x) in self.counts.iter_mut().zip(&reference.features).zip(features) {
+            counts[histogram.bin(x)] += 1;
+        }
+        self.samples += 1;
+        if self.samples < self.thresholds.window.max(1) {
+            return None;
+        }
+
+        let drift = self.evaluate(reference);
+        for counts in &mut self.counts {
+            counts.iter_mut().for_each(|c| *c = 0);
+        }
+        self.samples = 0;
+
+        let drift = drift?;
+        tracing::warn!(
+            "Model {} input drift on {} feature(s), worst PSI {:.3}",
+            drift.model_version, drift.drifted.len(), drift.drifted[0].psi
+        );
+        let _ = self.events.send(drift.clone());
+        Some(drift)
+    }
+
+    fn evaluate(&self, reference: &DriftReference) -> Option<ModelDrift> {
+        let mut drifted: Vec<FeatureDrift> = self.counts.iter()
+            .zip(&reference.features)
+            .enumerate()
+            .map(|(feature, (counts, histogram))| {
+                let (psi, kl) = divergences(&histogram.expected, counts, self.samples);
+                FeatureDrift { feature, psi, kl }
+            })
+            .filter(|d| d.psi > self.thresholds.psi || d.kl > self.thresholds.kl)
+            .collect();
+        if drifted.is_empty() {
+            return None;
+        }
+        drifted.sort_by(|a, b| b.psi.total_cmp(&a.psi));
+
+        Some(ModelDrift {
+            model_version: self.model_version,
+            samples: self.samples,
+            drifted,
+            retrain_requested: self.thresholds.request_retraining,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        })
+    }
+}
+
+// PSI and KL(observed || expected) over smoothed bin proportions
+fn divergences(expected: &[f64], counts: &[u64], samples: usize) -> (f64, f64) {
+    let mut psi = 0.0;
+    let mut kl = 0.0;
+    for (&e, &c) in expected.iter().zip(counts) {
+        let e = e.max(SMOOTHING);
+        let o = (c as f64 / samples.max(1) as f64).max(SMOOTHING);
+        psi += (o - e) * (o / e).ln();
+        kl += o * (o / e).ln();
+    }
+    (psi, kl.max(0.0))
+}
@@ -1,13 +1,161 @@
 // src/ebpf_monitor.rs
 use bcc::BccError;
 use bcc::core::BPF;
+use crate::metrics;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use dashmap::DashMap;
 use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+// Syscall events buffered between the perf reader and the feature
+// pipeline; past this the reader drops and counts instead of queueing
+pub const SYSCALL_QUEUE: usize = 65536;
+// trace_config slots shared with the BPF program
+const TRACE_SAMPLE_SLOT: u32 = 0;
+const TRACE_SELF_SLOT: u32 = 1;
 
 pub struct EBPFMonitor {
     bpf: Arc<BPF>,
     syscall_stats: Arc<DashMap<u32, SyscallStat>>,
+    // Syscall events lost to a full queue since start
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MprotectEvent {
+    pub pid: u32,
+    pub prot: u32,
+    pub start: u64,
+    pub len: u64,
+}
+
+impl MprotectEvent {
+    pub fn is_write_exec(&self) -> bool {
+        let wx = (libc::PROT_WRITE | libc::PROT_EXEC) as u32;
+        self.prot & wx == wx
+    }
+}
+
+// One completed syscall, for per-process feature extraction
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SyscallEvent {
+    pub pid: u32,
+    pub syscall: u32,
+    pub duration_ns: u64,
+    pub retval: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkAction {
+    // A TCP socket started connecting out
+    Connect,
+    Listen,
+    // setns or unshare moved the process into another network namespace
+    NamespaceSwitch,
+}
+
+impl NetworkAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NetworkAction::Connect => "connect",
+            NetworkAction::Listen => "listen",
+            NetworkAction::NamespaceSwitch => "namespace_switch",
+        }
+    }
+}
+
+// A network event, tagged with the network namespace (its nsfs inode) the
+// socket or process is in. Addresses are unset for namespace switches.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NetworkEvent {
+    pub pid: u32,
+    pub action: NetworkAction,
+    pub netns: u64,
+    // The namespace left, when a switch was seen from a known one
+    pub previous_netns: Option<u64>,
+    pub source: Option<IpAddr>,
+    pub source_port: u16,
+    pub destination: Option<IpAddr>,
+    pub destination_port: u16,
+    pub timestamp: u64,
+}
+
+// Where process telemetry comes from: eBPF when it loads, else the proc
+// connector (proc_connector.rs), else /proc polling (proc_poll.rs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TelemetrySource {
+    Ebpf,
+    ProcConnector,
+    ProcPoll,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fidelity {
+    // Every occurrence, as it happens
+    Full,
+    // Inferred from differences between scans; short-lived changes are missed
+    Sampled,
+    Unavailable,
+}
+
+// What the running source sees of each kind of activity
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Coverage {
+    pub source: TelemetrySource,
+    pub syscalls: Fidelity,
+    pub process_lifecycle: Fidelity,
+    pub file_descriptors: Fidelity,
+    pub memory_maps: Fidelity,
+    pub write_exec: Fidelity,
+    pub network: Fidelity,
+}
+
+impl TelemetrySource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TelemetrySource::Ebpf => "ebpf",
+            TelemetrySource::ProcConnector => "proc-connector",
+            TelemetrySource::ProcPoll => "proc-poll",
+            TelemetrySource::None => "none",
+        }
+    }
+
+    pub fn coverage(self) -> Coverage {
+        use Fidelity::*;
+        let (syscalls, process_lifecycle, file_descriptors, memory_maps, write_exec, network) = match self {
+            TelemetrySource::Ebpf => (Full, Full, Full, Full, Full, Full),
+            TelemetrySource::ProcConnector => (Unavailable, Full, Unavailable, Unavailable, Unavailable, Unavailable),
+            TelemetrySource::ProcPoll => (Unavailable, Sampled, Sampled, Sampled, Sampled, Unavailable),
+            TelemetrySource::None => (Unavailable, Unavailable, Unavailable, Unavailable, Unavailable, Unavailable),
+        };
+        Coverage { source: self, syscalls, process_lifecycle, file_descriptors, memory_maps, write_exec, network }
+    }
+}
+
+impl Coverage {
+    // "syscalls unavailable, memory_maps sampled, ..."; empty at full coverage
+    pub fn degraded(&self) -> String {
+        let kinds = [
+            ("syscalls", self.syscalls),
+            ("process_lifecycle", self.process_lifecycle),
+            ("file_descriptors", self.file_descriptors),
+            ("memory_maps", self.memory_maps),
+            ("write_exec", self.write_exec),
+            ("network", self.network),
+        ];
+        kinds
+            .iter()
+            .filter(|(_, fidelity)| *fidelity != Fidelity::Full)
+            .map(|(kind, fidelity)| format!("{} {}", kind, if *fidelity == Fidelity::Sampled { "sampled" } else { "unavailable" }))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -29,6 +177,10 @@ BPF_HASH(syscall_start, u64, u64);
 BPF_HASH(syscall_count, u32, u64);
 BPF_HASH(syscall_errors, u32, u64);
 BPF_PERF_OUTPUT(events);
+BPF_PERF_OUTPUT(syscall_trace);
+// [0]: trace one process in N (0 or 1: all); [1]: the daemon's own TGID,
+// whose syscalls would otherwise feed back into the stream
+BPF_ARRAY(trace_config, u32, 2);
 
 struct data_t {
     u32 pid;
@@ -68,19 +220,130 @@ int syscall_exit(struct pt_regs *ctx) {
         (*errors)++;
     }
     
+    struct data_t data = {};
+    data.pid = pid_tgid >> 32;
+    data.syscall = syscall_id;
+    data.duration = duration;
+    data.retval = retval;
+    
+    // Trace for the feature pipeline, filtered here so the perf buffer
+    // never carries what userspace would drop. Sampling picks whole
+    // processes by TGID hash, keeping each traced window complete.
+    int sample_slot = 0, self_slot = 1;
+    u32 *sample = trace_config.lookup(&sample_slot);
+    u32 *self = trace_config.lookup(&self_slot);
+    bool own = self && *self == data.pid;
+    bool sampled_out = sample && *sample > 1 && (data.pid * 2654435761u) % *sample != 0;
+    if (!own && !sampled_out) {
+        syscall_trace.perf_submit(ctx, &data, sizeof(data));
+    }
+    
     // Send to userspace if suspicious
     if (duration > 1000000000) { // >1 second
-        struct data_t data = {};
-        data.pid = pid_tgid >> 32;
-        data.syscall = syscall_id;
-        data.duration = duration;
-        data.retval = retval;
         events.perf_submit(ctx, &data, sizeof(data));
     }
     
     syscall_start.delete(&pid_tgid);
     return 0;
 }
+
+BPF_PERF_OUTPUT(mprotect_events);
+
+struct mprotect_t {
+    u32 pid;
+    u32 prot;
+    u64 start;
+    u64 len;
+};
+
+// Only W+X requests are interesting to the W^X enforcer
+TRACEPOINT_PROBE(syscalls, sys_enter_mprotect) {
+    u32 prot = args->prot;
+    if ((prot & 0x2) == 0 || (prot & 0x4) == 0) {
+        return 0;
+    }
+    
+    struct mprotect_t data = {};
+    data.pid = bpf_get_current_pid_tgid() >> 32;
+    data.prot = prot;
+    data.start = args->start;
+    data.len = args->len;
+    mprotect_events.perf_submit(args, &data, sizeof(data));
+    return 0;
+}
+
+#include <net/sock.h>
+#include <net/net_namespace.h>
+#include <linux/nsproxy.h>
+
+BPF_PERF_OUTPUT(network_events);
+
+struct network_t {
+    u32 pid;
+    u32 action;
+    u64 netns;
+    u16 family;
+    u16 sport;
+    u16 dport;
+    u16 pad;
+    u8 saddr[16];
+    u8 daddr[16];
+};
+
+// TCP_SYN_SENT is a connect, TCP_LISTEN a listen; both run in the
+// caller's context. The socket's own namespace is reported, which is the
+// one its traffic is subject to.
+TRACEPOINT_PROBE(sock, inet_sock_set_state) {
+    if (args->protocol != IPPROTO_TCP) {
+        return 0;
+    }
+    u32 action;
+    if (args->newstate == TCP_SYN_SENT) {
+        action = 0;
+    } else if (args->newstate == TCP_LISTEN) {
+        action = 1;
+    } else {
+        return 0;
+    }
+
+    struct network_t data = {};
+    struct sock *sk = (struct sock *)args->skaddr;
+    data.pid = bpf_get_current_pid_tgid() >> 32;
+    data.action = action;
+    data.netns = sk->__sk_common.skc_net.net->ns.inum;
+    data.family = args->family;
+    data.sport = args->sport;
+    data.dport = args->dport;
+    if (args->family == AF_INET) {
+        __builtin_memcpy(data.saddr, args->saddr, 4);
+        __builtin_memcpy(data.daddr, args->daddr, 4);
+    } else {
+        __builtin_memcpy(data.saddr, args->saddr_v6, 16);
+        __builtin_memcpy(data.daddr, args->daddr_v6, 16);
+    }
+    network_events.perf_submit(args, &data, sizeof(data));
+    return 0;
+}
+
+// The namespace the caller ends up in after a successful setns or unshare;
+// other namespace types are reported too and filtered in userspace
+static int namespace_switch(void *ctx) {
+    struct network_t data = {};
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task();
+    data.pid = bpf_get_current_pid_tgid() >> 32;
+    data.action = 2;
+    data.netns = task->nsproxy->net_ns->ns.inum;
+    network_events.perf_submit(ctx, &data, sizeof(data));
+    return 0;
+}
+
+TRACEPOINT_PROBE(syscalls, sys_exit_setns) {
+    return args->ret == 0 ? namespace_switch(args) : 0;
+}
+
+TRACEPOINT_PROBE(syscalls, sys_exit_unshare) {
+    return args->ret == 0 ? namespace_switch(args) : 0;
+}
 "#;
 
         let mut bpf = BPF::new(bpf_code)?;
@@ -89,10 +352,36 @@ int syscall_exit(struct pt_regs *ctx) {
         bpf.attach_kprobe("syscall_entry", "syscall_entry")?;
         bpf.attach_kretprobe("syscall_exit", "syscall_exit")?;
         
-        Ok(Self {
+        let monitor = Self {
             bpf: Arc::new(bpf),
             syscall_stats: Arc::new(DashMap::new()),
-        })
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        monitor.set_trace_config(TRACE_SELF_SLOT, std::process::id())?;
+        Ok(monitor)
+    }
+    
+    // Trace one process in `one_in` for the syscall stream; 1 traces all
+    pub fn set_syscall_sampling(&self, one_in: u32) -> Result<(), BccError> {
+        self.set_trace_config(TRACE_SAMPLE_SLOT, one_in)
+    }
+    
+    fn set_trace_config(&self, slot: u32, value: u32) -> Result<(), BccError> {
+        let mut table = self.bpf.table("trace_config")?;
+        table.set(&mut slot.to_ne_bytes(), &mut value.to_ne_bytes())
+    }
+    
+    pub fn dropped_syscall_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    
+    // Per-syscall counters, busiest first
+    pub fn syscall_stats(&self) -> Vec<(u32, SyscallStat)> {
+        let mut stats: Vec<(u32, SyscallStat)> = self.syscall_stats.iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        stats.sort_by(|a, b| b.1.count.cmp(&a.1.count));
+        stats
     }
     
     pub fn start_monitoring(&self) -> tokio::task::JoinHandle<()> {
@@ -124,6 +413,8 @@ int syscall_exit(struct pt_regs *ctx) {
                     
                     if stat.suspicious_score > 0.8 {
                         tracing::warn!(
+                            syscall,
+                            score = stat.suspicious_score,
                             "Suspicious syscall detected: {} (score: {:.2})",
                             syscall, stat.suspicious_score
                         );
@@ -134,6 +425,131 @@ int syscall_exit(struct pt_regs *ctx) {
         })
     }
     
+    pub fn start_mprotect_watch(&self) -> (tokio::task::JoinHandle<()>, mpsc::UnboundedReceiver<MprotectEvent>) {
+        let bpf = self.bpf.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        
+        let handle = tokio::spawn(async move {
+            let mut perf_map = bpf.table("mprotect_events").unwrap().into_perf().unwrap();
+            
+            loop {
+                for data in perf_map.read().unwrap() {
+                    let event = MprotectEvent {
+                        pid: u32::from_ne_bytes(data[0..4].try_into().unwrap()),
+                        prot: u32::from_ne_bytes(data[4..8].try_into().unwrap()),
+                        start: u64::from_ne_bytes(data[8..16].try_into().unwrap()),
+                        len: u64::from_ne_bytes(data[16..24].try_into().unwrap()),
+                    };
+                    
+                    // Receiver gone: nobody enforces W^X any more
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        });
+        
+        (handle, rx)
+    }
+    
+    // Connects, listens and namespace switches, each with its netns. A
+    // switch that left the process where it was (another namespace type
+    // changed) is dropped by the caller, which knows where it was before.
+    pub fn start_network_watch(&self) -> (tokio::task::JoinHandle<()>, mpsc::UnboundedReceiver<NetworkEvent>) {
+        let bpf = self.bpf.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(async move {
+            let mut perf_map = bpf.table("network_events").unwrap().into_perf().unwrap();
+
+            loop {
+                for data in perf_map.read().unwrap() {
+                    let action = match u32::from_ne_bytes(data[4..8].try_into().unwrap()) {
+                        0 => NetworkAction::Connect,
+                        1 => NetworkAction::Listen,
+                        _ => NetworkAction::NamespaceSwitch,
+                    };
+                    let family = u16::from_ne_bytes(data[16..18].try_into().unwrap()) as i32;
+                    let address = |bytes: &[u8]| match family {
+                        libc::AF_INET => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..4]).unwrap()))),
+                        libc::AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()))),
+                        _ => None,
+                    };
+                    let event = NetworkEvent {
+                        pid: u32::from_ne_bytes(data[0..4].try_into().unwrap()),
+                        action,
+                        netns: u64::from_ne_bytes(data[8..16].try_into().unwrap()),
+                        previous_netns: None,
+                        source: address(&data[24..40]),
+                        source_port: u16::from_ne_bytes(data[18..20].try_into().unwrap()),
+                        destination: address(&data[40..56]),
+                        destination_port: u16::from_ne_bytes(data[20..22].try_into().unwrap()),
+                        timestamp: crate::systemd::now_secs(),
+                    };
+
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        });
+
+        (handle, rx)
+    }
+
+    // Traced syscall completions. A receiver that falls behind loses the
+    // newest events, counted in qks_syscall_events_dropped_total, rather
+    // than growing the queue without bound.
+    pub fn start_syscall_stream(&self) -> (tokio::task::JoinHandle<()>, mpsc::Receiver<SyscallEvent>) {
+        let bpf = self.bpf.clone();
+        let dropped = self.dropped.clone();
+        let (tx, rx) = mpsc::channel(SYSCALL_QUEUE);
+        
+        let handle = tokio::spawn(async move {
+            let mut perf_map = match bpf.table("syscall_trace").and_then(|table| table.into_perf()) {
+                Ok(perf_map) => perf_map,
+                Err(e) => {
+                    tracing::error!("Syscall trace buffer unavailable: {}", e);
+                    return;
+                }
+            };
+            
+            loop {
+                let batch = match perf_map.read() {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        tracing::warn!("Reading the syscall trace buffer failed: {}", e);
+                        metrics::global().incr("qks_syscall_trace_read_errors_total", &[]);
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                for data in batch {
+                    let event = SyscallEvent {
+                        pid: u32::from_ne_bytes(data[0..4].try_into().unwrap()),
+                        syscall: u32::from_ne_bytes(data[4..8].try_into().unwrap()),
+                        duration_ns: u64::from_ne_bytes(data[8..16].try_into().unwrap()),
+                        retval: i32::from_ne_bytes(data[16..20].try_into().unwrap()),
+                    };
+                    
+                    match tx.try_send(event) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                            metrics::global().incr("qks_syscall_events_dropped_total", &[]);
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => return,
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        });
+        
+        (handle, rx)
+    }
+    
     fn calculate_suspicious_score(stat: &SyscallStat) -> f32 {
         let mut score = 0.0;
         
@@ -0,0 +1,256 @@
+// src/elasticsearch.rs
+// Indexes bus events into Elasticsearch or OpenSearch through the bulk API.
+// The index name is a template over the event's own time (UTC) and kind:
+// %Y, %m, %d and {kind}, e.g. "qks-events-%Y.%m.%d" for daily indices.
+// Documents are ECS or the event as qks serializes it, with @timestamp
+// added either way. Before the first batch an index template covering the
+// indices is installed, so fields are mapped by type (PIDs as longs,
+// scores as floats, times as dates, strings as keywords) rather than
+// guessed from whichever event arrived first.
+//
+// Bulk is per item: the response says which documents went in. Those the
+// cluster was too busy for (429, 5xx) are retried through event_forward.rs,
+// which also shrinks batches while the cluster pushes back; documents it
+// refused (mapping conflicts) are dropped and counted.
+use crate::config::ElasticsearchSection;
+use crate::event_forward::{Delivery, Destination, ForwardError, SendError};
+use crate::events::SecurityEvent;
+use crate::siem_format::{self, EventFormat};
+use crate::syslog_sink;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const TEMPLATE_PRIORITY: u32 = 200;
+
+pub struct Elasticsearch {
+    section: ElasticsearchSection,
+    authorization: Option<String>,
+    password: Option<String>,
+    host: String,
+    client: reqwest::Client,
+}
+
+impl Elasticsearch {
+    pub fn new(section: &ElasticsearchSection) -> Result<Self, ForwardError> {
+        let read = |path: &std::path::PathBuf| {
+            std::fs::read_to_string(path).map(|secret| secret.trim().to_string()).map_err(|source| ForwardError::Secret { path: path.clone(), source })
+        };
+        let authorization = section.api_key_file.as_ref().map(read).transpose()?.map(|key| format!("ApiKey {}", key));
+        let password = section.password_file.as_ref().map(read).transpose()?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .danger_accept_invalid_certs(!section.verify_tls)
+            .build()
+            .map_err(|e| ForwardError::Client(e.to_string()))?;
+        Ok(Self { section: section.clone(), authorization, password, host: syslog_sink::hostname(), client })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/{}", self.section.url.trim_end_matches('/'), path);
+        let request = self.client.request(method, url);
+        match (&self.authorization, &self.section.username) {
+            (Some(authorization), _) => request.header(reqwest::header::AUTHORIZATION, authorization),
+            (None, Some(username)) => request.basic_auth(username, self.password.as_deref()),
+            (None, None) => request,
+        }
+    }
+
+    fn index(&self, event: &SecurityEvent) -> String {
+        // YYYY-MM-DD of the RFC 3339 time
+        let timestamp = syslog_sink::timestamp(siem_format::event_time(event));
+        self.section
+            .index
+            .replace("%Y", &timestamp[0..4])
+            .replace("%m", &timestamp[5..7])
+            .replace("%d", &timestamp[8..10])
+            .replace("{kind}", event.kind().as_str())
+    }
+
+    fn template(&self) -> Value {
+        let strings_as_keywords = json!([{
+            "strings": { "match_mapping_type": "string", "mapping": { "type": "keyword", "ignore_above": 1024 } }
+        }]);
+        let properties = match self.section.format {
+            EventFormat::Ecs => json!({
+                "@timestamp": { "type": "date" },
+                "message": { "type": "text" },
+                "ecs": { "properties": { "version": { "type": "keyword" } } },
+                "event": { "properties": {
+                    "kind": { "type": "keyword" },
+                    "category": { "type": "keyword" },
+                    "type": { "type": "keyword" },
+                    "action": { "type": "keyword" },
+                    "outcome": { "type": "keyword" },
+                    "module": { "type": "keyword" },
+                    "dataset": { "type": "keyword" },
+                    "severity": { "type": "long" },
+                    "risk_score": { "type": "float" },
+                } },
+                "host": { "properties": { "hostname": { "type": "keyword" } } },
+                "process": { "properties": {
+                    "pid": { "type": "long" },
+                    "executable": { "type": "keyword" },
+                    "parent": { "properties": {
+                        "pid": { "type": "long" },
+                        "name": { "type": "keyword" },
+                        "executable": { "type": "keyword" },
+                    } },
+                } },
+                "observer": { "properties": {
+                    "vendor": { "type": "keyword" },
+                    "product": { "type": "keyword" },
+                    "version": { "type": "keyword" },
+                    "type": { "type": "keyword" },
+                } },
+                "qks": { "properties": payload_properties() },
+            }),
+            // Validated to be json or ecs
+            _ => json!({
+                "@timestamp": { "type": "date" },
+                "host": { "type": "keyword" },
+                "kind": { "type": "keyword" },
+                "event": { "properties": payload_properties() },
+            }),
+        };
+        json!({
+            "index_patterns": [index_pattern(&self.section.index)],
+            "priority": TEMPLATE_PRIORITY,
+            "template": {
+                "mappings": { "dynamic_templates": strings_as_keywords, "properties": properties },
+            },
+            "_meta": { "managed_by": "qksd", "version": env!("CARGO_PKG_VERSION") },
+        })
+    }
+}
+
+// The fields of the event payloads dynamic mapping would get wrong: times
+// are epoch seconds, and a score that happens to be 1 must not make the
+// field a long
+fn payload_properties() -> Value {
+    json!({
+        "pid": { "type": "long" },
+        "score": { "type": "float" },
+        "threshold": { "type": "float" },
+        "model_version": { "type": "long" },
+        "timestamp": { "type": "date", "format": "epoch_second" },
+        "revoked_at": { "type": "date", "format": "epoch_second" },
+        "syscall": { "type": "long" },
+        "retval": { "type": "long" },
+        "duration_ns": { "type": "long" },
+        "regeneration_count": { "type": "long" },
+        "succeeded": { "type": "boolean" },
+        "critical": { "type": "boolean" },
+        "valid": { "type": "boolean" },
+        "recent_syscalls": { "type": "long" },
+        "pids": { "type": "long" },
+    })
+}
+
+// "qks-events-%Y.%m.%d" matches qks-events-*
+fn index_pattern(index: &str) -> String {
+    let fixed = index.find(['%', '{']).map_or(index, |at| &index[..at]);
+    format!("{}*", fixed)
+}
+
+// Whether a failed request or document is worth sending again
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+async fn failure(response: reqwest::Response) -> SendError {
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or_default();
+    let reason = format!("{}: {}", status, body["error"]["reason"].as_str().unwrap_or("no reason given"));
+    match status {
+        // Credentials are fixed outside qksd; keep the events until then
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            tracing::error!("Elasticsearch refuses the credentials: {}", reason);
+            SendError::Retry(reason)
+        }
+        status if retryable(status) => SendError::Retry(reason),
+        _ => SendError::Reject(reason),
+    }
+}
+
+impl Destination for Elasticsearch {
+    fn name(&self) -> &str {
+        "elasticsearch"
+    }
+
+    // The action line and the document, as they go in the bulk body
+    fn encode(&self, event: &SecurityEvent) -> Option<String> {
+        let mut document = match self.section.format {
+            EventFormat::Ecs => siem_format::ecs(event, &self.host),
+            _ => {
+                let mut document = serde_json::to_value(event).ok()?;
+                document["host"] = json!(self.host);
+                document
+            }
+        };
+        document["@timestamp"] = json!(syslog_sink::timestamp(siem_format::event_time(event)));
+        let action = json!({ "create": { "_index": self.index(event) } });
+        Some(format!("{}\n{}", action, document))
+    }
+
+    fn send<'a>(&'a self, batch: &'a [String]) -> Delivery<'a> {
+        Box::pin(async move {
+            let mut body = batch.join("\n");
+            body.push('\n');
+            let response = self
+                .request(reqwest::Method::POST, "_bulk")
+                .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| SendError::Retry(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(failure(response).await);
+            }
+
+            let result: Value = response.json().await.map_err(|e| SendError::Retry(format!("unreadable bulk response: {}", e)))?;
+            if result["errors"].as_bool() != Some(true) {
+                return Ok(());
+            }
+            let items = result["items"].as_array().map(Vec::as_slice).unwrap_or_default();
+            let (mut retry, mut rejected, mut reason) = (Vec::new(), 0, String::new());
+            for (record, item) in batch.iter().zip(items) {
+                let item = &item["create"];
+                let status = item["status"].as_u64().and_then(|s| StatusCode::from_u16(s as u16).ok()).unwrap_or(StatusCode::OK);
+                if status.is_success() {
+                    continue;
+                }
+                if reason.is_empty() {
+                    reason = format!("{}: {}", status, item["error"]["reason"].as_str().unwrap_or("no reason given"));
+                }
+                if retryable(status) {
+                    retry.push(record.clone());
+                } else {
+                    rejected += 1;
+                }
+            }
+            Err(SendError::Partial { retry, rejected, reason })
+        })
+    }
+
+    fn prepare(&self) -> Delivery<'_> {
+        Box::pin(async move {
+            if !self.section.install_template {
+                return Ok(());
+            }
+            let path = format!("_index_template/{}", self.section.template_name);
+            let response = self
+                .request(reqwest::Method::PUT, &path)
+                .json(&self.template())
+                .send()
+                .await
+                .map_err(|e| SendError::Retry(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(failure(response).await);
+            }
+            tracing::info!("Index template {} installed for {}", self.section.template_name, index_pattern(&self.section.index));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,166 @@
+// src/ensemble.rs
+// Several detectors, one verdict. Each component reports a score in [0, 1];
+// the ensemble combines whatever components produced a score this round
+// (weights renormalise over the ones present) so one weak or missing model
+// neither dominates nor silences alerting.
+use crate::behavior_profiles::ProfileKey;
+use crate::inference_backend::InferenceError;
+use crate::ml_detector::{MLAnomalyDetector, ProcessMetadata, ScoringMode};
+use crate::sequence_detector::SequenceDetector;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetectorKind {
+    Autoencoder,
+    Baseline,
+    Sequence,
+    Profile,
+    Rules,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+pub enum Combiner {
+    WeightedMean,
+    // Most alarmed component wins; sensitive, noisy
+    Max,
+    // Meta-score trained offline: sigmoid(bias + sum(weight * score))
+    Logistic { bias: f32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnsembleConfig {
+    pub combiner: Combiner,
+    pub weights: HashMap<DetectorKind, f32>,
+    // Components at or above this count as "voting anomalous"
+    pub vote_threshold: f32,
+    // The combined score is capped below vote_threshold unless at least this
+    // many components vote anomalous
+    pub quorum: usize,
+}
+
+impl Default for EnsembleConfig {
+    fn default() -> Self {
+        Self {
+            combiner: Combiner::WeightedMean,
+            weights: HashMap::from([
+                (DetectorKind::Autoencoder, 1.0),
+                (DetectorKind::Baseline, 0.5),
+                (DetectorKind::Sequence, 1.0),
+                (DetectorKind::Profile, 1.0),
+                (DetectorKind::Rules, 1.5),
+            ]),
+            vote_threshold: 0.8,
+            quorum: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ComponentScore {
+    pub detector: DetectorKind,
+    pub score: f32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EnsembleScore {
+    pub score: f32,
+    pub votes: usize,
+    pub components: Vec<ComponentScore>,
+}
+
+// One process window as seen by every detector
+pub struct ProcessObservation<'a> {
+    pub key: Option<&'a ProfileKey>,
+    pub syscalls: &'a [u32],
+    pub timing: &'a [u64],
+    pub metadata: &'a ProcessMetadata,
+    pub rule_hits: u32,
+}
+
+pub struct Ensemble {
+    config: EnsembleConfig,
+}
+
+impl Ensemble {
+    pub fn new(config: EnsembleConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &EnsembleConfig {
+        &self.config
+    }
+
+    pub fn combine(&self, scores: &[(DetectorKind, f32)]) -> EnsembleScore {
+        let components: Vec<ComponentScore> = scores.iter()
+            .map(|&(detector, score)| ComponentScore {
+                detector,
+                score: score.clamp(0.0, 1.0),
+                weight: self.config.weights.get(&detector).copied().unwrap_or(0.0),
+            })
+            .filter(|c| c.weight > 0.0)
+            .collect();
+        let votes = components.iter().filter(|c| c.score >= self.config.vote_threshold).count();
+
+        let combined = match self.config.combiner {
+            Combiner::WeightedMean => {
+                let total: f32 = components.iter().map(|c| c.weight).sum();
+                if total > 0.0 {
+                    components.iter().map(|c| c.weight * c.score).sum::<f32>() / total
+                } else {
+                    0.0
+                }
+            }
+            Combiner::Max => components.iter().map(|c| c.score).fold(0.0, f32::max),
+            Combiner::Logistic { bias } => {
+                let z = bias + components.iter().map(|c| c.weight * c.score).sum::<f32>();
+                1.0 / (1.0 + (-z).exp())
+            }
+        };
+
+        // Without quorum the ensemble may warn but never cross the vote line
+        let score = if votes < self.config.quorum {
+            combined.min(self.config.vote_threshold - f32::EPSILON)
+        } else {
+            combined
+        };
+
+        EnsembleScore { score, votes, components }
+    }
+
+    // Run every available detector for one process window
+    pub fn score_process(
+        &self,
+        detector: &mut MLAnomalyDetector,
+        sequence: Option<&mut SequenceDetector>,
+        observation: &ProcessObservation<'_>,
+    ) -> Result<EnsembleScore, InferenceError> {
+        let mut scores = Vec::new();
+
+        let features = detector.extract_features(observation.syscalls, observation.timing, observation.metadata);
+        let kind = match detector.scoring_mode() {
+            ScoringMode::Model => DetectorKind::Autoencoder,
+            _ => DetectorKind::Baseline,
+        };
+        scores.push((kind, detector.detect_anomaly(&features)?.0));
+
+        if let Some(sequence) = sequence {
+            scores.push((DetectorKind::Sequence, sequence.score_sequence(observation.syscalls)?.max));
+        }
+        if let (Some(profiles), Some(key)) = (detector.behavior_profiles(), observation.key) {
+            if let Some(profile) = profiles.observe(key, observation.syscalls, observation.timing, observation.metadata) {
+                scores.push((DetectorKind::Profile, profile.score));
+            }
+        }
+        // Rules only ever add evidence: no hit is not a benign vote. Each
+        // hit halves the remaining doubt.
+        if observation.rule_hits > 0 {
+            scores.push((DetectorKind::Rules, 1.0 - 0.5f32.powi(observation.rule_hits as i32)));
+        }
+
+        Ok(self.combine(&scores))
+    }
+}
@@ -0,0 +1,107 @@
+// src/entropy_audit.rs
+// Statistical checks on generated addresses. These catch implementation
+// bugs (masks that drop bits, offsets that never reach part of a window),
+// not weak RNGs: a CSPRNG passes trivially, a masking bug never does.
+
+// Per-bit frequency further than this from 0.5 marks the bit as biased
+const BIT_BIAS_TOLERANCE: f64 = 0.05;
+// Buckets for the chi-square test come from the top bits of the offset
+const MAX_BUCKET_BITS: u32 = 8;
+// z for alpha = 0.001 (one-sided)
+const CHI_SQUARE_Z: f64 = 3.09;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RegionEntropy {
+    pub region: String,
+    pub configured_bits: u32,
+    // Sum of per-bit Shannon entropy; equals configured_bits when every
+    // bit is a fair, independent coin
+    pub effective_bits: f64,
+    // Offset bits (counted in pages) that never or always came up set
+    pub stuck_bits: Vec<u32>,
+    pub biased_bits: Vec<u32>,
+    pub chi_square: f64,
+    pub degrees_of_freedom: u32,
+    pub uniform: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EntropyAudit {
+    pub sample_size: usize,
+    pub regions: Vec<RegionEntropy>,
+}
+
+impl EntropyAudit {
+    pub fn passed(&self) -> bool {
+        self.regions.iter().all(|r| r.uniform && r.stuck_bits.is_empty() && r.biased_bits.is_empty())
+    }
+}
+
+// `offsets` are page offsets from the start of the region's window
+pub fn analyze(region: &str, offsets: &[u64], configured_bits: u32) -> RegionEntropy {
+    let n = offsets.len().max(1) as f64;
+    let mut effective_bits = 0.0;
+    let mut stuck_bits = Vec::new();
+    let mut biased_bits = Vec::new();
+
+    for bit in 0..configured_bits {
+        let ones = offsets.iter().filter(|o| (*o >> bit) & 1 == 1).count() as f64;
+        let p = ones / n;
+
+        if ones == 0.0 || ones == n {
+            stuck_bits.push(bit);
+        } else if (p - 0.5).abs() > BIT_BIAS_TOLERANCE {
+            biased_bits.push(bit);
+        }
+        effective_bits += binary_entropy(p);
+    }
+
+    let bucket_bits = configured_bits.min(MAX_BUCKET_BITS);
+    let (chi_square, degrees_of_freedom) = chi_square(offsets, configured_bits, bucket_bits);
+
+    RegionEntropy {
+        region: region.to_string(),
+        configured_bits,
+        effective_bits,
+        stuck_bits,
+        biased_bits,
+        chi_square,
+        degrees_of_freedom,
+        uniform: degrees_of_freedom == 0 || chi_square <= critical_value(degrees_of_freedom),
+    }
+}
+
+fn binary_entropy(p: f64) -> f64 {
+    if p <= 0.0 || p >= 1.0 {
+        return 0.0;
+    }
+    -(p * p.log2() + (1.0 - p) * (1.0 - p).log2())
+}
+
+// Buckets on the top `bucket_bits` of each offset against a uniform spread
+fn chi_square(offsets: &[u64], configured_bits: u32, bucket_bits: u32) -> (f64, u32) {
+    if bucket_bits == 0 || offsets.is_empty() {
+        return (0.0, 0);
+    }
+
+    let buckets = 1usize << bucket_bits;
+    let shift = configured_bits - bucket_bits;
+    let mut counts = vec![0u64; buckets];
+    for offset in offsets {
+        counts[((offset >> shift) as usize) & (buckets - 1)] += 1;
+    }
+
+    let expected = offsets.len() as f64 / buckets as f64;
+    let statistic = counts.iter()
+        .map(|&c| (c as f64 - expected).powi(2) / expected)
+        .sum();
+
+    (statistic, buckets as u32 - 1)
+}
+
+// Wilson-Hilferty approximation of the chi-square upper quantile
+fn critical_value(df: u32) -> f64 {
+    let k = df as f64;
+    let term = 1.0 - 2.0 / (9.0 * k) + CHI_SQUARE_Z * (2.0 / (9.0 * k)).sqrt();
+    k * term.powi(3)
+}
@@ -0,0 +1,351 @@
+// src/event_forward.rs
+// The machinery shared by sinks that ship bus events to a remote collector
+// (Splunk HEC, ...). A sink only says how one event is encoded and how a
+// batch is delivered; here events are batched up to `batch_size` or every
+// `flush_secs`, whichever comes first. A batch that fails for a reason that
+// may pass (the collector is down, overloaded or throttling) is written to a
+// spool directory and retried with exponential backoff, oldest first, and
+// new batches queue behind it so order is kept. Each time the collector
+// pushes back the batch size halves, and it doubles back once batches go
+// through, so an overloaded cluster is given less at a time. Collectors that
+// take part of a batch (Elasticsearch bulk) have only the rest retried. The spool is capped at
+// `buffer_max_mb`; past that the oldest batches are dropped and counted. A
+// batch the collector rejects as malformed is dropped, since resending it
+// can't help. The spool survives restarts.
+use crate::config::BatchSection;
+use crate::events::{EventBus, EventKind, SecurityEvent};
+use crate::metrics;
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// Spooled batches resent per flush once the collector is back
+const DRAIN_PER_FLUSH: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    #[error("spool {}: {source}", .path.display())]
+    Spool { path: PathBuf, source: std::io::Error },
+    #[error("cannot read {}: {source}", .path.display())]
+    Secret { path: PathBuf, source: std::io::Error },
+    #[error("client: {0}")]
+    Client(String),
+    #[error("{0} support not compiled in (enable the {0} feature)")]
+    Unsupported(&'static str),
+}
+
+// Why a delivery failed, which decides what happens to the batch
+#[derive(Debug)]
+pub enum SendError {
+    // Spool and try again later
+    Retry(String),
+    // Drop; sending it again would fail the same way
+    Reject(String),
+    // Some records went through; `retry` are the ones to try again and
+    // `rejected` how many were dropped like a rejected batch
+    Partial { retry: Vec<String>, rejected: usize, reason: String },
+}
+
+pub type Delivery<'a> = Pin<Box<dyn Future<Output = Result<(), SendError>> + Send + 'a>>;
+
+pub trait Destination: Send + Sync + 'static {
+    // Names the sink in logs, metrics and the spool directory
+    fn name(&self) -> &str;
+    // One record of a batch; None skips the event
+    fn encode(&self, event: &SecurityEvent) -> Option<String>;
+    fn send<'a>(&'a self, batch: &'a [String]) -> Delivery<'a>;
+    // Run once before the first batch, e.g. to install an index template;
+    // retried like a batch until it succeeds or is rejected
+    fn prepare(&self) -> Delivery<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Batches waiting on disk, one file each, named by sequence so a directory
+// listing gives the send order
+struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    files: VecDeque<(PathBuf, u64)>,
+    bytes: u64,
+    next: u64,
+}
+
+impl Spool {
+    fn open(dir: PathBuf, max_bytes: u64) -> Result<Self, ForwardError> {
+        let spool_error = |source| ForwardError::Spool { path: dir.clone(), source };
+        fs::create_dir_all(&dir).map_err(spool_error)?;
+        let mut files: Vec<(u64, PathBuf, u64)> = fs::read_dir(&dir)
+            .map_err(spool_error)?
+            .flatten()
+            .filter_map(|entry| {
+                let seq = entry.file_name().to_str()?.strip_suffix(".batch")?.parse().ok()?;
+                Some((seq, entry.path(), entry.metadata().ok()?.len()))
+            })
+            .collect();
+        files.sort();
+        let next = files.last().map_or(0, |(seq, _, _)| seq + 1);
+        let bytes = files.iter().map(|(_, _, len)| len).sum();
+        let files = files.into_iter().map(|(_, path, len)| (path, len)).collect();
+        Ok(Self { dir, max_bytes, files, bytes, next })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn push(&mut self, name: &str, batch: &[String]) {
+        let data = match serde_json::to_vec(batch) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("{}: batch not spooled: {}", name, e);
+                return;
+            }
+        };
+        while !self.files.is_empty() && self.bytes + data.len() as u64 > self.max_bytes {
+            let dropped = self.read_front().map_or(0, |batch| batch.len());
+            self.pop();
+            metrics::global().add("qks_forward_dropped_total", &[("sink", name)], dropped as u64);
+            tracing::warn!("{}: spool full, {} oldest events dropped", name, dropped);
+        }
+        let path = self.dir.join(format!("{:020}.batch", self.next));
+        if let Err(e) = fs::write(&path, &data) {
+            metrics::global().add("qks_forward_dropped_total", &[("sink", name)], batch.len() as u64);
+            tracing::error!("{}: batch of {} events lost, spool not writable: {}", name, batch.len(), e);
+            return;
+        }
+        self.next += 1;
+        self.bytes += data.len() as u64;
+        self.files.push_back((path, data.len() as u64));
+    }
+
+    // An unreadable file is removed rather than blocking the spool
+    fn read_front(&mut self) -> Option<Vec<String>> {
+        let (path, _) = self.files.front()?;
+        let batch = fs::read(path).ok().and_then(|data| serde_json::from_slice(&data).ok());
+        if batch.is_none() {
+            tracing::warn!("Spooled batch {} unreadable, discarded", path.display());
+        }
+        batch
+    }
+
+    // What is left of the oldest batch after part of it went through
+    fn replace_front(&mut self, name: &str, batch: &[String]) {
+        let Some((path, len)) = self.files.front_mut() else {
+            return;
+        };
+        let written = serde_json::to_vec(batch).map_err(std::io::Error::from).and_then(|data| fs::write(&*path, &data).map(|()| data.len() as u64));
+        match written {
+            Ok(new_len) => {
+                self.bytes = self.bytes - *len + new_len;
+                *len = new_len;
+            }
+            Err(e) => tracing::warn!("{}: spooled batch not trimmed, delivered events will be resent: {}", name, e),
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((path, len)) = self.files.pop_front() {
+            let _ = fs::remove_file(path);
+            self.bytes -= len;
+        }
+    }
+}
+
+struct Backoff {
+    max: Duration,
+    delay: Duration,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    fn waiting(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now < until)
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.delay = if self.until.is_some() { (self.delay * 2).min(self.max) } else { INITIAL_BACKOFF.min(self.max) };
+        self.until = Some(now + self.delay);
+    }
+}
+
+struct Forwarder<D> {
+    destination: Arc<D>,
+    spool: Spool,
+    backoff: Backoff,
+    // Whether prepare() has been done
+    prepared: bool,
+    // Events per batch right now: halved each time the collector pushes
+    // back, doubled back up to batch_size as it keeps up
+    limit: usize,
+    batch_size: usize,
+}
+
+impl<D: Destination> Forwarder<D> {
+    async fn deliver(&mut self, batch: Vec<String>) {
+        let name = self.destination.name().to_string();
+        if !self.spool.is_empty() || self.backoff.waiting(Instant::now()) {
+            self.spool.push(&name, &batch);
+            return;
+        }
+        if let Some(retry) = self.attempt(&name, &batch).await {
+            tracing::warn!("{}: spooling {} events until the collector takes them", name, retry.len());
+            self.spool.push(&name, &retry);
+        }
+    }
+
+    async fn drain(&mut self) {
+        let name = self.destination.name().to_string();
+        for _ in 0..DRAIN_PER_FLUSH {
+            if self.spool.is_empty() || self.backoff.waiting(Instant::now()) {
+                return;
+            }
+            let Some(batch) = self.spool.read_front() else {
+                self.spool.pop();
+                continue;
+            };
+            match self.attempt(&name, &batch).await {
+                None => self.spool.pop(),
+                Some(retry) => {
+                    if retry.len() < batch.len() {
+                        self.spool.replace_front(&name, &retry);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // Send one batch; what comes back is what still has to be retried
+    async fn attempt(&mut self, name: &str, batch: &[String]) -> Option<Vec<String>> {
+        if !self.prepare(name).await {
+            return Some(batch.to_vec());
+        }
+        match self.destination.send(batch).await {
+            Ok(()) => {
+                self.sent(name, batch.len());
+                None
+            }
+            Err(SendError::Reject(reason)) => {
+                self.rejected(name, batch.len(), &reason);
+                None
+            }
+            Err(SendError::Retry(reason)) => {
+                tracing::debug!("{}: batch of {} events not delivered: {}", name, batch.len(), reason);
+                self.pushed_back();
+                Some(batch.to_vec())
+            }
+            Err(SendError::Partial { retry, rejected, reason }) => {
+                let sent = batch.len() - retry.len() - rejected;
+                metrics::global().add("qks_forward_events_total", &[("sink", name), ("result", "sent")], sent as u64);
+                if rejected > 0 {
+                    self.rejected(name, rejected, &reason);
+                }
+                if retry.is_empty() {
+                    return None;
+                }
+                tracing::debug!("{}: {} of {} events not delivered: {}", name, retry.len(), batch.len(), reason);
+                self.pushed_back();
+                Some(retry)
+            }
+        }
+    }
+
+    async fn prepare(&mut self, name: &str) -> bool {
+        if self.prepared {
+            return true;
+        }
+        match self.destination.prepare().await {
+            Ok(()) => self.prepared = true,
+            Err(SendError::Reject(reason)) => {
+                tracing::error!("{}: setup refused, forwarding without it: {}", name, reason);
+                self.prepared = true;
+            }
+            Err(SendError::Retry(reason) | SendError::Partial { reason, .. }) => {
+                tracing::debug!("{}: setup not done yet: {}", name, reason);
+                self.backoff.failed(Instant::now());
+            }
+        }
+        self.prepared
+    }
+
+    fn sent(&mut self, name: &str, events: usize) {
+        if self.backoff.until.take().is_some() {
+            tracing::info!("{}: collector reachable again", name);
+        }
+        self.limit = (self.limit * 2).min(self.batch_size);
+        metrics::global().add("qks_forward_events_total", &[("sink", name), ("result", "sent")], events as u64);
+    }
+
+    fn pushed_back(&mut self) {
+        self.backoff.failed(Instant::now());
+        self.limit = (self.limit / 2).max(1);
+    }
+
+    fn rejected(&mut self, name: &str, events: usize, reason: &str) {
+        metrics::global().add("qks_forward_events_total", &[("sink", name), ("result", "rejected")], events as u64);
+        tracing::error!("{}: {} events rejected and dropped: {}", name, events, reason);
+    }
+}
+
+// Feed `kinds` from the bus to `destination` until the bus goes away; what
+// is still pending then is spooled for the next start
+pub fn start<D: Destination>(
+    destination: D,
+    batch: &BatchSection,
+    kinds: &[EventKind],
+    spool_dir: &Path,
+    bus: &EventBus,
+) -> Result<tokio::task::JoinHandle<()>, ForwardError> {
+    let spool = Spool::open(spool_dir.to_path_buf(), batch.buffer_max_mb * 1024 * 1024)?;
+    if !spool.is_empty() {
+        tracing::info!("{}: {} spooled batches left from before, resending", destination.name(), spool.files.len());
+    }
+    let backoff = Backoff { max: Duration::from_secs(batch.max_backoff_secs), delay: INITIAL_BACKOFF, until: None };
+    let mut forwarder = Forwarder {
+        destination: Arc::new(destination),
+        spool,
+        backoff,
+        prepared: false,
+        limit: batch.batch_size,
+        batch_size: batch.batch_size,
+    };
+    let flush = Duration::from_secs(batch.flush_secs);
+    let mut events = bus.subscribe_to(kinds).with_name("forward");
+
+    Ok(tokio::spawn(async move {
+        let mut pending = Vec::with_capacity(forwarder.batch_size);
+        let mut ticks = tokio::time::interval(flush);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    if let Some(record) = forwarder.destination.encode(&event) {
+                        pending.push(record);
+                    }
+                    if pending.len() >= forwarder.limit {
+                        forwarder.deliver(std::mem::take(&mut pending)).await;
+                    }
+                }
+                _ = ticks.tick() => {
+                    forwarder.drain().await;
+                    if !pending.is_empty() {
+                        forwarder.deliver(std::mem::take(&mut pending)).await;
+                    }
+                }
+            }
+        }
+        if !pending.is_empty() {
+            let name = forwarder.destination.name().to_string();
+            forwarder.spool.push(&name, &pending);
+        }
+    }))
+}
@@ -0,0 +1,331 @@
+// src/events.rs
+// The daemon's event bus. Every subsystem publishes what happened as a
+// SecurityEvent and anything that needs to react (the remote APIs, the
+// policy engine, responders) subscribes, instead of scraping logs or being
+// called directly. Each kind has its own broadcast channel so a flood of
+// syscalls can't make an anomaly subscriber lag. Publishing never blocks;
+// a subscriber that falls behind loses the oldest events and is told how
+// many.
+use crate::anomaly_events::AnomalyEvent;
+use crate::container_events::ContainerInfo;
+use crate::crypto_identifiers::Capability;
+use crate::drift_monitor::ModelDrift;
+use crate::ebpf_monitor::{NetworkEvent, SyscallEvent};
+use crate::memory_randomizer::LayoutChangeEvent;
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+// Syscalls arrive orders of magnitude faster than anything else
+const SYSCALL_CAPACITY: usize = 8192;
+const DEFAULT_CAPACITY: usize = 1024;
+// Events EventHistory keeps for polling clients
+const HISTORY_CAPACITY: usize = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Syscall,
+    Anomaly,
+    Token,
+    Layout,
+    Snapshot,
+    Response,
+    Integrity,
+    Container,
+    Network,
+    Intel,
+    Drift,
+}
+
+impl EventKind {
+    pub const ALL: &'static [EventKind] = &[
+        EventKind::Syscall,
+        EventKind::Anomaly,
+        EventKind::Token,
+        EventKind::Layout,
+        EventKind::Snapshot,
+        EventKind::Response,
+        EventKind::Integrity,
+        EventKind::Container,
+        EventKind::Network,
+        EventKind::Intel,
+        EventKind::Drift,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Syscall => "syscall",
+            EventKind::Anomaly => "anomaly",
+            EventKind::Token => "token",
+            EventKind::Layout => "layout",
+            EventKind::Snapshot => "snapshot",
+            EventKind::Response => "response",
+            EventKind::Integrity => "integrity",
+            EventKind::Container => "container",
+            EventKind::Network => "network",
+            EventKind::Intel => "intel",
+            EventKind::Drift => "drift",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum TokenEvent {
+    Issued { pid: u32, capabilities: Vec<Capability>, timestamp: u64 },
+    Revoked { pid: u32, revoked_at: u64 },
+    // The holder hit its MemoryAllocation budget `count` more times;
+    // `breach` is limit_hit, oom_kill or over_budget (capability_metering.rs)
+    #[serde(rename = "budget_exceeded")]
+    BudgetExceeded { pid: u32, limit: u64, breach: String, count: u64, timestamp: u64 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum SnapshotEvent {
+    Taken { snapshot_id: String },
+    Restored { snapshot_id: String, pids: Vec<u32> },
+    Verified { snapshot_id: String, valid: bool },
+}
+
+// An action taken against a process, by an operator or automatically
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseEvent {
+    pub action: String,
+    pub pid: Option<u32>,
+    // executed, dry_run, refused, cooling_down or failed
+    pub outcome: String,
+    pub succeeded: bool,
+    pub detail: Option<String>,
+    pub timestamp: u64,
+}
+
+// Something the host's integrity checks found: a file whose measurement
+// doesn't match its baseline, a protection that is off
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityEvent {
+    // The check that found it: ima, ...
+    pub source: String,
+    // What was found, e.g. hash_mismatch, unknown_file, violation
+    pub finding: String,
+    pub critical: bool,
+    pub path: Option<String>,
+    pub pid: Option<u32>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub detail: Option<String>,
+    pub timestamp: u64,
+}
+
+// A container's init process starting or exiting, as containerd reports it
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum ContainerEvent {
+    Started { pid: u32, container: ContainerInfo, timestamp: u64 },
+    Stopped { pid: u32, container: ContainerInfo, exit_status: u32, timestamp: u64 },
+}
+
+// Something seen on the host that a threat intelligence feed lists
+// (intel.rs)
+#[derive(Debug, Clone, Serialize)]
+pub struct IntelEvent {
+    // What was seen: connect, exec or the integrity source's name
+    pub observed: String,
+    pub pid: Option<u32>,
+    pub exe: Option<String>,
+    pub path: Option<String>,
+    // ip, domain or hash
+    pub indicator_type: String,
+    // As the feed has it: an address or network, a domain, algorithm:hex
+    pub indicator: String,
+    // What matched: the address connected to, or the file's digest
+    pub matched: String,
+    pub feed: String,
+    // The STIX indicator's name and ID, or a list's comment column
+    pub description: Option<String>,
+    // 0-100, where the feed gives one
+    pub confidence: Option<u8>,
+    // 0-10, from the feed's configuration
+    pub severity: u8,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "event", rename_all = "lowercase")]
+pub enum SecurityEvent {
+    Syscall(SyscallEvent),
+    Anomaly(AnomalyEvent),
+    Token(TokenEvent),
+    Layout(LayoutChangeEvent),
+    Snapshot(SnapshotEvent),
+    Response(ResponseEvent),
+    Integrity(IntegrityEvent),
+    Container(ContainerEvent),
+    Network(NetworkEvent),
+    Intel(IntelEvent),
+    // The installed model's inputs moved away from its training data
+    Drift(ModelDrift),
+}
+
+impl SecurityEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            SecurityEvent::Syscall(_) => EventKind::Syscall,
+            SecurityEvent::Anomaly(_) => EventKind::Anomaly,
+            SecurityEvent::Token(_) => EventKind::Token,
+            SecurityEvent::Layout(_) => EventKind::Layout,
+            SecurityEvent::Snapshot(_) => EventKind::Snapshot,
+            SecurityEvent::Response(_) => EventKind::Response,
+            SecurityEvent::Integrity(_) => EventKind::Integrity,
+            SecurityEvent::Container(_) => EventKind::Container,
+            SecurityEvent::Network(_) => EventKind::Network,
+            SecurityEvent::Intel(_) => EventKind::Intel,
+            SecurityEvent::Drift(_) => EventKind::Drift,
+        }
+    }
+
+    // The process the event is about, if it is about one
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            SecurityEvent::Syscall(e) => Some(e.pid),
+            SecurityEvent::Anomaly(e) => Some(e.pid),
+            SecurityEvent::Token(TokenEvent::Issued { pid, .. } | TokenEvent::Revoked { pid, .. } | TokenEvent::BudgetExceeded { pid, .. }) => Some(*pid),
+            SecurityEvent::Layout(e) => Some(e.pid),
+            SecurityEvent::Snapshot(_) => None,
+            SecurityEvent::Response(e) => e.pid,
+            SecurityEvent::Integrity(e) => e.pid,
+            SecurityEvent::Container(ContainerEvent::Started { pid, .. } | ContainerEvent::Stopped { pid, .. }) => Some(*pid),
+            SecurityEvent::Network(e) => Some(e.pid),
+            SecurityEvent::Intel(e) => e.pid,
+            SecurityEvent::Drift(_) => None,
+        }
+    }
+}
+
+pub struct EventBus {
+    channels: HashMap<EventKind, broadcast::Sender<SecurityEvent>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let channels = EventKind::ALL
+            .iter()
+            .map(|kind| {
+                let capacity = if *kind == EventKind::Syscall { SYSCALL_CAPACITY } else { DEFAULT_CAPACITY };
+                (*kind, broadcast::channel(capacity).0)
+            })
+            .collect();
+        Self { channels }
+    }
+
+    fn channel(&self, kind: EventKind) -> &broadcast::Sender<SecurityEvent> {
+        &self.channels[&kind]
+    }
+
+    // Lets hot paths skip building an event nobody will see
+    pub fn has_subscribers(&self, kind: EventKind) -> bool {
+        self.channel(kind).receiver_count() > 0
+    }
+
+    pub fn publish(&self, event: SecurityEvent) {
+        let kind = event.kind();
+        metrics::global().incr("qks_events_published_total", &[("kind", kind.as_str())]);
+        // No subscribers is not an error
+        let _ = self.channel(kind).send(event);
+    }
+
+    pub fn subscribe(&self, kind: EventKind) -> broadcast::Receiver<SecurityEvent> {
+        self.channel(kind).subscribe()
+    }
+
+    pub fn subscribe_to(&self, kinds: &[EventKind]) -> Subscription {
+        let mut streams = StreamMap::new();
+        for kind in kinds {
+            streams.insert(*kind, BroadcastStream::new(self.subscribe(*kind)));
+        }
+        Subscription { name: "event subscriber", streams }
+    }
+}
+
+// Several kinds merged into one stream, in arrival order per kind
+pub struct Subscription {
+    name: &'static str,
+    streams: StreamMap<EventKind, BroadcastStream<SecurityEvent>>,
+}
+
+impl Subscription {
+    // Names the subscriber in lag warnings
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    // None once the bus is gone. Lagging is logged and counted, not returned.
+    pub async fn recv(&mut self) -> Option<SecurityEvent> {
+        loop {
+            match self.streams.next().await? {
+                (_, Ok(event)) => return Some(event),
+                (kind, Err(BroadcastStreamRecvError::Lagged(missed))) => {
+                    tracing::warn!("{} fell behind; {} {} events dropped", self.name, missed, kind.as_str());
+                    metrics::global().add("qks_events_lagged_total", &[("kind", kind.as_str())], missed);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    // Increases by one per recorded event since the daemon started
+    pub seq: u64,
+    pub event: SecurityEvent,
+}
+
+// The latest events of every kind but syscall and network, which arrive
+// too often to be worth keeping, for clients that poll the control socket
+// (qkstop) rather than subscribe
+#[derive(Default)]
+pub struct EventHistory {
+    events: Mutex<(u64, VecDeque<RecordedEvent>)>,
+}
+
+impl EventHistory {
+    pub fn start(self: Arc<Self>, bus: &EventBus) -> tokio::task::JoinHandle<()> {
+        let kinds: Vec<EventKind> = EventKind::ALL.iter().copied().filter(|kind| !matches!(kind, EventKind::Syscall | EventKind::Network)).collect();
+        let mut events = bus.subscribe_to(&kinds).with_name("Event history");
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let mut guard = self.events.lock().unwrap();
+                let (next_seq, events) = &mut *guard;
+                *next_seq += 1;
+                if events.len() == HISTORY_CAPACITY {
+                    events.pop_front();
+                }
+                events.push_back(RecordedEvent { seq: *next_seq, event });
+            }
+        })
+    }
+
+    // Up to `limit` events after `after`, oldest first, and the sequence
+    // number to ask after next time
+    pub fn after(&self, after: u64, limit: usize) -> (Vec<RecordedEvent>, u64) {
+        let guard = self.events.lock().unwrap();
+        let (next_seq, events) = &*guard;
+        let items: Vec<RecordedEvent> = events.iter().filter(|recorded| recorded.seq > after).take(limit).cloned().collect();
+        // Past the end means the daemon restarted since the client last asked
+        let next_after = items.last().map_or(after.min(*next_seq), |recorded| recorded.seq);
+        (items, next_after)
+    }
+}
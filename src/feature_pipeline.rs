@@ -0,0 +1,275 @@
+// src/feature_pipeline.rs
+// eBPF syscall stream -> per-PID windows -> features -> detector. Events are
+// buffered per process; every cadence tick each process with enough calls
+// is turned into a feature row, the tick is scored as one batch, and the
+// results go out on the returned channel.
+use crate::behavior_profiles::ProfileKey;
+use crate::ebpf_monitor::SyscallEvent;
+use crate::kubernetes::{self, MonitoringLevel};
+use crate::metrics;
+use crate::ml_detector::{FeatureVector, MLAnomalyDetector, ProcessMetadata, ScoringMode};
+use crate::privsep;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// Newest calls kept on each scored row for responders
+const SYSCALL_EXCERPT: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    pub cadence_secs: u64,
+    // Processes with fewer calls in a tick wait for the next one
+    pub min_events: usize,
+    // Per-PID cap; a syscall storm keeps only the newest calls
+    pub max_sequence: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self { cadence_secs: 5, min_events: 32, max_sequence: 4096 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoredProcess {
+    pub pid: u32,
+    pub exe: Option<String>,
+    pub score: f32,
+    pub mode: ScoringMode,
+    pub model_version: u64,
+    pub syscalls: usize,
+    pub timestamp: u64,
+    // Raw (unscaled) row, kept for training export
+    pub features: FeatureVector,
+    pub recent_syscalls: Vec<u32>,
+}
+
+#[derive(Default)]
+struct ProcessWindow {
+    syscalls: Vec<u32>,
+    timing: Vec<u64>,
+}
+
+pub struct FeaturePipeline {
+    detector: Arc<Mutex<MLAnomalyDetector>>,
+    config: PipelineConfig,
+    results: mpsc::UnboundedSender<ScoredProcess>,
+}
+
+impl FeaturePipeline {
+    pub fn new(
+        detector: Arc<Mutex<MLAnomalyDetector>>,
+        config: PipelineConfig,
+    ) -> (Self, mpsc::UnboundedReceiver<ScoredProcess>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { detector, config, results: tx }, rx)
+    }
+
+    pub fn start(&self, mut events: mpsc::Receiver<SyscallEvent>) -> tokio::task::JoinHandle<()> {
+        let detector = self.detector.clone();
+        let config = self.config;
+        let results = self.results.clone();
+
+        tokio::spawn(async move {
+            let mut windows: HashMap<u32, ProcessWindow> = HashMap::new();
+            // utime+stime at the previous tick, for the CPU share
+            let mut cpu_ticks: HashMap<u32, u64> = HashMap::new();
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(config.cadence_secs));
+
+            loop {
+                tokio::select! {
+                    event = events.recv() => {
+                        let Some(event) = event else {
+                            tracing::info!("Syscall stream closed; feature pipeline stopping");
+                            return;
+                        };
+                        let window = windows.entry(event.pid).or_default();
+                        window.syscalls.push(event.syscall);
+                        window.timing.push(event.duration_ns);
+                        if window.syscalls.len() > config.max_sequence {
+                            let excess = window.syscalls.len() - config.max_sequence;
+                            window.syscalls.drain(..excess);
+                            window.timing.drain(..excess);
+                        }
+                    }
+                    _ = interval.tick() => {
+                        // Pods with monitoring off are not scored at all
+                        windows.retain(|pid, _| kubernetes::global().monitoring_for(*pid) != MonitoringLevel::Off);
+                        let pids: Vec<u32> = windows.iter()
+                            .filter(|(_, window)| window.syscalls.len() >= config.min_events)
+                            .map(|(pid, _)| *pid)
+                            .collect();
+                        let ready: Vec<(u32, ProcessWindow)> = pids.into_iter()
+                            .filter_map(|pid| windows.remove(&pid).map(|window| (pid, window)))
+                            .collect();
+                        windows.retain(|pid, _| std::path::Path::new(&format!("/proc/{}", pid)).exists());
+                        cpu_ticks.retain(|pid, _| std::path::Path::new(&format!("/proc/{}", pid)).exists());
+                        if ready.is_empty() {
+                            continue;
+                        }
+
+                        let detector = detector.clone();
+                        let previous = std::mem::take(&mut cpu_ticks);
+                        let cadence = config.cadence_secs;
+                        let scored = tokio::task::spawn_blocking(move || {
+                            Self::score_tick(&detector, ready, previous, cadence)
+                        }).await;
+
+                        match scored {
+                            Ok((scored, ticks)) => {
+                                cpu_ticks = ticks;
+                                for result in scored {
+                                    if results.send(result).is_err() {
+                                        return;
+                                    }
+                                }
+                            }
+                            Err(e) => tracing::warn!("Feature pipeline tick failed: {}", e),
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn score_tick(
+        detector: &Mutex<MLAnomalyDetector>,
+        ready: Vec<(u32, ProcessWindow)>,
+        previous_ticks: HashMap<u32, u64>,
+        cadence_secs: u64,
+    ) -> (Vec<ScoredProcess>, HashMap<u32, u64>) {
+        let started = Instant::now();
+        let mut detector = detector.lock().unwrap();
+        let profiles = detector.behavior_profiles().cloned();
+        let mut ticks = previous_ticks;
+        let mut rows = Vec::new();
+        let mut scored = Vec::new();
+
+        for (pid, window) in ready {
+            // Exited since its events arrived
+            let Some((metadata, cpu)) = Self::process_metadata(pid, &window, ticks.get(&pid).copied(), cadence_secs) else {
+                ticks.remove(&pid);
+                continue;
+            };
+            ticks.insert(pid, cpu);
+            let features = detector.extract_features(&window.syscalls, &window.timing, &metadata);
+            // A warm profile of the process's own executable judges it, as in
+            // detect_process_anomaly; the rest go to the global batch
+            let profile_score = profiles.as_ref()
+                .and_then(|profiles| {
+                    let key = ProfileKey::for_pid(pid)?;
+                    profiles.observe(&key, &window.syscalls, &window.timing, &metadata)
+                })
+                .map(|profile| profile.score);
+            if profile_score.is_none() {
+                rows.push(features.clone());
+            }
+            let excerpt = window.syscalls[window.syscalls.len().saturating_sub(SYSCALL_EXCERPT)..].to_vec();
+            scored.push((pid, window.syscalls.len(), excerpt, features, profile_score));
+        }
+
+        let mode = detector.scoring_mode();
+        let model_version = detector.model_version();
+        let results = match detector.detect_anomalies_batch(&rows) {
+            Ok(results) => results,
+            Err(e) => {
+                tracing::warn!("Scoring {} processes failed: {}", rows.len(), e);
+                return (Vec::new(), ticks);
+            }
+        };
+        metrics::global().add("qks_pipeline_processes_scored_total", &[], scored.len() as u64);
+        metrics::global().observe("qks_pipeline_tick_seconds", &[], started.elapsed());
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut results = results.into_iter();
+        let out = scored.into_iter()
+            .filter_map(|(pid, syscalls, recent_syscalls, features, profile_score)| {
+                let (score, mode) = match profile_score {
+                    Some(score) => (score, ScoringMode::Profile),
+                    None => (results.next()?.score, mode),
+                };
+                Some(ScoredProcess {
+                    pid,
+                    exe: privsep::read_exe(pid).ok().map(|p| p.display().to_string()),
+                    score,
+                    mode,
+                    model_version,
+                    syscalls,
+                    timestamp,
+                    features,
+                    recent_syscalls,
+                })
+            })
+            .collect();
+        (out, ticks)
+    }
+
+    // Metadata from procfs plus the process's current utime+stime
+    fn process_metadata(
+        pid: u32,
+        window: &ProcessWindow,
+        previous_cpu: Option<u64>,
+        cadence_secs: u64,
+    ) -> Option<(ProcessMetadata, u64)> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let field = |name: &str| {
+            status.lines()
+                .find(|l| l.starts_with(name))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+
+        // Effective UID 0 is root; seccomp filter mode counts as restricted
+        let euid = status.lines()
+            .find(|l| l.starts_with("Uid:"))
+            .and_then(|l| l.split_whitespace().nth(2))
+            .and_then(|v| v.parse::<u32>().ok())?;
+        let privilege_level = if euid == 0 {
+            0
+        } else if field("Seccomp:") == Some(2) {
+            2
+        } else {
+            1
+        };
+
+        let children_count = std::fs::read_dir(format!("/proc/{}/task", pid)).ok()?
+            .filter_map(|task| task.ok())
+            .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+            .map(|children| children.split_whitespace().count() as u32)
+            .sum();
+
+        // Fields after the parenthesised comm: utime and stime are 14 and 15
+        let after_comm = &stat[stat.rfind(')')? + 2..];
+        let fields: Vec<&str> = after_comm.split_whitespace().collect();
+        let cpu: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+        // CPU share over the last tick and resident share of RAM, averaged
+        let clk_tck = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f32;
+        let cpu_share = previous_cpu
+            .map(|prev| (cpu.saturating_sub(prev) as f32 / clk_tck / cadence_secs.max(1) as f32).min(1.0))
+            .unwrap_or(0.0);
+        let mem_total_kb = std::fs::read_to_string("/proc/meminfo").ok()
+            .and_then(|m| m.lines().next().and_then(|l| l.split_whitespace().nth(1)).and_then(|v| v.parse::<u64>().ok()))
+            .unwrap_or(0);
+        let mem_share = match (field("VmRSS:"), mem_total_kb) {
+            (Some(rss), total) if total > 0 => rss as f32 / total as f32,
+            _ => 0.0,
+        };
+
+        let metadata = ProcessMetadata {
+            privilege_level,
+            children_count,
+            resource_usage: (cpu_share + mem_share) / 2.0,
+            signature: privsep::read_exe(pid)
+                .map(|p| p.as_os_str().as_bytes().to_vec())
+                .unwrap_or_default(),
+            syscall_pattern: window.syscalls.clone(),
+        };
+        Some((metadata, cpu))
+    }
+}
@@ -0,0 +1,280 @@
+// src/federated.rs
+// Fleet-wide baselines without fleet-wide telemetry. Each agent signs and
+// sends only its feature statistics (and, when it fine-tunes locally, a
+// flattened model update). In controller mode the aggregator FedAvgs one
+// round of contributions, weighting each host by its sample count, signs
+// the result and hands it back out. Raw syscalls never leave the host.
+use crate::baseline_model::BaselineStats;
+use crate::crypto_identifiers::CryptoIdentifier;
+use crate::ml_detector::MLAnomalyDetector;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// One host claiming a huge sample count must not outvote the fleet
+const MAX_HOST_WEIGHT: u64 = 100_000;
+// Contributions older than this are refused
+const MAX_CONTRIBUTION_AGE_SECS: u64 = 3600;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FederationError {
+    #[error("unknown host {0}")]
+    UnknownHost(String),
+    #[error("signature from {0} is invalid")]
+    BadSignature(String),
+    #[error("contribution from {host} is for round {got}, aggregating round {expected}")]
+    WrongRound { host: String, got: u64, expected: u64 },
+    #[error("contribution from {0} is outside the freshness window")]
+    Expired(String),
+    #[error("{host} reports {got} features, the fleet has {expected}")]
+    Schema { host: String, got: usize, expected: usize },
+    #[error("only {have} of {need} hosts contributed this round")]
+    NotEnoughHosts { have: usize, need: usize },
+}
+
+// Flattened parameters (or a delta) from local fine-tuning; averaged only
+// among hosts on the same model version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelUpdate {
+    pub model_version: u64,
+    pub weights: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostContribution {
+    pub host_id: String,
+    pub round: u64,
+    pub sent_at: u64,
+    pub baseline: BaselineStats,
+    pub model: Option<ModelUpdate>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FleetAggregate {
+    pub round: u64,
+    pub hosts: usize,
+    pub baseline: BaselineStats,
+    pub model: Option<ModelUpdate>,
+    pub signature: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn signed_bytes(host_id: &str, round: u64, sent_at: u64, baseline: &BaselineStats, model: &Option<ModelUpdate>) -> Vec<u8> {
+    // serde_json output is stable for these plain structs
+    serde_json::to_vec(&(host_id, round, sent_at, baseline, model)).unwrap_or_default()
+}
+
+fn aggregate_bytes(round: u64, hosts: usize, baseline: &BaselineStats, model: &Option<ModelUpdate>) -> Vec<u8> {
+    serde_json::to_vec(&("fleet", round, hosts, baseline, model)).unwrap_or_default()
+}
+
+fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+// Agent side: this host's share for a round
+pub fn contribution(
+    host_id: &str,
+    identity: &CryptoIdentifier,
+    round: u64,
+    detector: &MLAnomalyDetector,
+    model: Option<ModelUpdate>,
+) -> HostContribution {
+    let baseline = detector.baseline().export();
+    let sent_at = now();
+    let signature = identity.sign(&signed_bytes(host_id, round, sent_at, &baseline, &model));
+    HostContribution { host_id: host_id.to_string(), round, sent_at, baseline, model, signature }
+}
+
+// Agent side: verify the controller's aggregate and fold it into the local
+// baseline. Model updates are left to the caller, which must re-sign and
+// reload through the usual model path.
+pub fn apply_aggregate(
+    detector: &mut MLAnomalyDetector,
+    aggregate: &FleetAggregate,
+    controller_key: &[u8],
+) -> Result<(), FederationError> {
+    let data = aggregate_bytes(aggregate.round, aggregate.hosts, &aggregate.baseline, &aggregate.model);
+    if !verify(controller_key, &data, &aggregate.signature) {
+        return Err(FederationError::BadSignature("controller".into()));
+    }
+    detector.baseline_mut().adopt(&aggregate.baseline);
+    tracing::info!("Adopted fleet baseline from round {} ({} hosts)", aggregate.round, aggregate.hosts);
+    Ok(())
+}
+
+// Controller side
+pub struct FederatedAggregator {
+    identity: Arc<CryptoIdentifier>,
+    // host_id -> Ed25519 public key
+    hosts: Arc<DashMap<String, Vec<u8>>>,
+    round: Arc<AtomicU64>,
+    contributions: Arc<DashMap<String, HostContribution>>,
+    min_hosts: usize,
+}
+
+impl FederatedAggregator {
+    pub fn new(identity: Arc<CryptoIdentifier>, min_hosts: usize) -> Self {
+        Self {
+            identity,
+            hosts: Arc::new(DashMap::new()),
+            round: Arc::new(AtomicU64::new(0)),
+            contributions: Arc::new(DashMap::new()),
+            min_hosts: min_hosts.max(1),
+        }
+    }
+
+    pub fn add_host(&self, host_id: &str, public_key: Vec<u8>) {
+        self.hosts.insert(host_id.to_string(), public_key);
+    }
+
+    pub fn remove_host(&self, host_id: &str) {
+        self.hosts.remove(host_id);
+        self.contributions.remove(host_id);
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round.load(Ordering::SeqCst)
+    }
+
+    // A host resubmitting within a round replaces its earlier contribution
+    pub fn submit(&self, contribution: HostContribution) -> Result<(), FederationError> {
+        let host = contribution.host_id.clone();
+        let public_key = self.hosts.get(&host)
+            .map(|key| key.clone())
+            .ok_or_else(|| FederationError::UnknownHost(host.clone()))?;
+
+        let expected = self.round();
+        if contribution.round != expected {
+            return Err(FederationError::WrongRound { host, got: contribution.round, expected });
+        }
+        if now().saturating_sub(contribution.sent_at) > MAX_CONTRIBUTION_AGE_SECS {
+            return Err(FederationError::Expired(host));
+        }
+        let data = signed_bytes(&host, contribution.round, contribution.sent_at, &contribution.baseline, &contribution.model);
+        if !verify(&public_key, &data, &contribution.signature) {
+            return Err(FederationError::BadSignature(host));
+        }
+        let stats = &contribution.baseline;
+        if stats.vars.len() != stats.width() {
+            return Err(FederationError::Schema { host, got: stats.vars.len(), expected: stats.width() });
+        }
+
+        self.contributions.insert(host, contribution);
+        Ok(())
+    }
+
+    // Close the round: FedAvg everything received and start the next one
+    pub fn aggregate(&self) -> Result<FleetAggregate, FederationError> {
+        let have = self.contributions.len();
+        if have < self.min_hosts {
+            return Err(FederationError::NotEnoughHosts { have, need: self.min_hosts });
+        }
+        let contributions: Vec<HostContribution> = self.contributions.iter().map(|c| c.value().clone()).collect();
+
+        // The most common feature width defines this round's schema
+        let mut widths = std::collections::HashMap::new();
+        for c in &contributions {
+            *widths.entry(c.baseline.width()).or_insert(0usize) += 1;
+        }
+        let width = widths.into_iter().max_by_key(|&(w, n)| (n, w)).map(|(w, _)| w).unwrap_or(0);
+        let members: Vec<&HostContribution> = contributions.iter()
+            .filter(|c| c.baseline.width() == width && c.baseline.samples > 0)
+            .collect();
+        for c in contributions.iter().filter(|c| c.baseline.width() != width) {
+            tracing::warn!("Dropping {} from round {}: {} features, fleet has {}", c.host_id, self.round(), c.baseline.width(), width);
+        }
+        if members.len() < self.min_hosts {
+            return Err(FederationError::NotEnoughHosts { have: members.len(), need: self.min_hosts });
+        }
+
+        let baseline = Self::average_baselines(&members, width);
+        let model = Self::average_models(&members);
+
+        let round = self.round.fetch_add(1, Ordering::SeqCst);
+        self.contributions.clear();
+
+        let signature = self.identity.sign(&aggregate_bytes(round, members.len(), &baseline, &model));
+        tracing::info!("Aggregated federated round {} over {} hosts", round, members.len());
+        Ok(FleetAggregate { round, hosts: members.len(), baseline, model, signature })
+    }
+
+    // Sample-weighted means; variances pooled with the between-host spread
+    fn average_baselines(members: &[&HostContribution], width: usize) -> BaselineStats {
+        let weight = |c: &HostContribution| c.baseline.samples.min(MAX_HOST_WEIGHT) as f64;
+        let total: f64 = members.iter().map(|c| weight(c)).sum();
+
+        let mut means = vec![0.0; width];
+        for c in members {
+            for (m, x) in means.iter_mut().zip(&c.baseline.means) {
+                *m += weight(c) * x / total;
+            }
+        }
+        let mut vars = vec![0.0; width];
+        for c in members {
+            for (i, v) in vars.iter_mut().enumerate() {
+                let spread = c.baseline.means[i] - means[i];
+                *v += weight(c) * (c.baseline.vars[i] + spread * spread) / total;
+            }
+        }
+
+        BaselineStats { means, vars, samples: members.iter().map(|c| c.baseline.samples).sum() }
+    }
+
+    // FedAvg over hosts on the newest model version with matching shapes
+    fn average_models(members: &[&HostContribution]) -> Option<ModelUpdate> {
+        let version = members.iter().filter_map(|c| c.model.as_ref()).map(|m| m.model_version).max()?;
+        let updates: Vec<(&ModelUpdate, f64)> = members.iter()
+            .filter_map(|c| c.model.as_ref().map(|m| (m, c.baseline.samples.min(MAX_HOST_WEIGHT) as f64)))
+            .filter(|(m, _)| m.model_version == version)
+            .collect();
+        let len = updates.first()?.0.weights.len();
+        let updates: Vec<_> = updates.into_iter().filter(|(m, _)| m.weights.len() == len).collect();
+        let total: f64 = updates.iter().map(|(_, w)| w).sum();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut weights = vec![0.0f32; len];
+        for (update, w) in &updates {
+            for (acc, x) in weights.iter_mut().zip(&update.weights) {
+                *acc += (*w / total) as f32 * x;
+            }
+        }
+        Some(ModelUpdate { model_version: version, weights })
+    }
+
+    // Close a round every interval; aggregates go out on the receiver for
+    // the transport to push to agents
+    pub fn start(self: Arc<Self>, interval_secs: u64) -> (tokio::task::JoinHandle<()>, mpsc::UnboundedReceiver<FleetAggregate>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.aggregate() {
+                    Ok(aggregate) => {
+                        if tx.send(aggregate).is_err() {
+                            return;
+                        }
+                    }
+                    // Keep collecting into the same round
+                    Err(e) => tracing::debug!("Federated round {} not aggregated: {}", self.round(), e),
+                }
+            }
+        });
+
+        (handle, rx)
+    }
+}
@@ -0,0 +1,1115 @@
+// src/fleet.rs
+// Fleet mode: one qksd is the controller for many agents. Agents dial the
+// controller's QksFleet service (proto/fleet.proto) over mutual TLS and send
+// it their events, batched and spooled by event_forward.rs like any
+// forwarding sink, so nothing is lost while the controller is away. The
+// controller appends what arrives to fleet/events.jsonl and pushes
+// directives back: the rules in fleet.policy_file, the signed model at
+// fleet.model_path, and token revocations issued anywhere in the fleet.
+//
+// A host's fleet ID is derived from its identity key, the one its tokens
+// are signed with, so an ID can't be claimed without the key. TLS shows a
+// host holds a certificate from fleet.ca; enrolment then has the agent sign
+// a fresh challenge with its identity key, and the controller binds the ID
+// to that certificate for every later call. Directives are signed by the
+// controller and journaled with a sequence number: a reconnecting agent
+// resumes after the last one it applied, and a restarted one is sent the
+// current policy and model again. Models are checked against
+// detector.trusted_keys on the agent like any other load. A fleet policy or
+// model stays in force until the next one, or until a local reload changes
+// [policy] or the model.
+//
+// Revocations an agent issues wait in fleet/outbox.json until the
+// controller has taken them.
+use crate::audit_log::hex;
+use crate::config::{FleetRole, FleetSection, PolicySection};
+use crate::constant_time;
+use crate::crypto_identifiers::{CryptoIdentifier, RevocationProof};
+use crate::event_forward::{self, Delivery, Destination, ForwardError, SendError};
+use crate::events::{EventBus, SecurityEvent};
+use crate::metrics;
+use crate::ml_detector::MLAnomalyDetector;
+use crate::model_signing;
+use crate::policy::PolicyEngine;
+use crate::revocation_propagation::{PropagationError, PropagationMode, RevocationMessage, RevocationPropagator};
+use crate::systemd::now_secs;
+use dashmap::DashMap;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("qks.fleet.v1");
+}
+
+use proto::qks_fleet_client::QksFleetClient;
+use proto::qks_fleet_server::{QksFleet, QksFleetServer};
+
+// A fleet ID is this much of the SHA-256 of the host's public key
+const ID_BYTES: usize = 16;
+// How long an enrolment challenge stays good
+const CHALLENGE_SECS: u64 = 60;
+// Far more than are live at once: tokens expire within hours
+const MAX_REVOCATIONS: usize = 10_000;
+// Directives issued while a subscriber is still catching up; one that falls
+// further behind is dropped and resumes from its last sequence
+const DIRECTIVE_BACKLOG: usize = 256;
+const SUBSCRIBER_QUEUE: usize = 16;
+// Model directives carry the model
+const MAX_MESSAGE_BYTES: usize = 512 * 1024 * 1024;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const JOURNAL_NAME: &str = "directives.json";
+const AGENTS_NAME: &str = "agents.json";
+const EVENTS_NAME: &str = "events.jsonl";
+const OUTBOX_NAME: &str = "outbox.json";
+const MODELS_NAME: &str = "models";
+
+#[derive(Debug, thiserror::Error)]
+pub enum FleetError {
+    #[error("fleet.{0} is not set")]
+    Missing(&'static str),
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("fleet.controller {0}: {1}")]
+    Controller(String, String),
+    #[error("fleet.listen {0}: {1}")]
+    Listen(SocketAddr, std::io::Error),
+    #[error("fleet TLS: {0}")]
+    Tls(#[from] tonic::transport::Error),
+    #[error("fleet event spool: {0}")]
+    Spool(#[from] ForwardError),
+}
+
+pub fn id_of(public_key: &[u8]) -> String {
+    hex(&digest::digest(&digest::SHA256, public_key).as_ref()[..ID_BYTES])
+}
+
+pub fn valid_id(id: &str) -> bool {
+    id.len() == ID_BYTES * 2 && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+// serde_json output is stable for these plain tuples
+fn directive_bytes(sequence: u64, kind: &str, issued_at: u64, payload: &str) -> Vec<u8> {
+    serde_json::to_vec(&("fleet-directive", sequence, kind, issued_at, payload)).unwrap_or_default()
+}
+
+fn enroll_bytes(challenge: &[u8], hostname: &str, controller_id: &str) -> Vec<u8> {
+    serde_json::to_vec(&("fleet-enroll", challenge, hostname, controller_id)).unwrap_or_default()
+}
+
+fn read_file(path: &Option<PathBuf>, field: &'static str) -> Result<Vec<u8>, FleetError> {
+    let path = path.as_ref().ok_or(FleetError::Missing(field))?;
+    fs::read(path).map_err(|source| FleetError::Io { path: path.clone(), source })
+}
+
+fn load_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            tracing::warn!("{} unreadable, starting afresh: {}", path.display(), e);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+// Through a temporary file, so a crash leaves the old copy
+fn save_json<T: Serialize>(path: &Path, value: &T) {
+    let temporary = path.with_extension("tmp");
+    let saved = serde_json::to_vec(value)
+        .map_err(std::io::Error::from)
+        .and_then(|data| fs::write(&temporary, data))
+        .and_then(|()| fs::rename(&temporary, path));
+    if let Err(e) = saved {
+        tracing::warn!("{} not saved: {}", path.display(), e);
+    }
+}
+
+// One component or more, none of them `..` or a root
+fn safe_name(name: &str) -> bool {
+    !name.is_empty() && Path::new(name).components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DirectiveKind {
+    Policy,
+    Model,
+    Revocation,
+}
+
+impl DirectiveKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            DirectiveKind::Policy => "policy",
+            DirectiveKind::Model => "model",
+            DirectiveKind::Revocation => "revocation",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        [DirectiveKind::Policy, DirectiveKind::Model, DirectiveKind::Revocation].into_iter().find(|k| k.as_str() == kind)
+    }
+}
+
+// A directive as journaled; model files travel beside it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Signed {
+    sequence: u64,
+    kind: DirectiveKind,
+    payload: String,
+    issued_at: u64,
+    signature: Vec<u8>,
+}
+
+impl Signed {
+    fn to_proto(&self, files: Vec<proto::BundleFile>) -> proto::Directive {
+        proto::Directive {
+            sequence: self.sequence,
+            kind: self.kind.as_str().to_string(),
+            payload: self.payload.clone(),
+            issued_at: self.issued_at,
+            signature: self.signature.clone(),
+            files,
+        }
+    }
+}
+
+// The payload of a model directive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ModelManifest {
+    // Over the names and digests below; names the bundle on agents
+    digest: String,
+    // The file to load, or empty for a model directory
+    load: String,
+    // (name, SHA-256) in name order
+    files: Vec<(String, String)>,
+}
+
+impl ModelManifest {
+    fn new(load: String, files: &[proto::BundleFile]) -> Self {
+        let mut digests: Vec<(String, String)> = files.iter().map(|file| (file.name.clone(), sha256_hex(&file.content))).collect();
+        digests.sort();
+        Self { digest: Self::digest_of(&digests), load, files: digests }
+    }
+
+    fn digest_of(files: &[(String, String)]) -> String {
+        hex(&digest::digest(&digest::SHA256, &serde_json::to_vec(files).unwrap_or_default()).as_ref()[..ID_BYTES])
+    }
+}
+
+// The payload of a revocation directive. The controller vouches for
+// `origin_key`; agents check the origin's ID derives from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RelayedRevocation {
+    message: RevocationMessage,
+    origin_key: Vec<u8>,
+}
+
+// What a controller has issued: the current policy and model, and recent
+// revocations, each superseding nothing
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    last: u64,
+    policy: Option<Signed>,
+    model: Option<Signed>,
+    revocations: VecDeque<Signed>,
+}
+
+impl Journal {
+    fn after(&self, sequence: u64) -> Vec<Signed> {
+        let mut directives: Vec<Signed> = self.policy.iter().chain(&self.model).chain(&self.revocations)
+            .filter(|directive| directive.sequence > sequence)
+            .cloned()
+            .collect();
+        directives.sort_by_key(|directive| directive.sequence);
+        directives
+    }
+
+    fn model_digest(&self) -> Option<String> {
+        let model = self.model.as_ref()?;
+        serde_json::from_str::<ModelManifest>(&model.payload).ok().map(|manifest| manifest.digest)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AgentRecord {
+    hostname: String,
+    public_key: Vec<u8>,
+    // SHA-256 of the client certificate it enrolled with
+    certificate: String,
+    enrolled_at: u64,
+    last_seen: u64,
+    events: u64,
+}
+
+// fleet.model_path as last read, and what it was read from
+struct ModelFiles {
+    stamp: Vec<(String, u64, Option<SystemTime>)>,
+    digest: String,
+    files: Arc<Vec<proto::BundleFile>>,
+}
+
+// Agents' events, one JSON record a line; rotated to events.jsonl.1
+struct EventLog {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Option<fs::File>,
+    bytes: u64,
+}
+
+impl EventLog {
+    fn append(&mut self, lines: &[u8]) -> std::io::Result<()> {
+        if self.file.is_some() && self.bytes + lines.len() as u64 > self.max_bytes {
+            self.file = None;
+            fs::rename(&self.path, self.path.with_extension("jsonl.1"))?;
+        }
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.bytes = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(lines)?;
+            self.bytes += lines.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn certificate<T>(request: &Request<T>) -> Result<String, Status> {
+    let certificates = request.peer_certs().ok_or_else(|| Status::unauthenticated("no client certificate"))?;
+    let certificate = certificates.first().ok_or_else(|| Status::unauthenticated("no client certificate"))?;
+    Ok(sha256_hex(certificate.get_ref()))
+}
+
+struct Controller {
+    identity: Arc<CryptoIdentifier>,
+    id: String,
+    allowed: Vec<String>,
+    dir: PathBuf,
+    agents: DashMap<String, AgentRecord>,
+    // Certificate digest -> challenge and when it was issued
+    challenges: DashMap<String, (Vec<u8>, u64)>,
+    journal: Mutex<Journal>,
+    model: Mutex<Option<ModelFiles>>,
+    directives: broadcast::Sender<Signed>,
+    revocations: Arc<RevocationPropagator>,
+    events: Mutex<EventLog>,
+}
+
+impl Controller {
+    fn open(section: &FleetSection, identity: Arc<CryptoIdentifier>, revocations: Arc<RevocationPropagator>, dir: PathBuf) -> Self {
+        let journal: Journal = load_json(&dir.join(JOURNAL_NAME));
+        let agents: DashMap<String, AgentRecord> = load_json::<Vec<(String, AgentRecord)>>(&dir.join(AGENTS_NAME)).into_iter().collect();
+        tracing::info!("Fleet controller has {} enrolled agents, directives up to {}", agents.len(), journal.last);
+        let events = EventLog { path: dir.join(EVENTS_NAME), max_bytes: section.events_max_mb * 1024 * 1024, file: None, bytes: 0 };
+        Self {
+            id: id_of(&identity.public_key_bytes()),
+            identity,
+            allowed: section.agents.clone(),
+            dir,
+            agents,
+            challenges: DashMap::new(),
+            journal: Mutex::new(journal),
+            model: Mutex::new(None),
+            directives: broadcast::channel(DIRECTIVE_BACKLOG).0,
+            revocations,
+            events: Mutex::new(events),
+        }
+    }
+
+    fn admits(&self, agent_id: &str) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|id| id == agent_id)
+    }
+
+    // The agent's hostname when the call came with the certificate it
+    // enrolled with
+    fn authenticate<T>(&self, request: &Request<T>, agent_id: &str) -> Result<String, Status> {
+        let certificate = certificate(request)?;
+        match self.agents.get(agent_id) {
+            Some(agent) if self.admits(agent_id) && constant_time::ct_eq(agent.certificate.as_bytes(), certificate.as_bytes()) => Ok(agent.hostname.clone()),
+            _ => Err(Status::unauthenticated(format!("{} is not enrolled with this certificate", agent_id))),
+        }
+    }
+
+    fn save_agents(&self) {
+        let agents: Vec<(String, AgentRecord)> = self.agents.iter().map(|agent| (agent.key().clone(), agent.value().clone())).collect();
+        save_json(&self.dir.join(AGENTS_NAME), &agents);
+    }
+
+    fn issue(&self, kind: DirectiveKind, payload: String) -> Signed {
+        let mut journal = self.journal.lock().unwrap();
+        // Past anything issued before a lost journal, too
+        let sequence = (journal.last + 1).max(now_nanos());
+        let issued_at = now_secs();
+        let signature = self.identity.sign(&directive_bytes(sequence, kind.as_str(), issued_at, &payload));
+        let directive = Signed { sequence, kind, payload, issued_at, signature };
+        journal.last = sequence;
+        match kind {
+            DirectiveKind::Policy => journal.policy = Some(directive.clone()),
+            DirectiveKind::Model => journal.model = Some(directive.clone()),
+            DirectiveKind::Revocation => {
+                journal.revocations.push_back(directive.clone());
+                if journal.revocations.len() > MAX_REVOCATIONS {
+                    journal.revocations.pop_front();
+                }
+            }
+        }
+        save_json(&self.dir.join(JOURNAL_NAME), &*journal);
+        // Under the lock, so subscribers see directives in sequence order
+        let _ = self.directives.send(directive.clone());
+        metrics::global().incr("qks_fleet_directives_issued_total", &[("kind", kind.as_str())]);
+        directive
+    }
+
+    fn relay(&self, message: &RevocationMessage, origin_key: Vec<u8>) {
+        let relayed = RelayedRevocation { message: message.clone(), origin_key };
+        match serde_json::to_string(&relayed) {
+            Ok(payload) => {
+                self.issue(DirectiveKind::Revocation, payload);
+            }
+            Err(e) => tracing::error!("Revocation {} from {} not relayed: {}", message.sequence, message.origin_host, e),
+        }
+    }
+
+    fn deliver(&self, directive: &Signed, agent_model: &str) -> proto::Directive {
+        let files = match directive.kind {
+            DirectiveKind::Model => {
+                let digest = serde_json::from_str::<ModelManifest>(&directive.payload).map(|manifest| manifest.digest).unwrap_or_default();
+                match &*self.model.lock().unwrap() {
+                    Some(model) if model.digest == digest && digest != agent_model => model.files.to_vec(),
+                    _ => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        };
+        directive.to_proto(files)
+    }
+
+    fn watch(self: Arc<Self>, policy_file: Option<PathBuf>, model_path: Option<PathBuf>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let (controller, policy_file, model_path) = (self.clone(), policy_file.clone(), model_path.clone());
+                let checked = tokio::task::spawn_blocking(move || {
+                    if let Some(path) = &policy_file {
+                        if let Err(e) = controller.refresh_policy(path) {
+                            tracing::warn!("Fleet policy {} not issued: {}", path.display(), e);
+                        }
+                    }
+                    if let Some(path) = &model_path {
+                        if let Err(e) = controller.refresh_model(path) {
+                            tracing::warn!("Fleet model {} not issued: {}", path.display(), e);
+                        }
+                    }
+                });
+                if let Err(e) = checked.await {
+                    tracing::warn!("Fleet file check failed: {}", e);
+                }
+            }
+        })
+    }
+
+    fn refresh_policy(&self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let policy: PolicySection = toml::from_str(&text).map_err(|e| e.to_string())?;
+        crate::policy::compile(&policy).map_err(|e| e.to_string())?;
+        let payload = serde_json::to_string(&policy).map_err(|e| e.to_string())?;
+        let current = self.journal.lock().unwrap().policy.as_ref().map(|directive| directive.payload.clone());
+        if current.as_deref() != Some(payload.as_str()) {
+            let directive = self.issue(DirectiveKind::Policy, payload);
+            tracing::info!("Fleet policy {} issued from {}: {} rules, {} transforms", directive.sequence, path.display(), policy.rules.len(), policy.transforms.len());
+        }
+        Ok(())
+    }
+
+    // Files are only read again when their sizes or times change
+    fn refresh_model(&self, path: &Path) -> Result<(), String> {
+        let bundle = model_signing::bundle(path).map_err(|e| e.to_string())?;
+        let stamp: Vec<_> = bundle
+            .iter()
+            .map(|(name, path)| {
+                let metadata = fs::metadata(path).ok();
+                (name.clone(), metadata.as_ref().map_or(0, |m| m.len()), metadata.and_then(|m| m.modified().ok()))
+            })
+            .collect();
+        if self.model.lock().unwrap().as_ref().is_some_and(|model| model.stamp == stamp) {
+            return Ok(());
+        }
+
+        let mut files = Vec::with_capacity(bundle.len());
+        for (name, path) in bundle {
+            let content = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            files.push(proto::BundleFile { name, content });
+        }
+        let load = if path.is_dir() { String::new() } else { path.file_name().unwrap_or_default().to_string_lossy().into_owned() };
+        let manifest = ModelManifest::new(load, &files);
+        let digest = manifest.digest.clone();
+        // Before the directive, so no subscriber gets it without its files
+        *self.model.lock().unwrap() = Some(ModelFiles { stamp, digest: digest.clone(), files: Arc::new(files) });
+
+        if self.journal.lock().unwrap().model_digest().as_deref() != Some(digest.as_str()) {
+            let count = manifest.files.len();
+            let payload = serde_json::to_string(&manifest).map_err(|e| e.to_string())?;
+            let directive = self.issue(DirectiveKind::Model, payload);
+            tracing::info!("Fleet model {} issued as directive {} from {} ({} files)", digest, directive.sequence, path.display(), count);
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let mut agents: Vec<_> = self
+            .agents
+            .iter()
+            .map(|agent| {
+                serde_json::json!({
+                    "id": agent.key(),
+                    "hostname": agent.hostname,
+                    "enrolled_at": agent.enrolled_at,
+                    "last_seen": agent.last_seen,
+                    "events": agent.events,
+                    "allowed": self.admits(agent.key()),
+                })
+            })
+            .collect();
+        agents.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+        let journal = self.journal.lock().unwrap();
+        serde_json::json!({
+            "sequence": journal.last,
+            "policy": journal.policy.as_ref().map(|directive| directive.sequence),
+            "model": journal.model_digest(),
+            "revocations": journal.revocations.len(),
+            "agents": agents,
+        })
+    }
+}
+
+struct FleetService {
+    controller: Arc<Controller>,
+}
+
+type DirectiveStream = Pin<Box<dyn Stream<Item = Result<proto::Directive, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl QksFleet for FleetService {
+    type SubscribeStream = DirectiveStream;
+
+    async fn hello(&self, request: Request<proto::HelloRequest>) -> Result<Response<proto::HelloReply>, Status> {
+        let certificate = certificate(&request)?;
+        let mut challenge = vec![0u8; 32];
+        SystemRandom::new().fill(&mut challenge).map_err(|_| Status::internal("no randomness for a challenge"))?;
+        let now = now_secs();
+        self.controller.challenges.retain(|_, (_, issued)| now.saturating_sub(*issued) <= CHALLENGE_SECS);
+        self.controller.challenges.insert(certificate, (challenge.clone(), now));
+        Ok(Response::new(proto::HelloReply {
+            controller_id: self.controller.id.clone(),
+            public_key: self.controller.identity.public_key_bytes(),
+            challenge,
+        }))
+    }
+
+    async fn enroll(&self, request: Request<proto::EnrollRequest>) -> Result<Response<proto::EnrollReply>, Status> {
+        let controller = &self.controller;
+        let certificate = certificate(&request)?;
+        let request = request.into_inner();
+        let Some((_, (challenge, issued))) = controller.challenges.remove(&certificate) else {
+            return Err(Status::failed_precondition("no challenge for this certificate; say hello first"));
+        };
+        if now_secs().saturating_sub(issued) > CHALLENGE_SECS {
+            return Err(Status::failed_precondition("challenge expired"));
+        }
+        if request.hostname.is_empty() || request.hostname.len() > 255 {
+            return Err(Status::invalid_argument("hostname must be 1 to 255 bytes"));
+        }
+        if !verify(&request.public_key, &enroll_bytes(&challenge, &request.hostname, &controller.id), &request.signature) {
+            return Err(Status::unauthenticated("challenge signature does not verify against the key offered"));
+        }
+        let agent_id = id_of(&request.public_key);
+        if !controller.admits(&agent_id) {
+            tracing::warn!("Agent {} ({}) refused: not in fleet.agents", agent_id, request.hostname);
+            return Err(Status::permission_denied(format!("{} is not in fleet.agents", agent_id)));
+        }
+
+        let now = now_secs();
+        let events = controller.agents.get(&agent_id).map_or(0, |agent| agent.events);
+        tracing::info!("Agent {} ({}) enrolled", agent_id, request.hostname);
+        let record = AgentRecord { hostname: request.hostname, public_key: request.public_key, certificate, enrolled_at: now, last_seen: now, events };
+        controller.agents.insert(agent_id.clone(), record);
+        controller.save_agents();
+        metrics::global().incr("qks_fleet_enrolments_total", &[]);
+        Ok(Response::new(proto::EnrollReply { agent_id }))
+    }
+
+    async fn push_events(&self, request: Request<proto::EventBatch>) -> Result<Response<proto::PushEventsReply>, Status> {
+        let controller = &self.controller;
+        let agent_id = request.get_ref().agent_id.clone();
+        let hostname = controller.authenticate(&request, &agent_id)?;
+        let batch = request.into_inner();
+
+        let received_at = now_secs();
+        let mut lines = Vec::new();
+        let mut accepted = 0u64;
+        for event in &batch.events {
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(event) else {
+                continue;
+            };
+            let record = serde_json::json!({ "agent": agent_id, "host": hostname, "received_at": received_at, "event": event });
+            if serde_json::to_writer(&mut lines, &record).is_ok() {
+                lines.push(b'\n');
+                accepted += 1;
+            }
+        }
+        if accepted == 0 && !batch.events.is_empty() {
+            return Err(Status::invalid_argument("no event in the batch is JSON"));
+        }
+        controller.events.lock().unwrap().append(&lines).map_err(|e| Status::unavailable(format!("fleet event log: {}", e)))?;
+
+        if let Some(mut agent) = controller.agents.get_mut(&agent_id) {
+            agent.last_seen = received_at;
+            agent.events += accepted;
+        }
+        metrics::global().add("qks_fleet_events_total", &[("agent", agent_id.as_str())], accepted);
+        Ok(Response::new(proto::PushEventsReply { accepted }))
+    }
+
+    async fn push_revocation(&self, request: Request<proto::Revocation>) -> Result<Response<proto::PushRevocationReply>, Status> {
+        let controller = &self.controller;
+        let agent_id = request.get_ref().agent_id.clone();
+        controller.authenticate(&request, &agent_id)?;
+        let message: RevocationMessage = serde_json::from_str(&request.into_inner().message)
+            .map_err(|e| Status::invalid_argument(format!("not a revocation message: {}", e)))?;
+        if message.origin_host != agent_id {
+            return Err(Status::permission_denied("agents only report revocations they issued"));
+        }
+        let origin_key = controller.agents.get(&agent_id).map(|agent| agent.public_key.clone()).unwrap_or_default();
+        // False for one already taken, e.g. resent after a lost reply
+        let applied = controller.revocations.apply_relayed(&message, &origin_key).map_err(|e| match e {
+            PropagationError::NotRevoker(_) => Status::permission_denied(e.to_string()),
+            e => Status::invalid_argument(e.to_string()),
+        })?;
+        if applied {
+            controller.relay(&message, origin_key);
+        }
+        Ok(Response::new(proto::PushRevocationReply {}))
+    }
+
+    async fn subscribe(&self, request: Request<proto::SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let controller = self.controller.clone();
+        let agent_id = request.get_ref().agent_id.clone();
+        controller.authenticate(&request, &agent_id)?;
+        let proto::SubscribeRequest { after, model_digest, .. } = request.into_inner();
+
+        // Live first, so nothing issued while the backlog is read is missed
+        let mut live = controller.directives.subscribe();
+        let backlog = controller.journal.lock().unwrap().after(after);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+        tokio::spawn(async move {
+            let mut last = after;
+            for directive in backlog {
+                if tx.send(Ok(controller.deliver(&directive, &model_digest))).await.is_err() {
+                    return;
+                }
+                last = directive.sequence;
+            }
+            loop {
+                match live.recv().await {
+                    Ok(directive) if directive.sequence > last => {
+                        if tx.send(Ok(controller.deliver(&directive, &model_digest))).await.is_err() {
+                            return;
+                        }
+                        last = directive.sequence;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let _ = tx.send(Err(Status::aborted(format!("{} directives behind; resubscribe", missed)))).await;
+                        return;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+// The agent's connection to its controller
+struct Link {
+    channel: Channel,
+    identity: Arc<CryptoIdentifier>,
+    id: String,
+    hostname: String,
+    url: String,
+    pinned: Option<String>,
+    // The controller's ID and key once enrolled
+    controller: Mutex<Option<(String, Vec<u8>)>>,
+    enrolling: tokio::sync::Mutex<()>,
+    last_contact: AtomicU64,
+}
+
+impl Link {
+    fn client(&self) -> QksFleetClient<Channel> {
+        QksFleetClient::new(self.channel.clone()).max_decoding_message_size(MAX_MESSAGE_BYTES)
+    }
+
+    fn contact(&self) {
+        self.last_contact.store(now_secs(), Ordering::Relaxed);
+    }
+
+    // Enrols on first use, and again whenever the controller has forgotten us
+    async fn enrolled(&self) -> Result<(String, Vec<u8>), Status> {
+        let _enrolling = self.enrolling.lock().await;
+        let known = self.controller.lock().unwrap().clone();
+        if let Some(controller) = known {
+            return Ok(controller);
+        }
+
+        let mut client = self.client();
+        let hello = client.hello(proto::HelloRequest {}).await?.into_inner();
+        if id_of(&hello.public_key) != hello.controller_id {
+            return Err(Status::unauthenticated("controller ID does not derive from its key"));
+        }
+        if let Some(pinned) = self.pinned.as_ref().filter(|pinned| **pinned != hello.controller_id) {
+            return Err(Status::permission_denied(format!("controller is {}; fleet.controller_id pins {}", hello.controller_id, pinned)));
+        }
+        let signature = self.identity.sign(&enroll_bytes(&hello.challenge, &self.hostname, &hello.controller_id));
+        let request = proto::EnrollRequest { public_key: self.identity.public_key_bytes(), hostname: self.hostname.clone(), signature };
+        client.enroll(request).await?;
+        tracing::info!("Enrolled as fleet agent {} with controller {} at {}", self.id, hello.controller_id, self.url);
+
+        let controller = (hello.controller_id, hello.public_key);
+        *self.controller.lock().unwrap() = Some(controller.clone());
+        self.contact();
+        Ok(controller)
+    }
+
+    // Anything but the controller being unreachable may mean it no longer
+    // knows this certificate
+    fn failed(&self, status: &Status) {
+        if matches!(status.code(), Code::Unauthenticated | Code::FailedPrecondition) {
+            *self.controller.lock().unwrap() = None;
+        }
+    }
+}
+
+// Events go to the controller through event_forward's batching and spool
+struct Uplink {
+    link: Arc<Link>,
+}
+
+impl Destination for Uplink {
+    fn name(&self) -> &str {
+        "fleet"
+    }
+
+    fn encode(&self, event: &SecurityEvent) -> Option<String> {
+        serde_json::to_string(event).ok()
+    }
+
+    fn send<'a>(&'a self, batch: &'a [String]) -> Delivery<'a> {
+        Box::pin(async move {
+            self.link.enrolled().await.map_err(|status| {
+                self.link.failed(&status);
+                SendError::Retry(status.message().to_string())
+            })?;
+            let request = proto::EventBatch { agent_id: self.link.id.clone(), events: batch.to_vec() };
+            match self.link.client().push_events(request).await {
+                Ok(_) => {
+                    self.link.contact();
+                    Ok(())
+                }
+                Err(status) if status.code() == Code::InvalidArgument => Err(SendError::Reject(status.message().to_string())),
+                Err(status) => {
+                    self.link.failed(&status);
+                    Err(SendError::Retry(status.to_string()))
+                }
+            }
+        })
+    }
+}
+
+struct Agent {
+    link: Arc<Link>,
+    dir: PathBuf,
+    revocations: Arc<RevocationPropagator>,
+    // The last directive applied
+    applied: AtomicU64,
+    // Digest of the installed fleet model
+    model: Mutex<Option<String>>,
+    outbox: Mutex<VecDeque<RevocationMessage>>,
+    queued: Notify,
+}
+
+impl Agent {
+    fn open(section: &FleetSection, identity: Arc<CryptoIdentifier>, revocations: Arc<RevocationPropagator>, dir: PathBuf) -> Result<Self, FleetError> {
+        let url = section.controller.clone().ok_or(FleetError::Missing("controller"))?;
+        let tls = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(read_file(&section.ca, "ca")?))
+            .identity(Identity::from_pem(read_file(&section.tls_cert, "tls_cert")?, read_file(&section.tls_key, "tls_key")?));
+        let channel = Endpoint::from_shared(url.clone())
+            .map_err(|e| FleetError::Controller(url.clone(), e.to_string()))?
+            .tls_config(tls)?
+            .connect_lazy();
+
+        // A bundle installed before the restart need not be sent again
+        let model = fs::read_dir(dir.join(MODELS_NAME))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .find(|name| valid_id(name));
+        let outbox: Vec<RevocationMessage> = load_json(&dir.join(OUTBOX_NAME));
+        if !outbox.is_empty() {
+            tracing::info!("{} revocations from before still to send to the controller", outbox.len());
+        }
+
+        let link = Link {
+            channel,
+            id: id_of(&identity.public_key_bytes()),
+            identity,
+            hostname: crate::syslog_sink::hostname(),
+            url,
+            pinned: section.controller_id.clone(),
+            controller: Mutex::new(None),
+            enrolling: tokio::sync::Mutex::new(()),
+            last_contact: AtomicU64::new(0),
+        };
+        Ok(Self {
+            link: Arc::new(link),
+            dir,
+            revocations,
+            applied: AtomicU64::new(0),
+            model: Mutex::new(model),
+            outbox: Mutex::new(outbox.into()),
+            queued: Notify::new(),
+        })
+    }
+
+    fn queue(&self, message: RevocationMessage) {
+        let mut outbox = self.outbox.lock().unwrap();
+        outbox.push_back(message);
+        save_json(&self.dir.join(OUTBOX_NAME), &*outbox);
+        self.queued.notify_one();
+    }
+
+    fn dequeue(&self) {
+        let mut outbox = self.outbox.lock().unwrap();
+        outbox.pop_front();
+        save_json(&self.dir.join(OUTBOX_NAME), &*outbox);
+    }
+
+    fn send_revocations(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = self.queued.notified() => {}
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                }
+                loop {
+                    let next = self.outbox.lock().unwrap().front().cloned();
+                    let Some(message) = next else {
+                        break;
+                    };
+                    match self.push_revocation(&message).await {
+                        Ok(()) => self.dequeue(),
+                        Err(status) if matches!(status.code(), Code::InvalidArgument | Code::PermissionDenied) => {
+                            tracing::error!("Controller refused revocation {}, dropped: {}", message.sequence, status.message());
+                            self.dequeue();
+                        }
+                        Err(status) => {
+                            self.link.failed(&status);
+                            tracing::debug!("Revocation {} not sent yet: {}", message.sequence, status);
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn push_revocation(&self, message: &RevocationMessage) -> Result<(), Status> {
+        self.link.enrolled().await?;
+        let message = serde_json::to_string(message).map_err(|e| Status::internal(e.to_string()))?;
+        self.link.client().push_revocation(proto::Revocation { agent_id: self.link.id.clone(), message }).await?;
+        self.link.contact();
+        Ok(())
+    }
+
+    fn follow(self: Arc<Self>, detector: Arc<Mutex<MLAnomalyDetector>>, policy: Arc<PolicyEngine>, max_backoff: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut delay = INITIAL_BACKOFF;
+            loop {
+                match self.follow_once(&detector, &policy, &mut delay).await {
+                    Ok(()) => tracing::info!("Controller closed the directive stream; resubscribing"),
+                    Err(status) => {
+                        self.link.failed(&status);
+                        tracing::debug!("Directive stream from {}: {}", self.link.url, status);
+                    }
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(max_backoff);
+            }
+        })
+    }
+
+    async fn follow_once(self: &Arc<Self>, detector: &Arc<Mutex<MLAnomalyDetector>>, policy: &PolicyEngine, delay: &mut Duration) -> Result<(), Status> {
+        let (_, controller_key) = self.link.enrolled().await?;
+        let request = proto::SubscribeRequest {
+            agent_id: self.link.id.clone(),
+            after: self.applied.load(Ordering::SeqCst),
+            model_digest: self.model.lock().unwrap().clone().unwrap_or_default(),
+        };
+        let mut directives = self.link.client().subscribe(request).await?.into_inner();
+        *delay = INITIAL_BACKOFF;
+
+        while let Some(directive) = directives.message().await? {
+            self.link.contact();
+            let sequence = directive.sequence;
+            let kind = DirectiveKind::parse(&directive.kind).map_or("unknown", DirectiveKind::as_str);
+            match self.apply(&controller_key, directive, detector, policy).await {
+                Ok(()) => metrics::global().incr("qks_fleet_directives_total", &[("kind", kind), ("result", "applied")]),
+                Err(e) => {
+                    metrics::global().incr("qks_fleet_directives_total", &[("kind", kind), ("result", "rejected")]);
+                    tracing::warn!("Fleet {} directive {} not applied: {}", kind, sequence, e);
+                }
+            }
+            // One that failed would fail the same way again
+            self.applied.store(sequence, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    async fn apply(
+        self: &Arc<Self>,
+        controller_key: &[u8],
+        directive: proto::Directive,
+        detector: &Arc<Mutex<MLAnomalyDetector>>,
+        policy: &PolicyEngine,
+    ) -> Result<(), String> {
+        let signed = directive_bytes(directive.sequence, &directive.kind, directive.issued_at, &directive.payload);
+        if !verify(controller_key, &signed, &directive.signature) {
+            return Err("signature does not verify against the controller's key".into());
+        }
+        match DirectiveKind::parse(&directive.kind) {
+            Some(DirectiveKind::Policy) => {
+                let section: PolicySection = serde_json::from_str(&directive.payload).map_err(|e| e.to_string())?;
+                policy.replace_rules(&section).map_err(|e| e.to_string())?;
+                tracing::info!("Applied fleet policy {}", directive.sequence);
+            }
+            Some(DirectiveKind::Model) => {
+                let manifest: ModelManifest = serde_json::from_str(&directive.payload).map_err(|e| e.to_string())?;
+                let (agent, detector, files) = (self.clone(), detector.clone(), directive.files);
+                tokio::task::spawn_blocking(move || agent.install_model(&manifest, files, &detector))
+                    .await
+                    .map_err(|e| e.to_string())??;
+            }
+            Some(DirectiveKind::Revocation) => {
+                let relayed: RelayedRevocation = serde_json::from_str(&directive.payload).map_err(|e| e.to_string())?;
+                if id_of(&relayed.origin_key) != relayed.message.origin_host {
+                    return Err(format!("origin {} does not derive from the key it came with", relayed.message.origin_host));
+                }
+                self.revocations.apply_relayed(&relayed.message, &relayed.origin_key).map_err(|e| e.to_string())?;
+            }
+            None => return Err(format!("unknown kind {:?}", directive.kind)),
+        }
+        Ok(())
+    }
+
+    // Written to models/<digest>, loaded through the usual signature check,
+    // then older bundles removed
+    fn install_model(&self, manifest: &ModelManifest, files: Vec<proto::BundleFile>, detector: &Mutex<MLAnomalyDetector>) -> Result<(), String> {
+        if !valid_id(&manifest.digest) || ModelManifest::digest_of(&manifest.files) != manifest.digest {
+            return Err("manifest digest does not match its files".into());
+        }
+        if !manifest.load.is_empty() && !safe_name(&manifest.load) {
+            return Err(format!("will not load {:?}", manifest.load));
+        }
+        let models = self.dir.join(MODELS_NAME);
+        let target = models.join(&manifest.digest);
+        let io = |path: &Path, e: std::io::Error| format!("{}: {}", path.display(), e);
+
+        if !target.exists() {
+            if files.is_empty() {
+                return Err("no files came with a model this host does not have".into());
+            }
+            let staging = models.join(format!("{}.partial", manifest.digest));
+            let _ = fs::remove_dir_all(&staging);
+            for (name, expected) in &manifest.files {
+                if !safe_name(name) {
+                    return Err(format!("will not write {:?}", name));
+                }
+                let file = files.iter().find(|file| file.name == *name).ok_or_else(|| format!("{} missing", name))?;
+                if sha256_hex(&file.content) != *expected {
+                    return Err(format!("{} does not match its digest", name));
+                }
+                let path = staging.join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| io(parent, e))?;
+                }
+                fs::write(&path, &file.content).map_err(|e| io(&path, e))?;
+            }
+            fs::rename(&staging, &target).map_err(|e| io(&target, e))?;
+        }
+
+        let load = if manifest.load.is_empty() { target.clone() } else { target.join(&manifest.load) };
+        let version = detector.lock().unwrap().reload(&load.to_string_lossy()).map_err(|e| e.to_string())?;
+        tracing::info!("Loaded fleet model {} as version {}", manifest.digest, version);
+        *self.model.lock().unwrap() = Some(manifest.digest.clone());
+        for entry in fs::read_dir(&models).into_iter().flatten().flatten() {
+            if entry.path() != target {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let controller = self.link.controller.lock().unwrap().as_ref().map(|(id, _)| id.clone());
+        serde_json::json!({
+            "controller": self.link.url,
+            "controller_id": controller,
+            "enrolled": controller.is_some(),
+            "last_contact": self.link.last_contact.load(Ordering::Relaxed),
+            "applied": self.applied.load(Ordering::SeqCst),
+            "model": *self.model.lock().unwrap(),
+            "unsent_revocations": self.outbox.lock().unwrap().len(),
+        })
+    }
+}
+
+// This host's part in the fleet; held by Control for revocations and status
+pub struct Fleet {
+    role: FleetRole,
+    id: String,
+    identity: Arc<CryptoIdentifier>,
+    revocations: Arc<RevocationPropagator>,
+    controller: Option<Arc<Controller>>,
+    agent: Option<Arc<Agent>>,
+}
+
+impl Fleet {
+    // `identity` must be the persistent key (CryptoIdentifier::load_or_create)
+    pub fn new(section: &FleetSection, identity: Arc<CryptoIdentifier>, dir: PathBuf) -> Result<Self, FleetError> {
+        fs::create_dir_all(&dir).map_err(|source| FleetError::Io { path: dir.clone(), source })?;
+        let id = id_of(&identity.public_key_bytes());
+        let mode = PropagationMode::ControllerPush { is_controller: section.role == FleetRole::Controller };
+        // Delivery goes through the journal and outbox, not the propagator's
+        // per-peer queue, so its outbound side is left unread
+        let (revocations, _) = RevocationPropagator::new(&id, identity.clone(), mode);
+        let revocations = Arc::new(revocations.with_revokers(section.revokers.iter().cloned()));
+
+        let (controller, agent) = match section.role {
+            FleetRole::Off => (None, None),
+            FleetRole::Controller => (Some(Arc::new(Controller::open(section, identity.clone(), revocations.clone(), dir))), None),
+            FleetRole::Agent => (None, Some(Arc::new(Agent::open(section, identity.clone(), revocations.clone(), dir)?))),
+        };
+        tracing::info!("Fleet {} with ID {}", match section.role { FleetRole::Controller => "controller", _ => "agent" }, id);
+        Ok(Self { role: section.role, id, identity, revocations, controller, agent })
+    }
+
+    // Named tasks for the daemon to supervise
+    pub(crate) fn start(
+        &self,
+        section: &FleetSection,
+        spool_dir: &Path,
+        detector: Arc<Mutex<MLAnomalyDetector>>,
+        policy: Arc<PolicyEngine>,
+        bus: &EventBus,
+    ) -> Result<Vec<(&'static str, JoinHandle<()>)>, FleetError> {
+        let mut tasks = Vec::new();
+        if let Some(controller) = &self.controller {
+            tasks.push(("fleet-server", Self::serve(section, controller.clone())?));
+            if section.policy_file.is_some() || section.model_path.is_some() {
+                let watch = controller.clone().watch(section.policy_file.clone(), section.model_path.clone(), Duration::from_secs(section.watch_secs));
+                tasks.push(("fleet-watch", watch));
+            }
+        }
+        if let Some(agent) = &self.agent {
+            let uplink = Uplink { link: agent.link.clone() };
+            tasks.push(("fleet-events", event_forward::start(uplink, &section.batch, &section.event_kinds, spool_dir, bus)?));
+            let max_backoff = Duration::from_secs(section.batch.max_backoff_secs);
+            tasks.push(("fleet-directives", agent.clone().follow(detector, policy, max_backoff)));
+            tasks.push(("fleet-revocations", agent.clone().send_revocations()));
+        }
+        Ok(tasks)
+    }
+
+    // Bound now so a taken port fails startup rather than a later task
+    fn serve(section: &FleetSection, controller: Arc<Controller>) -> Result<JoinHandle<()>, FleetError> {
+        let listen = section.listen.ok_or(FleetError::Missing("listen"))?;
+        let tls = ServerTlsConfig::new()
+            .identity(Identity::from_pem(read_file(&section.tls_cert, "tls_cert")?, read_file(&section.tls_key, "tls_key")?))
+            .client_ca_root(Certificate::from_pem(read_file(&section.ca, "ca")?));
+        let service = QksFleetServer::new(FleetService { controller }).max_encoding_message_size(MAX_MESSAGE_BYTES);
+        let router = Server::builder().tls_config(tls)?.add_service(service);
+
+        let bound = std::net::TcpListener::bind(listen)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .and_then(tokio::net::TcpListener::from_std);
+        let listener = bound.map_err(|e| FleetError::Listen(listen, e))?;
+        tracing::info!("Fleet controller taking agents on {}", listen);
+        Ok(tokio::spawn(async move {
+            if let Err(e) = router.serve_with_incoming(TcpListenerStream::new(listener)).await {
+                tracing::error!("Fleet controller on {} stopped: {}", listen, e);
+            }
+        }))
+    }
+
+    // A token revoked on this host, for the rest of the fleet
+    pub(crate) fn revoked(&self, proof: RevocationProof) {
+        let message = self.revocations.broadcast(proof);
+        if let Some(controller) = &self.controller {
+            controller.relay(&message, self.identity.public_key_bytes());
+        }
+        if let Some(agent) = &self.agent {
+            agent.queue(message);
+        }
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let mut status = serde_json::json!({
+            "role": self.role,
+            "id": self.id,
+            "revoked_tokens": self.revocations.revoked_count(),
+        });
+        if let Some(controller) = &self.controller {
+            status["controller"] = controller.status();
+        }
+        if let Some(agent) = &self.agent {
+            status["agent"] = agent.status();
+        }
+        status
+    }
+}
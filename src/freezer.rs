@@ -0,0 +1,315 @@
+// src/freezer.rs
+// Suspends processes for the randomizer, snapshot restores and the stop
+// action; a process resumes once every owner holding it has thawed it.
+use crate::metrics;
+use crate::privsep::{self, Signal};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Upper bound on waiting for every thread to actually stop
+const SETTLE_TIMEOUT: Duration = Duration::from_millis(500);
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum FreezeError {
+    #[error("cannot freeze PID {0}: {1}")]
+    Freeze(u32, std::io::Error),
+    #[error("PID {0} did not stop within {1:?}")]
+    Timeout(u32, Duration),
+    #[error("PID {pid} is not frozen by {owner}")]
+    NotHeld { pid: u32, owner: String },
+    #[error("PID {0} is frozen with its cgroup, which ptrace cannot work on")]
+    CgroupFrozen(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FreezeMethod {
+    Cgroup,
+    Signal,
+}
+
+// One owner's hold on a frozen process
+#[derive(Debug, Clone, Serialize)]
+pub struct FreezeHold {
+    pub pid: u32,
+    pub owner: String,
+    pub method: FreezeMethod,
+    pub held_secs: u64,
+    pub expires_in_secs: Option<u64>,
+}
+
+struct FrozenProcess {
+    // cgroup.freeze of the cgroup holding it; None when stopped by signal
+    freeze_file: Option<PathBuf>,
+    // owner -> (since, deadline)
+    owners: HashMap<String, (Instant, Option<Instant>)>,
+}
+
+impl FrozenProcess {
+    fn method(&self) -> FreezeMethod {
+        if self.freeze_file.is_some() {
+            FreezeMethod::Cgroup
+        } else {
+            FreezeMethod::Signal
+        }
+    }
+}
+
+pub struct Freezer {
+    frozen: Mutex<HashMap<u32, FrozenProcess>>,
+}
+
+static GLOBAL: OnceLock<Freezer> = OnceLock::new();
+
+// Holds have to be shared by everyone in the process to mean anything
+pub fn global() -> &'static Freezer {
+    GLOBAL.get_or_init(Freezer::new)
+}
+
+impl Default for Freezer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Freezer {
+    pub fn new() -> Self {
+        Self { frozen: Mutex::new(HashMap::new()) }
+    }
+
+    // Freeze `pid` for `owner`, or add `owner` to an existing freeze. A
+    // second freeze by the same owner replaces its deadline. Blocks until
+    // the process has stopped.
+    pub fn freeze(&self, pid: u32, owner: &str, thaw_after: Option<Duration>) -> Result<(), FreezeError> {
+        self.suspend(pid, owner, thaw_after, FreezeMethod::Cgroup)
+    }
+
+    // Like freeze() but always with SIGSTOP: a task in a frozen cgroup
+    // can't be single-stepped, so anything that ptraces the process takes
+    // this one
+    pub fn stop(&self, pid: u32, owner: &str, thaw_after: Option<Duration>) -> Result<(), FreezeError> {
+        self.suspend(pid, owner, thaw_after, FreezeMethod::Signal)
+    }
+
+    fn suspend(&self, pid: u32, owner: &str, thaw_after: Option<Duration>, method: FreezeMethod) -> Result<(), FreezeError> {
+        let mut frozen = self.frozen.lock().unwrap();
+        let now = Instant::now();
+        let hold = (now, thaw_after.map(|after| now + after));
+        if let Some(process) = frozen.get_mut(&pid) {
+            if method == FreezeMethod::Signal && process.method() == FreezeMethod::Cgroup {
+                return Err(FreezeError::CgroupFrozen(pid));
+            }
+            process.owners.insert(owner.to_string(), hold);
+            return Ok(());
+        }
+
+        let dir = cgroup_dir(pid).filter(|dir| dir.join("cgroup.freeze").exists());
+        let frozen_with = |dir: &PathBuf| frozen.values().any(|other| other.freeze_file == Some(dir.join("cgroup.freeze")));
+        if method == FreezeMethod::Signal && dir.as_ref().is_some_and(frozen_with) {
+            return Err(FreezeError::CgroupFrozen(pid));
+        }
+        let mut process = match dir.filter(|_| method == FreezeMethod::Cgroup) {
+            Some(dir) => freeze_cgroup(pid, dir)?,
+            None => {
+                privsep::signal(pid, Signal::Stop).map_err(|e| FreezeError::Freeze(pid, e))?;
+                FrozenProcess { freeze_file: None, owners: HashMap::new() }
+            }
+        };
+        if let Err(e) = wait_stopped(pid, &process) {
+            unfreeze(pid, &process, frozen.values());
+            return Err(e);
+        }
+        process.owners.insert(owner.to_string(), hold);
+        let method = if process.method() == FreezeMethod::Cgroup { "cgroup" } else { "signal" };
+        frozen.insert(pid, process);
+        metrics::global().incr("qks_freezes_total", &[("owner", owner), ("method", method)]);
+        tracing::debug!(pid, "PID {} frozen for {} ({})", pid, owner, method);
+        Ok(())
+    }
+
+    // Drop `owner`'s hold; the process resumes once no holds are left
+    pub fn thaw(&self, pid: u32, owner: &str) -> Result<(), FreezeError> {
+        let mut frozen = self.frozen.lock().unwrap();
+        let not_held = || FreezeError::NotHeld { pid, owner: owner.to_string() };
+        let process = frozen.get_mut(&pid).ok_or_else(not_held)?;
+        process.owners.remove(owner).ok_or_else(not_held)?;
+        if process.owners.is_empty() {
+            if let Some(process) = frozen.remove(&pid) {
+                unfreeze(pid, &process, frozen.values());
+                tracing::debug!(pid, "PID {} thawed by {}", pid, owner);
+            }
+        }
+        Ok(())
+    }
+
+    // Frozen for as long as the guard lives; dropping it thaws on every
+    // path, including early returns and panics
+    pub fn hold<'a>(&'a self, pid: u32, owner: &'a str, thaw_after: Option<Duration>) -> Result<Frozen<'a>, FreezeError> {
+        self.freeze(pid, owner, thaw_after)?;
+        Ok(Frozen { freezer: self, pid, owner })
+    }
+
+    // stop() for as long as the guard lives
+    pub fn hold_stopped<'a>(&'a self, pid: u32, owner: &'a str, thaw_after: Option<Duration>) -> Result<Frozen<'a>, FreezeError> {
+        self.stop(pid, owner, thaw_after)?;
+        Ok(Frozen { freezer: self, pid, owner })
+    }
+
+    pub fn holds(&self) -> Vec<FreezeHold> {
+        let now = Instant::now();
+        let frozen = self.frozen.lock().unwrap();
+        let mut holds: Vec<FreezeHold> = frozen
+            .iter()
+            .flat_map(|(pid, process)| {
+                process.owners.iter().map(move |(owner, (since, deadline))| FreezeHold {
+                    pid: *pid,
+                    owner: owner.clone(),
+                    method: process.method(),
+                    held_secs: now.duration_since(*since).as_secs(),
+                    expires_in_secs: deadline.map(|d| d.saturating_duration_since(now).as_secs()),
+                })
+            })
+            .collect();
+        holds.sort_by_key(|h| (h.pid, h.owner.clone()));
+        holds
+    }
+
+    // Everything, whoever holds it; returns how many processes resumed
+    pub fn thaw_all(&self) -> usize {
+        let mut frozen = self.frozen.lock().unwrap();
+        let count = frozen.len();
+        for (pid, process) in std::mem::take(&mut *frozen) {
+            unfreeze(pid, &process, std::iter::empty());
+        }
+        count
+    }
+
+    // Thaws holds past their deadline; returns the (pid, owner) released
+    pub fn thaw_expired(&self) -> Vec<(u32, String)> {
+        let now = Instant::now();
+        let expired: Vec<(u32, String)> = {
+            let frozen = self.frozen.lock().unwrap();
+            frozen
+                .iter()
+                .flat_map(|(pid, process)| {
+                    process
+                        .owners
+                        .iter()
+                        .filter(|(_, (_, deadline))| deadline.is_some_and(|d| d <= now))
+                        .map(move |(owner, _)| (*pid, owner.clone()))
+                })
+                .collect()
+        };
+        for (pid, owner) in &expired {
+            tracing::warn!(pid, "Freeze of PID {} by {} timed out; thawing", pid, owner);
+            metrics::global().incr("qks_freeze_timeouts_total", &[("owner", owner.as_str())]);
+            let _ = self.thaw(*pid, owner);
+        }
+        expired
+    }
+}
+
+pub struct Frozen<'a> {
+    freezer: &'a Freezer,
+    pid: u32,
+    owner: &'a str,
+}
+
+impl Frozen<'_> {
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+impl Drop for Frozen<'_> {
+    fn drop(&mut self) {
+        // Already thawed if the hold expired first
+        if let Err(e) = self.freezer.thaw(self.pid, self.owner) {
+            tracing::debug!(pid = self.pid, "Freeze guard for PID {}: {}", self.pid, e);
+        }
+    }
+}
+
+// Thaws expired holds on the global freezer
+pub fn start_reaper() -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = tokio::task::spawn_blocking(|| global().thaw_expired()).await {
+                tracing::error!("Freeze reaper failed: {}", e);
+            }
+        }
+    })
+}
+
+fn cgroup_dir(pid: u32) -> Option<PathBuf> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let relative = cgroups.lines().find_map(|l| l.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/')))
+}
+
+// Freezes the whole cgroup the process is in, so whatever else runs there
+// stops too; the root cgroup has no cgroup.freeze, so it never gets here
+fn freeze_cgroup(pid: u32, dir: PathBuf) -> Result<FrozenProcess, FreezeError> {
+    let freeze_file = dir.join("cgroup.freeze");
+    privsep::cgroup_write(&freeze_file, "1").map_err(|e| FreezeError::Freeze(pid, e))?;
+    Ok(FrozenProcess { freeze_file: Some(freeze_file), owners: HashMap::new() })
+}
+
+// A cgroup stays frozen while another held process is still in it
+fn unfreeze<'a>(pid: u32, process: &FrozenProcess, others: impl IntoIterator<Item = &'a FrozenProcess>) {
+    let resumed = match &process.freeze_file {
+        Some(freeze_file) if others.into_iter().any(|other| other.freeze_file.as_ref() == Some(freeze_file)) => true,
+        Some(freeze_file) => privsep::cgroup_write(freeze_file, "0").is_ok(),
+        None => privsep::signal(pid, Signal::Cont).is_ok(),
+    };
+    if !resumed {
+        tracing::error!(pid, "Failed to resume PID {}", pid);
+    }
+}
+
+fn wait_stopped(pid: u32, process: &FrozenProcess) -> Result<(), FreezeError> {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    loop {
+        let stopped = match &process.freeze_file {
+            Some(freeze_file) => freezer_settled(freeze_file),
+            None => all_threads_stopped(pid),
+        };
+        if stopped {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(FreezeError::Timeout(pid, SETTLE_TIMEOUT));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// cgroup.events reports "frozen 1" once every task is actually frozen
+fn freezer_settled(freeze_file: &Path) -> bool {
+    fs::read_to_string(freeze_file.with_file_name("cgroup.events"))
+        .map_or(false, |events| events.lines().any(|l| l.trim() == "frozen 1"))
+}
+
+fn all_threads_stopped(pid: u32) -> bool {
+    let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return false,
+    };
+
+    tasks.filter_map(|t| t.ok()).all(|task| {
+        // State is the first field after the parenthesised comm
+        fs::read_to_string(task.path().join("stat"))
+            .ok()
+            .and_then(|stat| stat.rsplit_once(')').map(|(_, rest)| rest.trim_start().to_string()))
+            .map_or(false, |rest| rest.starts_with('T') || rest.starts_with('t'))
+    })
+}
@@ -0,0 +1,426 @@
+// src/grpc_api.rs
+// The remote face of the control socket: the same operations as protobuf
+// RPCs (proto/qks.proto), plus server-streamed anomaly and layout events for
+// orchestration systems and fleet controllers. Off unless [grpc] listen is
+// set; always mutual TLS, each client certificate with a control access level.
+use crate::anomaly_events::AnomalyEvent as Anomaly;
+use crate::config::GrpcSection;
+use crate::control::AccessLevel;
+use crate::crypto_identifiers::{Capability, ProcessToken};
+use crate::daemon::{Control, SubsystemHealth};
+use crate::events::{EventKind, SecurityEvent};
+use crate::fleet::sha256_hex;
+use crate::memory_randomizer::{LayoutChangeEvent as LayoutChange, LayoutRestoreMode, LayoutTrigger};
+use crate::ml_detector::ScoringMode;
+use crate::randomization_policy::{RandomizationPolicy, RandomizationProfile};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("qks.v1");
+}
+
+use proto::qks_control_server::{QksControl, QksControlServer};
+
+#[derive(Debug, thiserror::Error)]
+pub enum GrpcError {
+    #[error("[grpc] listen is not set")]
+    NotConfigured,
+    #[error("[grpc] needs tls_cert, tls_key and client_ca")]
+    NoTls,
+    #[error("{}: {source}", .path.display())]
+    Pem { path: PathBuf, source: std::io::Error },
+    #[error("gRPC listener: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("gRPC TLS: {0}")]
+    Tls(#[from] tonic::transport::Error),
+}
+
+// Bind now so a taken port fails daemon startup rather than a later task
+pub(crate) fn serve(config: &GrpcSection, control: Arc<Control>) -> Result<tokio::task::JoinHandle<()>, GrpcError> {
+    let listen = config.listen.ok_or(GrpcError::NotConfigured)?;
+    let (Some(cert), Some(key), Some(ca)) = (&config.tls_cert, &config.tls_key, &config.client_ca) else {
+        return Err(GrpcError::NoTls);
+    };
+    let tls = ServerTlsConfig::new()
+        .identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?))
+        .client_ca_root(Certificate::from_pem(read_pem(ca)?));
+    let mut server = Server::builder().tls_config(tls)?;
+
+    let listener = std::net::TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+    let service = GrpcService { control, clients: config.clients.clone() };
+    let router = server.add_service(QksControlServer::new(service));
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = router.serve_with_incoming(incoming).await {
+            tracing::error!("gRPC server on {} stopped: {}", listen, e);
+        }
+    }))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, GrpcError> {
+    std::fs::read(path).map_err(|source| GrpcError::Pem { path: path.to_path_buf(), source })
+}
+
+struct GrpcService {
+    control: Arc<Control>,
+    // Client certificate digest -> access level
+    clients: BTreeMap<String, AccessLevel>,
+}
+
+impl GrpcService {
+    // The same levels as the control socket's requests (ControlRequest::required_level)
+    fn authorize<T>(&self, request: &Request<T>, required: AccessLevel) -> Result<(), Status> {
+        let certificates = request.peer_certs().ok_or_else(|| Status::unauthenticated("no client certificate"))?;
+        let certificate = certificates.first().ok_or_else(|| Status::unauthenticated("no client certificate"))?;
+        let digest = sha256_hex(certificate.get_ref());
+        match self.clients.get(&digest) {
+            Some(level) if *level >= required => Ok(()),
+            Some(level) => Err(Status::permission_denied(format!("needs {:?} access, client has {:?}", required, level).to_lowercase())),
+            None => Err(Status::permission_denied(format!("client certificate {} is not in [grpc] clients", digest))),
+        }
+    }
+}
+
+type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+// Snapshot, ptrace and procfs work stays off the async workers
+async fn blocking<T, F>(work: F) -> Result<Response<T>, Status>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await
+        .map_err(|e| Status::internal(format!("request task failed: {}", e)))?
+        .map(Response::new)
+}
+
+fn snapshot_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl QksControl for GrpcService {
+    type StreamAnomaliesStream = EventStream<proto::AnomalyEvent>;
+    type StreamLayoutChangesStream = EventStream<proto::LayoutChangeEvent>;
+
+    async fn health(&self, request: Request<proto::HealthRequest>) -> Result<Response<proto::HealthReply>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let mut subsystems: Vec<_> = self.control.health.iter().map(|e| {
+            let (state, reason) = match e.value() {
+                SubsystemHealth::Starting => ("starting", String::new()),
+                SubsystemHealth::Running => ("running", String::new()),
+                SubsystemHealth::Degraded { reason } => ("degraded", reason.clone()),
+                SubsystemHealth::Failed { reason } => ("failed", reason.clone()),
+                SubsystemHealth::Stopped => ("stopped", String::new()),
+            };
+            proto::SubsystemHealth { subsystem: e.key().to_string(), state: state.to_string(), reason }
+        }).collect();
+        subsystems.sort_by(|a, b| a.subsystem.cmp(&b.subsystem));
+        Ok(Response::new(proto::HealthReply { subsystems }))
+    }
+
+    async fn take_snapshot(&self, request: Request<proto::TakeSnapshotRequest>) -> Result<Response<proto::SnapshotSummary>, Status> {
+        self.authorize(&request, AccessLevel::Operate)?;
+        let control = self.control.clone();
+        blocking(move || {
+            let s = control.response.take_snapshot(None, None).map_err(snapshot_status)?;
+            Ok(proto::SnapshotSummary {
+                snapshot_id: s.snapshot_id,
+                timestamp: s.timestamp,
+                processes: s.processes as u64,
+                memory_layouts: s.memory_layouts as u64,
+                tag: s.tag.unwrap_or_default(),
+                note: s.note.unwrap_or_default(),
+            })
+        }).await
+    }
+
+    async fn list_snapshots(&self, request: Request<proto::ListSnapshotsRequest>) -> Result<Response<proto::ListSnapshotsReply>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let control = self.control.clone();
+        blocking(move || {
+            let snapshots = control.snapshots.list_snapshots().map_err(snapshot_status)?;
+            Ok(proto::ListSnapshotsReply {
+                snapshots: snapshots.into_iter().map(|s| proto::SnapshotSummary {
+                    snapshot_id: s.snapshot_id,
+                    timestamp: s.timestamp,
+                    processes: s.processes as u64,
+                    memory_layouts: s.memory_layouts as u64,
+                    tag: s.tag.unwrap_or_default(),
+                    note: s.note.unwrap_or_default(),
+                }).collect(),
+            })
+        }).await
+    }
+
+    async fn diff_snapshots(&self, request: Request<proto::DiffSnapshotsRequest>) -> Result<Response<proto::SnapshotDiff>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let control = self.control.clone();
+        let request = request.into_inner();
+        blocking(move || {
+            let diff = control.snapshots.diff_snapshots(&request.from, &request.to).map_err(snapshot_status)?;
+            Ok(proto::SnapshotDiff {
+                from: diff.from,
+                to: diff.to,
+                processes_added: diff.processes_added,
+                processes_removed: diff.processes_removed,
+                layouts_changed: diff.layouts_changed,
+                layouts_added: diff.layouts_added,
+                layouts_removed: diff.layouts_removed,
+                verity_changed: diff.verity_changed,
+            })
+        }).await
+    }
+
+    async fn restore_snapshot(&self, request: Request<proto::RestoreSnapshotRequest>) -> Result<Response<proto::RestoreSnapshotReply>, Status> {
+        self.authorize(&request, AccessLevel::Admin)?;
+        let control = self.control.clone();
+        let request = request.into_inner();
+        let mode = if request.fresh { LayoutRestoreMode::Fresh } else { LayoutRestoreMode::Recorded };
+        blocking(move || {
+            let restored = control.restore_snapshot(&request.snapshot_id, mode).map_err(snapshot_status)?;
+            Ok(proto::RestoreSnapshotReply { restored })
+        }).await
+    }
+
+    async fn verify_snapshot(&self, request: Request<proto::VerifySnapshotRequest>) -> Result<Response<proto::VerifySnapshotReply>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let control = self.control.clone();
+        let request = request.into_inner();
+        blocking(move || {
+            let valid = control.verify_snapshot(&request.snapshot_id).map_err(snapshot_status)?;
+            Ok(proto::VerifySnapshotReply { valid })
+        }).await
+    }
+
+    async fn issue_token(&self, request: Request<proto::IssueTokenRequest>) -> Result<Response<proto::ProcessToken>, Status> {
+        self.authorize(&request, AccessLevel::Admin)?;
+        let request = request.into_inner();
+        let capabilities = request.capabilities.into_iter().map(capability).collect::<Result<Vec<_>, _>>()?;
+        let token = self.control.issue_token(request.pid, &capabilities)
+            .map_err(|e| Status::internal(format!("token generation failed: {:?}", e)))?;
+        Ok(Response::new(process_token(&token)))
+    }
+
+    async fn verify_token(&self, request: Request<proto::ProcessToken>) -> Result<Response<proto::TokenStatus>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let token = token_from_proto(request.into_inner())?;
+        let status = self.control.identity.introspect(&token);
+        Ok(Response::new(proto::TokenStatus {
+            pid: status.pid,
+            valid: status.valid,
+            expires_at: status.expires_at,
+            failures: status.failures.into_iter().map(|f| proto::TokenCheckFailure {
+                check: format!("{:?}", f.check).to_lowercase(),
+                detail: f.detail,
+            }).collect(),
+        }))
+    }
+
+    async fn revoke_token(&self, request: Request<proto::ProcessToken>) -> Result<Response<proto::RevocationProof>, Status> {
+        self.authorize(&request, AccessLevel::Admin)?;
+        let token = token_from_proto(request.into_inner())?;
+        let proof = self.control.revoke_token(&token, "gRPC");
+        Ok(Response::new(proto::RevocationProof {
+            token_signature: proof.token_signature,
+            revoked_at: proof.revoked_at,
+            proof: proof.proof,
+        }))
+    }
+
+    async fn get_policy(&self, request: Request<proto::GetPolicyRequest>) -> Result<Response<proto::RandomizationPolicy>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let policy = self.control.randomizer.lock().unwrap().policy().clone();
+        Ok(Response::new(policy_to_proto(&policy)))
+    }
+
+    async fn set_policy(&self, request: Request<proto::RandomizationPolicy>) -> Result<Response<proto::RandomizationPolicy>, Status> {
+        self.authorize(&request, AccessLevel::Admin)?;
+        let request = request.into_inner();
+        let policy = RandomizationPolicy {
+            default: profile(request.default_profile)?,
+            binaries: request.binaries.into_iter()
+                .map(|(exe, p)| Ok((exe, profile(p)?)))
+                .collect::<Result<_, Status>>()?,
+        };
+        tracing::info!("Randomization policy replaced over gRPC ({} binary rules)", policy.binaries.len());
+        self.control.randomizer.lock().unwrap().set_policy(policy.clone());
+        Ok(Response::new(policy_to_proto(&policy)))
+    }
+
+    async fn profile_for(&self, request: Request<proto::ProfileForRequest>) -> Result<Response<proto::ProfileForReply>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let control = self.control.clone();
+        let pid = request.into_inner().pid;
+        blocking(move || {
+            let profile = control.randomizer.lock().unwrap().profile_for(pid);
+            Ok(proto::ProfileForReply { profile: profile_to_proto(profile) as i32 })
+        }).await
+    }
+
+    async fn stream_anomalies(&self, request: Request<proto::StreamAnomaliesRequest>) -> Result<Response<Self::StreamAnomaliesStream>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let filter = request.into_inner();
+        let anomalies = self.control.events.subscribe(EventKind::Anomaly);
+        let events = BroadcastStream::new(anomalies).filter_map(move |event| match event {
+            Ok(SecurityEvent::Anomaly(event)) if event.score >= filter.min_score && (filter.pid == 0 || event.pid == filter.pid) => {
+                Some(Ok(anomaly_event(&event)))
+            }
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                tracing::warn!("gRPC anomaly subscriber fell behind; {} events dropped", missed);
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn stream_layout_changes(&self, request: Request<proto::StreamLayoutChangesRequest>) -> Result<Response<Self::StreamLayoutChangesStream>, Status> {
+        self.authorize(&request, AccessLevel::Read)?;
+        let pid = request.into_inner().pid;
+        let layouts = self.control.events.subscribe(EventKind::Layout);
+        let events = BroadcastStream::new(layouts).filter_map(move |event| match event {
+            Ok(SecurityEvent::Layout(event)) if pid == 0 || event.pid == pid => Some(Ok(layout_event(&event))),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                tracing::warn!("gRPC layout subscriber fell behind; {} events dropped", missed);
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+fn capability(capability: proto::Capability) -> Result<Capability, Status> {
+    use proto::capability::Kind;
+    match capability.kind {
+        Some(Kind::NetworkAccess(_)) => Ok(Capability::NetworkAccess),
+        Some(Kind::FilesystemAccess(path)) => Ok(Capability::FilesystemAccess(path)),
+        Some(Kind::Syscall(nr)) => Ok(Capability::Syscall(nr)),
+        Some(Kind::MemoryAllocation(bytes)) => Ok(Capability::MemoryAllocation(bytes)),
+        None => Err(Status::invalid_argument("capability without a kind")),
+    }
+}
+
+fn capability_to_proto(capability: &Capability) -> proto::Capability {
+    use proto::capability::Kind;
+    let kind = match capability {
+        Capability::NetworkAccess => Kind::NetworkAccess(true),
+        Capability::FilesystemAccess(path) => Kind::FilesystemAccess(path.clone()),
+        Capability::Syscall(nr) => Kind::Syscall(*nr),
+        Capability::MemoryAllocation(bytes) => Kind::MemoryAllocation(*bytes),
+    };
+    proto::Capability { kind: Some(kind) }
+}
+
+fn process_token(token: &ProcessToken) -> proto::ProcessToken {
+    proto::ProcessToken {
+        pid: token.pid,
+        parent_token: token.parent_token.clone(),
+        signature: token.signature.clone(),
+        timestamp: token.timestamp,
+        capabilities: token.capabilities.iter().map(capability_to_proto).collect(),
+        nonce: token.nonce.to_vec(),
+    }
+}
+
+fn token_from_proto(token: proto::ProcessToken) -> Result<ProcessToken, Status> {
+    let nonce = token.nonce.as_slice().try_into()
+        .map_err(|_| Status::invalid_argument(format!("token nonce is {} bytes, expected 16", token.nonce.len())))?;
+    Ok(ProcessToken {
+        pid: token.pid,
+        parent_token: token.parent_token,
+        signature: token.signature,
+        timestamp: token.timestamp,
+        capabilities: token.capabilities.into_iter().map(capability).collect::<Result<_, _>>()?,
+        nonce,
+    })
+}
+
+fn profile(value: i32) -> Result<RandomizationProfile, Status> {
+    match proto::RandomizationProfile::try_from(value) {
+        Ok(proto::RandomizationProfile::Full) => Ok(RandomizationProfile::Full),
+        Ok(proto::RandomizationProfile::Partial) => Ok(RandomizationProfile::Partial),
+        Ok(proto::RandomizationProfile::VdsoOnly) => Ok(RandomizationProfile::VdsoOnly),
+        Ok(proto::RandomizationProfile::Excluded) => Ok(RandomizationProfile::Excluded),
+        _ => Err(Status::invalid_argument(format!("unknown randomization profile {}", value))),
+    }
+}
+
+fn profile_to_proto(profile: RandomizationProfile) -> proto::RandomizationProfile {
+    match profile {
+        RandomizationProfile::Full => proto::RandomizationProfile::Full,
+        RandomizationProfile::Partial => proto::RandomizationProfile::Partial,
+        RandomizationProfile::VdsoOnly => proto::RandomizationProfile::VdsoOnly,
+        RandomizationProfile::Excluded => proto::RandomizationProfile::Excluded,
+    }
+}
+
+fn policy_to_proto(policy: &RandomizationPolicy) -> proto::RandomizationPolicy {
+    proto::RandomizationPolicy {
+        default_profile: profile_to_proto(policy.default) as i32,
+        binaries: policy.binaries.iter().map(|(exe, p)| (exe.clone(), profile_to_proto(*p) as i32)).collect(),
+    }
+}
+
+fn anomaly_event(event: &Anomaly) -> proto::AnomalyEvent {
+    proto::AnomalyEvent {
+        pid: event.pid,
+        exe: event.exe.clone(),
+        score: event.score,
+        threshold: event.threshold,
+        mode: match event.mode {
+            ScoringMode::Baseline => "baseline",
+            ScoringMode::Model => "model",
+            ScoringMode::Profile => "profile",
+        }.to_string(),
+        model_version: event.model_version,
+        timestamp: event.timestamp,
+        contributions: event.explanation.iter().flat_map(|e| &e.contributions).map(|c| proto::FeatureContribution {
+            feature: c.feature.to_string(),
+            value: c.value,
+            expected: c.expected,
+            share: c.share,
+        }).collect(),
+        process_tree: event.process_tree.iter().map(|a| proto::ProcessAncestor {
+            pid: a.pid,
+            comm: a.comm.clone(),
+            exe: a.exe.clone(),
+        }).collect(),
+        capabilities: event.capabilities.as_ref().map(|caps| proto::TokenCapabilities {
+            capabilities: caps.iter().map(capability_to_proto).collect(),
+        }),
+        recent_syscalls: event.recent_syscalls.clone(),
+        layout_hash: event.layout_hash.map(|hash| hash.to_vec()),
+    }
+}
+
+fn layout_event(event: &LayoutChange) -> proto::LayoutChangeEvent {
+    proto::LayoutChangeEvent {
+        pid: event.pid,
+        trigger: match event.trigger {
+            LayoutTrigger::Initial => "initial",
+            LayoutTrigger::Regeneration => "regeneration",
+            LayoutTrigger::Scheduled => "scheduled",
+            LayoutTrigger::SnapshotRestore => "snapshot-restore",
+            LayoutTrigger::Applied => "applied",
+        }.to_string(),
+        regions: event.regions.iter().map(|r| r.to_string()).collect(),
+        regeneration_count: event.regeneration_count,
+        layout_hash: event.layout_hash.to_vec(),
+        timestamp: event.timestamp,
+    }
+}
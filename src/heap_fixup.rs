@@ -0,0 +1,98 @@
+// src/heap_fixup.rs
+// Pointer fixups for heap rebases. Moving the heap is only safe when every
+// absolute pointer into it can be found, so it is limited to processes whose
+// allocator cooperates by publishing a relocation table:
+//
+//   u64 magic ("QKSRELOC"), u64 count, then `count` u64 slot addresses
+//
+// Each slot is a word (anywhere in the process) that may hold a heap pointer.
+use crate::privsep::{self, ProcFile};
+use crate::remap_engine::{PointerPatch, RegionKind, RegionMove};
+use dashmap::DashMap;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+const TABLE_MAGIC: u64 = u64::from_le_bytes(*b"QKSRELOC");
+// Sanity bound on what a (possibly corrupted) table may claim
+const MAX_TABLE_SLOTS: u64 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FixupError {
+    #[error("PID {0} has no registered relocation table; heap move refused")]
+    NotRegistered(u32),
+    #[error("cannot read relocation table of PID {0}: {1}")]
+    Memory(u32, io::Error),
+    #[error("relocation table of PID {pid} at {addr:#x} is invalid: {reason}")]
+    InvalidTable { pid: u32, addr: u64, reason: String },
+}
+
+#[derive(Clone, Default)]
+pub struct HeapFixups {
+    tables: Arc<DashMap<u32, u64>>,
+}
+
+impl HeapFixups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, pid: u32, table_addr: u64) {
+        self.tables.insert(pid, table_addr);
+    }
+
+    pub fn unregister(&self, pid: u32) {
+        self.tables.remove(&pid);
+    }
+
+    pub fn is_registered(&self, pid: u32) -> bool {
+        self.tables.contains_key(&pid)
+    }
+
+    // Patches (at post-move addresses) for every slot pointing into the
+    // heap being moved. Read while the target is stopped, before the move.
+    pub fn patches_for(&self, pid: u32, heap: &RegionMove) -> Result<Vec<PointerPatch>, FixupError> {
+        debug_assert_eq!(heap.kind, RegionKind::Heap);
+        let table = *self.tables.get(&pid).ok_or(FixupError::NotRegistered(pid))?;
+
+        let mem = privsep::open_proc(pid, ProcFile::Mem).map_err(|e| FixupError::Memory(pid, e))?;
+        let read = |addr: u64| -> Result<u64, FixupError> {
+            let mut word = [0u8; 8];
+            mem.read_exact_at(&mut word, addr).map_err(|e| FixupError::Memory(pid, e))?;
+            Ok(u64::from_ne_bytes(word))
+        };
+        let invalid = |reason: &str| FixupError::InvalidTable { pid, addr: table, reason: reason.to_string() };
+
+        if read(table)? != TABLE_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let count = read(table + 8)?;
+        if count > MAX_TABLE_SLOTS {
+            return Err(invalid("too many slots"));
+        }
+
+        let old_end = heap.old_start + heap.len;
+        let delta = heap.new_start.wrapping_sub(heap.old_start);
+        let in_heap = |addr: u64| addr >= heap.old_start && addr < old_end;
+
+        let mut patches = Vec::new();
+        for i in 0..count {
+            let slot = read(table + 16 + i * 8)?;
+            if slot % 8 != 0 {
+                return Err(invalid("unaligned slot"));
+            }
+
+            let value = read(slot)?;
+            // One past the end is a valid C pointer too
+            if !(in_heap(value) || value == old_end) {
+                continue;
+            }
+
+            // Slots that live in the heap move along with it
+            let addr = if in_heap(slot) { slot.wrapping_add(delta) } else { slot };
+            patches.push(PointerPatch { addr, old_value: value, new_value: value.wrapping_add(delta) });
+        }
+
+        Ok(patches)
+    }
+}
@@ -0,0 +1,219 @@
+// src/host_posture.rs
+// The boot-time protections everything else leans on: EFI Secure Boot,
+// kernel lockdown and module signature enforcement, plus the kernel taint
+// flags that record when something got past them. None of these can be
+// weakened without a reboot, so the last posture seen is kept in the state
+// directory and a check compares against it across restarts too. Any step
+// down (Secure Boot off, a lower lockdown mode, unsigned modules loadable)
+// is a critical finding; new taint flags are a warning.
+use crate::events::{EventBus, IntegrityEvent, SecurityEvent};
+use crate::metrics;
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const EFI_DIR: &str = "/sys/firmware/efi";
+// EFI global variables: 4 bytes of attributes, then the value
+const SECURE_BOOT_VAR: &str = "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+const SETUP_MODE_VAR: &str = "/sys/firmware/efi/efivars/SetupMode-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+const LOCKDOWN: &str = "/sys/kernel/security/lockdown";
+const SIG_ENFORCE: &str = "/sys/module/module/parameters/sig_enforce";
+const TAINTED: &str = "/proc/sys/kernel/tainted";
+// Taint bits, in the order the kernel prints them as letters
+const TAINT_FLAGS: &[u8] = b"PFSRMBUDAWCIOELKXTN";
+
+// Weakest first, so a lower value is a degradation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecureBoot {
+    // Legacy BIOS boot
+    Unsupported,
+    Disabled,
+    // Firmware has no platform key: nothing is actually verified
+    SetupMode,
+    Enabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lockdown {
+    // Kernel built without the lockdown LSM
+    Unsupported,
+    None,
+    Integrity,
+    Confidentiality,
+}
+
+impl SecureBoot {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecureBoot::Unsupported => "unsupported",
+            SecureBoot::Disabled => "disabled",
+            SecureBoot::SetupMode => "setup_mode",
+            SecureBoot::Enabled => "enabled",
+        }
+    }
+}
+
+impl Lockdown {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Lockdown::Unsupported => "unsupported",
+            Lockdown::None => "none",
+            Lockdown::Integrity => "integrity",
+            Lockdown::Confidentiality => "confidentiality",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Posture {
+    pub secure_boot: SecureBoot,
+    pub lockdown: Lockdown,
+    // module.sig_enforce; None without CONFIG_MODULE_SIG
+    pub module_sig_enforce: Option<bool>,
+    // Whether an unsigned module would be refused: sig_enforce, or lockdown
+    // (which refuses them regardless)
+    pub modules_must_be_signed: bool,
+    pub tainted: u64,
+    pub checked_at: u64,
+}
+
+impl Posture {
+    pub fn read() -> Self {
+        let efi_var = |path: &str| std::fs::read(path).ok().and_then(|bytes| bytes.get(4).copied());
+        let secure_boot = if !Path::new(EFI_DIR).exists() {
+            SecureBoot::Unsupported
+        } else if efi_var(SECURE_BOOT_VAR) != Some(1) {
+            SecureBoot::Disabled
+        } else if efi_var(SETUP_MODE_VAR) == Some(1) {
+            SecureBoot::SetupMode
+        } else {
+            SecureBoot::Enabled
+        };
+
+        // "none [integrity] confidentiality"
+        let lockdown = match std::fs::read_to_string(LOCKDOWN) {
+            Ok(modes) => match modes.split_whitespace().find(|mode| mode.starts_with('[')).map(|mode| mode.trim_matches(['[', ']'])) {
+                Some("integrity") => Lockdown::Integrity,
+                Some("confidentiality") => Lockdown::Confidentiality,
+                _ => Lockdown::None,
+            },
+            Err(_) => Lockdown::Unsupported,
+        };
+        let module_sig_enforce = std::fs::read_to_string(SIG_ENFORCE).ok().map(|value| value.trim() == "Y");
+        let tainted = std::fs::read_to_string(TAINTED).ok().and_then(|value| value.trim().parse().ok()).unwrap_or(0);
+        Self {
+            secure_boot,
+            lockdown,
+            module_sig_enforce,
+            modules_must_be_signed: module_sig_enforce == Some(true) || (module_sig_enforce.is_some() && lockdown >= Lockdown::Integrity),
+            tainted,
+            checked_at: systemd::now_secs(),
+        }
+    }
+
+    // The taint bits as the kernel's letters, e.g. "OE"
+    pub fn taint_flags(bits: u64) -> String {
+        TAINT_FLAGS.iter().enumerate().filter(|(bit, _)| bits & (1 << bit) != 0).map(|(_, flag)| *flag as char).collect()
+    }
+
+    // (finding, critical, detail) for each way this is weaker than before
+    fn degradations(&self, before: &Posture) -> Vec<(&'static str, bool, String)> {
+        let mut found = Vec::new();
+        if self.secure_boot < before.secure_boot {
+            found.push(("secure_boot_degraded", true, format!("Secure Boot {} (was {})", self.secure_boot.as_str(), before.secure_boot.as_str())));
+        }
+        if self.lockdown < before.lockdown {
+            found.push(("lockdown_degraded", true, format!("lockdown {} (was {})", self.lockdown.as_str(), before.lockdown.as_str())));
+        }
+        if before.modules_must_be_signed && !self.modules_must_be_signed {
+            found.push(("module_signing_degraded", true, "unsigned kernel modules can be loaded".to_string()));
+        }
+        let new_taint = self.tainted & !before.tainted;
+        if new_taint != 0 {
+            found.push(("kernel_tainted", false, format!("new taint flags {} (now {})", Self::taint_flags(new_taint), Self::taint_flags(self.tainted))));
+        }
+        found
+    }
+}
+
+pub struct PostureMonitor {
+    interval_secs: u64,
+    // Where the last posture is kept across restarts
+    state_path: PathBuf,
+    last: Mutex<Option<Posture>>,
+}
+
+impl PostureMonitor {
+    pub fn new(interval_secs: u64, state_path: PathBuf) -> Self {
+        // No record (first run, or unreadable) means nothing to compare with
+        let last = std::fs::read(&state_path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok());
+        Self { interval_secs, state_path, last: Mutex::new(last) }
+    }
+
+    // As of the last check
+    pub fn posture(&self) -> Option<Posture> {
+        self.last.lock().unwrap().clone()
+    }
+
+    // Reads the posture, records it and returns how it got weaker
+    pub fn check(&self) -> Vec<IntegrityEvent> {
+        let current = Posture::read();
+        let mut last = self.last.lock().unwrap();
+        let mut findings = Vec::new();
+        for (finding, critical, detail) in last.as_ref().map(|before| current.degradations(before)).unwrap_or_default() {
+            if critical {
+                tracing::error!("Host posture degraded: {}", detail);
+            } else {
+                tracing::warn!("Host posture: {}", detail);
+            }
+            metrics::global().incr("qks_integrity_findings_total", &[("source", "posture"), ("finding", finding)]);
+            findings.push(IntegrityEvent {
+                source: "posture".to_string(),
+                finding: finding.to_string(),
+                critical,
+                path: None,
+                pid: None,
+                expected: None,
+                actual: None,
+                detail: Some(detail),
+                timestamp: current.checked_at,
+            });
+        }
+        if last.as_ref().map_or(true, |before| before.secure_boot != current.secure_boot || before.lockdown != current.lockdown || before.modules_must_be_signed != current.modules_must_be_signed) {
+            tracing::info!(
+                "Host posture: Secure Boot {}, lockdown {}, unsigned modules {}",
+                current.secure_boot.as_str(),
+                current.lockdown.as_str(),
+                if current.modules_must_be_signed { "refused" } else { "allowed" },
+            );
+        }
+        let saved = serde_json::to_vec(&current).map_err(std::io::Error::from).and_then(|bytes| std::fs::write(&self.state_path, bytes));
+        if let Err(e) = saved {
+            tracing::warn!("Host posture not recorded in {}: {}", self.state_path.display(), e);
+        }
+        *last = Some(current);
+        findings
+    }
+
+    // Checks at once, then every interval_secs
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.interval_secs));
+            loop {
+                ticker.tick().await;
+                let monitor = self.clone();
+                match tokio::task::spawn_blocking(move || monitor.check()).await {
+                    Ok(findings) => {
+                        for finding in findings {
+                            bus.publish(SecurityEvent::Integrity(finding));
+                        }
+                    }
+                    Err(e) => tracing::error!("Host posture check panicked: {}", e),
+                }
+            }
+        })
+    }
+}
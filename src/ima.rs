@@ -0,0 +1,408 @@
+// src/ima.rs
+// Brings the kernel's Integrity Measurement Architecture into the event
+// model. IMA hashes files as its policy says (with ima_policy=tcb: every
+// executable, mmapped library and file root reads) and appends each to the
+// runtime measurement list; a file is measured again whenever it changes.
+// The list is read incrementally and every new measurement compared with a
+// baseline manifest in sha256sum format ("<hex>  <path>", as
+// `sha256sum /usr/bin/* > baseline` writes it; a path may be listed more
+// than once to allow several versions). A digest the baseline doesn't allow
+// is a critical hash_mismatch; a file under the watched paths the baseline
+// doesn't know is an unknown_file; a measurement the kernel could not take
+// reliably (the file was open for writing) is a violation. The list doesn't
+// say which process caused a measurement, so findings carry none.
+//
+// Appraisal and EVM are reported as status: the ima_appraise mode, how many
+// appraise rules the policy has, and the EVM initialization bits. With
+// require_appraisal, appraisal not enforcing or EVM not initialized is a
+// finding of its own, raised when it appears and again if it comes back.
+use crate::config::ImaSection;
+use crate::events::{EventBus, IntegrityEvent, SecurityEvent};
+use crate::metrics;
+use crate::systemd;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Both places securityfs has kept IMA and EVM
+const IMA_DIRS: [&str; 2] = ["ima", "integrity/ima"];
+const EVM_FILES: [&str; 2] = ["evm", "integrity/evm/evm"];
+const MEASUREMENTS: &str = "ascii_runtime_measurements";
+
+// Bits of securityfs/evm
+const EVM_INIT_HMAC: u32 = 0x1;
+const EVM_INIT_X509: u32 = 0x2;
+const EVM_SETUP_COMPLETE: u32 = 0x8000_0000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImaError {
+    #[error("IMA is not active: no measurement list under {} (boot with ima_policy=tcb or load a policy)", .0.display())]
+    Unavailable(PathBuf),
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("{}:{line}: {reason}", .path.display())]
+    Baseline { path: PathBuf, line: usize, reason: String },
+}
+
+// One entry of the runtime measurement list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Measurement {
+    pub pcr: u32,
+    pub template: String,
+    // algorithm:hex; the old `ima` template's bare SHA-1 gets its prefix
+    pub digest: String,
+    pub path: String,
+    // ima-sig entries carrying a file signature
+    pub signed: bool,
+    // The kernel couldn't measure reliably and recorded zeros instead
+    pub violation: bool,
+}
+
+// `10 <template hash> ima-ng sha256:<hex> /usr/bin/ls`, and for ima-sig an
+// optional signature after the path
+pub fn parse_measurement(line: &str) -> Option<Measurement> {
+    let mut fields = line.splitn(5, ' ');
+    let pcr = fields.next()?.parse().ok()?;
+    let template_hash = fields.next()?;
+    let template = fields.next()?.to_string();
+    let digest = fields.next()?;
+    let mut path = fields.next()?.trim_end();
+    let mut signed = false;
+    if template == "ima-sig" {
+        // Signatures are hex and start with their type: 03 for v2
+        if let Some((rest, signature)) = path.rsplit_once(' ') {
+            if signature.starts_with("03") && signature.bytes().all(|b| b.is_ascii_hexdigit()) {
+                path = rest;
+                signed = true;
+            }
+        }
+    }
+    let digest = if digest.contains(':') { digest.to_string() } else { format!("sha1:{}", digest) };
+    Some(Measurement {
+        pcr,
+        template,
+        digest: digest.to_ascii_lowercase(),
+        path: path.to_string(),
+        signed,
+        violation: template_hash.bytes().all(|b| b == b'0'),
+    })
+}
+
+fn algorithm_for(hex: &str) -> Option<&'static str> {
+    match hex.len() {
+        40 => Some("sha1"),
+        64 => Some("sha256"),
+        96 => Some("sha384"),
+        128 => Some("sha512"),
+        _ => None,
+    }
+}
+
+// Expected digests by path, reloaded when the file changes
+struct Baseline {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    digests: HashMap<String, Vec<String>>,
+}
+
+impl Baseline {
+    fn load(path: &Path) -> Result<Self, ImaError> {
+        let io = |source| ImaError::Io { path: path.to_path_buf(), source };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).map_err(io)?;
+        let file = File::open(path).map_err(io)?;
+        let mut digests: HashMap<String, Vec<String>> = HashMap::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(io)?;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |reason: &str| ImaError::Baseline { path: path.to_path_buf(), line: i + 1, reason: reason.to_string() };
+            // "<hex>  <path>", or "<hex> *<path>" for binary mode
+            let (digest, file) = line.split_once(' ').ok_or_else(|| bad("expected \"<digest>  <path>\""))?;
+            let file = file.strip_prefix([' ', '*']).unwrap_or(file);
+            let digest = digest.to_ascii_lowercase();
+            let (algorithm, hex) = match digest.split_once(':') {
+                Some((algorithm, hex)) => (algorithm.to_string(), hex),
+                None => (algorithm_for(&digest).ok_or_else(|| bad("digest length matches no known hash"))?.to_string(), digest.as_str()),
+            };
+            if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(bad("digest is not hex"));
+            }
+            if !file.starts_with('/') {
+                return Err(bad("paths must be absolute"));
+            }
+            digests.entry(file.to_string()).or_default().push(format!("{}:{}", algorithm, hex));
+        }
+        Ok(Self { path: path.to_path_buf(), modified: Some(modified), digests })
+    }
+
+    fn changed(&self) -> bool {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok() != self.modified
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EvmStatus {
+    // The raw securityfs value; None when EVM isn't built in
+    pub raw: Option<u32>,
+    pub hmac: bool,
+    pub x509: bool,
+    pub setup_complete: bool,
+}
+
+impl EvmStatus {
+    pub fn initialized(&self) -> bool {
+        self.hmac || self.x509
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImaStatus {
+    // ima_policy= from the kernel command line
+    pub boot_policy: Option<String>,
+    // enforce, fix, log or off
+    pub appraise: String,
+    // None when the kernel doesn't let the policy be read back
+    pub appraise_rules: Option<usize>,
+    pub evm: EvmStatus,
+    // The kernel's own counters
+    pub measurements: u64,
+    pub violations: u64,
+    pub template: Option<String>,
+    pub baseline: Option<PathBuf>,
+    pub baseline_entries: usize,
+    // Measurements compared so far, and what they turned up
+    pub checked: u64,
+    pub mismatches: u64,
+    pub unknown: u64,
+    // Measured with an algorithm the baseline has no digest in
+    pub incomparable: u64,
+}
+
+struct State {
+    offset: u64,
+    baseline: Option<Baseline>,
+    // (path, digest) pairs already reported
+    flagged: HashSet<(String, String)>,
+    // Status findings standing since they were reported
+    standing: HashSet<&'static str>,
+    status: ImaStatus,
+}
+
+pub struct ImaMonitor {
+    section: ImaSection,
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+impl ImaMonitor {
+    pub fn new(section: &ImaSection) -> Result<Self, ImaError> {
+        let dir = IMA_DIRS
+            .iter()
+            .map(|dir| section.securityfs.join(dir))
+            .find(|dir| dir.join(MEASUREMENTS).exists())
+            .ok_or_else(|| ImaError::Unavailable(section.securityfs.clone()))?;
+        let baseline = section.baseline.as_deref().map(Baseline::load).transpose()?;
+        let status = ImaStatus {
+            baseline: section.baseline.clone(),
+            baseline_entries: baseline.as_ref().map_or(0, |b| b.digests.len()),
+            ..ImaStatus::default()
+        };
+        let state = State { offset: 0, baseline, flagged: HashSet::new(), standing: HashSet::new(), status };
+        Ok(Self { section: section.clone(), dir, state: Mutex::new(state) })
+    }
+
+    pub fn status(&self) -> ImaStatus {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    // Reads what was measured since the last call and refreshes the status;
+    // returns the findings
+    pub fn poll(&self) -> Result<Vec<IntegrityEvent>, ImaError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.baseline.as_ref().is_some_and(Baseline::changed) {
+            // A baseline that no longer parses keeps the old one in force
+            match self.section.baseline.as_deref().map(Baseline::load).transpose() {
+                Ok(baseline) => {
+                    tracing::info!("IMA baseline reloaded");
+                    state.status.baseline_entries = baseline.as_ref().map_or(0, |b| b.digests.len());
+                    state.baseline = baseline;
+                    state.flagged.clear();
+                }
+                Err(e) => {
+                    tracing::error!("IMA baseline not reloaded: {}", e);
+                    if let Some(baseline) = &mut state.baseline {
+                        baseline.modified = std::fs::metadata(&baseline.path).and_then(|m| m.modified()).ok();
+                    }
+                }
+            }
+        }
+
+        let mut findings = self.refresh_status(state);
+        let path = self.dir.join(MEASUREMENTS);
+        let io = |source| ImaError::Io { path: path.clone(), source };
+        let mut file = File::open(&path).map_err(io)?;
+        file.seek(SeekFrom::Start(state.offset)).map_err(io)?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(io)?;
+            // A line still being written is read whole next time
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            state.offset += read as u64;
+            let Some(measurement) = parse_measurement(line.trim_end_matches('\n')) else {
+                tracing::debug!("Unparsed IMA measurement: {}", line.trim_end());
+                continue;
+            };
+            state.status.template = Some(measurement.template.clone());
+            state.status.checked += 1;
+            metrics::global().incr("qks_ima_measurements_total", &[("template", measurement.template.as_str())]);
+            if let Some(finding) = self.check(state, &measurement) {
+                metrics::global().incr("qks_integrity_findings_total", &[("source", "ima"), ("finding", finding.finding.as_str())]);
+                findings.push(finding);
+            }
+        }
+        Ok(findings)
+    }
+
+    fn check(&self, state: &mut State, measurement: &Measurement) -> Option<IntegrityEvent> {
+        // boot_aggregate is the PCR 0-9 digest, not a file
+        if measurement.path == "boot_aggregate" {
+            return None;
+        }
+        let key = (measurement.path.clone(), measurement.digest.clone());
+        if state.flagged.contains(&key) {
+            return None;
+        }
+        let finding = |finding: &str, critical: bool, expected: Option<String>, detail: Option<String>| IntegrityEvent {
+            source: "ima".to_string(),
+            finding: finding.to_string(),
+            critical,
+            path: Some(measurement.path.clone()),
+            pid: None,
+            expected,
+            actual: (!measurement.violation).then(|| measurement.digest.clone()),
+            detail,
+            timestamp: systemd::now_secs(),
+        };
+
+        let event = if measurement.violation {
+            finding("violation", false, None, Some("measured while open for writing; the recorded digest is void".to_string()))
+        } else {
+            let baseline = state.baseline.as_ref()?;
+            let algorithm = measurement.digest.split(':').next().unwrap_or_default();
+            match baseline.digests.get(&measurement.path) {
+                Some(allowed) if allowed.contains(&measurement.digest) => return None,
+                Some(allowed) => {
+                    let expected: Vec<&String> = allowed.iter().filter(|d| d.split(':').next() == Some(algorithm)).collect();
+                    if expected.is_empty() {
+                        state.status.incomparable += 1;
+                        return None;
+                    }
+                    state.status.mismatches += 1;
+                    let expected = expected.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(",");
+                    finding("hash_mismatch", true, Some(expected), None)
+                }
+                None if self.section.flag_unknown && self.section.watch_paths.iter().any(|dir| Path::new(&measurement.path).starts_with(dir)) => {
+                    state.status.unknown += 1;
+                    finding("unknown_file", false, None, Some("not in the baseline".to_string()))
+                }
+                None => return None,
+            }
+        };
+        state.flagged.insert(key);
+        tracing::warn!("IMA {} for {} ({})", event.finding, measurement.path, measurement.digest);
+        Some(event)
+    }
+
+    // Appraisal, EVM and the kernel's counters; with require_appraisal, a
+    // finding for each weakness as it appears
+    fn refresh_status(&self, state: &mut State) -> Vec<IntegrityEvent> {
+        let read = |path: PathBuf| std::fs::read_to_string(path).ok();
+        let counter = |name: &str| read(self.dir.join(name)).and_then(|s| s.trim().parse().ok()).unwrap_or(0);
+        let cmdline = read(PathBuf::from("/proc/cmdline")).unwrap_or_default();
+        let option = |name: &str| cmdline.split_whitespace().find_map(|arg| arg.strip_prefix(name)?.strip_prefix('=').map(str::to_string));
+
+        let status = &mut state.status;
+        status.measurements = counter("runtime_measurements_count");
+        status.violations = counter("violations");
+        status.boot_policy = option("ima_policy");
+        // Only readable with CONFIG_IMA_READ_POLICY
+        status.appraise_rules = read(self.dir.join("policy")).map(|policy| policy.lines().filter(|rule| rule.trim_start().starts_with("appraise")).count());
+        let tcb_appraisal = status.boot_policy.as_deref().is_some_and(|policy| policy.contains("appraise_tcb")) || cmdline.split_whitespace().any(|arg| arg == "ima_appraise_tcb");
+        status.appraise = match option("ima_appraise") {
+            Some(mode) => mode,
+            None if tcb_appraisal || status.appraise_rules.is_some_and(|rules| rules > 0) => "enforce".to_string(),
+            None => "off".to_string(),
+        };
+        let raw = EVM_FILES.iter().find_map(|file| read(self.section.securityfs.join(file))).and_then(|s| s.trim().parse::<u32>().ok());
+        status.evm = EvmStatus {
+            raw,
+            hmac: raw.is_some_and(|bits| bits & EVM_INIT_HMAC != 0),
+            x509: raw.is_some_and(|bits| bits & EVM_INIT_X509 != 0),
+            setup_complete: raw.is_some_and(|bits| bits & EVM_SETUP_COMPLETE != 0),
+        };
+
+        if !self.section.require_appraisal {
+            return Vec::new();
+        }
+        let weaknesses = [
+            ("appraisal_not_enforced", status.appraise != "enforce", format!("ima_appraise is {}", status.appraise)),
+            ("evm_not_initialized", !status.evm.initialized(), "no EVM key loaded; file metadata is not protected".to_string()),
+        ];
+        let mut findings = Vec::new();
+        for (finding, present, detail) in weaknesses {
+            if !present {
+                state.standing.remove(finding);
+                continue;
+            }
+            if !state.standing.insert(finding) {
+                continue;
+            }
+            tracing::error!("IMA {}: {}", finding.replace('_', " "), detail);
+            metrics::global().incr("qks_integrity_findings_total", &[("source", "ima"), ("finding", finding)]);
+            findings.push(IntegrityEvent {
+                source: "ima".to_string(),
+                finding: finding.to_string(),
+                critical: true,
+                path: None,
+                pid: None,
+                expected: None,
+                actual: None,
+                detail: Some(detail),
+                timestamp: systemd::now_secs(),
+            });
+        }
+        findings
+    }
+
+    // Polls every interval_secs and publishes what it finds
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.section.interval_secs));
+            loop {
+                ticker.tick().await;
+                // Reading the list is file I/O; keep it off the async workers
+                let monitor = self.clone();
+                match tokio::task::spawn_blocking(move || monitor.poll()).await {
+                    Ok(Ok(findings)) => {
+                        for finding in findings {
+                            bus.publish(SecurityEvent::Integrity(finding));
+                        }
+                    }
+                    Ok(Err(e)) => tracing::warn!("IMA measurements not read: {}", e),
+                    Err(e) => tracing::error!("IMA poll panicked: {}", e),
+                }
+            }
+        })
+    }
+}
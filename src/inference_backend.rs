@@ -0,0 +1,522 @@
+// src/inference_backend.rs
+// Model runtimes behind one interface. TensorFlow needs the full C library
+// on the host; the ONNX backend (tract, pure Rust) avoids that. Pick with
+// cargo features `tensorflow-backend` / `onnx`; with both enabled the model
+// file decides (*.onnx goes to tract).
+use crate::metrics;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Consecutive over-budget calls before inference is shed
+const BUDGET_STRIKES: u32 = 3;
+// How long to shed before trying the model again
+const BUDGET_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum InferenceError {
+    #[error("no inference backend compiled in for {0}")]
+    NoBackend(String),
+    #[error("model error: {0}")]
+    Model(String),
+    #[error("untrusted model: {0}")]
+    Untrusted(String),
+    #[error("inference shed: {0}")]
+    OverBudget(String),
+    #[error("inference failed: {0}")]
+    Inference(String),
+    #[error("feature schema mismatch: scaler has {scaler} features, model expects {model}")]
+    Schema { scaler: usize, model: usize },
+    #[error("feature schema mismatch: {0}")]
+    FeatureNames(String),
+}
+
+#[cfg(feature = "tensorflow-backend")]
+impl From<tensorflow::Status> for InferenceError {
+    fn from(status: tensorflow::Status) -> Self {
+        InferenceError::Inference(status.to_string())
+    }
+}
+
+// Models take [N, F] float rows and produce an anomaly score plus the
+// reconstruction of each row
+pub trait InferenceBackend: Send {
+    fn infer(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), InferenceError>;
+
+    // Rows share one width; backends that can should run them as a single
+    // [N, F] tensor
+    fn infer_batch(&mut self, rows: &[Vec<f32>]) -> Result<Vec<(f32, Vec<f32>)>, InferenceError> {
+        rows.iter().map(|row| self.infer(row)).collect()
+    }
+
+    fn name(&self) -> &'static str;
+
+    // Feature width the model was exported with, when the graph pins it
+    fn input_width(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Cpu,
+    // CUDA device index; TensorFlow builds with GPU support only
+    Gpu(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Float32,
+    // int8-quantized ONNX export (model.int8.onnx beside the float model);
+    // roughly a quarter of the memory, for small hosts
+    Int8,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct InferenceOptions {
+    pub device: Device,
+    pub precision: Precision,
+    // None leaves the runtime default (usually one thread per core)
+    pub cpu_threads: Option<usize>,
+    // Per-row wall-clock budget; repeated overruns shed inference for a
+    // cooldown instead of letting detection eat the host
+    pub latency_budget: Option<Duration>,
+}
+
+impl Default for InferenceOptions {
+    fn default() -> Self {
+        Self { device: Device::Cpu, precision: Precision::Float32, cpu_threads: None, latency_budget: None }
+    }
+}
+
+// The artifact to load for the requested precision: for Int8,
+// <dir>/model.int8.onnx inside a directory or <stem>.int8.onnx beside a
+// file. Its scaler, drift reference and signature go by its own name.
+pub fn resolve_model_path(model_path: &str, options: &InferenceOptions) -> Result<String, InferenceError> {
+    if options.precision == Precision::Float32 {
+        return Ok(model_path.to_string());
+    }
+
+    let path = Path::new(model_path);
+    let quantized = if path.is_dir() {
+        path.join("model.int8.onnx")
+    } else if path.to_string_lossy().ends_with(".int8.onnx") {
+        path.to_path_buf()
+    } else {
+        path.with_extension("int8.onnx")
+    };
+    if !quantized.exists() {
+        return Err(InferenceError::Model(format!("no int8 model at {}", quantized.display())));
+    }
+    Ok(quantized.to_string_lossy().into_owned())
+}
+
+pub fn load_backend(model_path: &str, options: &InferenceOptions) -> Result<Box<dyn InferenceBackend>, InferenceError> {
+    let backend = load_runtime(model_path, options)?;
+    Ok(match options.latency_budget {
+        Some(budget) => Box::new(BudgetedBackend { inner: backend, budget, strikes: 0, shed_until: None }),
+        None => backend,
+    })
+}
+
+fn load_runtime(model_path: &str, options: &InferenceOptions) -> Result<Box<dyn InferenceBackend>, InferenceError> {
+    let is_onnx = Path::new(model_path)
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("onnx"));
+
+    // Quantized models only exist as ONNX
+    if options.precision == Precision::Int8 && !cfg!(feature = "onnx") {
+        return Err(InferenceError::NoBackend(format!("{} (int8 needs the onnx feature)", model_path)));
+    }
+
+    #[cfg(feature = "onnx")]
+    if is_onnx || cfg!(not(feature = "tensorflow-backend")) {
+        if let Device::Gpu(index) = options.device {
+            tracing::warn!("ONNX backend is CPU-only; ignoring GPU {} for {}", index, model_path);
+        }
+        return Ok(Box::new(OnnxBackend::load(model_path)?));
+    }
+
+    #[cfg(feature = "tensorflow-backend")]
+    if !is_onnx {
+        return Ok(Box::new(TensorflowBackend::load(model_path, options)?));
+    }
+
+    let _ = (is_onnx, options);
+    Err(InferenceError::NoBackend(model_path.to_string()))
+}
+
+// SavedModel exporting ops "input", "anomaly_score" and "reconstruction"
+#[cfg(feature = "tensorflow-backend")]
+pub struct TensorflowBackend {
+    graph: tensorflow::Graph,
+    bundle: tensorflow::SavedModelBundle,
+}
+
+#[cfg(feature = "tensorflow-backend")]
+impl TensorflowBackend {
+    pub fn load(model_path: &str, options: &InferenceOptions) -> Result<Self, InferenceError> {
+        use tensorflow as tf;
+
+        let mut session_options = tf::SessionOptions::new();
+        session_options.set_config(&session_config(options))
+            .map_err(|e| InferenceError::Model(e.to_string()))?;
+
+        // Load pre-trained TensorFlow model
+        let mut graph = tf::Graph::new();
+        let bundle = tf::SavedModelBundle::load(
+            &session_options,
+            ["serve"],
+            &mut graph,
+            Path::new(model_path),
+        )
+        .map_err(|e| InferenceError::Model(e.to_string()))?;
+
+        Ok(Self { graph, bundle })
+    }
+}
+
+// Serialized ConfigProto. Hand-encoded to avoid a protobuf dependency for
+// four fields: device_count (1), intra_op_parallelism_threads (2),
+// inter_op_parallelism_threads (5), gpu_options (6) with allow_growth (4)
+// and visible_device_list (5).
+#[cfg(feature = "tensorflow-backend")]
+fn session_config(options: &InferenceOptions) -> Vec<u8> {
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+    fn bytes_field(out: &mut Vec<u8>, tag: u8, bytes: &[u8]) {
+        out.push(tag);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    let mut config = Vec::new();
+
+    // device_count {"GPU": n}: 0 pins the session to the CPU
+    let mut entry = Vec::new();
+    bytes_field(&mut entry, 0x0a, b"GPU");
+    entry.push(0x10);
+    varint(&mut entry, matches!(options.device, Device::Gpu(_)) as u64);
+    bytes_field(&mut config, 0x0a, &entry);
+
+    if let Some(threads) = options.cpu_threads {
+        config.push(0x10);
+        varint(&mut config, threads as u64);
+        config.push(0x28);
+        varint(&mut config, threads as u64);
+    }
+
+    if let Device::Gpu(index) = options.device {
+        let mut gpu = vec![0x20, 0x01];
+        bytes_field(&mut gpu, 0x2a, index.to_string().as_bytes());
+        bytes_field(&mut config, 0x32, &gpu);
+    }
+
+    config
+}
+
+#[cfg(feature = "tensorflow-backend")]
+impl InferenceBackend for TensorflowBackend {
+    fn infer(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), InferenceError> {
+        use tensorflow as tf;
+
+        // Prepare input tensor
+        let input_tensor = tf::Tensor::new(&[1, features.len() as u64])
+            .with_values(features)?;
+
+        // Run inference
+        let mut args = tf::SessionRunArgs::new();
+        args.add_feed(&self.graph.operation_by_name_required("input")?, 0, &input_tensor);
+
+        let anomaly_op = self.graph.operation_by_name_required("anomaly_score")?;
+        let reconstruction_op = self.graph.operation_by_name_required("reconstruction")?;
+
+        let anomaly_token = args.request_fetch(&anomaly_op, 0);
+        let reconstruction_token = args.request_fetch(&reconstruction_op, 0);
+
+        self.bundle.session.run(&mut args)?;
+
+        let anomaly_score: f32 = args.fetch::<f32>(anomaly_token)?[0];
+        let reconstruction: Vec<f32> = args.fetch::<f32>(reconstruction_token)?.to_vec();
+
+        Ok((anomaly_score, reconstruction))
+    }
+
+    fn infer_batch(&mut self, rows: &[Vec<f32>]) -> Result<Vec<(f32, Vec<f32>)>, InferenceError> {
+        use tensorflow as tf;
+
+        let width = batch_width(rows)?;
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let flat: Vec<f32> = rows.concat();
+        let input_tensor = tf::Tensor::new(&[rows.len() as u64, width as u64])
+            .with_values(&flat)?;
+
+        let mut args = tf::SessionRunArgs::new();
+        args.add_feed(&self.graph.operation_by_name_required("input")?, 0, &input_tensor);
+
+        let anomaly_op = self.graph.operation_by_name_required("anomaly_score")?;
+        let reconstruction_op = self.graph.operation_by_name_required("reconstruction")?;
+
+        let anomaly_token = args.request_fetch(&anomaly_op, 0);
+        let reconstruction_token = args.request_fetch(&reconstruction_op, 0);
+
+        self.bundle.session.run(&mut args)?;
+
+        let scores = args.fetch::<f32>(anomaly_token)?.to_vec();
+        let reconstructions = args.fetch::<f32>(reconstruction_token)?.to_vec();
+        split_batch(rows.len(), &scores, &reconstructions)
+    }
+
+    fn name(&self) -> &'static str {
+        "tensorflow"
+    }
+
+    fn input_width(&self) -> Option<usize> {
+        let input = self.graph.operation_by_name_required("input").ok()?;
+        let shape = self.graph.tensor_shape(tensorflow::Output { operation: input, index: 0 }).ok()?;
+        let dims = shape.dims()?;
+        shape[dims.checked_sub(1)?].and_then(|w| usize::try_from(w).ok())
+    }
+}
+
+// Wraps a backend with a latency budget. Overruns are counted; after
+// BUDGET_STRIKES in a row calls fail fast with OverBudget until the
+// cooldown passes, so a struggling host skips scoring rather than queueing.
+struct BudgetedBackend {
+    inner: Box<dyn InferenceBackend>,
+    budget: Duration,
+    strikes: u32,
+    shed_until: Option<Instant>,
+}
+
+impl BudgetedBackend {
+    fn admit(&mut self) -> Result<(), InferenceError> {
+        match self.shed_until {
+            Some(until) if Instant::now() < until => {
+                metrics::global().incr("qks_inference_shed_total", &[("backend", self.inner.name())]);
+                Err(InferenceError::OverBudget(format!("{} backend cooling down", self.inner.name())))
+            }
+            _ => {
+                self.shed_until = None;
+                Ok(())
+            }
+        }
+    }
+
+    fn account(&mut self, elapsed: Duration, budget: Duration) {
+        metrics::global().observe("qks_inference_seconds", &[("backend", self.inner.name())], elapsed);
+        if elapsed <= budget {
+            self.strikes = 0;
+            return;
+        }
+
+        metrics::global().incr("qks_inference_over_budget_total", &[("backend", self.inner.name())]);
+        self.strikes += 1;
+        if self.strikes >= BUDGET_STRIKES {
+            tracing::warn!(
+                "{} inference over its {:?} budget {} times running ({:?}); shedding for {:?}",
+                self.inner.name(), budget, self.strikes, elapsed, BUDGET_COOLDOWN
+            );
+            self.strikes = 0;
+            self.shed_until = Some(Instant::now() + BUDGET_COOLDOWN);
+        }
+    }
+}
+
+impl InferenceBackend for BudgetedBackend {
+    fn infer(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), InferenceError> {
+        self.admit()?;
+        let started = Instant::now();
+        let result = self.inner.infer(features);
+        self.account(started.elapsed(), self.budget);
+        result
+    }
+
+    fn infer_batch(&mut self, rows: &[Vec<f32>]) -> Result<Vec<(f32, Vec<f32>)>, InferenceError> {
+        self.admit()?;
+        let started = Instant::now();
+        let result = self.inner.infer_batch(rows);
+        self.account(started.elapsed(), self.budget * rows.len().max(1) as u32);
+        result
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn input_width(&self) -> Option<usize> {
+        self.inner.input_width()
+    }
+}
+
+fn batch_width(rows: &[Vec<f32>]) -> Result<usize, InferenceError> {
+    let width = rows.first().map_or(0, |row| row.len());
+    match rows.iter().find(|row| row.len() != width) {
+        Some(row) => Err(InferenceError::Inference(format!("ragged batch: rows of {} and {} features", width, row.len()))),
+        None => Ok(width),
+    }
+}
+
+// Per-row results out of flat [N] (or [N, 1]) scores and [N, F] reconstructions
+fn split_batch(rows: usize, scores: &[f32], reconstructions: &[f32]) -> Result<Vec<(f32, Vec<f32>)>, InferenceError> {
+    if scores.len() < rows || reconstructions.len() % rows.max(1) != 0 {
+        return Err(InferenceError::Inference(format!(
+            "batch of {} rows returned {} scores and {} reconstruction values",
+            rows, scores.len(), reconstructions.len()
+        )));
+    }
+    let width = reconstructions.len() / rows.max(1);
+
+    Ok(scores.iter()
+        .take(rows)
+        .zip(reconstructions.chunks(width.max(1)))
+        .map(|(&score, reconstruction)| (score, reconstruction.to_vec()))
+        .collect())
+}
+
+// Affine int8 parameters for a fully quantized graph (int8 input and
+// outputs), from model.int8.quant.json beside model.int8.onnx. QDQ-style
+// exports keep float I/O and need none: tract runs the quantized ops
+// inside the graph.
+#[cfg(feature = "onnx")]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct QuantParams {
+    pub input_scale: f32,
+    pub input_zero_point: i8,
+    pub output_scale: f32,
+    pub output_zero_point: i8,
+}
+
+#[cfg(feature = "onnx")]
+impl QuantParams {
+    fn quantize(&self, x: f32) -> i8 {
+        (x / self.input_scale + self.input_zero_point as f32).round().clamp(-128.0, 127.0) as i8
+    }
+
+    fn dequantize(&self, q: i8) -> f32 {
+        (q as f32 - self.output_zero_point as f32) * self.output_scale
+    }
+}
+
+// ONNX graph with one input and outputs ordered (anomaly_score, reconstruction)
+#[cfg(feature = "onnx")]
+pub struct OnnxBackend {
+    model: tract_onnx::prelude::InferenceModel,
+    quant: Option<QuantParams>,
+    // Plans are shape-specialised; one per (rows, feature width) seen. Batches
+    // are padded to a power of two rows to keep this small.
+    plans: std::collections::HashMap<(usize, usize), tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>>,
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxBackend {
+    pub fn load(model_path: &str) -> Result<Self, InferenceError> {
+        use tract_onnx::prelude::*;
+
+        let model = tract_onnx::onnx()
+            .model_for_path(model_path)
+            .map_err(|e| InferenceError::Model(e.to_string()))?;
+
+        let quant_path = format!("{}.quant.json", model_path.trim_end_matches(".onnx"));
+        let quant = match std::fs::read(&quant_path) {
+            Ok(data) => Some(serde_json::from_slice(&data)
+                .map_err(|e| InferenceError::Model(format!("{}: {}", quant_path, e)))?),
+            Err(_) => None,
+        };
+
+        Ok(Self { model, quant, plans: std::collections::HashMap::new() })
+    }
+
+    fn plan(&mut self, rows: usize, width: usize) -> Result<&tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>, InferenceError> {
+        use tract_onnx::prelude::*;
+
+        if !self.plans.contains_key(&(rows, width)) {
+            let fact = match self.quant {
+                Some(_) => i8::fact([rows, width]),
+                None => f32::fact([rows, width]),
+            };
+            let plan = self.model.clone()
+                .with_input_fact(0, fact.into())
+                .and_then(|m| m.into_optimized())
+                .and_then(|m| m.into_runnable())
+                .map_err(|e| InferenceError::Model(e.to_string()))?;
+            self.plans.insert((rows, width), plan);
+        }
+        Ok(&self.plans[&(rows, width)])
+    }
+
+    fn run(&mut self, rows: usize, width: usize, flat: Vec<f32>) -> Result<(Vec<f32>, Vec<f32>), InferenceError> {
+        use tract_onnx::prelude::*;
+
+        let quant = self.quant;
+        let input = match quant {
+            Some(q) => tract_ndarray::Array2::from_shape_vec((rows, width), flat.iter().map(|&x| q.quantize(x)).collect())
+                .map(Tensor::from),
+            None => tract_ndarray::Array2::from_shape_vec((rows, width), flat).map(Tensor::from),
+        }
+        .map_err(|e| InferenceError::Inference(e.to_string()))?;
+        let outputs = self.plan(rows, width)?
+            .run(tvec!(input.into()))
+            .map_err(|e| InferenceError::Inference(e.to_string()))?;
+
+        if outputs.len() < 2 {
+            return Err(InferenceError::Model(format!("expected 2 outputs, model has {}", outputs.len())));
+        }
+        let read = |i: usize| -> Result<Vec<f32>, InferenceError> {
+            match quant {
+                Some(q) if outputs[i].datum_type() == i8::datum_type() => outputs[i].as_slice::<i8>()
+                    .map(|s| s.iter().map(|&v| q.dequantize(v)).collect()),
+                _ => outputs[i].as_slice::<f32>().map(|s| s.to_vec()),
+            }
+            .map_err(|e| InferenceError::Inference(e.to_string()))
+        };
+
+        Ok((read(0)?, read(1)?))
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl InferenceBackend for OnnxBackend {
+    fn infer(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), InferenceError> {
+        let (scores, reconstruction) = self.run(1, features.len(), features.to_vec())?;
+
+        let anomaly_score = scores.first().copied()
+            .ok_or_else(|| InferenceError::Inference("empty anomaly_score output".into()))?;
+        Ok((anomaly_score, reconstruction))
+    }
+
+    fn infer_batch(&mut self, rows: &[Vec<f32>]) -> Result<Vec<(f32, Vec<f32>)>, InferenceError> {
+        let width = batch_width(rows)?;
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Zero rows pad up to the planned batch size; their results are dropped
+        let padded = rows.len().next_power_of_two();
+        let mut flat = rows.concat();
+        flat.resize(padded * width, 0.0);
+
+        let (scores, reconstructions) = self.run(padded, width, flat)?;
+        let mut results = split_batch(padded, &scores, &reconstructions)?;
+        results.truncate(rows.len());
+        Ok(results)
+    }
+
+    fn name(&self) -> &'static str {
+        "onnx"
+    }
+
+    fn input_width(&self) -> Option<usize> {
+        let fact = self.model.input_fact(0).ok()?;
+        let dims = fact.shape.concretize()?;
+        dims.last()?.to_usize().ok()
+    }
+}
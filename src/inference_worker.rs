@@ -0,0 +1,145 @@
+// src/inference_worker.rs
+// Inference off the caller's thread. Rows queue for a dedicated worker
+// thread that owns the expensive part (locking the detector and running the
+// model). Each call has a deadline. When the queue is full, or the deadline
+// passes, the caller is answered by the statistical baseline, so detection
+// degrades rather than stalling the eBPF or pipeline tasks. The worker
+// refreshes a snapshot of the baseline periodically, so the fallback never
+// needs the detector lock.
+use crate::baseline_model::OnlineBaseline;
+use crate::inference_backend::InferenceError;
+use crate::metrics;
+use crate::ml_detector::{FeatureVector, MLAnomalyDetector, ScoringMode};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Clone, Copy)]
+pub struct OffloadConfig {
+    pub queue_depth: usize,
+    pub deadline: Duration,
+    // Jobs between refreshes of the fallback baseline snapshot
+    pub snapshot_every: u64,
+}
+
+impl Default for OffloadConfig {
+    fn default() -> Self {
+        Self { queue_depth: 256, deadline: Duration::from_millis(50), snapshot_every: 64 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShedReason {
+    QueueFull,
+    Deadline,
+    WorkerGone,
+}
+
+impl ShedReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ShedReason::QueueFull => "queue-full",
+            ShedReason::Deadline => "deadline",
+            ShedReason::WorkerGone => "worker-gone",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct OffloadScore {
+    pub score: f32,
+    pub mode: ScoringMode,
+    // Set when the baseline answered instead of the detector
+    pub shed: Option<ShedReason>,
+}
+
+struct Job {
+    features: FeatureVector,
+    queued: Instant,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<(f32, ScoringMode), InferenceError>>,
+}
+
+#[derive(Clone)]
+pub struct InferenceWorker {
+    jobs: mpsc::Sender<Job>,
+    fallback: Arc<RwLock<OnlineBaseline>>,
+    config: OffloadConfig,
+}
+
+impl InferenceWorker {
+    pub fn start(
+        detector: Arc<Mutex<MLAnomalyDetector>>,
+        config: OffloadConfig,
+    ) -> std::io::Result<(Self, std::thread::JoinHandle<()>)> {
+        let (tx, rx) = mpsc::channel(config.queue_depth.max(1));
+        let fallback = Arc::new(RwLock::new(detector.lock().unwrap().baseline().clone()));
+
+        let snapshot = fallback.clone();
+        let handle = std::thread::Builder::new()
+            .name("qks-inference".into())
+            .spawn(move || Self::run(detector, rx, snapshot, config))?;
+
+        Ok((Self { jobs: tx, fallback, config }, handle))
+    }
+
+    // Stops when every InferenceWorker handle has been dropped
+    fn run(
+        detector: Arc<Mutex<MLAnomalyDetector>>,
+        mut jobs: mpsc::Receiver<Job>,
+        fallback: Arc<RwLock<OnlineBaseline>>,
+        config: OffloadConfig,
+    ) {
+        let mut served = 0u64;
+        while let Some(job) = jobs.blocking_recv() {
+            metrics::global().observe("qks_inference_queue_seconds", &[], job.queued.elapsed());
+            // The caller has already been answered by the fallback
+            if job.reply.is_closed() || Instant::now() >= job.deadline {
+                continue;
+            }
+
+            let mut detector = detector.lock().unwrap();
+            let result = detector.detect_anomaly(&job.features)
+                .map(|(score, _)| (score, detector.scoring_mode()));
+            served += 1;
+            if served % config.snapshot_every.max(1) == 0 {
+                *fallback.write().unwrap() = detector.baseline().clone();
+            }
+            drop(detector);
+
+            let _ = job.reply.send(result);
+        }
+        tracing::debug!("Inference worker stopped after {} jobs", served);
+    }
+
+    pub async fn score(&self, features: FeatureVector) -> Result<OffloadScore, InferenceError> {
+        self.score_within(features, self.config.deadline).await
+    }
+
+    pub async fn score_within(&self, features: FeatureVector, deadline: Duration) -> Result<OffloadScore, InferenceError> {
+        let (reply, answer) = oneshot::channel();
+        let now = Instant::now();
+        // Rows are a few dozen floats; keep one for the fallback
+        let job = Job { features: features.clone(), queued: now, deadline: now + deadline, reply };
+
+        match self.jobs.try_send(job) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => return Ok(self.shed(&features, ShedReason::QueueFull)),
+            Err(mpsc::error::TrySendError::Closed(_)) => return Ok(self.shed(&features, ShedReason::WorkerGone)),
+        }
+
+        match tokio::time::timeout(deadline, answer).await {
+            Ok(Ok(result)) => result.map(|(score, mode)| OffloadScore { score, mode, shed: None }),
+            // Dropped unanswered: it expired in the queue
+            Ok(Err(_)) | Err(_) => Ok(self.shed(&features, ShedReason::Deadline)),
+        }
+    }
+
+    fn shed(&self, features: &[f32], reason: ShedReason) -> OffloadScore {
+        metrics::global().incr("qks_inference_offload_shed_total", &[("reason", reason.as_str())]);
+        let (score, _) = self.fallback.read().unwrap().score(features);
+        OffloadScore { score, mode: ScoringMode::Baseline, shed: Some(reason) }
+    }
+}
@@ -0,0 +1,925 @@
+// src/intel.rs
+// Threat intelligence feeds: addresses, networks, domains and file digests
+// that someone has seen used in attacks, pulled from TAXII 2.1 collections,
+// STIX 2.1 bundles or plain lists, and matched against what the host does.
+// An outbound connect to a listed address, an exec of a listed binary or an
+// integrity finding whose digest is listed becomes an IntelEvent naming the
+// feed, the indicator and the process, which alerts, forwarding and policy
+// rules take like any other event.
+//
+// Only STIX patterns that are one comparison, or several joined by OR, on
+// an address, domain or file digest are taken; anything that needs state
+// to match (AND, FOLLOWEDBY, qualifiers) is skipped and counted. Revoked
+// indicators and those past valid_until are left out. Domains are matched
+// through the addresses they resolve to at each refresh, leaving out
+// loopback and private ones, which sinkholed domains resolve to.
+//
+// Execs come from syscall events (the eBPF syscalls probe group, or the
+// proc connector standing in for it). The binary is hashed through
+// /proc/PID/exe, so a file replaced or deleted since is still the one
+// hashed; digests are cached by inode and modification time.
+//
+// Every feed's last good indicators are kept under intel/ in the state
+// directory and indexed at startup, before the first fetch.
+use crate::config::IntelSection;
+use crate::ebpf_monitor::{NetworkAction, SyscallEvent};
+use crate::events::{EventBus, EventKind, IntelEvent, SecurityEvent};
+use crate::metrics;
+use crate::syslog_sink;
+use crate::systemd::now_secs;
+use md5::{Digest, Md5};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::net::IpAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+const TAXII_MEDIA_TYPE: &str = "application/taxii+json;version=2.1";
+// Per feed, however many pages a collection has
+const MAX_INDICATORS: usize = 1_000_000;
+const MAX_PAGES: usize = 10_000;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+const RESOLVE_CONCURRENCY: usize = 32;
+// Execs waiting to be hashed; more than that are counted and dropped
+const EXEC_QUEUE: usize = 1024;
+const DIGEST_CACHE: usize = 4096;
+const MAX_REPEATS: usize = 65_536;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedFormat {
+    // One indicator a line; what follows it is its description
+    #[default]
+    Text,
+    Csv,
+    // A STIX 2.1 bundle
+    Stix,
+    // A TAXII 2.1 collection
+    Taxii,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndicatorType {
+    // Told apart by their form
+    #[default]
+    Auto,
+    Ip,
+    Domain,
+    Hash,
+}
+
+// One [[intel.feeds]]: fetched from `url`, or read from `path`. For TAXII,
+// `url` is the API root and `collection` the collection's ID.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntelFeed {
+    // Names the feed in events, metrics and its cache file
+    pub name: String,
+    #[serde(default)]
+    pub format: FeedFormat,
+    pub url: Option<String>,
+    pub path: Option<PathBuf>,
+    pub collection: Option<String>,
+    // What a text or CSV feed's entries are
+    #[serde(default)]
+    pub indicator: IndicatorType,
+    // CSV columns, from 0
+    #[serde(default)]
+    pub column: usize,
+    pub description_column: Option<usize>,
+    // A bearer token; or `username` with the password in `password_file`
+    pub token_file: Option<PathBuf>,
+    pub username: Option<String>,
+    pub password_file: Option<PathBuf>,
+    #[serde(default = "default_verify_tls")]
+    pub verify_tls: bool,
+    // 0-10, carried by every match; 8 and up alert as critical
+    #[serde(default = "default_severity")]
+    pub severity: u8,
+}
+
+fn default_verify_tls() -> bool {
+    true
+}
+
+fn default_severity() -> u8 {
+    8
+}
+
+// Feed names become file names and metric labels
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntelError {
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("feed {0}: {1}")]
+    Client(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+enum Target {
+    Ip(IpAddr),
+    // Masked address and prefix length
+    Network(IpAddr, u8),
+    Domain(String),
+    // algorithm:hex
+    Hash(String),
+}
+
+impl Target {
+    fn parse(value: &str, as_type: IndicatorType) -> Option<Self> {
+        let value = value.trim().trim_matches('"');
+        if value.is_empty() {
+            return None;
+        }
+        let address = || match value.split_once('/') {
+            Some((address, prefix)) => {
+                let address: IpAddr = address.parse().ok()?;
+                let prefix: u8 = prefix.parse().ok()?;
+                let bits = if address.is_ipv4() { 32 } else { 128 };
+                match prefix {
+                    prefix if prefix > bits => None,
+                    prefix if prefix == bits => Some(Target::Ip(address)),
+                    prefix => Some(Target::Network(mask(address, prefix), prefix)),
+                }
+            }
+            None => value.parse().ok().map(Target::Ip),
+        };
+        let hash = || match value.split_once(':') {
+            Some((algorithm, hex)) => hash_with(algorithm, hex),
+            None => hash_with(algorithm_for(value.len())?, value),
+        };
+        let domain = || {
+            let domain = value.trim_end_matches('.').to_ascii_lowercase();
+            let labels_ok = domain.split('.').all(|label| !label.is_empty() && label.len() <= 63 && !label.starts_with('-'));
+            let chars_ok = domain.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+            (domain.contains('.') && domain.len() <= 253 && labels_ok && chars_ok).then_some(Target::Domain(domain))
+        };
+        match as_type {
+            IndicatorType::Auto => address().or_else(hash).or_else(domain),
+            IndicatorType::Ip => address(),
+            IndicatorType::Domain => domain(),
+            IndicatorType::Hash => hash(),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Target::Ip(_) | Target::Network(..) => "ip",
+            Target::Domain(_) => "domain",
+            Target::Hash(_) => "hash",
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Ip(address) => write!(f, "{}", address),
+            Target::Network(address, prefix) => write!(f, "{}/{}", address, prefix),
+            Target::Domain(domain) => f.write_str(domain),
+            Target::Hash(digest) => f.write_str(digest),
+        }
+    }
+}
+
+fn mask(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) => IpAddr::from(u32::from(v4) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)),
+        IpAddr::V6(v6) => IpAddr::from(u128::from(v6) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)),
+    }
+}
+
+fn algorithm_for(hex_len: usize) -> Option<&'static str> {
+    match hex_len {
+        32 => Some("md5"),
+        40 => Some("sha1"),
+        64 => Some("sha256"),
+        _ => None,
+    }
+}
+
+// md5, sha1 or sha256 in any of the spellings feeds use
+fn hash_with(algorithm: &str, hex: &str) -> Option<Target> {
+    let algorithm = match algorithm.to_ascii_lowercase().replace('-', "").as_str() {
+        "md5" => "md5",
+        "sha1" => "sha1",
+        "sha256" => "sha256",
+        _ => return None,
+    };
+    (algorithm_for(hex.len()) == Some(algorithm) && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| Target::Hash(format!("{}:{}", algorithm, hex.to_ascii_lowercase())))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Indicator {
+    target: Target,
+    description: Option<String>,
+    confidence: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Quoted(String),
+}
+
+fn quoted(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Option<String> {
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '\\' => text.push(chars.next()?),
+            '\'' => return Some(text),
+            c => text.push(c),
+        }
+    }
+}
+
+fn tokenize(pattern: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '[' | '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ']' | ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '\'' => {
+                chars.next();
+                tokens.push(Token::Quoted(quoted(&mut chars)?));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        // file:hashes.'SHA-256' is one path
+                        '\'' if word.ends_with('.') => {
+                            chars.next();
+                            word.push_str(&quoted(&mut chars)?);
+                        }
+                        c if c.is_whitespace() || "[]()'".contains(c) => break,
+                        c => {
+                            chars.next();
+                            word.push(c);
+                        }
+                    }
+                }
+                // ipv4-addr:value='...' without spaces
+                match word.strip_suffix('=') {
+                    Some(path) if !path.is_empty() => {
+                        tokens.push(Token::Word(path.to_string()));
+                        tokens.push(Token::Word("=".to_string()));
+                    }
+                    _ => tokens.push(Token::Word(word)),
+                }
+            }
+        }
+    }
+    Some(tokens)
+}
+
+// The targets of a pattern made only of comparisons joined by OR; the
+// brackets can be dropped because OR is all there is
+fn stix_targets(pattern: &str) -> Option<Vec<Target>> {
+    let tokens: Vec<Token> = tokenize(pattern)?.into_iter().filter(|token| !matches!(token, Token::Open | Token::Close)).collect();
+    let mut targets = Vec::new();
+    let mut rest = tokens.as_slice();
+    loop {
+        let [Token::Word(path), Token::Word(operator), Token::Quoted(value), tail @ ..] = rest else {
+            return None;
+        };
+        let target = match path.as_str() {
+            "ipv4-addr:value" | "ipv6-addr:value" if operator == "=" || operator.eq_ignore_ascii_case("ISSUBSET") => Target::parse(value, IndicatorType::Ip)?,
+            "domain-name:value" if operator == "=" => Target::parse(value, IndicatorType::Domain)?,
+            path if operator == "=" => hash_with(path.strip_prefix("file:hashes.")?, value)?,
+            _ => return None,
+        };
+        targets.push(target);
+        match tail {
+            [] => return Some(targets),
+            [Token::Word(or), more @ ..] if or.eq_ignore_ascii_case("OR") => rest = more,
+            _ => return None,
+        }
+    }
+}
+
+// Indicator objects into `out`; returns how many were skipped. `now` is
+// RFC 3339 UTC, which compares with STIX times as text.
+fn stix_indicators(objects: &[Value], now: &str, out: &mut Vec<Indicator>) -> usize {
+    let mut skipped = 0;
+    for object in objects {
+        if object["type"] != "indicator" || object["revoked"] == true {
+            continue;
+        }
+        if let Some(until) = object["valid_until"].as_str() {
+            if until.get(..19).unwrap_or(until) <= now.get(..19).unwrap_or(now) {
+                continue;
+            }
+        }
+        let pattern = match object["pattern_type"].as_str() {
+            None | Some("stix") => object["pattern"].as_str(),
+            Some(_) => None,
+        };
+        let Some(targets) = pattern.and_then(stix_targets) else {
+            skipped += 1;
+            continue;
+        };
+        let description = match (object["name"].as_str(), object["id"].as_str()) {
+            (Some(name), Some(id)) => Some(format!("{} ({})", name, id)),
+            (name, id) => name.or(id).map(str::to_string),
+        };
+        let confidence = object["confidence"].as_u64().map(|confidence| confidence.min(100) as u8);
+        out.extend(targets.into_iter().map(|target| Indicator { target, description: description.clone(), confidence }));
+    }
+    skipped
+}
+
+// Quoted fields may hold commas; "" is a quote
+fn split_csv(line: &str) -> Vec<String> {
+    let mut columns = vec![String::new()];
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                columns.last_mut().into_iter().for_each(|column| column.push('"'));
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => columns.push(String::new()),
+            c => columns.last_mut().into_iter().for_each(|column| column.push(c)),
+        }
+    }
+    columns.into_iter().map(|column| column.trim().to_string()).collect()
+}
+
+// Text and CSV lists into `out`; returns how many lines were skipped,
+// headers included
+fn list_indicators(text: &str, feed: &IntelFeed, out: &mut Vec<Indicator>) -> usize {
+    let mut skipped = 0;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') || line.starts_with("//") {
+            continue;
+        }
+        let (value, description) = match feed.format {
+            FeedFormat::Csv => {
+                let columns = split_csv(line);
+                let description = feed.description_column.and_then(|column| columns.get(column)).filter(|d| !d.is_empty()).cloned();
+                (columns.get(feed.column).cloned().unwrap_or_default(), description)
+            }
+            // `1.2.3.0/24 ; SBL123` and `1.2.3.4 # botnet C2` both
+            _ => {
+                let (value, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let description = rest.trim().trim_start_matches(['#', ';']).trim();
+                (value.to_string(), (!description.is_empty()).then(|| description.to_string()))
+            }
+        };
+        match Target::parse(&value, feed.indicator) {
+            Some(target) => out.push(Indicator { target, description, confidence: None }),
+            None => skipped += 1,
+        }
+        if out.len() >= MAX_INDICATORS {
+            break;
+        }
+    }
+    skipped
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FeedStatus {
+    pub name: String,
+    pub indicators: usize,
+    // Entries that were not indicators this can match
+    pub skipped: usize,
+    pub fetched_at: Option<u64>,
+    // Why the last fetch failed; the indicators are from before
+    pub error: Option<String>,
+}
+
+struct Feed {
+    config: IntelFeed,
+    client: reqwest::Client,
+    token: Option<String>,
+    password: Option<String>,
+    cache: PathBuf,
+    indicators: Mutex<Arc<Vec<Indicator>>>,
+    status: Mutex<FeedStatus>,
+}
+
+impl Feed {
+    fn open(config: &IntelFeed, dir: &std::path::Path) -> Result<Self, IntelError> {
+        let read = |path: &PathBuf| fs::read_to_string(path).map(|secret| secret.trim().to_string()).map_err(|source| IntelError::Io { path: path.clone(), source });
+        let token = config.token_file.as_ref().map(read).transpose()?;
+        let password = config.password_file.as_ref().map(read).transpose()?;
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .danger_accept_invalid_certs(!config.verify_tls)
+            .build()
+            .map_err(|e| IntelError::Client(config.name.clone(), e.to_string()))?;
+
+        let cache = dir.join(format!("{}.json", config.name));
+        let indicators: Vec<Indicator> = fs::read(&cache).ok().and_then(|data| serde_json::from_slice(&data).ok()).unwrap_or_default();
+        if !indicators.is_empty() {
+            tracing::info!("Intel feed {}: {} indicators from the last fetch", config.name, indicators.len());
+        }
+        let status = FeedStatus { name: config.name.clone(), indicators: indicators.len(), ..FeedStatus::default() };
+        Ok(Self { config: config.clone(), client, token, password, cache, indicators: Mutex::new(Arc::new(indicators)), status: Mutex::new(status) })
+    }
+
+    async fn get(&self, url: &str, accept: &str, query: &[(&str, &str)]) -> Result<String, String> {
+        let request = self.client.get(url).header(reqwest::header::ACCEPT, accept).query(query);
+        let request = match (&self.token, &self.config.username) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some(username)) => request.basic_auth(username, self.password.as_deref()),
+            (None, None) => request,
+        };
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+        response.text().await.map_err(|e| e.to_string())
+    }
+
+    async fn read(&self) -> Result<String, String> {
+        match (&self.config.url, &self.config.path) {
+            (Some(url), _) => self.get(url, "text/plain, text/csv, application/json, */*", &[]).await,
+            (None, Some(path)) => tokio::fs::read_to_string(path).await.map_err(|e| format!("{}: {}", path.display(), e)),
+            (None, None) => Err("neither url nor path is set".to_string()),
+        }
+    }
+
+    // Every page of the collection's indicators
+    async fn taxii(&self, now: &str, out: &mut Vec<Indicator>) -> Result<usize, String> {
+        let root = self.config.url.as_deref().unwrap_or_default().trim_end_matches('/');
+        let url = format!("{}/collections/{}/objects/", root, self.config.collection.as_deref().unwrap_or_default());
+        let mut skipped = 0;
+        let mut next: Option<String> = None;
+        for _ in 0..MAX_PAGES {
+            let mut query = vec![("match[type]", "indicator")];
+            if let Some(next) = &next {
+                query.push(("next", next));
+            }
+            let envelope: Value = serde_json::from_str(&self.get(&url, TAXII_MEDIA_TYPE, &query).await?).map_err(|e| format!("not a TAXII envelope: {}", e))?;
+            skipped += stix_indicators(envelope["objects"].as_array().map_or(&[][..], Vec::as_slice), now, out);
+            match (envelope["more"].as_bool(), envelope["next"].as_str()) {
+                (Some(true), Some(token)) if out.len() < MAX_INDICATORS => next = Some(token.to_string()),
+                _ => break,
+            }
+        }
+        Ok(skipped)
+    }
+
+    async fn fetch(&self, now: &str) -> Result<(Vec<Indicator>, usize), String> {
+        let mut indicators = Vec::new();
+        let skipped = match self.config.format {
+            FeedFormat::Text | FeedFormat::Csv => list_indicators(&self.read().await?, &self.config, &mut indicators),
+            FeedFormat::Stix => {
+                let bundle: Value = serde_json::from_str(&self.read().await?).map_err(|e| format!("not a STIX bundle: {}", e))?;
+                stix_indicators(bundle["objects"].as_array().map_or(&[][..], Vec::as_slice), now, &mut indicators)
+            }
+            FeedFormat::Taxii => self.taxii(now, &mut indicators).await?,
+        };
+        indicators.truncate(MAX_INDICATORS);
+        Ok((indicators, skipped))
+    }
+
+    // Through a temporary file, so a crash leaves the old copy
+    fn save(&self, indicators: &[Indicator]) {
+        let temporary = self.cache.with_extension("tmp");
+        let saved = serde_json::to_vec(indicators)
+            .map_err(std::io::Error::from)
+            .and_then(|data| fs::write(&temporary, data))
+            .and_then(|()| fs::rename(&temporary, &self.cache));
+        if let Err(e) = saved {
+            tracing::warn!("{} not saved: {}", self.cache.display(), e);
+        }
+    }
+}
+
+// (feed, indicator) positions
+type Ref = (usize, usize);
+
+struct Listed {
+    name: String,
+    severity: u8,
+    indicators: Arc<Vec<Indicator>>,
+}
+
+// Rebuilt whole after each refresh; the first feed to list a value wins
+#[derive(Default)]
+struct Index {
+    feeds: Vec<Listed>,
+    addresses: HashMap<IpAddr, Ref>,
+    // Most specific prefix first
+    networks: Vec<(u8, bool, HashMap<IpAddr, Ref>)>,
+    domains: usize,
+    // Addresses domain indicators resolved to
+    resolved: HashMap<IpAddr, Ref>,
+    hashes: HashMap<String, Ref>,
+}
+
+impl Index {
+    fn build(feeds: Vec<Listed>, resolved: HashMap<IpAddr, Ref>) -> Self {
+        let mut index = Index { resolved, ..Index::default() };
+        let mut networks: HashMap<(u8, bool), HashMap<IpAddr, Ref>> = HashMap::new();
+        for (f, feed) in feeds.iter().enumerate() {
+            for (i, indicator) in feed.indicators.iter().enumerate() {
+                match &indicator.target {
+                    Target::Ip(address) => {
+                        index.addresses.entry(*address).or_insert((f, i));
+                    }
+                    Target::Network(address, prefix) => {
+                        networks.entry((*prefix, address.is_ipv6())).or_default().entry(*address).or_insert((f, i));
+                    }
+                    Target::Domain(_) => index.domains += 1,
+                    Target::Hash(digest) => {
+                        index.hashes.entry(digest.clone()).or_insert((f, i));
+                    }
+                }
+            }
+        }
+        index.networks = networks.into_iter().map(|((prefix, v6), map)| (prefix, v6, map)).collect();
+        index.networks.sort_by(|a, b| b.0.cmp(&a.0));
+        index.feeds = feeds;
+        index
+    }
+
+    fn get(&self, (f, i): Ref) -> (&Listed, &Indicator) {
+        let feed = &self.feeds[f];
+        (feed, &feed.indicators[i])
+    }
+
+    fn address(&self, address: IpAddr) -> Option<Ref> {
+        if let Some(found) = self.addresses.get(&address) {
+            return Some(*found);
+        }
+        let found = self.networks.iter().filter(|(_, v6, _)| *v6 == address.is_ipv6()).find_map(|(prefix, _, map)| map.get(&mask(address, *prefix)));
+        found.or_else(|| self.resolved.get(&address)).copied()
+    }
+}
+
+// What was seen, for the event
+struct Seen {
+    observed: String,
+    pid: Option<u32>,
+    exe: Option<String>,
+    path: Option<String>,
+    matched: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntelStatus {
+    pub feeds: Vec<FeedStatus>,
+    pub addresses: usize,
+    pub networks: usize,
+    pub domains: usize,
+    pub resolved: usize,
+    pub hashes: usize,
+    pub refreshed_at: Option<u64>,
+}
+
+pub struct Intel {
+    section: IntelSection,
+    feeds: Vec<Feed>,
+    index: Mutex<Arc<Index>>,
+    refreshed_at: Mutex<Option<u64>>,
+    // (dev, inode, size, mtime) -> md5, sha1 and sha256
+    digests: Mutex<HashMap<(u64, u64, u64, i64, i64), Arc<[String; 3]>>>,
+    // Matches already reported, and when
+    repeats: Mutex<HashMap<String, u64>>,
+}
+
+impl Intel {
+    pub fn new(section: &IntelSection, dir: PathBuf) -> Result<Self, IntelError> {
+        fs::create_dir_all(&dir).map_err(|source| IntelError::Io { path: dir.clone(), source })?;
+        let feeds = section.feeds.iter().map(|feed| Feed::open(feed, &dir)).collect::<Result<Vec<_>, _>>()?;
+        let intel = Self {
+            section: section.clone(),
+            feeds,
+            index: Mutex::new(Arc::new(Index::default())),
+            refreshed_at: Mutex::new(None),
+            digests: Mutex::new(HashMap::new()),
+            repeats: Mutex::new(HashMap::new()),
+        };
+        intel.rebuild(HashMap::new());
+        Ok(intel)
+    }
+
+    fn index(&self) -> Arc<Index> {
+        self.index.lock().unwrap().clone()
+    }
+
+    fn listed(&self) -> Vec<Listed> {
+        self.feeds
+            .iter()
+            .map(|feed| Listed { name: feed.config.name.clone(), severity: feed.config.severity, indicators: feed.indicators.lock().unwrap().clone() })
+            .collect()
+    }
+
+    fn rebuild(&self, resolved: HashMap<IpAddr, Ref>) {
+        *self.index.lock().unwrap() = Arc::new(Index::build(self.listed(), resolved));
+    }
+
+    async fn refresh(&self) {
+        let now = syslog_sink::timestamp(SystemTime::now());
+        for feed in &self.feeds {
+            let name = feed.config.name.as_str();
+            match feed.fetch(&now).await {
+                Ok((indicators, skipped)) => {
+                    tracing::info!("Intel feed {}: {} indicators, {} entries skipped", name, indicators.len(), skipped);
+                    metrics::global().incr("qks_intel_fetches_total", &[("feed", name), ("result", "ok")]);
+                    feed.save(&indicators);
+                    *feed.status.lock().unwrap() = FeedStatus { name: name.to_string(), indicators: indicators.len(), skipped, fetched_at: Some(now_secs()), error: None };
+                    *feed.indicators.lock().unwrap() = Arc::new(indicators);
+                }
+                Err(e) => {
+                    let kept = feed.indicators.lock().unwrap().len();
+                    tracing::warn!("Intel feed {} not refreshed, keeping {} indicators: {}", name, kept, e);
+                    metrics::global().incr("qks_intel_fetches_total", &[("feed", name), ("result", "error")]);
+                    feed.status.lock().unwrap().error = Some(e);
+                }
+            }
+        }
+        let resolved = self.resolve().await;
+        self.rebuild(resolved);
+        *self.refreshed_at.lock().unwrap() = Some(now_secs());
+    }
+
+    // Public addresses of the first resolve_domains domain indicators
+    async fn resolve(&self) -> HashMap<IpAddr, Ref> {
+        let mut domains = Vec::new();
+        for (f, feed) in self.listed().iter().enumerate() {
+            for (i, indicator) in feed.indicators.iter().enumerate() {
+                if let Target::Domain(domain) = &indicator.target {
+                    domains.push(((f, i), domain.clone()));
+                }
+            }
+        }
+        domains.truncate(self.section.resolve_domains);
+
+        let mut resolved = HashMap::new();
+        for chunk in domains.chunks(RESOLVE_CONCURRENCY) {
+            let mut lookups = tokio::task::JoinSet::new();
+            for (reference, domain) in chunk.iter().cloned() {
+                lookups.spawn(async move {
+                    let found = tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host((domain.as_str(), 0))).await;
+                    let addresses: Vec<IpAddr> = found.ok().and_then(Result::ok).into_iter().flatten().map(|socket| socket.ip()).collect();
+                    (reference, addresses)
+                });
+            }
+            while let Some(joined) = lookups.join_next().await {
+                let Ok((reference, addresses)) = joined else {
+                    continue;
+                };
+                for address in addresses.into_iter().filter(|address| public(*address)) {
+                    resolved.entry(address).or_insert(reference);
+                }
+            }
+        }
+        resolved
+    }
+
+    fn report(&self, bus: &EventBus, index: &Index, found: Ref, seen: Seen) {
+        let (feed, indicator) = index.get(found);
+        let indicator_value = indicator.target.to_string();
+        // Integrity findings have a path and may have no PID
+        let subject = seen.pid.map_or_else(|| seen.path.clone().unwrap_or_default(), |pid| pid.to_string());
+        let key = format!("{}|{}|{}", indicator_value, seen.matched, subject);
+        let now = now_secs();
+        {
+            let mut repeats = self.repeats.lock().unwrap();
+            if repeats.get(&key).is_some_and(|at| now < at + self.section.repeat_secs) {
+                return;
+            }
+            if repeats.len() >= MAX_REPEATS {
+                repeats.retain(|_, at| now < *at + self.section.repeat_secs);
+            }
+            repeats.insert(key, now);
+        }
+
+        let event = IntelEvent {
+            observed: seen.observed,
+            pid: seen.pid,
+            exe: seen.exe,
+            path: seen.path,
+            indicator_type: indicator.target.type_name().to_string(),
+            indicator: indicator_value,
+            matched: seen.matched,
+            feed: feed.name.clone(),
+            description: indicator.description.clone(),
+            confidence: indicator.confidence,
+            severity: feed.severity,
+            timestamp: now,
+        };
+        metrics::global().incr("qks_intel_matches_total", &[("feed", &feed.name), ("type", &event.indicator_type)]);
+        let event = SecurityEvent::Intel(event);
+        tracing::warn!("Threat intel match: {}", crate::siem_format::describe(&event));
+        bus.publish(event);
+    }
+
+    fn check_connect(&self, bus: &EventBus, pid: u32, destination: IpAddr) {
+        let index = self.index();
+        if let Some(found) = index.address(destination) {
+            let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok().map(|exe| exe.to_string_lossy().into_owned());
+            self.report(bus, &index, found, Seen { observed: "connect".to_string(), pid: Some(pid), exe, path: None, matched: destination.to_string() });
+        }
+    }
+
+    fn check_exec(&self, bus: &EventBus, pid: u32) {
+        let index = self.index();
+        if index.hashes.is_empty() {
+            return;
+        }
+        let link = format!("/proc/{}/exe", pid);
+        // Gone already, or not ours to read
+        let (Ok(exe), Ok(metadata)) = (fs::read_link(&link), fs::metadata(&link)) else {
+            return;
+        };
+        if metadata.len() > self.section.max_exec_mb * 1024 * 1024 {
+            return;
+        }
+        let key = (metadata.dev(), metadata.ino(), metadata.len(), metadata.mtime(), metadata.mtime_nsec());
+        let cached = self.digests.lock().unwrap().get(&key).cloned();
+        let digests = match cached {
+            Some(digests) => digests,
+            None => {
+                let Ok(digests) = digests_of(&link) else {
+                    return;
+                };
+                let digests = Arc::new(digests);
+                let mut cache = self.digests.lock().unwrap();
+                if cache.len() >= DIGEST_CACHE {
+                    cache.clear();
+                }
+                cache.insert(key, digests.clone());
+                digests
+            }
+        };
+        let exe = exe.to_string_lossy().into_owned();
+        for digest in digests.iter() {
+            if let Some(found) = index.hashes.get(digest) {
+                let seen = Seen { observed: "exec".to_string(), pid: Some(pid), exe: Some(exe.clone()), path: Some(exe.clone()), matched: digest.clone() };
+                self.report(bus, &index, *found, seen);
+            }
+        }
+    }
+
+    fn check_digest(&self, bus: &EventBus, source: &str, pid: Option<u32>, path: Option<&str>, digest: &str) {
+        let index = self.index();
+        let digest = digest.to_ascii_lowercase();
+        if let Some(found) = index.hashes.get(&digest) {
+            let seen = Seen { observed: source.to_string(), pid, exe: None, path: path.map(str::to_string), matched: digest.clone() };
+            self.report(bus, &index, *found, seen);
+        }
+    }
+
+    // Refreshing now, matching from the cached indicators meanwhile
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> Vec<(&'static str, JoinHandle<()>)> {
+        let mut tasks = Vec::new();
+        let intel = self.clone();
+        let refresh = Duration::from_secs(self.section.refresh_secs);
+        tasks.push((
+            "intel-refresh",
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(refresh);
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    ticks.tick().await;
+                    intel.refresh().await;
+                }
+            }),
+        ));
+
+        let mut kinds = Vec::new();
+        if self.section.match_network {
+            kinds.push(EventKind::Network);
+        }
+        if self.section.match_integrity {
+            kinds.push(EventKind::Integrity);
+        }
+        let execs = if self.section.match_exec {
+            kinds.push(EventKind::Syscall);
+            let (tx, rx) = mpsc::channel(EXEC_QUEUE);
+            tasks.push(("intel-exec", self.clone().hash_execs(rx, bus.clone())));
+            Some(tx)
+        } else {
+            None
+        };
+        if kinds.is_empty() {
+            return tasks;
+        }
+        let mut events = bus.subscribe_to(&kinds).with_name("intel");
+        let intel = self;
+        tasks.push((
+            "intel-match",
+            tokio::spawn(async move {
+                while let Some(event) = events.recv().await {
+                    match &event {
+                        SecurityEvent::Network(e) if e.action == NetworkAction::Connect => {
+                            if let Some(destination) = e.destination {
+                                intel.check_connect(&bus, e.pid, destination);
+                            }
+                        }
+                        SecurityEvent::Syscall(e) if is_exec(e) => {
+                            if let Some(execs) = &execs {
+                                if execs.try_send(e.pid).is_err() {
+                                    metrics::global().incr("qks_intel_execs_dropped_total", &[]);
+                                }
+                            }
+                        }
+                        SecurityEvent::Integrity(e) => {
+                            if let Some(actual) = &e.actual {
+                                intel.check_digest(&bus, &e.source, e.pid, e.path.as_deref(), actual);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }),
+        ));
+        tasks
+    }
+
+    // Hashing is file I/O; one blocking thread takes execs in turn
+    fn hash_execs(self: Arc<Self>, mut execs: mpsc::Receiver<u32>, bus: Arc<EventBus>) -> JoinHandle<()> {
+        tokio::task::spawn_blocking(move || {
+            while let Some(pid) = execs.blocking_recv() {
+                self.check_exec(&bus, pid);
+            }
+        })
+    }
+
+    pub fn status(&self) -> IntelStatus {
+        let index = self.index();
+        IntelStatus {
+            feeds: self.feeds.iter().map(|feed| feed.status.lock().unwrap().clone()).collect(),
+            addresses: index.addresses.len(),
+            networks: index.networks.iter().map(|(_, _, map)| map.len()).sum(),
+            domains: index.domains,
+            resolved: index.resolved.len(),
+            hashes: index.hashes.len(),
+            refreshed_at: *self.refreshed_at.lock().unwrap(),
+        }
+    }
+}
+
+fn is_exec(event: &SyscallEvent) -> bool {
+    event.retval == 0 && (event.syscall == libc::SYS_execve as u32 || event.syscall == libc::SYS_execveat as u32)
+}
+
+// Sinkholed domains resolve to these
+fn public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => !(v4.is_unspecified() || v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_broadcast()),
+        IpAddr::V6(v6) => !(v6.is_unspecified() || v6.is_loopback()),
+    }
+}
+
+// md5, sha1 and sha256 in one pass, as algorithm:hex
+fn digests_of(path: &str) -> std::io::Result<[String; 3]> {
+    let mut file = fs::File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha1 = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+    let mut sha256 = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buf[..n]);
+        sha1.update(&buf[..n]);
+        sha256.update(&buf[..n]);
+    }
+    let hex = crate::audit_log::hex;
+    Ok([
+        format!("md5:{}", hex(&md5.finalize())),
+        format!("sha1:{}", hex(sha1.finish().as_ref())),
+        format!("sha256:{}", hex(sha256.finish().as_ref())),
+    ])
+}
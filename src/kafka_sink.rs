@@ -0,0 +1,165 @@
+// src/kafka_sink.rs
+// Streams bus events to Kafka for fleet-wide pipelines, with the `kafka`
+// feature (librdkafka). Each event goes to the topic for its kind, `topic`
+// with {kind} filled in unless `topics` names one, keyed by host and PID
+// ("web-01:4242") so a process's events stay in one partition and in
+// order. The producer is idempotent with acks=all: once a batch is
+// acknowledged every event is on all in-sync replicas, exactly once per
+// producer session. What the brokers don't acknowledge within
+// `delivery_timeout_secs` is handed back to event_forward.rs to spool and
+// retry, so across restarts delivery is at least once. Batches are
+// compressed with `compression`; `properties` are passed to librdkafka
+// as-is (security.protocol, sasl.*, ssl.*).
+use crate::config::KafkaSection;
+use crate::event_forward::{Delivery, Destination, ForwardError, SendError};
+use crate::events::SecurityEvent;
+use crate::siem_format;
+use crate::syslog_sink;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    #[default]
+    Zstd,
+}
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+// One message as it sits in a batch (and in the spool)
+#[derive(Serialize, Deserialize)]
+struct Record {
+    topic: String,
+    key: String,
+    payload: String,
+}
+
+pub struct Kafka {
+    section: KafkaSection,
+    host: String,
+    #[cfg(feature = "kafka")]
+    producer: rdkafka::producer::FutureProducer,
+}
+
+impl Kafka {
+    #[cfg(feature = "kafka")]
+    pub fn new(section: &KafkaSection) -> Result<Self, ForwardError> {
+        let mut config = rdkafka::ClientConfig::new();
+        config
+            .set("bootstrap.servers", section.brokers.join(","))
+            .set("enable.idempotence", "true")
+            .set("acks", "all")
+            .set("compression.type", section.compression.as_str())
+            .set("message.timeout.ms", (section.delivery_timeout_secs * 1000).to_string())
+            // Batches arrive whole from event_forward; don't wait for more
+            .set("linger.ms", "5");
+        for (name, value) in &section.properties {
+            config.set(name, value);
+        }
+        let producer = config.create().map_err(|e| ForwardError::Client(e.to_string()))?;
+        Ok(Self { section: section.clone(), host: syslog_sink::hostname(), producer })
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    pub fn new(_section: &KafkaSection) -> Result<Self, ForwardError> {
+        Err(ForwardError::Unsupported("kafka"))
+    }
+
+    fn topic(&self, event: &SecurityEvent) -> String {
+        let kind = event.kind().as_str();
+        self.section.topics.get(kind).cloned().unwrap_or_else(|| self.section.topic.replace("{kind}", kind))
+    }
+
+    #[cfg(feature = "kafka")]
+    async fn produce(&self, batch: &[String]) -> Result<(), SendError> {
+        use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+        use rdkafka::producer::FutureRecord;
+
+        // Messages the brokers could never take; anything else may pass
+        let permanent = |e: &KafkaError| {
+            matches!(
+                e.rdkafka_error_code(),
+                Some(RDKafkaErrorCode::MessageSizeTooLarge | RDKafkaErrorCode::InvalidMessage | RDKafkaErrorCode::InvalidMessageSize | RDKafkaErrorCode::InvalidRecord)
+            )
+        };
+
+        // Everything is queued before anything is awaited so librdkafka can
+        // batch and compress across the whole lot
+        let mut deliveries = Vec::with_capacity(batch.len());
+        for (i, line) in batch.iter().enumerate() {
+            let Ok(record) = serde_json::from_str::<Record>(line) else {
+                deliveries.push((i, None));
+                continue;
+            };
+            let message = FutureRecord::to(&record.topic).key(&record.key).payload(&record.payload);
+            deliveries.push((i, Some(self.producer.send_result(message).map_err(|(e, _)| e))));
+        }
+
+        let (mut retry, mut rejected, mut reason) = (Vec::new(), 0, String::new());
+        for (i, delivery) in deliveries {
+            let error = match delivery {
+                None => Some((true, "unreadable spooled record".to_string())),
+                Some(Err(e)) => Some((permanent(&e), e.to_string())),
+                Some(Ok(future)) => match future.await {
+                    Ok(Ok(_)) => None,
+                    Ok(Err((e, _))) => Some((permanent(&e), e.to_string())),
+                    Err(_) => Some((false, "producer shut down".to_string())),
+                },
+            };
+            let Some((permanent, error)) = error else {
+                continue;
+            };
+            if reason.is_empty() {
+                reason = error;
+            }
+            if permanent {
+                rejected += 1;
+            } else {
+                retry.push(batch[i].clone());
+            }
+        }
+        if retry.is_empty() && rejected == 0 {
+            Ok(())
+        } else {
+            Err(SendError::Partial { retry, rejected, reason })
+        }
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    async fn produce(&self, _batch: &[String]) -> Result<(), SendError> {
+        Err(SendError::Reject(ForwardError::Unsupported("kafka").to_string()))
+    }
+}
+
+impl Destination for Kafka {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn encode(&self, event: &SecurityEvent) -> Option<String> {
+        let key = match event.pid() {
+            Some(pid) => format!("{}:{}", self.host, pid),
+            None => self.host.clone(),
+        };
+        let payload = siem_format::render(self.section.format, event, &self.host);
+        serde_json::to_string(&Record { topic: self.topic(event), key, payload }).ok()
+    }
+
+    fn send<'a>(&'a self, batch: &'a [String]) -> Delivery<'a> {
+        Box::pin(self.produce(batch))
+    }
+}
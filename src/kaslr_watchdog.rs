@@ -0,0 +1,263 @@
+// src/kaslr_watchdog.rs
+// Watches for ways user space can learn kernel addresses. Once any of these
+// is open, kernel-side randomization buys nothing, so findings are alerted
+// when they first appear rather than on every sweep.
+use crate::privsep::{self, ProcFile};
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// x86-64 kernel text and modules live in the top 2GB
+const KERNEL_TEXT_START: u64 = 0xFFFF_FFFF_8000_0000;
+// Per-process cap on memory inspected in one sweep
+const MAX_SCAN_BYTES: u64 = 16 * 1024 * 1024;
+// A single stray value is noise; this many distinct ones is a leak
+const MIN_KERNEL_POINTERS: usize = 4;
+
+// Files that hand out kernel symbol addresses when readable
+const SYMBOL_SOURCES: [&str; 4] = ["/proc/kallsyms", "/sys/kernel/notes", "/dev/kmsg", "/proc/modules"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+pub enum LeakFinding {
+    KptrRestrictDisabled,
+    DmesgUnrestricted,
+    KallsymsWorldReadable,
+    SystemMapReadable { path: String },
+    PerfEventUnrestricted { level: i32 },
+    SymbolSourceOpen { pid: u32, path: String },
+    KernelPointersInMemory { pid: u32, count: usize, sample: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum LeakSeverity {
+    // Weakens KASLR; an attacker needs another primitive
+    Warning,
+    // Kernel addresses are already in unprivileged hands
+    Defeated,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KaslrAlert {
+    pub finding: LeakFinding,
+    pub severity: LeakSeverity,
+    pub detected_at: u64,
+}
+
+pub struct KaslrWatchdog {
+    watched: Arc<DashMap<u32, ()>>,
+    reported: Arc<DashMap<LeakFinding, ()>>,
+    alerts: mpsc::UnboundedSender<KaslrAlert>,
+}
+
+impl KaslrWatchdog {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<KaslrAlert>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let watchdog = Self {
+            watched: Arc::new(DashMap::new()),
+            reported: Arc::new(DashMap::new()),
+            alerts: tx,
+        };
+
+        (watchdog, rx)
+    }
+
+    pub fn watch(&self, pid: u32) {
+        self.watched.insert(pid, ());
+    }
+
+    pub fn unwatch(&self, pid: u32) {
+        self.watched.remove(&pid);
+        self.reported.retain(|finding, _| Self::finding_pid(finding) != Some(pid));
+    }
+
+    // One pass over host settings and watched processes
+    pub fn sweep(&self) -> Vec<LeakFinding> {
+        let pids: Vec<u32> = self.watched.iter().map(|e| *e.key()).collect();
+        Self::run_sweep(&pids, &self.reported, &self.alerts)
+    }
+
+    pub fn start(&self, interval_secs: u64) -> tokio::task::JoinHandle<()> {
+        let watched = self.watched.clone();
+        let reported = self.reported.clone();
+        let alerts = self.alerts.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let pids: Vec<u32> = watched.iter().map(|e| *e.key()).collect();
+                // Memory scans block; keep them off the async workers
+                let reported = reported.clone();
+                let alerts = alerts.clone();
+                let _ = tokio::task::spawn_blocking(move || Self::run_sweep(&pids, &reported, &alerts)).await;
+
+                tokio::time::sleep(tokio::time::Duration::from_secs(interval_secs)).await;
+            }
+        })
+    }
+
+    fn run_sweep(
+        pids: &[u32],
+        reported: &DashMap<LeakFinding, ()>,
+        alerts: &mpsc::UnboundedSender<KaslrAlert>,
+    ) -> Vec<LeakFinding> {
+        let mut findings = Self::host_findings();
+        for &pid in pids {
+            findings.extend(Self::process_findings(pid));
+        }
+
+        let current: HashSet<LeakFinding> = findings.iter().map(Self::dedup_key).collect();
+        // Fixed problems can be reported again if they come back
+        reported.retain(|finding, _| current.contains(finding));
+
+        let exposed = findings.contains(&LeakFinding::KallsymsWorldReadable);
+        for finding in &findings {
+            if reported.insert(Self::dedup_key(finding), ()).is_some() {
+                continue;
+            }
+
+            let severity = match finding {
+                LeakFinding::KallsymsWorldReadable
+                | LeakFinding::SystemMapReadable { .. }
+                | LeakFinding::KernelPointersInMemory { .. } => LeakSeverity::Defeated,
+                LeakFinding::SymbolSourceOpen { .. } if exposed => LeakSeverity::Defeated,
+                _ => LeakSeverity::Warning,
+            };
+            tracing::warn!("KASLR leak ({:?}): {:?}", severity, finding);
+
+            let _ = alerts.send(KaslrAlert {
+                finding: finding.clone(),
+                severity,
+                detected_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            });
+        }
+
+        findings
+    }
+
+    // Scan results vary from sweep to sweep; only the PID identifies them
+    fn dedup_key(finding: &LeakFinding) -> LeakFinding {
+        match finding {
+            LeakFinding::KernelPointersInMemory { pid, .. } => {
+                LeakFinding::KernelPointersInMemory { pid: *pid, count: 0, sample: 0 }
+            }
+            other => other.clone(),
+        }
+    }
+
+    fn host_findings() -> Vec<LeakFinding> {
+        let mut findings = Vec::new();
+        let sysctl = |name: &str| -> Option<i32> {
+            fs::read_to_string(format!("/proc/sys/kernel/{}", name)).ok()?.trim().parse().ok()
+        };
+
+        if sysctl("kptr_restrict") == Some(0) {
+            findings.push(LeakFinding::KptrRestrictDisabled);
+            // kallsyms is 0444 almost everywhere; it only shows real
+            // addresses to unprivileged readers with kptr_restrict=0
+            if Self::world_readable("/proc/kallsyms") {
+                findings.push(LeakFinding::KallsymsWorldReadable);
+            }
+        }
+        if sysctl("dmesg_restrict") == Some(0) {
+            findings.push(LeakFinding::DmesgUnrestricted);
+        }
+        if let Some(level) = sysctl("perf_event_paranoid").filter(|level| *level < 2) {
+            findings.push(LeakFinding::PerfEventUnrestricted { level });
+        }
+        if let Ok(boot) = fs::read_dir("/boot") {
+            for entry in boot.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                let is_system_map = path.file_name()
+                    .and_then(|n| n.to_str())
+                    .map_or(false, |n| n.starts_with("System.map"));
+                if is_system_map && Self::world_readable(&path.to_string_lossy()) {
+                    findings.push(LeakFinding::SystemMapReadable { path: path.to_string_lossy().into_owned() });
+                }
+            }
+        }
+
+        findings
+    }
+
+    fn process_findings(pid: u32) -> Vec<LeakFinding> {
+        let mut findings = Vec::new();
+
+        if let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", pid)) {
+            for fd in fds.filter_map(|e| e.ok()) {
+                let target = match fs::read_link(fd.path()) {
+                    Ok(target) => target.to_string_lossy().into_owned(),
+                    Err(_) => continue,
+                };
+                if SYMBOL_SOURCES.contains(&target.as_str()) || target.starts_with("/boot/System.map") {
+                    findings.push(LeakFinding::SymbolSourceOpen { pid, path: target });
+                }
+            }
+        }
+
+        if let Some((count, sample)) = Self::scan_for_kernel_pointers(pid) {
+            findings.push(LeakFinding::KernelPointersInMemory { pid, count, sample });
+        }
+
+        findings
+    }
+
+    // Kernel text addresses sitting in a process's private writable memory
+    // mean something handed it kernel pointers
+    fn scan_for_kernel_pointers(pid: u32) -> Option<(usize, u64)> {
+        let maps = crate::process_maps::ProcessMaps::read(pid).ok()?;
+        let mem = privsep::open_proc(pid, ProcFile::Mem).ok()?;
+
+        let mut seen = HashSet::new();
+        let mut budget = MAX_SCAN_BYTES;
+
+        for entry in maps.entries.iter().filter(|e| e.writable() && e.readable() && e.inode == 0) {
+            // [vvar] and friends are kernel-managed and may hold such values
+            if entry.path.as_deref().map_or(false, |p| p.starts_with('[') && p != "[heap]" && p != "[stack]") {
+                continue;
+            }
+            let len = entry.len().min(budget);
+            if len == 0 {
+                break;
+            }
+            budget -= len;
+
+            let mut buffer = vec![0u8; len as usize];
+            if mem.read_exact_at(&mut buffer, entry.start).is_err() {
+                continue;
+            }
+            for word in buffer.chunks_exact(8) {
+                let value = u64::from_ne_bytes(word.try_into().unwrap());
+                // -1 and small negative sentinels are common and meaningless
+                if value >= KERNEL_TEXT_START && value < 0xFFFF_FFFF_FFFF_0000 {
+                    seen.insert(value);
+                }
+            }
+        }
+
+        if seen.len() < MIN_KERNEL_POINTERS {
+            return None;
+        }
+        let sample = *seen.iter().min()?;
+        Some((seen.len(), sample))
+    }
+
+    fn world_readable(path: &str) -> bool {
+        fs::metadata(path)
+            .map(|m| m.permissions().mode() & 0o004 != 0)
+            .unwrap_or(false)
+    }
+
+    fn finding_pid(finding: &LeakFinding) -> Option<u32> {
+        match finding {
+            LeakFinding::SymbolSourceOpen { pid, .. } | LeakFinding::KernelPointersInMemory { pid, .. } => Some(*pid),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// src/kernel_modules.rs
+// Checks the kernel modules that are loaded, however and whenever they got
+// there, rather than watching loads: a module loaded before qksd started is
+// covered the same as one loaded since. Each module in /proc/modules is
+// matched through the running kernel's modules.dep to its file, whose md5 is
+// compared with the package that installed it (dpkg.rs), and whose appended
+// signature is looked for after decompressing it. The kernel's own taint
+// flags for the module say whether it was loaded unsigned or out of tree.
+//
+// A loaded module with no file in the module tree, a file that no longer
+// matches its package, or an unsigned module is critical. Out-of-tree and
+// unpackaged modules (DKMS builds, vendor drivers) are warnings, and not
+// raised at all for the modules in `allowed`. Findings are raised when they
+// appear and again if they come back. When the eBPF monitor sees a
+// successful init_module or finit_module, the check runs straight away
+// instead of waiting for the interval.
+use crate::config::ModulesSection;
+use crate::dpkg::{self, Owned};
+use crate::events::{EventBus, IntegrityEvent, SecurityEvent};
+use crate::metrics;
+use crate::systemd;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+const PROC_MODULES: &str = "/proc/modules";
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+// What scripts/sign-file appends after the signature
+const SIGNATURE_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ModuleError {
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoadedModule {
+    pub name: String,
+    // None when modules.dep doesn't list it
+    pub file: Option<PathBuf>,
+    pub package: Option<String>,
+    // The file's md5 and the one its package recorded; None when unpackaged
+    pub md5: Option<String>,
+    pub package_md5: Option<String>,
+    // Whether the file carries a signature; None when it can't be read
+    pub signed: Option<bool>,
+    // The kernel's taint letters for this module, e.g. "OE"
+    pub taint: String,
+}
+
+impl LoadedModule {
+    // (finding, critical, detail) for each way the module is not as it
+    // should be
+    fn problems(&self, allowed: bool) -> Vec<(&'static str, bool, String)> {
+        let mut found = Vec::new();
+        if self.file.is_none() {
+            found.push(("module_file_missing", true, "loaded, but not in the running kernel's module tree".to_string()));
+        }
+        if self.package_md5.is_some() && self.md5 != self.package_md5 {
+            found.push(("module_modified", true, format!("file differs from package {}", self.package.as_deref().unwrap_or("?"))));
+        }
+        if self.taint.contains('E') || self.signed == Some(false) {
+            found.push(("module_unsigned", true, format!("{} without a valid signature", if self.taint.contains('E') { "loaded" } else { "file is" })));
+        }
+        if !allowed && self.taint.contains('O') {
+            found.push(("module_out_of_tree", false, "built outside the kernel tree".to_string()));
+        }
+        if !allowed && self.file.is_some() && self.package.is_none() {
+            found.push(("module_unpackaged", false, "file installed by no package".to_string()));
+        }
+        found
+    }
+}
+
+// Name -> file, for the running kernel; names as the kernel has them, with
+// '-' turned into '_'
+fn module_files(release: &str) -> Result<HashMap<String, PathBuf>, ModuleError> {
+    let tree = Path::new("/lib/modules").join(release);
+    let path = tree.join("modules.dep");
+    let deps = std::fs::read_to_string(&path).map_err(|source| ModuleError::Io { path, source })?;
+    Ok(deps
+        .lines()
+        .filter_map(|line| {
+            let (file, _) = line.split_once(':')?;
+            let base = Path::new(file).file_name()?.to_str()?;
+            let name = base.split_once(".ko")?.0.replace('-', "_");
+            Some((name, tree.join(file)))
+        })
+        .collect())
+}
+
+// Whether the file ends in a module signature, decompressing it first when
+// it is compressed; None for formats that can't be read here
+fn has_signature(path: &Path) -> Option<bool> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut contents = Vec::new();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ko") => std::io::BufReader::new(file).read_to_end(&mut contents).ok()?,
+        Some("xz") => xz2::read::XzDecoder::new(file).read_to_end(&mut contents).ok()?,
+        Some("zst") => zstd::stream::read::Decoder::new(file).ok()?.read_to_end(&mut contents).ok()?,
+        _ => return None,
+    };
+    Some(contents.ends_with(SIGNATURE_MAGIC))
+}
+
+// The kernel's taint letters for a module; empty when untainted
+fn module_taint(name: &str) -> String {
+    std::fs::read_to_string(format!("/sys/module/{}/taint", name)).map(|taint| taint.trim().to_string()).unwrap_or_default()
+}
+
+pub struct ModuleMonitor {
+    section: ModulesSection,
+    modules: Mutex<Vec<LoadedModule>>,
+    // The module tree's files dpkg knows about, as of the info directory's
+    // mtime
+    manifest: Mutex<Option<(SystemTime, HashMap<String, Owned>)>>,
+    // (module, finding) reported and not cleared since
+    standing: Mutex<HashSet<(String, &'static str)>>,
+    loads: Arc<Notify>,
+}
+
+impl ModuleMonitor {
+    // `loads` is notified by whoever sees a module being loaded
+    pub fn new(section: &ModulesSection, loads: Arc<Notify>) -> Self {
+        Self {
+            section: section.clone(),
+            modules: Mutex::new(Vec::new()),
+            manifest: Mutex::new(None),
+            standing: Mutex::new(HashSet::new()),
+            loads,
+        }
+    }
+
+    // As of the last check
+    pub fn modules(&self) -> Vec<LoadedModule> {
+        self.modules.lock().unwrap().clone()
+    }
+
+    // Owners of the files under the module trees, reread when a package
+    // has been installed or removed since
+    fn owners(&self) -> Result<HashMap<String, Owned>, ModuleError> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let changed = dpkg::last_change().unwrap_or(SystemTime::UNIX_EPOCH);
+        if let Some((_, owned)) = manifest.as_ref().filter(|(read_at, _)| *read_at == changed) {
+            return Ok(owned.clone());
+        }
+        let owned = dpkg::owned_files(|path| path.starts_with("/usr/lib/modules/"))
+            .map_err(|source| ModuleError::Io { path: PathBuf::from(dpkg::INFO_DIR), source })?;
+        *manifest = Some((changed, owned.clone()));
+        Ok(owned)
+    }
+
+    // Every loaded module, matched to its file and package
+    pub fn inspect(&self) -> Result<Vec<LoadedModule>, ModuleError> {
+        let release = std::fs::read_to_string(OSRELEASE).map_err(|source| ModuleError::Io { path: PathBuf::from(OSRELEASE), source })?;
+        let files = module_files(release.trim())?;
+        let owners = self.owners()?;
+        let loaded = std::fs::read_to_string(PROC_MODULES).map_err(|source| ModuleError::Io { path: PathBuf::from(PROC_MODULES), source })?;
+
+        let mut modules = Vec::new();
+        // "<name> <size> <refcount> <deps> <state> <address> [(taint)]"
+        for name in loaded.lines().filter_map(|line| line.split_whitespace().next()) {
+            let file = files.get(name).cloned();
+            let owner = file.as_ref().and_then(|file| owners.get(&dpkg::merged_path(&file.to_string_lossy())));
+            // An unreadable file counts as not matching
+            let md5 = owner.and(file.as_deref()).map(|file| dpkg::file_md5(file).unwrap_or_default());
+            modules.push(LoadedModule {
+                name: name.to_string(),
+                signed: file.as_deref().and_then(has_signature),
+                package: owner.map(|owner| owner.package.clone()),
+                md5,
+                package_md5: owner.map(|owner| owner.md5.clone()),
+                file,
+                taint: module_taint(name),
+            });
+        }
+        Ok(modules)
+    }
+
+    // Inspects the loaded modules and returns what newly went wrong
+    pub fn check(&self) -> Result<Vec<IntegrityEvent>, ModuleError> {
+        let modules = self.inspect()?;
+        let problems: Vec<(&LoadedModule, Vec<(&'static str, bool, String)>)> =
+            modules.iter().map(|module| (module, module.problems(self.section.allowed.contains(&module.name)))).collect();
+        let mut standing = self.standing.lock().unwrap();
+        let current: HashSet<(String, &'static str)> =
+            problems.iter().flat_map(|(module, found)| found.iter().map(|(finding, _, _)| (module.name.clone(), *finding))).collect();
+        standing.retain(|problem| current.contains(problem));
+
+        let mut findings = Vec::new();
+        for (module, found) in problems {
+            for (finding, critical, detail) in found {
+                if !standing.insert((module.name.clone(), finding)) {
+                    continue;
+                }
+                if critical {
+                    tracing::error!("Kernel module {}: {}", module.name, detail);
+                } else {
+                    tracing::warn!("Kernel module {}: {}", module.name, detail);
+                }
+                metrics::global().incr("qks_integrity_findings_total", &[("source", "kmod"), ("finding", finding)]);
+                let modified = finding == "module_modified";
+                findings.push(IntegrityEvent {
+                    source: "kmod".to_string(),
+                    finding: finding.to_string(),
+                    critical,
+                    path: Some(module.file.as_ref().map_or_else(|| module.name.clone(), |file| file.display().to_string())),
+                    pid: None,
+                    expected: module.package_md5.clone().filter(|_| modified),
+                    actual: module.md5.clone().filter(|_| modified),
+                    detail: Some(detail),
+                    timestamp: systemd::now_secs(),
+                });
+            }
+        }
+        drop(standing);
+        *self.modules.lock().unwrap() = modules;
+        Ok(findings)
+    }
+
+    // Checks at once, then every interval_secs and after every module load
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.section.interval_secs));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = self.loads.notified() => {}
+                }
+                let monitor = self.clone();
+                match tokio::task::spawn_blocking(move || monitor.check()).await {
+                    Ok(Ok(findings)) => {
+                        for finding in findings {
+                            bus.publish(SecurityEvent::Integrity(finding));
+                        }
+                    }
+                    Ok(Err(e)) => tracing::warn!("Kernel modules not checked: {}", e),
+                    Err(e) => tracing::error!("Kernel module check panicked: {}", e),
+                }
+            }
+        })
+    }
+}
+
+// Whether a syscall event is a module load that succeeded
+pub fn is_module_load(syscall: u32, retval: i32) -> bool {
+    retval == 0 && (syscall as libc::c_long == libc::SYS_init_module || syscall as libc::c_long == libc::SYS_finit_module)
+}
@@ -0,0 +1,278 @@
+// src/kubernetes.rs
+// DaemonSet mode: per-pod policy from pod annotations. Every [kubernetes]
+// sync_secs the pods on this node are listed from the kubelet's /pods
+// endpoint, authenticating with the service account token (re-read each
+// time, as projected tokens rotate). Three annotations select how a pod is
+// treated, each falling back to the section's default when absent or
+// invalid:
+//
+//   monitoring.qks.io/level    = "off" | "standard" | "strict"
+//   randomization.qks.io/profile = "full" | "partial" | "vdso-only" | "excluded"
+//   response.qks.io/mode       = "enforce" | "dry-run" | "log-only"
+//
+// `off` processes are not scored, `strict` ones alert at a lower score;
+// the profile sits between container annotations and the per-binary
+// policy; the mode decides what policy rules may do to the pod's processes.
+// Operator-requested responses are never downgraded.
+//
+// The pods' containers also go into the container registry, so every event
+// about a process in one is labelled with namespace, pod and container even
+// without the containerd watcher.
+use crate::config::KubernetesSection;
+use crate::container_events::{self, ContainerInfo};
+use crate::container_exclusions::PROFILE_ANNOTATION;
+use crate::metrics;
+use crate::randomization_policy::RandomizationProfile;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+pub const MONITORING_ANNOTATION: &str = "monitoring.qks.io/level";
+pub const RESPONSE_ANNOTATION: &str = "response.qks.io/mode";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Strict pods alert at this fraction of the usual threshold
+const STRICT_THRESHOLD_SCALE: f32 = 0.75;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MonitoringLevel {
+    Off,
+    #[default]
+    Standard,
+    Strict,
+}
+
+impl MonitoringLevel {
+    // The alert threshold for a process at this level; None when it is not
+    // watched at all
+    pub fn threshold(self, threshold: f32) -> Option<f32> {
+        match self {
+            MonitoringLevel::Off => None,
+            MonitoringLevel::Standard => Some(threshold),
+            MonitoringLevel::Strict => Some(threshold * STRICT_THRESHOLD_SCALE),
+        }
+    }
+}
+
+// What policy rules may do to a pod's processes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseMode {
+    #[default]
+    Enforce,
+    // Record what would have been done
+    DryRun,
+    // Match and log, never act
+    LogOnly,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KubeletError {
+    #[error("cannot read service account token {}: {source}", .path.display())]
+    Token { path: std::path::PathBuf, source: std::io::Error },
+    #[error("kubelet: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PodPolicy {
+    pub monitoring: MonitoringLevel,
+    // None leaves it to container annotations and the binary policy
+    pub randomization: Option<RandomizationProfile>,
+    pub response: ResponseMode,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PodEntry {
+    pub namespace: String,
+    pub name: String,
+    pub uid: String,
+    pub policy: PodPolicy,
+}
+
+// The kubelet's PodList, as far as it is read here
+#[derive(Deserialize)]
+struct PodList {
+    #[serde(default)]
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: PodMetadata,
+    #[serde(default)]
+    status: PodStatus,
+}
+
+#[derive(Deserialize)]
+struct PodMetadata {
+    name: String,
+    namespace: String,
+    uid: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PodStatus {
+    #[serde(default)]
+    container_statuses: Vec<ContainerStatus>,
+    #[serde(default)]
+    init_container_statuses: Vec<ContainerStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerStatus {
+    name: String,
+    #[serde(default)]
+    image: String,
+    // <runtime>://<id>; missing until the container has been created
+    #[serde(default, rename = "containerID")]
+    container_id: String,
+}
+
+// Policy for each pod on this node, by pod UID
+#[derive(Default)]
+pub struct PodPolicies {
+    pods: DashMap<String, Arc<PodEntry>>,
+}
+
+static GLOBAL: OnceLock<PodPolicies> = OnceLock::new();
+
+pub fn global() -> &'static PodPolicies {
+    GLOBAL.get_or_init(PodPolicies::default)
+}
+
+impl PodPolicies {
+    // The pod the process runs in, if it is one on this node
+    pub fn for_pid(&self, pid: u32) -> Option<Arc<PodEntry>> {
+        if self.pods.is_empty() {
+            return None;
+        }
+        let pod = container_events::global().for_pid(pid)?.pod.clone()?;
+        self.pods.get(&pod.uid).map(|entry| entry.clone())
+    }
+
+    // Processes outside any pod are monitored as usual
+    pub fn monitoring_for(&self, pid: u32) -> MonitoringLevel {
+        self.for_pid(pid).map_or(MonitoringLevel::Standard, |entry| entry.policy.monitoring)
+    }
+
+    pub fn randomization_for(&self, pid: u32) -> Option<RandomizationProfile> {
+        self.for_pid(pid)?.policy.randomization
+    }
+
+    pub fn response_for(&self, pid: u32) -> ResponseMode {
+        self.for_pid(pid).map_or(ResponseMode::Enforce, |entry| entry.policy.response)
+    }
+
+    pub fn list(&self) -> Vec<Arc<PodEntry>> {
+        self.pods.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    fn replace(&self, current: Vec<PodEntry>) {
+        let uids: HashSet<String> = current.iter().map(|entry| entry.uid.clone()).collect();
+        // In place, so a pod never briefly loses its policy mid-sync
+        self.pods.retain(|uid, _| uids.contains(uid));
+        for entry in current {
+            self.pods.insert(entry.uid.clone(), Arc::new(entry));
+        }
+    }
+}
+
+pub struct PodWatcher {
+    section: KubernetesSection,
+    client: reqwest::Client,
+}
+
+impl PodWatcher {
+    pub fn new(section: &KubernetesSection) -> Result<Self, KubeletError> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            // Kubelet serving certificates are often self-signed
+            .danger_accept_invalid_certs(!section.verify_tls)
+            .build()?;
+        Ok(Self { section: section.clone(), client })
+    }
+
+    pub fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.section.sync_secs));
+            loop {
+                interval.tick().await;
+                match self.sync().await {
+                    Ok(count) => tracing::debug!("Pod policies synced: {}", count),
+                    Err(e) => {
+                        metrics::global().incr("qks_kubelet_sync_failures_total", &[]);
+                        tracing::warn!("{}; keeping the last pod list", e);
+                    }
+                }
+            }
+        })
+    }
+
+    async fn sync(&self) -> Result<usize, KubeletError> {
+        let token = std::fs::read_to_string(&self.section.token_file)
+            .map_err(|source| KubeletError::Token { path: self.section.token_file.clone(), source })?;
+        let url = format!("{}/pods", self.section.kubelet_url.trim_end_matches('/'));
+        let pods: PodList = self.client.get(url).bearer_auth(token.trim()).send().await?.error_for_status()?.json().await?;
+
+        let defaults = PodPolicy { monitoring: self.section.monitoring, randomization: self.section.randomization, response: self.section.response };
+        let mut entries = Vec::with_capacity(pods.items.len());
+        let mut containers = Vec::new();
+        for pod in pods.items {
+            let known = global().pods.contains_key(&pod.metadata.uid);
+            let policy = Self::policy(&pod.metadata, &defaults, !known);
+            for status in pod.status.init_container_statuses.iter().chain(&pod.status.container_statuses) {
+                if let Some(container) = Self::container(&pod.metadata, status) {
+                    containers.push(container);
+                }
+            }
+            entries.push(PodEntry { namespace: pod.metadata.namespace, name: pod.metadata.name, uid: pod.metadata.uid, policy });
+        }
+        let count = entries.len();
+        container_events::global().replace_known(containers);
+        global().replace(entries);
+        Ok(count)
+    }
+
+    // Invalid values are only reported the first time the pod is seen
+    fn policy(metadata: &PodMetadata, defaults: &PodPolicy, report: bool) -> PodPolicy {
+        fn annotation<T: serde::de::DeserializeOwned>(metadata: &PodMetadata, key: &str, report: bool) -> Option<T> {
+            let value = metadata.annotations.get(key)?;
+            match serde_json::from_value(serde_json::Value::String(value.clone())) {
+                Ok(parsed) => Some(parsed),
+                Err(_) => {
+                    if report {
+                        tracing::warn!("Pod {}/{}: ignoring invalid {} value {:?}", metadata.namespace, metadata.name, key, value);
+                    }
+                    None
+                }
+            }
+        }
+        PodPolicy {
+            monitoring: annotation(metadata, MONITORING_ANNOTATION, report).unwrap_or(defaults.monitoring),
+            randomization: annotation(metadata, PROFILE_ANNOTATION, report).or(defaults.randomization),
+            response: annotation(metadata, RESPONSE_ANNOTATION, report).unwrap_or(defaults.response),
+        }
+    }
+
+    fn container(metadata: &PodMetadata, status: &ContainerStatus) -> Option<ContainerInfo> {
+        let (runtime, id) = status.container_id.split_once("://")?;
+        // The namespace the CRI plugin creates containers in
+        let namespace = if runtime == "containerd" { "k8s.io" } else { runtime };
+        let mut labels = metadata.labels.clone();
+        labels.insert(container_events::POD_NAME_LABEL.to_string(), metadata.name.clone());
+        labels.insert(container_events::POD_NAMESPACE_LABEL.to_string(), metadata.namespace.clone());
+        labels.insert(container_events::POD_UID_LABEL.to_string(), metadata.uid.clone());
+        labels.insert(container_events::CONTAINER_NAME_LABEL.to_string(), status.name.clone());
+        Some(ContainerInfo::new(id.to_string(), namespace.to_string(), status.image.clone(), labels))
+    }
+}
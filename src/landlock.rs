@@ -0,0 +1,239 @@
+// src/landlock.rs
+// Starts a process confined by Landlock to what its token grants, so a
+// token's FilesystemAccess capabilities are enforced by the kernel from
+// exec onwards instead of only being checked when someone asks. Each
+// FilesystemAccess prefix becomes a path-beneath rule with every right the
+// running kernel's ABI handles; everything else on the filesystem is denied
+// except read and execute under RUNTIME_PATHS, which the program needs to
+// be loaded at all. Without NetworkAccess, TCP bind and connect are denied
+// too, on kernels whose ABI (4+) can express that. A token naming syscalls
+// also gets their seccomp filter (seccomp.rs), installed after the ruleset.
+//
+// The ruleset is built in the parent and only applied between fork and
+// exec, which keeps the child's pre-exec work to two syscalls. Checking
+// that the token is genuine and unrevoked is the caller's job.
+use crate::crypto_identifiers::{Capability, ProcessToken};
+use crate::seccomp::{DenyAction, Filter, SeccompError};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+// Read-only and executable for every confined process: the loader, shared
+// libraries and the program itself when it lives under /usr, plus what
+// libc and most runtimes read at startup (/dev/urandom for hash seeds and
+// stack protectors without getrandom, /proc/self for maps, limits and
+// the executable's own path)
+const RUNTIME_PATHS: &[&str] = &["/usr", "/lib", "/lib64", "/etc/ld.so.cache", "/dev/null", "/dev/urandom", "/proc"];
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+// Filesystem rights by the ABI version that introduced them
+const FS_EXECUTE: u64 = 1 << 0;
+const FS_WRITE_FILE: u64 = 1 << 1;
+const FS_READ_FILE: u64 = 1 << 2;
+const FS_READ_DIR: u64 = 1 << 3;
+const FS_V1: u64 = (1 << 13) - 1;
+const FS_REFER: u64 = 1 << 13;
+const FS_TRUNCATE: u64 = 1 << 14;
+const FS_IOCTL_DEV: u64 = 1 << 15;
+// Rights that apply to a rule on a regular file rather than a directory
+const FS_FILE: u64 = FS_EXECUTE | FS_WRITE_FILE | FS_READ_FILE | FS_TRUNCATE | FS_IOCTL_DEV;
+
+const NET_BIND_TCP: u64 = 1 << 0;
+const NET_CONNECT_TCP: u64 = 1 << 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LandlockError {
+    #[error("Landlock is not available on this kernel (needs 5.13 with lsm=landlock)")]
+    Unsupported,
+    #[error("{call}: {source}")]
+    Syscall { call: &'static str, source: io::Error },
+    #[error("{}: {source}", .path.display())]
+    Path { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Seccomp(#[from] SeccompError),
+    #[error("failed to launch target: {0}")]
+    Spawn(io::Error),
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    // ABI 4 and later; left out of the size passed to older kernels
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// The ABI version the kernel speaks, None when Landlock is off
+pub fn abi_version() -> Option<u32> {
+    // SAFETY: a NULL attribute with size 0 and the version flag only queries
+    let ret = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0usize, CREATE_RULESET_VERSION) };
+    (ret > 0).then_some(ret as u32)
+}
+
+fn handled_fs(abi: u32) -> u64 {
+    let mut handled = FS_V1;
+    if abi >= 2 {
+        handled |= FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= FS_IOCTL_DEV;
+    }
+    handled
+}
+
+// What a spawned process ends up confined to, for logging and replies
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Confinement {
+    pub abi: u32,
+    pub writable: Vec<PathBuf>,
+    pub network_restricted: bool,
+    // Syscalls the seccomp filter allows; None when unfiltered
+    pub syscalls: Option<usize>,
+}
+
+// A ruleset ready to be applied to the next process spawned
+pub struct Ruleset {
+    fd: OwnedFd,
+    confinement: Confinement,
+}
+
+impl Ruleset {
+    pub fn from_token(token: &ProcessToken) -> Result<Self, LandlockError> {
+        let abi = abi_version().ok_or(LandlockError::Unsupported)?;
+        let handled_access_fs = handled_fs(abi);
+        let network = token.capabilities.iter().any(|capability| matches!(capability, Capability::NetworkAccess));
+        let mut ruleset = Self::create(abi, !network && abi >= 4)?;
+
+        for path in RUNTIME_PATHS.iter().map(Path::new).filter(|path| path.exists()) {
+            ruleset.allow(path, (FS_EXECUTE | FS_READ_FILE | FS_READ_DIR) & handled_access_fs)?;
+        }
+        for capability in &token.capabilities {
+            let Capability::FilesystemAccess(prefix) = capability else {
+                continue;
+            };
+            let path = Path::new(prefix);
+            if !path.exists() {
+                tracing::warn!("Token grants {} but it does not exist; not allowed", prefix);
+                continue;
+            }
+            ruleset.allow(path, handled_access_fs)?;
+            ruleset.confinement.writable.push(path.to_path_buf());
+        }
+        Ok(ruleset)
+    }
+
+    // For qksd itself: read and execute beneath `read`, everything beneath
+    // `write`; paths that don't exist are skipped. The network is left alone.
+    pub fn for_paths(read: &[PathBuf], write: &[PathBuf]) -> Result<Self, LandlockError> {
+        let abi = abi_version().ok_or(LandlockError::Unsupported)?;
+        let handled_access_fs = handled_fs(abi);
+        let mut ruleset = Self::create(abi, false)?;
+        for path in read.iter().filter(|path| path.exists()) {
+            ruleset.allow(path, (FS_EXECUTE | FS_READ_FILE | FS_READ_DIR) & handled_access_fs)?;
+        }
+        for path in write.iter().filter(|path| path.exists()) {
+            ruleset.allow(path, handled_access_fs)?;
+            ruleset.confinement.writable.push(path.clone());
+        }
+        Ok(ruleset)
+    }
+
+    fn create(abi: u32, network_restricted: bool) -> Result<Self, LandlockError> {
+        let attr = RulesetAttr { handled_access_fs: handled_fs(abi), handled_access_net: if network_restricted { NET_BIND_TCP | NET_CONNECT_TCP } else { 0 } };
+        let size = if abi >= 4 { std::mem::size_of::<RulesetAttr>() } else { std::mem::size_of::<u64>() };
+
+        // SAFETY: attr outlives the call and size never exceeds it
+        let ret = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, size, 0u32) };
+        if ret < 0 {
+            return Err(LandlockError::Syscall { call: "landlock_create_ruleset", source: io::Error::last_os_error() });
+        }
+        // SAFETY: the kernel just handed us this descriptor, close-on-exec
+        let fd = unsafe { OwnedFd::from_raw_fd(ret as i32) };
+        Ok(Self { fd, confinement: Confinement { abi, writable: Vec::new(), network_restricted, syscalls: None } })
+    }
+
+    fn allow(&self, path: &Path, access: u64) -> Result<(), LandlockError> {
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|source| LandlockError::Path { path: path.to_path_buf(), source })?;
+        let is_dir = file.metadata().map(|meta| meta.is_dir()).unwrap_or(false);
+        let rule = PathBeneathAttr { allowed_access: if is_dir { access } else { access & FS_FILE }, parent_fd: file.as_raw_fd() };
+
+        // SAFETY: rule outlives the call and its fd stays open until after it
+        let ret = unsafe { libc::syscall(libc::SYS_landlock_add_rule, self.fd.as_raw_fd(), RULE_PATH_BENEATH, &rule as *const PathBeneathAttr, 0u32) };
+        if ret < 0 {
+            return Err(LandlockError::Path { path: path.to_path_buf(), source: io::Error::last_os_error() });
+        }
+        Ok(())
+    }
+
+    pub fn confinement(&self) -> &Confinement {
+        &self.confinement
+    }
+
+    // Confines the calling thread and the threads and processes it starts
+    // from here on; threads already running are not affected
+    pub fn restrict_self(self) -> Result<Confinement, LandlockError> {
+        // SAFETY: no pointers
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+            return Err(LandlockError::Syscall { call: "prctl(PR_SET_NO_NEW_PRIVS)", source: io::Error::last_os_error() });
+        }
+        // SAFETY: no pointers; the ruleset fd is open for the call
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd(), 0u32) } == -1 {
+            return Err(LandlockError::Syscall { call: "landlock_restrict_self", source: io::Error::last_os_error() });
+        }
+        Ok(self.confinement)
+    }
+
+    // Applies the ruleset, then `filter`, to `command`'s child between fork
+    // and exec
+    pub fn spawn(self, command: &mut Command, filter: Option<Filter>) -> Result<Child, LandlockError> {
+        let fd = self.fd.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // Landlock refuses unprivileged callers without this
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::syscall(libc::SYS_landlock_restrict_self, fd, 0u32) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Last, so setting up the ruleset needs no allowed syscalls
+                match &filter {
+                    Some(filter) => filter.install(),
+                    None => Ok(()),
+                }
+            });
+        }
+        // The ruleset fd is dropped here, after the fork has used it
+        command.spawn().map_err(LandlockError::Spawn)
+    }
+}
+
+// Spawns `command` confined to `token`'s filesystem, network and syscall
+// capabilities
+pub fn spawn_confined(command: &mut Command, token: &ProcessToken) -> Result<(Child, Confinement), LandlockError> {
+    let ruleset = Ruleset::from_token(token)?;
+    let filter = Filter::from_token(token, DenyAction::default())?;
+    let mut confinement = ruleset.confinement().clone();
+    confinement.syscalls = filter.as_ref().map(Filter::allowed);
+    let child = ruleset.spawn(command, filter)?;
+    tracing::info!(pid = child.id(), abi = confinement.abi, "Spawned under Landlock with {} writable paths", confinement.writable.len());
+    Ok((child, confinement))
+}
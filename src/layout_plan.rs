@@ -0,0 +1,62 @@
+// src/layout_plan.rs
+use crate::memory_randomizer::MemoryLayout;
+use crate::randomization_policy::RandomizationProfile;
+use std::time::Duration;
+
+// Rough costs of the steps a layout application takes while the target is
+// stopped; good enough to tell a 1ms pause from a 100ms one
+const ATTACH_COST: Duration = Duration::from_micros(1000);
+const REMOTE_SYSCALL_COST: Duration = Duration::from_micros(20);
+const POINTER_PATCH_COST: Duration = Duration::from_micros(2);
+
+#[derive(Debug, Clone)]
+pub struct PlannedMove {
+    pub region: &'static str,
+    pub from: u64,
+    pub to: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExcludedRegion {
+    pub region: &'static str,
+    pub reason: String,
+}
+
+// What `apply_layout_to_process` would do, computed without touching the
+// process or the recorded layout
+#[derive(Debug, Clone)]
+pub struct LayoutPlan {
+    pub pid: u32,
+    pub profile: RandomizationProfile,
+    pub proposed: MemoryLayout,
+    pub moves: Vec<PlannedMove>,
+    pub excluded: Vec<ExcludedRegion>,
+    pub pointer_patches: usize,
+    pub estimated_pause: Duration,
+    // Mean of past applications on this host, when there were any
+    pub observed_mean_pause: Option<Duration>,
+}
+
+impl LayoutPlan {
+    pub fn is_noop(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    // Heap moves set start_brk and brk, stack moves start_stack, on top of
+    // one mremap per region
+    pub fn estimate_pause(moves: &[PlannedMove], pointer_patches: usize) -> Duration {
+        let syscalls: u32 = moves.iter()
+            .map(|mv| match mv.region {
+                "[heap]" => 3,
+                "[stack]" => 2,
+                _ => 1,
+            })
+            .sum();
+
+        if syscalls == 0 {
+            return Duration::ZERO;
+        }
+        ATTACH_COST + REMOTE_SYSCALL_COST * syscalls + POINTER_PATCH_COST * pointer_patches as u32
+    }
+}
@@ -0,0 +1,68 @@
+// src/layout_verification.rs
+use crate::process_maps::{self, ProcessMaps};
+use crate::remap_engine::{RegionKind, RegionMove};
+use std::fmt;
+use std::io;
+
+// One field that didn't end up where the layout said it would
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutMismatch {
+    pub field: &'static str,
+    pub expected: u64,
+    // None when the region is missing altogether
+    pub actual: Option<u64>,
+}
+
+impl fmt::Display for LayoutMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.actual {
+            Some(actual) => write!(f, "{}: expected {:#x}, found {:#x}", self.field, self.expected, actual),
+            None => write!(f, "{}: expected {:#x}, region missing", self.field, self.expected),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LayoutVerificationError {
+    #[error("cannot read back PID {0}: {1}")]
+    Unreadable(u32, io::Error),
+    #[error("PID {pid} layout differs from plan: {}", .mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>().join("; "))]
+    Mismatch { pid: u32, mismatches: Vec<LayoutMismatch> },
+}
+
+// Compare the live address space against the moves that were just applied
+pub fn verify_moves(pid: u32, moves: &[RegionMove]) -> Result<(), LayoutVerificationError> {
+    let maps = ProcessMaps::read(pid).map_err(|e| LayoutVerificationError::Unreadable(pid, e))?;
+    let stat = process_maps::mm_stat(pid).map_err(|e| LayoutVerificationError::Unreadable(pid, e))?;
+    let mut mismatches = Vec::new();
+
+    for mv in moves {
+        let (name, field) = match mv.kind {
+            RegionKind::Heap => ("[heap]", "heap start"),
+            RegionKind::Stack => ("[stack]", "stack start"),
+            // Library moves are checked by their own callers
+            RegionKind::Library => continue,
+        };
+        let new_end = mv.new_start + mv.len;
+
+        let region = maps.named(name);
+        if region.map(|r| r.start) != Some(mv.new_start) {
+            mismatches.push(LayoutMismatch { field, expected: mv.new_start, actual: region.map(|r| r.start) });
+        }
+
+        // The kernel's own bookkeeping has to follow the pages
+        let (stat_field, value) = match mv.kind {
+            RegionKind::Heap => ("start_brk", stat.start_brk),
+            _ => ("start_stack", stat.start_stack),
+        };
+        if value < mv.new_start || value > new_end {
+            mismatches.push(LayoutMismatch { field: stat_field, expected: mv.new_start, actual: Some(value) });
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(LayoutVerificationError::Mismatch { pid, mismatches })
+    }
+}
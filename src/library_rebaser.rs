@@ -0,0 +1,524 @@
+// src/library_rebaser.rs
+// Rebase selected shared libraries of a process we launch ourselves, before
+// its main() runs. x86-64 only, like the remap engine it drives.
+use crate::arch_profile::ArchProfile;
+use crate::privsep::{self, ProcFile};
+use crate::process_maps::{self, MapEntry, ProcessMaps};
+use crate::remap_engine::{PointerPatch, RegionKind, RegionMove, RemapEngine, RemapError};
+use crate::secure_random::{SecureRandomSource, SystemRandomSource};
+use goblin::elf::{dynamic, program_header, reloc, Elf};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+const MAX_PLACEMENT_ATTEMPTS: u32 = 64;
+const AT_ENTRY: u64 = 9;
+const INT3: u64 = 0xCC;
+// Bytes of a link_map searched for its private address fields (l_phdr,
+// l_map_start, l_map_end, l_text_end, l_relro_addr); their offsets differ
+// between glibc releases, and the struct is about this large on x86-64
+const LINK_MAP_SCAN: usize = 1152;
+// After l_addr, l_name, l_ld, l_next and l_prev
+const LINK_MAP_PRIVATE: u64 = 40;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RebaseError {
+    #[error("failed to launch target: {0}")]
+    Spawn(io::Error),
+    #[error("tracing PID {0} failed: {1}")]
+    Trace(u32, String),
+    #[error("cannot parse ELF {0}: {1}")]
+    Elf(String, String),
+    #[error("cannot access memory of PID {0}: {1}")]
+    Memory(u32, io::Error),
+    #[error("no free address range for {0}")]
+    NoRoom(String),
+    #[error("{0} has text relocations and cannot be moved")]
+    TextRelocations(String),
+    #[error(transparent)]
+    Remap(#[from] RemapError),
+}
+
+#[derive(Debug, Clone)]
+pub struct RebasedLibrary {
+    pub path: String,
+    pub old_base: u64,
+    pub new_base: u64,
+    pub patched_pointers: usize,
+}
+
+pub struct LibraryRebaser {
+    // Matched against the library file name, e.g. "libcrypto.so"
+    selected: Vec<String>,
+}
+
+// One ELF object mapped into the target
+struct LoadedModule {
+    path: String,
+    elf_bytes: Vec<u8>,
+    bias: u64,
+    start: u64,
+    end: u64,
+    mappings: Vec<MapEntry>,
+}
+
+impl LibraryRebaser {
+    pub fn new(selected: Vec<String>) -> Self {
+        Self { selected }
+    }
+
+    // Launch `command`, stop it at its ELF entry point (libraries mapped and
+    // relocated, main() not yet running), rebase, then let it go
+    pub fn spawn(&self, command: &mut Command) -> Result<(Child, Vec<RebasedLibrary>), RebaseError> {
+        unsafe {
+            command.pre_exec(|| {
+                if libc::ptrace(libc::PTRACE_TRACEME, 0, std::ptr::null_mut::<libc::c_void>(), std::ptr::null_mut::<libc::c_void>()) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let child = command.spawn().map_err(RebaseError::Spawn)?;
+        let pid = child.id();
+
+        // First stop is the SIGTRAP delivered on exec
+        Self::wait_stop(pid)?;
+        Self::run_to_entry(pid)?;
+
+        // Leave it stopped while the remap engine takes over tracing
+        unsafe {
+            libc::ptrace(libc::PTRACE_DETACH, pid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(), libc::SIGSTOP as usize as *mut libc::c_void)
+        };
+
+        let result = self.rebase(pid);
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGCONT) };
+
+        Ok((child, result?))
+    }
+
+    pub fn rebase(&self, pid: u32) -> Result<Vec<RebasedLibrary>, RebaseError> {
+        let maps = ProcessMaps::read(pid).map_err(|e| RebaseError::Memory(pid, e))?;
+        let modules = Self::load_modules(&maps)?;
+        let arch = ArchProfile::for_pid(pid);
+        let mem = privsep::open_proc(pid, ProcFile::Mem).map_err(|e| RebaseError::Memory(pid, e))?;
+
+        // old start -> (new start, span) for every library being moved
+        let mut placements: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        let mut moves = Vec::new();
+        let mut rebased = Vec::new();
+
+        for module in modules.iter().filter(|m| self.is_selected(&m.path)) {
+            let elf = Self::parse(module)?;
+            if Self::has_text_relocations(&elf) {
+                return Err(RebaseError::TextRelocations(module.path.clone()));
+            }
+
+            let span = module.end - module.start;
+            let new_start = Self::place(&maps, &placements, &arch, span, Self::max_align(&elf, &arch))
+                .ok_or_else(|| RebaseError::NoRoom(module.path.clone()))?;
+            let delta = new_start.wrapping_sub(module.start);
+
+            for mapping in &module.mappings {
+                moves.push(RegionMove {
+                    kind: RegionKind::Library,
+                    old_start: mapping.start,
+                    len: mapping.len(),
+                    new_start: mapping.start.wrapping_add(delta),
+                });
+            }
+
+            placements.insert(module.start, (new_start, span));
+            rebased.push(RebasedLibrary {
+                path: module.path.clone(),
+                old_base: module.bias,
+                new_base: module.bias.wrapping_add(delta),
+                patched_pointers: 0,
+            });
+        }
+
+        if rebased.is_empty() {
+            return Ok(rebased);
+        }
+
+        let translate = |addr: u64| -> u64 {
+            match placements.range(..=addr).next_back() {
+                Some((old, (new, span))) if addr < old + span => addr - old + new,
+                _ => addr,
+            }
+        };
+
+        let mut patches = Vec::new();
+
+        // Every relocated slot in every module that points into a moved library
+        for module in &modules {
+            let elf = Self::parse(module)?;
+            for rela in elf.dynrelas.iter().chain(elf.pltrelocs.iter()) {
+                match rela.r_type {
+                    reloc::R_X86_64_RELATIVE | reloc::R_X86_64_GLOB_DAT | reloc::R_X86_64_JUMP_SLOT
+                    | reloc::R_X86_64_64 | reloc::R_X86_64_IRELATIVE => {}
+                    _ => continue,
+                }
+
+                let slot = module.bias.wrapping_add(rela.r_offset);
+                Self::queue_patch(&mem, pid, slot, &translate, &mut patches)?;
+            }
+
+            // glibc rewrites d_ptr entries of _DYNAMIC to absolute addresses
+            if let Some(dynamic) = Self::dynamic_address(&elf, module.bias) {
+                let count = elf.dynamic.as_ref().map(|d| d.dyns.len()).unwrap_or(0);
+                for i in 0..count as u64 {
+                    Self::queue_patch(&mem, pid, dynamic + i * 16 + 8, &translate, &mut patches)?;
+                }
+            }
+        }
+
+        // The dynamic linker's link_map still records the old load bias
+        if let Some(main) = modules.first() {
+            self.patch_link_map(&mem, pid, main, &modules, &translate, &mut patches)?;
+        }
+
+        for library in rebased.iter_mut() {
+            let old_start = modules.iter().find(|m| m.path == library.path).map(|m| m.start).unwrap_or(0);
+            let new_start = placements.get(&old_start).map(|(n, _)| *n).unwrap_or(0);
+            let span = placements.get(&old_start).map(|(_, s)| *s).unwrap_or(0);
+            library.patched_pointers = patches.iter()
+                .filter(|p| p.new_value >= new_start && p.new_value < new_start + span)
+                .count();
+        }
+
+        RemapEngine::relocate(pid, &moves, &patches)?;
+
+        for library in &rebased {
+            tracing::info!(
+                pid,
+                "PID {}: rebased {} {:#x} -> {:#x} ({} pointers)",
+                pid, library.path, library.old_base, library.new_base, library.patched_pointers
+            );
+        }
+
+        Ok(rebased)
+    }
+
+    fn is_selected(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.selected.iter().any(|s| name.starts_with(s.as_str()))
+    }
+
+    fn queue_patch<F>(
+        mem: &File,
+        pid: u32,
+        slot: u64,
+        translate: &F,
+        patches: &mut Vec<PointerPatch>,
+    ) -> Result<(), RebaseError>
+    where
+        F: Fn(u64) -> u64,
+    {
+        let current = Self::read_u64(mem, pid, slot)?;
+        let new_value = translate(current);
+
+        if new_value != current {
+            // Slot itself may live inside a moved library
+            patches.push(PointerPatch {
+                addr: translate(slot),
+                old_value: current,
+                new_value,
+            });
+        }
+        Ok(())
+    }
+
+    fn patch_link_map<F>(
+        &self,
+        mem: &File,
+        pid: u32,
+        main: &LoadedModule,
+        modules: &[LoadedModule],
+        translate: &F,
+        patches: &mut Vec<PointerPatch>,
+    ) -> Result<(), RebaseError>
+    where
+        F: Fn(u64) -> u64,
+    {
+        let elf = Self::parse(main)?;
+        let debug_tag = elf.dynamic.as_ref()
+            .and_then(|d| d.dyns.iter().position(|e| e.d_tag == dynamic::DT_DEBUG));
+        let dynamic = Self::dynamic_address(&elf, main.bias);
+
+        let r_debug = match (debug_tag, dynamic) {
+            (Some(index), Some(dynamic)) => Self::read_u64(mem, pid, dynamic + index as u64 * 16 + 8)?,
+            _ => return Ok(()),
+        };
+        if r_debug == 0 {
+            return Ok(());
+        }
+
+        // struct r_debug { int r_version; struct link_map *r_map; ... }
+        let mut link = Self::read_u64(mem, pid, r_debug + 8)?;
+        while link != 0 {
+            // struct link_map { l_addr, l_name, l_ld, l_next, l_prev }
+            let l_addr = Self::read_u64(mem, pid, link)?;
+            let l_ld = Self::read_u64(mem, pid, link + 16)?;
+            let new_ld = translate(l_ld);
+
+            if new_ld != l_ld {
+                let delta = new_ld.wrapping_sub(l_ld);
+                patches.push(PointerPatch {
+                    addr: link,
+                    old_value: l_addr,
+                    new_value: l_addr.wrapping_add(delta),
+                });
+                patches.push(PointerPatch { addr: link + 16, old_value: l_ld, new_value: new_ld });
+
+                if let Some(module) = modules.iter().find(|m| l_ld >= m.start && l_ld < m.end) {
+                    Self::patch_link_map_private(mem, pid, link, module, delta, patches)?;
+                }
+            }
+
+            link = Self::read_u64(mem, pid, link + 24)?;
+        }
+
+        Ok(())
+    }
+
+    // The dynamic linker keeps more of the module's addresses after the
+    // public fields: its program headers, mapped range, end of text and
+    // RELRO start. Each is found by the value it must hold, so nothing else
+    // in the struct is touched.
+    fn patch_link_map_private(
+        mem: &File,
+        pid: u32,
+        link: u64,
+        module: &LoadedModule,
+        delta: u64,
+        patches: &mut Vec<PointerPatch>,
+    ) -> Result<(), RebaseError> {
+        let elf = Self::parse(module)?;
+        let segment = |kind: u32| elf.program_headers.iter().find(|ph| ph.p_type == kind);
+        let loads = || elf.program_headers.iter().filter(|ph| ph.p_type == program_header::PT_LOAD);
+
+        let phdr = segment(program_header::PT_PHDR)
+            .map(|ph| module.bias.wrapping_add(ph.p_vaddr))
+            .unwrap_or_else(|| module.start.wrapping_add(elf.header.e_phoff));
+        let map_end = loads().map(|ph| module.bias.wrapping_add(ph.p_vaddr + ph.p_memsz)).max();
+        let text_end = loads()
+            .filter(|ph| ph.is_executable())
+            .map(|ph| module.bias.wrapping_add(ph.p_vaddr + ph.p_memsz))
+            .max();
+        let relro = segment(program_header::PT_GNU_RELRO).map(|ph| module.bias.wrapping_add(ph.p_vaddr));
+        let expected: Vec<u64> = [Some(phdr), Some(module.start), map_end, text_end, relro]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let mut block = vec![0u8; LINK_MAP_SCAN - LINK_MAP_PRIVATE as usize];
+        // The allocation may end before the scan does
+        let read = mem.read_at(&mut block, link + LINK_MAP_PRIVATE).map_err(|e| RebaseError::Memory(pid, e))?;
+        for (i, word) in block[..read].chunks_exact(8).enumerate() {
+            let value = u64::from_ne_bytes(word.try_into().unwrap());
+            if expected.contains(&value) {
+                patches.push(PointerPatch {
+                    addr: link + LINK_MAP_PRIVATE + i as u64 * 8,
+                    old_value: value,
+                    new_value: value.wrapping_add(delta),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn load_modules(maps: &ProcessMaps) -> Result<Vec<LoadedModule>, RebaseError> {
+        let mut modules: Vec<LoadedModule> = Vec::new();
+
+        for (i, entry) in maps.entries.iter().enumerate() {
+            let path = match entry.path.as_deref() {
+                Some(p) if p.starts_with('/') => p,
+                _ => continue,
+            };
+
+            if let Some(module) = modules.iter_mut().find(|m| m.path == path) {
+                module.end = module.end.max(entry.end);
+                module.mappings.push(entry.clone());
+            } else if entry.offset == 0 {
+                // Locale archives, fonts and other mapped data files
+                let Some(elf_bytes) = Self::read_elf(path) else {
+                    continue;
+                };
+                modules.push(LoadedModule {
+                    path: path.to_string(),
+                    elf_bytes,
+                    bias: 0,
+                    start: entry.start,
+                    end: entry.end,
+                    mappings: vec![entry.clone()],
+                });
+            }
+
+            // Anonymous .bss directly after a module's last file mapping
+            if let Some(next) = maps.entries.get(i + 1) {
+                if next.path.is_none() && next.start == entry.end && next.writable() {
+                    if let Some(module) = modules.iter_mut().find(|m| m.path == path) {
+                        module.end = next.end;
+                        module.mappings.push(next.clone());
+                    }
+                }
+            }
+        }
+
+        modules.retain_mut(|module| {
+            let first_vaddr = match Elf::parse(&module.elf_bytes) {
+                Ok(elf) => elf.program_headers.iter()
+                    .filter(|ph| ph.p_type == program_header::PT_LOAD)
+                    .map(|ph| ph.p_vaddr & !0xFFF)
+                    .min()
+                    .unwrap_or(0),
+                Err(e) => {
+                    tracing::debug!("Not rebasing {}: {}", module.path, e);
+                    return false;
+                }
+            };
+            module.bias = module.start.wrapping_sub(first_vaddr);
+            true
+        });
+
+        Ok(modules)
+    }
+
+    // The file's contents if it starts with the ELF magic; None for any
+    // other mapped file, or one that can't be read
+    fn read_elf(path: &str) -> Option<Vec<u8>> {
+        let mut magic = [0u8; 4];
+        File::open(path).ok()?.read_exact_at(&mut magic, 0).ok()?;
+        if &magic != b"\x7fELF" {
+            return None;
+        }
+        fs::read(path).ok()
+    }
+
+    fn parse(module: &LoadedModule) -> Result<Elf<'_>, RebaseError> {
+        Elf::parse(&module.elf_bytes).map_err(|e| RebaseError::Elf(module.path.clone(), e.to_string()))
+    }
+
+    fn has_text_relocations(elf: &Elf) -> bool {
+        elf.dynamic.as_ref().map_or(false, |d| {
+            d.dyns.iter().any(|e| {
+                e.d_tag == dynamic::DT_TEXTREL
+                    || (e.d_tag == dynamic::DT_FLAGS && e.d_val & dynamic::DF_TEXTREL != 0)
+            })
+        })
+    }
+
+    fn dynamic_address(elf: &Elf, bias: u64) -> Option<u64> {
+        elf.program_headers.iter()
+            .find(|ph| ph.p_type == program_header::PT_DYNAMIC)
+            .map(|ph| bias.wrapping_add(ph.p_vaddr))
+    }
+
+    fn max_align(elf: &Elf, arch: &ArchProfile) -> u64 {
+        elf.program_headers.iter()
+            .filter(|ph| ph.p_type == program_header::PT_LOAD)
+            .map(|ph| ph.p_align)
+            .max()
+            .unwrap_or(0)
+            .max(arch.page_size())
+    }
+
+    fn place(
+        maps: &ProcessMaps,
+        placed: &BTreeMap<u64, (u64, u64)>,
+        arch: &ArchProfile,
+        span: u64,
+        align: u64,
+    ) -> Option<u64> {
+        let (low, high) = arch.mmap_window;
+        let min_addr = process_maps::mmap_min_addr().max(low);
+        if high <= min_addr + span {
+            return None;
+        }
+
+        let rng = SystemRandomSource::new();
+        for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+            let candidate = (min_addr + rng.next_below(high - span - min_addr)) & !(align - 1);
+            let end = candidate + span;
+
+            let clashes_existing = !maps.overlapping(candidate, end).is_empty();
+            let clashes_placed = placed.values().any(|(start, len)| candidate < start + len && *start < end);
+            if candidate >= min_addr && !clashes_existing && !clashes_placed {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn read_u64(mem: &File, pid: u32, addr: u64) -> Result<u64, RebaseError> {
+        let mut buf = [0u8; 8];
+        mem.read_exact_at(&mut buf, addr).map_err(|e| RebaseError::Memory(pid, e))?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    fn run_to_entry(pid: u32) -> Result<(), RebaseError> {
+        let entry = Self::auxv_entry(pid)?;
+        let tid = pid as libc::pid_t;
+        let ptrace = |request: libc::c_uint, addr: u64, data: u64, op: &str| -> Result<libc::c_long, RebaseError> {
+            let ret = unsafe { libc::ptrace(request, tid, addr as *mut libc::c_void, data as *mut libc::c_void) };
+            if ret == -1 {
+                return Err(RebaseError::Trace(pid, format!("{}: {}", op, io::Error::last_os_error())));
+            }
+            Ok(ret)
+        };
+
+        // Plant an int3 at the entry point and run until it fires
+        let original = unsafe {
+            // PEEKTEXT returns the word, so -1 is only an error with errno set
+            *libc::__errno_location() = 0;
+            let word = libc::ptrace(libc::PTRACE_PEEKTEXT, tid, entry as *mut libc::c_void, std::ptr::null_mut::<libc::c_void>());
+            if word == -1 && *libc::__errno_location() != 0 {
+                return Err(RebaseError::Trace(pid, format!("PEEKTEXT: {}", io::Error::last_os_error())));
+            }
+            word as u64
+        };
+        ptrace(libc::PTRACE_POKETEXT, entry, (original & !0xFF) | INT3, "POKETEXT")?;
+        ptrace(libc::PTRACE_CONT, 0, 0, "CONT")?;
+        Self::wait_stop(pid)?;
+
+        ptrace(libc::PTRACE_POKETEXT, entry, original, "POKETEXT")?;
+
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        ptrace(libc::PTRACE_GETREGS, 0, &mut regs as *mut _ as u64, "GETREGS")?;
+        if regs.rip != entry + 1 {
+            return Err(RebaseError::Trace(pid, format!("stopped at {:#x}, expected entry {:#x}", regs.rip, entry)));
+        }
+        regs.rip = entry;
+        ptrace(libc::PTRACE_SETREGS, 0, &regs as *const _ as u64, "SETREGS")?;
+
+        Ok(())
+    }
+
+    fn auxv_entry(pid: u32) -> Result<u64, RebaseError> {
+        let auxv = fs::read(format!("/proc/{}/auxv", pid)).map_err(|e| RebaseError::Memory(pid, e))?;
+
+        auxv.chunks_exact(16)
+            .map(|pair| {
+                let key = u64::from_ne_bytes(pair[0..8].try_into().unwrap());
+                let value = u64::from_ne_bytes(pair[8..16].try_into().unwrap());
+                (key, value)
+            })
+            .find(|(key, _)| *key == AT_ENTRY)
+            .map(|(_, value)| value)
+            .ok_or_else(|| RebaseError::Trace(pid, "no AT_ENTRY in auxv".into()))
+    }
+
+    fn wait_stop(pid: u32) -> Result<(), RebaseError> {
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) };
+
+        if ret < 0 || !libc::WIFSTOPPED(status) {
+            return Err(RebaseError::Trace(pid, format!("unexpected wait status {:#x}", status)));
+        }
+        Ok(())
+    }
+}
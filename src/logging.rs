@@ -0,0 +1,65 @@
+// src/logging.rs
+// One subscriber for the whole daemon. Under systemd, records go to journald
+// with their tracing fields as journal fields under a QKS_ prefix, so
+// `pid = 1234` on an event becomes QKS_PID=1234 and can be queried with
+// `journalctl QKS_PID=1234` or `journalctl QKS_SNAPSHOT_ID=...`. The field
+// names used across the daemon are: pid, syscall, score, threshold, exe,
+// snapshot_id. Elsewhere the same records go to stderr as text. With
+// [logging.syslog] enabled every record is also sent on as RFC 5424.
+use crate::config::{LogTarget, LoggingSection};
+use crate::syslog_sink::{SyslogError, SyslogLayer};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+const FIELD_PREFIX: &str = "QKS";
+const SYSLOG_IDENTIFIER: &str = "qksd";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoggingError {
+    #[error("journald unavailable: {0}")]
+    Journald(std::io::Error),
+    #[error("invalid log filter {directive:?}: {reason}")]
+    Filter { directive: String, reason: String },
+    #[error(transparent)]
+    Syslog(#[from] SyslogError),
+    #[error("a global subscriber is already installed")]
+    AlreadyInitialized,
+}
+
+// systemd sets JOURNAL_STREAM when stdout/stderr are connected to the journal
+fn under_journal() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+fn filter(section: &LoggingSection) -> Result<EnvFilter, LoggingError> {
+    let directive = std::env::var("RUST_LOG").unwrap_or_else(|_| section.level.clone());
+    EnvFilter::try_new(&directive).map_err(|e| LoggingError::Filter { directive, reason: e.to_string() })
+}
+
+// Install the global subscriber. `auto` falls back to stderr when the
+// journal socket can't be opened; an explicit `journald` target fails.
+pub fn init(section: &LoggingSection) -> Result<(), LoggingError> {
+    let filter = filter(section)?;
+    let journald = match section.target {
+        LogTarget::Stderr => None,
+        LogTarget::Auto if !under_journal() => None,
+        LogTarget::Auto => tracing_journald::layer().ok(),
+        LogTarget::Journald => Some(tracing_journald::layer().map_err(LoggingError::Journald)?),
+    };
+
+    let syslog = if section.syslog.enabled { Some(SyslogLayer::new(&section.syslog)?) } else { None };
+
+    let registry = tracing_subscriber::registry().with(filter).with(syslog);
+    match journald {
+        Some(layer) => registry
+            .with(
+                layer
+                    .with_field_prefix(Some(FIELD_PREFIX.to_string()))
+                    .with_syslog_identifier(SYSLOG_IDENTIFIER.to_string()),
+            )
+            .try_init(),
+        None => registry.with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)).try_init(),
+    }
+    .map_err(|_| LoggingError::AlreadyInitialized)
+}
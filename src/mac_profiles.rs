@@ -0,0 +1,554 @@
+// src/mac_profiles.rs
+// Drafts AppArmor profiles, and SELinux policy modules on request, for the
+// binaries listed in [profiles], from what their processes were seen doing.
+// Every `sample_secs` each running process of a listed binary is sampled:
+// the files it has open (read, write or both, from fdinfo) and mapped
+// (executable mappings are "m"), the socket families and types it holds,
+// whether it runs as root, and, when the detector keeps behaviour profiles,
+// the syscalls it has made. The samples accumulate per binary.
+//
+// A binary stays in `learning` until `learning_secs` after its first
+// sample, then waits in `review`. An operator reads the draft (`show`),
+// exports it to edit elsewhere, and approves it; only an approved AppArmor
+// profile can be enforced, which loads it with apparmor_parser, in complain
+// mode if asked. New samples after approval go back to review, so what is
+// enforced is always what was read. SELinux modules are export-only: their
+// types come from the files' labels as seen, and installing one is a
+// decision for the policy's owner.
+use crate::behavior_profiles::{BehaviorProfiles, ProfileKey};
+use crate::config::ProfilesSection;
+use crate::privsep;
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+// Syscalls that need a capability when made as root, and the AppArmor
+// capability rule they imply
+const CAPABILITY_SYSCALLS: &[(libc::c_long, &str)] = &[
+    (libc::SYS_setuid, "setuid"),
+    (libc::SYS_setresuid, "setuid"),
+    (libc::SYS_setgid, "setgid"),
+    (libc::SYS_setresgid, "setgid"),
+    (libc::SYS_setgroups, "setgid"),
+    (libc::SYS_chown, "chown"),
+    (libc::SYS_fchown, "chown"),
+    (libc::SYS_fchownat, "chown"),
+    (libc::SYS_chroot, "sys_chroot"),
+    (libc::SYS_mount, "sys_admin"),
+    (libc::SYS_umount2, "sys_admin"),
+    (libc::SYS_ptrace, "sys_ptrace"),
+    (libc::SYS_init_module, "sys_module"),
+    (libc::SYS_finit_module, "sys_module"),
+    (libc::SYS_reboot, "sys_boot"),
+    (libc::SYS_settimeofday, "sys_time"),
+    (libc::SYS_clock_settime, "sys_time"),
+    (libc::SYS_mknod, "mknod"),
+    (libc::SYS_mknodat, "mknod"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileGenError {
+    #[error("{0} is not a profiled binary")]
+    UnknownBinary(String),
+    #[error("{0} is still learning")]
+    Learning(String),
+    #[error("{0} has not been approved since its last change")]
+    NotApproved(String),
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("apparmor_parser: {0}")]
+    Parser(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileFormat {
+    Apparmor,
+    Selinux,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewState {
+    Learning,
+    Review,
+    Approved,
+    Enforced,
+}
+
+// File access seen, as AppArmor letters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+    // Mapped executable
+    pub map: bool,
+}
+
+impl Access {
+    fn merge(&mut self, other: Access) -> bool {
+        let before = *self;
+        self.read |= other.read;
+        self.write |= other.write;
+        self.map |= other.map;
+        *self != before
+    }
+
+    fn letters(&self) -> String {
+        [(self.read, 'r'), (self.write, 'w'), (self.map, 'm')].iter().filter(|(on, _)| *on).map(|(_, c)| *c).collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Observed {
+    pub files: BTreeMap<String, Access>,
+    // ("inet", "stream"), ("unix", "dgram"), ...
+    pub network: BTreeSet<(String, String)>,
+    pub syscalls: BTreeSet<u32>,
+    pub ran_as_root: bool,
+    // SELinux type of each file, where it has one
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryProfile {
+    pub binary: PathBuf,
+    pub state: ReviewState,
+    pub first_sample: u64,
+    pub last_change: u64,
+    pub samples: u64,
+    pub observed: Observed,
+    pub complain: bool,
+}
+
+// What `list` shows
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileSummary {
+    pub binary: PathBuf,
+    pub state: ReviewState,
+    pub samples: u64,
+    pub files: usize,
+    pub network: usize,
+    pub ready_at: u64,
+}
+
+pub struct ProfileGenerator {
+    section: ProfilesSection,
+    store: PathBuf,
+    behavior: Option<BehaviorProfiles>,
+    profiles: Mutex<BTreeMap<PathBuf, BinaryProfile>>,
+}
+
+impl ProfileGenerator {
+    // `behavior` supplies the syscalls; without it profiles have none
+    pub fn new(section: &ProfilesSection, store: PathBuf, behavior: Option<BehaviorProfiles>) -> Self {
+        let profiles = std::fs::read(&store).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()).unwrap_or_default();
+        Self { section: section.clone(), store, behavior, profiles: Mutex::new(profiles) }
+    }
+
+    pub fn list(&self) -> Vec<ProfileSummary> {
+        self.profiles
+            .lock()
+            .unwrap()
+            .values()
+            .map(|profile| ProfileSummary {
+                binary: profile.binary.clone(),
+                state: profile.state,
+                samples: profile.samples,
+                files: profile.observed.files.len(),
+                network: profile.observed.network.len(),
+                ready_at: profile.first_sample + self.section.learning_secs,
+            })
+            .collect()
+    }
+
+    fn with_profile<T>(&self, binary: &Path, f: impl FnOnce(&mut BinaryProfile) -> Result<T, ProfileGenError>) -> Result<T, ProfileGenError> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let profile = profiles.get_mut(binary).ok_or_else(|| ProfileGenError::UnknownBinary(binary.display().to_string()))?;
+        let result = f(profile)?;
+        self.save(&profiles);
+        Ok(result)
+    }
+
+    // The draft in either format, at any stage
+    pub fn render(&self, binary: &Path, format: ProfileFormat) -> Result<String, ProfileGenError> {
+        let profiles = self.profiles.lock().unwrap();
+        let profile = profiles.get(binary).ok_or_else(|| ProfileGenError::UnknownBinary(binary.display().to_string()))?;
+        Ok(match format {
+            ProfileFormat::Apparmor => self.apparmor(profile),
+            ProfileFormat::Selinux => self.selinux(profile),
+        })
+    }
+
+    pub fn export(&self, binary: &Path, format: ProfileFormat, output: &Path) -> Result<(), ProfileGenError> {
+        let text = self.render(binary, format)?;
+        std::fs::write(output, text).map_err(|source| ProfileGenError::Io { path: output.to_path_buf(), source })
+    }
+
+    pub fn approve(&self, binary: &Path, complain: bool) -> Result<(), ProfileGenError> {
+        self.with_profile(binary, |profile| match profile.state {
+            ReviewState::Learning => Err(ProfileGenError::Learning(binary.display().to_string())),
+            _ => {
+                profile.state = ReviewState::Approved;
+                profile.complain = complain;
+                tracing::info!("AppArmor profile for {} approved{}", binary.display(), if complain { " in complain mode" } else { "" });
+                Ok(())
+            }
+        })
+    }
+
+    // Writes the approved profile to apparmor_dir and loads it
+    pub fn enforce(&self, binary: &Path) -> Result<PathBuf, ProfileGenError> {
+        let text = self.render(binary, ProfileFormat::Apparmor)?;
+        self.with_profile(binary, |profile| {
+            if profile.state != ReviewState::Approved && profile.state != ReviewState::Enforced {
+                return Err(ProfileGenError::NotApproved(binary.display().to_string()));
+            }
+            let path = self.section.apparmor_dir.join(format!("qks.{}", profile_name(binary)));
+            std::fs::write(&path, &text).map_err(|source| ProfileGenError::Io { path: path.clone(), source })?;
+            let output = Command::new("apparmor_parser")
+                .arg("--replace")
+                .arg(&path)
+                .stdin(Stdio::null())
+                .output()
+                .map_err(|e| ProfileGenError::Parser(e.to_string()))?;
+            if !output.status.success() {
+                return Err(ProfileGenError::Parser(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+            }
+            profile.state = ReviewState::Enforced;
+            tracing::info!("AppArmor profile for {} loaded from {}", binary.display(), path.display());
+            Ok(path)
+        })
+    }
+
+    fn save(&self, profiles: &BTreeMap<PathBuf, BinaryProfile>) {
+        let tmp = self.store.with_extension("tmp");
+        let saved = serde_json::to_vec(profiles)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| std::fs::write(&tmp, bytes))
+            .and_then(|()| std::fs::rename(&tmp, &self.store));
+        if let Err(e) = saved {
+            tracing::warn!("Profile drafts not saved to {}: {}", self.store.display(), e);
+        }
+    }
+
+    // Samples every running process of a listed binary
+    pub fn sample(&self) {
+        let now = systemd::now_secs();
+        let mut seen: Vec<(PathBuf, Observed)> = Vec::new();
+        for pid in std::fs::read_dir("/proc").into_iter().flatten().flatten().filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok()) {
+            let Ok(exe) = privsep::read_exe(pid) else {
+                continue;
+            };
+            if !self.section.binaries.contains(&exe) {
+                continue;
+            }
+            let mut observed = sample_process(pid);
+            if let Some(profile) = self.behavior.as_ref().and_then(|behavior| behavior.profile(&ProfileKey::Executable(exe.clone()))) {
+                observed.syscalls.extend(profile.syscalls());
+            }
+            seen.push((exe, observed));
+        }
+
+        let mut profiles = self.profiles.lock().unwrap();
+        let mut changed = false;
+        for (binary, observed) in seen {
+            let profile = profiles.entry(binary.clone()).or_insert_with(|| BinaryProfile {
+                binary: binary.clone(),
+                state: ReviewState::Learning,
+                first_sample: now,
+                last_change: now,
+                samples: 0,
+                observed: Observed::default(),
+                complain: false,
+            });
+            profile.samples += 1;
+            if merge(&mut profile.observed, observed) {
+                profile.last_change = now;
+                if matches!(profile.state, ReviewState::Approved | ReviewState::Enforced) {
+                    tracing::warn!("{} did something its approved profile doesn't cover; back to review", binary.display());
+                    profile.state = ReviewState::Review;
+                }
+            }
+            changed = true;
+        }
+        for profile in profiles.values_mut() {
+            if profile.state == ReviewState::Learning && now >= profile.first_sample + self.section.learning_secs {
+                tracing::info!("Profile for {} is ready for review after {} samples", profile.binary.display(), profile.samples);
+                profile.state = ReviewState::Review;
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&profiles);
+        }
+    }
+
+    pub fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.section.sample_secs));
+            loop {
+                ticker.tick().await;
+                let generator = self.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || generator.sample()).await {
+                    tracing::error!("Profile sampling panicked: {}", e);
+                }
+            }
+        })
+    }
+
+    // Files under a directory with more than glob_threshold of them seen
+    // become "dir/*"; per-PID /proc paths become @{pid}
+    fn file_rules(&self, observed: &Observed) -> BTreeMap<String, Access> {
+        let mut per_dir: BTreeMap<String, usize> = BTreeMap::new();
+        for path in observed.files.keys() {
+            if let Some((dir, _)) = path.rsplit_once('/') {
+                *per_dir.entry(dir.to_string()).or_default() += 1;
+            }
+        }
+        let mut rules: BTreeMap<String, Access> = BTreeMap::new();
+        for (path, access) in &observed.files {
+            let mut rule = generalize_pid(path);
+            if let Some((dir, _)) = path.rsplit_once('/') {
+                if per_dir.get(dir).copied().unwrap_or(0) > self.section.glob_threshold {
+                    rule = format!("{}/*", generalize_pid(dir));
+                }
+            }
+            rules.entry(rule).or_default().merge(*access);
+        }
+        rules
+    }
+
+    fn apparmor(&self, profile: &BinaryProfile) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "# Drafted by qksd from {} samples of {}; review before enforcing", profile.samples, profile.binary.display());
+        let _ = writeln!(text, "abi <abi/3.0>,");
+        let _ = writeln!(text, "include <tunables/global>\n");
+        let flags = if profile.complain { " flags=(complain)" } else { "" };
+        let _ = writeln!(text, "profile qks.{} {}{} {{", profile_name(&profile.binary), profile.binary.display(), flags);
+        let _ = writeln!(text, "  include <abstractions/base>\n");
+        if profile.observed.ran_as_root {
+            let capabilities: BTreeSet<&str> = CAPABILITY_SYSCALLS
+                .iter()
+                .filter(|(nr, _)| profile.observed.syscalls.contains(&(*nr as u32)))
+                .map(|(_, capability)| *capability)
+                .collect();
+            for capability in &capabilities {
+                let _ = writeln!(text, "  capability {},", capability);
+            }
+            if !capabilities.is_empty() {
+                text.push('\n');
+            }
+        }
+        for (family, kind) in &profile.observed.network {
+            let _ = writeln!(text, "  network {} {},", family, kind);
+        }
+        if !profile.observed.network.is_empty() {
+            text.push('\n');
+        }
+        let _ = writeln!(text, "  {} mr,", profile.binary.display());
+        let binary = profile.binary.to_string_lossy();
+        for (path, access) in self.file_rules(&profile.observed).into_iter().filter(|(path, _)| *path != binary) {
+            let _ = writeln!(text, "  {} {},", path, access.letters());
+        }
+        text.push_str("}\n");
+        text
+    }
+
+    fn selinux(&self, profile: &BinaryProfile) -> String {
+        let name = format!("qks_{}", profile_name(&profile.binary).replace(['.', '-'], "_"));
+        let domain = format!("{}_t", name);
+        let exec = format!("{}_exec_t", name);
+
+        // Permissions per target type and class
+        let mut allow: BTreeMap<(String, &str), BTreeSet<&str>> = BTreeMap::new();
+        for (path, access) in &profile.observed.files {
+            let Some(label) = profile.observed.labels.get(path) else {
+                continue;
+            };
+            let perms = allow.entry((label.clone(), "file")).or_default();
+            perms.extend(["open", "getattr"]);
+            if access.read {
+                perms.insert("read");
+            }
+            if access.write {
+                perms.extend(["write", "append"]);
+            }
+            if access.map {
+                perms.extend(["map", "execute"]);
+            }
+        }
+        for (family, kind) in &profile.observed.network {
+            let class = match (family.as_str(), kind.as_str()) {
+                ("inet" | "inet6", "stream") => "tcp_socket",
+                ("inet" | "inet6", "dgram") => "udp_socket",
+                ("unix", "stream") => "unix_stream_socket",
+                ("unix", "dgram") => "unix_dgram_socket",
+                ("netlink", _) => "netlink_socket",
+                _ => "rawip_socket",
+            };
+            allow.entry(("self".to_string(), class)).or_default().extend(["create", "read", "write", "getattr", "setopt", "connect", "bind"]);
+        }
+
+        let types: BTreeSet<&str> = allow.keys().map(|(target, _)| target.as_str()).filter(|target| *target != "self").collect();
+        let classes: BTreeMap<&str, BTreeSet<&str>> = allow.iter().fold(BTreeMap::new(), |mut classes, ((_, class), perms)| {
+            classes.entry(*class).or_insert_with(BTreeSet::new).extend(perms.iter().copied());
+            classes
+        });
+
+        let mut text = String::new();
+        let _ = writeln!(text, "# Drafted by qksd from {} samples of {}; review before installing", profile.samples, profile.binary.display());
+        let _ = writeln!(text, "module {} 1.0;\n", name);
+        let _ = writeln!(text, "require {{");
+        let _ = writeln!(text, "    role system_r;");
+        for target in &types {
+            let _ = writeln!(text, "    type {};", target);
+        }
+        let _ = writeln!(text, "    class file {{ entrypoint execute map open read getattr write append }};");
+        for (class, perms) in classes.iter().filter(|(class, _)| **class != "file") {
+            let _ = writeln!(text, "    class {} {{ {} }};", class, perms.iter().copied().collect::<Vec<_>>().join(" "));
+        }
+        let _ = writeln!(text, "}}\n");
+        let _ = writeln!(text, "type {};", domain);
+        let _ = writeln!(text, "type {};", exec);
+        let _ = writeln!(text, "role system_r types {};", domain);
+        let _ = writeln!(text, "allow {} {}:file {{ entrypoint execute map read open getattr }};\n", domain, exec);
+        for ((target, class), perms) in &allow {
+            let _ = writeln!(text, "allow {} {}:{} {{ {} }};", domain, target, class, perms.iter().copied().collect::<Vec<_>>().join(" "));
+        }
+        let _ = writeln!(text, "\n# Label the binary with: semanage fcontext -a -t {} '{}'", exec, profile.binary.display());
+        text
+    }
+}
+
+// Adds what `new` saw to `into`; whether anything was new
+fn merge(into: &mut Observed, new: Observed) -> bool {
+    let mut changed = false;
+    for (path, access) in new.files {
+        let is_new = !into.files.contains_key(&path);
+        changed |= into.files.entry(path).or_default().merge(access) || is_new;
+    }
+    for socket in new.network {
+        changed |= into.network.insert(socket);
+    }
+    for syscall in new.syscalls {
+        changed |= into.syscalls.insert(syscall);
+    }
+    changed |= new.ran_as_root && !into.ran_as_root;
+    into.ran_as_root |= new.ran_as_root;
+    into.labels.extend(new.labels);
+    changed
+}
+
+// "/usr/sbin/nginx" -> "usr.sbin.nginx", as AppArmor names profile files
+fn profile_name(binary: &Path) -> String {
+    binary.to_string_lossy().trim_start_matches('/').replace('/', ".")
+}
+
+fn generalize_pid(path: &str) -> String {
+    match path.strip_prefix("/proc/").and_then(|rest| rest.split_once('/')) {
+        Some((pid, rest)) if pid.bytes().all(|b| b.is_ascii_digit()) => format!("@{{PROC}}/@{{pid}}/{}", rest),
+        _ => path.to_string(),
+    }
+}
+
+// The SELinux type from a file's security.selinux label
+fn selinux_type(path: &str) -> Option<String> {
+    let path = std::ffi::CString::new(path).ok()?;
+    let mut buf = [0u8; 256];
+    // SAFETY: both names are NUL-terminated and buf is as long as stated
+    let len = unsafe { libc::getxattr(path.as_ptr(), b"security.selinux\0".as_ptr().cast(), buf.as_mut_ptr().cast(), buf.len()) };
+    if len <= 0 {
+        return None;
+    }
+    // user:role:type:level
+    let label = String::from_utf8_lossy(&buf[..len as usize]);
+    label.trim_end_matches('\0').split(':').nth(2).map(str::to_string)
+}
+
+// Socket inode -> (family, type), from the process's network namespace
+fn sockets(pid: u32) -> BTreeMap<String, (String, String)> {
+    let mut found = BTreeMap::new();
+    let tables = [("tcp", "inet", "stream", 9), ("tcp6", "inet6", "stream", 9), ("udp", "inet", "dgram", 9), ("udp6", "inet6", "dgram", 9), ("raw", "inet", "raw", 9), ("unix", "unix", "", 6)];
+    for (table, family, kind, inode_field) in tables {
+        let Ok(contents) = std::fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) else {
+            continue;
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let Some(inode) = fields.get(inode_field) else {
+                continue;
+            };
+            // unix: "Num RefCount Protocol Flags Type St Inode Path", Type
+            // 0001 stream, 0002 dgram, 0005 seqpacket
+            let kind = match (table, fields.get(4).copied()) {
+                ("unix", Some("0002")) => "dgram",
+                ("unix", Some("0005")) => "seqpacket",
+                ("unix", _) => "stream",
+                _ => kind,
+            };
+            found.insert(inode.to_string(), (family.to_string(), kind.to_string()));
+        }
+    }
+    found
+}
+
+// What one process has open and mapped right now
+fn sample_process(pid: u32) -> Observed {
+    let mut observed = Observed::default();
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    // "Uid: real effective saved fs"
+    observed.ran_as_root = status.lines().find_map(|line| line.strip_prefix("Uid:")).and_then(|uids| uids.split_whitespace().nth(1)) == Some("0");
+
+    let mut socket_inodes = Vec::new();
+    for entry in std::fs::read_dir(format!("/proc/{}/fd", pid)).into_iter().flatten().flatten() {
+        let Ok(target) = std::fs::read_link(entry.path()) else {
+            continue;
+        };
+        let target = target.to_string_lossy().into_owned();
+        if let Some(inode) = target.strip_prefix("socket:[").and_then(|rest| rest.strip_suffix(']')) {
+            socket_inodes.push(inode.to_string());
+            continue;
+        }
+        if !target.starts_with('/') || target.ends_with(" (deleted)") {
+            continue;
+        }
+        // "flags:\t0100002", octal; the low two bits are the access mode
+        let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, entry.file_name().to_string_lossy())).unwrap_or_default();
+        let mode = fdinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("flags:"))
+            .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+            .map_or(0, |flags| flags & libc::O_ACCMODE as u32);
+        let access = Access { read: mode != libc::O_WRONLY as u32, write: mode != libc::O_RDONLY as u32, map: false };
+        observed.files.entry(target).or_default().merge(access);
+    }
+    if !socket_inodes.is_empty() {
+        let known = sockets(pid);
+        observed.network.extend(socket_inodes.iter().filter_map(|inode| known.get(inode).cloned()));
+    }
+
+    // "start-end perms offset dev inode path"
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    for line in maps.lines() {
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        let (Some(perms), Some(path)) = (fields.get(1), fields.get(5).map(|path| path.trim())) else {
+            continue;
+        };
+        if !path.starts_with('/') || path.ends_with(" (deleted)") {
+            continue;
+        }
+        let access = Access { read: true, write: false, map: perms.contains('x') };
+        observed.files.entry(path.to_string()).or_default().merge(access);
+    }
+    for path in observed.files.keys() {
+        if let Some(label) = selinux_type(path) {
+            observed.labels.insert(path.clone(), label);
+        }
+    }
+    observed
+}
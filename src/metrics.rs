@@ -0,0 +1,80 @@
+// src/metrics.rs
+// Process-wide counters and timings. Subsystems record into the global
+// registry; exporters read it back with `counters()` / `timings()`.
+use dashmap::DashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MetricKey {
+    pub name: &'static str,
+    pub labels: Labels,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    pub count: u64,
+    pub sum_secs: f64,
+    pub max_secs: f64,
+}
+
+impl Timing {
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_secs_f64(self.sum_secs / self.count as f64))
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    counters: DashMap<MetricKey, u64>,
+    timings: DashMap<MetricKey, Timing>,
+}
+
+static GLOBAL: OnceLock<Metrics> = OnceLock::new();
+
+pub fn global() -> &'static Metrics {
+    GLOBAL.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    fn key(name: &'static str, labels: &[(&'static str, &str)]) -> MetricKey {
+        MetricKey {
+            name,
+            labels: labels.iter().map(|(k, v)| (*k, v.to_string())).collect(),
+        }
+    }
+
+    pub fn incr(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], n: u64) {
+        *self.counters.entry(Self::key(name, labels)).or_insert(0) += n;
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut timing = self.timings.entry(Self::key(name, labels)).or_default();
+        timing.count += 1;
+        timing.sum_secs += secs;
+        timing.max_secs = timing.max_secs.max(secs);
+    }
+
+    pub fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+        self.counters.get(&Self::key(name, labels)).map_or(0, |v| *v)
+    }
+
+    pub fn timing(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Option<Timing> {
+        self.timings.get(&Self::key(name, labels)).map(|v| *v)
+    }
+
+    pub fn counters(&self) -> Vec<(MetricKey, u64)> {
+        self.counters.iter().map(|e| (e.key().clone(), *e.value())).collect()
+    }
+
+    pub fn timings(&self) -> Vec<(MetricKey, Timing)> {
+        self.timings.iter().map(|e| (e.key().clone(), *e.value())).collect()
+    }
+}
@@ -0,0 +1,259 @@
+// src/model_signing.rs
+// Detached Ed25519 signatures over model artifacts. A swapped or edited
+// model (or scaler: dividing every feature by 1e9 blinds detection just as
+// well) is refused before any runtime parses it. The signature lives in
+// model.sig inside a SavedModel directory, or <model>.sig next to a file.
+//
+// Signed digest: SHA-256 over every artifact as (name, length, bytes), in
+// name order. For a directory that is every file except the signature; for
+// a file model, the model plus its scaler, schema, drift, embedding and
+// thresholds sidecars when present. Thresholds decide what alerts, so
+// calibrating a model means signing it again.
+//
+// Loads never parse the original files: every artifact is read once, the
+// signature is checked over those bytes, and the same bytes are written to
+// a private staging directory the runtime and sidecar loaders open.
+use crate::crypto_identifiers::CryptoIdentifier;
+use crate::drift_monitor::DriftReference;
+use crate::inference_backend::InferenceError;
+use crate::ml_detector::{FeatureScaler, FeatureSchema};
+use crate::syscall_embedding::SyscallEmbedding;
+use crate::threshold_calibration::CalibratedThresholds;
+use ring::{digest, signature};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SIGNATURE_NAME: &str = "model.sig";
+const UNSIGNED_NAMES: &[&str] = &[SIGNATURE_NAME];
+// Verified copies of models being loaded; only the daemon can write here
+pub const MODEL_STAGING_DIR: &str = "/run/qks/models";
+
+static STAGED: AtomicU64 = AtomicU64::new(0);
+
+// One artifact as read: its name in the digest, where it goes in the
+// staged copy, and its bytes
+struct Artifact {
+    name: String,
+    staged_name: PathBuf,
+    bytes: Vec<u8>,
+}
+
+// The staged copy of a verified model, removed when dropped. Runtimes read
+// the model into memory at load, so it only has to outlive the load.
+pub struct VerifiedModel {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl VerifiedModel {
+    // Where the loaders should open the model, in place of the original
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for VerifiedModel {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ModelTrust {
+    // Ed25519 public keys allowed to sign models
+    Verify { trusted_keys: Vec<Vec<u8>> },
+    // Explicit opt-out; every load is logged as unverified
+    Insecure,
+}
+
+impl ModelTrust {
+    pub fn trust_identifier(identifier: &CryptoIdentifier) -> Self {
+        ModelTrust::Verify { trusted_keys: vec![identifier.public_key_bytes()] }
+    }
+
+    // Each file holds a raw 32-byte key or its hex encoding
+    pub fn from_key_files(paths: &[PathBuf]) -> Result<Self, InferenceError> {
+        let mut trusted_keys = Vec::new();
+        for path in paths {
+            let raw = std::fs::read(path).map_err(|e| InferenceError::Untrusted(format!("{}: {}", path.display(), e)))?;
+            let key = match decode_hex(String::from_utf8_lossy(&raw).trim()) {
+                Some(key) => key,
+                None => raw,
+            };
+            if key.len() != 32 {
+                return Err(InferenceError::Untrusted(format!("{}: not an Ed25519 public key", path.display())));
+            }
+            trusted_keys.push(key);
+        }
+        Ok(ModelTrust::Verify { trusted_keys })
+    }
+}
+
+pub fn signature_path(model_path: &Path) -> PathBuf {
+    if model_path.is_dir() {
+        model_path.join(SIGNATURE_NAME)
+    } else {
+        model_path.with_extension("sig")
+    }
+}
+
+pub fn sign_model(model_path: &Path, identifier: &CryptoIdentifier) -> Result<PathBuf, InferenceError> {
+    let digest = model_digest(&read_artifacts(model_path)?);
+    let path = signature_path(model_path);
+    std::fs::write(&path, identifier.sign(digest.as_ref()))
+        .map_err(|e| InferenceError::Model(format!("{}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+// Read, verify and stage `model_path`; load from the returned path only
+pub fn stage_verified(model_path: &Path, trust: &ModelTrust) -> Result<VerifiedModel, InferenceError> {
+    let artifacts = read_artifacts(model_path)?;
+    verify_artifacts(model_path, &artifacts, trust)?;
+    stage(model_path, &artifacts)
+}
+
+fn verify_artifacts(model_path: &Path, artifacts: &[Artifact], trust: &ModelTrust) -> Result<(), InferenceError> {
+    let trusted_keys = match trust {
+        ModelTrust::Insecure => {
+            tracing::warn!("Loading model {} WITHOUT signature verification", model_path.display());
+            return Ok(());
+        }
+        ModelTrust::Verify { trusted_keys } => trusted_keys,
+    };
+
+    let sig_path = signature_path(model_path);
+    let sig = std::fs::read(&sig_path)
+        .map_err(|e| InferenceError::Untrusted(format!("no signature at {}: {}", sig_path.display(), e)))?;
+    let digest = model_digest(artifacts);
+
+    let trusted = trusted_keys.iter().any(|key| {
+        signature::UnparsedPublicKey::new(&signature::ED25519, key)
+            .verify(digest.as_ref(), &sig)
+            .is_ok()
+    });
+    if !trusted {
+        return Err(InferenceError::Untrusted(format!(
+            "{} is not signed by a trusted key", model_path.display()
+        )));
+    }
+    Ok(())
+}
+
+// Every file a load of `model_path` reads, signature included, named
+// relative to the directory the model sits in (or is), so the set can be
+// copied elsewhere and still verify
+pub fn bundle(model_path: &Path) -> Result<Vec<(String, PathBuf)>, InferenceError> {
+    let mut files = Vec::new();
+    if model_path.is_dir() {
+        collect_files(model_path, model_path, &mut files)?;
+    } else {
+        for path in [
+            model_path.to_path_buf(),
+            FeatureScaler::sidecar_path(model_path),
+            DriftReference::sidecar_path(model_path),
+            SyscallEmbedding::sidecar_path(model_path),
+            FeatureSchema::sidecar_path(model_path),
+            CalibratedThresholds::sidecar_path(model_path),
+        ] {
+            if path.exists() {
+                files.push((path.file_name().unwrap_or_default().to_string_lossy().into_owned(), path));
+            }
+        }
+    }
+    let signature = signature_path(model_path);
+    files.push((signature.file_name().unwrap_or_default().to_string_lossy().into_owned(), signature));
+    Ok(files)
+}
+
+// Every signed artifact of `model_path`, each read exactly once, in name
+// order
+fn read_artifacts(model_path: &Path) -> Result<Vec<Artifact>, InferenceError> {
+    let mut paths: Vec<(String, PathBuf, PathBuf)> = Vec::new();
+    if model_path.is_dir() {
+        let mut files = Vec::new();
+        collect_files(model_path, model_path, &mut files)?;
+        let root = PathBuf::from(model_path.file_name().unwrap_or_default());
+        paths.extend(files.into_iter().map(|(name, path)| (name.clone(), root.join(&name), path)));
+    } else {
+        for (name, path) in [
+            ("model", model_path.to_path_buf()),
+            ("scaler", FeatureScaler::sidecar_path(model_path)),
+            ("drift", DriftReference::sidecar_path(model_path)),
+            ("embedding", SyscallEmbedding::sidecar_path(model_path)),
+            ("schema", FeatureSchema::sidecar_path(model_path)),
+            ("thresholds", CalibratedThresholds::sidecar_path(model_path)),
+        ] {
+            if name == "model" || path.exists() {
+                paths.push((name.into(), PathBuf::from(path.file_name().unwrap_or_default()), path));
+            }
+        }
+    }
+    paths.sort();
+
+    paths.into_iter()
+        .map(|(name, staged_name, path)| {
+            let bytes = std::fs::read(&path).map_err(|e| InferenceError::Model(format!("{}: {}", path.display(), e)))?;
+            Ok(Artifact { name, staged_name, bytes })
+        })
+        .collect()
+}
+
+fn model_digest(artifacts: &[Artifact]) -> digest::Digest {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for artifact in artifacts {
+        context.update(&(artifact.name.len() as u64).to_le_bytes());
+        context.update(artifact.name.as_bytes());
+        context.update(&(artifact.bytes.len() as u64).to_le_bytes());
+        context.update(&artifact.bytes);
+    }
+    context.finish()
+}
+
+// Write the verified bytes into a fresh directory of their own. create()
+// rather than create_all(): a directory someone else made first is refused.
+fn stage(model_path: &Path, artifacts: &[Artifact]) -> Result<VerifiedModel, InferenceError> {
+    let staging_error = |path: &Path, e: std::io::Error| InferenceError::Model(format!("staging {}: {}", path.display(), e));
+    let root = Path::new(MODEL_STAGING_DIR);
+    std::fs::create_dir_all(root).map_err(|e| staging_error(root, e))?;
+    std::fs::set_permissions(root, std::fs::Permissions::from_mode(0o700)).map_err(|e| staging_error(root, e))?;
+
+    let dir = root.join(format!("{}-{}", std::process::id(), STAGED.fetch_add(1, Ordering::Relaxed)));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir).map_err(|e| staging_error(&dir, e))?;
+    let staged = VerifiedModel { path: dir.join(model_path.file_name().unwrap_or_default()), dir };
+
+    for artifact in artifacts {
+        let path = staged.dir.join(&artifact.staged_name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| staging_error(parent, e))?;
+        }
+        std::fs::write(&path, &artifact.bytes).map_err(|e| staging_error(&path, e))?;
+    }
+    Ok(staged)
+}
+
+fn collect_files(root: &Path, dir: &Path, out: &mut Vec<(String, PathBuf)>) -> Result<(), InferenceError> {
+    let entries = std::fs::read_dir(dir).map_err(|e| InferenceError::Model(format!("{}: {}", dir.display(), e)))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(root, &path, out)?;
+            continue;
+        }
+        let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().into_owned();
+        if !UNSIGNED_NAMES.contains(&name.as_str()) {
+            out.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,163 @@
+// src/netns.rs
+// Network namespaces as the monitor sees them. Every network event carries
+// the nsfs inode of the namespace it happened in; this module remembers
+// which namespace each process was last seen in, so a setns or unshare
+// that moved it shows up as a switch from one to the other (and one that
+// did not is dropped), and answers which namespace a process is in for
+// events that don't say.
+//
+// For reporting, `list` walks /proc and groups processes by namespace with
+// the containers running in each and the host-side veth interfaces whose
+// peers live there. A veth's peer is found from inside the namespace: the
+// peer's iflink is the host veth's ifindex. That is read through the
+// namespace's own sysfs, reached via the root of a process in it, so it
+// works for containers (whose runtime mounted sysfs in their namespace) but
+// not for `ip netns exec` shells sharing the host's /sys.
+use crate::container_events;
+use crate::ebpf_monitor::{NetworkAction, NetworkEvent};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// The namespace's nsfs inode, from /proc/PID/ns/net -> "net:[4026531840]"
+pub fn of_pid(pid: u32) -> Option<u64> {
+    let link = fs::read_link(format!("/proc/{}/ns/net", pid)).ok()?;
+    link.to_str()?.strip_prefix("net:[")?.strip_suffix(']')?.parse().ok()
+}
+
+// init's namespace; everything else is a container, sandbox or netns
+pub fn host() -> Option<u64> {
+    static HOST: OnceLock<Option<u64>> = OnceLock::new();
+    *HOST.get_or_init(|| of_pid(1))
+}
+
+#[derive(Default)]
+pub struct NamespaceTracker {
+    by_pid: DashMap<u32, u64>,
+}
+
+static GLOBAL: OnceLock<NamespaceTracker> = OnceLock::new();
+
+pub fn global() -> &'static NamespaceTracker {
+    GLOBAL.get_or_init(NamespaceTracker::default)
+}
+
+impl NamespaceTracker {
+    // Records where the event's process is and fills in where a switch
+    // came from; false for switches that left the network namespace alone
+    pub fn observe(&self, event: &mut NetworkEvent) -> bool {
+        let previous = self.by_pid.insert(event.pid, event.netns);
+        if event.action != NetworkAction::NamespaceSwitch {
+            return true;
+        }
+        // First sight of the process: only a switch if it is not where it started
+        event.previous_netns = previous.or_else(|| parent_netns(event.pid));
+        event.previous_netns != Some(event.netns)
+    }
+
+    // Last seen, else read now
+    pub fn netns_of(&self, pid: u32) -> Option<u64> {
+        if let Some(netns) = self.by_pid.get(&pid) {
+            return Some(*netns);
+        }
+        of_pid(pid)
+    }
+
+    // Forget processes that have exited
+    pub fn prune(&self) {
+        self.by_pid.retain(|pid, _| Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
+
+// Where the parent is, as a stand-in for where the process was
+fn parent_netns(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let ppid = stat[stat.rfind(')')? + 2..].split_whitespace().nth(1)?.parse().ok()?;
+    of_pid(ppid)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NamespaceSummary {
+    pub netns: u64,
+    pub host: bool,
+    pub processes: usize,
+    pub containers: Vec<String>,
+    // Host-side veth interfaces whose peer is in this namespace
+    pub veths: Vec<String>,
+}
+
+// Blocking: walks /proc and sysfs
+pub fn list() -> Vec<NamespaceSummary> {
+    let host = host();
+    let mut namespaces: BTreeMap<u64, (usize, u32, BTreeSet<String>)> = BTreeMap::new();
+    for pid in fs::read_dir("/proc").into_iter().flatten().flatten().filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok()) {
+        let Some(netns) = of_pid(pid) else {
+            continue;
+        };
+        let (processes, first, containers) = namespaces.entry(netns).or_insert((0, pid, BTreeSet::new()));
+        *processes += 1;
+        if Some(netns) != host {
+            if let Some(container) = container_events::global().for_pid(pid) {
+                containers.insert(container.id.clone());
+            }
+        }
+        *first = (*first).min(pid);
+    }
+
+    let host_veths = host_interfaces();
+    namespaces
+        .into_iter()
+        .map(|(netns, (processes, first, containers))| NamespaceSummary {
+            netns,
+            host: Some(netns) == host,
+            processes,
+            containers: containers.into_iter().collect(),
+            veths: if Some(netns) == host { Vec::new() } else { peers_in(first, &host_veths) },
+        })
+        .collect()
+}
+
+// ifindex -> name in the host namespace
+fn host_interfaces() -> HashMap<u32, String> {
+    fs::read_dir("/sys/class/net")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let index = read_index(&entry.path().join("ifindex"))?;
+            Some((index, entry.file_name().to_str()?.to_string()))
+        })
+        .collect()
+}
+
+fn peers_in(pid: u32, host_interfaces: &HashMap<u32, String>) -> Vec<String> {
+    let sysfs = format!("/proc/{}/root/sys/class/net", pid);
+    // The host's own /sys would pair every host veth with itself
+    let (Ok(theirs), Ok(ours)) = (fs::metadata(&sysfs), fs::metadata("/sys/class/net")) else {
+        return Vec::new();
+    };
+    if theirs.dev() == ours.dev() {
+        return Vec::new();
+    }
+    let mut peers: Vec<String> = fs::read_dir(&sysfs)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let index = read_index(&entry.path().join("ifindex"))?;
+            let link = read_index(&entry.path().join("iflink"))?;
+            // An interface not paired with anything links to itself
+            (link != index).then(|| host_interfaces.get(&link).cloned()).flatten()
+        })
+        .collect();
+    peers.sort();
+    peers
+}
+
+fn read_index(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,449 @@
+// src/notify.rs
+// Pushes anomalies and response actions to people: generic webhooks,
+// Slack incoming webhooks and email. Each sink has a minimum severity and
+// an optional template. Anomalies are warnings, critical from
+// notify.critical_score up. Executed responses are warnings; refused and
+// failed ones are critical, since the process they were aimed at is still
+// running; dry runs and cooldowns are info.
+//
+// Templates substitute {{path}} with a field of the alert: severity, kind,
+// summary, host, timestamp, and the full enriched event under `event`
+// (event.pid, event.process_tree.0.exe, ...). Webhook templates are JSON
+// and placeholders render as JSON values; Slack and email render plain
+// text. A webhook may instead be given a SIEM `format` (siem_format.rs),
+// which posts the bare event in that format. Repeats are deduplicated and
+// rate limited first (alert_dedup.rs).
+// Deliveries run concurrently and are never retried; a failed one is
+// logged and counted.
+use crate::alert_dedup::{self, AlertGate};
+use crate::config::{DedupSection, NotifySection};
+use crate::events::{EventBus, EventKind, SecurityEvent};
+use crate::metrics;
+use crate::siem_format::{self, EventFormat};
+use crate::syslog_sink;
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TEXT: &str = "[{{severity}}] {{host}}: {{summary}}";
+const DEFAULT_SUBJECT: &str = "[qks {{severity}}] {{kind}} on {{host}}";
+// Intel matches from feeds configured at this severity (0-10) or above
+const INTEL_CRITICAL: u8 = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError {
+    #[error("template: unclosed {{{{ at offset {0}")]
+    Template(usize),
+    #[error("{0}: {1}")]
+    Http(String, reqwest::Error),
+    #[error("{0}: HTTP {1}")]
+    Status(String, reqwest::StatusCode),
+    #[error("{0}: {1}")]
+    Email(String, String),
+    #[error("{0}: timed out")]
+    Timeout(String),
+    #[error("{}: {source}", .path.display())]
+    Secret { path: PathBuf, source: std::io::Error },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertSeverity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+impl AlertSeverity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertSeverity::Info => "info",
+            AlertSeverity::Warning => "warning",
+            AlertSeverity::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookNotifier {
+    pub url: String,
+    #[serde(default)]
+    pub min_severity: AlertSeverity,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    // JSON body; the whole alert when unset
+    pub template: Option<String>,
+    // Post the event as a SIEM parses it instead of the alert
+    pub format: Option<EventFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlackNotifier {
+    pub webhook_url: String,
+    #[serde(default)]
+    pub min_severity: AlertSeverity,
+    // Message text, Slack mrkdwn allowed
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailNotifier {
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    // Plain SMTP only when false; meant for a local relay
+    #[serde(default = "default_starttls")]
+    pub starttls: bool,
+    pub username: Option<String>,
+    // Kept out of the config file, which is world-readable
+    pub password_file: Option<PathBuf>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub min_severity: AlertSeverity,
+    pub subject: Option<String>,
+    pub template: Option<String>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_starttls() -> bool {
+    true
+}
+
+// What every sink is given
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub severity: AlertSeverity,
+    pub kind: EventKind,
+    pub summary: String,
+    pub host: String,
+    pub timestamp: u64,
+    // What deduplication keys on
+    pub fingerprint: String,
+    // Identical alerts held back since the last one sent
+    pub suppressed: u64,
+    pub persisting_secs: u64,
+    // Raised a severity for persisting
+    pub escalated: bool,
+    // Alerts of any kind dropped by the global rate limit since the last sent
+    pub rate_limited: u64,
+    pub event: SecurityEvent,
+}
+
+impl Alert {
+    // Only anomalies, responses, integrity findings and intel matches are
+    // alerts
+    pub fn from_event(event: &SecurityEvent, critical_score: f32, host: &str) -> Option<Self> {
+        let severity = match event {
+            SecurityEvent::Anomaly(anomaly) if anomaly.score >= critical_score => AlertSeverity::Critical,
+            SecurityEvent::Anomaly(_) => AlertSeverity::Warning,
+            SecurityEvent::Response(response) => match response.outcome.as_str() {
+                "executed" => AlertSeverity::Warning,
+                "refused" | "failed" => AlertSeverity::Critical,
+                _ => AlertSeverity::Info,
+            },
+            SecurityEvent::Integrity(integrity) if integrity.critical => AlertSeverity::Critical,
+            SecurityEvent::Integrity(_) => AlertSeverity::Warning,
+            SecurityEvent::Intel(intel) if intel.severity >= INTEL_CRITICAL => AlertSeverity::Critical,
+            SecurityEvent::Intel(_) => AlertSeverity::Warning,
+            _ => return None,
+        };
+        Some(Self {
+            severity,
+            kind: event.kind(),
+            summary: siem_format::describe(event),
+            host: host.to_string(),
+            timestamp: systemd::now_secs(),
+            fingerprint: alert_dedup::fingerprint(event),
+            suppressed: 0,
+            persisting_secs: 0,
+            escalated: false,
+            rate_limited: 0,
+            event: event.clone(),
+        })
+    }
+
+    // What templates see; `event` is the payload without its kind tag
+    pub fn context(&self) -> Result<Value, serde_json::Error> {
+        let mut context = serde_json::to_value(self)?;
+        if let Some(event) = context.get_mut("event") {
+            *event = event["event"].take();
+        }
+        Ok(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    Json,
+    Plain,
+}
+
+// {{path}} placeholders, split up front so a bad template fails the config
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<(String, Option<String>)>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, NotifyError> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        let mut offset = 0;
+        while let Some(open) = rest.find("{{") {
+            let close = rest[open..].find("}}").ok_or(NotifyError::Template(offset + open))?;
+            let path = rest[open + 2..open + close].trim().to_string();
+            parts.push((rest[..open].to_string(), Some(path)));
+            offset += open + close + 2;
+            rest = &rest[open + close + 2..];
+        }
+        parts.push((rest.to_string(), None));
+        Ok(Self { parts })
+    }
+
+    fn render(&self, context: &Value, escape: Escape) -> String {
+        let mut out = String::new();
+        for (literal, path) in &self.parts {
+            out.push_str(literal);
+            let Some(path) = path else { continue };
+            let value = path.split('.').try_fold(context, |value, key| match value {
+                Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => value.get(key),
+            });
+            match (value, escape) {
+                (Some(value), Escape::Json) => out.push_str(&value.to_string()),
+                (None, Escape::Json) => out.push_str("null"),
+                (Some(Value::String(s)), Escape::Plain) => out.push_str(s),
+                (Some(Value::Null) | None, Escape::Plain) => {}
+                (Some(value), Escape::Plain) => out.push_str(&value.to_string()),
+            }
+        }
+        out
+    }
+}
+
+type Delivery<'a> = Pin<Box<dyn Future<Output = Result<(), NotifyError>> + Send + 'a>>;
+
+pub trait Notifier: Send + Sync {
+    // Names the sink in logs and metrics
+    fn name(&self) -> &str;
+    fn min_severity(&self) -> AlertSeverity;
+    fn send<'a>(&'a self, alert: &'a Alert, context: &'a Value) -> Delivery<'a>;
+}
+
+struct Webhook {
+    name: String,
+    config: WebhookNotifier,
+    template: Option<Template>,
+    client: reqwest::Client,
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn min_severity(&self) -> AlertSeverity {
+        self.config.min_severity
+    }
+
+    fn send<'a>(&'a self, alert: &'a Alert, context: &'a Value) -> Delivery<'a> {
+        Box::pin(async move {
+            let (body, content_type) = match (self.config.format, &self.template) {
+                (Some(format), _) => (siem_format::render(format, &alert.event, &alert.host), format.content_type()),
+                (None, Some(template)) => (template.render(context, Escape::Json), "application/json"),
+                (None, None) => (context.to_string(), "application/json"),
+            };
+            let mut request = self.client.post(&self.config.url).header(reqwest::header::CONTENT_TYPE, content_type);
+            for (name, value) in &self.config.headers {
+                request = request.header(name, value);
+            }
+            post(&self.name, request.body(body)).await
+        })
+    }
+}
+
+struct Slack {
+    name: String,
+    config: SlackNotifier,
+    template: Template,
+    client: reqwest::Client,
+}
+
+impl Notifier for Slack {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn min_severity(&self) -> AlertSeverity {
+        self.config.min_severity
+    }
+
+    fn send<'a>(&'a self, _alert: &'a Alert, context: &'a Value) -> Delivery<'a> {
+        Box::pin(async move {
+            let text = self.template.render(context, Escape::Plain);
+            post(&self.name, self.client.post(&self.config.webhook_url).json(&serde_json::json!({ "text": text }))).await
+        })
+    }
+}
+
+async fn post(name: &str, request: reqwest::RequestBuilder) -> Result<(), NotifyError> {
+    let response = request.send().await.map_err(|e| NotifyError::Http(name.to_string(), e))?;
+    if !response.status().is_success() {
+        return Err(NotifyError::Status(name.to_string(), response.status()));
+    }
+    Ok(())
+}
+
+struct Email {
+    name: String,
+    config: EmailNotifier,
+    subject: Template,
+    template: Template,
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+}
+
+impl Email {
+    fn new(name: String, config: EmailNotifier) -> Result<Self, NotifyError> {
+        use lettre::transport::smtp::authentication::Credentials;
+        use lettre::{AsyncSmtpTransport, Tokio1Executor};
+
+        let email_error = |e: &dyn std::fmt::Display| NotifyError::Email(name.clone(), e.to_string());
+        let mut transport = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host).map_err(|e| email_error(&e))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+        }
+        .port(config.smtp_port)
+        .timeout(Some(DELIVERY_TIMEOUT));
+        if let (Some(username), Some(path)) = (&config.username, &config.password_file) {
+            let password = std::fs::read_to_string(path).map_err(|source| NotifyError::Secret { path: path.clone(), source })?;
+            transport = transport.credentials(Credentials::new(username.clone(), password.trim_end().to_string()));
+        }
+        Ok(Self {
+            subject: Template::parse(config.subject.as_deref().unwrap_or(DEFAULT_SUBJECT))?,
+            template: Template::parse(config.template.as_deref().unwrap_or(DEFAULT_TEXT))?,
+            transport: transport.build(),
+            name,
+            config,
+        })
+    }
+}
+
+impl Notifier for Email {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn min_severity(&self) -> AlertSeverity {
+        self.config.min_severity
+    }
+
+    fn send<'a>(&'a self, _alert: &'a Alert, context: &'a Value) -> Delivery<'a> {
+        Box::pin(async move {
+            use lettre::message::header::ContentType;
+            use lettre::AsyncTransport;
+
+            let email_error = |e: &dyn std::fmt::Display| NotifyError::Email(self.name.clone(), e.to_string());
+            let mut message = lettre::Message::builder()
+                .from(self.config.from.parse().map_err(|e| email_error(&e))?)
+                .subject(self.subject.render(context, Escape::Plain))
+                .header(ContentType::TEXT_PLAIN);
+            for to in &self.config.to {
+                message = message.to(to.parse().map_err(|e| email_error(&e))?);
+            }
+            let message = message.body(self.template.render(context, Escape::Plain)).map_err(|e| email_error(&e))?;
+            self.transport.send(message).await.map_err(|e| email_error(&e))?;
+            Ok(())
+        })
+    }
+}
+
+pub struct Notifications {
+    critical_score: f32,
+    dedup: DedupSection,
+    host: String,
+    sinks: Vec<Arc<dyn Notifier>>,
+}
+
+impl Notifications {
+    pub fn new(config: &NotifySection) -> Result<Self, NotifyError> {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .map_err(|e| NotifyError::Http("client".to_string(), e))?;
+        let mut sinks: Vec<Arc<dyn Notifier>> = Vec::new();
+        for (i, webhook) in config.webhook.iter().enumerate() {
+            let template = webhook.template.as_deref().map(Template::parse).transpose()?;
+            sinks.push(Arc::new(Webhook { name: format!("webhook[{}]", i), config: webhook.clone(), template, client: client.clone() }));
+        }
+        for (i, slack) in config.slack.iter().enumerate() {
+            let template = Template::parse(slack.template.as_deref().unwrap_or(DEFAULT_TEXT))?;
+            sinks.push(Arc::new(Slack { name: format!("slack[{}]", i), config: slack.clone(), template, client: client.clone() }));
+        }
+        for (i, email) in config.email.iter().enumerate() {
+            sinks.push(Arc::new(Email::new(format!("email[{}]", i), email.clone())?));
+        }
+        Ok(Self { critical_score: config.critical_score, dedup: config.dedup.clone(), host: syslog_sink::hostname(), sinks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    // Hand `alert` to every sink that wants its severity, without waiting
+    pub fn dispatch(&self, alert: Alert) {
+        let context = match alert.context() {
+            Ok(context) => context,
+            Err(e) => {
+                tracing::error!("Alert not serializable: {}", e);
+                return;
+            }
+        };
+        let delivery = Arc::new((alert, context));
+        for sink in self.sinks.iter().filter(|sink| delivery.0.severity >= sink.min_severity()) {
+            let (sink, delivery) = (sink.clone(), delivery.clone());
+            tokio::spawn(async move {
+                let (alert, context) = &*delivery;
+                let delivered = match tokio::time::timeout(DELIVERY_TIMEOUT, sink.send(alert, context)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(NotifyError::Timeout(sink.name().to_string())),
+                };
+                let result = if delivered.is_ok() { "success" } else { "failure" };
+                metrics::global().incr("qks_notifications_total", &[("sink", sink.name()), ("result", result)]);
+                if let Err(e) = delivered {
+                    tracing::warn!("Alert not delivered: {}", e);
+                }
+            });
+        }
+    }
+
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut events = bus.subscribe_to(&[EventKind::Anomaly, EventKind::Response, EventKind::Integrity, EventKind::Intel]).with_name("notifier");
+            let mut gate = AlertGate::new(&self.dedup);
+            while let Some(event) = events.recv().await {
+                let Some(mut alert) = Alert::from_event(&event, self.critical_score, &self.host) else {
+                    continue;
+                };
+                if gate.admit(&mut alert, std::time::Instant::now()) {
+                    self.dispatch(alert);
+                }
+            }
+        })
+    }
+}
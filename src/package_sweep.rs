@@ -0,0 +1,239 @@
+// src/package_sweep.rs
+// debsums, spread out: every file an installed package shipped is compared
+// with the md5 dpkg recorded for it (dpkg.rs), a batch at a time so a full
+// sweep never competes with the host's own disk I/O. Each tick hashes files
+// until `batch_bytes` have been read, then waits for the next; a sweep that
+// finishes starts again after `sweep_interval_secs`.
+//
+// A sweep is planned up front from dpkg's records: md5sums, conffiles when
+// enabled, and for packages without md5sums the .deb in apt's cache when it
+// matches apt's index. Files under `critical_paths` (binaries, PAM, sudo,
+// the loader) go first and a modification there is critical; elsewhere it
+// is a warning. Diverted files and `skip_paths` are left out. Packages that
+// can't be verified at all are listed in the status. Findings are raised
+// when a file is found modified or missing and again if, after a sweep saw
+// it clean, it changes again.
+use crate::config::PackagesSection;
+use crate::dpkg;
+use crate::events::{EventBus, IntegrityEvent, SecurityEvent};
+use crate::metrics;
+use crate::systemd;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// One file to check
+struct Entry {
+    path: String,
+    package: String,
+    md5: String,
+    conffile: bool,
+    critical: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepStatus {
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub files_total: usize,
+    pub files_checked: usize,
+    pub bytes_hashed: u64,
+    // Files modified or missing as of their last check
+    pub findings: usize,
+    // Packages with neither md5sums nor a verifiable .deb
+    pub unverifiable: Vec<String>,
+}
+
+#[derive(Default)]
+struct SweepState {
+    queue: VecDeque<Entry>,
+    status: SweepStatus,
+    // (path, finding) reported and not cleared since
+    standing: HashSet<(String, &'static str)>,
+}
+
+pub struct PackageSweep {
+    section: PackagesSection,
+    state: Mutex<SweepState>,
+}
+
+impl PackageSweep {
+    pub fn new(section: &PackagesSection) -> Self {
+        Self { section: section.clone(), state: Mutex::new(SweepState::default()) }
+    }
+
+    pub fn status(&self) -> SweepStatus {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    fn under(paths: &[std::path::PathBuf], path: &str) -> bool {
+        paths.iter().any(|prefix| Path::new(path).starts_with(prefix))
+    }
+
+    // Every file to check this sweep, critical ones first
+    fn plan(&self) -> std::io::Result<(VecDeque<Entry>, Vec<String>)> {
+        let installed = dpkg::installed()?;
+        let diverted = dpkg::diversions();
+        let mut entries = Vec::new();
+        let mut push = |package: &str, path: String, md5: String, conffile: bool| {
+            if diverted.contains(&path) || Self::under(&self.section.skip_paths, &path) {
+                return;
+            }
+            let critical = Self::under(&self.section.critical_paths, &path);
+            entries.push(Entry { path, package: package.to_string(), md5, conffile, critical });
+        };
+
+        let mut without = Vec::new();
+        for package in &installed {
+            match dpkg::md5sums(package) {
+                Some(md5sums) => md5sums.into_iter().for_each(|(path, md5)| push(&package.package, path, md5, false)),
+                None => without.push(package),
+            }
+            if self.section.conffiles {
+                package.conffiles.iter().for_each(|(path, md5)| push(&package.package, path.clone(), md5.clone(), true));
+            }
+        }
+        // Packages with only directories, symlinks or conffiles have no
+        // md5sums either; their .deb has nothing more to offer
+        let indexed = dpkg::index_sha256(&without);
+        let mut unverifiable = Vec::new();
+        for package in without {
+            let deb = dpkg::cached_deb(package).zip(indexed.get(&package.package));
+            match deb.map(|(deb, sha256)| dpkg::deb_md5sums(&deb, sha256)) {
+                Some(Ok(md5sums)) => md5sums.into_iter().for_each(|(path, md5)| push(&package.package, path, md5, false)),
+                Some(Err(e)) => {
+                    tracing::warn!("{} not verifiable from apt's cache: {}", package.package, e);
+                    unverifiable.push(package.package.clone());
+                }
+                None => unverifiable.push(package.package.clone()),
+            }
+        }
+        entries.sort_by_key(|entry| !entry.critical);
+        Ok((entries.into(), unverifiable))
+    }
+
+    // (finding, critical, actual md5) when the file is not as dpkg left it
+    fn verify(entry: &Entry) -> (Option<(&'static str, bool, Option<String>)>, u64) {
+        let path = Path::new(&entry.path);
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            return (Some(("package_file_missing", false, None)), 0);
+        };
+        // A file that can't be read counts as modified
+        let md5 = dpkg::file_md5(path).unwrap_or_default();
+        if md5 == entry.md5 {
+            return (None, meta.len());
+        }
+        let finding = match entry.conffile {
+            true => ("conffile_modified", false, Some(md5)),
+            false => ("package_file_modified", entry.critical, Some(md5)),
+        };
+        (Some(finding), meta.len())
+    }
+
+    // Checks the next batch, planning a sweep first when one is due. The
+    // lock is only held between files, so status stays answerable.
+    pub fn step(&self) -> Vec<IntegrityEvent> {
+        let now = systemd::now_secs();
+        let due = {
+            let state = self.state.lock().unwrap();
+            state.queue.is_empty() && state.status.finished_at.map_or(state.status.started_at.is_none(), |finished| now >= finished + self.section.sweep_interval_secs)
+        };
+        if due {
+            let planned = self.plan();
+            let mut state = self.state.lock().unwrap();
+            match planned {
+                Ok((queue, unverifiable)) => {
+                    tracing::info!("Package sweep started: {} files, {} packages unverifiable", queue.len(), unverifiable.len());
+                    state.status = SweepStatus { started_at: Some(now), files_total: queue.len(), unverifiable, findings: state.standing.len(), ..Default::default() };
+                    state.queue = queue;
+                }
+                Err(e) => {
+                    tracing::warn!("Package sweep not planned: {}", e);
+                    state.status.finished_at = Some(now);
+                    return Vec::new();
+                }
+            }
+        }
+
+        let mut findings = Vec::new();
+        let mut budget = self.section.batch_bytes;
+        while budget > 0 {
+            let Some(entry) = self.state.lock().unwrap().queue.pop_front() else {
+                break;
+            };
+            let (problem, bytes) = Self::verify(&entry);
+            budget = budget.saturating_sub(bytes.max(1));
+            metrics::global().add("qks_package_sweep_bytes_total", &[], bytes);
+
+            let mut state = self.state.lock().unwrap();
+            state.status.files_checked += 1;
+            state.status.bytes_hashed += bytes;
+            let Some((finding, critical, actual)) = problem else {
+                state.standing.retain(|(path, _)| *path != entry.path);
+                continue;
+            };
+            if !state.standing.insert((entry.path.clone(), finding)) {
+                continue;
+            }
+            state.status.findings = state.standing.len();
+            drop(state);
+
+            let detail = match finding {
+                "package_file_missing" => format!("shipped by {} but missing", entry.package),
+                "conffile_modified" => format!("conffile of {} edited", entry.package),
+                _ => format!("differs from package {}", entry.package),
+            };
+            if critical {
+                tracing::error!("{}: {}", entry.path, detail);
+            } else {
+                tracing::warn!("{}: {}", entry.path, detail);
+            }
+            metrics::global().incr("qks_integrity_findings_total", &[("source", "dpkg"), ("finding", finding)]);
+            findings.push(IntegrityEvent {
+                source: "dpkg".to_string(),
+                finding: finding.to_string(),
+                critical,
+                path: Some(entry.path),
+                pid: None,
+                expected: Some(format!("md5:{}", entry.md5)),
+                actual: actual.map(|md5| format!("md5:{}", md5)),
+                detail: Some(detail),
+                timestamp: now,
+            });
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.status.findings = state.standing.len();
+        if state.queue.is_empty() && state.status.started_at.is_some() && state.status.finished_at.is_none() {
+            state.status.finished_at = Some(systemd::now_secs());
+            tracing::info!(
+                "Package sweep finished: {} files, {} bytes, {} modified or missing",
+                state.status.files_checked,
+                state.status.bytes_hashed,
+                state.status.findings,
+            );
+        }
+        findings
+    }
+
+    // A batch every tick_secs
+    pub fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(self.section.tick_secs));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let sweep = self.clone();
+                match tokio::task::spawn_blocking(move || sweep.step()).await {
+                    Ok(findings) => {
+                        for finding in findings {
+                            bus.publish(SecurityEvent::Integrity(finding));
+                        }
+                    }
+                    Err(e) => tracing::error!("Package sweep panicked: {}", e),
+                }
+            }
+        })
+    }
+}
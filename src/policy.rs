@@ -0,0 +1,800 @@
+// src/policy.rs
+// Declarative response rules, from [[policy.rules]] in the config:
+//
+//   name = "unprivileged-exfil"
+//   on = ["anomaly"]
+//   when = "score > 0.9 AND NOT has_capability(network)"
+//   then = ["quarantine", "snapshot"]
+//
+// `when` combines comparisons on event fields (see FIELDS) with AND, OR,
+// NOT and parentheses; has_capability(network | fs[:PATH] | syscall[:NR] |
+// memory) looks at the capabilities the process's token grants; image and
+// pod ("namespace/name") are those of the process's enrolled container,
+// and never match outside one. netns is the inode of the process's
+// network namespace and host_network whether that is init's, so egress
+// rules can be scoped per namespace: `event == connect AND host_network ==
+// false AND destination_port == 25` on ["network"]. Conditions are parsed
+// and type-checked against the listed event kinds when the config is
+// loaded, so a misspelt field fails validation instead of never matching. Every rule whose kind matches is evaluated on each event, in
+// order; a rule fires at most once per PID per cooldown. Pods annotated
+// dry-run or log-only (kubernetes.rs) get their matches recorded rather
+// than acted on.
+//
+// For logic the condition language can't express, a rule may also carry a
+// Rhai `script` (policy_script.rs), alone or alongside `when`; both must
+// hold for the rule to match:
+//
+//   script = '''
+//     let exe = event.exe ?? "";
+//     event.score > 0.8 && (exe.ends_with("/xmrig") || exe.starts_with("/tmp/"))
+//   '''
+//
+// [[policy.transforms]] run first, on the kinds they list, and return a
+// map of fields derived from the event, which later transforms and rule
+// scripts see as part of `event` and which are named with any match they
+// lead to:
+//
+//   name = "internal-destination"
+//   on = ["network"]
+//   script = '#{ internal: in_cidr(event.destination ?? "", "10.0.0.0/8") }'
+//
+// `event` holds every FIELDS entry the event carries, with whole numbers
+// as integers, and `capabilities` as strings ("network", "fs:/srv",
+// "syscall:59", "memory:1048576"). A transform can't overwrite a field
+// that is already there. Scripts are compiled when the config is loaded,
+// but field names are not checked: one the event lacks reads as (). A
+// script that fails, runs over its limits or returns the wrong type counts
+// as not matching, in qks_policy_script_errors_total.
+use crate::config::PolicySection;
+use crate::crypto_identifiers::Capability;
+use crate::container_events;
+use crate::events::{ContainerEvent, EventBus, EventKind, SecurityEvent, SnapshotEvent, TokenEvent};
+use crate::kubernetes::{self, ResponseMode};
+use crate::metrics;
+use crate::netns;
+use crate::policy_script::Script;
+use crate::response::{Responder, ResponseAction, ResponseRequest};
+use dashmap::DashMap;
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("rule {rule:?}: {message}")]
+    Invalid { rule: String, message: String },
+    #[error("rule {rule:?}: at offset {offset}: {message}")]
+    Syntax { rule: String, offset: usize, message: String },
+}
+
+// Carried out by the response subsystem, except `log`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    // Capped cgroup, no network; see quarantine.rs
+    Quarantine,
+    // SIGSTOP the process
+    Stop,
+    Kill,
+    Snapshot,
+    // Draw and apply a fresh layout
+    Rerandomize,
+    RevokeToken,
+    BlockEgress,
+    // Only record that the rule matched
+    Log,
+}
+
+impl PolicyAction {
+    fn as_str(self) -> &'static str {
+        match self.response() {
+            Some(action) => action.as_str(),
+            None => "log",
+        }
+    }
+
+    fn response(self) -> Option<ResponseAction> {
+        match self {
+            PolicyAction::Quarantine => Some(ResponseAction::Quarantine),
+            PolicyAction::Stop => Some(ResponseAction::Stop),
+            PolicyAction::Kill => Some(ResponseAction::Kill),
+            PolicyAction::Snapshot => Some(ResponseAction::Snapshot),
+            PolicyAction::Rerandomize => Some(ResponseAction::Rerandomize),
+            PolicyAction::RevokeToken => Some(ResponseAction::RevokeToken),
+            PolicyAction::BlockEgress => Some(ResponseAction::BlockEgress),
+            PolicyAction::Log => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub name: String,
+    pub on: Vec<EventKind>,
+    #[serde(default)]
+    pub when: String,
+    #[serde(default)]
+    pub script: Option<String>,
+    pub then: Vec<PolicyAction>,
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyTransform {
+    pub name: String,
+    pub on: Vec<EventKind>,
+    pub script: String,
+}
+
+fn default_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldType {
+    Number,
+    Text,
+    Bool,
+}
+
+const PROCESS_KINDS: &[EventKind] = &[EventKind::Syscall, EventKind::Anomaly, EventKind::Token, EventKind::Layout, EventKind::Response, EventKind::Integrity, EventKind::Container, EventKind::Network, EventKind::Intel];
+
+// Every field a condition may name, its type, and the kinds that carry it
+const FIELDS: &[(&str, FieldType, &[EventKind])] = &[
+    ("kind", FieldType::Text, EventKind::ALL),
+    ("pid", FieldType::Number, PROCESS_KINDS),
+    ("image", FieldType::Text, PROCESS_KINDS),
+    ("pod", FieldType::Text, PROCESS_KINDS),
+    ("netns", FieldType::Number, PROCESS_KINDS),
+    ("host_network", FieldType::Bool, PROCESS_KINDS),
+    ("score", FieldType::Number, &[EventKind::Anomaly]),
+    ("threshold", FieldType::Number, &[EventKind::Anomaly]),
+    ("exe", FieldType::Text, &[EventKind::Anomaly, EventKind::Intel]),
+    ("model_version", FieldType::Number, &[EventKind::Anomaly, EventKind::Drift]),
+    ("syscall", FieldType::Number, &[EventKind::Syscall]),
+    ("duration_ns", FieldType::Number, &[EventKind::Syscall]),
+    ("retval", FieldType::Number, &[EventKind::Syscall]),
+    ("event", FieldType::Text, &[EventKind::Token, EventKind::Snapshot, EventKind::Container, EventKind::Network]),
+    ("trigger", FieldType::Text, &[EventKind::Layout]),
+    ("regeneration_count", FieldType::Number, &[EventKind::Layout]),
+    ("snapshot_id", FieldType::Text, &[EventKind::Snapshot]),
+    ("valid", FieldType::Bool, &[EventKind::Snapshot]),
+    ("action", FieldType::Text, &[EventKind::Response]),
+    ("outcome", FieldType::Text, &[EventKind::Response]),
+    ("succeeded", FieldType::Bool, &[EventKind::Response]),
+    ("source", FieldType::Text, &[EventKind::Integrity]),
+    ("finding", FieldType::Text, &[EventKind::Integrity]),
+    ("critical", FieldType::Bool, &[EventKind::Integrity]),
+    ("path", FieldType::Text, &[EventKind::Integrity, EventKind::Intel]),
+    ("destination", FieldType::Text, &[EventKind::Network]),
+    ("destination_port", FieldType::Number, &[EventKind::Network]),
+    ("source_port", FieldType::Number, &[EventKind::Network]),
+    ("observed", FieldType::Text, &[EventKind::Intel]),
+    ("indicator", FieldType::Text, &[EventKind::Intel]),
+    ("indicator_type", FieldType::Text, &[EventKind::Intel]),
+    ("matched", FieldType::Text, &[EventKind::Intel]),
+    ("feed", FieldType::Text, &[EventKind::Intel]),
+    ("severity", FieldType::Number, &[EventKind::Intel]),
+];
+
+// Kinds whose events say which capabilities the process holds
+const CAPABILITY_KINDS: &[EventKind] = &[EventKind::Anomaly, EventKind::Token];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CapabilityPattern {
+    Network,
+    Filesystem(Option<String>),
+    Syscall(Option<u32>),
+    Memory,
+}
+
+impl CapabilityPattern {
+    fn parse(arg: &str) -> Option<Self> {
+        let (kind, value) = match arg.split_once(':') {
+            Some((kind, value)) => (kind, Some(value)),
+            None => (arg, None),
+        };
+        match (kind, value) {
+            ("network", None) => Some(CapabilityPattern::Network),
+            ("fs", path) => Some(CapabilityPattern::Filesystem(path.map(str::to_string))),
+            ("syscall", None) => Some(CapabilityPattern::Syscall(None)),
+            ("syscall", Some(nr)) => nr.parse().ok().map(|nr| CapabilityPattern::Syscall(Some(nr))),
+            ("memory", None) => Some(CapabilityPattern::Memory),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, capability: &Capability) -> bool {
+        match (self, capability) {
+            (CapabilityPattern::Network, Capability::NetworkAccess) => true,
+            (CapabilityPattern::Filesystem(None), Capability::FilesystemAccess(_)) => true,
+            // The granted prefix must cover the path asked about
+            (CapabilityPattern::Filesystem(Some(path)), Capability::FilesystemAccess(prefix)) => path.starts_with(prefix.as_str()),
+            (CapabilityPattern::Syscall(None), Capability::Syscall(_)) => true,
+            (CapabilityPattern::Syscall(Some(nr)), Capability::Syscall(granted)) => nr == granted,
+            (CapabilityPattern::Memory, Capability::MemoryAllocation(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Compare { field: String, op: CmpOp, value: Value },
+    HasCapability(CapabilityPattern),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Op(CmpOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+struct Parser<'a> {
+    rule: &'a str,
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(rule: &'a str, source: &str) -> Result<Self, PolicyError> {
+        let syntax = |offset: usize, message: String| PolicyError::Syntax { rule: rule.to_string(), offset, message };
+        let chars: Vec<(usize, char)> = source.char_indices().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let (offset, c) = chars[i];
+            let two: String = chars[i..].iter().take(2).map(|(_, c)| *c).collect();
+            let (token, width) = match c {
+                c if c.is_whitespace() => {
+                    i += 1;
+                    continue;
+                }
+                '(' => (Token::Open, 1),
+                ')' => (Token::Close, 1),
+                _ if two == ">=" => (Token::Op(CmpOp::Ge), 2),
+                _ if two == "<=" => (Token::Op(CmpOp::Le), 2),
+                _ if two == "==" => (Token::Op(CmpOp::Eq), 2),
+                _ if two == "!=" => (Token::Op(CmpOp::Ne), 2),
+                _ if two == "&&" => (Token::And, 2),
+                _ if two == "||" => (Token::Or, 2),
+                '>' => (Token::Op(CmpOp::Gt), 1),
+                '<' => (Token::Op(CmpOp::Lt), 1),
+                '!' => (Token::Not, 1),
+                '"' => {
+                    let close = chars[i + 1..].iter().position(|(_, c)| *c == '"')
+                        .ok_or_else(|| syntax(offset, "unterminated string".into()))?;
+                    let text = chars[i + 1..i + 1 + close].iter().map(|(_, c)| *c).collect();
+                    (Token::Text(text), close + 2)
+                }
+                c if c.is_ascii_digit() || c == '-' => {
+                    let len = chars[i..].iter().take_while(|(_, c)| c.is_ascii_digit() || matches!(c, '.' | '-' | 'e' | 'E')).count();
+                    let text: String = chars[i..i + len].iter().map(|(_, c)| *c).collect();
+                    let number = text.parse().map_err(|_| syntax(offset, format!("bad number {:?}", text)))?;
+                    (Token::Number(number), len)
+                }
+                c if c.is_alphabetic() || c == '_' => {
+                    let len = chars[i..].iter().take_while(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.' | ':' | '/' | '-')).count();
+                    let word: String = chars[i..i + len].iter().map(|(_, c)| *c).collect();
+                    let token = match word.to_ascii_uppercase().as_str() {
+                        "AND" => Token::And,
+                        "OR" => Token::Or,
+                        "NOT" => Token::Not,
+                        _ => Token::Ident(word),
+                    };
+                    (token, len)
+                }
+                c => return Err(syntax(offset, format!("unexpected {:?}", c))),
+            };
+            tokens.push((offset, token));
+            i += width;
+        }
+        Ok(Self { rule, tokens, next: 0, end: source.len() })
+    }
+
+    fn error(&self, message: impl Into<String>) -> PolicyError {
+        let offset = self.tokens.get(self.next).map_or(self.end, |(offset, _)| *offset);
+        PolicyError::Syntax { rule: self.rule.to_string(), offset, message: message.into() }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn take(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).map(|(_, token)| token.clone());
+        self.next += 1;
+        token
+    }
+
+    fn parse(mut self) -> Result<Condition, PolicyError> {
+        let condition = self.or()?;
+        if self.peek().is_some() {
+            return Err(self.error("expected AND, OR or the end of the condition"));
+        }
+        Ok(condition)
+    }
+
+    fn or(&mut self) -> Result<Condition, PolicyError> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            left = Condition::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Condition, PolicyError> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next += 1;
+            left = Condition::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Condition, PolicyError> {
+        match self.take() {
+            Some(Token::Not) => Ok(Condition::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                match self.take() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(self.error("expected )")),
+                }
+            }
+            Some(Token::Ident(name)) if name == "has_capability" => {
+                let (Some(Token::Open), Some(Token::Ident(arg)), Some(Token::Close)) = (self.take(), self.take(), self.take()) else {
+                    return Err(self.error("expected has_capability(network | fs[:PATH] | syscall[:NR] | memory)"));
+                };
+                CapabilityPattern::parse(&arg)
+                    .map(Condition::HasCapability)
+                    .ok_or_else(|| self.error(format!("unknown capability {:?}", arg)))
+            }
+            Some(Token::Ident(field)) => {
+                let Some(Token::Op(op)) = self.take() else {
+                    return Err(self.error(format!("expected a comparison after {}", field)));
+                };
+                let value = match self.take() {
+                    Some(Token::Number(n)) => Value::Number(n),
+                    Some(Token::Text(text)) => Value::Text(text),
+                    Some(Token::Ident(word)) if word == "true" || word == "false" => Value::Bool(word == "true"),
+                    // Bare words compare as text: `trigger == scheduled`
+                    Some(Token::Ident(word)) => Value::Text(word),
+                    _ => return Err(self.error("expected a number, \"text\", true or false")),
+                };
+                Ok(Condition::Compare { field, op, value })
+            }
+            _ => Err(self.error("expected a field, NOT, ( or has_capability")),
+        }
+    }
+}
+
+impl Condition {
+    // Every field must exist, with a matching type, on every listed kind
+    fn check(&self, rule: &str, kinds: &[EventKind]) -> Result<(), PolicyError> {
+        let invalid = |message: String| PolicyError::Invalid { rule: rule.to_string(), message };
+        match self {
+            Condition::And(a, b) | Condition::Or(a, b) => {
+                a.check(rule, kinds)?;
+                b.check(rule, kinds)
+            }
+            Condition::Not(inner) => inner.check(rule, kinds),
+            Condition::HasCapability(_) => match kinds.iter().find(|k| !CAPABILITY_KINDS.contains(k)) {
+                Some(kind) => Err(invalid(format!("has_capability is not available on {} events", kind.as_str()))),
+                None => Ok(()),
+            },
+            Condition::Compare { field, op, value } => {
+                let Some((_, ty, carried_by)) = FIELDS.iter().find(|(name, _, _)| name == field) else {
+                    let known: Vec<&str> = FIELDS.iter().map(|(name, _, _)| *name).collect();
+                    return Err(invalid(format!("unknown field {:?} (known: {})", field, known.join(", "))));
+                };
+                if let Some(kind) = kinds.iter().find(|k| !carried_by.contains(k)) {
+                    return Err(invalid(format!("{} is not available on {} events", field, kind.as_str())));
+                }
+                let compatible = match (ty, value) {
+                    (FieldType::Number, Value::Number(_)) => true,
+                    (FieldType::Text, Value::Text(_)) | (FieldType::Bool, Value::Bool(_)) => matches!(op, CmpOp::Eq | CmpOp::Ne),
+                    _ => false,
+                };
+                if compatible {
+                    Ok(())
+                } else {
+                    Err(invalid(format!("{} is a {:?} field and cannot be compared that way with {:?}", field, ty, value)))
+                }
+            }
+        }
+    }
+
+    fn eval(&self, event: &SecurityEvent) -> bool {
+        match self {
+            Condition::And(a, b) => a.eval(event) && b.eval(event),
+            Condition::Or(a, b) => a.eval(event) || b.eval(event),
+            Condition::Not(inner) => !inner.eval(event),
+            Condition::HasCapability(pattern) => capabilities(event).iter().any(|c| pattern.matches(c)),
+            // A field the event doesn't carry (a PID on a snapshot) never compares
+            Condition::Compare { field, op, value } => match (field_value(event, field), value) {
+                (Some(Value::Number(a)), Value::Number(b)) => match op {
+                    CmpOp::Gt => a > *b,
+                    CmpOp::Ge => a >= *b,
+                    CmpOp::Lt => a < *b,
+                    CmpOp::Le => a <= *b,
+                    CmpOp::Eq => a == *b,
+                    CmpOp::Ne => a != *b,
+                },
+                (Some(actual), expected) => match op {
+                    CmpOp::Eq => actual == *expected,
+                    CmpOp::Ne => actual != *expected,
+                    _ => false,
+                },
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+fn field_value(event: &SecurityEvent, field: &str) -> Option<Value> {
+    let number = |n: f64| Some(Value::Number(n));
+    let text = |s: &str| Some(Value::Text(s.to_string()));
+    match (field, event) {
+        ("kind", _) => text(event.kind().as_str()),
+        ("pid", _) => event.pid().and_then(|pid| number(pid as f64)),
+        ("image", _) => container_events::for_event(event).and_then(|container| text(&container.image)),
+        ("pod", _) => container_events::for_event(event)?.pod.and_then(|pod| text(&format!("{}/{}", pod.namespace, pod.name))),
+        ("netns", _) => netns_of(event).and_then(|netns| number(netns as f64)),
+        ("host_network", _) => netns_of(event).map(|netns| Value::Bool(Some(netns) == netns::host())),
+        ("score", SecurityEvent::Anomaly(e)) => number(e.score as f64),
+        ("threshold", SecurityEvent::Anomaly(e)) => number(e.threshold as f64),
+        ("exe", SecurityEvent::Anomaly(e)) => e.exe.as_deref().and_then(text),
+        ("exe", SecurityEvent::Intel(e)) => e.exe.as_deref().and_then(text),
+        ("model_version", SecurityEvent::Anomaly(e)) => number(e.model_version as f64),
+        ("model_version", SecurityEvent::Drift(e)) => number(e.model_version as f64),
+        ("syscall", SecurityEvent::Syscall(e)) => number(e.syscall as f64),
+        ("duration_ns", SecurityEvent::Syscall(e)) => number(e.duration_ns as f64),
+        ("retval", SecurityEvent::Syscall(e)) => number(e.retval as f64),
+        ("event", SecurityEvent::Token(TokenEvent::Issued { .. })) => text("issued"),
+        ("event", SecurityEvent::Token(TokenEvent::Revoked { .. })) => text("revoked"),
+        ("event", SecurityEvent::Token(TokenEvent::BudgetExceeded { .. })) => text("budget_exceeded"),
+        ("event", SecurityEvent::Snapshot(SnapshotEvent::Taken { .. })) => text("taken"),
+        ("event", SecurityEvent::Snapshot(SnapshotEvent::Restored { .. })) => text("restored"),
+        ("event", SecurityEvent::Snapshot(SnapshotEvent::Verified { .. })) => text("verified"),
+        ("event", SecurityEvent::Container(ContainerEvent::Started { .. })) => text("started"),
+        ("event", SecurityEvent::Container(ContainerEvent::Stopped { .. })) => text("stopped"),
+        ("event", SecurityEvent::Network(e)) => text(e.action.as_str()),
+        ("trigger", SecurityEvent::Layout(e)) => text(&format!("{:?}", e.trigger).to_lowercase()),
+        ("regeneration_count", SecurityEvent::Layout(e)) => number(e.regeneration_count as f64),
+        ("snapshot_id", SecurityEvent::Snapshot(
+            SnapshotEvent::Taken { snapshot_id } | SnapshotEvent::Restored { snapshot_id, .. } | SnapshotEvent::Verified { snapshot_id, .. },
+        )) => text(snapshot_id),
+        ("valid", SecurityEvent::Snapshot(SnapshotEvent::Verified { valid, .. })) => Some(Value::Bool(*valid)),
+        ("action", SecurityEvent::Response(e)) => text(&e.action),
+        ("outcome", SecurityEvent::Response(e)) => Some(Value::Text(e.outcome.clone())),
+        ("succeeded", SecurityEvent::Response(e)) => Some(Value::Bool(e.succeeded)),
+        ("source", SecurityEvent::Integrity(e)) => text(&e.source),
+        ("finding", SecurityEvent::Integrity(e)) => text(&e.finding),
+        ("critical", SecurityEvent::Integrity(e)) => Some(Value::Bool(e.critical)),
+        ("path", SecurityEvent::Integrity(e)) => e.path.as_deref().and_then(text),
+        ("path", SecurityEvent::Intel(e)) => e.path.as_deref().and_then(text),
+        ("destination", SecurityEvent::Network(e)) => e.destination.and_then(|address| text(&address.to_string())),
+        ("destination_port", SecurityEvent::Network(e)) if e.destination.is_some() => number(e.destination_port as f64),
+        ("source_port", SecurityEvent::Network(e)) if e.source.is_some() => number(e.source_port as f64),
+        ("observed", SecurityEvent::Intel(e)) => text(&e.observed),
+        ("indicator", SecurityEvent::Intel(e)) => text(&e.indicator),
+        ("indicator_type", SecurityEvent::Intel(e)) => text(&e.indicator_type),
+        ("matched", SecurityEvent::Intel(e)) => text(&e.matched),
+        ("feed", SecurityEvent::Intel(e)) => text(&e.feed),
+        ("severity", SecurityEvent::Intel(e)) => number(e.severity as f64),
+        _ => None,
+    }
+}
+
+// Network events say where they happened; for the rest, where the process is
+fn netns_of(event: &SecurityEvent) -> Option<u64> {
+    match event {
+        SecurityEvent::Network(e) => Some(e.netns),
+        _ => netns::global().netns_of(event.pid()?),
+    }
+}
+
+// What a script sees as `event`. Whole numbers become integers, so
+// `event.pid == 1234` holds.
+fn script_fields(event: &SecurityEvent) -> rhai::Map {
+    let mut fields: rhai::Map = FIELDS
+        .iter()
+        .filter_map(|(name, _, _)| {
+            let value = match field_value(event, name)? {
+                Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Dynamic::from_int(n as i64),
+                Value::Number(n) => Dynamic::from_float(n),
+                Value::Text(text) => Dynamic::from(text),
+                Value::Bool(b) => Dynamic::from_bool(b),
+            };
+            Some(((*name).into(), value))
+        })
+        .collect();
+    let capabilities: rhai::Array = capabilities(event).iter().map(|capability| Dynamic::from(capability_name(capability))).collect();
+    fields.insert("capabilities".into(), Dynamic::from_array(capabilities));
+    fields
+}
+
+// In has_capability's own syntax, with the granted value after the colon
+fn capability_name(capability: &Capability) -> String {
+    match capability {
+        Capability::NetworkAccess => "network".to_string(),
+        Capability::FilesystemAccess(prefix) => format!("fs:{}", prefix),
+        Capability::Syscall(nr) => format!("syscall:{}", nr),
+        Capability::MemoryAllocation(bytes) => format!("memory:{}", bytes),
+    }
+}
+
+// A process without a token holds no capabilities
+fn capabilities(event: &SecurityEvent) -> &[Capability] {
+    match event {
+        SecurityEvent::Anomaly(e) => e.capabilities.as_deref().unwrap_or_default(),
+        SecurityEvent::Token(TokenEvent::Issued { capabilities, .. }) => capabilities,
+        _ => &[],
+    }
+}
+
+pub struct CompiledRule {
+    rule: PolicyRule,
+    // None for rules with only a script
+    condition: Option<Condition>,
+    script: Option<Script>,
+}
+
+impl CompiledRule {
+    pub fn name(&self) -> &str {
+        &self.rule.name
+    }
+}
+
+struct CompiledTransform {
+    transform: PolicyTransform,
+    script: Script,
+}
+
+pub struct CompiledPolicy {
+    rules: Vec<CompiledRule>,
+    transforms: Vec<CompiledTransform>,
+}
+
+// Parse and check every rule and transform; the first problem fails the
+// whole set
+pub fn compile(policy: &PolicySection) -> Result<CompiledPolicy, PolicyError> {
+    let mut transforms: Vec<CompiledTransform> = Vec::new();
+    for transform in &policy.transforms {
+        let invalid = |message: String| PolicyError::Invalid { rule: transform.name.clone(), message };
+        if transform.name.is_empty() {
+            return Err(invalid("transform name must not be empty".into()));
+        }
+        if transforms.iter().any(|c| c.transform.name == transform.name) {
+            return Err(invalid("duplicate transform name".into()));
+        }
+        if transform.on.is_empty() {
+            return Err(invalid("`on` must list at least one event kind".into()));
+        }
+        let script = Script::compile(&transform.script).map_err(|e| invalid(format!("script: {}", e)))?;
+        transforms.push(CompiledTransform { transform: transform.clone(), script });
+    }
+
+    let mut compiled: Vec<CompiledRule> = Vec::new();
+    for rule in &policy.rules {
+        let invalid = |message: &str| PolicyError::Invalid { rule: rule.name.clone(), message: message.to_string() };
+        if rule.name.is_empty() {
+            return Err(invalid("name must not be empty"));
+        }
+        if compiled.iter().any(|c| c.rule.name == rule.name) {
+            return Err(invalid("duplicate rule name"));
+        }
+        if rule.on.is_empty() {
+            return Err(invalid("`on` must list at least one event kind"));
+        }
+        if rule.then.is_empty() {
+            return Err(invalid("`then` must list at least one action"));
+        }
+        // Acting on responses would let a rule trigger itself
+        if rule.on.contains(&EventKind::Response) && rule.then.iter().any(|a| *a != PolicyAction::Log) {
+            return Err(invalid("rules on response events may only log"));
+        }
+        if rule.when.trim().is_empty() && rule.script.is_none() {
+            return Err(invalid("needs a `when` condition, a `script` or both"));
+        }
+        let condition = match rule.when.trim() {
+            "" => None,
+            when => {
+                let condition = Parser::new(&rule.name, when)?.parse()?;
+                condition.check(&rule.name, &rule.on)?;
+                Some(condition)
+            }
+        };
+        let script = match &rule.script {
+            Some(source) => Some(Script::compile(source).map_err(|e| invalid(&format!("script: {}", e)))?),
+            None => None,
+        };
+        compiled.push(CompiledRule { rule: rule.clone(), condition, script });
+    }
+    Ok(CompiledPolicy { rules: compiled, transforms })
+}
+
+fn script_error(script: &str, error: &str) {
+    tracing::debug!("Policy script {} failed: {}", script, error);
+    metrics::global().incr("qks_policy_script_errors_total", &[("script", script)]);
+}
+
+pub struct PolicyEngine {
+    policy: RwLock<Arc<CompiledPolicy>>,
+    response: Arc<Responder>,
+    // (rule, pid) -> when it last fired; PID 0 for events without one
+    fired: DashMap<(String, u32), Instant>,
+    changed: Notify,
+}
+
+impl PolicyEngine {
+    pub(crate) fn new(policy: &PolicySection, response: Arc<Responder>) -> Result<Self, PolicyError> {
+        Ok(Self {
+            policy: RwLock::new(Arc::new(compile(policy)?)),
+            response,
+            fired: DashMap::new(),
+            changed: Notify::new(),
+        })
+    }
+
+    // Swap in a new rule set; the old one stays if the new one is invalid
+    pub fn replace_rules(&self, policy: &PolicySection) -> Result<(), PolicyError> {
+        let compiled = compile(policy)?;
+        tracing::info!("Policy now has {} rules and {} transforms", compiled.rules.len(), compiled.transforms.len());
+        *self.policy.write().unwrap() = Arc::new(compiled);
+        self.fired.clear();
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn kinds(&self) -> Vec<EventKind> {
+        let policy = self.policy.read().unwrap().clone();
+        let mut kinds: Vec<EventKind> = Vec::new();
+        for kind in policy.rules.iter().filter(|r| r.rule.enabled).flat_map(|r| r.rule.on.iter()) {
+            if !kinds.contains(kind) {
+                kinds.push(*kind);
+            }
+        }
+        kinds
+    }
+
+    // Subscribes only to the kinds some enabled rule is on, so syscalls are
+    // not copied onto the bus unless a rule asks for them
+    pub(crate) fn start(self: Arc<Self>, bus: Arc<EventBus>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let mut events = bus.subscribe_to(&self.kinds()).with_name("Policy engine");
+                loop {
+                    tokio::select! {
+                        event = events.recv() => match event {
+                            Some(event) => {
+                                let engine = self.clone();
+                                if let Err(e) = tokio::task::spawn_blocking(move || engine.evaluate(&event)).await {
+                                    tracing::warn!("Policy evaluation task failed: {}", e);
+                                }
+                            }
+                            // No rules, no subscription: wait for new ones
+                            None => {
+                                self.changed.notified().await;
+                                break;
+                            }
+                        },
+                        _ = self.changed.notified() => break,
+                    }
+                }
+            }
+        })
+    }
+
+    fn evaluate(&self, event: &SecurityEvent) {
+        let policy = self.policy.read().unwrap().clone();
+        let kind = event.kind();
+        // Only scripts read it, so it is built the first time one runs
+        let mut fields: Option<rhai::Map> = None;
+        let mut derived: Vec<String> = Vec::new();
+        for transform in policy.transforms.iter().filter(|t| t.transform.on.contains(&kind)) {
+            let fields = fields.get_or_insert_with(|| script_fields(event));
+            match transform.script.derive(fields) {
+                Ok(map) => {
+                    for (name, value) in map {
+                        if !fields.contains_key(&name) {
+                            derived.push(format!("{}={}", name, value));
+                            fields.insert(name, value);
+                        }
+                    }
+                }
+                Err(e) => script_error(&transform.transform.name, &e),
+            }
+        }
+        let detail = if derived.is_empty() { String::new() } else { format!(" ({})", derived.join(", ")) };
+
+        for rule in policy.rules.iter().filter(|r| r.rule.enabled && r.rule.on.contains(&kind)) {
+            let started = Instant::now();
+            let matched = rule.condition.as_ref().map_or(true, |condition| condition.eval(event))
+                && rule.script.as_ref().map_or(true, |script| match script.matches(fields.get_or_insert_with(|| script_fields(event))) {
+                    Ok(matched) => matched,
+                    Err(e) => {
+                        script_error(rule.name(), &e);
+                        false
+                    }
+                });
+            let labels = [("rule", rule.name())];
+            metrics::global().observe("qks_policy_eval_seconds", &labels, started.elapsed());
+            metrics::global().incr("qks_policy_evaluations_total", &labels);
+            if !matched {
+                continue;
+            }
+            metrics::global().incr("qks_policy_matches_total", &labels);
+
+            let pid = event.pid();
+            let key = (rule.rule.name.clone(), pid.unwrap_or(0));
+            let cooldown = Duration::from_secs(rule.rule.cooldown_secs);
+            if self.fired.get(&key).map_or(false, |at| at.elapsed() < cooldown) {
+                metrics::global().incr("qks_policy_suppressed_total", &labels);
+                continue;
+            }
+            self.fired.insert(key, Instant::now());
+
+            tracing::warn!(pid, "Policy rule {} matched {} event{}", rule.name(), kind.as_str(), detail);
+            // A pod may ask for its processes to be left alone (kubernetes.rs)
+            let mode = pid.map_or(ResponseMode::Enforce, |pid| kubernetes::global().response_for(pid));
+            if mode == ResponseMode::LogOnly {
+                metrics::global().incr("qks_policy_suppressed_total", &labels);
+                continue;
+            }
+            // The responder logs and audits each action itself
+            for action in rule.rule.then.iter().filter_map(|a| a.response()) {
+                let record = self.response.execute(ResponseRequest {
+                    action,
+                    pid,
+                    origin: format!("policy:{}", rule.name()),
+                    reason: format!("rule {} matched {} event{}", rule.name(), kind.as_str(), detail),
+                    dry_run: mode == ResponseMode::DryRun,
+                });
+                let outcome = if record.outcome.is_success() { "success" } else { "failure" };
+                metrics::global().incr("qks_policy_actions_total", &[("rule", rule.name()), ("action", action.as_str()), ("result", outcome)]);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// src/policy_script.rs
+// The Rhai engine behind policy `script` conditions and transforms
+// (policy.rs). Scripts get a deliberately small API: the standard Rhai
+// packages (strings, numbers, arrays, maps; Rhai itself has no file or
+// network I/O), in_cidr(address, cidr), and print/debug going to the
+// daemon's debug log. `eval` is disabled and `import` resolves nothing.
+// Every run is bounded in operations, call depth, expression depth and
+// string, array and map sizes, so a script can slow one evaluation down
+// but not wedge the policy engine.
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 16;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 4096;
+const MAX_MAP_SIZE: usize = 1024;
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut engine = Engine::new();
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_MAP_SIZE);
+        engine.on_print(|text| tracing::debug!("Policy script: {}", text));
+        engine.on_debug(|text, source, position| tracing::debug!("Policy script {} at {}: {}", source.unwrap_or("-"), position, text));
+        engine.register_fn("in_cidr", in_cidr);
+        engine
+    })
+}
+
+// A compiled script; `event` is the only variable in scope when it runs
+pub(crate) struct Script {
+    ast: AST,
+}
+
+impl Script {
+    pub(crate) fn compile(source: &str) -> Result<Self, String> {
+        engine().compile(source).map(|ast| Self { ast }).map_err(|e| e.to_string())
+    }
+
+    fn run(&self, event: &Map) -> Result<Dynamic, String> {
+        let mut scope = Scope::new();
+        scope.push_constant("event", event.clone());
+        engine().eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast).map_err(|e| e.to_string())
+    }
+
+    // For conditions, which must come out true or false
+    pub(crate) fn matches(&self, event: &Map) -> Result<bool, String> {
+        let value = self.run(event)?;
+        value.as_bool().map_err(|type_name| format!("returned {} instead of a bool", type_name))
+    }
+
+    // For transforms, which return the fields they derive
+    pub(crate) fn derive(&self, event: &Map) -> Result<Map, String> {
+        let value = self.run(event)?;
+        if value.is::<Map>() {
+            Ok(value.cast::<Map>())
+        } else {
+            Err(format!("returned {} instead of a map", value.type_name()))
+        }
+    }
+}
+
+// "10.1.2.3" in "10.0.0.0/8"; anything unparsable is not in it
+fn in_cidr(address: &str, cidr: &str) -> bool {
+    let Some((network, bits)) = cidr.split_once('/') else {
+        return false;
+    };
+    let (Ok(address), Ok(network), Ok(bits)) = (address.parse::<IpAddr>(), network.parse::<IpAddr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) if bits <= 32 => same_prefix(u32::from(address).into(), u32::from(network).into(), 32, bits),
+        (IpAddr::V6(address), IpAddr::V6(network)) if bits <= 128 => same_prefix(address.into(), network.into(), 128, bits),
+        _ => false,
+    }
+}
+
+fn same_prefix(address: u128, network: u128, width: u32, bits: u32) -> bool {
+    bits == 0 || (address ^ network) >> (width - bits) == 0
+}
@@ -0,0 +1,909 @@
+// src/privsep.rs
+// Privilege separation: with [privsep] qksd drops root after startup and
+// asks qks-helper, which checks every request against the configuration.
+use crate::config::QksConfig;
+use crate::crash_watchdog;
+use crate::process_maps::ProcessMaps;
+use crate::quarantine::{self, CGROUP_ROOT};
+use crate::remap_engine::{PointerPatch, ProtectionChange, RegionMove, RemapEngine};
+use crate::response::{self, NFT_TABLE};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+pub const DEFAULT_HELPER_SOCKET: &str = "/run/qks/helper.sock";
+// Remap requests carry every heap pointer patch
+const MAX_MESSAGE_BYTES: usize = 16 << 20;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONTROLLERS: &[&str] = &["+cpu", "+memory", "+pids"];
+const LIMIT_FILES: &[&str] = &["cpu.max", "memory.max", "memory.swap.max", "pids.max"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum PrivsepError {
+    #[error("privsep {call}: {source}")]
+    Syscall { call: &'static str, source: io::Error },
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("no such user {0:?}")]
+    UnknownUser(String),
+}
+
+fn syscall_error(call: &'static str) -> PrivsepError {
+    PrivsepError::Syscall { call, source: io::Error::last_os_error() }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Signal {
+    Kill,
+    Stop,
+    Cont,
+}
+
+impl Signal {
+    fn number(self) -> libc::c_int {
+        match self {
+            Signal::Kill => libc::SIGKILL,
+            Signal::Stop => libc::SIGSTOP,
+            Signal::Cont => libc::SIGCONT,
+        }
+    }
+}
+
+// /proc/PID files whose reads need more than the core's own user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcFile {
+    Maps,
+    Smaps,
+    Mem,
+}
+
+impl ProcFile {
+    fn name(self) -> &'static str {
+        match self {
+            ProcFile::Maps => "maps",
+            ProcFile::Smaps => "smaps",
+            ProcFile::Mem => "mem",
+        }
+    }
+}
+
+// What the watchdog does to a service, as systemctl verbs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnitAction {
+    Stop,
+    ResetFailed,
+    Start,
+}
+
+impl UnitAction {
+    pub fn verb(self) -> &'static str {
+        match self {
+            UnitAction::Stop => "stop",
+            UnitAction::ResetFailed => "reset-failed",
+            UnitAction::Start => "start",
+        }
+    }
+}
+
+// Cgroup paths are relative to the cgroup2 mount
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum HelperRequest {
+    Signal { pid: u32, signal: Signal },
+    // The pidfd travels as SCM_RIGHTS on the same message
+    SignalPidfd { signal: Signal },
+    CgroupCreate { path: String },
+    CgroupRemove { path: String },
+    CgroupWrite { path: String, value: String },
+    Nft { script: String },
+    DestroySockets { pid: u32 },
+    ReadExe { pid: u32 },
+    ReadStat { pid: u32 },
+    OpenProc { pid: u32, file: ProcFile },
+    ApplyMoves { pid: u32, moves: Vec<RegionMove>, patches: Vec<PointerPatch> },
+    Protect { pid: u32, changes: Vec<ProtectionChange> },
+    Relocate { pid: u32, moves: Vec<RegionMove>, patches: Vec<PointerPatch> },
+    Unit { unit: String, action: UnitAction },
+}
+
+impl HelperRequest {
+    pub fn op(&self) -> &'static str {
+        match self {
+            HelperRequest::Signal { .. } => "signal",
+            HelperRequest::SignalPidfd { .. } => "signal-pidfd",
+            HelperRequest::CgroupCreate { .. } => "cgroup-create",
+            HelperRequest::CgroupRemove { .. } => "cgroup-remove",
+            HelperRequest::CgroupWrite { .. } => "cgroup-write",
+            HelperRequest::Nft { .. } => "nft",
+            HelperRequest::DestroySockets { .. } => "destroy-sockets",
+            HelperRequest::ReadExe { .. } => "read-exe",
+            HelperRequest::ReadStat { .. } => "read-stat",
+            HelperRequest::OpenProc { .. } => "open-proc",
+            HelperRequest::ApplyMoves { .. } => "apply-moves",
+            HelperRequest::Protect { .. } => "protect",
+            HelperRequest::Relocate { .. } => "relocate",
+            HelperRequest::Unit { .. } => "unit",
+        }
+    }
+}
+
+// An open-proc reply carries its descriptor as SCM_RIGHTS on the same
+// message
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", content = "body", rename_all = "lowercase")]
+enum HelperResponse {
+    Ok(serde_json::Value),
+    // The errno travels so callers can still tell NotFound from the rest
+    Error { errno: Option<i32>, message: String },
+}
+
+impl From<io::Error> for HelperResponse {
+    fn from(e: io::Error) -> Self {
+        HelperResponse::Error { errno: e.raw_os_error(), message: e.to_string() }
+    }
+}
+
+pub struct HelperClient {
+    path: PathBuf,
+    // One request at a time, each waiting for its reply
+    stream: Mutex<Option<UnixStream>>,
+}
+
+static HELPER: OnceLock<HelperClient> = OnceLock::new();
+
+// Set once the daemon has connected; None in the helper itself and when
+// privsep is off
+pub fn helper() -> Option<&'static HelperClient> {
+    HELPER.get()
+}
+
+// From here on privileged operations go to the helper
+pub fn connect(path: &Path) -> Result<&'static HelperClient, PrivsepError> {
+    let stream = HelperClient::open(path).map_err(|source| PrivsepError::Io { path: path.to_path_buf(), source })?;
+    Ok(HELPER.get_or_init(|| HelperClient { path: path.to_path_buf(), stream: Mutex::new(Some(stream)) }))
+}
+
+impl HelperClient {
+    fn open(path: &Path) -> io::Result<UnixStream> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    // Blocking. The helper's refusals and failures come back as io::Error
+    // with the errno it saw.
+    pub fn call(&self, request: &HelperRequest) -> io::Result<(serde_json::Value, Option<OwnedFd>)> {
+        self.call_with_fd(request, None)
+    }
+
+    // `fd` goes to the helper as SCM_RIGHTS alongside the request
+    pub fn call_with_fd(&self, request: &HelperRequest, fd: Option<&OwnedFd>) -> io::Result<(serde_json::Value, Option<OwnedFd>)> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        let mut guard = self.stream.lock().unwrap();
+        let mut stream = match guard.take() {
+            Some(stream) => stream,
+            None => Self::open(&self.path)?,
+        };
+        // A restarted helper closed the old connection; the peer being gone
+        // fails the write before anything is sent, so retrying can't run a
+        // request twice
+        if let Err(e) = send_with_fd(&stream, &line, fd) {
+            tracing::debug!("Helper connection lost ({}); reconnecting", e);
+            stream = Self::open(&self.path)?;
+            send_with_fd(&stream, &line, fd)?;
+        }
+        let (response, fd) = receive(&stream)?;
+        *guard = Some(stream);
+        match response {
+            HelperResponse::Ok(value) => Ok((value, fd)),
+            HelperResponse::Error { errno, message } => {
+                let kind = errno.map_or(io::ErrorKind::Other, |errno| io::Error::from_raw_os_error(errno).kind());
+                Err(io::Error::new(kind, format!("qks-helper: {}", message)))
+            }
+        }
+    }
+}
+
+// Needs no privilege: opening a pidfd only pins the process, signalling
+// through it is what gets checked
+pub fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+    // SAFETY: no pointers
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0 as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: a fresh descriptor nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+// Reaches the process the pidfd was opened on, or fails with ESRCH once it
+// has exited; never whoever holds its PID now
+pub fn signal_pidfd(pidfd: &OwnedFd, signal: Signal) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call_with_fd(&HelperRequest::SignalPidfd { signal }, Some(pidfd)).map(drop);
+    }
+    // SAFETY: a null siginfo is allowed and means kill()-style delivery
+    let ret = unsafe {
+        libc::syscall(libc::SYS_pidfd_send_signal, pidfd.as_raw_fd(), signal.number(), std::ptr::null::<libc::siginfo_t>(), 0 as libc::c_uint)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// The PID a pidfd refers to, from its fdinfo; None once the process is gone
+fn pidfd_pid(pidfd: &OwnedFd) -> Option<u32> {
+    let info = fs::read_to_string(format!("/proc/self/fdinfo/{}", pidfd.as_raw_fd())).ok()?;
+    info.lines().find_map(|line| line.strip_prefix("Pid:")).and_then(|pid| pid.trim().parse().ok())
+}
+
+pub fn signal(pid: u32, signal: Signal) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call(&HelperRequest::Signal { pid, signal }).map(drop);
+    }
+    // SAFETY: no pointers
+    if unsafe { libc::kill(pid as libc::pid_t, signal.number()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub fn cgroup_create(path: &Path) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call(&HelperRequest::CgroupCreate { path: relative(path)? }).map(drop);
+    }
+    fs::create_dir(path)
+}
+
+pub fn cgroup_remove(path: &Path) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call(&HelperRequest::CgroupRemove { path: relative(path)? }).map(drop);
+    }
+    fs::remove_dir(path)
+}
+
+pub fn cgroup_write(path: &Path, value: &str) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call(&HelperRequest::CgroupWrite { path: relative(path)?, value: value.to_string() }).map(drop);
+    }
+    fs::write(path, value)
+}
+
+pub fn read_exe(pid: u32) -> io::Result<PathBuf> {
+    if let Some(helper) = helper() {
+        let (exe, _) = helper.call(&HelperRequest::ReadExe { pid })?;
+        return serde_json::from_value(exe).map_err(io::Error::from);
+    }
+    fs::read_link(format!("/proc/{}/exe", pid))
+}
+
+pub fn read_stat(pid: u32) -> io::Result<String> {
+    if let Some(helper) = helper() {
+        let (stat, _) = helper.call(&HelperRequest::ReadStat { pid })?;
+        return serde_json::from_value(stat).map_err(io::Error::from);
+    }
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+}
+
+pub fn open_proc(pid: u32, file: ProcFile) -> io::Result<File> {
+    if let Some(helper) = helper() {
+        return match helper.call(&HelperRequest::OpenProc { pid, file })? {
+            (_, Some(fd)) => Ok(File::from(fd)),
+            (_, None) => Err(io::Error::new(io::ErrorKind::InvalidData, "qks-helper: open-proc reply without a descriptor")),
+        };
+    }
+    File::open(format!("/proc/{}/{}", pid, file.name()))
+}
+
+pub fn unit(unit: &str, action: UnitAction) -> io::Result<()> {
+    if let Some(helper) = helper() {
+        return helper.call(&HelperRequest::Unit { unit: unit.to_string(), action }).map(drop);
+    }
+    crash_watchdog::systemctl(&[action.verb(), "--", unit]).map(drop)
+}
+
+fn relative(path: &Path) -> io::Result<String> {
+    path.strip_prefix(CGROUP_ROOT)
+        .ok()
+        .and_then(Path::to_str)
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is outside the cgroup2 mount", path.display())))
+}
+
+// (uid, gid) of a user; getpwnam's buffer is static, called at startup
+pub fn user_ids(name: &str) -> Option<(u32, u32)> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: name is NUL-terminated and outlives the call
+    let user = unsafe { libc::getpwnam(name.as_ptr()) };
+    // SAFETY: non-null entries point at getpwnam's static buffer
+    (!user.is_null()).then(|| unsafe { ((*user).pw_uid, (*user).pw_gid) })
+}
+
+// The last step of startup, once everything that needs root is open.
+// `owned` are the files and directories the core goes on writing; they are
+// handed to the user first. glibc applies setresuid and friends to every
+// thread of the process, runtime workers included.
+pub fn drop_privileges(user: &str, owned: &[PathBuf]) -> Result<(), PrivsepError> {
+    let (uid, gid) = user_ids(user).ok_or_else(|| PrivsepError::UnknownUser(user.to_string()))?;
+    for path in owned {
+        chown_tree(path, uid, gid).map_err(|source| PrivsepError::Io { path: path.clone(), source })?;
+    }
+    // SAFETY: a zero-length group list is never read
+    if unsafe { libc::setgroups(0, std::ptr::null()) } < 0 {
+        return Err(syscall_error("setgroups"));
+    }
+    // SAFETY: no pointers
+    if unsafe { libc::setresgid(gid, gid, gid) } < 0 {
+        return Err(syscall_error("setresgid"));
+    }
+    // SAFETY: no pointers; clears every capability along with uid 0
+    if unsafe { libc::setresuid(uid, uid, uid) } < 0 {
+        return Err(syscall_error("setresuid"));
+    }
+    // SAFETY: no pointers; must fail now that no uid is 0
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(PrivsepError::Syscall { call: "setuid", source: io::Error::new(io::ErrorKind::Other, "root could be regained") });
+    }
+    tracing::info!("Dropped privileges to {} (uid {}, gid {})", user, uid, gid);
+    Ok(())
+}
+
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+// One newline-terminated reply and the descriptor that came with it
+fn receive(stream: &UnixStream) -> io::Result<(HelperResponse, Option<OwnedFd>)> {
+    match read_message(stream)? {
+        Some((message, fd)) => Ok((serde_json::from_slice(&message)?, fd)),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "qks-helper closed the connection")),
+    }
+}
+
+// One newline-terminated message, either direction. Each side waits for
+// the other's reply before sending again, so nothing past the newline is
+// ever read. None when the peer closed before sending anything.
+fn read_message(stream: &UnixStream) -> io::Result<Option<(Vec<u8>, Option<OwnedFd>)>> {
+    let mut message = Vec::new();
+    let mut fd = None;
+    let mut buf = [0u8; 4096];
+    while message.last() != Some(&b'\n') {
+        let (len, received) = match recv_with_fd(stream.as_raw_fd(), &mut buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if received.is_some() {
+            fd = received;
+        }
+        if len == 0 {
+            if message.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-message"));
+        }
+        message.extend_from_slice(&buf[..len]);
+        if message.len() > MAX_MESSAGE_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+        }
+    }
+    Ok(Some((message, fd)))
+}
+
+fn recv_with_fd(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)> {
+    // Room for one descriptor, aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    // SAFETY: all-zero is a valid msghdr
+    let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: iov and control outlive the call and the lengths are theirs
+    let len = unsafe { libc::recvmsg(socket, &mut header, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fd = None;
+    // SAFETY: the kernel filled control up to msg_controllen; SCM_RIGHTS
+    // data is a descriptor now owned by this process
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let received = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+                fd = Some(OwnedFd::from_raw_fd(received));
+            }
+            cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+        }
+    }
+    Ok((len as usize, fd))
+}
+
+fn send_with_fd(mut stream: &UnixStream, data: &[u8], fd: Option<&OwnedFd>) -> io::Result<()> {
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    // SAFETY: all-zero is a valid msghdr
+    let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        header.msg_control = control.as_mut_ptr().cast();
+        // SAFETY: pure size arithmetic
+        header.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as _;
+        // SAFETY: control is large enough for one cmsghdr and a descriptor
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd.as_raw_fd());
+        }
+    }
+    // SAFETY: iov and control outlive the call
+    let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &header, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The descriptor went with the first part; the rest of a long line
+    // follows without it
+    stream.write_all(&data[sent as usize..])
+}
+
+// The root side
+pub struct Helper {
+    // The user the core runs as; root may connect too
+    core_uid: u32,
+    // Cgroups qksd manages, relative to the cgroup2 mount
+    cgroups: Vec<String>,
+    // Services the watchdog may stop and start; none unless it may roll back
+    units: Vec<String>,
+    // Executables whose processes may be remapped and have their memory
+    // opened
+    remap_targets: Vec<PathBuf>,
+}
+
+impl Helper {
+    pub fn new(config: &QksConfig) -> Result<Self, PrivsepError> {
+        let (core_uid, _) = user_ids(&config.privsep.user).ok_or_else(|| PrivsepError::UnknownUser(config.privsep.user.clone()))?;
+        let mut cgroups = vec![config.quarantine.cgroup.clone()];
+        if config.tokens.enforce_memory {
+            cgroups.push(config.tokens.memory_cgroup.clone());
+        }
+        let watchdog = &config.watchdog;
+        let units = if watchdog.enabled && watchdog.rollback { watchdog.services.clone() } else { Vec::new() };
+        Ok(Self { core_uid, cgroups, units, remap_targets: config.privsep.remap_targets.clone() })
+    }
+
+    // Blocking: serves until the process is killed, a thread per connection
+    pub fn serve(self, path: &Path) -> Result<(), PrivsepError> {
+        let listener = self.bind(path).map_err(|source| PrivsepError::Io { path: path.to_path_buf(), source })?;
+        tracing::info!("qks-helper listening on {} for uid {}", path.display(), self.core_uid);
+        let helper = Arc::new(self);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let helper = helper.clone();
+                    std::thread::spawn(move || helper.serve_connection(stream));
+                }
+                Err(e) => tracing::warn!("Helper socket accept failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn bind(&self, path: &Path) -> io::Result<UnixListener> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::os::unix::fs::chown(path, Some(self.core_uid), None)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    fn serve_connection(&self, stream: UnixStream) {
+        let peer = match peer_credentials(&stream) {
+            Ok(peer) => peer,
+            Err(e) => {
+                tracing::warn!("Helper connection without credentials: {}", e);
+                return;
+            }
+        };
+        // The socket's mode already keeps others out; this holds even if
+        // someone loosens it
+        if peer.uid != 0 && peer.uid != self.core_uid {
+            tracing::warn!(uid = peer.uid, pid = peer.pid, "Helper connection from uid {} (pid {}) refused", peer.uid, peer.pid);
+            return;
+        }
+
+        loop {
+            // Requests may carry a descriptor (signal-pidfd), so they are
+            // read with recvmsg rather than through a BufReader
+            let (response, fd, oversized) = match read_message(&stream) {
+                Ok(None) => return,
+                Ok(Some((line, received))) => match serde_json::from_slice::<HelperRequest>(&line) {
+                    Ok(request) => {
+                        let (response, fd) = self.handle(request, received);
+                        (response, fd, false)
+                    }
+                    Err(e) => (HelperResponse::Error { errno: Some(libc::EINVAL), message: format!("malformed request: {}", e) }, None, false),
+                },
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    (HelperResponse::Error { errno: Some(libc::E2BIG), message: "request too large".to_string() }, None, true)
+                }
+                Err(e) => {
+                    tracing::debug!("Helper connection ended: {}", e);
+                    return;
+                }
+            };
+            let sent = serde_json::to_vec(&response).map_err(io::Error::from).and_then(|mut out| {
+                out.push(b'\n');
+                send_with_fd(&stream, &out, fd.as_ref())
+            });
+            // The rest of an oversized line is still unread
+            if let Err(e) = sent {
+                tracing::debug!("Helper connection ended: {}", e);
+                return;
+            }
+            if oversized {
+                return;
+            }
+        }
+    }
+
+    // `received` is the descriptor that came with the request, if any
+    fn handle(&self, request: HelperRequest, received: Option<OwnedFd>) -> (HelperResponse, Option<OwnedFd>) {
+        let op = request.op();
+        let request = match self.check(request, received.as_ref()) {
+            Ok(request) => request,
+            Err(refusal) => {
+                tracing::warn!("Helper refused {}: {}", op, refusal);
+                return (HelperResponse::Error { errno: Some(libc::EPERM), message: format!("{} refused: {}", op, refusal) }, None);
+            }
+        };
+        tracing::debug!("Helper {}", op);
+        match execute(request, received) {
+            Ok((value, fd)) => (HelperResponse::Ok(value), fd),
+            Err(e) => (e.into(), None),
+        }
+    }
+
+    // What passes is what gets executed: remaps come back with their moves
+    // rebuilt from the target's maps, not as the core sent them
+    fn check(&self, request: HelperRequest, received: Option<&OwnedFd>) -> Result<HelperRequest, String> {
+        match request {
+            HelperRequest::ApplyMoves { pid, moves, patches } => {
+                self.remap_target(pid)?;
+                let maps = target_maps(pid)?;
+                let moves = real_moves(pid, &maps, &moves)?;
+                check_patches(&maps, &moves, &patches)?;
+                Ok(HelperRequest::ApplyMoves { pid, moves, patches })
+            }
+            HelperRequest::Relocate { pid, moves, patches } => {
+                self.remap_target(pid)?;
+                let maps = target_maps(pid)?;
+                let moves = real_moves(pid, &maps, &moves)?;
+                check_patches(&maps, &moves, &patches)?;
+                Ok(HelperRequest::Relocate { pid, moves, patches })
+            }
+            HelperRequest::Protect { pid, changes } => {
+                self.remap_target(pid)?;
+                check_protect(&target_maps(pid)?, &changes)?;
+                Ok(HelperRequest::Protect { pid, changes })
+            }
+            request => self.check_other(&request, received).map(|()| request),
+        }
+    }
+
+    fn check_other(&self, request: &HelperRequest, received: Option<&OwnedFd>) -> Result<(), String> {
+        match request {
+            HelperRequest::Signal { pid, .. } => target(*pid),
+            HelperRequest::SignalPidfd { .. } => {
+                let pidfd = received.ok_or("no pidfd came with the request")?;
+                let pid = pidfd_pid(pidfd).ok_or("the pidfd's process has exited")?;
+                target(pid)
+            }
+            HelperRequest::CgroupCreate { path } => {
+                valid_cgroup(path)?;
+                self.within_reach(path).then_some(()).ok_or_else(|| format!("{} is not a cgroup qksd manages", path))
+            }
+            HelperRequest::CgroupRemove { path } => {
+                valid_cgroup(path)?;
+                self.managed(path).then_some(()).ok_or_else(|| format!("{} is not a cgroup qksd manages", path))
+            }
+            HelperRequest::CgroupWrite { path, value } => self.check_write(path, value),
+            HelperRequest::Nft { script } => check_nft(script),
+            HelperRequest::DestroySockets { pid } => {
+                let cgroup = cgroup_of(*pid)?;
+                self.managed(&cgroup).then_some(()).ok_or_else(|| format!("PID {} is not quarantined", pid))
+            }
+            HelperRequest::ReadExe { .. } | HelperRequest::ReadStat { .. } => Ok(()),
+            // Memory contents only of processes qksd may remap anyway
+            HelperRequest::OpenProc { pid, file: ProcFile::Mem } => self.remap_target(*pid),
+            HelperRequest::OpenProc { pid, .. } => target(*pid),
+            HelperRequest::ApplyMoves { .. } | HelperRequest::Relocate { .. } | HelperRequest::Protect { .. } => Ok(()),
+            HelperRequest::Unit { unit, .. } => {
+                self.units.contains(unit).then_some(()).ok_or_else(|| format!("{} is not a service the watchdog may roll back", unit))
+            }
+        }
+    }
+
+    // Running one of the configured executables; the core's own list of
+    // enrolled PIDs is not taken on trust
+    fn remap_target(&self, pid: u32) -> Result<(), String> {
+        target(pid)?;
+        let exe = fs::read_link(format!("/proc/{}/exe", pid)).map_err(|e| format!("executable of PID {}: {}", pid, e))?;
+        if self.remap_targets.iter().any(|allowed| *allowed == exe) {
+            Ok(())
+        } else {
+            Err(format!("PID {} ({}) is not a remap target", pid, exe.display()))
+        }
+    }
+
+    // Inside one of the managed cgroups
+    fn managed(&self, path: &str) -> bool {
+        self.cgroups.iter().any(|root| path == root || path.strip_prefix(root.as_str()).is_some_and(|rest| rest.starts_with('/')))
+    }
+
+    // Managed, or on the way down to one (the root included)
+    fn within_reach(&self, path: &str) -> bool {
+        path.is_empty() || self.managed(path) || self.cgroups.iter().any(|root| root.strip_prefix(path).is_some_and(|rest| rest.starts_with('/')))
+    }
+
+    fn check_write(&self, path: &str, value: &str) -> Result<(), String> {
+        let (dir, file) = path.rsplit_once('/').unwrap_or(("", path));
+        if !dir.is_empty() {
+            valid_cgroup(dir)?;
+        }
+        let managed = || self.managed(dir).then_some(()).ok_or_else(|| format!("{} is not a cgroup qksd manages", dir));
+        match file {
+            "cgroup.subtree_control" => {
+                if !self.within_reach(dir) {
+                    return Err(format!("{} is not a cgroup qksd manages", dir));
+                }
+                match value.split_whitespace().find(|controller| !CONTROLLERS.contains(controller)) {
+                    Some(controller) => Err(format!("controller {} is not one qksd enables", controller)),
+                    None => Ok(()),
+                }
+            }
+            // Into a managed cgroup, or back out of one
+            "cgroup.procs" => {
+                let pid = value.trim().parse().map_err(|_| format!("{:?} is not a PID", value))?;
+                target(pid)?;
+                if self.managed(dir) || self.managed(&cgroup_of(pid)?) {
+                    Ok(())
+                } else {
+                    Err(format!("PID {} is neither moving into nor out of a cgroup qksd manages", pid))
+                }
+            }
+            // Thawing is always allowed; freezing a cgroup only if every
+            // process in it is one qksd could stop anyway
+            "cgroup.freeze" => match value.trim() {
+                "0" => Ok(()),
+                "1" if self.managed(dir) => Ok(()),
+                "1" => {
+                    let procs = fs::read_to_string(Path::new(CGROUP_ROOT).join(dir).join("cgroup.procs")).map_err(|e| e.to_string())?;
+                    let pids: Vec<u32> = procs.split_whitespace().filter_map(|pid| pid.parse().ok()).collect();
+                    if pids.is_empty() {
+                        return Err(format!("{} holds no process", dir));
+                    }
+                    pids.into_iter().try_for_each(target)
+                }
+                _ => Err(format!("{:?} is not a freezer state", value)),
+            },
+            file if LIMIT_FILES.contains(&file) => managed(),
+            _ => Err(format!("{} is not a file qksd writes", file)),
+        }
+    }
+}
+
+fn execute(request: HelperRequest, received: Option<OwnedFd>) -> io::Result<(serde_json::Value, Option<OwnedFd>)> {
+    let done = |result: io::Result<()>| result.map(|()| (serde_json::Value::Null, None));
+    let remapped = |result: Result<(), crate::remap_engine::RemapError>| done(result.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())));
+    let cgroup = |path: &str| Path::new(CGROUP_ROOT).join(path);
+    match request {
+        HelperRequest::Signal { pid, signal: sent } => done(signal(pid, sent)),
+        HelperRequest::SignalPidfd { signal: sent } => match received {
+            Some(pidfd) => done(signal_pidfd(&pidfd, sent)),
+            None => Err(io::Error::from_raw_os_error(libc::EBADF)),
+        },
+        HelperRequest::CgroupCreate { path } => done(cgroup_create(&cgroup(&path))),
+        HelperRequest::CgroupRemove { path } => done(cgroup_remove(&cgroup(&path))),
+        HelperRequest::CgroupWrite { path, value } => done(cgroup_write(&cgroup(&path), &value)),
+        HelperRequest::Nft { script } => done(response::run_nft(&script).map_err(|e| io::Error::new(io::ErrorKind::Other, e))),
+        HelperRequest::DestroySockets { pid } => Ok((serde_json::json!(quarantine::destroy_sockets(pid)), None)),
+        HelperRequest::ReadExe { pid } => Ok((serde_json::Value::String(read_exe(pid)?.to_string_lossy().into_owned()), None)),
+        HelperRequest::ReadStat { pid } => Ok((serde_json::Value::String(read_stat(pid)?), None)),
+        HelperRequest::OpenProc { pid, file } => Ok((serde_json::Value::Null, Some(open_proc(pid, file)?.into()))),
+        HelperRequest::ApplyMoves { pid, moves, patches } => remapped(RemapEngine::apply_moves(pid, &moves, &patches)),
+        HelperRequest::Protect { pid, changes } => remapped(RemapEngine::protect(pid, &changes)),
+        HelperRequest::Relocate { pid, moves, patches } => remapped(RemapEngine::relocate(pid, &moves, &patches)),
+        HelperRequest::Unit { unit: name, action } => done(unit(&name, action)),
+    }
+}
+
+// init, kthreadd and the helper itself are never acted on
+fn target(pid: u32) -> Result<(), String> {
+    if pid <= 2 || pid == std::process::id() {
+        Err(format!("PID {} may not be targeted", pid))
+    } else {
+        Ok(())
+    }
+}
+
+fn valid_cgroup(path: &str) -> Result<(), String> {
+    let valid = path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+        && path.chars().all(|c| c.is_ascii_alphanumeric() || "._-/:@".contains(c));
+    valid.then_some(()).ok_or_else(|| format!("invalid cgroup path {:?}", path))
+}
+
+// Relative to the cgroup2 mount, without the leading slash
+fn cgroup_of(pid: u32) -> Result<String, String> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).map_err(|e| format!("cgroup of PID {}: {}", pid, e))?;
+    cgroups
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|path| path.trim_start_matches('/').to_string())
+        .ok_or_else(|| format!("PID {} has no cgroup v2 membership", pid))
+}
+
+// Every command has to name qksd's table, and none may hide a second one
+// behind a `;`
+fn check_nft(script: &str) -> Result<(), String> {
+    for line in script.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let words: Vec<&str> = line.split_whitespace().take(4).collect();
+        let ours = matches!(words.first(), Some(&("add" | "flush" | "delete" | "insert")))
+            && matches!(words.get(1), Some(&("table" | "chain" | "rule")))
+            && words.get(2) == Some(&"inet")
+            && words.get(3) == Some(&NFT_TABLE);
+        if !ours {
+            return Err(format!("{:?} is outside table inet {}", line, NFT_TABLE));
+        }
+        // Chain definitions separate their clauses with `;` inside braces
+        let (mut depth, mut quoted) = (0i32, false);
+        for c in line.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                '{' if !quoted => depth += 1,
+                '}' if !quoted => depth -= 1,
+                ';' if !quoted && depth <= 0 => return Err(format!("{:?} holds more than one command", line)),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// Each move has to cover whole mappings the target really has, back to
+// back from its start to its end; the moves returned are built from those
+// mappings, keeping only the destination (and kind) from the request
+fn real_moves(pid: u32, maps: &ProcessMaps, requested: &[RegionMove]) -> Result<Vec<RegionMove>, String> {
+    requested
+        .iter()
+        .map(|mv| {
+            let end = mv.old_start.checked_add(mv.len).ok_or_else(|| format!("move at {:#x} wraps", mv.old_start))?;
+            let mut covered = mv.old_start;
+            for entry in maps.entries.iter().filter(|entry| entry.overlaps(mv.old_start, end)) {
+                if entry.start != covered {
+                    return Err(format!("move {:#x}-{:#x} does not match PID {}'s mappings", mv.old_start, end, pid));
+                }
+                covered = entry.end;
+            }
+            if mv.len == 0 || covered != end {
+                return Err(format!("move {:#x}-{:#x} does not match PID {}'s mappings", mv.old_start, end, pid));
+            }
+            Ok(RegionMove { kind: mv.kind, old_start: mv.old_start, len: covered - mv.old_start, new_start: mv.new_start })
+        })
+        .collect()
+}
+
+fn target_maps(pid: u32) -> Result<ProcessMaps, String> {
+    ProcessMaps::read(pid).map_err(|e| format!("maps of PID {}: {}", pid, e))
+}
+
+// A patch moves a pointer along with what it points at: the old value has
+// to lie in one of the moved regions (or just past its end) and the new one
+// shift by that region's distance. The word patched has to sit in writable
+// memory (its address is after the moves, the maps from before them); the
+// engine reads it under ptrace and refuses a patch whose old value isn't
+// what is there.
+fn check_patches(maps: &ProcessMaps, moves: &[RegionMove], patches: &[PointerPatch]) -> Result<(), String> {
+    let follows = |patch: &PointerPatch| {
+        moves.iter().any(|mv| {
+            (mv.old_start..=mv.old_start + mv.len).contains(&patch.old_value)
+                && patch.new_value.wrapping_sub(patch.old_value) == mv.new_start.wrapping_sub(mv.old_start)
+        })
+    };
+    let writable = |patch: &PointerPatch| {
+        let before = moves.iter()
+            .find(|mv| (mv.new_start..mv.new_start + mv.len).contains(&patch.addr))
+            .map_or(patch.addr, |mv| patch.addr - mv.new_start + mv.old_start);
+        maps.containing(before)
+            .is_some_and(|entry| entry.writable() && before.checked_add(8).is_some_and(|end| end <= entry.end))
+    };
+    if let Some(patch) = patches.iter().find(|patch| !follows(patch)) {
+        return Err(format!("patch at {:#x} does not follow any of the moves", patch.addr));
+    }
+    match patches.iter().find(|patch| !writable(patch)) {
+        Some(patch) => Err(format!("patch at {:#x} is not in writable memory", patch.addr)),
+        None => Ok(()),
+    }
+}
+
+// mprotect may only take permissions away, from mappings that have
+// exactly the permissions the change says they have
+fn check_protect(maps: &ProcessMaps, changes: &[ProtectionChange]) -> Result<(), String> {
+    let known = (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64;
+    for change in changes {
+        let range = format!("{:#x}+{:#x}", change.start, change.len);
+        if change.new_prot & !known != 0 || change.new_prot & !change.old_prot != 0 {
+            return Err(format!("protection change at {} adds permissions", range));
+        }
+        let end = change.start.checked_add(change.len).filter(|_| change.len > 0).ok_or_else(|| format!("protection change at {} is empty or wraps", range))?;
+        let mut covered = change.start;
+        for entry in maps.entries.iter().filter(|entry| entry.overlaps(change.start, end)) {
+            let prot = [(entry.readable(), libc::PROT_READ), (entry.writable(), libc::PROT_WRITE), (entry.executable(), libc::PROT_EXEC)]
+                .iter()
+                .filter(|(set, _)| *set)
+                .fold(0u64, |prot, (_, bit)| prot | *bit as u64);
+            if entry.start > covered || prot != change.old_prot {
+                return Err(format!("protection change at {} does not match the target's mappings", range));
+            }
+            covered = entry.end;
+        }
+        if covered < end {
+            return Err(format!("protection change at {} does not match the target's mappings", range));
+        }
+    }
+    Ok(())
+}
+
+struct Peer {
+    uid: u32,
+    pid: i32,
+}
+
+fn peer_credentials(stream: &UnixStream) -> io::Result<Peer> {
+    // SAFETY: all-zero is a valid ucred
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes of their sizes
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Peer { uid: cred.uid, pid: cred.pid })
+}
@@ -0,0 +1,214 @@
+// src/proc_connector.rs
+// Process events without eBPF. Where the BPF programs can't be loaded
+// (lockdown, no kprobes, a locked-down container host) the kernel's proc
+// connector still multicasts fork, exec and exit over netlink. Each one
+// becomes the SyscallEvent the eBPF stream would have carried for it
+// (clone in the parent returning the child, execve, exit_group with the
+// wait status) and goes down the same path: probe gate, event bus, feature
+// windows, anomaly enrichment. Fidelity is much lower, since nothing in
+// between is seen and there are no durations. Threads are skipped, as
+// their clones and exits add nothing to the process's own. Subscribing
+// needs CAP_NET_ADMIN.
+use crate::ebpf_monitor::{SyscallEvent, SYSCALL_QUEUE};
+use crate::metrics;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_CN_MCAST_IGNORE: u32 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+// nlmsghdr, then cn_msg: id (idx, val), seq, ack, len, flags
+const NLMSG_HDRLEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+// proc_event: what, cpu, timestamp_ns, then the event's own fields
+const EVENT_DATA: usize = 16;
+// A burst of forks arrives as many datagrams, each one message
+const RECV_BUFFER: usize = 8192;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcConnectorError {
+    #[error("proc connector {call}: {source}")]
+    Syscall { call: &'static str, source: io::Error },
+}
+
+fn syscall_error(call: &'static str) -> ProcConnectorError {
+    ProcConnectorError::Syscall { call, source: io::Error::last_os_error() }
+}
+
+pub struct ProcConnector {
+    fd: OwnedFd,
+}
+
+impl AsRawFd for ProcConnector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl ProcConnector {
+    pub fn new() -> Result<Self, ProcConnectorError> {
+        // SAFETY: no pointers; the descriptor is owned right below
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, NETLINK_CONNECTOR) };
+        if fd < 0 {
+            return Err(syscall_error("socket"));
+        }
+        // SAFETY: just created and not shared
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: all-zero is a valid sockaddr_nl
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = CN_IDX_PROC;
+        // SAFETY: address outlives the call and the size is its own
+        let ret = unsafe {
+            libc::bind(fd.as_raw_fd(), &address as *const libc::sockaddr_nl as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(syscall_error("bind"));
+        }
+
+        let connector = Self { fd };
+        connector.send_op(PROC_CN_MCAST_LISTEN).map_err(|source| ProcConnectorError::Syscall { call: "listen", source })?;
+        Ok(connector)
+    }
+
+    fn send_op(&self, op: u32) -> io::Result<()> {
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + 4;
+        let mut message = Vec::with_capacity(len);
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        // flags, seq, port: the kernel needs none of them
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        message.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        // seq, ack
+        message.extend_from_slice(&[0; 8]);
+        message.extend_from_slice(&4u16.to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&op.to_ne_bytes());
+        // SAFETY: message is valid for its length
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), message.as_ptr().cast(), message.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for writes of its length
+        let len = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    pub fn start(self) -> (tokio::task::JoinHandle<()>, mpsc::Receiver<SyscallEvent>) {
+        let (tx, rx) = mpsc::channel(SYSCALL_QUEUE);
+
+        let handle = tokio::spawn(async move {
+            let socket = match AsyncFd::new(self) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    tracing::error!("Proc connector: {}", e);
+                    return;
+                }
+            };
+            let mut buf = vec![0u8; RECV_BUFFER];
+            loop {
+                let mut ready = match socket.readable().await {
+                    Ok(ready) => ready,
+                    Err(e) => {
+                        tracing::error!("Proc connector: {}", e);
+                        return;
+                    }
+                };
+                let len = match ready.try_io(|socket| socket.get_ref().recv(&mut buf)) {
+                    Ok(Ok(len)) => len,
+                    // The socket buffer overflowed in a fork storm; what was
+                    // dropped stays lost, the stream itself carries on
+                    Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                        metrics::global().incr("qks_proc_connector_overruns_total", &[]);
+                        continue;
+                    }
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(Err(e)) => {
+                        tracing::error!("Proc connector: {}", e);
+                        return;
+                    }
+                    Err(_would_block) => continue,
+                };
+                for event in parse(&buf[..len]) {
+                    // Receiver gone: the pipeline stopped
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        (handle, rx)
+    }
+}
+
+impl Drop for ProcConnector {
+    // The kernel counts listeners and only builds events while there are any
+    fn drop(&mut self) {
+        let _ = self.send_op(PROC_CN_MCAST_IGNORE);
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// Every complete netlink message in the datagram
+fn parse(datagram: &[u8]) -> Vec<SyscallEvent> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while let Some(len) = u32_at(datagram, offset).map(|len| len as usize) {
+        if len < NLMSG_HDRLEN || offset + len > datagram.len() {
+            break;
+        }
+        if let Some(event) = event(&datagram[offset + NLMSG_HDRLEN..offset + len]) {
+            events.push(event);
+        }
+        // Messages are 4-byte aligned
+        offset += (len + 3) & !3;
+    }
+    events
+}
+
+fn event(message: &[u8]) -> Option<SyscallEvent> {
+    if u32_at(message, 0)? != CN_IDX_PROC || u32_at(message, 4)? != CN_VAL_PROC {
+        return None;
+    }
+    let data = message.get(CN_MSG_LEN..)?;
+    let field = |index: usize| u32_at(data, EVENT_DATA + index * 4);
+    let call = |pid: u32, syscall: libc::c_long, retval: i32| SyscallEvent { pid, syscall: syscall as u32, duration_ns: 0, retval };
+    match u32_at(data, 0)? {
+        // parent pid, parent tgid, child pid, child tgid
+        PROC_EVENT_FORK => {
+            let (parent, child_pid, child) = (field(1)?, field(2)?, field(3)?);
+            (child_pid == child).then(|| call(parent, libc::SYS_clone, child as i32))
+        }
+        // pid, tgid
+        PROC_EVENT_EXEC => Some(call(field(1)?, libc::SYS_execve, 0)),
+        // pid, tgid, exit code (a wait status), exit signal
+        PROC_EVENT_EXIT => {
+            let (pid, tgid, status) = (field(0)?, field(1)?, field(2)?);
+            (pid == tgid).then(|| call(tgid, libc::SYS_exit_group, status as i32))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,225 @@
+// src/proc_poll.rs
+// The last resort, for when neither eBPF nor the proc connector can be used
+// (a container without CAP_BPF or CAP_NET_ADMIN): /proc is scanned every
+// [monitor] poll_interval_ms and each difference from the previous scan is
+// reported as the syscall that must have caused it. A new process is a
+// clone in its parent, a changed /proc/PID/exe an execve, a vanished
+// process an exit_group; descriptors that appeared or went are socket,
+// pipe2 or openat and close; mappings that appeared, went, moved their end
+// or changed permissions are mmap, munmap, mremap and mprotect. A mapping
+// newly found writable and executable also goes to W^X enforcement, as the
+// eBPF mprotect probe's events do. Anything done and undone between two
+// scans is never seen, and a process that lives shorter than the interval
+// leaves no trace.
+use crate::ebpf_monitor::{MprotectEvent, SyscallEvent, SYSCALL_QUEUE};
+use crate::privsep;
+use crate::process_maps::ProcessMaps;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+struct Mapping {
+    end: u64,
+    perms: String,
+}
+
+impl Mapping {
+    fn write_exec(&self) -> bool {
+        self.perms.as_bytes().get(1) == Some(&b'w') && self.perms.as_bytes().get(2) == Some(&b'x')
+    }
+}
+
+// One process as of the last scan
+struct Seen {
+    // Clock ticks after boot; tells a reused PID from the same process
+    start: u64,
+    exe: PathBuf,
+    fds: BTreeMap<u32, PathBuf>,
+    maps: BTreeMap<u64, Mapping>,
+}
+
+#[derive(Default)]
+struct Scan {
+    syscalls: Vec<SyscallEvent>,
+    mprotects: Vec<MprotectEvent>,
+}
+
+impl Scan {
+    fn call(&mut self, pid: u32, syscall: libc::c_long, retval: i32) {
+        self.syscalls.push(SyscallEvent { pid, syscall: syscall as u32, duration_ns: 0, retval });
+    }
+}
+
+pub struct ProcPoller {
+    interval: Duration,
+    // None until the first scan, which only records what is there
+    seen: Mutex<Option<HashMap<u32, Seen>>>,
+}
+
+impl ProcPoller {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, seen: Mutex::new(None) }
+    }
+
+    pub fn start(self: Arc<Self>) -> (tokio::task::JoinHandle<()>, mpsc::Receiver<SyscallEvent>, mpsc::UnboundedReceiver<MprotectEvent>) {
+        let (syscalls, syscall_rx) = mpsc::channel(SYSCALL_QUEUE);
+        let (mprotects, mprotect_rx) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let poller = self.clone();
+                let scan = match tokio::task::spawn_blocking(move || poller.scan()).await {
+                    Ok(scan) => scan,
+                    Err(e) => {
+                        tracing::error!("/proc scan panicked: {}", e);
+                        continue;
+                    }
+                };
+                // W^X enforcement may be switched off; the syscall stream may not
+                for event in scan.mprotects {
+                    let _ = mprotects.send(event);
+                }
+                for event in scan.syscalls {
+                    if syscalls.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        (handle, syscall_rx, mprotect_rx)
+    }
+
+    // Blocking: reads stat, exe, fd/ and maps of every process
+    fn scan(&self) -> Scan {
+        let own = std::process::id();
+        let mut current = HashMap::new();
+        let mut parents = HashMap::new();
+        for pid in fs::read_dir("/proc").into_iter().flatten().flatten().filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok()) {
+            if pid == own {
+                continue;
+            }
+            let Some((ppid, start)) = stat(pid) else {
+                continue;
+            };
+            // Kernel threads have no executable and nothing to compare
+            let Ok(exe) = privsep::read_exe(pid) else {
+                continue;
+            };
+            parents.insert(pid, ppid);
+            current.insert(pid, Seen { start, exe, fds: fds(pid), maps: maps(pid) });
+        }
+
+        let mut scan = Scan::default();
+        // Scans never overlap: the next waits for this one
+        let previous = self.seen.lock().unwrap().take();
+        let Some(previous) = previous else {
+            *self.seen.lock().unwrap() = Some(current);
+            return scan;
+        };
+        for (pid, before) in previous.iter() {
+            if current.get(pid).map_or(true, |now| now.start != before.start) {
+                scan.call(*pid, libc::SYS_exit_group, 0);
+            }
+        }
+        for (pid, now) in &current {
+            match previous.get(pid).filter(|before| before.start == now.start) {
+                Some(before) => Self::compare(*pid, before, now, &mut scan),
+                None => {
+                    let ppid = parents.get(pid).copied().unwrap_or(0);
+                    scan.call(ppid, libc::SYS_clone, *pid as i32);
+                    // Running something else than its parent: it has exec'd
+                    if current.get(&ppid).map_or(true, |parent| parent.exe != now.exe) {
+                        scan.call(*pid, libc::SYS_execve, 0);
+                    }
+                    Self::wx(*pid, &BTreeMap::new(), &now.maps, &mut scan);
+                }
+            }
+        }
+        *self.seen.lock().unwrap() = Some(current);
+        scan
+    }
+
+    fn compare(pid: u32, before: &Seen, now: &Seen, scan: &mut Scan) {
+        if before.exe != now.exe {
+            scan.call(pid, libc::SYS_execve, 0);
+        }
+
+        for (fd, target) in &before.fds {
+            if now.fds.get(fd) != Some(target) {
+                scan.call(pid, libc::SYS_close, 0);
+            }
+        }
+        for (fd, target) in &now.fds {
+            if before.fds.get(fd) != Some(target) {
+                let target = target.to_string_lossy();
+                let syscall = if target.starts_with("socket:") {
+                    libc::SYS_socket
+                } else if target.starts_with("pipe:") {
+                    libc::SYS_pipe2
+                } else {
+                    libc::SYS_openat
+                };
+                scan.call(pid, syscall, *fd as i32);
+            }
+        }
+
+        for start in before.maps.keys() {
+            if !now.maps.contains_key(start) {
+                scan.call(pid, libc::SYS_munmap, 0);
+            }
+        }
+        for (start, mapping) in &now.maps {
+            match before.maps.get(start) {
+                None => scan.call(pid, libc::SYS_mmap, 0),
+                Some(old) if old.perms != mapping.perms => scan.call(pid, libc::SYS_mprotect, 0),
+                Some(old) if old.end != mapping.end => scan.call(pid, libc::SYS_mremap, 0),
+                Some(_) => {}
+            }
+        }
+        Self::wx(pid, &before.maps, &now.maps, scan);
+    }
+
+    // Mappings that became writable and executable since the last scan
+    fn wx(pid: u32, before: &BTreeMap<u64, Mapping>, now: &BTreeMap<u64, Mapping>, scan: &mut Scan) {
+        for (start, mapping) in now {
+            if mapping.write_exec() && !before.get(start).is_some_and(Mapping::write_exec) {
+                scan.mprotects.push(MprotectEvent {
+                    pid,
+                    prot: (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u32,
+                    start: *start,
+                    len: mapping.end - start,
+                });
+            }
+        }
+    }
+}
+
+// Parent PID and start time
+fn stat(pid: u32) -> Option<(u32, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm is parenthesised and may itself contain ") "
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    Some((fields.get(1)?.parse().ok()?, fields.get(19)?.parse().ok()?))
+}
+
+fn fds(pid: u32) -> BTreeMap<u32, PathBuf> {
+    fs::read_dir(format!("/proc/{}/fd", pid))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| Some((entry.file_name().to_str()?.parse().ok()?, fs::read_link(entry.path()).ok()?)))
+        .collect()
+}
+
+fn maps(pid: u32) -> BTreeMap<u64, Mapping> {
+    ProcessMaps::read(pid)
+        .map(|maps| maps.entries.into_iter().map(|entry| (entry.start, Mapping { end: entry.end, perms: entry.perms })).collect())
+        .unwrap_or_default()
+}
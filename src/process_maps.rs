@@ -0,0 +1,215 @@
+// src/process_maps.rs
+use crate::privsep::{self, ProcFile};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+// One line of /proc/[pid]/maps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapEntry {
+    pub start: u64,
+    pub end: u64,
+    pub perms: String,
+    pub offset: u64,
+    pub inode: u64,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessMaps {
+    pub entries: Vec<MapEntry>,
+}
+
+impl MapEntry {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end && self.start < end
+    }
+
+    pub fn readable(&self) -> bool {
+        self.perms.as_bytes().first() == Some(&b'r')
+    }
+
+    pub fn writable(&self) -> bool {
+        self.perms.as_bytes().get(1) == Some(&b'w')
+    }
+
+    pub fn executable(&self) -> bool {
+        self.perms.as_bytes().get(2) == Some(&b'x')
+    }
+}
+
+impl ProcessMaps {
+    pub fn read(pid: u32) -> Result<Self, io::Error> {
+        Ok(Self::parse(&io::read_to_string(privsep::open_proc(pid, ProcFile::Maps)?)?))
+    }
+
+    pub fn parse(maps: &str) -> Self {
+        let entries = maps.lines().filter_map(Self::parse_line).collect();
+        Self { entries }
+    }
+
+    fn parse_line(line: &str) -> Option<MapEntry> {
+        // start-end perms offset dev inode [path]
+        let mut fields = line.splitn(6, char::is_whitespace);
+        let (start, end) = fields.next()?.split_once('-')?;
+        let perms = fields.next()?.to_string();
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let _dev = fields.next()?;
+        let inode = fields.next()?.parse().ok()?;
+        let path = fields.next()
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string());
+
+        Some(MapEntry {
+            start: u64::from_str_radix(start, 16).ok()?,
+            end: u64::from_str_radix(end, 16).ok()?,
+            perms,
+            offset,
+            inode,
+            path,
+        })
+    }
+
+    // Pseudo-mappings such as "[heap]" or "[stack]"
+    pub fn named(&self, name: &str) -> Option<&MapEntry> {
+        self.entries.iter().find(|e| e.path.as_deref() == Some(name))
+    }
+
+    pub fn overlapping(&self, start: u64, end: u64) -> Vec<&MapEntry> {
+        self.entries.iter().filter(|e| e.overlaps(start, end)).collect()
+    }
+
+    pub fn containing(&self, addr: u64) -> Option<&MapEntry> {
+        self.entries.iter().find(|e| addr >= e.start && addr < e.end)
+    }
+}
+
+// Address-space fields of /proc/[pid]/stat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmStat {
+    pub start_stack: u64,
+    pub start_brk: u64,
+}
+
+pub fn mm_stat(pid: u32) -> Result<MmStat, io::Error> {
+    Ok(parse_mm_stat(&privsep::read_stat(pid)?))
+}
+
+pub fn parse_mm_stat(stat: &str) -> MmStat {
+    // Fields after the parenthesised comm; see proc(5)
+    let rest = stat.rsplit_once(')').map(|(_, r)| r).unwrap_or("");
+    let fields: Vec<u64> = rest.split_whitespace()
+        .map(|f| f.parse().unwrap_or(0))
+        .collect();
+    // proc(5) numbering starts at 1 and `rest` begins at field 3
+    let field = |n: usize| fields.get(n - 3).copied().unwrap_or(0);
+
+    MmStat {
+        start_stack: field(28),
+        start_brk: field(47),
+    }
+}
+
+// What tells a process from a later one that was given its PID: start
+// time (field 22 of /proc/[pid]/stat, clock ticks after boot) and
+// executable
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ProcessIdentity {
+    pub start_time: u64,
+    pub exe: String,
+}
+
+// Now, in the clock ticks after boot that ProcessIdentity::start_time uses
+pub fn boot_ticks() -> u64 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: now is valid for writes; CLOCK_BOOTTIME always exists on Linux
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut now) };
+    // SAFETY: no pointers
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    now.tv_sec as u64 * hz + now.tv_nsec as u64 * hz / 1_000_000_000
+}
+
+pub fn identity(pid: u32) -> Option<ProcessIdentity> {
+    let start_time = start_time(pid)?;
+    let exe = privsep::read_exe(pid).ok()?.to_string_lossy().into_owned();
+    Some(ProcessIdentity { start_time, exe })
+}
+
+// ProcessIdentity::start_time alone, for telling a reused PID apart
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = privsep::read_stat(pid).ok()?;
+    let rest = stat.rsplit_once(')').map(|(_, r)| r)?;
+    // `rest` begins at field 3
+    rest.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+// Lowest address the kernel lets user space map
+pub fn mmap_min_addr() -> u64 {
+    fs::read_to_string("/proc/sys/vm/mmap_min_addr")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(65536)
+}
+
+// Regions backed by huge pages, keyed by start address, with the huge page
+// size they need to stay aligned to. Covers hugetlbfs mappings (larger
+// KernelPageSize) and anonymous regions currently holding THPs.
+pub fn huge_page_backing(pid: u32) -> Result<HashMap<u64, u64>, io::Error> {
+    let smaps = io::read_to_string(privsep::open_proc(pid, ProcFile::Smaps)?)?;
+
+    let base_page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as u64;
+    // PMD size: 2MB with 4K pages, larger on 64K-page arm64 kernels
+    let thp_size = fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(2 * 1024 * 1024);
+
+    Ok(parse_huge_page_backing(&smaps, base_page, thp_size))
+}
+
+pub fn parse_huge_page_backing(smaps: &str, base_page: u64, thp_size: u64) -> HashMap<u64, u64> {
+    let mut backing = HashMap::new();
+    let mut current: Option<u64> = None;
+
+    for line in smaps.lines() {
+        // Region header lines start with "start-end"
+        if let Some(start) = line.split_whitespace().next()
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(start, _)| u64::from_str_radix(start, 16).ok())
+        {
+            current = Some(start);
+            continue;
+        }
+
+        let start = match current {
+            Some(start) => start,
+            None => continue,
+        };
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let kb: u64 = value.trim().trim_end_matches("kB").trim().parse().unwrap_or(0);
+
+        match key {
+            "KernelPageSize" if kb * 1024 > base_page => {
+                backing.insert(start, kb * 1024);
+            }
+            "AnonHugePages" | "ShmemPmdMapped" if kb > 0 => {
+                backing.entry(start).or_insert(thp_size);
+            }
+            _ => {}
+        }
+    }
+
+    backing
+}
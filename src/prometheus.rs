@@ -0,0 +1,191 @@
+// src/prometheus.rs
+// GET /metrics in the Prometheus text format (0.0.4), on its own listener.
+// Counters and timings come from the global metrics registry; gauges that
+// describe current state (subsystem health, probe groups, per-syscall
+// monitor stats, latest detector scores, host posture, snapshots on disk)
+// are read from the daemon at scrape time. Timings are exported as
+// summaries without quantiles plus a `_max` gauge. The endpoint is
+// unauthenticated: bind it to loopback or a management network.
+use crate::config::MetricsSection;
+use crate::daemon::{Control, SubsystemHealth};
+use crate::metrics::{self, MetricKey};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// Per-syscall series are bounded to the busiest syscalls
+const TOP_SYSCALLS: usize = 64;
+const SCORE_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99];
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("metrics listener not configured")]
+    NotConfigured,
+    #[error("metrics listener: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// Groups samples by metric so each family gets one HELP/TYPE header
+#[derive(Default)]
+struct Exposition {
+    families: BTreeMap<String, (&'static str, &'static str, Vec<String>)>,
+}
+
+impl Exposition {
+    fn sample(&mut self, name: &str, kind: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        let family = self.families.entry(family_name(name, kind)).or_insert_with(|| (kind, help, Vec::new()));
+        family.2.push(format!("{}{} {}", name, render_labels(labels), render_value(value)));
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (family, (kind, help, samples)) in &self.families {
+            if !help.is_empty() {
+                let _ = writeln!(out, "# HELP {} {}", family, help);
+            }
+            let _ = writeln!(out, "# TYPE {} {}", family, kind);
+            for sample in samples {
+                out.push_str(sample);
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+// Summary and histogram samples carry suffixes their family name doesn't
+fn family_name(name: &str, kind: &str) -> String {
+    let suffixes: &[&str] = match kind {
+        "summary" => &["_sum", "_count"],
+        "histogram" => &["_bucket", "_sum", "_count"],
+        _ => &[],
+    };
+    suffixes
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name)
+        .to_string()
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let rendered: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    format!("{{{}}}", rendered.join(","))
+}
+
+fn render_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn key_labels(key: &MetricKey) -> Vec<(&str, &str)> {
+    key.labels.iter().map(|(k, v)| (*k, v.as_str())).collect()
+}
+
+fn health_state(health: &SubsystemHealth) -> &'static str {
+    match health {
+        SubsystemHealth::Starting => "starting",
+        SubsystemHealth::Running => "running",
+        SubsystemHealth::Degraded { .. } => "degraded",
+        SubsystemHealth::Failed { .. } => "failed",
+        SubsystemHealth::Stopped => "stopped",
+    }
+}
+
+pub(crate) fn collect(control: &Control) -> String {
+    let mut exposition = Exposition::default();
+    let registry = metrics::global();
+
+    for (key, value) in registry.counters() {
+        exposition.sample(key.name, "counter", "", &key_labels(&key), value as f64);
+    }
+    for (key, timing) in registry.timings() {
+        let labels = key_labels(&key);
+        let name = key.name.strip_suffix("_seconds").unwrap_or(key.name);
+        exposition.sample(&format!("{}_seconds_sum", name), "summary", "", &labels, timing.sum_secs);
+        exposition.sample(&format!("{}_seconds_count", name), "summary", "", &labels, timing.count as f64);
+        exposition.sample(&format!("{}_max_seconds", name), "gauge", "", &labels, timing.max_secs);
+    }
+
+    for entry in control.health.iter() {
+        let state = health_state(entry.value());
+        let up = matches!(entry.value(), SubsystemHealth::Running | SubsystemHealth::Degraded { .. });
+        exposition.sample("qks_subsystem_up", "gauge", "Subsystem running, possibly degraded", &[("subsystem", entry.key())], up as u8 as f64);
+        exposition.sample("qks_subsystem_state", "gauge", "Current state of each subsystem", &[("subsystem", entry.key()), ("state", state)], 1.0);
+    }
+    for entry in control.probe_groups.iter() {
+        exposition.sample("qks_monitor_probe_group_enabled", "gauge", "Probe group events forwarded", &[("group", entry.key())], *entry.value() as u8 as f64);
+    }
+
+    if let Some(monitor) = &control.monitor {
+        for (nr, stat) in monitor.syscall_stats().into_iter().take(TOP_SYSCALLS) {
+            let nr = nr.to_string();
+            let labels = [("syscall", nr.as_str())];
+            exposition.sample("qks_monitor_syscalls_total", "counter", "Syscalls observed by the eBPF monitor", &labels, stat.count as f64);
+            exposition.sample("qks_monitor_syscall_avg_duration_seconds", "gauge", "Running average syscall duration", &labels, stat.avg_duration_ns as f64 / 1e9);
+            exposition.sample("qks_monitor_syscall_error_ratio", "gauge", "Share of calls returning an error", &labels, stat.error_rate as f64);
+            exposition.sample("qks_monitor_syscall_suspicious_score", "gauge", "Heuristic suspiciousness", &labels, stat.suspicious_score as f64);
+        }
+    }
+
+    // Distribution of each tracked process's latest score
+    let scores: Vec<f64> = control.latest.iter().map(|e| e.value().score as f64).collect();
+    for bound in SCORE_BUCKETS {
+        let le = bound.to_string();
+        let count = scores.iter().filter(|s| **s <= *bound).count();
+        exposition.sample("qks_detector_latest_score_bucket", "histogram", "Latest anomaly score of each tracked process", &[("le", le.as_str())], count as f64);
+    }
+    exposition.sample("qks_detector_latest_score_bucket", "histogram", "", &[("le", "+Inf")], scores.len() as f64);
+    exposition.sample("qks_detector_latest_score_sum", "histogram", "", &[], scores.iter().sum());
+    exposition.sample("qks_detector_latest_score_count", "histogram", "", &[], scores.len() as f64);
+
+    if let Some(posture) = control.posture.as_ref().and_then(|posture| posture.posture()) {
+        exposition.sample("qks_posture_secure_boot", "gauge", "EFI Secure Boot state", &[("state", posture.secure_boot.as_str())], 1.0);
+        exposition.sample("qks_posture_lockdown", "gauge", "Kernel lockdown mode", &[("mode", posture.lockdown.as_str())], 1.0);
+        exposition.sample("qks_posture_module_signing_enforced", "gauge", "Unsigned kernel modules are refused", &[], posture.modules_must_be_signed as u8 as f64);
+        exposition.sample("qks_posture_kernel_tainted", "gauge", "Kernel taint bitmask", &[], posture.tainted as f64);
+    }
+
+    match control.snapshots.list_snapshots() {
+        Ok(snapshots) => exposition.sample("qks_snapshots", "gauge", "Snapshots on disk", &[], snapshots.len() as f64),
+        Err(e) => tracing::debug!("Snapshot count unavailable for metrics: {}", e),
+    }
+
+    exposition.render()
+}
+
+async fn scrape(State(control): State<Arc<Control>>) -> impl IntoResponse {
+    // Reading stats and listing snapshots touch locks and the disk
+    let body = tokio::task::spawn_blocking(move || collect(&control)).await.unwrap_or_default();
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], body)
+}
+
+pub(crate) fn serve(config: &MetricsSection, control: Arc<Control>) -> Result<tokio::task::JoinHandle<()>, MetricsError> {
+    let listen = config.listen.ok_or(MetricsError::NotConfigured)?;
+    let listener = std::net::TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let app = Router::new().route("/metrics", get(scrape)).with_state(control);
+
+    Ok(tokio::spawn(async move {
+        let served = async move { axum::serve(tokio::net::TcpListener::from_std(listener)?, app).await };
+        if let Err(e) = served.await {
+            tracing::error!("Metrics endpoint on {} stopped: {}", listen, e);
+        }
+    }))
+}
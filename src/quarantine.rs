@@ -0,0 +1,276 @@
+// src/quarantine.rs
+// Quarantine keeps a suspicious process alive for forensics while taking
+// away its capacity to do harm. Each quarantined PID is moved into its own
+// child of the quarantine cgroup with CPU, memory and task caps, and all
+// traffic of sockets in that cgroup, loopback included, is dropped by
+// nftables. Linux cannot move a running process into another network
+// namespace (setns only ever applies to the caller), so these rules stand in
+// for an empty namespace with no routes. A socket belongs to the cgroup it
+// was created in, so the process's existing TCP sockets are destroyed with
+// `ss -K` as it moves; UDP sockets it already holds are not covered.
+use crate::config::QuarantineSection;
+use crate::privsep::{self, HelperRequest};
+use crate::response::{run_nft, NFT_TABLE};
+use crate::systemd;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+pub(crate) const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CONTROLLERS: &str = "+cpu +memory +pids";
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuarantineError {
+    #[error("{}: {source}", .path.display())]
+    Cgroup { path: PathBuf, source: std::io::Error },
+    #[error("PID {0} is not in a cgroup v2 hierarchy")]
+    NoCgroup(u32),
+    #[error("PID {0} is already quarantined")]
+    AlreadyQuarantined(u32),
+    #[error("PID {0} is not quarantined")]
+    NotQuarantined(u32),
+    #[error("network isolation: {0}")]
+    Network(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quarantined {
+    pub pid: u32,
+    // Where release puts it back
+    pub original_cgroup: String,
+    pub cgroup: String,
+    pub since: u64,
+    pub sockets_destroyed: usize,
+}
+
+pub struct Quarantine {
+    section: QuarantineSection,
+    active: DashMap<u32, Quarantined>,
+    // The parent cgroup and the drop rules are set up on first use
+    prepared: Mutex<bool>,
+}
+
+impl Quarantine {
+    pub fn new(section: QuarantineSection) -> Self {
+        Self { section, active: DashMap::new(), prepared: Mutex::new(false) }
+    }
+
+    fn root(&self) -> PathBuf {
+        Path::new(CGROUP_ROOT).join(&self.section.cgroup)
+    }
+
+    pub fn list(&self) -> Vec<Quarantined> {
+        let mut list: Vec<Quarantined> = self.active.iter().map(|e| e.value().clone()).collect();
+        list.sort_by_key(|q| q.since);
+        list
+    }
+
+    // Blocking: writes cgroupfs and runs nft and ss
+    pub fn isolate(&self, pid: u32) -> Result<Quarantined, QuarantineError> {
+        if self.active.contains_key(&pid) {
+            return Err(QuarantineError::AlreadyQuarantined(pid));
+        }
+        self.prepare()?;
+        let original_cgroup = cgroup_of(pid)?;
+
+        let name = format!("pid-{}", pid);
+        let dir = self.root().join(&name);
+        create_dir(&dir)?;
+        let memory_max = self.section.memory_max_mb * 1024 * 1024;
+        let cpu_quota = CPU_PERIOD_US * self.section.cpu_percent as u64 / 100;
+        write(&dir.join("cpu.max"), &format!("{} {}", cpu_quota, CPU_PERIOD_US))?;
+        write(&dir.join("memory.max"), &memory_max.to_string())?;
+        write(&dir.join("memory.swap.max"), "0").or_else(ignore_missing)?;
+        write(&dir.join("pids.max"), &self.section.pids_max.to_string())?;
+
+        // Sockets have to be found before the move, while /proc/PID/net
+        // still describes them, and destroyed after it so that anything the
+        // process opens in between is already caught by the drop rules. The
+        // helper can only look once the process is in the quarantine cgroup.
+        let sockets = privsep::helper().is_none().then(|| tcp_sockets(pid));
+        if let Err(e) = write(&dir.join("cgroup.procs"), &pid.to_string()) {
+            let _ = privsep::cgroup_remove(&dir);
+            return Err(e);
+        }
+        let (found, sockets_destroyed) = match (privsep::helper(), sockets) {
+            (Some(helper), _) => helper
+                .call(&HelperRequest::DestroySockets { pid })
+                .and_then(|(counts, _)| Ok(serde_json::from_value::<(usize, usize)>(counts)?))
+                .unwrap_or_else(|e| {
+                    tracing::warn!(pid, "TCP sockets of PID {} not destroyed: {}", pid, e);
+                    (0, 0)
+                }),
+            (None, sockets) => destroy_all(&sockets.unwrap_or_default()),
+        };
+        if sockets_destroyed < found {
+            tracing::warn!(pid, "{} of PID {}'s TCP sockets could not be destroyed and keep their connectivity", found - sockets_destroyed, pid);
+        }
+
+        let quarantined = Quarantined {
+            pid,
+            original_cgroup,
+            cgroup: format!("/{}/{}", self.section.cgroup, name),
+            since: systemd::now_secs(),
+            sockets_destroyed,
+        };
+        self.active.insert(pid, quarantined.clone());
+        tracing::warn!(pid, "PID {} quarantined in {}", pid, quarantined.cgroup);
+        Ok(quarantined)
+    }
+
+    // Sockets the process opened while quarantined stay blocked
+    pub fn release(&self, pid: u32) -> Result<Quarantined, QuarantineError> {
+        let (_, quarantined) = self.active.remove(&pid).ok_or(QuarantineError::NotQuarantined(pid))?;
+        let original = Path::new(CGROUP_ROOT).join(quarantined.original_cgroup.trim_start_matches('/'));
+        let moved = if Path::new(&format!("/proc/{}", pid)).exists() {
+            write(&original.join("cgroup.procs"), &pid.to_string())
+        } else {
+            Ok(())
+        };
+        if let Err(e) = moved {
+            self.active.insert(pid, quarantined);
+            return Err(e);
+        }
+        let dir = Path::new(CGROUP_ROOT).join(quarantined.cgroup.trim_start_matches('/'));
+        if let Err(e) = privsep::cgroup_remove(&dir) {
+            tracing::warn!(pid, "Quarantine cgroup {} not removed: {}", dir.display(), e);
+        }
+        tracing::info!(pid, "PID {} released from quarantine back to {}", pid, quarantined.original_cgroup);
+        Ok(quarantined)
+    }
+
+    fn prepare(&self) -> Result<(), QuarantineError> {
+        let mut prepared = self.prepared.lock().unwrap();
+        if *prepared {
+            return Ok(());
+        }
+        // Controllers have to be enabled on every level down to the
+        // per-PID children that carry the caps
+        let root = self.root();
+        let mut level = PathBuf::from(CGROUP_ROOT);
+        write(&level.join("cgroup.subtree_control"), CONTROLLERS)?;
+        for part in self.section.cgroup.split('/') {
+            level.push(part);
+            create_dir(&level)?;
+            write(&level.join("cgroup.subtree_control"), CONTROLLERS)?;
+        }
+
+        // `socket cgroupv2` resolves the path when the rule is loaded, so the
+        // cgroup must exist first. Flushing keeps restarts from stacking rules.
+        let depth = self.section.cgroup.split('/').count();
+        let script = format!(
+            "add table inet {table}\n\
+             add chain inet {table} quarantine_out {{ type filter hook output priority 0 ; policy accept ; }}\n\
+             add chain inet {table} quarantine_in {{ type filter hook input priority 0 ; policy accept ; }}\n\
+             flush chain inet {table} quarantine_out\n\
+             flush chain inet {table} quarantine_in\n\
+             add rule inet {table} quarantine_out socket cgroupv2 level {depth} \"{path}\" counter drop comment \"qks quarantine\"\n\
+             add rule inet {table} quarantine_in socket cgroupv2 level {depth} \"{path}\" counter drop comment \"qks quarantine\"\n",
+            table = NFT_TABLE,
+            depth = depth,
+            path = self.section.cgroup,
+        );
+        run_nft(&script).map_err(QuarantineError::Network)?;
+        tracing::info!("Quarantine cgroup {} ready", root.display());
+        *prepared = true;
+        Ok(())
+    }
+}
+
+fn write(path: &Path, value: &str) -> Result<(), QuarantineError> {
+    privsep::cgroup_write(path, value).map_err(|source| QuarantineError::Cgroup { path: path.to_path_buf(), source })
+}
+
+fn create_dir(path: &Path) -> Result<(), QuarantineError> {
+    match privsep::cgroup_create(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(QuarantineError::Cgroup { path: path.to_path_buf(), source: e }),
+        _ => Ok(()),
+    }
+}
+
+// memory.swap.max is absent without swap accounting
+fn ignore_missing(e: QuarantineError) -> Result<(), QuarantineError> {
+    match e {
+        QuarantineError::Cgroup { source, .. } if source.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn cgroup_of(pid: u32) -> Result<String, QuarantineError> {
+    fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .ok()
+        .and_then(|cgroups| cgroups.lines().find_map(|l| l.strip_prefix("0::")).map(str::to_string))
+        .ok_or(QuarantineError::NoCgroup(pid))
+}
+
+// (local, remote) of the TCP sockets the process holds; a listener's
+// remote port is 0
+fn tcp_sockets(pid: u32) -> Vec<(SocketAddr, SocketAddr)> {
+    let inodes: HashSet<String> = fs::read_dir(format!("/proc/{}/fd", pid))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|fd| fs::read_link(fd.path()).ok())
+        .filter_map(|link| Some(link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.to_string()))
+        .collect();
+
+    let mut sockets = Vec::new();
+    for table in ["tcp", "tcp6"] {
+        let Ok(contents) = fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) else {
+            continue;
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || !inodes.contains(fields[9]) {
+                continue;
+            }
+            if let (Some(local), Some(remote)) = (proc_endpoint(fields[1]), proc_endpoint(fields[2])) {
+                sockets.push((local, remote));
+            }
+        }
+    }
+    sockets
+}
+
+// "0100007F:1F90": the address words are printed in host byte order
+fn proc_endpoint(field: &str) -> Option<SocketAddr> {
+    let (addr, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for i in (0..addr.len()).step_by(8) {
+        bytes.extend_from_slice(&u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// The process's TCP sockets as found now, for the helper; (found, destroyed)
+pub(crate) fn destroy_sockets(pid: u32) -> (usize, usize) {
+    destroy_all(&tcp_sockets(pid))
+}
+
+fn destroy_all(sockets: &[(SocketAddr, SocketAddr)]) -> (usize, usize) {
+    (sockets.len(), sockets.iter().filter(|socket| destroy_socket(socket)).count())
+}
+
+// Needs CONFIG_INET_DIAG_DESTROY
+fn destroy_socket((local, remote): &(SocketAddr, SocketAddr)) -> bool {
+    let mut ss = Command::new("ss");
+    ss.args(["-K", "-n", "-t"]);
+    if remote.port() == 0 {
+        ss.args(["state", "listening", "src", &local.to_string()]);
+    } else {
+        ss.args(["src", &local.to_string(), "dst", &remote.to_string()]);
+    }
+    ss.stdout(Stdio::null()).stderr(Stdio::null()).status().map_or(false, |status| status.success())
+}
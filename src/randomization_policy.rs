@@ -0,0 +1,100 @@
+// src/randomization_policy.rs
+use crate::privsep;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// How much of a process's address space may be moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RandomizationProfile {
+    Full,
+    // Heap and mmap base stay put: JITs and databases keep absolute
+    // pointers into them
+    Partial,
+    VdsoOnly,
+    Excluded,
+}
+
+impl RandomizationProfile {
+    pub fn moves_heap(&self) -> bool {
+        *self == Self::Full
+    }
+
+    pub fn moves_mmap(&self) -> bool {
+        *self == Self::Full
+    }
+
+    pub fn moves_stack(&self) -> bool {
+        matches!(self, Self::Full | Self::Partial)
+    }
+
+    pub fn moves_vdso(&self) -> bool {
+        !matches!(self, Self::Excluded)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("cannot read policy file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid policy file: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+// Policy file layout:
+// { "default": "full",
+//   "binaries": { "/usr/bin/java": "partial", "/opt/db/bin/*": "excluded" } }
+// A trailing '*' matches any path with that prefix; the longest match wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RandomizationPolicy {
+    #[serde(default = "RandomizationPolicy::default_profile")]
+    pub default: RandomizationProfile,
+    #[serde(default)]
+    pub binaries: HashMap<String, RandomizationProfile>,
+}
+
+impl Default for RandomizationPolicy {
+    fn default() -> Self {
+        Self {
+            default: Self::default_profile(),
+            binaries: HashMap::new(),
+        }
+    }
+}
+
+impl RandomizationPolicy {
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn default_profile() -> RandomizationProfile {
+        RandomizationProfile::Full
+    }
+
+    pub fn profile_for_exe(&self, exe: &str) -> RandomizationProfile {
+        if let Some(profile) = self.binaries.get(exe) {
+            return *profile;
+        }
+
+        self.binaries.iter()
+            .filter_map(|(pattern, profile)| {
+                let prefix = pattern.strip_suffix('*')?;
+                exe.starts_with(prefix).then_some((prefix.len(), *profile))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map_or(self.default, |(_, profile)| profile)
+    }
+
+    // Resolved on every call: the process may have exec'd since last time
+    pub fn profile_for_pid(&self, pid: u32) -> RandomizationProfile {
+        match privsep::read_exe(pid) {
+            Ok(exe) => {
+                let exe = exe.to_string_lossy();
+                self.profile_for_exe(exe.trim_end_matches(" (deleted)"))
+            }
+            Err(_) => self.default,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// src/randomization_scheduler.rs
+use crate::memory_randomizer::{LayoutTrigger, MemoryRandomizer};
+use crate::randomization_policy::RandomizationProfile;
+use dashmap::DashMap;
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TICK_MS: u64 = 250;
+const DEFAULT_MAX_CPU_PERCENT: f32 = 80.0;
+
+pub struct RandomizationScheduler {
+    randomizer: Arc<Mutex<MemoryRandomizer>>,
+    enrolled: Arc<DashMap<u32, ScheduledProcess>>,
+    blackouts: Arc<Mutex<Vec<BlackoutWindow>>>,
+    max_cpu_percent: f32,
+    apply_layouts: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cadence {
+    pub interval: Duration,
+    // Fraction of the interval randomly added or removed (0.0 - 1.0)
+    pub jitter: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledProcess {
+    pub pid: u32,
+    pub cadence: Cadence,
+    pub next_run: Instant,
+    pub runs: u64,
+    pub skipped_load: u64,
+    pub skipped_blackout: u64,
+    pub skipped_policy: u64,
+    last_cpu_ticks: Option<(u64, Instant)>,
+}
+
+// Daily window in UTC seconds-of-day; may wrap midnight (start > end)
+#[derive(Debug, Clone)]
+pub struct BlackoutWindow {
+    pub start_secs: u32,
+    pub end_secs: u32,
+    // None applies to every enrolled process
+    pub pid: Option<u32>,
+}
+
+impl BlackoutWindow {
+    pub fn contains(&self, secs_of_day: u32) -> bool {
+        if self.start_secs <= self.end_secs {
+            secs_of_day >= self.start_secs && secs_of_day < self.end_secs
+        } else {
+            secs_of_day >= self.start_secs || secs_of_day < self.end_secs
+        }
+    }
+}
+
+impl RandomizationScheduler {
+    pub fn new(randomizer: Arc<Mutex<MemoryRandomizer>>) -> Self {
+        Self {
+            randomizer,
+            enrolled: Arc::new(DashMap::new()),
+            blackouts: Arc::new(Mutex::new(Vec::new())),
+            max_cpu_percent: DEFAULT_MAX_CPU_PERCENT,
+            apply_layouts: true,
+        }
+    }
+
+    pub fn with_max_cpu_percent(mut self, percent: f32) -> Self {
+        self.max_cpu_percent = percent;
+        self
+    }
+
+    // Only regenerate the recorded layout, don't touch the live process
+    pub fn with_apply(mut self, apply: bool) -> Self {
+        self.apply_layouts = apply;
+        self
+    }
+
+    pub fn enroll(&self, pid: u32, cadence: Cadence) {
+        let next_run = Instant::now() + Self::jittered(&cadence);
+
+        self.enrolled.insert(pid, ScheduledProcess {
+            pid,
+            cadence,
+            next_run,
+            runs: 0,
+            skipped_load: 0,
+            skipped_blackout: 0,
+            skipped_policy: 0,
+            last_cpu_ticks: None,
+        });
+    }
+
+    pub fn unenroll(&self, pid: u32) {
+        self.enrolled.remove(&pid);
+    }
+
+    pub fn add_blackout(&self, window: BlackoutWindow) {
+        self.blackouts.lock().unwrap().push(window);
+    }
+
+    pub fn status(&self, pid: u32) -> Option<ScheduledProcess> {
+        self.enrolled.get(&pid).map(|p| p.clone())
+    }
+
+    pub fn start(&self) -> tokio::task::JoinHandle<()> {
+        let randomizer = self.randomizer.clone();
+        let enrolled = self.enrolled.clone();
+        let blackouts = self.blackouts.clone();
+        let max_cpu = self.max_cpu_percent;
+        let apply = self.apply_layouts;
+
+        tokio::spawn(async move {
+            loop {
+                let now = Instant::now();
+                let secs_of_day = Self::utc_secs_of_day();
+                let due: Vec<u32> = enrolled.iter()
+                    .filter(|p| p.next_run <= now)
+                    .map(|p| p.pid)
+                    .collect();
+
+                for pid in due {
+                    // Process is gone: stop scheduling it
+                    if !std::path::Path::new(&format!("/proc/{}", pid)).exists() {
+                        enrolled.remove(&pid);
+                        continue;
+                    }
+
+                    let mut entry = match enrolled.get_mut(&pid) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+                    entry.next_run = now + Self::jittered(&entry.cadence);
+
+                    let blacked_out = blackouts.lock().unwrap().iter()
+                        .any(|w| w.pid.map_or(true, |p| p == pid) && w.contains(secs_of_day));
+                    if blacked_out {
+                        entry.skipped_blackout += 1;
+                        continue;
+                    }
+
+                    let cpu = Self::sample_cpu_percent(&mut entry);
+                    if cpu.map_or(false, |c| c > max_cpu) {
+                        entry.skipped_load += 1;
+                        tracing::debug!(pid, "Skipping re-randomization of busy PID {} ({:.1}% CPU)", pid, cpu.unwrap_or(0.0));
+                        continue;
+                    }
+
+                    drop(entry);
+
+                    // The randomizer lock and the ptrace under it both block,
+                    // so they run on the blocking pool, not a runtime worker
+                    let randomizer = randomizer.clone();
+                    let run = tokio::task::spawn_blocking(move || {
+                        let mut randomizer = randomizer.lock().unwrap();
+                        // Checked every run: an exec may have changed the binary
+                        if randomizer.profile_for(pid) == RandomizationProfile::Excluded {
+                            return None;
+                        }
+                        let layout = randomizer.regenerate_layout_with(pid, LayoutTrigger::Scheduled);
+                        let applied = if apply { randomizer.apply_layout_to_process(pid) } else { Ok(()) };
+                        Some(applied.map(|()| layout.regeneration_count))
+                    }).await;
+
+                    let run = match run {
+                        Ok(run) => run,
+                        Err(e) => Some(Err(e.to_string())),
+                    };
+                    if let Some(mut entry) = enrolled.get_mut(&pid) {
+                        match run {
+                            None => entry.skipped_policy += 1,
+                            Some(_) => entry.runs += 1,
+                        }
+                    }
+                    match run {
+                        None => {}
+                        Some(Ok(regeneration)) => tracing::info!(
+                            pid,
+                            "Re-randomized PID {} (regeneration #{})",
+                            pid, regeneration
+                        ),
+                        Some(Err(e)) => tracing::warn!(pid, error = %e, "Scheduled re-randomization of PID {} failed: {}", pid, e),
+                    }
+                }
+
+                tokio::time::sleep(Duration::from_millis(TICK_MS)).await;
+            }
+        })
+    }
+
+    fn jittered(cadence: &Cadence) -> Duration {
+        let base = cadence.interval.as_secs_f64();
+        let jitter = cadence.jitter.clamp(0.0, 1.0) as f64;
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+
+        Duration::from_secs_f64((base * factor).max(0.001))
+    }
+
+    // CPU share since the previous sample; None on the first sample
+    fn sample_cpu_percent(entry: &mut ScheduledProcess) -> Option<f32> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", entry.pid)).ok()?;
+        let rest = stat.rsplit_once(')')?.1;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+
+        // utime and stime are fields 14 and 15; `rest` starts at field 3
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+        let ticks = utime + stime;
+        let now = Instant::now();
+
+        let previous = entry.last_cpu_ticks.replace((ticks, now));
+        let (prev_ticks, prev_at) = previous?;
+
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f32;
+        let elapsed = now.duration_since(prev_at).as_secs_f32();
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        Some((ticks.saturating_sub(prev_ticks) as f32 / hz) / elapsed * 100.0)
+    }
+
+    fn utc_secs_of_day() -> u32 {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        (secs % 86_400) as u32
+    }
+}
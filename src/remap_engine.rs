@@ -0,0 +1,554 @@
+// src/remap_engine.rs
+// Remote syscall injection below is x86-64 only (register names, insn encoding)
+use crate::arch_profile::ArchProfile;
+use crate::memory_randomizer::MemoryLayout;
+use crate::privsep::{self, HelperRequest};
+use crate::process_maps::{self, ProcessMaps};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+
+// x86-64 `syscall` instruction
+const SYSCALL_INSN: u64 = 0x050f;
+// PR_SET_MM, which heap and stack moves need, checks the caller's
+// capabilities, and the caller is the target
+const CAP_SYS_RESOURCE: u32 = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemapError {
+    #[error("ptrace {op} failed on PID {pid}: {source}")]
+    Ptrace { op: &'static str, pid: u32, source: io::Error },
+    #[error("cannot read mappings of PID {0}: {1}")]
+    Maps(u32, io::Error),
+    #[error("remote syscall {nr} in PID {pid} returned errno {errno}")]
+    RemoteSyscall { pid: u32, nr: i64, errno: i64 },
+    #[error("PID {0} has no {1} mapping")]
+    MissingRegion(u32, &'static str),
+    #[error("remap unsupported: {0}")]
+    Unsupported(String),
+    #[error("remap of PID {pid} failed ({cause}); rolled back: {rolled_back}")]
+    Aborted { pid: u32, cause: Box<RemapError>, rolled_back: bool },
+    #[error("remap through the privileged helper: {0}")]
+    Helper(io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugepagePolicy {
+    // Relocate to a base aligned to the region's huge page size
+    Align,
+    // Never move huge-page backed regions
+    Pin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Library,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionMove {
+    pub kind: RegionKind,
+    pub old_start: u64,
+    pub len: u64,
+    pub new_start: u64,
+}
+
+// Every completed step is journaled so it can be undone in reverse order
+#[derive(Debug, Clone)]
+enum JournalEntry {
+    Moved { from: u64, to: u64, len: u64 },
+    MmField { field: libc::c_int, old_value: u64 },
+    Protected { start: u64, len: u64, old_prot: u64 },
+    Patched { addr: u64, old_value: u64 },
+}
+
+// One pointer-sized word to rewrite in the target after a move
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointerPatch {
+    pub addr: u64,
+    pub old_value: u64,
+    pub new_value: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtectionChange {
+    pub start: u64,
+    pub len: u64,
+    pub old_prot: u64,
+    pub new_prot: u64,
+}
+
+pub struct RemapEngine {
+    pid: u32,
+    // Other threads of the target, stopped while the change runs
+    threads: Vec<u32>,
+    journal: Vec<JournalEntry>,
+    saved_regs: Option<libc::user_regs_struct>,
+    original_regs: Option<libc::user_regs_struct>,
+    // (tid, signal) that arrived while stopped, delivered on detach
+    pending_signals: Vec<(u32, libc::c_int)>,
+}
+
+impl RemapEngine {
+    pub fn apply(pid: u32, layout: &MemoryLayout, hugepages: HugepagePolicy) -> Result<Vec<RegionMove>, RemapError> {
+        let moves = Self::plan(pid, layout, hugepages)?;
+
+        Self::apply_moves(pid, &moves, &[])?;
+        Ok(moves)
+    }
+
+    // Region moves a layout would take, without touching the process
+    pub fn plan(pid: u32, layout: &MemoryLayout, hugepages: HugepagePolicy) -> Result<Vec<RegionMove>, RemapError> {
+        Self::plan_moves(pid, layout, hugepages)
+    }
+
+    // Heap/stack moves plus pointer patches (post-move addresses) in one
+    // transaction: a failed patch rolls the moves back too. Only targets
+    // holding CAP_SYS_RESOURCE can have their heap or stack moved.
+    pub fn apply_moves(pid: u32, moves: &[RegionMove], patches: &[PointerPatch]) -> Result<(), RemapError> {
+        // Saved frame pointers, argv/envp/auxv and __environ all point into
+        // the stack; rewriting rsp/rbp alone leaves them dangling
+        for mv in moves.iter().filter(|mv| mv.kind == RegionKind::Stack) {
+            let new_end = mv.new_start + mv.len;
+            if !patches.iter().any(|p| p.new_value >= mv.new_start && p.new_value < new_end) {
+                return Err(RemapError::Unsupported(format!("stack move of PID {} has no pointer fixups", pid)));
+            }
+        }
+        if let Some(helper) = privsep::helper() {
+            let request = HelperRequest::ApplyMoves { pid, moves: moves.to_vec(), patches: patches.to_vec() };
+            return helper.call(&request).map(drop).map_err(RemapError::Helper);
+        }
+        Self::run(pid, |engine| {
+            engine.execute(moves)?;
+            for patch in patches {
+                engine.patch(patch)?;
+                engine.journal.push(JournalEntry::Patched {
+                    addr: patch.addr,
+                    old_value: patch.old_value,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    // mprotect inside the target, all-or-nothing like a layout move
+    pub fn protect(pid: u32, changes: &[ProtectionChange]) -> Result<(), RemapError> {
+        if let Some(helper) = privsep::helper() {
+            return helper.call(&HelperRequest::Protect { pid, changes: changes.to_vec() }).map(drop).map_err(RemapError::Helper);
+        }
+        Self::run(pid, |engine| {
+            for change in changes {
+                engine.remote_syscall(libc::SYS_mprotect, [change.start, change.len, change.new_prot, 0, 0, 0])?;
+                engine.journal.push(JournalEntry::Protected {
+                    start: change.start,
+                    len: change.len,
+                    old_prot: change.old_prot,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    // Move arbitrary mappings and then rewrite pointers that referred to
+    // them; used for library rebasing where several mappings move together
+    pub fn relocate(pid: u32, moves: &[RegionMove], patches: &[PointerPatch]) -> Result<(), RemapError> {
+        if let Some(helper) = privsep::helper() {
+            let request = HelperRequest::Relocate { pid, moves: moves.to_vec(), patches: patches.to_vec() };
+            return helper.call(&request).map(drop).map_err(RemapError::Helper);
+        }
+        Self::run(pid, |engine| {
+            for mv in moves {
+                engine.remote_mremap(mv.old_start, mv.len, mv.new_start)?;
+                engine.journal.push(JournalEntry::Moved {
+                    from: mv.old_start,
+                    to: mv.new_start,
+                    len: mv.len,
+                });
+            }
+
+            for patch in patches {
+                engine.patch(patch)?;
+                engine.journal.push(JournalEntry::Patched {
+                    addr: patch.addr,
+                    old_value: patch.old_value,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn run<F>(pid: u32, steps: F) -> Result<(), RemapError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), RemapError>,
+    {
+        let mut engine = Self {
+            pid,
+            threads: Vec::new(),
+            journal: Vec::new(),
+            saved_regs: None,
+            original_regs: None,
+            pending_signals: Vec::new(),
+        };
+
+        // Stop the target: nothing it does may race with the change
+        engine.attach()?;
+        if let Err(e) = engine.stop_threads() {
+            engine.detach();
+            return Err(e);
+        }
+
+        if let Err(cause) = steps(&mut engine) {
+            let rolled_back = engine.rollback().is_ok();
+            engine.detach();
+
+            tracing::error!(pid, "Remap of PID {} aborted: {} (rolled back: {})", pid, cause, rolled_back);
+            return Err(RemapError::Aborted {
+                pid,
+                cause: Box::new(cause),
+                rolled_back,
+            });
+        }
+
+        engine.detach();
+        Ok(())
+    }
+
+    fn plan_moves(pid: u32, layout: &MemoryLayout, hugepages: HugepagePolicy) -> Result<Vec<RegionMove>, RemapError> {
+        let maps = ProcessMaps::read(pid).map_err(|e| RemapError::Maps(pid, e))?;
+        let huge_backed = process_maps::huge_page_backing(pid).map_err(|e| RemapError::Maps(pid, e))?;
+
+        let arch = ArchProfile::for_pid(pid);
+
+        let mut moves = Vec::new();
+        for (kind, name, target) in [
+            (RegionKind::Heap, "[heap]", layout.heap_base),
+            (RegionKind::Stack, "[stack]", layout.stack_base),
+        ] {
+            let region = maps.named(name).ok_or(RemapError::MissingRegion(pid, name))?;
+            let (start, end) = (region.start, region.end);
+
+            // Huge-page backed regions need huge-boundary bases (or stay put)
+            let alignment = match huge_backed.get(&start) {
+                Some(_) if hugepages == HugepagePolicy::Pin => {
+                    tracing::info!(pid, "PID {}: leaving huge-page backed {} in place", pid, name);
+                    continue;
+                }
+                Some(huge_size) => *huge_size,
+                None => arch.page_size(),
+            };
+            let align = |addr: u64| addr & !(alignment - 1);
+
+            // Stack grows down: keep the top of the region at the new base
+            let len = end - start;
+            let new_start = match kind {
+                RegionKind::Stack => align(target.saturating_sub(len)),
+                _ => align(target),
+            };
+
+            if new_start != start {
+                moves.push(RegionMove { kind, old_start: start, len, new_start });
+            }
+        }
+
+        Ok(moves)
+    }
+
+    fn execute(&mut self, moves: &[RegionMove]) -> Result<(), RemapError> {
+        // Refused before anything moves rather than rolled back after
+        if moves.iter().any(|mv| mv.kind != RegionKind::Library) && !Self::has_sys_resource(self.pid) {
+            return Err(RemapError::Unsupported(format!("PID {} lacks CAP_SYS_RESOURCE, which moving its heap or stack needs", self.pid)));
+        }
+        let stat = process_maps::mm_stat(self.pid).map_err(|e| RemapError::Maps(self.pid, e))?;
+
+        for mv in moves {
+            // mremap with MREMAP_FIXED moves the pages without copying;
+            // process_vm_writev is only needed when the kernel refuses
+            self.remote_mremap(mv.old_start, mv.len, mv.new_start)?;
+            self.journal.push(JournalEntry::Moved {
+                from: mv.old_start,
+                to: mv.new_start,
+                len: mv.len,
+            });
+
+            let delta = mv.new_start.wrapping_sub(mv.old_start);
+            match mv.kind {
+                RegionKind::Heap => {
+                    // Kernel requires start_brk <= brk at every step, so the
+                    // order depends on which way the heap moves
+                    let start_brk = (libc::PR_SET_MM_START_BRK, stat.start_brk, stat.start_brk.wrapping_add(delta));
+                    // The current break isn't exported by /proc; the [heap]
+                    // VMA ends at the page-rounded break, which is as good
+                    let heap_end = mv.old_start + mv.len;
+                    let brk = (libc::PR_SET_MM_BRK, heap_end, heap_end.wrapping_add(delta));
+                    let order = if mv.new_start > mv.old_start { [brk, start_brk] } else { [start_brk, brk] };
+
+                    for (field, old, new) in order {
+                        self.set_mm_field(field, old, new)?;
+                    }
+                }
+                RegionKind::Stack => {
+                    self.set_mm_field(libc::PR_SET_MM_START_STACK, stat.start_stack, stat.start_stack.wrapping_add(delta))?;
+                    self.relocate_stack_registers(mv.old_start, mv.len, delta);
+                }
+                // Libraries go through relocate(), which carries their pointer patches
+                RegionKind::Library => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RemapError> {
+        let mut failed = false;
+
+        while let Some(entry) = self.journal.pop() {
+            let result = match entry {
+                JournalEntry::Moved { from, to, len } => self.remote_mremap(to, len, from),
+                JournalEntry::MmField { field, old_value } => {
+                    self.remote_syscall(libc::SYS_prctl, [libc::PR_SET_MM as u64, field as u64, old_value, 0, 0, 0])
+                        .map(|_| ())
+                }
+                JournalEntry::Patched { addr, old_value } => self.poke(addr, old_value),
+                JournalEntry::Protected { start, len, old_prot } => {
+                    self.remote_syscall(libc::SYS_mprotect, [start, len, old_prot, 0, 0, 0])
+                        .map(|_| ())
+                }
+            };
+
+            if let Err(e) = result {
+                // Keep going: a partial rollback beats none
+                tracing::error!(pid = self.pid, "Rollback step {:?} failed on PID {}: {}", entry, self.pid, e);
+                failed = true;
+            }
+        }
+
+        // Undo any stack pointer adjustment; registers are written back on detach
+        self.saved_regs = self.original_regs;
+
+        if failed {
+            Err(RemapError::Unsupported("rollback incomplete".into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn has_sys_resource(pid: u32) -> bool {
+        let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+            return false;
+        };
+        status.lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_SYS_RESOURCE) != 0)
+    }
+
+    fn set_mm_field(&mut self, field: libc::c_int, old_value: u64, new_value: u64) -> Result<(), RemapError> {
+        // PR_SET_MM acts on the caller's mm, so it must run inside the target
+        self.remote_syscall(libc::SYS_prctl, [libc::PR_SET_MM as u64, field as u64, new_value, 0, 0, 0])?;
+        self.journal.push(JournalEntry::MmField { field, old_value });
+        Ok(())
+    }
+
+    fn remote_mremap(&mut self, from: u64, len: u64, to: u64) -> Result<(), RemapError> {
+        let flags = (libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED) as u64;
+        let ret = self.remote_syscall(libc::SYS_mremap, [from, len, len, flags, to, 0])?;
+
+        if ret != to {
+            return Err(RemapError::Unsupported(format!("mremap placed region at {:#x}, wanted {:#x}", ret, to)));
+        }
+        Ok(())
+    }
+
+    // Only over the value the patch expects, so a stale or forged patch
+    // can't overwrite something else
+    fn patch(&mut self, patch: &PointerPatch) -> Result<(), RemapError> {
+        let found = self.peek(patch.addr)?;
+        if found != patch.old_value {
+            return Err(RemapError::Unsupported(format!("word at {:#x} is {:#x}, not {:#x}", patch.addr, found, patch.old_value)));
+        }
+        self.poke(patch.addr, patch.new_value)
+    }
+
+    fn peek(&self, addr: u64) -> Result<u64, RemapError> {
+        // SAFETY: PEEKDATA writes nothing through its arguments; -1 is a
+        // valid word, so errno tells an error from it
+        unsafe {
+            *libc::__errno_location() = 0;
+            let word = libc::ptrace(libc::PTRACE_PEEKDATA, self.pid as libc::pid_t, addr as *mut libc::c_void, std::ptr::null_mut::<libc::c_void>());
+            if word == -1 && *libc::__errno_location() != 0 {
+                return Err(self.ptrace_err("PEEKDATA"));
+            }
+            Ok(word as u64)
+        }
+    }
+
+    fn poke(&mut self, addr: u64, value: u64) -> Result<(), RemapError> {
+        unsafe { self.ptrace_call(libc::PTRACE_POKEDATA, addr, value, "POKEDATA") }.map(|_| ())
+    }
+
+    fn relocate_stack_registers(&mut self, old_start: u64, len: u64, delta: u64) {
+        if let Some(regs) = self.saved_regs.as_mut() {
+            let in_stack = |addr: u64| addr >= old_start && addr < old_start + len;
+            if in_stack(regs.rsp) {
+                regs.rsp = regs.rsp.wrapping_add(delta);
+            }
+            if in_stack(regs.rbp) {
+                regs.rbp = regs.rbp.wrapping_add(delta);
+            }
+        }
+    }
+
+    fn remote_syscall(&mut self, nr: libc::c_long, args: [u64; 6]) -> Result<u64, RemapError> {
+        let saved = self.saved_regs.ok_or_else(|| RemapError::Unsupported("target not attached".into()))?;
+        let pid = self.pid as libc::pid_t;
+        let rip = saved.rip;
+
+        unsafe {
+            // Temporarily plant a syscall instruction at the stopped rip
+            *libc::__errno_location() = 0;
+            let original = libc::ptrace(libc::PTRACE_PEEKTEXT, pid, rip as *mut libc::c_void, std::ptr::null_mut::<libc::c_void>());
+            if original == -1 && *libc::__errno_location() != 0 {
+                return Err(self.ptrace_err("PEEKTEXT"));
+            }
+            let patched = (original as u64 & !0xFFFF) | SYSCALL_INSN;
+            self.ptrace_call(libc::PTRACE_POKETEXT, rip, patched, "POKETEXT")?;
+
+            let mut regs = saved;
+            regs.rax = nr as u64;
+            regs.orig_rax = u64::MAX;
+            regs.rdi = args[0];
+            regs.rsi = args[1];
+            regs.rdx = args[2];
+            regs.r10 = args[3];
+            regs.r8 = args[4];
+            regs.r9 = args[5];
+
+            let step = self.ptrace_call(libc::PTRACE_SETREGS, 0, &regs as *const _ as u64, "SETREGS")
+                .and_then(|_| self.ptrace_call(libc::PTRACE_SINGLESTEP, 0, 0, "SINGLESTEP"))
+                .and_then(|_| self.wait_task_stopped(self.pid, libc::PTRACE_SINGLESTEP));
+
+            let mut result_regs: libc::user_regs_struct = std::mem::zeroed();
+            let fetched = step.and_then(|_| {
+                self.ptrace_call(libc::PTRACE_GETREGS, 0, &mut result_regs as *mut _ as u64, "GETREGS")
+            });
+
+            // Always restore the original instruction, even on failure
+            self.ptrace_call(libc::PTRACE_POKETEXT, rip, original as u64, "POKETEXT")?;
+            fetched?;
+
+            let ret = result_regs.rax as i64;
+            if (-4095..0).contains(&ret) {
+                return Err(RemapError::RemoteSyscall { pid: self.pid, nr: nr as i64, errno: -ret });
+            }
+            Ok(ret as u64)
+        }
+    }
+
+    fn attach(&mut self) -> Result<(), RemapError> {
+        unsafe {
+            self.ptrace_call(libc::PTRACE_ATTACH, 0, 0, "ATTACH")?;
+            self.wait_task_stopped(self.pid, libc::PTRACE_CONT)?;
+
+            let mut regs: libc::user_regs_struct = std::mem::zeroed();
+            self.ptrace_call(libc::PTRACE_GETREGS, 0, &mut regs as *mut _ as u64, "GETREGS")?;
+            self.saved_regs = Some(regs);
+            self.original_regs = Some(regs);
+        }
+        Ok(())
+    }
+
+    // PTRACE_ATTACH stops one thread; every other one in the thread group
+    // is attached too, until a pass over /proc/PID/task finds none new (a
+    // running thread may have started another meanwhile)
+    fn stop_threads(&mut self) -> Result<(), RemapError> {
+        loop {
+            let tasks = fs::read_dir(format!("/proc/{}/task", self.pid)).map_err(|e| RemapError::Maps(self.pid, e))?;
+            let new: Vec<u32> = tasks
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter(|tid| *tid != self.pid && !self.threads.contains(tid))
+                .collect();
+            if new.is_empty() {
+                return Ok(());
+            }
+
+            for tid in new {
+                let ret = unsafe { libc::ptrace(libc::PTRACE_ATTACH, tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(), std::ptr::null_mut::<libc::c_void>()) };
+                if ret == -1 {
+                    let source = io::Error::last_os_error();
+                    // Exited since the directory was read
+                    if source.raw_os_error() == Some(libc::ESRCH) {
+                        continue;
+                    }
+                    return Err(RemapError::Ptrace { op: "ATTACH", pid: tid, source });
+                }
+                self.threads.push(tid);
+                self.wait_task_stopped(tid, libc::PTRACE_CONT)?;
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        unsafe {
+            // Restore (possibly stack-adjusted) registers, then resume
+            if let Some(regs) = self.saved_regs.take() {
+                let _ = self.ptrace_call(libc::PTRACE_SETREGS, 0, &regs as *const _ as u64, "SETREGS");
+            }
+            let signal = self.take_signal(self.pid);
+            let _ = self.ptrace_call(libc::PTRACE_DETACH, 0, signal as u64, "DETACH");
+            let threads = std::mem::take(&mut self.threads);
+            for tid in threads {
+                let signal = self.take_signal(tid) as usize;
+                libc::ptrace(libc::PTRACE_DETACH, tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(), signal as *mut libc::c_void);
+            }
+            // A thread can only be handed one signal as it is let go
+            for (tid, signal) in self.pending_signals.drain(..) {
+                libc::syscall(libc::SYS_tgkill, self.pid as libc::pid_t, tid as libc::pid_t, signal);
+            }
+        }
+    }
+
+    fn take_signal(&mut self, tid: u32) -> libc::c_int {
+        match self.pending_signals.iter().position(|(t, _)| *t == tid) {
+            Some(i) => self.pending_signals.remove(i).1,
+            None => 0,
+        }
+    }
+
+    unsafe fn ptrace_call(&self, request: libc::c_uint, addr: u64, data: u64, op: &'static str) -> Result<libc::c_long, RemapError> {
+        let ret = libc::ptrace(request, self.pid as libc::pid_t, addr as *mut libc::c_void, data as *mut libc::c_void);
+        if ret == -1 {
+            return Err(self.ptrace_err(op));
+        }
+        Ok(ret)
+    }
+
+    fn ptrace_err(&self, op: &'static str) -> RemapError {
+        RemapError::Ptrace { op, pid: self.pid, source: io::Error::last_os_error() }
+    }
+
+    // Until the SIGSTOP of an attach or the SIGTRAP of a step. Any other
+    // signal that stops the task is held back for detach, and the task
+    // resumed with `resume` to get on to the stop we want.
+    fn wait_task_stopped(&mut self, tid: u32, resume: libc::c_uint) -> Result<(), RemapError> {
+        loop {
+            let mut status = 0;
+            let ret = unsafe { libc::waitpid(tid as libc::pid_t, &mut status, libc::__WALL) };
+
+            if ret < 0 || !libc::WIFSTOPPED(status) {
+                return Err(RemapError::Ptrace { op: "waitpid", pid: tid, source: io::Error::last_os_error() });
+            }
+            let signal = libc::WSTOPSIG(status);
+            if signal == libc::SIGSTOP || signal == libc::SIGTRAP {
+                return Ok(());
+            }
+            self.pending_signals.push((tid, signal));
+            let ret = unsafe { libc::ptrace(resume, tid as libc::pid_t, std::ptr::null_mut::<libc::c_void>(), std::ptr::null_mut::<libc::c_void>()) };
+            if ret == -1 {
+                return Err(RemapError::Ptrace { op: "resume", pid: tid, source: io::Error::last_os_error() });
+            }
+        }
+    }
+}
@@ -0,0 +1,474 @@
+// src/response.rs
+// Everything qksd does to a process goes through here, whoever asked: the
+// policy engine, an operator over qksctl/D-Bus, or a remote API. Before
+// acting, the target is checked (never init, kernel threads, qksd itself
+// or a protected executable) and the action's cooldown for that PID
+// consulted. In dry-run mode the checks still run but nothing is done.
+// Every request, including refused and dry-run ones, is appended to the
+// audit log (audit_log.rs) as a JSON line and published on the event bus.
+use crate::audit_log::AuditLog;
+use crate::config::{QuarantineSection, ResponseSection};
+use crate::crypto_identifiers::{CryptoIdentifier, ProcessToken};
+use crate::events::{EventBus, ResponseEvent, SecurityEvent, SnapshotEvent};
+use crate::freezer;
+use crate::memory_randomizer::{LayoutTrigger, MemoryRandomizer};
+use crate::metrics;
+use crate::privsep::{self, HelperRequest, Signal};
+use crate::quarantine::{Quarantine, Quarantined};
+use crate::recovery_snapshot::{SnapshotManager, SnapshotSummary, TrackedState};
+use crate::systemd;
+use crate::token_keyring::{KeyringScope, TokenKeyring};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Holds taken by `stop`; other subsystems' freezes are theirs to undo
+const FREEZE_OWNER: &str = "response";
+
+// Tag of the snapshots the Snapshot action takes
+const SNAPSHOT_TAG: &str = "response";
+
+// How often the keyrings of exited token holders are revoked
+const TOKEN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// nftables table holding the egress blocks and quarantine rules
+pub(crate) const NFT_TABLE: &str = "qks";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseAction {
+    // SIGKILL
+    Kill,
+    // Freeze; thawed by `thaw` or after response.freeze_secs
+    Stop,
+    Thaw,
+    // Draw and apply a fresh memory layout
+    Rerandomize,
+    // Revoke the process's token, found in the keyring by PID
+    RevokeToken,
+    // Recovery snapshot of what qksd tracks, tagged "response"; no PID
+    Snapshot,
+    // Drop outgoing traffic from the process's cgroup
+    BlockEgress,
+    // Move into the quarantine cgroup: capped and cut off from the network
+    Quarantine,
+    // Undo a quarantine
+    Release,
+}
+
+impl ResponseAction {
+    pub const ALL: &'static [ResponseAction] = &[
+        ResponseAction::Kill,
+        ResponseAction::Stop,
+        ResponseAction::Thaw,
+        ResponseAction::Rerandomize,
+        ResponseAction::RevokeToken,
+        ResponseAction::Snapshot,
+        ResponseAction::BlockEgress,
+        ResponseAction::Quarantine,
+        ResponseAction::Release,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResponseAction::Kill => "kill",
+            ResponseAction::Stop => "stop",
+            ResponseAction::Thaw => "thaw",
+            ResponseAction::Rerandomize => "rerandomize",
+            ResponseAction::RevokeToken => "revoke_token",
+            ResponseAction::Snapshot => "snapshot",
+            ResponseAction::BlockEgress => "block_egress",
+            ResponseAction::Quarantine => "quarantine",
+            ResponseAction::Release => "release",
+        }
+    }
+
+    fn needs_pid(self) -> bool {
+        self != ResponseAction::Snapshot
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseOutcome {
+    Executed,
+    DryRun,
+    // A safety check said no
+    Refused,
+    CoolingDown,
+    Failed,
+}
+
+impl ResponseOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResponseOutcome::Executed => "executed",
+            ResponseOutcome::DryRun => "dry_run",
+            ResponseOutcome::Refused => "refused",
+            ResponseOutcome::CoolingDown => "cooling_down",
+            ResponseOutcome::Failed => "failed",
+        }
+    }
+
+    // Done, or would have been
+    pub fn is_success(self) -> bool {
+        matches!(self, ResponseOutcome::Executed | ResponseOutcome::DryRun)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseRequest {
+    pub action: ResponseAction,
+    pub pid: Option<u32>,
+    // "operator", "policy:<rule>", ...
+    pub origin: String,
+    pub reason: String,
+    // On top of the configured dry-run mode
+    pub dry_run: bool,
+}
+
+// One line of the audit log
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct ResponseRecord {
+    pub timestamp: u64,
+    pub action: ResponseAction,
+    pub pid: Option<u32>,
+    pub exe: Option<String>,
+    pub origin: String,
+    pub reason: String,
+    pub outcome: ResponseOutcome,
+    pub detail: Option<String>,
+}
+
+pub struct Responder {
+    section: ResponseSection,
+    audit: Arc<AuditLog>,
+    identity: Arc<CryptoIdentifier>,
+    keyring: TokenKeyring,
+    quarantine: Quarantine,
+    randomizer: Arc<Mutex<MemoryRandomizer>>,
+    snapshots: Arc<SnapshotManager>,
+    bus: Arc<EventBus>,
+    // (action, pid) -> last time it was executed
+    last_executed: DashMap<(ResponseAction, u32), Instant>,
+}
+
+impl Responder {
+    pub(crate) fn new(
+        section: ResponseSection,
+        quarantine: QuarantineSection,
+        audit: Arc<AuditLog>,
+        identity: Arc<CryptoIdentifier>,
+        randomizer: Arc<Mutex<MemoryRandomizer>>,
+        snapshots: Arc<SnapshotManager>,
+        bus: Arc<EventBus>,
+    ) -> Self {
+        Self {
+            section,
+            audit,
+            identity,
+            keyring: TokenKeyring::new(KeyringScope::Session),
+            quarantine: Quarantine::new(quarantine),
+            randomizer,
+            snapshots,
+            bus,
+            last_executed: DashMap::new(),
+        }
+    }
+
+    // Keep issued tokens where RevokeToken can find them by PID
+    pub(crate) fn track_token(&self, token: &ProcessToken) {
+        if let Err(e) = self.keyring.store(token) {
+            tracing::warn!(pid = token.pid, "Token for PID {} not stored in the keyring: {}", token.pid, e);
+        }
+    }
+
+    // Out of the keyring for revoking elsewhere. RevokeToken checks its
+    // target, which a process that has exited fails.
+    pub(crate) fn retire_token(&self, pid: u32) -> Option<ProcessToken> {
+        let token = match self.keyring.load(pid) {
+            Ok(token) => token?,
+            Err(e) => {
+                tracing::warn!(pid, "Token for PID {} not read from the keyring: {}", pid, e);
+                return None;
+            }
+        };
+        if let Err(e) = self.keyring.revoke(pid) {
+            tracing::warn!(pid, "Retired token of PID {} left in the keyring: {}", pid, e);
+        }
+        Some(token)
+    }
+
+    pub(crate) fn audit(&self) -> &Arc<AuditLog> {
+        &self.audit
+    }
+
+    // Blocking: reads every token holder's keyring and procfs
+    pub(crate) fn take_snapshot(&self, tag: Option<&str>, note: Option<&str>) -> Result<SnapshotSummary, anyhow::Error> {
+        let (layouts, policy) = {
+            let randomizer = self.randomizer.lock().unwrap();
+            (randomizer.export_layouts(), randomizer.policy().clone())
+        };
+        let state = TrackedState {
+            layouts,
+            tokens: self.keyring.list()?,
+            revocations: self.identity.revocations().iter().map(|proof| proof.value().clone()).collect(),
+            policy,
+        };
+        let summary = self.snapshots.take_snapshot(state, tag, note)?;
+        self.bus.publish(SecurityEvent::Snapshot(SnapshotEvent::Taken { snapshot_id: summary.snapshot_id.clone() }));
+        Ok(summary)
+    }
+
+    pub(crate) fn start_token_sweep(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TOKEN_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let responder = self.clone();
+                match tokio::task::spawn_blocking(move || responder.keyring.sweep()).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(revoked)) => tracing::debug!("Revoked the token keyrings of {} exited processes", revoked),
+                    Ok(Err(e)) => tracing::warn!("Token keyrings not swept: {}", e),
+                    Err(e) => tracing::error!("Token keyring sweep failed: {}", e),
+                }
+            }
+        })
+    }
+
+    pub(crate) fn quarantined(&self) -> Vec<Quarantined> {
+        self.quarantine.list()
+    }
+
+    fn cooldown(&self, action: ResponseAction) -> Duration {
+        let secs = self.section.cooldowns.get(action.as_str()).copied().unwrap_or(self.section.cooldown_secs);
+        Duration::from_secs(secs)
+    }
+
+    // Blocking: reads procfs, signals, and may run nft
+    pub fn execute(&self, request: ResponseRequest) -> ResponseRecord {
+        // Pinned before anything is read about the target: if the PID is
+        // reused after this, the checks below may describe the newcomer but
+        // a kill through the pidfd still only reaches the original, and
+        // fails once it has exited
+        let pidfd = request.pid.and_then(|pid| privsep::pidfd_open(pid).ok());
+        let exe = request.pid.and_then(|pid| privsep::read_exe(pid).ok()).map(|p| p.display().to_string());
+        let (outcome, detail) = self.decide_and_run(&request, exe.as_deref(), pidfd.as_ref());
+        let record = ResponseRecord {
+            timestamp: systemd::now_secs(),
+            action: request.action,
+            pid: request.pid,
+            exe,
+            origin: request.origin,
+            reason: request.reason,
+            outcome,
+            detail,
+        };
+        self.record(&record);
+        record
+    }
+
+    fn decide_and_run(&self, request: &ResponseRequest, exe: Option<&str>, pidfd: Option<&OwnedFd>) -> (ResponseOutcome, Option<String>) {
+        let action = request.action;
+        let pid = match (request.pid, action.needs_pid()) {
+            (Some(pid), true) => Some(pid),
+            (None, true) => return (ResponseOutcome::Refused, Some(format!("{} needs a PID", action.as_str()))),
+            (_, false) => None,
+        };
+        if let Some(pid) = pid {
+            // Releasing and thawing only undo what qksd did, even to a
+            // process that has since exited
+            let undoing = matches!(action, ResponseAction::Release | ResponseAction::Thaw);
+            let checked = if undoing { Ok(()) } else { self.check_target(pid, exe) };
+            if let Err(reason) = checked {
+                return (ResponseOutcome::Refused, Some(reason));
+            }
+        }
+        // PID 0 for actions without one: a rule taking a snapshot on every
+        // event would otherwise rotate the known-good ones out
+        if let Some(last) = self.last_executed.get(&(action, pid.unwrap_or_default())) {
+            let cooldown = self.cooldown(action);
+            if last.elapsed() < cooldown {
+                return (ResponseOutcome::CoolingDown, Some(format!("last {} {}s ago; cooldown is {}s", action.as_str(), last.elapsed().as_secs(), cooldown.as_secs())));
+            }
+        }
+        if self.section.dry_run || request.dry_run {
+            return (ResponseOutcome::DryRun, None);
+        }
+
+        match self.run(action, pid, pidfd) {
+            Ok(detail) => {
+                self.last_executed.insert((action, pid.unwrap_or_default()), Instant::now());
+                (ResponseOutcome::Executed, detail)
+            }
+            Err(e) => (ResponseOutcome::Failed, Some(e)),
+        }
+    }
+
+    // The safety checks; Err says why the target is off limits
+    fn check_target(&self, pid: u32, exe: Option<&str>) -> Result<(), String> {
+        if pid <= 1 || pid == std::process::id() {
+            return Err(format!("PID {} is init, a process group or qksd itself", pid));
+        }
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).map_err(|_| format!("PID {} does not exist", pid))?;
+        let ppid = status.lines().find_map(|l| l.strip_prefix("PPid:")).and_then(|v| v.trim().parse::<u32>().ok());
+        // Children of kthreadd, and kthreadd itself
+        if pid == 2 || ppid == Some(2) {
+            return Err(format!("PID {} is a kernel thread", pid));
+        }
+        if let Some(exe) = exe {
+            if self.section.protected_exes.iter().any(|p| Path::new(exe) == p) {
+                return Err(format!("{} is protected", exe));
+            }
+        }
+        Ok(())
+    }
+
+    // Ok carries an optional note for the audit record
+    fn run(&self, action: ResponseAction, pid: Option<u32>, pidfd: Option<&OwnedFd>) -> Result<Option<String>, String> {
+        let pid = pid.unwrap_or_default();
+        match action {
+            ResponseAction::Kill => {
+                let pidfd = pidfd.ok_or_else(|| format!("PID {} exited before it could be pinned", pid))?;
+                privsep::signal_pidfd(pidfd, Signal::Kill).map(|()| None).map_err(|e| e.to_string())
+            }
+            ResponseAction::Stop => {
+                let thaw_after = Duration::from_secs(self.section.freeze_secs);
+                freezer::global().freeze(pid, FREEZE_OWNER, Some(thaw_after)).map_err(|e| e.to_string())?;
+                Ok(Some(format!("thawed automatically in {}s", thaw_after.as_secs())))
+            }
+            ResponseAction::Thaw => freezer::global().thaw(pid, FREEZE_OWNER).map(|()| None).map_err(|e| e.to_string()),
+            ResponseAction::Rerandomize => {
+                let mut randomizer = self.randomizer.lock().unwrap();
+                let layout = randomizer.regenerate_layout_with(pid, LayoutTrigger::Regeneration);
+                randomizer.apply_layout_to_process(pid)?;
+                Ok(Some(format!("regeneration #{}", layout.regeneration_count)))
+            }
+            ResponseAction::RevokeToken => {
+                let token = self.keyring.load(pid)
+                    .map_err(|e| format!("keyring: {}", e))?
+                    .ok_or_else(|| format!("PID {} holds no token qksd knows of", pid))?;
+                self.identity.revoke_token(&token);
+                if let Err(e) = self.keyring.revoke(pid) {
+                    tracing::warn!(pid, "Revoked token of PID {} left in the keyring: {}", pid, e);
+                }
+                Ok(None)
+            }
+            ResponseAction::Snapshot => self.take_snapshot(Some(SNAPSHOT_TAG), None)
+                .map(|summary| Some(format!("took {}", summary.snapshot_id)))
+                .map_err(|e| e.to_string()),
+            ResponseAction::BlockEgress => block_egress(pid).map(Some),
+            ResponseAction::Quarantine => {
+                let quarantined = self.quarantine.isolate(pid).map_err(|e| e.to_string())?;
+                Ok(Some(format!("moved to {}; {} TCP sockets destroyed", quarantined.cgroup, quarantined.sockets_destroyed)))
+            }
+            ResponseAction::Release => {
+                let quarantined = self.quarantine.release(pid).map_err(|e| e.to_string())?;
+                Ok(Some(format!("returned to {}", quarantined.original_cgroup)))
+            }
+        }
+    }
+
+    fn record(&self, record: &ResponseRecord) {
+        let labels = [("action", record.action.as_str()), ("outcome", record.outcome.as_str())];
+        metrics::global().incr("qks_response_actions_total", &labels);
+        tracing::warn!(
+            pid = record.pid,
+            action = record.action.as_str(),
+            outcome = record.outcome.as_str(),
+            "Response {} on PID {:?} for {} ({}): {}{}",
+            record.action.as_str(),
+            record.pid,
+            record.origin,
+            record.reason,
+            record.outcome.as_str(),
+            record.detail.as_deref().map(|d| format!(" ({})", d)).unwrap_or_default()
+        );
+
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Response audit record not serializable: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.audit.append(record.timestamp, &line) {
+            tracing::error!("Response audit log not written: {}", e);
+        }
+
+        self.bus.publish(SecurityEvent::Response(ResponseEvent {
+            action: record.action.as_str().to_string(),
+            pid: record.pid,
+            outcome: record.outcome.as_str().to_string(),
+            succeeded: record.outcome == ResponseOutcome::Executed,
+            detail: record.detail.clone(),
+            timestamp: record.timestamp,
+        }));
+    }
+}
+
+fn cgroup_of(pid: &str) -> Result<String, String> {
+    let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).map_err(|e| format!("cgroup of {}: {}", pid, e))?;
+    cgroups
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(str::to_string)
+        .ok_or_else(|| "no cgroup v2 membership (unified hierarchy required)".to_string())
+}
+
+// nftables matches sockets by cgroup, not by process, so this blocks the
+// whole cgroup: everything in the same service or scope loses egress too
+fn block_egress(pid: u32) -> Result<String, String> {
+    let cgroup = cgroup_of(&pid.to_string())?;
+    if cgroup == "/" {
+        return Err("process is in the root cgroup; blocking it would cut off the host".to_string());
+    }
+    if cgroup == cgroup_of("self")? {
+        return Err(format!("process shares qksd's cgroup {}", cgroup));
+    }
+    // Delegated cgroups are named by unprivileged users; nothing that could
+    // break out of the quoted string goes into the nft script
+    if !cgroup.chars().all(|c| c.is_ascii_graphic() && c != '"' && c != '\\') {
+        return Err(format!("refusing unusual cgroup name {:?}", cgroup));
+    }
+    let path = cgroup.trim_start_matches('/');
+    let level = path.split('/').count();
+    let script = format!(
+        "add table inet {table}\n\
+         add chain inet {table} egress {{ type filter hook output priority 0 ; policy accept ; }}\n\
+         add rule inet {table} egress socket cgroupv2 level {level} \"{path}\" counter drop comment \"qks pid {pid}\"\n",
+        table = NFT_TABLE,
+        level = level,
+        path = path,
+        pid = pid,
+    );
+    run_nft(&script)?;
+    Ok(format!("egress dropped for cgroup {}", cgroup))
+}
+
+// Through qks-helper once the daemon has dropped root
+pub(crate) fn run_nft(script: &str) -> Result<(), String> {
+    if let Some(helper) = privsep::helper() {
+        return helper.call(&HelperRequest::Nft { script: script.to_string() }).map(drop).map_err(|e| format!("nft: {}", e));
+    }
+    let mut nft = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("nft: {}", e))?;
+    if let Some(mut stdin) = nft.stdin.take() {
+        stdin.write_all(script.as_bytes()).map_err(|e| format!("nft: {}", e))?;
+    }
+    let output = nft.wait_with_output().map_err(|e| format!("nft: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("nft: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
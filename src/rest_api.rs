@@ -0,0 +1,665 @@
+// src/rest_api.rs
+// HTTP/JSON version of the control surface, for teams that can't consume
+// gRPC. Requests carry a bearer token; the daemon only knows the tokens'
+// SHA-256 digests (one hex digest per line in [rest] tokens_file, followed
+// by the token's comma-separated scopes), so the file holds no secrets.
+// Each route needs one scope; a line without scopes grants only `read`.
+// Snapshot and event listings are paginated. The OpenAPI description at
+// /openapi.json is generated from the handlers.
+use crate::anomaly_events::{AnomalyEvent, ProcessAncestor};
+use crate::config::RestSection;
+use crate::constant_time;
+use crate::control::{ControlRequest, ControlResponse};
+use crate::crypto_identifiers::{Capability, ProcessToken, RevocationProof, TokenCheck, TokenCheckFailure, TokenStatus};
+use crate::daemon::{Control, SubsystemHealth};
+use crate::diagnostics::CheckStatus;
+use crate::events::{EventKind, SecurityEvent};
+use crate::metrics;
+use crate::ml_detector::{AnomalyExplanation, FeatureContribution, ScoringMode};
+use crate::recovery_snapshot::{SnapshotDiff, SnapshotSummary};
+use axum::extract::{MatchedPath, Path, Query, Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+// Anomalies kept for GET /v1/events
+const EVENT_LOG_CAPACITY: usize = 10_000;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestError {
+    #[error("[rest] listen is not set")]
+    NotConfigured,
+    #[error("[rest] tokens_file is not set")]
+    NoTokens,
+    #[error("{}: {source}", .path.display())]
+    Tokens { path: PathBuf, source: std::io::Error },
+    #[error("{}: line {line} is not a SHA-256 hex digest", .path.display())]
+    TokenFormat { path: PathBuf, line: usize },
+    #[error("{}: line {line}: unknown scope {scope:?}", .path.display())]
+    TokenScope { path: PathBuf, line: usize, scope: String },
+    #[error("REST listener: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone)]
+struct ApiState {
+    control: Arc<Control>,
+    // SHA-256 of each accepted bearer token, with what it may do
+    tokens: Arc<Vec<TokenEntry>>,
+    events: Arc<Mutex<EventLog>>,
+}
+
+// What a bearer token may do, following the control socket's access
+// levels: `admin` implies every other scope, and is the only one that acts
+// on processes (restores, layout changes, token issue and revocation).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    // Health, posture, listings, plans and scores
+    Read,
+    // Take snapshots
+    Snapshot,
+    // Snapshots, probe groups, model reloads
+    Operate,
+    Admin,
+}
+
+impl Scope {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "snapshot" => Some(Scope::Snapshot),
+            "operate" => Some(Scope::Operate),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Snapshot => "snapshot",
+            Scope::Operate => "operate",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+struct TokenEntry {
+    digest: [u8; 32],
+    scopes: Vec<Scope>,
+}
+
+impl TokenEntry {
+    fn allows(&self, needed: Scope) -> bool {
+        self.scopes.iter().any(|&scope| scope == needed || scope == Scope::Admin || (scope == Scope::Operate && needed == Scope::Snapshot))
+    }
+}
+
+// The scope each protected route needs, as ControlRequest::required_level
+// ranks the same operation; anything unlisted is admin-only
+fn required_scope(method: &Method, path: &str) -> Scope {
+    match (method.as_str(), path) {
+        ("GET", _) => Scope::Read,
+        ("POST", "/v1/tokens/verify") => Scope::Read,
+        ("POST", "/v1/snapshots") => Scope::Snapshot,
+        ("PUT", "/v1/monitor/probe-groups/:group") | ("POST", "/v1/detector/reload") => Scope::Operate,
+        _ => Scope::Admin,
+    }
+}
+
+#[derive(Default)]
+struct EventLog {
+    next_seq: u64,
+    events: VecDeque<SequencedEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SequencedEvent {
+    // Increases by one per anomaly since the daemon started
+    pub seq: u64,
+    pub event: AnomalyEvent,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EventPage {
+    pub items: Vec<SequencedEvent>,
+    // Pass as `after` for the next page, or to poll for newer events
+    pub next_after: u64,
+    pub more: bool,
+    // Events older than this have been dropped from the log
+    pub oldest: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SnapshotPage {
+    pub items: Vec<SnapshotSummary>,
+    pub total: usize,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct PageQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct EventQuery {
+    // Only events with a larger sequence number
+    pub after: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DiffQuery {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct StatsQuery {
+    pub top: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RestoreBody {
+    // Draw new layouts instead of re-applying the recorded ones
+    pub fresh: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct IssueTokenBody {
+    pub pid: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ProbeGroupBody {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ReloadBody {
+    // Defaults to the configured model
+    pub path: Option<String>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "qksd REST API", description = "Control and telemetry for the quantum kernel security daemon"),
+    paths(
+        health, diagnostics, posture, list_snapshots, take_snapshot, diff_snapshots, verify_snapshot, restore_snapshot,
+        issue_token, verify_token, revoke_token, list_events, monitor_stats, set_probe_group,
+        plan_layout, apply_layout, score, reload_model,
+    ),
+    components(schemas(
+        SubsystemHealth, SnapshotSummary, SnapshotPage, SnapshotDiff, ProcessToken, Capability, TokenStatus,
+        TokenCheckFailure, TokenCheck, RevocationProof, AnomalyEvent, ProcessAncestor, AnomalyExplanation,
+        FeatureContribution, ScoringMode, SequencedEvent, EventPage, ErrorBody, RestoreBody, IssueTokenBody,
+        ProbeGroupBody, ReloadBody,
+    )),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+)]
+struct ApiDoc;
+
+struct BearerAuth;
+
+impl utoipa::Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+// One hex SHA-256 per line, optionally followed by whitespace and
+// comma-separated scopes; blank lines and '#' comments are skipped
+fn load_token_digests(path: &std::path::Path) -> Result<Vec<TokenEntry>, RestError> {
+    let text = std::fs::read_to_string(path).map_err(|source| RestError::Tokens { path: path.to_path_buf(), source })?;
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || RestError::TokenFormat { path: path.to_path_buf(), line: i + 1 };
+        let (hex_digest, scope_list) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if hex_digest.len() != 64 || !hex_digest.is_ascii() {
+            return Err(bad());
+        }
+        let mut digest = [0u8; 32];
+        for (byte, hex) in digest.iter_mut().zip(hex_digest.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(hex).map_err(|_| bad())?, 16).map_err(|_| bad())?;
+        }
+
+        let mut scopes = Vec::new();
+        for name in scope_list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let scope = Scope::parse(name).ok_or_else(|| RestError::TokenScope {
+                path: path.to_path_buf(),
+                line: i + 1,
+                scope: name.to_string(),
+            })?;
+            scopes.push(scope);
+        }
+        if scopes.is_empty() {
+            scopes.push(Scope::Read);
+        }
+        entries.push(TokenEntry { digest, scopes });
+    }
+    Ok(entries)
+}
+
+// Bind now so a taken port fails daemon startup rather than a later task
+pub(crate) async fn serve(config: &RestSection, control: Arc<Control>) -> Result<tokio::task::JoinHandle<()>, RestError> {
+    let listen = config.listen.ok_or(RestError::NotConfigured)?;
+    let tokens = load_token_digests(config.tokens_file.as_deref().ok_or(RestError::NoTokens)?)?;
+    if tokens.is_empty() {
+        tracing::warn!("REST API has no tokens configured; every request will be refused");
+    }
+
+    let state = ApiState { control: control.clone(), tokens: Arc::new(tokens), events: Arc::new(Mutex::new(EventLog::default())) };
+    let record = record_events(control.events.subscribe(EventKind::Anomaly), state.events.clone());
+    let app = router(state);
+
+    let listener = std::net::TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key).await?),
+        _ => None,
+    };
+
+    Ok(tokio::spawn(async move {
+        let served = match tls {
+            Some(tls) => {
+                let server = axum_server::from_tcp_rustls(listener, tls).serve(app.into_make_service());
+                tokio::select! {
+                    result = server => result,
+                    _ = record => Ok(()),
+                }
+            }
+            None => {
+                let server = async move { axum::serve(tokio::net::TcpListener::from_std(listener)?, app).await };
+                tokio::select! {
+                    result = server => result,
+                    _ = record => Ok(()),
+                }
+            }
+        };
+        if let Err(e) = served {
+            tracing::error!("REST API on {} stopped: {}", listen, e);
+        }
+    }))
+}
+
+async fn record_events(mut anomalies: broadcast::Receiver<SecurityEvent>, log: Arc<Mutex<EventLog>>) {
+    loop {
+        match anomalies.recv().await {
+            Ok(SecurityEvent::Anomaly(event)) => {
+                let mut log = log.lock().unwrap();
+                log.next_seq += 1;
+                let seq = log.next_seq;
+                if log.events.len() >= EVENT_LOG_CAPACITY {
+                    log.events.pop_front();
+                }
+                log.events.push_back(SequencedEvent { seq, event });
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                // Keep sequence numbers honest about the gap
+                log.lock().unwrap().next_seq += missed;
+                tracing::warn!("REST event log fell behind; {} anomalies not recorded", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/v1/health", get(health))
+        .route("/v1/diagnostics", get(diagnostics))
+        .route("/v1/posture", get(posture))
+        .route("/v1/snapshots", get(list_snapshots).post(take_snapshot))
+        .route("/v1/snapshots/diff", get(diff_snapshots))
+        .route("/v1/snapshots/:id/verify", get(verify_snapshot))
+        .route("/v1/snapshots/:id/restore", post(restore_snapshot))
+        .route("/v1/tokens", post(issue_token))
+        .route("/v1/tokens/verify", post(verify_token))
+        .route("/v1/tokens/revoke", post(revoke_token))
+        .route("/v1/events", get(list_events))
+        .route("/v1/monitor/stats", get(monitor_stats))
+        .route("/v1/monitor/probe-groups/:group", put(set_probe_group))
+        .route("/v1/randomizer/:pid/plan", get(plan_layout))
+        .route("/v1/randomizer/:pid/apply", post(apply_layout))
+        .route("/v1/detector/:pid/score", get(score))
+        .route("/v1/detector/reload", post(reload_model))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        // The description itself is public
+        .route("/openapi.json", get(|| async { Json(openapi()) }))
+        .with_state(state)
+}
+
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let presented = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = presented else {
+        return error(StatusCode::UNAUTHORIZED, "missing or unknown bearer token");
+    };
+
+    // Compare against every entry so timing doesn't reveal which matched
+    let key = constant_time::lookup_key(token.as_bytes());
+    let mut matched = None;
+    for entry in state.tokens.iter() {
+        if constant_time::ct_eq(&entry.digest, &key) {
+            matched = Some(entry);
+        }
+    }
+    let Some(entry) = matched else {
+        return error(StatusCode::UNAUTHORIZED, "missing or unknown bearer token");
+    };
+
+    let path = request.extensions().get::<MatchedPath>().map(|p| p.as_str()).unwrap_or("");
+    let needed = required_scope(request.method(), path);
+    if !entry.allows(needed) {
+        metrics::global().incr("qks_rest_requests_forbidden_total", &[("scope", needed.as_str())]);
+        return error(StatusCode::FORBIDDEN, format!("token lacks the {} scope", needed.as_str()));
+    }
+    next.run(request).await
+}
+
+fn error(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    (status, Json(ErrorBody { error: message.to_string() })).into_response()
+}
+
+// Same handler as the control socket; its errors are the caller's to fix
+async fn call(state: ApiState, request: ControlRequest) -> Response {
+    let control = state.control.clone();
+    match tokio::task::spawn_blocking(move || control.handle(request)).await {
+        Ok(ControlResponse::Ok(value)) => Json(value).into_response(),
+        Ok(ControlResponse::Error(message)) => error(StatusCode::BAD_REQUEST, message),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("request task failed: {}", e)),
+    }
+}
+
+#[utoipa::path(get, path = "/v1/health", responses(
+    (status = 200, description = "Health per subsystem", body = BTreeMap<String, SubsystemHealth>),
+))]
+async fn health(State(state): State<ApiState>) -> Response {
+    call(state, ControlRequest::Health).await
+}
+
+// 503 when any check failed, so a probe needs only the status code; the
+// body's `reasons` say which
+#[utoipa::path(get, path = "/v1/diagnostics", responses(
+    (status = 200, description = "Every check ok or degraded", body = serde_json::Value),
+    (status = 503, description = "At least one check failed", body = serde_json::Value),
+    (status = 500, body = ErrorBody),
+))]
+async fn diagnostics(State(state): State<ApiState>) -> Response {
+    let control = state.control.clone();
+    match tokio::task::spawn_blocking(move || control.diagnostics()).await {
+        Ok(report) if report.status == CheckStatus::Failed => (StatusCode::SERVICE_UNAVAILABLE, Json(report)).into_response(),
+        Ok(report) => Json(report).into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("request task failed: {}", e)),
+    }
+}
+
+#[utoipa::path(get, path = "/v1/posture", responses(
+    (status = 200, description = "Secure Boot, lockdown and module signing as of the last check", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn posture(State(state): State<ApiState>) -> Response {
+    call(state, ControlRequest::Posture).await
+}
+
+#[utoipa::path(get, path = "/v1/snapshots", params(PageQuery), responses(
+    (status = 200, description = "Snapshots, oldest first", body = SnapshotPage),
+    (status = 500, body = ErrorBody),
+))]
+async fn list_snapshots(State(state): State<ApiState>, Query(page): Query<PageQuery>) -> Response {
+    let control = state.control.clone();
+    let snapshots = match tokio::task::spawn_blocking(move || control.snapshots.list_snapshots()).await {
+        Ok(Ok(snapshots)) => snapshots,
+        Ok(Err(e)) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("request task failed: {}", e)),
+    };
+
+    let offset = page.offset.unwrap_or(0);
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let total = snapshots.len();
+    let items: Vec<_> = snapshots.into_iter().skip(offset).take(limit).collect();
+    let next_offset = (offset + items.len() < total).then_some(offset + items.len());
+    Json(SnapshotPage { items, total, next_offset }).into_response()
+}
+
+#[utoipa::path(post, path = "/v1/snapshots", responses(
+    (status = 200, body = SnapshotSummary),
+    (status = 400, body = ErrorBody),
+))]
+async fn take_snapshot(State(state): State<ApiState>) -> Response {
+    call(state, ControlRequest::SnapshotTake { tag: None, note: None }).await
+}
+
+#[utoipa::path(get, path = "/v1/snapshots/diff", params(DiffQuery), responses(
+    (status = 200, body = SnapshotDiff),
+    (status = 400, body = ErrorBody),
+))]
+async fn diff_snapshots(State(state): State<ApiState>, Query(diff): Query<DiffQuery>) -> Response {
+    call(state, ControlRequest::SnapshotDiff { from: diff.from, to: diff.to }).await
+}
+
+#[utoipa::path(get, path = "/v1/snapshots/{id}/verify", params(("id" = String, Path)), responses(
+    (status = 200, description = "Whether the stored checksum matches", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn verify_snapshot(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    call(state, ControlRequest::SnapshotVerify { id }).await
+}
+
+#[utoipa::path(post, path = "/v1/snapshots/{id}/restore", params(("id" = String, Path)), request_body = RestoreBody, responses(
+    (status = 200, description = "PIDs whose layouts were restored", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn restore_snapshot(State(state): State<ApiState>, Path(id): Path<String>, body: Option<Json<RestoreBody>>) -> Response {
+    let Json(body) = body.unwrap_or_default();
+    call(state, ControlRequest::SnapshotRestore { id, fresh: body.fresh }).await
+}
+
+#[utoipa::path(post, path = "/v1/tokens", request_body = IssueTokenBody, responses(
+    (status = 200, body = ProcessToken),
+    (status = 400, body = ErrorBody),
+))]
+async fn issue_token(State(state): State<ApiState>, Json(body): Json<IssueTokenBody>) -> Response {
+    call(state, ControlRequest::TokenIssue { pid: body.pid, capabilities: body.capabilities }).await
+}
+
+#[utoipa::path(post, path = "/v1/tokens/verify", request_body = ProcessToken, responses(
+    (status = 200, body = TokenStatus),
+))]
+async fn verify_token(State(state): State<ApiState>, Json(token): Json<ProcessToken>) -> Response {
+    call(state, ControlRequest::TokenVerify { token }).await
+}
+
+#[utoipa::path(post, path = "/v1/tokens/revoke", request_body = ProcessToken, responses(
+    (status = 200, body = RevocationProof),
+))]
+async fn revoke_token(State(state): State<ApiState>, Json(token): Json<ProcessToken>) -> Response {
+    call(state, ControlRequest::TokenRevoke { token }).await
+}
+
+#[utoipa::path(get, path = "/v1/events", params(EventQuery), responses(
+    (status = 200, description = "Anomalies, oldest first", body = EventPage),
+))]
+async fn list_events(State(state): State<ApiState>, Query(query): Query<EventQuery>) -> Response {
+    let after = query.after.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let log = state.events.lock().unwrap();
+    let mut items: Vec<_> = log.events.iter().filter(|e| e.seq > after).take(limit + 1).cloned().collect();
+    let more = items.len() > limit;
+    items.truncate(limit);
+    let page = EventPage {
+        next_after: items.last().map_or(after, |e| e.seq),
+        more,
+        oldest: log.events.front().map(|e| e.seq),
+        items,
+    };
+    Json(page).into_response()
+}
+
+#[utoipa::path(get, path = "/v1/monitor/stats", params(StatsQuery), responses(
+    (status = 200, description = "Busiest syscalls and probe group states", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn monitor_stats(State(state): State<ApiState>, Query(query): Query<StatsQuery>) -> Response {
+    call(state, ControlRequest::MonitorStats { top: query.top.unwrap_or(20) }).await
+}
+
+#[utoipa::path(put, path = "/v1/monitor/probe-groups/{group}", params(("group" = String, Path)), request_body = ProbeGroupBody, responses(
+    (status = 200, body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn set_probe_group(State(state): State<ApiState>, Path(group): Path<String>, Json(body): Json<ProbeGroupBody>) -> Response {
+    call(state, ControlRequest::ProbeGroup { group, enabled: body.enabled }).await
+}
+
+#[utoipa::path(get, path = "/v1/randomizer/{pid}/plan", params(("pid" = u32, Path)), responses(
+    (status = 200, description = "What applying a new layout would do", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn plan_layout(State(state): State<ApiState>, Path(pid): Path<u32>) -> Response {
+    call(state, ControlRequest::RandomizerPlan { pid }).await
+}
+
+#[utoipa::path(post, path = "/v1/randomizer/{pid}/apply", params(("pid" = u32, Path)), responses(
+    (status = 200, body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn apply_layout(State(state): State<ApiState>, Path(pid): Path<u32>) -> Response {
+    call(state, ControlRequest::RandomizerApply { pid }).await
+}
+
+#[utoipa::path(get, path = "/v1/detector/{pid}/score", params(("pid" = u32, Path)), responses(
+    (status = 200, description = "Latest score and its top contributing features", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn score(State(state): State<ApiState>, Path(pid): Path<u32>) -> Response {
+    call(state, ControlRequest::DetectorScore { pid }).await
+}
+
+#[utoipa::path(post, path = "/v1/detector/reload", request_body = ReloadBody, responses(
+    (status = 200, description = "The model version now loaded", body = serde_json::Value),
+    (status = 400, body = ErrorBody),
+))]
+async fn reload_model(State(state): State<ApiState>, body: Option<Json<ReloadBody>>) -> Response {
+    let Json(body) = body.unwrap_or_default();
+    call(state, ControlRequest::DetectorReload { path: body.path }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::AccessLevel;
+
+    fn token() -> ProcessToken {
+        ProcessToken { pid: 1000, parent_token: None, signature: vec![1; 64], timestamp: 0, capabilities: Vec::new(), nonce: [0; 16] }
+    }
+
+    fn level(scope: Scope) -> AccessLevel {
+        match scope {
+            Scope::Read => AccessLevel::Read,
+            Scope::Snapshot | Scope::Operate => AccessLevel::Operate,
+            Scope::Admin => AccessLevel::Admin,
+        }
+    }
+
+    // Every protected route, with the control request for the same operation
+    fn routes() -> Vec<(Method, &'static str, ControlRequest)> {
+        let id = || "snapshot".to_string();
+        vec![
+            (Method::GET, "/v1/health", ControlRequest::Health),
+            (Method::GET, "/v1/diagnostics", ControlRequest::Diagnostics),
+            (Method::GET, "/v1/posture", ControlRequest::Posture),
+            (Method::GET, "/v1/snapshots", ControlRequest::SnapshotList),
+            (Method::POST, "/v1/snapshots", ControlRequest::SnapshotTake { tag: None, note: None }),
+            (Method::GET, "/v1/snapshots/diff", ControlRequest::SnapshotDiff { from: id(), to: id() }),
+            (Method::GET, "/v1/snapshots/:id/verify", ControlRequest::SnapshotVerify { id: id() }),
+            (Method::POST, "/v1/snapshots/:id/restore", ControlRequest::SnapshotRestore { id: id(), fresh: false }),
+            (Method::POST, "/v1/tokens", ControlRequest::TokenIssue { pid: 1000, capabilities: Vec::new() }),
+            (Method::POST, "/v1/tokens/verify", ControlRequest::TokenVerify { token: token() }),
+            (Method::POST, "/v1/tokens/revoke", ControlRequest::TokenRevoke { token: token() }),
+            (Method::GET, "/v1/events", ControlRequest::RecentEvents { after: 0, limit: 10 }),
+            (Method::GET, "/v1/monitor/stats", ControlRequest::MonitorStats { top: 20 }),
+            (Method::PUT, "/v1/monitor/probe-groups/:group", ControlRequest::ProbeGroup { group: "exec".to_string(), enabled: true }),
+            (Method::GET, "/v1/randomizer/:pid/plan", ControlRequest::RandomizerPlan { pid: 1000 }),
+            (Method::POST, "/v1/randomizer/:pid/apply", ControlRequest::RandomizerApply { pid: 1000 }),
+            (Method::GET, "/v1/detector/:pid/score", ControlRequest::DetectorScore { pid: 1000 }),
+            (Method::POST, "/v1/detector/reload", ControlRequest::DetectorReload { path: None }),
+        ]
+    }
+
+    #[test]
+    fn scopes_match_the_control_socket() {
+        for (method, path, request) in routes() {
+            assert_eq!(level(required_scope(&method, path)), request.required_level(), "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn processes_are_admin_only() {
+        for (method, path) in [
+            (Method::POST, "/v1/snapshots/:id/restore"),
+            (Method::POST, "/v1/tokens"),
+            (Method::POST, "/v1/tokens/revoke"),
+            (Method::POST, "/v1/randomizer/:pid/apply"),
+        ] {
+            assert_eq!(required_scope(&method, path), Scope::Admin, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn unknown_routes_are_admin_only() {
+        assert_eq!(required_scope(&Method::POST, "/v1/unknown"), Scope::Admin);
+        assert_eq!(required_scope(&Method::DELETE, "/v1/snapshots"), Scope::Admin);
+    }
+
+    #[test]
+    fn scopes_imply_narrower_ones() {
+        let entry = |scopes: Vec<Scope>| TokenEntry { digest: [0; 32], scopes };
+        assert!(entry(vec![Scope::Operate]).allows(Scope::Snapshot));
+        assert!(!entry(vec![Scope::Operate]).allows(Scope::Admin));
+        assert!(!entry(vec![Scope::Snapshot]).allows(Scope::Operate));
+        assert!(entry(vec![Scope::Admin]).allows(Scope::Operate));
+        assert!(!entry(vec![Scope::Read]).allows(Scope::Snapshot));
+    }
+
+    #[test]
+    fn token_scope_is_gone() {
+        assert_eq!(Scope::parse("token"), None);
+        assert_eq!(Scope::parse("operate"), Some(Scope::Operate));
+    }
+}
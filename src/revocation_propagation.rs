@@ -0,0 +1,504 @@
+// src/revocation_propagation.rs
+use crate::constant_time;
+use crate::crypto_identifiers::{CryptoIdentifier, RevocationProof};
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+
+// Proofs older than this are dropped even if never seen (replay window)
+const DEFAULT_MAX_MESSAGE_AGE_SECS: u64 = 3600;
+// Number of peers a received revocation is re-gossiped to
+const DEFAULT_GOSSIP_FANOUT: usize = 3;
+
+pub struct RevocationPropagator {
+    host_id: String,
+    identity: Arc<CryptoIdentifier>,
+    peers: Arc<RwLock<HashMap<String, PeerHost>>>,
+    // Hosts whose revocations apply here besides our own; being a peer
+    // is not enough, or any one host could revoke every token in the fleet
+    revokers: HashSet<String>,
+    mode: PropagationMode,
+    next_sequence: Arc<RwLock<u64>>,
+    seen: Arc<DashMap<String, SeenWindow>>,
+    pending_acks: Arc<DashMap<(String, u64), PendingDelivery>>,
+    // The identity's revocation list, which introspection consults
+    revoked: Arc<DashMap<[u8; 32], RevocationProof>>,
+    outbound: mpsc::UnboundedSender<OutboundMessage>,
+    max_message_age_secs: u64,
+    gossip_fanout: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropagationMode {
+    // Every host forwards to a random subset of its peers
+    Gossip,
+    // Agents only report to the controller, which pushes to everyone
+    ControllerPush { is_controller: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerHost {
+    pub host_id: String,
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum PropagationMessage {
+    Revocation(RevocationMessage),
+    Ack(RevocationAck),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RevocationMessage {
+    pub origin_host: String,
+    pub sequence: u64,
+    pub sent_at: u64,
+    pub proof: RevocationProof,
+    // Origin's signature over host, sequence, timestamp and proof
+    pub envelope_signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RevocationAck {
+    pub from_host: String,
+    pub origin_host: String,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboundMessage {
+    pub peer: String,
+    pub message: PropagationMessage,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingDelivery {
+    pub message: RevocationMessage,
+    pub awaiting: HashSet<String>,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Default)]
+struct SeenWindow {
+    // sequence -> sent_at
+    sequences: HashMap<u64, u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PropagationError {
+    #[error("unknown origin host {0}")]
+    UnknownOrigin(String),
+    #[error("envelope signature from {0} is invalid")]
+    BadEnvelope(String),
+    #[error("revocation proof from {0} is invalid")]
+    BadProof(String),
+    #[error("replayed message {1} from {0}")]
+    Replay(String, u64),
+    #[error("message {1} from {0} is outside the replay window")]
+    Expired(String, u64),
+    #[error("message {1} from {0} is dated in the future")]
+    Future(String, u64),
+    #[error("{0} is not a fleet revoker")]
+    NotRevoker(String),
+}
+
+impl RevocationPropagator {
+    pub fn new(
+        host_id: &str,
+        identity: Arc<CryptoIdentifier>,
+        mode: PropagationMode,
+    ) -> (Self, mpsc::UnboundedReceiver<OutboundMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Wall-clock nanoseconds, so a restarted host does not reuse
+        // sequence numbers its peers still remember
+        let first_sequence = identity.now().saturating_mul(1_000_000_000);
+
+        let propagator = Self {
+            host_id: host_id.to_string(),
+            revoked: identity.revocations(),
+            identity,
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            revokers: HashSet::new(),
+            mode,
+            next_sequence: Arc::new(RwLock::new(first_sequence)),
+            seen: Arc::new(DashMap::new()),
+            pending_acks: Arc::new(DashMap::new()),
+            outbound: tx,
+            max_message_age_secs: DEFAULT_MAX_MESSAGE_AGE_SECS,
+            gossip_fanout: DEFAULT_GOSSIP_FANOUT,
+        };
+
+        (propagator, rx)
+    }
+
+    pub fn with_revokers(mut self, host_ids: impl IntoIterator<Item = String>) -> Self {
+        self.revokers = host_ids.into_iter().collect();
+        self
+    }
+
+    pub fn add_peer(&self, host_id: &str, public_key: Vec<u8>) {
+        self.peers.write().unwrap().insert(host_id.to_string(), PeerHost {
+            host_id: host_id.to_string(),
+            public_key,
+        });
+    }
+
+    pub fn remove_peer(&self, host_id: &str) {
+        self.peers.write().unwrap().remove(host_id);
+
+        for mut pending in self.pending_acks.iter_mut() {
+            pending.awaiting.remove(host_id);
+        }
+        self.pending_acks.retain(|_, p| !p.awaiting.is_empty());
+    }
+
+    pub fn is_revoked(&self, token_signature: &[u8]) -> bool {
+        match self.revoked.get(&constant_time::lookup_key(token_signature)) {
+            Some(proof) => constant_time::ct_eq(&proof.token_signature, token_signature),
+            None => false,
+        }
+    }
+
+    // Publish a revocation issued on this host
+    pub fn broadcast(&self, proof: RevocationProof) -> RevocationMessage {
+        let sequence = {
+            let mut next = self.next_sequence.write().unwrap();
+            *next += 1;
+            *next
+        };
+
+        let sent_at = self.identity.now();
+        let envelope = Self::envelope_bytes(&self.host_id, sequence, sent_at, &proof);
+
+        let message = RevocationMessage {
+            origin_host: self.host_id.clone(),
+            sequence,
+            sent_at,
+            proof: proof.clone(),
+            envelope_signature: self.identity.sign(&envelope),
+        };
+
+        self.revoked.insert(constant_time::lookup_key(&proof.token_signature), proof);
+        self.seen.entry(self.host_id.clone()).or_default().sequences.insert(sequence, sent_at);
+
+        // Locally issued revocations go to every peer; for an agent in
+        // controller-push mode that is just the controller
+        self.send_to(&self.peer_ids(), &message);
+        message
+    }
+
+    // Handle a revocation received from `from_host`
+    pub fn receive(
+        &self,
+        from_host: &str,
+        message: RevocationMessage,
+    ) -> Result<(), PropagationError> {
+        // Our own revocation gossiped back to us
+        if message.origin_host == self.host_id {
+            self.send_ack(from_host, &message.origin_host, message.sequence);
+            return Ok(());
+        }
+
+        self.verify_message(&message)?;
+
+        let origin = message.origin_host.clone();
+        let sequence = message.sequence;
+
+        {
+            let mut window = self.seen.entry(origin.clone()).or_default();
+            if window.sequences.insert(sequence, message.sent_at).is_some() {
+                // Still ack so the sender stops retransmitting
+                self.send_ack(from_host, &origin, sequence);
+                return Err(PropagationError::Replay(origin, sequence));
+            }
+        }
+
+        tracing::info!(
+            "Applied revocation {} from {} (via {})",
+            sequence, origin, from_host
+        );
+        self.revoked.insert(
+            constant_time::lookup_key(&message.proof.token_signature),
+            message.proof.clone(),
+        );
+        self.send_ack(from_host, &origin, sequence);
+
+        let targets = match self.mode {
+            PropagationMode::Gossip => self.gossip_targets(&[from_host, &origin]),
+            PropagationMode::ControllerPush { is_controller: true } => {
+                self.peer_ids().into_iter()
+                    .filter(|p| p != from_host && *p != origin)
+                    .collect()
+            }
+            PropagationMode::ControllerPush { is_controller: false } => Vec::new(),
+        };
+
+        self.send_to(&targets, &message);
+        Ok(())
+    }
+
+    // A revocation relayed by a controller that vouches for the origin's
+    // key (fleet.rs), so the origin need not be a peer. The relay journals
+    // and replays what it sends, which stands in for the age window.
+    // Returns whether the revocation was new here.
+    pub fn apply_relayed(&self, message: &RevocationMessage, origin_key: &[u8]) -> Result<bool, PropagationError> {
+        let key = constant_time::lookup_key(&message.proof.token_signature);
+        if self.revoked.contains_key(&key) {
+            return Ok(false);
+        }
+        self.check_origin(message)?;
+        Self::verify_signed(message, origin_key)?;
+        tracing::info!("Applied revocation {} from {} (relayed)", message.sequence, message.origin_host);
+        self.revoked.insert(key, message.proof.clone());
+        Ok(true)
+    }
+
+    pub fn revoked_count(&self) -> usize {
+        self.revoked.len()
+    }
+
+    pub fn handle_ack(&self, ack: &RevocationAck) {
+        let key = (ack.origin_host.clone(), ack.sequence);
+
+        let delivered = match self.pending_acks.get_mut(&key) {
+            Some(mut pending) => {
+                pending.awaiting.remove(&ack.from_host);
+                pending.awaiting.is_empty()
+            }
+            None => false,
+        };
+
+        if delivered {
+            self.pending_acks.remove(&key);
+        }
+    }
+
+    pub fn pending_deliveries(&self) -> Vec<PendingDelivery> {
+        self.pending_acks.iter().map(|p| p.value().clone()).collect()
+    }
+
+    // Re-send everything still missing acknowledgements
+    pub fn retransmit_pending(&self) {
+        for mut pending in self.pending_acks.iter_mut() {
+            pending.attempts += 1;
+
+            for peer in &pending.awaiting {
+                let _ = self.outbound.send(OutboundMessage {
+                    peer: peer.clone(),
+                    message: PropagationMessage::Revocation(pending.message.clone()),
+                });
+            }
+        }
+    }
+
+    pub fn start_retransmit(self: Arc<Self>, interval_ms: u64) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_millis(interval_ms)).await;
+                self.retransmit_pending();
+                self.prune_seen();
+            }
+        })
+    }
+
+    fn verify_message(&self, message: &RevocationMessage) -> Result<(), PropagationError> {
+        let origin = &message.origin_host;
+
+        let public_key = self.peers.read().unwrap()
+            .get(origin)
+            .map(|p| p.public_key.clone())
+            .ok_or_else(|| PropagationError::UnknownOrigin(origin.clone()))?;
+
+        self.check_origin(message)?;
+        if self.identity.now().saturating_sub(message.sent_at) > self.max_message_age_secs {
+            return Err(PropagationError::Expired(origin.clone(), message.sequence));
+        }
+
+        Self::verify_signed(message, &public_key)
+    }
+
+    // A proof names only the token's signature, not who issued it, so the
+    // origin has to be trusted to revoke fleet-wide
+    fn check_origin(&self, message: &RevocationMessage) -> Result<(), PropagationError> {
+        let origin = &message.origin_host;
+        if *origin != self.host_id && !self.revokers.contains(origin) {
+            return Err(PropagationError::NotRevoker(origin.clone()));
+        }
+        if message.sent_at > self.identity.now().saturating_add(self.identity.skew_tolerance()) {
+            return Err(PropagationError::Future(origin.clone(), message.sequence));
+        }
+        Ok(())
+    }
+
+    fn verify_signed(message: &RevocationMessage, public_key: &[u8]) -> Result<(), PropagationError> {
+        let origin = &message.origin_host;
+        let envelope = Self::envelope_bytes(origin, message.sequence, message.sent_at, &message.proof);
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+            .verify(&envelope, &message.envelope_signature)
+            .map_err(|_| PropagationError::BadEnvelope(origin.clone()))?;
+
+        CryptoIdentifier::verify_revocation(&message.proof, public_key)
+            .map_err(|_| PropagationError::BadProof(origin.clone()))
+    }
+
+    fn envelope_bytes(origin: &str, sequence: u64, sent_at: u64, proof: &RevocationProof) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(origin.as_bytes());
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(&sent_at.to_be_bytes());
+        data.extend_from_slice(&proof.token_signature);
+        data.extend_from_slice(&proof.revoked_at.to_be_bytes());
+        data.extend_from_slice(&proof.proof);
+        data
+    }
+
+    fn send_to(&self, targets: &[String], message: &RevocationMessage) {
+        if targets.is_empty() {
+            return;
+        }
+
+        self.pending_acks.insert(
+            (message.origin_host.clone(), message.sequence),
+            PendingDelivery {
+                message: message.clone(),
+                awaiting: targets.iter().cloned().collect(),
+                attempts: 1,
+            },
+        );
+
+        for peer in targets {
+            let _ = self.outbound.send(OutboundMessage {
+                peer: peer.clone(),
+                message: PropagationMessage::Revocation(message.clone()),
+            });
+        }
+    }
+
+    fn send_ack(&self, to_host: &str, origin: &str, sequence: u64) {
+        let _ = self.outbound.send(OutboundMessage {
+            peer: to_host.to_string(),
+            message: PropagationMessage::Ack(RevocationAck {
+                from_host: self.host_id.clone(),
+                origin_host: origin.to_string(),
+                sequence,
+            }),
+        });
+    }
+
+    fn gossip_targets(&self, exclude: &[&str]) -> Vec<String> {
+        use rand::seq::SliceRandom;
+
+        let mut candidates: Vec<String> = self.peer_ids().into_iter()
+            .filter(|p| !exclude.contains(&p.as_str()))
+            .collect();
+
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(self.gossip_fanout);
+        candidates
+    }
+
+    fn peer_ids(&self) -> Vec<String> {
+        self.peers.read().unwrap().keys().cloned().collect()
+    }
+
+    fn prune_seen(&self) {
+        // Anything older than the age window is rejected before the seen
+        // check, so its sequence number no longer needs to be remembered
+        let cutoff = self.identity.now().saturating_sub(self.max_message_age_secs);
+
+        for mut window in self.seen.iter_mut() {
+            window.sequences.retain(|_, sent_at| *sent_at >= cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::crypto_identifiers::ProcessToken;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn host(id: &str) -> RevocationPropagator {
+        let identity = CryptoIdentifier::new().unwrap().with_clock(Arc::new(ManualClock::new(NOW)));
+        RevocationPropagator::new(id, Arc::new(identity), PropagationMode::Gossip).0
+    }
+
+    // `origin` revoking a token of its own
+    fn revocation(origin: &RevocationPropagator) -> RevocationMessage {
+        let token = ProcessToken { pid: 1000, parent_token: None, signature: vec![7; 64], timestamp: NOW, capabilities: Vec::new(), nonce: [0; 16] };
+        origin.broadcast(origin.identity.revoke_token(&token))
+    }
+
+    // The same message, dated `sent_at` and signed again by the origin
+    fn dated(origin: &RevocationPropagator, mut message: RevocationMessage, sent_at: u64) -> RevocationMessage {
+        message.sent_at = sent_at;
+        let envelope = RevocationPropagator::envelope_bytes(&message.origin_host, message.sequence, sent_at, &message.proof);
+        message.envelope_signature = origin.identity.sign(&envelope);
+        message
+    }
+
+    fn pair() -> (RevocationPropagator, RevocationPropagator) {
+        let origin = host("a");
+        let receiver = host("b").with_revokers(["a".to_string()]);
+        receiver.add_peer("a", origin.identity.public_key_bytes());
+        (origin, receiver)
+    }
+
+    #[test]
+    fn applies_a_revokers_revocation() {
+        let (origin, receiver) = pair();
+        let message = revocation(&origin);
+        receiver.receive("a", message.clone()).unwrap();
+        assert!(receiver.is_revoked(&message.proof.token_signature));
+        assert!(matches!(receiver.receive("a", message), Err(PropagationError::Replay(..))));
+    }
+
+    #[test]
+    fn refuses_peers_that_are_not_revokers() {
+        let origin = host("a");
+        let receiver = host("b");
+        receiver.add_peer("a", origin.identity.public_key_bytes());
+        let message = revocation(&origin);
+        assert!(matches!(receiver.receive("a", message.clone()), Err(PropagationError::NotRevoker(id)) if id == "a"));
+        assert!(matches!(receiver.apply_relayed(&message, &origin.identity.public_key_bytes()), Err(PropagationError::NotRevoker(_))));
+        assert!(!receiver.is_revoked(&message.proof.token_signature));
+    }
+
+    #[test]
+    fn refuses_messages_from_the_future() {
+        let (origin, receiver) = pair();
+        let skew = receiver.identity.skew_tolerance();
+        let message = dated(&origin, revocation(&origin), NOW + skew + 1);
+        assert!(matches!(receiver.receive("a", message.clone()), Err(PropagationError::Future(..))));
+        assert!(matches!(receiver.apply_relayed(&message, &origin.identity.public_key_bytes()), Err(PropagationError::Future(..))));
+
+        // Within the skew tolerance is fine
+        let message = dated(&origin, revocation(&origin), NOW + skew);
+        receiver.receive("a", message).unwrap();
+    }
+
+    #[test]
+    fn refuses_messages_past_the_replay_window() {
+        let (origin, receiver) = pair();
+        let message = dated(&origin, revocation(&origin), NOW - DEFAULT_MAX_MESSAGE_AGE_SECS - 1);
+        assert!(matches!(receiver.receive("a", message), Err(PropagationError::Expired(..))));
+    }
+
+    #[test]
+    fn refuses_tampered_envelopes() {
+        let (origin, receiver) = pair();
+        let mut message = revocation(&origin);
+        message.sent_at -= 1;
+        assert!(matches!(receiver.receive("a", message), Err(PropagationError::BadEnvelope(_))));
+    }
+
+    #[test]
+    fn refuses_unknown_origins() {
+        let origin = host("a");
+        let receiver = host("b").with_revokers(["a".to_string()]);
+        assert!(matches!(receiver.receive("a", revocation(&origin)), Err(PropagationError::UnknownOrigin(_))));
+    }
+}
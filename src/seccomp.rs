@@ -0,0 +1,438 @@
+// src/seccomp.rs
+// Compiles a token's Syscall capabilities into a seccomp-BPF program. The
+// allowed numbers are merged into ranges and the ranges searched as a
+// balanced tree, so a lookup costs log2 of the number of ranges rather than
+// one comparison per syscall. Anything outside the set gets the deny
+// action: EPERM by default, so a program sees an ordinary error.
+//
+// A curated set of syscalls is allowed only with safe arguments, checked on
+// the low 32 bits, which is all these arguments use:
+//   clone         no namespace flags
+//   socket        AF_UNIX only, unless the token also grants NetworkAccess
+//   ioctl         never TIOCSTI (pushing input into the terminal)
+//   mmap/mprotect never writable and executable at once
+//   personality   PER_LINUX or the query value only
+// clone3 passes its flags behind a pointer BPF can't read, so it fails with
+// ENOSYS and libc falls back to clone. execve and the exit paths are
+// always allowed: the filter is installed before exec, and a process that
+// can't exit is worse than one that can.
+use crate::crypto_identifiers::{Capability, ProcessToken};
+use std::collections::BTreeMap;
+use std::io;
+
+// Classic BPF opcodes
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x04 | 0x50;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_JMP_JEQ_K: u16 = 0x05 | 0x10;
+const BPF_JMP_JGE_K: u16 = 0x05 | 0x30;
+const BPF_JMP_JSET_K: u16 = 0x05 | 0x40;
+const BPF_RET_K: u16 = 0x06;
+
+const RET_KILL_PROCESS: u32 = 0x8000_0000;
+const RET_ERRNO: u32 = 0x0005_0000;
+const RET_LOG: u32 = 0x7ffc_0000;
+const RET_ALLOW: u32 = 0x7fff_0000;
+
+const SET_MODE_FILTER: libc::c_ulong = 1;
+const FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+// seccomp_data: nr, arch, instruction_pointer, args[6]
+const OFFSET_NR: u32 = 0;
+const OFFSET_ARCH: u32 = 4;
+const OFFSET_ARGS: u32 = 16;
+// x32 syscalls share the x86_64 arch value and set this bit in nr
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+// compile() refuses other architectures before this is used
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: u32 = 0;
+
+const MAX_JUMP: usize = u8::MAX as usize;
+// BPF_MAXINSNS
+const MAX_INSTRUCTIONS: usize = 4096;
+
+const NAMESPACE_FLAGS: u32 = (libc::CLONE_NEWNS | libc::CLONE_NEWUSER | libc::CLONE_NEWPID | libc::CLONE_NEWNET | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS | libc::CLONE_NEWCGROUP) as u32;
+const PERSONALITY_QUERY: u32 = 0xffff_ffff;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeccompError {
+    #[error("seccomp filters are only compiled for x86_64 and aarch64")]
+    UnsupportedArch,
+    #[error("filter needs {0} instructions, more than the kernel's {MAX_INSTRUCTIONS}")]
+    TooLarge(usize),
+}
+
+// What a call outside the allowed set gets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DenyAction {
+    #[default]
+    Errno,
+    Kill,
+    // Allowed but logged, to learn what a profile is missing
+    Log,
+}
+
+impl DenyAction {
+    fn ret(self) -> u32 {
+        match self {
+            DenyAction::Errno => RET_ERRNO | libc::EPERM as u32,
+            DenyAction::Kill => RET_KILL_PROCESS,
+            DenyAction::Log => RET_LOG,
+        }
+    }
+}
+
+// A check on the low word of one argument; failing it denies the call
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArgCheck {
+    // arg & mask == 0
+    Clear { arg: u32, mask: u32 },
+    OneOf { arg: u32, values: Vec<u32> },
+    Not { arg: u32, value: u32 },
+    // arg & mask != mask
+    NotAll { arg: u32, mask: u32 },
+}
+
+// What a range of syscall numbers leads to
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Leaf {
+    Allow,
+    Deny,
+    Errno(i32),
+    Checked(Vec<ArgCheck>),
+}
+
+// A compiled program, ready to install in this process or a child
+pub struct Filter {
+    program: Vec<libc::sock_filter>,
+    allowed: usize,
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+// Syscalls that are always let through
+fn always_allowed() -> [u32; 4] {
+    [libc::SYS_execve, libc::SYS_exit, libc::SYS_exit_group, libc::SYS_rt_sigreturn].map(|nr| nr as u32)
+}
+
+fn arg_checks(nr: u32, network: bool) -> Option<Vec<ArgCheck>> {
+    let nr = nr as libc::c_long;
+    let prot_wx = (libc::PROT_WRITE | libc::PROT_EXEC) as u32;
+    Some(match nr {
+        libc::SYS_clone => {
+            // x86_64 passes flags first; so does aarch64
+            vec![ArgCheck::Clear { arg: 0, mask: NAMESPACE_FLAGS }]
+        }
+        libc::SYS_socket if !network => vec![ArgCheck::OneOf { arg: 0, values: vec![libc::AF_UNIX as u32] }],
+        libc::SYS_ioctl => vec![ArgCheck::Not { arg: 1, value: libc::TIOCSTI as u32 }],
+        libc::SYS_mmap | libc::SYS_mprotect => vec![ArgCheck::NotAll { arg: 2, mask: prot_wx }],
+        libc::SYS_personality => vec![ArgCheck::OneOf { arg: 0, values: vec![0, PERSONALITY_QUERY] }],
+        _ => return None,
+    })
+}
+
+impl Filter {
+    // None when the token names no syscalls, which leaves them unrestricted
+    pub fn from_token(token: &ProcessToken, deny: DenyAction) -> Result<Option<Self>, SeccompError> {
+        let syscalls: Vec<u32> = token
+            .capabilities
+            .iter()
+            .filter_map(|capability| match capability {
+                Capability::Syscall(nr) => Some(*nr),
+                _ => None,
+            })
+            .collect();
+        if syscalls.is_empty() {
+            return Ok(None);
+        }
+        let network = token.capabilities.iter().any(|capability| matches!(capability, Capability::NetworkAccess));
+        Self::compile(&syscalls, network, deny).map(Some)
+    }
+
+    pub fn compile(syscalls: &[u32], network: bool, deny: DenyAction) -> Result<Self, SeccompError> {
+        if !cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            return Err(SeccompError::UnsupportedArch);
+        }
+        let mut leaves: BTreeMap<u32, Leaf> = BTreeMap::new();
+        for nr in syscalls.iter().copied().chain(always_allowed()) {
+            let leaf = if nr as libc::c_long == libc::SYS_clone3 {
+                Leaf::Errno(libc::ENOSYS)
+            } else {
+                arg_checks(nr, network).map_or(Leaf::Allow, Leaf::Checked)
+            };
+            leaves.insert(nr, leaf);
+        }
+        let allowed = leaves.len();
+
+        // Partition 0..=u32::MAX into runs of the same leaf, each run
+        // starting at its key; gaps between allowed numbers deny
+        let mut runs: Vec<(u32, Leaf)> = Vec::new();
+        let mut next = 0u32;
+        for (nr, leaf) in leaves {
+            if nr > next {
+                runs.push((next, Leaf::Deny));
+            }
+            match runs.last() {
+                // Plain allows merge; each checked syscall is its own run
+                Some((_, Leaf::Allow)) if leaf == Leaf::Allow && nr == next => {}
+                _ => runs.push((nr, leaf)),
+            }
+            next = nr.saturating_add(1);
+        }
+        runs.push((next, Leaf::Deny));
+
+        let mut program = vec![stmt(BPF_LD_W_ABS, OFFSET_ARCH), jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0), stmt(BPF_RET_K, RET_KILL_PROCESS), stmt(BPF_LD_W_ABS, OFFSET_NR)];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1), stmt(BPF_RET_K, deny.ret())]);
+        program.extend(Self::tree(&runs, deny));
+        if program.len() > MAX_INSTRUCTIONS {
+            return Err(SeccompError::TooLarge(program.len()));
+        }
+        Ok(Self { program, allowed })
+    }
+
+    // Binary search over the runs; every path ends in its own return, so
+    // the only long jumps are over left subtrees
+    fn tree(runs: &[(u32, Leaf)], deny: DenyAction) -> Vec<libc::sock_filter> {
+        if let [(_, leaf)] = runs {
+            return Self::leaf(leaf, deny);
+        }
+        let mid = runs.len() / 2;
+        let left = Self::tree(&runs[..mid], deny);
+        let right = Self::tree(&runs[mid..], deny);
+        let boundary = runs[mid].0;
+        let mut code = Vec::with_capacity(left.len() + right.len() + 2);
+        if left.len() <= MAX_JUMP {
+            code.push(jump(BPF_JMP_JGE_K, boundary, left.len() as u8, 0));
+        } else {
+            // Conditional jumps reach 255 ahead; hop over the left side
+            // with an unconditional one, which takes 32 bits
+            code.push(jump(BPF_JMP_JGE_K, boundary, 0, 1));
+            code.push(stmt(BPF_JMP_JA, left.len() as u32));
+        }
+        code.extend(left);
+        code.extend(right);
+        code
+    }
+
+    fn leaf(leaf: &Leaf, deny: DenyAction) -> Vec<libc::sock_filter> {
+        match leaf {
+            Leaf::Allow => vec![stmt(BPF_RET_K, RET_ALLOW)],
+            Leaf::Deny => vec![stmt(BPF_RET_K, deny.ret())],
+            Leaf::Errno(errno) => vec![stmt(BPF_RET_K, RET_ERRNO | *errno as u32)],
+            Leaf::Checked(checks) => {
+                let mut code = Vec::new();
+                for check in checks {
+                    match check {
+                        ArgCheck::Clear { arg, mask } => {
+                            code.push(stmt(BPF_LD_W_ABS, OFFSET_ARGS + 8 * arg));
+                            code.push(jump(BPF_JMP_JSET_K, *mask, 0, 1));
+                        }
+                        ArgCheck::Not { arg, value } => {
+                            code.push(stmt(BPF_LD_W_ABS, OFFSET_ARGS + 8 * arg));
+                            code.push(jump(BPF_JMP_JEQ_K, *value, 0, 1));
+                        }
+                        ArgCheck::NotAll { arg, mask } => {
+                            // AND with the mask, then compare with it
+                            code.push(stmt(BPF_LD_W_ABS, OFFSET_ARGS + 8 * arg));
+                            code.push(stmt(BPF_ALU_AND_K, *mask));
+                            code.push(jump(BPF_JMP_JEQ_K, *mask, 0, 1));
+                        }
+                        ArgCheck::OneOf { arg, values } => {
+                            code.push(stmt(BPF_LD_W_ABS, OFFSET_ARGS + 8 * arg));
+                            // A match skips the rest and the deny after them
+                            for (i, value) in values.iter().enumerate() {
+                                code.push(jump(BPF_JMP_JEQ_K, *value, (values.len() - i) as u8, 0));
+                            }
+                        }
+                    }
+                    code.push(stmt(BPF_RET_K, deny.ret()));
+                }
+                code.push(stmt(BPF_RET_K, RET_ALLOW));
+                code
+            }
+        }
+    }
+
+    pub fn instructions(&self) -> usize {
+        self.program.len()
+    }
+
+    // Distinct syscall numbers the filter lets through, checked or not
+    pub fn allowed(&self) -> usize {
+        self.allowed
+    }
+
+    // Restricts the calling thread and its future children. Only makes two
+    // syscalls, so it is safe between fork and exec.
+    pub fn install(&self) -> io::Result<()> {
+        self.load(0)
+    }
+
+    // Restricts every thread of the calling process at once, for a process
+    // that is already running its threads
+    pub fn install_all_threads(&self) -> io::Result<()> {
+        self.load(FILTER_FLAG_TSYNC)
+    }
+
+    fn load(&self, flags: libc::c_ulong) -> io::Result<()> {
+        let prog = libc::sock_fprog { len: self.program.len() as u16, filter: self.program.as_ptr() as *mut libc::sock_filter };
+        // SAFETY: prog points into self.program, which outlives the call
+        let ret = unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::syscall(libc::SYS_seccomp, SET_MODE_FILTER, flags, &prog as *const libc::sock_fprog)
+        };
+        match ret {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            // With TSYNC: a thread whose own filters couldn't be synced
+            tid => Err(io::Error::new(io::ErrorKind::Other, format!("thread {} could not take the filter", tid))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the program over one seccomp_data the way the kernel would
+    fn run(filter: &Filter, arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let mut a = 0u32;
+        let mut pc = 0usize;
+        loop {
+            let insn = filter.program[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS => {
+                    a = match insn.k {
+                        OFFSET_NR => nr,
+                        OFFSET_ARCH => arch,
+                        k => args[((k - OFFSET_ARGS) / 8) as usize] as u32,
+                    }
+                }
+                BPF_ALU_AND_K => a &= insn.k,
+                BPF_JMP_JA => pc += insn.k as usize,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K | BPF_JMP_JSET_K => {
+                    let taken = match insn.code {
+                        BPF_JMP_JEQ_K => a == insn.k,
+                        BPF_JMP_JGE_K => a >= insn.k,
+                        _ => a & insn.k != 0,
+                    };
+                    pc += usize::from(if taken { insn.jt } else { insn.jf });
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected opcode {:#x}", code),
+            }
+        }
+    }
+
+    fn call(filter: &Filter, nr: libc::c_long, args: [u64; 6]) -> u32 {
+        run(filter, AUDIT_ARCH, nr as u32, args)
+    }
+
+    const EPERM: u32 = RET_ERRNO | libc::EPERM as u32;
+
+    #[test]
+    fn allows_only_listed_syscalls() {
+        let filter = Filter::compile(&[libc::SYS_read as u32, libc::SYS_write as u32], false, DenyAction::Errno).unwrap();
+        assert_eq!(call(&filter, libc::SYS_read, [0; 6]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_write, [0; 6]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_exit_group, [0; 6]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_openat, [0; 6]), EPERM);
+        assert_eq!(run(&filter, AUDIT_ARCH, u32::MAX, [0; 6]), EPERM);
+        assert_eq!(filter.allowed(), 2 + always_allowed().len());
+    }
+
+    #[test]
+    fn deny_action_decides_the_return() {
+        let kill = Filter::compile(&[], false, DenyAction::Kill).unwrap();
+        assert_eq!(call(&kill, libc::SYS_openat, [0; 6]), RET_KILL_PROCESS);
+        let log = Filter::compile(&[], false, DenyAction::Log).unwrap();
+        assert_eq!(call(&log, libc::SYS_openat, [0; 6]), RET_LOG);
+    }
+
+    #[test]
+    fn other_architectures_are_killed() {
+        let filter = Filter::compile(&[libc::SYS_read as u32], false, DenyAction::Errno).unwrap();
+        assert_eq!(run(&filter, AUDIT_ARCH ^ 1, libc::SYS_read as u32, [0; 6]), RET_KILL_PROCESS);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x32_calls_are_denied() {
+        let filter = Filter::compile(&[libc::SYS_read as u32], false, DenyAction::Errno).unwrap();
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_read as u32 | X32_SYSCALL_BIT, [0; 6]), EPERM);
+    }
+
+    // Enough ranges that the top of the tree has to hop its left side
+    // with an unconditional jump
+    #[test]
+    fn tree_finds_every_range() {
+        let syscalls: Vec<u32> = (10_000..11_500).step_by(3).collect();
+        let filter = Filter::compile(&syscalls, false, DenyAction::Errno).unwrap();
+        assert!(filter.program.iter().any(|insn| insn.code == BPF_JMP_JA));
+        for nr in 9_990..11_510 {
+            let expected = if syscalls.contains(&nr) { RET_ALLOW } else { EPERM };
+            assert_eq!(run(&filter, AUDIT_ARCH, nr, [0; 6]), expected, "syscall {}", nr);
+        }
+    }
+
+    #[test]
+    fn adjacent_syscalls_merge_into_one_range() {
+        let syscalls: Vec<u32> = (20_000..20_100).collect();
+        let filter = Filter::compile(&syscalls, false, DenyAction::Errno).unwrap();
+        let spread: Vec<u32> = (20_000..20_200).step_by(2).collect();
+        let spread = Filter::compile(&spread, false, DenyAction::Errno).unwrap();
+        assert!(filter.instructions() < spread.instructions());
+        assert_eq!(run(&filter, AUDIT_ARCH, 20_099, [0; 6]), RET_ALLOW);
+        assert_eq!(run(&filter, AUDIT_ARCH, 20_100, [0; 6]), EPERM);
+    }
+
+    #[test]
+    fn refuses_programs_over_the_kernel_limit() {
+        let syscalls: Vec<u32> = (0..20_000).step_by(2).collect();
+        assert!(matches!(Filter::compile(&syscalls, false, DenyAction::Errno), Err(SeccompError::TooLarge(_))));
+    }
+
+    #[test]
+    fn checks_arguments() {
+        let syscalls = [libc::SYS_clone, libc::SYS_socket, libc::SYS_mmap, libc::SYS_ioctl, libc::SYS_clone3].map(|nr| nr as u32);
+        let filter = Filter::compile(&syscalls, false, DenyAction::Errno).unwrap();
+
+        assert_eq!(call(&filter, libc::SYS_clone, [libc::SIGCHLD as u64, 0, 0, 0, 0, 0]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_clone, [libc::CLONE_NEWUSER as u64, 0, 0, 0, 0, 0]), EPERM);
+        assert_eq!(call(&filter, libc::SYS_clone3, [0; 6]), RET_ERRNO | libc::ENOSYS as u32);
+
+        assert_eq!(call(&filter, libc::SYS_socket, [libc::AF_UNIX as u64, 0, 0, 0, 0, 0]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_socket, [libc::AF_INET as u64, 0, 0, 0, 0, 0]), EPERM);
+
+        let rw = (libc::PROT_READ | libc::PROT_WRITE) as u64;
+        let wx = (libc::PROT_WRITE | libc::PROT_EXEC) as u64;
+        assert_eq!(call(&filter, libc::SYS_mmap, [0, 4096, rw, 0, 0, 0]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_mmap, [0, 4096, wx, 0, 0, 0]), EPERM);
+
+        assert_eq!(call(&filter, libc::SYS_ioctl, [0, libc::TIOCGWINSZ as u64, 0, 0, 0, 0]), RET_ALLOW);
+        assert_eq!(call(&filter, libc::SYS_ioctl, [0, libc::TIOCSTI as u64, 0, 0, 0, 0]), EPERM);
+    }
+
+    #[test]
+    fn network_access_lifts_the_socket_check() {
+        let filter = Filter::compile(&[libc::SYS_socket as u32], true, DenyAction::Errno).unwrap();
+        assert_eq!(call(&filter, libc::SYS_socket, [libc::AF_INET as u64, 0, 0, 0, 0, 0]), RET_ALLOW);
+    }
+}
@@ -0,0 +1,137 @@
+// src/secure_random.rs
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::Mutex;
+
+// Source of every draw that decides where memory ends up. Production uses
+// the kernel CSPRNG; tests can substitute a seeded source to get
+// reproducible layouts.
+pub trait SecureRandomSource: Send + Sync {
+    fn fill(&self, dest: &mut [u8]);
+
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill(&mut bytes);
+        u64::from_ne_bytes(bytes)
+    }
+
+    // Uniform in [0, bound) without modulo bias
+    fn next_below(&self, bound: u64) -> u64 {
+        if bound == 0 {
+            return 0;
+        }
+        let zone = u64::MAX - (u64::MAX % bound);
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+}
+
+// getrandom(2) via ring
+pub struct SystemRandomSource {
+    rng: SystemRandom,
+}
+
+impl SystemRandomSource {
+    pub fn new() -> Self {
+        Self { rng: SystemRandom::new() }
+    }
+}
+
+impl Default for SystemRandomSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecureRandomSource for SystemRandomSource {
+    fn fill(&self, dest: &mut [u8]) {
+        // Failing closed: a layout drawn from a broken RNG is worse than none
+        self.rng.fill(dest).expect("system CSPRNG unavailable");
+    }
+}
+
+// Deterministic, for tests and reproducing a reported layout only
+pub struct SeededRandomSource {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededRandomSource {
+    pub fn new(seed: u64) -> Self {
+        Self { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+}
+
+impl SecureRandomSource for SeededRandomSource {
+    fn fill(&self, dest: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(dest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out the given words in order
+    struct Scripted(Mutex<Vec<u64>>);
+
+    impl SecureRandomSource for Scripted {
+        fn fill(&self, dest: &mut [u8]) {
+            let word = self.0.lock().unwrap().remove(0);
+            dest.copy_from_slice(&word.to_ne_bytes()[..dest.len()]);
+        }
+    }
+
+    #[test]
+    fn next_below_stays_below_the_bound() {
+        let source = SeededRandomSource::new(1);
+        for bound in [1, 2, 3, 7, 4096, 1 << 47, (1 << 63) + 1, u64::MAX] {
+            for _ in 0..1000 {
+                assert!(source.next_below(bound) < bound, "bound {}", bound);
+            }
+        }
+    }
+
+    #[test]
+    fn next_below_zero_is_zero() {
+        assert_eq!(SeededRandomSource::new(1).next_below(0), 0);
+    }
+
+    #[test]
+    fn seeded_sources_repeat() {
+        let draws = |seed| {
+            let source = SeededRandomSource::new(seed);
+            (0..16).map(|_| source.next_below(1 << 40)).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(7), draws(7));
+        assert_ne!(draws(7), draws(8));
+    }
+
+    #[test]
+    fn next_below_is_roughly_uniform() {
+        let source = SeededRandomSource::new(42);
+        let mut counts = [0u32; 6];
+        for _ in 0..60_000 {
+            counts[source.next_below(6) as usize] += 1;
+        }
+        for count in counts {
+            assert!((9_400..10_600).contains(&count), "{:?}", counts);
+        }
+    }
+
+    // u64::MAX % 3 == 0, so the zone ends at u64::MAX and that one value
+    // would land on 0 once more than the others
+    #[test]
+    fn next_below_redraws_past_the_zone() {
+        let source = Scripted(Mutex::new(vec![u64::MAX, 5]));
+        assert_eq!(source.next_below(3), 2);
+
+        let half = (1 << 63) + 1;
+        let source = Scripted(Mutex::new(vec![u64::MAX - 1, half, 4]));
+        assert_eq!(source.next_below(half), 4);
+    }
+}
@@ -0,0 +1,418 @@
+// src/self_sandbox.rs
+// qksd confining itself, so that taking the daemon over gains as little as
+// possible. It happens in two steps, because capabilities and Landlock only
+// bind the calling thread and the threads it starts afterwards:
+//
+// `restrict` runs in main before the runtime or any logging thread exists.
+// It empties the ambient and inheritable sets, drops everything but
+// KEPT_CAPABILITIES from the bounding, effective and permitted sets (CHOWN,
+// SETUID and SETGID are kept too under [privsep], whose drop to its user
+// clears everything later), sets no_new_privs and, with [sandbox] landlock,
+// applies a ruleset built from the configured paths.
+//
+// `install_seccomp` runs at the end of Daemon::start and puts one filter on
+// every thread at once (TSYNC). It lets through what the daemon, restarts of
+// its subsystems and the programs it runs need, and nothing for mounting,
+// modules, kexec, reboot, setting clocks, changing uids, namespaces,
+// io_uring or userfaultfd. Programs the daemon starts inherit the filter;
+// spawn_confined ones stack their own on top.
+use crate::config::{QksConfig, SandboxSection};
+use crate::landlock::{LandlockError, Ruleset};
+use crate::seccomp::{Filter, SeccompError};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+// Highest capability number before /proc/sys/kernel/cap_last_cap existed
+const FALLBACK_LAST_CAP: u32 = 40;
+
+const CAP_CHOWN: u32 = 0;
+const CAP_KILL: u32 = 5;
+const CAP_SETGID: u32 = 6;
+const CAP_SETUID: u32 = 7;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_SYS_RESOURCE: u32 = 24;
+
+// The unit's CapabilityBoundingSet, less what only privsep uses
+const KEPT_CAPABILITIES: &[(u32, &str)] = &[
+    (CAP_SYS_ADMIN, "CAP_SYS_ADMIN"),
+    (CAP_SYS_PTRACE, "CAP_SYS_PTRACE"),
+    (CAP_SYS_RESOURCE, "CAP_SYS_RESOURCE"),
+    (CAP_NET_ADMIN, "CAP_NET_ADMIN"),
+    (CAP_KILL, "CAP_KILL"),
+];
+const PRIVSEP_CAPABILITIES: &[(u32, &str)] = &[(CAP_CHOWN, "CAP_CHOWN"), (CAP_SETUID, "CAP_SETUID"), (CAP_SETGID, "CAP_SETGID")];
+
+// Everything the daemon calls itself, through std, tokio and the libraries
+// it links, and what nft, ss, dmsetup and apparmor_parser call when it runs
+// them
+const DAEMON_SYSCALLS: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_ftruncate,
+    libc::SYS_truncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_flock,
+    libc::SYS_utimensat,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_umask,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    libc::SYS_fanotify_init,
+    libc::SYS_fanotify_mark,
+    // Descriptors and polling
+    libc::SYS_ioctl,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mincore,
+    libc::SYS_msync,
+    libc::SYS_mlock,
+    libc::SYS_mlock2,
+    libc::SYS_munlock,
+    libc::SYS_membarrier,
+    libc::SYS_memfd_create,
+    libc::SYS_get_mempolicy,
+    // Threads, signals and time
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_setaffinity,
+    libc::SYS_sched_getparam,
+    libc::SYS_sched_getscheduler,
+    libc::SYS_sched_get_priority_max,
+    libc::SYS_sched_get_priority_min,
+    libc::SYS_getpriority,
+    libc::SYS_getcpu,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    // Processes: the daemon's own and the ones it acts on
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getppid,
+    libc::SYS_getpgid,
+    libc::SYS_getsid,
+    libc::SYS_setpgid,
+    libc::SYS_setsid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_capget,
+    libc::SYS_capset,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_times,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_getrandom,
+    libc::SYS_execveat,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    libc::SYS_pidfd_open,
+    libc::SYS_pidfd_send_signal,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_personality,
+    // Probes, the token keyring and confining what the daemon starts
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_seccomp,
+    libc::SYS_landlock_create_ruleset,
+    libc::SYS_landlock_add_rule,
+    libc::SYS_landlock_restrict_self,
+];
+
+// Older calls glibc on x86_64 still makes in places; aarch64 never had them
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_getdents,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_create,
+    libc::SYS_eventfd,
+    libc::SYS_arch_prctl,
+    libc::SYS_vfork,
+    libc::SYS_time,
+    libc::SYS_getpgrp,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("{call}: {source}")]
+    Syscall { call: &'static str, source: io::Error },
+    #[error("Landlock: {0}")]
+    Landlock(#[from] LandlockError),
+    #[error("seccomp: {0}")]
+    Seccomp(#[from] SeccompError),
+}
+
+fn syscall_error(call: &'static str) -> SandboxError {
+    SandboxError::Syscall { call, source: io::Error::last_os_error() }
+}
+
+// What qksd ended up confined to, for its health
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SandboxReport {
+    pub capabilities: Vec<&'static str>,
+    // False when qksd lacked CAP_SETPCAP to shrink it
+    pub bounding_set: bool,
+    // Writable paths under Landlock; None when not applied
+    pub landlock: Option<Vec<PathBuf>>,
+    // Syscalls the filter allows; None until installed
+    pub seccomp: Option<usize>,
+    // Steps that were configured but could not be taken
+    pub missing: Vec<String>,
+}
+
+static REPORT: OnceLock<Mutex<SandboxReport>> = OnceLock::new();
+
+pub fn report() -> Option<SandboxReport> {
+    REPORT.get().map(|report| report.lock().unwrap().clone())
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn kept(privsep: bool) -> Vec<(u32, &'static str)> {
+    let mut kept = KEPT_CAPABILITIES.to_vec();
+    if privsep {
+        kept.extend_from_slice(PRIVSEP_CAPABILITIES);
+    }
+    kept
+}
+
+fn last_cap() -> u32 {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap").ok().and_then(|last| last.trim().parse().ok()).unwrap_or(FALLBACK_LAST_CAP)
+}
+
+// Single-threaded, before the runtime starts: everything here binds only
+// the calling thread and the threads it goes on to create
+pub fn restrict(config: &QksConfig) -> Result<(), SandboxError> {
+    let sandbox = &config.sandbox;
+    if !sandbox.enabled {
+        return Ok(());
+    }
+    let kept = kept(config.privsep.enabled);
+    let mask = kept.iter().fold(0u64, |mask, (cap, _)| mask | 1 << cap);
+    let mut report = SandboxReport { capabilities: kept.iter().map(|(_, name)| *name).collect(), bounding_set: true, ..Default::default() };
+
+    for cap in (0..=last_cap()).filter(|cap| mask & 1 << cap == 0) {
+        // SAFETY: no pointers
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } == -1 {
+            match io::Error::last_os_error().raw_os_error() {
+                // Not ours to shrink; effective and permitted still are
+                Some(libc::EPERM) => {
+                    report.bounding_set = false;
+                    break;
+                }
+                // Numbers past what this kernel knows
+                Some(libc::EINVAL) => break,
+                _ => return Err(syscall_error("prctl(PR_CAPBSET_DROP)")),
+            }
+        }
+    }
+    // SAFETY: no pointers; EINVAL only on kernels without ambient capabilities
+    if unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0, 0, 0) } == -1 && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL) {
+        return Err(syscall_error("prctl(PR_CAP_AMBIENT_CLEAR_ALL)"));
+    }
+    restrict_capabilities(mask)?;
+
+    // SAFETY: no pointers
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+        return Err(syscall_error("prctl(PR_SET_NO_NEW_PRIVS)"));
+    }
+
+    if sandbox.landlock {
+        match Ruleset::for_paths(&sandbox.read_paths, &writable(config)) {
+            Ok(ruleset) => report.landlock = Some(ruleset.restrict_self()?.writable),
+            Err(LandlockError::Unsupported) => report.missing.push("Landlock is not available on this kernel".to_string()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let _ = REPORT.set(Mutex::new(report));
+    Ok(())
+}
+
+// Effective and permitted down to `mask`, inheritable emptied
+fn restrict_capabilities(mask: u64) -> Result<(), SandboxError> {
+    let mut header = CapHeader { version: CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    // SAFETY: header and data are the version 3 layout the kernel fills
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header as *mut CapHeader, data.as_mut_ptr()) } == -1 {
+        return Err(syscall_error("capget"));
+    }
+    for (i, data) in data.iter_mut().enumerate() {
+        let keep = (mask >> (32 * i)) as u32;
+        data.effective &= keep;
+        data.permitted &= keep;
+        data.inheritable = 0;
+    }
+    // SAFETY: as above; lowering the sets needs no privilege
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) } == -1 {
+        return Err(syscall_error("capset"));
+    }
+    Ok(())
+}
+
+// The configured write paths and wherever the daemon keeps its own files
+fn writable(config: &QksConfig) -> Vec<PathBuf> {
+    let mut paths = config.sandbox.write_paths.clone();
+    paths.push(config.daemon.state_dir.clone());
+    paths.push(config.snapshot_dir());
+    paths.extend(config.response_audit_log().parent().map(PathBuf::from));
+    paths.extend(config.daemon.control_socket.parent().map(PathBuf::from));
+    paths.push(PathBuf::from(crate::model_signing::MODEL_STAGING_DIR));
+    paths.extend(config.randomizer.arena_socket.as_ref().and_then(|s| s.parent()).map(PathBuf::from));
+    paths.push(config.profiles.apparmor_dir.clone());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+// Once every subsystem is running: one filter for every thread
+pub fn install_seccomp(sandbox: &SandboxSection) -> Result<SandboxReport, SandboxError> {
+    let report = REPORT.get_or_init(Default::default);
+    if sandbox.seccomp {
+        let syscalls: Vec<u32> = DAEMON_SYSCALLS.iter().chain(LEGACY_SYSCALLS).map(|nr| *nr as u32).collect();
+        let filter = Filter::compile(&syscalls, true, sandbox.seccomp_deny)?;
+        filter.install_all_threads().map_err(|source| SandboxError::Syscall { call: "seccomp", source })?;
+        report.lock().unwrap().seccomp = Some(filter.allowed());
+    }
+    Ok(report.lock().unwrap().clone())
+}
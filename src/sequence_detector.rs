@@ -0,0 +1,147 @@
+// src/sequence_detector.rs
+// Detector over raw syscall IDs instead of aggregate features. Streams are
+// cut into fixed-length windows and scored by a sequence model (embedding +
+// LSTM/transformer) exported with input [N, window] and outputs ordered
+// (anomaly_score, reconstruction) like the feature models. Token IDs are
+// fed as floats; the exported graph casts them before the embedding lookup.
+use crate::inference_backend::{self, InferenceBackend, InferenceError, InferenceOptions};
+use crate::model_signing::{self, ModelTrust};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Token 0 is padding, so syscall n becomes token n + 1
+pub const PAD_TOKEN: u32 = 0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SequenceConfig {
+    pub window: usize,
+    pub stride: usize,
+    // Embedding table size; the last token stands for any syscall beyond it
+    pub vocab_size: u32,
+}
+
+impl Default for SequenceConfig {
+    fn default() -> Self {
+        Self { window: 64, stride: 16, vocab_size: 512 }
+    }
+}
+
+impl SequenceConfig {
+    fn token(&self, syscall: u32) -> u32 {
+        syscall.saturating_add(1).min(self.vocab_size - 1)
+    }
+
+    // Windows of `window` tokens every `stride` calls. A short sequence is
+    // left-padded into one window; a tail the stride skips gets a final
+    // window aligned to the end so the newest calls are always scored.
+    pub fn windows(&self, sequence: &[u32]) -> Vec<Vec<f32>> {
+        let tokens: Vec<f32> = sequence.iter().map(|&s| self.token(s) as f32).collect();
+        if tokens.is_empty() {
+            return Vec::new();
+        }
+
+        if tokens.len() <= self.window {
+            let mut padded = vec![PAD_TOKEN as f32; self.window - tokens.len()];
+            padded.extend_from_slice(&tokens);
+            return vec![padded];
+        }
+
+        let stride = self.stride.max(1);
+        let last = tokens.len() - self.window;
+        let mut windows: Vec<Vec<f32>> = (0..=last)
+            .step_by(stride)
+            .map(|start| tokens[start..start + self.window].to_vec())
+            .collect();
+        if last % stride != 0 {
+            windows.push(tokens[last..].to_vec());
+        }
+        windows
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SequenceScore {
+    pub max: f32,
+    pub mean: f32,
+    // Per-window scores in stream order
+    pub windows: Vec<f32>,
+}
+
+pub struct SequenceDetector {
+    backend: Box<dyn InferenceBackend>,
+    config: SequenceConfig,
+    // Per-PID tail of the stream not yet covered by a full stride
+    pending: HashMap<u32, Vec<u32>>,
+}
+
+impl SequenceDetector {
+    pub fn new(
+        model_path: &str,
+        config: SequenceConfig,
+        trust: &ModelTrust,
+        options: &InferenceOptions,
+    ) -> Result<Self, InferenceError> {
+        if config.window == 0 || config.vocab_size < 2 {
+            return Err(InferenceError::Model(format!("unusable sequence config {:?}", config)));
+        }
+
+        let original = inference_backend::resolve_model_path(model_path, options)?;
+        let verified = model_signing::stage_verified(std::path::Path::new(&original), trust)?;
+        let backend = inference_backend::load_backend(&verified.path().to_string_lossy(), options)?;
+        if let Some(width) = backend.input_width() {
+            if width != config.window {
+                return Err(InferenceError::Model(format!(
+                    "window of {} calls, model expects {}", config.window, width
+                )));
+            }
+        }
+        tracing::info!(
+            "Loaded sequence model {} ({} backend, window {}, stride {})",
+            original, backend.name(), config.window, config.stride
+        );
+
+        Ok(Self { backend, config, pending: HashMap::new() })
+    }
+
+    pub fn config(&self) -> SequenceConfig {
+        self.config
+    }
+
+    pub fn score_sequence(&mut self, sequence: &[u32]) -> Result<SequenceScore, InferenceError> {
+        let windows = self.config.windows(sequence);
+        let scores: Vec<f32> = self.backend.infer_batch(&windows)?
+            .into_iter()
+            .map(|(score, _)| score)
+            .collect();
+
+        let max = scores.iter().copied().fold(0.0, f32::max);
+        let mean = if scores.is_empty() { 0.0 } else { scores.iter().sum::<f32>() / scores.len() as f32 };
+        Ok(SequenceScore { max, mean, windows: scores })
+    }
+
+    // Streaming use: append a process's newest syscalls and score every
+    // window completed since the last call. Returns None until a full
+    // window has accumulated.
+    pub fn observe(&mut self, pid: u32, syscalls: &[u32]) -> Result<Option<SequenceScore>, InferenceError> {
+        let window = self.config.window;
+        let stride = self.config.stride.max(1);
+
+        let buffer = self.pending.entry(pid).or_default();
+        buffer.extend_from_slice(syscalls);
+        if buffer.len() < window {
+            return Ok(None);
+        }
+
+        // Score whole strides only; keep the overlap for the next window
+        let complete = (buffer.len() - window) / stride * stride + window;
+        let ready: Vec<u32> = buffer[..complete].to_vec();
+        let consumed = (complete - window + stride).min(buffer.len());
+        buffer.drain(..consumed);
+
+        self.score_sequence(&ready).map(Some)
+    }
+
+    pub fn forget(&mut self, pid: u32) {
+        self.pending.remove(&pid);
+    }
+}
@@ -0,0 +1,538 @@
+// src/siem_format.rs
+// Renders SecurityEvents the way SIEMs already parse them: ArcSight CEF,
+// QRadar LEEF 1.0 and Elastic Common Schema JSON, besides the crate's own
+// JSON. Output sinks take a `format` so events can go straight into an
+// existing pipeline. Fields with a standard key use it (dvchost, dpid,
+// dproc, act, outcome in CEF; process.*, event.* in ECS); the rest go in
+// labelled custom fields (CEF cfp/cn/cs) or under `qks` (ECS). Severity is
+// on the 0-10 scale CEF and LEEF share; ECS gets the same number. Events
+// about a process in an enrolled container carry its ID, image and pod in
+// every format (container_events.rs); network events carry the network
+// namespace they happened in, and intel matches go under ECS's threat.*.
+use crate::container_events::for_event as container;
+use crate::ebpf_monitor::{NetworkAction, NetworkEvent};
+use crate::events::{ContainerEvent, SecurityEvent, SnapshotEvent, TokenEvent};
+use crate::syslog_sink;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const VENDOR: &str = "QKS";
+const PRODUCT: &str = "Quantum Kernel Security";
+const ECS_VERSION: &str = "8.11.0";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    // SecurityEvent as serialized everywhere else
+    #[default]
+    Json,
+    Cef,
+    Leef,
+    Ecs,
+}
+
+impl EventFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            EventFormat::Json | EventFormat::Ecs => "application/json",
+            EventFormat::Cef | EventFormat::Leef => "text/plain",
+        }
+    }
+}
+
+pub fn render(format: EventFormat, event: &SecurityEvent, host: &str) -> String {
+    match format {
+        EventFormat::Json => json(event).to_string(),
+        EventFormat::Cef => cef(event, host),
+        EventFormat::Leef => leef(event, host),
+        EventFormat::Ecs => ecs(event, host).to_string(),
+    }
+}
+
+// The crate's own JSON, with the process's container beside the event
+pub fn json(event: &SecurityEvent) -> Value {
+    let mut value = serde_json::to_value(event).unwrap_or_default();
+    if let Some(container) = container(event) {
+        value["container"] = json!(container);
+    }
+    value
+}
+
+// One line for people: log messages, alert summaries, CEF names
+pub fn describe(event: &SecurityEvent) -> String {
+    match event {
+        SecurityEvent::Syscall(e) => format!("syscall {} by PID {} returned {}", e.syscall, e.pid, e.retval),
+        SecurityEvent::Anomaly(e) => format!(
+            "anomaly score {:.3} (threshold {:.3}) for PID {} ({})",
+            e.score,
+            e.threshold,
+            e.pid,
+            e.exe.as_deref().unwrap_or("unknown executable"),
+        ),
+        SecurityEvent::Token(TokenEvent::Issued { pid, capabilities, .. }) => {
+            format!("token issued to PID {} with {} capabilities", pid, capabilities.len())
+        }
+        SecurityEvent::Token(TokenEvent::Revoked { pid, .. }) => format!("token of PID {} revoked", pid),
+        SecurityEvent::Token(TokenEvent::BudgetExceeded { pid, limit, breach, count, .. }) => {
+            format!("PID {} exceeded its {} byte memory budget ({} x{})", pid, limit, breach.replace('_', " "), count)
+        }
+        SecurityEvent::Layout(e) => format!("layout of PID {} changed ({}), regeneration {}", e.pid, action(event), e.regeneration_count),
+        SecurityEvent::Snapshot(SnapshotEvent::Taken { snapshot_id }) => format!("snapshot {} taken", snapshot_id),
+        SecurityEvent::Snapshot(SnapshotEvent::Restored { snapshot_id, pids }) => {
+            format!("snapshot {} restored to {} processes", snapshot_id, pids.len())
+        }
+        SecurityEvent::Snapshot(SnapshotEvent::Verified { snapshot_id, valid }) => {
+            format!("snapshot {} {}", snapshot_id, if *valid { "verified" } else { "failed verification" })
+        }
+        SecurityEvent::Response(e) => {
+            let target = e.pid.map(|pid| format!(" on PID {}", pid)).unwrap_or_default();
+            let detail = e.detail.as_deref().map(|d| format!(": {}", d)).unwrap_or_default();
+            format!("{}{} {}{}", e.action, target, e.outcome, detail)
+        }
+        SecurityEvent::Integrity(e) => {
+            let path = e.path.as_deref().map(|p| format!(" for {}", p)).unwrap_or_default();
+            let detail = e.detail.as_deref().map(|d| format!(": {}", d)).unwrap_or_default();
+            format!("{} {}{}{}", e.source, e.finding.replace('_', " "), path, detail)
+        }
+        SecurityEvent::Container(ContainerEvent::Started { pid, container, .. }) => {
+            format!("container {} ({}) started as PID {}", short_id(&container.id), container.image, pid)
+        }
+        SecurityEvent::Container(ContainerEvent::Stopped { container, exit_status, .. }) => {
+            format!("container {} ({}) exited with status {}", short_id(&container.id), container.image, exit_status)
+        }
+        SecurityEvent::Network(e) => match e.action {
+            NetworkAction::Connect => format!("PID {} connecting to {} in netns {}", e.pid, endpoint(e.destination, e.destination_port), e.netns),
+            NetworkAction::Listen => format!("PID {} listening on {} in netns {}", e.pid, endpoint(e.source, e.source_port), e.netns),
+            NetworkAction::NamespaceSwitch => {
+                let from = e.previous_netns.map(|netns| format!(" from {}", netns)).unwrap_or_default();
+                format!("PID {} moved to netns {}{}", e.pid, e.netns, from)
+            }
+        },
+        SecurityEvent::Intel(e) => {
+            let seen = match e.observed.as_str() {
+                "connect" => format!("connect to {}", e.matched),
+                observed => format!("{} of {}", observed, e.path.as_deref().unwrap_or(&e.matched)),
+            };
+            let by = e.pid.map(|pid| format!(" by PID {}", pid)).unwrap_or_default();
+            let description = e.description.as_deref().map(|d| format!(": {}", d)).unwrap_or_default();
+            format!("{}{} matches {} {} from {}{}", seen, by, e.indicator_type, e.indicator, e.feed, description)
+        }
+        SecurityEvent::Drift(e) => {
+            let worst = e.drifted.first().map(|f| format!(", worst feature {} (PSI {:.3})", f.feature, f.psi)).unwrap_or_default();
+            format!("model v{} inputs drifted on {} features over {} rows{}", e.model_version, e.drifted.len(), e.samples, worst)
+        }
+    }
+}
+
+fn endpoint(address: Option<IpAddr>, port: u16) -> String {
+    match address {
+        Some(IpAddr::V6(address)) => format!("[{}]:{}", address, port),
+        Some(address) => format!("{}:{}", address, port),
+        None => format!("port {}", port),
+    }
+}
+
+// 0-10, as CEF and LEEF define it
+pub fn severity(event: &SecurityEvent) -> u8 {
+    match event {
+        SecurityEvent::Syscall(_) => 0,
+        SecurityEvent::Anomaly(e) => (e.score.clamp(0.0, 1.0) * 10.0).round() as u8,
+        SecurityEvent::Token(TokenEvent::Issued { .. }) => 1,
+        SecurityEvent::Token(TokenEvent::Revoked { .. }) => 5,
+        SecurityEvent::Token(TokenEvent::BudgetExceeded { breach, .. }) => if breach == "oom_kill" { 7 } else { 5 },
+        SecurityEvent::Layout(_) => 1,
+        SecurityEvent::Snapshot(SnapshotEvent::Verified { valid: false, .. }) => 8,
+        SecurityEvent::Snapshot(_) => 2,
+        SecurityEvent::Response(e) => match e.outcome.as_str() {
+            "refused" | "failed" => 8,
+            "executed" => 6,
+            _ => 3,
+        },
+        SecurityEvent::Integrity(e) => if e.critical { 9 } else { 5 },
+        SecurityEvent::Container(ContainerEvent::Stopped { exit_status, .. }) if *exit_status != 0 => 3,
+        SecurityEvent::Container(_) => 1,
+        SecurityEvent::Network(NetworkEvent { action: NetworkAction::NamespaceSwitch, .. }) => 4,
+        SecurityEvent::Network(_) => 1,
+        SecurityEvent::Intel(e) => e.severity.min(10),
+        SecurityEvent::Drift(_) => 5,
+    }
+}
+
+// What happened, as a verb-ish keyword
+fn action(event: &SecurityEvent) -> String {
+    match event {
+        SecurityEvent::Syscall(_) => "syscall".to_string(),
+        SecurityEvent::Anomaly(_) => "anomaly-detected".to_string(),
+        SecurityEvent::Token(TokenEvent::Issued { .. }) => "token-issued".to_string(),
+        SecurityEvent::Token(TokenEvent::Revoked { .. }) => "token-revoked".to_string(),
+        SecurityEvent::Token(TokenEvent::BudgetExceeded { .. }) => "budget-exceeded".to_string(),
+        SecurityEvent::Layout(e) => format!("{:?}", e.trigger).to_lowercase(),
+        SecurityEvent::Snapshot(SnapshotEvent::Taken { .. }) => "snapshot-taken".to_string(),
+        SecurityEvent::Snapshot(SnapshotEvent::Restored { .. }) => "snapshot-restored".to_string(),
+        SecurityEvent::Snapshot(SnapshotEvent::Verified { .. }) => "snapshot-verified".to_string(),
+        SecurityEvent::Response(e) => e.action.clone(),
+        SecurityEvent::Integrity(e) => e.finding.replace('_', "-"),
+        SecurityEvent::Container(ContainerEvent::Started { .. }) => "container-started".to_string(),
+        SecurityEvent::Container(ContainerEvent::Stopped { .. }) => "container-stopped".to_string(),
+        SecurityEvent::Network(e) => e.action.as_str().replace('_', "-"),
+        SecurityEvent::Intel(_) => "indicator-match".to_string(),
+        SecurityEvent::Drift(_) => "model-drift".to_string(),
+    }
+}
+
+// Seconds since the epoch; now for events that don't carry a time
+pub fn event_time(event: &SecurityEvent) -> SystemTime {
+    let secs = match event {
+        SecurityEvent::Anomaly(e) => Some(e.timestamp),
+        SecurityEvent::Token(TokenEvent::Issued { timestamp, .. }) => Some(*timestamp),
+        SecurityEvent::Token(TokenEvent::Revoked { revoked_at, .. }) => Some(*revoked_at),
+        SecurityEvent::Token(TokenEvent::BudgetExceeded { timestamp, .. }) => Some(*timestamp),
+        SecurityEvent::Layout(e) => Some(e.timestamp),
+        SecurityEvent::Response(e) => Some(e.timestamp),
+        SecurityEvent::Integrity(e) => Some(e.timestamp),
+        SecurityEvent::Container(ContainerEvent::Started { timestamp, .. } | ContainerEvent::Stopped { timestamp, .. }) => Some(*timestamp),
+        SecurityEvent::Network(e) => Some(e.timestamp),
+        SecurityEvent::Intel(e) => Some(e.timestamp),
+        SecurityEvent::Drift(e) => Some(e.timestamp),
+        SecurityEvent::Syscall(_) | SecurityEvent::Snapshot(_) => None,
+    };
+    secs.map_or_else(SystemTime::now, |secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn epoch_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+// Fields each format maps to its own keys: (CEF key, LEEF key, value)
+fn fields(event: &SecurityEvent) -> Vec<(&'static str, &'static str, String)> {
+    let mut fields = Vec::new();
+    if let Some(pid) = event.pid() {
+        fields.push(("dpid", "pid", pid.to_string()));
+    }
+    match event {
+        SecurityEvent::Syscall(e) => {
+            fields.push(("cn1", "syscall", e.syscall.to_string()));
+            fields.push(("cn2", "retval", e.retval.to_string()));
+            fields.push(("cn3", "durationNs", e.duration_ns.to_string()));
+        }
+        SecurityEvent::Anomaly(e) => {
+            if let Some(exe) = &e.exe {
+                fields.push(("dproc", "proc", exe.clone()));
+            }
+            fields.push(("cfp1", "score", e.score.to_string()));
+            fields.push(("cfp2", "threshold", e.threshold.to_string()));
+            fields.push(("cn1", "modelVersion", e.model_version.to_string()));
+            if let Some(parent) = e.process_tree.get(1) {
+                fields.push(("cn2", "ppid", parent.pid.to_string()));
+            }
+        }
+        SecurityEvent::Layout(e) => {
+            fields.push(("cn1", "regenerationCount", e.regeneration_count.to_string()));
+            fields.push(("cs1", "regions", e.regions.join(",")));
+        }
+        SecurityEvent::Snapshot(
+            SnapshotEvent::Taken { snapshot_id } | SnapshotEvent::Restored { snapshot_id, .. } | SnapshotEvent::Verified { snapshot_id, .. },
+        ) => {
+            fields.push(("cs1", "snapshotId", snapshot_id.clone()));
+        }
+        SecurityEvent::Response(e) => {
+            fields.push(("outcome", "outcome", e.outcome.clone()));
+            if let Some(detail) = &e.detail {
+                fields.push(("msg", "detail", detail.clone()));
+            }
+        }
+        SecurityEvent::Integrity(e) => {
+            fields.push(("cs1", "source", e.source.clone()));
+            if let Some(path) = &e.path {
+                fields.push(("filePath", "filePath", path.clone()));
+            }
+            if let Some(expected) = &e.expected {
+                fields.push(("cs2", "expectedHash", expected.clone()));
+            }
+            if let Some(actual) = &e.actual {
+                fields.push(("fileHash", "fileHash", actual.clone()));
+            }
+            if let Some(detail) = &e.detail {
+                fields.push(("msg", "detail", detail.clone()));
+            }
+        }
+        SecurityEvent::Container(ContainerEvent::Stopped { exit_status, .. }) => {
+            fields.push(("cn1", "exitStatus", exit_status.to_string()));
+        }
+        SecurityEvent::Network(e) => {
+            if let Some(source) = e.source {
+                fields.push(("src", "src", source.to_string()));
+                fields.push(("spt", "srcPort", e.source_port.to_string()));
+            }
+            if let Some(destination) = e.destination {
+                fields.push(("dst", "dst", destination.to_string()));
+                fields.push(("dpt", "dstPort", e.destination_port.to_string()));
+            }
+            fields.push(("cn1", "netns", e.netns.to_string()));
+            if let Some(previous) = e.previous_netns {
+                fields.push(("cn2", "previousNetns", previous.to_string()));
+            }
+        }
+        SecurityEvent::Intel(e) => {
+            if let Some(exe) = &e.exe {
+                fields.push(("dproc", "proc", exe.clone()));
+            }
+            if let Some(path) = &e.path {
+                fields.push(("filePath", "filePath", path.clone()));
+            }
+            if e.indicator_type == "hash" {
+                fields.push(("fileHash", "fileHash", e.matched.clone()));
+            } else {
+                fields.push(("dst", "dst", e.matched.clone()));
+            }
+            fields.push(("cs1", "feed", e.feed.clone()));
+            fields.push(("cs2", "indicator", e.indicator.clone()));
+            fields.push(("cs3", "indicatorType", e.indicator_type.clone()));
+            if let Some(confidence) = e.confidence {
+                fields.push(("cn1", "confidence", confidence.to_string()));
+            }
+            if let Some(description) = &e.description {
+                fields.push(("msg", "detail", description.clone()));
+            }
+        }
+        SecurityEvent::Drift(e) => {
+            fields.push(("cn1", "modelVersion", e.model_version.to_string()));
+            fields.push(("cn2", "samples", e.samples.to_string()));
+            let drifted: Vec<String> = e.drifted.iter().map(|f| f.feature.to_string()).collect();
+            fields.push(("cs1", "features", drifted.join(",")));
+        }
+        SecurityEvent::Token(_) | SecurityEvent::Container(_) => {}
+    }
+    if let Some(container) = container(event) {
+        fields.push(("cs4", "containerId", container.id));
+        fields.push(("cs5", "containerImage", container.image));
+        if let Some(pod) = container.pod {
+            // namespace/pod, then /container when the runtime named it
+            let mut path = format!("{}/{}", pod.namespace, pod.name);
+            if let Some(name) = &pod.container {
+                path = format!("{}/{}", path, name);
+            }
+            fields.push(("cs6", "pod", path));
+        }
+    }
+    fields
+}
+
+// CEF custom fields need a label naming them
+fn cef_label(key: &str, event: &SecurityEvent) -> Option<&'static str> {
+    Some(match (key, event) {
+        ("cn1", SecurityEvent::Syscall(_)) => "syscall",
+        ("cn2", SecurityEvent::Syscall(_)) => "retval",
+        ("cn3", SecurityEvent::Syscall(_)) => "durationNs",
+        ("cfp1", _) => "score",
+        ("cfp2", _) => "threshold",
+        ("cn1", SecurityEvent::Anomaly(_)) => "modelVersion",
+        ("cn2", SecurityEvent::Anomaly(_)) => "parentPid",
+        ("cn1", SecurityEvent::Layout(_)) => "regenerationCount",
+        ("cs1", SecurityEvent::Layout(_)) => "regions",
+        ("cs1", SecurityEvent::Snapshot(_)) => "snapshotId",
+        ("cs1", SecurityEvent::Integrity(_)) => "source",
+        ("cs2", SecurityEvent::Integrity(_)) => "expectedHash",
+        ("cn1", SecurityEvent::Container(_)) => "exitStatus",
+        ("cn1", SecurityEvent::Network(_)) => "netns",
+        ("cn2", SecurityEvent::Network(_)) => "previousNetns",
+        ("cs1", SecurityEvent::Intel(_)) => "feed",
+        ("cs2", SecurityEvent::Intel(_)) => "indicator",
+        ("cs3", SecurityEvent::Intel(_)) => "indicatorType",
+        ("cn1", SecurityEvent::Intel(_)) => "confidence",
+        ("cn1", SecurityEvent::Drift(_)) => "modelVersion",
+        ("cn2", SecurityEvent::Drift(_)) => "samples",
+        ("cs1", SecurityEvent::Drift(_)) => "features",
+        ("cs4", _) => "containerId",
+        ("cs5", _) => "containerImage",
+        ("cs6", _) => "pod",
+        _ => return None,
+    })
+}
+
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// CEF:0|Vendor|Product|Version|SignatureID|Name|Severity|Extension
+pub fn cef(event: &SecurityEvent, host: &str) -> String {
+    let mut line = format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|",
+        cef_header(VENDOR),
+        cef_header(PRODUCT),
+        env!("CARGO_PKG_VERSION"),
+        cef_header(&format!("{}:{}", event.kind().as_str(), action(event))),
+        cef_header(&describe(event)),
+        severity(event),
+    );
+    let _ = write!(line, "rt={} dvchost={} act={}", epoch_millis(event_time(event)), cef_value(host), cef_value(&action(event)));
+    for (key, _, value) in fields(event) {
+        let _ = write!(line, " {}={}", key, cef_value(&value));
+        if let Some(label) = cef_label(key, event) {
+            let _ = write!(line, " {}Label={}", key, label);
+        }
+    }
+    line
+}
+
+fn leef_value(value: &str) -> String {
+    value.replace('\t', " ").replace('\n', " ").replace('\r', " ")
+}
+
+// LEEF:1.0|Vendor|Product|Version|EventID| then tab-separated attributes
+pub fn leef(event: &SecurityEvent, host: &str) -> String {
+    let mut line = format!(
+        "LEEF:1.0|{}|{}|{}|{}|",
+        cef_header(VENDOR),
+        cef_header(PRODUCT),
+        env!("CARGO_PKG_VERSION"),
+        cef_header(&format!("{}:{}", event.kind().as_str(), action(event))),
+    );
+    let _ = write!(
+        line,
+        "devTime={}\tsev={}\tidentHostName={}\tcat={}\tmsg={}",
+        epoch_millis(event_time(event)),
+        severity(event).max(1),
+        leef_value(host),
+        event.kind().as_str(),
+        leef_value(&describe(event)),
+    );
+    for (_, key, value) in fields(event) {
+        let _ = write!(line, "\t{}={}", key, leef_value(&value));
+    }
+    line
+}
+
+pub fn ecs(event: &SecurityEvent, host: &str) -> Value {
+    let (kind, category, types) = match event {
+        SecurityEvent::Anomaly(_) => ("alert", "intrusion_detection", vec!["info"]),
+        SecurityEvent::Response(_) => ("event", "intrusion_detection", vec!["denied"]),
+        SecurityEvent::Syscall(_) => ("event", "process", vec!["info"]),
+        SecurityEvent::Token(TokenEvent::Issued { .. }) => ("event", "iam", vec!["creation"]),
+        SecurityEvent::Token(TokenEvent::Revoked { .. }) => ("event", "iam", vec!["deletion"]),
+        SecurityEvent::Token(TokenEvent::BudgetExceeded { .. }) => ("event", "process", vec!["denied"]),
+        SecurityEvent::Layout(_) => ("event", "process", vec!["change"]),
+        SecurityEvent::Snapshot(_) => ("event", "configuration", vec!["info"]),
+        SecurityEvent::Integrity(e) if e.critical => ("alert", "file", vec!["change"]),
+        SecurityEvent::Integrity(_) => ("event", "file", vec!["info"]),
+        SecurityEvent::Container(ContainerEvent::Started { .. }) => ("event", "process", vec!["start"]),
+        SecurityEvent::Container(ContainerEvent::Stopped { .. }) => ("event", "process", vec!["end"]),
+        SecurityEvent::Network(e) => match e.action {
+            NetworkAction::Connect => ("event", "network", vec!["connection", "start"]),
+            NetworkAction::Listen => ("event", "network", vec!["start"]),
+            NetworkAction::NamespaceSwitch => ("event", "process", vec!["change"]),
+        },
+        SecurityEvent::Intel(_) => ("alert", "threat", vec!["indicator"]),
+        SecurityEvent::Drift(_) => ("event", "intrusion_detection", vec!["change"]),
+    };
+    let outcome = match event {
+        SecurityEvent::Response(e) if e.succeeded => "success",
+        SecurityEvent::Response(e) if e.outcome == "refused" || e.outcome == "failed" => "failure",
+        SecurityEvent::Snapshot(SnapshotEvent::Verified { valid, .. }) => if *valid { "success" } else { "failure" },
+        _ => "unknown",
+    };
+
+    let mut document = json!({
+        "@timestamp": syslog_sink::timestamp(event_time(event)),
+        "ecs": { "version": ECS_VERSION },
+        "message": describe(event),
+        "event": {
+            "kind": kind,
+            "category": [category],
+            "type": types,
+            "action": action(event),
+            "outcome": outcome,
+            "severity": severity(event),
+            "module": "qks",
+            "dataset": format!("qks.{}", event.kind().as_str()),
+        },
+        "host": { "hostname": host },
+        "observer": { "vendor": VENDOR, "product": PRODUCT, "version": env!("CARGO_PKG_VERSION"), "type": "hids" },
+        // The event as qks itself describes it
+        "qks": serde_json::to_value(event).map(|mut v| v["event"].take()).unwrap_or_default(),
+    });
+    if let Some(pid) = event.pid() {
+        document["process"] = json!({ "pid": pid });
+    }
+    if let SecurityEvent::Anomaly(e) = event {
+        document["event"]["risk_score"] = json!(e.score * 100.0);
+        if let Some(exe) = &e.exe {
+            document["process"]["executable"] = json!(exe);
+        }
+        if let Some(parent) = e.process_tree.get(1) {
+            document["process"]["parent"] = json!({ "pid": parent.pid, "name": parent.comm, "executable": parent.exe });
+        }
+    }
+    if let Some(container) = container(event) {
+        document["container"] = json!({ "id": container.id, "image": { "name": container.image }, "labels": container.labels });
+        if let Some(pod) = container.pod {
+            if let Some(name) = &pod.container {
+                document["container"]["name"] = json!(name);
+            }
+            document["orchestrator"] = json!({ "type": "kubernetes", "namespace": pod.namespace, "resource": { "type": "pod", "name": pod.name, "id": pod.uid } });
+        }
+    }
+    if let SecurityEvent::Network(e) = event {
+        if let Some(address) = e.destination.or(e.source) {
+            document["network"] = json!({ "transport": "tcp", "type": if address.is_ipv6() { "ipv6" } else { "ipv4" } });
+        }
+        if let Some(source) = e.source {
+            document["source"] = json!({ "ip": source, "port": e.source_port });
+        }
+        if let Some(destination) = e.destination {
+            document["destination"] = json!({ "ip": destination, "port": e.destination_port });
+        }
+    }
+    if let SecurityEvent::Integrity(e) = event {
+        if let Some(path) = &e.path {
+            document["file"]["path"] = json!(path);
+        }
+        if let Some(actual) = &e.actual {
+            // "sha256:ab12..." as IMA writes it
+            let (algorithm, digest) = actual.split_once(':').unwrap_or(("sha256", actual));
+            document["file"]["hash"][algorithm] = json!(digest);
+        }
+    }
+    if let SecurityEvent::Intel(e) = event {
+        document["threat"] = json!({ "feed": { "name": e.feed }, "indicator": { "provider": e.feed, "description": e.description } });
+        if let Some(exe) = &e.exe {
+            document["process"]["executable"] = json!(exe);
+        }
+        if let Some(path) = &e.path {
+            document["file"]["path"] = json!(path);
+        }
+        match e.indicator_type.as_str() {
+            "hash" => {
+                let (algorithm, digest) = e.matched.split_once(':').unwrap_or(("sha256", &e.matched));
+                document["file"]["hash"][algorithm] = json!(digest);
+                document["threat"]["indicator"]["type"] = json!("file");
+            }
+            indicator_type => {
+                let ipv6 = e.matched.contains(':');
+                document["destination"] = json!({ "ip": e.matched });
+                document["threat"]["indicator"]["ip"] = json!(e.matched);
+                document["threat"]["indicator"]["type"] = json!(match (indicator_type, ipv6) {
+                    ("domain", _) => "domain-name",
+                    (_, true) => "ipv6-addr",
+                    (_, false) => "ipv4-addr",
+                });
+                if indicator_type == "domain" {
+                    document["threat"]["indicator"]["url"] = json!({ "domain": e.indicator });
+                }
+            }
+        }
+    }
+    document
+}
+
+// Twelve characters, as docker and crictl show container IDs
+fn short_id(id: &str) -> &str {
+    id.get(..12).unwrap_or(id)
+}
@@ -0,0 +1,117 @@
+// src/splunk_hec.rs
+// Forwards bus events to a Splunk HTTP Event Collector. Each event becomes
+// one HEC event object (time, host, source, sourcetype, index, event) and a
+// batch is those objects concatenated in one POST, authorized with
+// `Authorization: Splunk <token>`. The event is JSON for the json and ecs
+// formats and a string for cef and leef. Batching, retries and the outage
+// spool are event_forward.rs; here only HEC's answers are sorted into
+// retry or drop. 400 means HEC could not parse the batch, which resending
+// won't change. A bad token (401, 403) is retried: the events are fine and
+// are kept until the token is fixed.
+use crate::config::SplunkSection;
+use crate::event_forward::{Delivery, Destination, ForwardError, SendError};
+use crate::events::SecurityEvent;
+use crate::siem_format::{self, EventFormat};
+use crate::syslog_sink;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::time::{Duration, UNIX_EPOCH};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct SplunkHec {
+    section: SplunkSection,
+    authorization: String,
+    host: String,
+    client: reqwest::Client,
+}
+
+impl SplunkHec {
+    pub fn new(section: &SplunkSection) -> Result<Self, ForwardError> {
+        // Validated to be set when enabled
+        let path = section.token_file.clone().unwrap_or_default();
+        let token = std::fs::read_to_string(&path).map_err(|source| ForwardError::Secret { path, source })?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .danger_accept_invalid_certs(!section.verify_tls)
+            .build()
+            .map_err(|e| ForwardError::Client(e.to_string()))?;
+        Ok(Self {
+            section: section.clone(),
+            authorization: format!("Splunk {}", token.trim()),
+            host: syslog_sink::hostname(),
+            client,
+        })
+    }
+
+    fn sourcetype(&self) -> &str {
+        if let Some(sourcetype) = &self.section.sourcetype {
+            return sourcetype;
+        }
+        match self.section.format {
+            EventFormat::Json => "qks:event",
+            EventFormat::Ecs => "qks:ecs",
+            EventFormat::Cef => "qks:cef",
+            EventFormat::Leef => "qks:leef",
+        }
+    }
+}
+
+impl Destination for SplunkHec {
+    fn name(&self) -> &str {
+        "splunk"
+    }
+
+    fn encode(&self, event: &SecurityEvent) -> Option<String> {
+        let body = match self.section.format {
+            EventFormat::Json => serde_json::to_value(event).ok()?,
+            EventFormat::Ecs => siem_format::ecs(event, &self.host),
+            format => Value::String(siem_format::render(format, event, &self.host)),
+        };
+        let time = siem_format::event_time(event).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut record = json!({
+            "time": time,
+            "host": self.host,
+            "source": self.section.source,
+            "sourcetype": self.sourcetype(),
+            "event": body,
+        });
+        if let Some(index) = &self.section.index {
+            record["index"] = json!(index);
+        }
+        Some(record.to_string())
+    }
+
+    fn send<'a>(&'a self, batch: &'a [String]) -> Delivery<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.section.url)
+                .header(reqwest::header::AUTHORIZATION, &self.authorization)
+                .body(batch.join("\n"))
+                .send()
+                .await
+                .map_err(|e| SendError::Retry(e.to_string()))?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            // HEC explains itself as {"text": "...", "code": N}
+            let text = response.text().await.unwrap_or_default();
+            let reason = serde_json::from_str::<Value>(&text)
+                .ok()
+                .and_then(|body| body["text"].as_str().map(str::to_string))
+                .unwrap_or(text);
+            let reason = format!("{}: {}", status, reason);
+            match status {
+                StatusCode::BAD_REQUEST => Err(SendError::Reject(reason)),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    tracing::error!("Splunk HEC refuses the token: {}", reason);
+                    Err(SendError::Retry(reason))
+                }
+                _ if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS => Err(SendError::Reject(reason)),
+                _ => Err(SendError::Retry(reason)),
+            }
+        })
+    }
+}
@@ -1,58 +1,716 @@
 // src/ml_detector.rs
-use tensorflow as tf;
-use std::path::Path;
-use serde_json::Value;
+use crate::baseline_model::OnlineBaseline;
+use crate::behavior_profiles::{BehaviorProfiles, ProfileKey};
+use crate::drift_monitor::{DriftMonitor, DriftReference, DriftThresholds, ModelDrift};
+use crate::inference_backend::{self, InferenceBackend, InferenceError, InferenceOptions};
+use crate::model_signing::{self, ModelTrust};
+use crate::syscall_embedding::SyscallEmbedding;
+use crate::threshold_calibration::CalibratedThresholds;
+use crate::tpm;
+use serde::{Deserialize, Serialize};
 use ring::hmac;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
 pub struct MLAnomalyDetector {
-    model: tf::SavedModelBundle,
-    session: tf::Session,
-    feature_scaler: FeatureScaler,
+    // Shared with the model watcher; an inference holds the lock, so a swap
+    // waits for in-flight inferences to drain
+    // None until a trained model is installed; the baseline scores meanwhile
+    model: Arc<Mutex<Option<LoadedModel>>>,
+    model_version: Arc<AtomicU64>,
+    // Signers whose models may be loaded, here and on every reload
+    trust: Arc<ModelTrust>,
+    // Device/threads/latency budget for this and later loads
+    options: Arc<Mutex<InferenceOptions>>,
+    // Shared with the model watcher, which validates reloads against it
+    feature_set: Arc<Mutex<FeatureSet>>,
+    baseline: OnlineBaseline,
+    profiles: Option<BehaviorProfiles>,
+    drift: Option<DriftMonitor>,
 }
 
-#[derive(Default)]
-struct FeatureScaler {
+// A model and the scaler it was trained with are swapped as one unit
+struct LoadedModel {
+    backend: Box<dyn InferenceBackend>,
+    scaler: FeatureScaler,
+    drift_reference: Option<DriftReference>,
+    thresholds: Option<CalibratedThresholds>,
+    // Appended to the features when the model was trained with one
+    embedding: Option<Arc<SyscallEmbedding>>,
+    schema: Option<FeatureSchema>,
+}
+
+pub type FeatureVector = Vec<f32>;
+
+// Bump whenever extract_features changes shape or meaning; exported
+// training data and models record it
+pub const FEATURE_SCHEMA_VERSION: u32 = 1;
+
+// Order of extract_features' output
+pub const FEATURE_NAMES: [&str; 8] = [
+    "syscall count",
+    "syscall entropy",
+    "mean syscall latency",
+    "syscall latency variance",
+    "privilege level",
+    "child processes",
+    "resource usage",
+    "signature similarity",
+];
+
+// Reduced drops the features that cost the most to compute (latency
+// variance, procfs resource usage, HMAC signature similarity) for small
+// hosts; models must be trained on the same set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureSet {
+    Full,
+    Reduced,
+}
+
+impl FeatureSet {
+    pub fn indices(&self) -> &'static [usize] {
+        match self {
+            FeatureSet::Full => &[0, 1, 2, 3, 4, 5, 6, 7],
+            FeatureSet::Reduced => &[0, 1, 2, 4, 5],
+        }
+    }
+    
+    pub fn names(&self) -> Vec<&'static str> {
+        self.indices().iter().map(|&i| FEATURE_NAMES[i]).collect()
+    }
+}
+
+// Ordered feature names a model was trained on, shipped next to it as
+// schema.json. Checked at load so a model is never fed rows whose columns
+// mean something else.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSchema {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+impl FeatureSchema {
+    // What extract_features produces for this set and embedding width
+    pub fn current(feature_set: FeatureSet, embedding_dim: usize) -> Self {
+        let mut features: Vec<String> = feature_set.names().iter().map(|n| n.to_string()).collect();
+        features.extend((0..embedding_dim).map(|i| format!("syscall embedding {}", i)));
+        Self { version: FEATURE_SCHEMA_VERSION, features }
+    }
+    
+    // The feature set whose names this schema starts with, if any
+    pub fn feature_set(&self) -> Option<FeatureSet> {
+        [FeatureSet::Full, FeatureSet::Reduced].into_iter().find(|set| {
+            let names = set.names();
+            self.features.len() >= names.len()
+                && names.iter().zip(&self.features).all(|(a, b)| a == b)
+                && self.features.get(names.len()).map_or(true, |next| next.starts_with("syscall embedding"))
+        })
+    }
+    
+    // Every disagreeing position, by name
+    pub fn check(&self, extractor: &FeatureSchema) -> Result<(), InferenceError> {
+        if self.version != extractor.version {
+            return Err(InferenceError::FeatureNames(format!(
+                "model uses feature schema v{}, extractor produces v{}", self.version, extractor.version
+            )));
+        }
+        
+        let width = self.features.len().max(extractor.features.len());
+        let mismatches: Vec<String> = (0..width)
+            .filter(|&i| self.features.get(i) != extractor.features.get(i))
+            .map(|i| format!(
+                "#{}: model '{}', extractor '{}'",
+                i,
+                self.features.get(i).map_or("<none>", |n| n.as_str()),
+                extractor.features.get(i).map_or("<none>", |n| n.as_str()),
+            ))
+            .collect();
+        if !mismatches.is_empty() {
+            return Err(InferenceError::FeatureNames(mismatches.join("; ")));
+        }
+        Ok(())
+    }
+    
+    pub fn sidecar_path(model_path: &Path) -> PathBuf {
+        if model_path.is_dir() {
+            model_path.join("schema.json")
+        } else {
+            model_path.with_extension("schema.json")
+        }
+    }
+    
+    pub fn save(&self, model_path: &Path) -> Result<(), InferenceError> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| InferenceError::Model(e.to_string()))?;
+        std::fs::write(Self::sidecar_path(model_path), json).map_err(|e| InferenceError::Model(e.to_string()))
+    }
+    
+    pub fn load(model_path: &Path) -> Result<Option<Self>, InferenceError> {
+        let path = Self::sidecar_path(model_path);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(InferenceError::Model(format!("{}: {}", path.display(), e))),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| InferenceError::Model(format!("{}: {}", path.display(), e)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct FeatureContribution {
+    pub feature: &'static str,
+    pub value: f32,
+    pub expected: f32,
+    // Share of the row's total reconstruction error (or deviation) in [0, 1]
+    pub share: f32,
+}
+
+impl std::fmt::Display for FeatureContribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:.2} vs expected {:.2} ({:.0}%)", self.feature, self.value, self.expected, self.share * 100.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct AnomalyExplanation {
+    pub score: f32,
+    pub mode: ScoringMode,
+    // Largest contributors first
+    pub contributions: Vec<FeatureContribution>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnomalyResult {
+    pub score: f32,
+    pub reconstruction: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+pub enum ScoringMode {
+    Baseline,
+    Model,
+    // Scored against the process's own executable/image profile
+    Profile,
+}
+
+// Per-feature standardization fitted on the training set; saved next to
+// the model as scaler.json (inside a SavedModel directory) or
+// <model>.scaler.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeatureScaler {
     means: Vec<f32>,
     stds: Vec<f32>,
 }
 
-impl MLAnomalyDetector {
-    pub fn new(model_path: &str) -> Result<Self, tf::Status> {
-        // Load pre-trained TensorFlow model
-        let mut graph = tf::Graph::new();
-        let session = tf::Session::new(&tf::SessionOptions::new(), &graph)?;
+impl FeatureScaler {
+    pub fn fit(dataset: &[Vec<f32>]) -> Result<Self, InferenceError> {
+        let width = dataset.first().map_or(0, |row| row.len());
+        if width == 0 {
+            return Err(InferenceError::Model("cannot fit scaler on an empty dataset".into()));
+        }
+        if let Some(row) = dataset.iter().find(|row| row.len() != width) {
+            return Err(InferenceError::Schema { scaler: width, model: row.len() });
+        }
         
-        let tags = vec!["serve".to_string()];
-        let model = tf::SavedModelBundle::load(&session, tags, Path::new(model_path))?;
+        let n = dataset.len() as f64;
+        let mut means = vec![0.0f64; width];
+        for row in dataset {
+            for (mean, &x) in means.iter_mut().zip(row) {
+                *mean += x as f64 / n;
+            }
+        }
+        let mut vars = vec![0.0f64; width];
+        for row in dataset {
+            for ((var, mean), &x) in vars.iter_mut().zip(&means).zip(row) {
+                *var += (x as f64 - mean).powi(2) / n;
+            }
+        }
         
         Ok(Self {
-            model,
-            session,
-            feature_scaler: FeatureScaler::default(),
+            means: means.iter().map(|&m| m as f32).collect(),
+            // Constant features pass through centred rather than blowing up
+            stds: vars.iter().map(|&v| if v > 1e-12 { v.sqrt() as f32 } else { 1.0 }).collect(),
         })
     }
     
-    pub fn detect_anomaly(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), tf::Status> {
-        // Prepare input tensor
-        let input_tensor = tf::Tensor::new(&[1, features.len() as u64])
-            .with_values(features)?;
+    pub fn width(&self) -> usize {
+        self.means.len()
+    }
+    
+    pub fn inverse_transform(&self, scaled: &[f32]) -> Vec<f32> {
+        if self.means.len() != scaled.len() {
+            return scaled.to_vec();
+        }
+        scaled.iter()
+            .zip(self.means.iter().zip(&self.stds))
+            .map(|(&x, (&mean, &std))| x * std + mean)
+            .collect()
+    }
+    
+    // An unfitted scaler is the identity
+    pub fn transform(&self, features: &[f32]) -> Result<Vec<f32>, InferenceError> {
+        if self.means.is_empty() {
+            return Ok(features.to_vec());
+        }
+        if features.len() != self.means.len() {
+            return Err(InferenceError::Schema { scaler: self.means.len(), model: features.len() });
+        }
+        
+        Ok(features.iter()
+            .zip(self.means.iter().zip(&self.stds))
+            .map(|(&x, (&mean, &std))| (x - mean) / std)
+            .collect())
+    }
+    
+    pub fn sidecar_path(model_path: &Path) -> PathBuf {
+        if model_path.is_dir() {
+            model_path.join("scaler.json")
+        } else {
+            model_path.with_extension("scaler.json")
+        }
+    }
+    
+    pub fn save(&self, model_path: &Path) -> Result<(), InferenceError> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| InferenceError::Model(e.to_string()))?;
+        std::fs::write(Self::sidecar_path(model_path), json).map_err(|e| InferenceError::Model(e.to_string()))
+    }
+    
+    // Missing file: unscaled model (older exports); anything else is an error
+    pub fn load(model_path: &Path) -> Result<Option<Self>, InferenceError> {
+        let path = Self::sidecar_path(model_path);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(InferenceError::Model(format!("{}: {}", path.display(), e))),
+        };
+        
+        let scaler: Self = serde_json::from_slice(&data)
+            .map_err(|e| InferenceError::Model(format!("{}: {}", path.display(), e)))?;
+        if scaler.stds.len() != scaler.means.len() || scaler.stds.iter().any(|&s| s <= 0.0 || !s.is_finite()) {
+            return Err(InferenceError::Model(format!("{}: malformed scaler", path.display())));
+        }
+        Ok(Some(scaler))
+    }
+}
+
+impl MLAnomalyDetector {
+    pub fn new(model_path: &str, trust: ModelTrust) -> Result<Self, InferenceError> {
+        Self::new_with_options(model_path, trust, InferenceOptions::default())
+    }
+    
+    pub fn new_with_options(model_path: &str, trust: ModelTrust, options: InferenceOptions) -> Result<Self, InferenceError> {
+        // Load pre-trained model with whichever runtime was compiled in
+        // The first model picks the feature set; reloads must then match it
+        let model = Self::load_model(model_path, &trust, &options, None)?;
+        tracing::info!("Loaded anomaly model {} ({} backend)", model_path, model.backend.name());
+        let feature_set = model.schema.as_ref().and_then(|schema| schema.feature_set()).unwrap_or(FeatureSet::Full);
+        
+        Ok(Self {
+            model: Arc::new(Mutex::new(Some(model))),
+            model_version: Arc::new(AtomicU64::new(1)),
+            trust: Arc::new(trust),
+            options: Arc::new(Mutex::new(options)),
+            feature_set: Arc::new(Mutex::new(feature_set)),
+            baseline: OnlineBaseline::new(),
+            profiles: None,
+            drift: None,
+        })
+    }
+    
+    // No model yet: learn this host's baseline and score against it until
+    // reload() or the model watcher installs one
+    pub fn learning(trust: ModelTrust) -> Self {
+        Self {
+            model: Arc::new(Mutex::new(None)),
+            model_version: Arc::new(AtomicU64::new(0)),
+            trust: Arc::new(trust),
+            options: Arc::new(Mutex::new(InferenceOptions::default())),
+            feature_set: Arc::new(Mutex::new(FeatureSet::Full)),
+            baseline: OnlineBaseline::new(),
+            profiles: None,
+            drift: None,
+        }
+    }
+    
+    pub fn scoring_mode(&self) -> ScoringMode {
+        if self.model.lock().unwrap().is_some() {
+            ScoringMode::Model
+        } else {
+            ScoringMode::Baseline
+        }
+    }
+    
+    pub fn detect_anomaly(&mut self, features: &[f32]) -> Result<(f32, Vec<f32>), InferenceError> {
+        if let Some(model) = self.model.lock().unwrap().as_mut() {
+            let scaled = model.scaler.transform(features)?;
+            if let (Some(monitor), Some(reference)) = (self.drift.as_mut(), model.drift_reference.as_ref()) {
+                monitor.observe(reference, self.model_version.load(Ordering::SeqCst), features);
+            }
+            let result = model.backend.infer(&scaled);
+            // Keep the baseline warm: it takes over when inference sheds load
+            self.baseline.update(features);
+            return result;
+        }
         
-        // Run inference
-        let mut args = tf::SessionRunArgs::new();
-        args.add_feed(&self.model.graph.operation_by_name_required("input")?, 0, &input_tensor);
+        // Score first so a sample never vouches for itself
+        let result = self.baseline.score(features);
+        self.baseline.update(features);
+        Ok(result)
+    }
+    
+    // Score a row and attribute it to named features, without learning from
+    // it. Model errors are compared in scaled space so large-magnitude
+    // features don't drown the rest; `expected` is mapped back to raw units.
+    pub fn explain_anomaly(&self, features: &[f32], top: usize) -> Result<AnomalyExplanation, InferenceError> {
+        let (score, mode, expected, errors) = if let Some(model) = self.model.lock().unwrap().as_mut() {
+            let scaled = model.scaler.transform(features)?;
+            let (score, reconstruction) = model.backend.infer(&scaled)?;
+            if reconstruction.len() != scaled.len() {
+                return Err(InferenceError::Schema { scaler: scaled.len(), model: reconstruction.len() });
+            }
+            let errors: Vec<f32> = scaled.iter().zip(&reconstruction).map(|(x, r)| (x - r).powi(2)).collect();
+            (score, ScoringMode::Model, model.scaler.inverse_transform(&reconstruction), errors)
+        } else {
+            let (score, expected) = self.baseline.score(features);
+            (score, ScoringMode::Baseline, expected, self.baseline.deviations(features))
+        };
         
-        let anomaly_op = self.model.graph.operation_by_name_required("anomaly_score")?;
-        let reconstruction_op = self.model.graph.operation_by_name_required("reconstruction")?;
+        let names = self.feature_set().names();
+        let total: f32 = errors.iter().sum();
+        let mut contributions: Vec<FeatureContribution> = errors.iter()
+            .enumerate()
+            .filter(|(i, _)| *i < features.len() && *i < expected.len())
+            .map(|(i, &error)| FeatureContribution {
+                feature: names.get(i).copied().unwrap_or("syscall embedding"),
+                value: features[i],
+                expected: expected[i],
+                share: if total > 0.0 { error / total } else { 0.0 },
+            })
+            .collect();
+        contributions.sort_by(|a, b| b.share.total_cmp(&a.share));
+        contributions.truncate(top);
         
-        let anomaly_token = args.request_fetch(&anomaly_op, 0);
-        let reconstruction_token = args.request_fetch(&reconstruction_op, 0);
+        Ok(AnomalyExplanation { score, mode, contributions })
+    }
+    
+    // One [N, F] inference for a whole scan tick; results are in input order
+    pub fn detect_anomalies_batch(&mut self, batch: &[FeatureVector]) -> Result<Vec<AnomalyResult>, InferenceError> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
         
-        self.session.run(&mut args)?;
+        let results = if let Some(model) = self.model.lock().unwrap().as_mut() {
+            let scaled = batch.iter()
+                .map(|features| model.scaler.transform(features))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let (Some(monitor), Some(reference)) = (self.drift.as_mut(), model.drift_reference.as_ref()) {
+                let version = self.model_version.load(Ordering::SeqCst);
+                for features in batch {
+                    monitor.observe(reference, version, features);
+                }
+            }
+            let results = model.backend.infer_batch(&scaled)?;
+            for features in batch {
+                self.baseline.update(features);
+            }
+            results
+        } else {
+            // Score the whole tick before learning from it, as in detect_anomaly
+            let results: Vec<_> = batch.iter().map(|features| self.baseline.score(features)).collect();
+            for features in batch {
+                self.baseline.update(features);
+            }
+            results
+        };
         
-        let anomaly_score: f32 = args.fetch(anomaly_token)?[0];
-        let reconstruction: Vec<f32> = args.fetch(reconstruction_token)?;
+        Ok(results.into_iter()
+            .map(|(score, reconstruction)| AnomalyResult { score, reconstruction })
+            .collect())
+    }
+    
+    // Compare model inputs with the training distribution of models that
+    // ship a drift reference; ModelDrift events arrive on the receiver
+    pub fn enable_drift_monitor(&mut self, thresholds: DriftThresholds) -> tokio::sync::mpsc::UnboundedReceiver<ModelDrift> {
+        let (monitor, events) = DriftMonitor::new(thresholds);
+        self.drift = Some(monitor);
+        events
+    }
+    
+    pub fn set_behavior_profiles(&mut self, profiles: BehaviorProfiles) {
+        self.profiles = Some(profiles);
+    }
+    
+    // The statistical detector, learning alongside any model
+    pub fn baseline(&self) -> &OnlineBaseline {
+        &self.baseline
+    }
+    
+    pub fn baseline_mut(&mut self) -> &mut OnlineBaseline {
+        &mut self.baseline
+    }
+    
+    pub fn behavior_profiles(&self) -> Option<&BehaviorProfiles> {
+        self.profiles.as_ref()
+    }
+    
+    // Judge a process against its own executable's profile once that has
+    // warmed up; until then, and without profiles, the global scorer decides.
+    // The profile keeps learning either way.
+    pub fn detect_process_anomaly(
+        &mut self,
+        key: &ProfileKey,
+        syscall_sequence: &[u32],
+        timing: &[u64],
+        process_metadata: &ProcessMetadata,
+    ) -> Result<(f32, ScoringMode), InferenceError> {
+        if let Some(profiles) = &self.profiles {
+            if let Some(profile_score) = profiles.observe(key, syscall_sequence, timing, process_metadata) {
+                return Ok((profile_score.score, ScoringMode::Profile));
+            }
+        }
         
-        Ok((anomaly_score, reconstruction))
+        let features = self.extract_features(syscall_sequence, timing, process_metadata);
+        let mode = self.scoring_mode();
+        let (score, _) = self.detect_anomaly(&features)?;
+        Ok((score, mode))
+    }
+    
+    // Trigram novelty of a syscall stream against what this host has seen;
+    // keeps learning in both modes
+    pub fn observe_syscalls(&mut self, syscall_sequence: &[u32]) -> f32 {
+        let novelty = self.baseline.sequence_novelty(syscall_sequence);
+        self.baseline.update_sequence(syscall_sequence);
+        novelty
+    }
+    
+    // Load and swap in a new model; on failure the current one stays
+    pub fn reload(&self, model_path: &str) -> Result<u64, InferenceError> {
+        let options = *self.options.lock().unwrap();
+        Self::swap_model(&self.model, &self.model_version, &self.trust, &options, self.feature_set(), model_path)
+    }
+    
+    // Refused when the installed model was trained on other features
+    pub fn set_feature_set(&mut self, feature_set: FeatureSet) -> Result<(), InferenceError> {
+        if let Some(model) = self.model.lock().unwrap().as_ref() {
+            if let Some(schema) = &model.schema {
+                let embedding_dim = model.embedding.as_ref().map_or(0, |e| e.dim());
+                schema.check(&FeatureSchema::current(feature_set, embedding_dim))?;
+            }
+        }
+        *self.feature_set.lock().unwrap() = feature_set;
+        Ok(())
+    }
+    
+    pub fn feature_set(&self) -> FeatureSet {
+        *self.feature_set.lock().unwrap()
+    }
+    
+    // The rows extract_features produces right now, embedding included
+    pub fn feature_schema(&self) -> FeatureSchema {
+        let embedding_dim = self.model.lock().unwrap().as_ref()
+            .and_then(|model| model.embedding.as_ref())
+            .map_or(0, |e| e.dim());
+        FeatureSchema::current(self.feature_set(), embedding_dim)
+    }
+    
+    // Takes effect at the next load (reload() or the model watcher)
+    pub fn set_inference_options(&self, options: InferenceOptions) {
+        *self.options.lock().unwrap() = options;
+    }
+    
+    // Thresholds calibrated for the installed model, if it shipped any
+    pub fn thresholds(&self) -> Option<CalibratedThresholds> {
+        self.model.lock().unwrap().as_ref().and_then(|model| model.thresholds.clone())
+    }
+    
+    pub fn model_version(&self) -> u64 {
+        self.model_version.load(Ordering::SeqCst)
+    }
+    
+    // Model plus its scaler, refusing pairs whose feature widths disagree
+    // feature_set: what the extractor is producing, or None to accept the
+    // set the model's schema names
+    fn load_model(
+        model_path: &str,
+        trust: &ModelTrust,
+        options: &InferenceOptions,
+        feature_set: Option<FeatureSet>,
+    ) -> Result<LoadedModel, InferenceError> {
+        let original = inference_backend::resolve_model_path(model_path, options)?;
+        // Before any runtime parses a byte of it; everything below reads the
+        // verified copy, never the original files
+        let verified = model_signing::stage_verified(Path::new(&original), trust)?;
+        let staged = verified.path().to_string_lossy().into_owned();
+        let model_path = staged.as_str();
+        let backend = inference_backend::load_backend(model_path, options)?;
+        let scaler = match FeatureScaler::load(Path::new(model_path))? {
+            Some(scaler) => scaler,
+            None => {
+                tracing::warn!("No feature scaler next to {}; features go to the model unscaled", original);
+                FeatureScaler::default()
+            }
+        };
+        
+        if let Some(width) = backend.input_width() {
+            if scaler.width() != 0 && scaler.width() != width {
+                return Err(InferenceError::Schema { scaler: scaler.width(), model: width });
+            }
+        }
+        
+        let embedding = SyscallEmbedding::load_sidecar(Path::new(model_path))
+            .map_err(|e| InferenceError::Model(format!("{}: {}", original, e)))?;
+        if let Some(embedding) = &embedding {
+            let width = backend.input_width().unwrap_or(scaler.width());
+            if width != 0 {
+                Self::check_embedding_width(embedding, width)
+                    .map_err(|e| InferenceError::Model(format!("{}: {}", original, e)))?;
+            }
+        }
+        
+        let schema = FeatureSchema::load(Path::new(model_path))?;
+        match &schema {
+            Some(schema) => {
+                let feature_set = feature_set.or_else(|| schema.feature_set()).unwrap_or(FeatureSet::Full);
+                let embedding_dim = embedding.as_ref().map_or(0, |e| e.dim());
+                if let Err(e) = schema.check(&FeatureSchema::current(feature_set, embedding_dim)) {
+                    tracing::error!("Refusing model {}: {}", original, e);
+                    return Err(e);
+                }
+            }
+            None => tracing::warn!("Model {} ships no feature schema; feature order is unchecked", original),
+        }
+        
+        let drift_reference = DriftReference::load(Path::new(model_path))
+            .map_err(|e| InferenceError::Model(e.to_string()))?;
+        if let Some(reference) = &drift_reference {
+            if scaler.width() != 0 && reference.width() != scaler.width() {
+                return Err(InferenceError::Schema { scaler: scaler.width(), model: reference.width() });
+            }
+        }
+        
+        let thresholds = CalibratedThresholds::load(Path::new(model_path))
+            .map_err(|e| InferenceError::Model(e.to_string()))?;
+        if thresholds.is_none() {
+            tracing::warn!("Model {} has no calibrated thresholds; run calibration before alerting on it", original);
+        }
+        
+        tpm::measure_file("model_load", verified.path());
+        Ok(LoadedModel { backend, scaler, drift_reference, thresholds, embedding: embedding.map(Arc::new), schema })
+    }
+    
+    // Whatever the embedding leaves of the model input must be a known
+    // feature set
+    fn check_embedding_width(embedding: &SyscallEmbedding, width: usize) -> Result<(), crate::syscall_embedding::EmbeddingError> {
+        let mut result = Ok(());
+        for set in [FeatureSet::Full, FeatureSet::Reduced] {
+            result = embedding.validate_dim(width.saturating_sub(set.indices().len()));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+    
+    fn swap_model(
+        model: &Mutex<Option<LoadedModel>>,
+        version: &AtomicU64,
+        trust: &ModelTrust,
+        options: &InferenceOptions,
+        feature_set: FeatureSet,
+        model_path: &str,
+    ) -> Result<u64, InferenceError> {
+        // Loading is slow; do it before taking the lock so scoring continues
+        let replacement = Self::load_model(model_path, trust, options, Some(feature_set))?;
+        
+        *model.lock().unwrap() = Some(replacement);
+        let version = version.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::info!("Swapped in anomaly model {} (version {})", model_path, version);
+        Ok(version)
+    }
+    
+    // Poll the model on disk and reload once a change has settled (same
+    // fingerprint on two consecutive polls, so half-copied models are skipped)
+    pub fn start_model_watch(&self, model_path: &str, interval_secs: u64) -> tokio::task::JoinHandle<()> {
+        let model = self.model.clone();
+        let version = self.model_version.clone();
+        let trust = self.trust.clone();
+        let options = self.options.clone();
+        let feature_set = self.feature_set.clone();
+        let model_path = model_path.to_string();
+        
+        tokio::spawn(async move {
+            let mut loaded = Self::watched_fingerprint(Path::new(&model_path));
+            let mut pending: Option<SystemTime> = None;
+            
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(interval_secs)).await;
+                
+                let current = Self::watched_fingerprint(Path::new(&model_path));
+                if current.is_none() || current == loaded {
+                    pending = None;
+                    continue;
+                }
+                if pending != current {
+                    pending = current;
+                    continue;
+                }
+                
+                let path = model_path.clone();
+                let model = model.clone();
+                let version = version.clone();
+                let trust = trust.clone();
+                let load_options = *options.lock().unwrap();
+                let load_features = *feature_set.lock().unwrap();
+                let result = tokio::task::spawn_blocking(move || {
+                    Self::swap_model(&model, &version, &trust, &load_options, load_features, &path)
+                }).await;
+                
+                match result {
+                    Ok(Ok(_)) => loaded = current,
+                    Ok(Err(e)) => tracing::warn!("Model reload from {} failed, keeping current model: {}", model_path, e),
+                    Err(e) => tracing::warn!("Model reload task failed: {}", e),
+                }
+                pending = None;
+            }
+        })
+    }
+    
+    // A single-file model's scaler and signature live beside it, outside
+    // the fingerprint
+    fn watched_fingerprint(model_path: &Path) -> Option<SystemTime> {
+        let model = Self::model_fingerprint(model_path)?;
+        Some(
+            [
+                FeatureScaler::sidecar_path(model_path),
+                SyscallEmbedding::sidecar_path(model_path),
+                FeatureSchema::sidecar_path(model_path),
+                model_signing::signature_path(model_path),
+            ]
+                .iter()
+                .filter_map(|sidecar| Self::model_fingerprint(sidecar))
+                .fold(model, SystemTime::max),
+        )
+    }
+    
+    // Newest modification time of the model file, or of anything inside a
+    // SavedModel directory
+    fn model_fingerprint(path: &Path) -> Option<SystemTime> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let mut newest = metadata.modified().ok()?;
+        
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(path).ok()?.filter_map(|e| e.ok()) {
+                if let Some(modified) = Self::model_fingerprint(&entry.path()) {
+                    newest = newest.max(modified);
+                }
+            }
+        }
+        
+        Some(newest)
     }
     
     pub fn extract_features(
@@ -62,6 +720,8 @@ impl MLAnomalyDetector {
         process_metadata: &ProcessMetadata
     ) -> Vec<f32> {
         let mut features = Vec::new();
+        // Skip the expensive ones outright rather than computing and dropping
+        let full = self.feature_set() == FeatureSet::Full;
         
         // Temporal features
         features.push(syscall_sequence.len() as f32);
@@ -70,19 +730,40 @@ impl MLAnomalyDetector {
         // Timing features
         let avg_time = timing.iter().sum::<u64>() as f32 / timing.len() as f32;
         features.push(avg_time);
-        features.push(Self::calculate_variance(timing));
+        if full {
+            features.push(Self::calculate_variance(timing));
+        }
         
         // Process context features
         features.push(process_metadata.privilege_level as f32);
         features.push(process_metadata.children_count as f32);
-        features.push(process_metadata.resource_usage);
+        if full {
+            features.push(process_metadata.resource_usage);
+            
+            // Behavioral signature similarity
+            features.push(self.calculate_signature_similarity(&process_metadata.signature));
+        }
         
-        // Behavioral signature similarity
-        features.push(self.calculate_signature_similarity(&process_metadata.signature));
+        // Where the sequence sits in syscall space, so read/pread64/readv
+        // variants of one attack look alike
+        let embedding = self.model.lock().unwrap().as_ref().and_then(|model| model.embedding.clone());
+        if let Some(embedding) = embedding {
+            features.extend(embedding.embed_sequence(syscall_sequence));
+        }
         
         features
     }
     
+    fn calculate_variance(timing: &[u64]) -> f32 {
+        if timing.is_empty() {
+            return 0.0;
+        }
+        let mean = timing.iter().sum::<u64>() as f32 / timing.len() as f32;
+        timing.iter()
+            .map(|&t| (t as f32 - mean).powi(2))
+            .sum::<f32>() / timing.len() as f32
+    }
+    
     fn calculate_entropy(sequence: &[u32]) -> f32 {
         use std::collections::HashMap;
         let mut counts = HashMap::new();
@@ -1,12 +1,148 @@
 // src/memory_randomizer.rs
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
-use rand::{Rng, SeedableRng};
-use rand::rngs::StdRng;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use crate::arch_profile::ArchProfile;
+use crate::container_exclusions::ContainerExclusions;
+use crate::entropy_audit::{self, EntropyAudit};
+use crate::freezer;
+use crate::heap_fixup::HeapFixups;
+use crate::kubernetes;
+use crate::layout_plan::{ExcludedRegion, LayoutPlan, PlannedMove};
+use crate::layout_verification::{self, LayoutVerificationError};
+use crate::metrics;
+use crate::process_maps::{self, ProcessMaps};
+use crate::randomization_policy::{RandomizationPolicy, RandomizationProfile};
+use crate::ebpf_monitor::MprotectEvent;
+use crate::recovery_snapshot::MemoryLayoutSnapshot;
+use crate::remap_engine::{HugepagePolicy, PointerPatch, ProtectionChange, RegionKind, RegionMove, RemapEngine};
+use crate::secure_random::{SecureRandomSource, SystemRandomSource};
+use crate::vdso_relocation::{self, VdsoSupport};
+
+// Give up rather than loop forever on a crowded address space
+const MAX_COLLISION_REDRAWS: u32 = 64;
+
+// Thawed regardless after this, should an apply wedge
+const FREEZE_HOLD: Duration = Duration::from_secs(30);
+
+// Slow subscribers lag (and are told so) rather than blocking randomization
+const LAYOUT_EVENT_CAPACITY: usize = 256;
+
+// Saved frame pointers, argv/envp/auxv and __environ point into the stack,
+// and nothing yet finds them all the way heap_fixup finds heap pointers
+const STACK_MOVE_REFUSED: &str = "no pointer fixups for the stack; it stays in place";
 
 pub struct MemoryRandomizer {
     layouts: Arc<RwLock<HashMap<u32, MemoryLayout>>>,
-    rng: StdRng,
+    rng: Box<dyn SecureRandomSource>,
+    entropy: EntropyConfig,
+    arch: ArchProfile,
+    wx_policy: WxPolicy,
+    hugepage_policy: HugepagePolicy,
+    policy: RandomizationPolicy,
+    containers: Option<ContainerExclusions>,
+    heap_fixups: HeapFixups,
+    vdso_support: HashMap<u32, VdsoSupport>,
+    layout_events: broadcast::Sender<LayoutChangeEvent>,
+}
+
+// What caused a layout to change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum LayoutTrigger {
+    Initial,
+    Regeneration,
+    Scheduled,
+    SnapshotRestore,
+    // The recorded layout was applied to the live process
+    Applied,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LayoutChangeEvent {
+    pub pid: u32,
+    pub trigger: LayoutTrigger,
+    // "stack", "heap", "mmap", "vdso"
+    pub regions: Vec<&'static str>,
+    pub regeneration_count: u32,
+    pub layout_hash: [u8; 32],
+    pub timestamp: u64,
+}
+
+// How recorded layouts are brought back after a snapshot restore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutRestoreMode {
+    // Exactly the layout captured in the snapshot
+    Recorded,
+    // A new draw, so an attacker who saw the old layout gains nothing
+    Fresh,
+}
+
+// What to do with mappings that are both writable and executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxPolicy {
+    Disabled,
+    StripWrite,
+    StripExec,
+}
+
+// Random bits applied per region (page granularity)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntropyConfig {
+    pub stack_bits: u32,
+    pub heap_bits: u32,
+    pub mmap_bits: u32,
+    pub vdso_bits: u32,
+}
+
+impl EntropyConfig {
+    // Largest setting the profile's windows can hold
+    pub fn maximum(arch: &ArchProfile) -> Self {
+        Self {
+            stack_bits: Self::max_bits(arch, arch.stack_window),
+            heap_bits: Self::max_bits(arch, arch.heap_window),
+            mmap_bits: Self::max_bits(arch, arch.mmap_window),
+            vdso_bits: arch.vdso_max_bits,
+        }
+    }
+    
+    // Page-granular bits that fit inside a window
+    pub fn max_bits(arch: &ArchProfile, window: (u64, u64)) -> u32 {
+        let size = window.1 - window.0 + 1;
+        (63 - size.leading_zeros()).saturating_sub(arch.page_shift)
+    }
+    
+    pub fn validate(&self, arch: &ArchProfile) -> Result<(), String> {
+        let max = Self::maximum(arch);
+        let checks = [
+            ("stack", self.stack_bits, max.stack_bits),
+            ("heap", self.heap_bits, max.heap_bits),
+            ("mmap", self.mmap_bits, max.mmap_bits),
+            ("vdso", self.vdso_bits, max.vdso_bits),
+        ];
+        
+        for (region, bits, max) in checks {
+            if bits > max {
+                return Err(format!(
+                    "{} entropy of {} bits exceeds the {} bits available on {:?}",
+                    region, bits, max, arch.arch
+                ));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Narrower ABIs (32-bit compat) can't hold the native setting
+    pub fn clamped_to(&self, arch: &ArchProfile) -> Self {
+        let max = Self::maximum(arch);
+        Self {
+            stack_bits: self.stack_bits.min(max.stack_bits),
+            heap_bits: self.heap_bits.min(max.heap_bits),
+            mmap_bits: self.mmap_bits.min(max.mmap_bits),
+            vdso_bits: self.vdso_bits.min(max.vdso_bits),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,57 +158,440 @@ pub struct MemoryLayout {
 
 impl MemoryRandomizer {
     pub fn new() -> Self {
+        let arch = ArchProfile::detect();
+        
         Self {
             layouts: Arc::new(RwLock::new(HashMap::new())),
-            rng: StdRng::from_entropy(),
+            rng: Box::new(SystemRandomSource::new()),
+            entropy: EntropyConfig::maximum(&arch),
+            arch,
+            wx_policy: WxPolicy::Disabled,
+            hugepage_policy: HugepagePolicy::Align,
+            policy: RandomizationPolicy::default(),
+            containers: None,
+            heap_fixups: HeapFixups::new(),
+            vdso_support: HashMap::new(),
+            layout_events: broadcast::channel(LAYOUT_EVENT_CAPACITY).0,
         }
     }
     
+    pub fn with_arch(mut self, arch: ArchProfile) -> Result<Self, String> {
+        arch.validate()?;
+        self.entropy = self.entropy.clamped_to(&arch);
+        self.arch = arch;
+        Ok(self)
+    }
+    
+    // Seeded sources make layouts reproducible; never use one in production
+    pub fn with_random_source(mut self, source: Box<dyn SecureRandomSource>) -> Self {
+        self.rng = source;
+        self
+    }
+    
+    pub fn with_entropy(mut self, entropy: EntropyConfig) -> Result<Self, String> {
+        self.set_entropy(entropy)?;
+        Ok(self)
+    }
+    
+    pub fn set_entropy(&mut self, entropy: EntropyConfig) -> Result<(), String> {
+        entropy.validate(&self.arch)?;
+        self.entropy = entropy;
+        Ok(())
+    }
+    
+    pub fn export_layouts(&self) -> Vec<MemoryLayoutSnapshot> {
+        let layouts = self.layouts.read().unwrap();
+        let mut exported: Vec<MemoryLayoutSnapshot> = layouts.values()
+            .map(|layout| MemoryLayoutSnapshot {
+                pid: layout.pid,
+                stack_base: layout.stack_base,
+                heap_base: layout.heap_base,
+                mmap_base: layout.mmap_base,
+                vdso_offset: layout.vdso_offset,
+                layout_hash: layout.layout_hash,
+                regeneration_count: layout.regeneration_count,
+                identity: process_maps::identity(layout.pid),
+            })
+            .collect();
+        
+        exported.sort_by_key(|l| l.pid);
+        exported
+    }
+    
+    pub fn import_layouts(&mut self, snapshots: &[MemoryLayoutSnapshot], mode: LayoutRestoreMode) -> Vec<u32> {
+        let mut imported = Vec::new();
+        
+        for snapshot in snapshots {
+            match mode {
+                LayoutRestoreMode::Recorded => {
+                    self.record_layout(MemoryLayout {
+                        pid: snapshot.pid,
+                        stack_base: snapshot.stack_base,
+                        heap_base: snapshot.heap_base,
+                        mmap_base: snapshot.mmap_base,
+                        vdso_offset: snapshot.vdso_offset,
+                        layout_hash: snapshot.layout_hash,
+                        regeneration_count: snapshot.regeneration_count,
+                    }, LayoutTrigger::SnapshotRestore);
+                }
+                LayoutRestoreMode::Fresh => {
+                    let profile = self.profile_for(snapshot.pid);
+                    let mut layout = self.draw_layout(snapshot.pid, profile);
+                    // Keep the history: this is a regeneration, not a new process
+                    layout.regeneration_count = snapshot.regeneration_count + 1;
+                    self.record_layout(layout, LayoutTrigger::SnapshotRestore);
+                    metrics::global().incr("qks_randomizer_layouts_generated_total", &[]);
+                }
+            }
+            imported.push(snapshot.pid);
+        }
+        
+        imported
+    }
+    
+    pub fn set_hugepage_policy(&mut self, policy: HugepagePolicy) {
+        self.hugepage_policy = policy;
+    }
+    
+    pub fn set_wx_policy(&mut self, policy: WxPolicy) {
+        self.wx_policy = policy;
+    }
+    
+    pub fn set_policy(&mut self, policy: RandomizationPolicy) {
+        self.policy = policy;
+    }
+    
+    pub fn policy(&self) -> &RandomizationPolicy {
+        &self.policy
+    }
+    
+    // Registry of cooperating allocators; the heap of any other process
+    // is never moved
+    pub fn heap_fixups(&self) -> &HeapFixups {
+        &self.heap_fixups
+    }
+    
+    pub fn set_container_exclusions(&mut self, containers: ContainerExclusions) {
+        self.containers = Some(containers);
+    }
+    
+    // Container annotations first, then the pod's, then the per-binary policy
+    pub fn profile_for(&self, pid: u32) -> RandomizationProfile {
+        self.containers.as_ref()
+            .and_then(|c| c.profile_for_pid(pid))
+            .or_else(|| kubernetes::global().randomization_for(pid))
+            .unwrap_or_else(|| self.policy.profile_for_pid(pid))
+    }
+    
+    pub fn entropy(&self) -> EntropyConfig {
+        self.entropy
+    }
+    
     pub fn randomize_for_pid(&mut self, pid: u32) -> MemoryLayout {
-        let mut layouts = self.layouts.write().unwrap();
+        let profile = self.profile_for(pid);
+        let layout = self.draw_layout(pid, profile);
+        
+        self.record_layout(layout.clone(), LayoutTrigger::Initial);
+        metrics::global().incr("qks_randomizer_layouts_generated_total", &[]);
+        layout
+    }
+    
+    pub fn subscribe_layout_events(&self) -> broadcast::Receiver<LayoutChangeEvent> {
+        self.layout_events.subscribe()
+    }
+    
+    // Every change to the recorded layouts goes through here so subscribers
+    // never disagree with what the randomizer holds
+    fn record_layout(&self, layout: MemoryLayout, trigger: LayoutTrigger) {
+        let previous = self.layouts.write().unwrap().insert(layout.pid, layout.clone());
+        
+        let regions = match previous {
+            None => vec!["stack", "heap", "mmap", "vdso"],
+            Some(old) => [
+                ("stack", old.stack_base != layout.stack_base),
+                ("heap", old.heap_base != layout.heap_base),
+                ("mmap", old.mmap_base != layout.mmap_base),
+                ("vdso", old.vdso_offset != layout.vdso_offset),
+            ]
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(region, _)| region)
+            .collect(),
+        };
+        self.publish_layout_event(&layout, trigger, regions);
+    }
+    
+    fn publish_layout_event(&self, layout: &MemoryLayout, trigger: LayoutTrigger, regions: Vec<&'static str>) {
+        // No subscribers is fine
+        let _ = self.layout_events.send(LayoutChangeEvent {
+            pid: layout.pid,
+            trigger,
+            regions,
+            regeneration_count: layout.regeneration_count,
+            layout_hash: layout.layout_hash,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        });
+    }
+    
+    // Dry run: what applying a fresh layout would move, skip and cost.
+    // Reads the process but neither stops nor modifies it, and the
+    // recorded layout is left alone.
+    pub fn plan_layout(&mut self, pid: u32) -> Result<LayoutPlan, String> {
+        let profile = self.profile_for(pid);
+        let proposed = self.draw_layout(pid, profile);
+        let mut plan = LayoutPlan {
+            pid,
+            profile,
+            proposed,
+            moves: Vec::new(),
+            excluded: Vec::new(),
+            pointer_patches: 0,
+            estimated_pause: Duration::ZERO,
+            observed_mean_pause: metrics::global()
+                .timing("qks_randomizer_apply_seconds", &[])
+                .and_then(|t| t.mean()),
+        };
+        
+        if profile == RandomizationProfile::Excluded {
+            for region in ["[heap]", "[stack]", "[vdso]"] {
+                plan.excluded.push(ExcludedRegion { region, reason: "binary excluded by policy".into() });
+            }
+            return Ok(plan);
+        }
+        
+        let maps = ProcessMaps::read(pid)
+            .map_err(|e| format!("Cannot read mappings of PID {}: {}", pid, e))?;
+        let engine_moves = RemapEngine::plan(pid, &plan.proposed, self.hugepage_policy)
+            .map_err(|e| e.to_string())?;
+        
+        for (kind, region, allowed) in [
+            (RegionKind::Heap, "[heap]", profile.moves_heap()),
+            (RegionKind::Stack, "[stack]", profile.moves_stack()),
+        ] {
+            if !allowed {
+                plan.excluded.push(ExcludedRegion { region, reason: format!("pinned by the {:?} profile", profile) });
+                continue;
+            }
+            let mv = match engine_moves.iter().find(|mv| mv.kind == kind) {
+                Some(mv) => mv,
+                None => {
+                    let reason = if maps.named(region).is_none() { "no such mapping" } else { "huge-page backed and pinned" };
+                    plan.excluded.push(ExcludedRegion { region, reason: reason.into() });
+                    continue;
+                }
+            };
+            if kind == RegionKind::Heap {
+                match self.heap_fixups.patches_for(pid, mv) {
+                    Ok(patches) => plan.pointer_patches = patches.len(),
+                    Err(e) => {
+                        plan.excluded.push(ExcludedRegion { region, reason: e.to_string() });
+                        continue;
+                    }
+                }
+            }
+            if kind == RegionKind::Stack {
+                plan.excluded.push(ExcludedRegion { region, reason: STACK_MOVE_REFUSED.into() });
+                continue;
+            }
+            plan.moves.push(PlannedMove { region, from: mv.old_start, to: mv.new_start, len: mv.len });
+        }
+        
+        let vdso = maps.named("[vdso]");
+        match (vdso, self.vdso_support.get(&pid)) {
+            _ if !profile.moves_vdso() => {
+                plan.excluded.push(ExcludedRegion { region: "[vdso]", reason: format!("pinned by the {:?} profile", profile) });
+            }
+            (_, Some(VdsoSupport::Unsupported { reason })) => {
+                plan.excluded.push(ExcludedRegion { region: "[vdso]", reason: reason.clone() });
+            }
+            (None, _) => {
+                plan.excluded.push(ExcludedRegion { region: "[vdso]", reason: "no such mapping".into() });
+            }
+            (Some(vdso), _) => {
+                let arch = self.profile_for_pid(pid);
+                plan.moves.push(PlannedMove {
+                    region: "[vdso]",
+                    from: vdso.start,
+                    to: arch.align(vdso.start.saturating_sub(plan.proposed.vdso_offset)),
+                    len: vdso.len(),
+                });
+            }
+        }
+        
+        plan.estimated_pause = LayoutPlan::estimate_pause(&plan.moves, plan.pointer_patches);
+        Ok(plan)
+    }
+    
+    // Draw `sample_size` addresses per region, through both the initial
+    // and the regeneration path, and test how random they really are
+    pub fn audit_entropy(&mut self, sample_size: usize) -> EntropyAudit {
+        let arch = self.arch.clone();
+        let entropy = self.entropy;
+        let mut regions = Vec::new();
+        
+        for (name, window, bits) in [
+            ("stack", arch.stack_window, entropy.stack_bits),
+            ("heap", arch.heap_window, entropy.heap_bits),
+            ("mmap", arch.mmap_window, entropy.mmap_bits),
+        ] {
+            let to_offset = |addr: u64| (addr - window.0) >> arch.page_shift;
+            
+            let drawn: Vec<u64> = (0..sample_size)
+                .map(|_| to_offset(self.generate_random_address(&arch, window, bits)))
+                .collect();
+            regions.push(entropy_audit::analyze(name, &drawn, bits));
+            
+            // Regeneration chains from the previous address, like regenerate_layout
+            let mut current = self.generate_random_address(&arch, window, bits);
+            let regenerated: Vec<u64> = (0..sample_size)
+                .map(|_| {
+                    current = self.rerandomize_address(&arch, current, window, bits);
+                    to_offset(current)
+                })
+                .collect();
+            regions.push(entropy_audit::analyze(&format!("{} (regenerated)", name), &regenerated, bits));
+        }
+        
+        // Offsets are 1-based so the vDSO always moves
+        let vdso: Vec<u64> = (0..sample_size)
+            .map(|_| (self.generate_vdso_offset(&arch, entropy.vdso_bits) >> arch.page_shift) - 1)
+            .collect();
+        regions.push(entropy_audit::analyze("vdso", &vdso, entropy.vdso_bits));
+        
+        let audit = EntropyAudit { sample_size, regions };
+        for region in audit.regions.iter().filter(|r| !r.uniform || !r.stuck_bits.is_empty()) {
+            tracing::warn!(
+                "Entropy audit: {} has {:.1} of {} bits (stuck {:?}, chi2 {:.1})",
+                region.region, region.effective_bits, region.configured_bits, region.stuck_bits, region.chi_square
+            );
+        }
+        audit
+    }
+    
+    // A fresh layout for `pid`, not yet recorded anywhere
+    fn draw_layout(&mut self, pid: u32, profile: RandomizationProfile) -> MemoryLayout {
+        let arch = self.profile_for_pid(pid);
+        let entropy = self.entropy.clamped_to(&arch);
+        let (current_stack, current_heap, current_mmap) = Self::current_bases(pid);
+        
+        // Regions the policy pins keep their current base
+        let stack_base = match current_stack {
+            Some(base) if !profile.moves_stack() => base,
+            _ => self.generate_random_address(&arch, arch.stack_window, entropy.stack_bits),
+        };
+        let heap_base = match current_heap {
+            Some(base) if !profile.moves_heap() => base,
+            _ => self.generate_random_address(&arch, arch.heap_window, entropy.heap_bits),
+        };
+        let mmap_base = match current_mmap {
+            Some(base) if !profile.moves_mmap() => base,
+            _ => self.generate_random_address(&arch, arch.mmap_window, entropy.mmap_bits),
+        };
         
-        let layout = MemoryLayout {
+        MemoryLayout {
             pid,
-            stack_base: self.generate_random_address(0x00007_000_0000, 0x00007_FFF_FFFF),
-            heap_base: self.generate_random_address(0x00001_000_0000, 0x00001_FFF_FFFF),
-            mmap_base: self.generate_random_address(0x00002_000_0000, 0x00002_FFF_FFFF),
-            vdso_offset: self.rng.gen_range(0x1000..0xFFFF),
+            stack_base,
+            heap_base,
+            mmap_base,
+            vdso_offset: self.generate_vdso_offset(&arch, entropy.vdso_bits),
             layout_hash: self.generate_layout_hash(pid),
             regeneration_count: 0,
+        }
+    }
+    
+    pub fn regenerate_layout(&mut self, pid: u32) -> MemoryLayout {
+        self.regenerate_layout_with(pid, LayoutTrigger::Regeneration)
+    }
+    
+    pub fn regenerate_layout_with(&mut self, pid: u32, trigger: LayoutTrigger) -> MemoryLayout {
+        let existing = self.layouts.read().unwrap().get(&pid).cloned();
+        
+        let mut layout = match existing {
+            Some(layout) => layout,
+            None => return self.randomize_for_pid(pid),
         };
+        let arch = self.profile_for_pid(pid);
+        let entropy = self.entropy.clamped_to(&arch);
+        let profile = self.profile_for(pid);
         
-        layouts.insert(pid, layout.clone());
+        layout.regeneration_count += 1;
+        
+        // Apply quantum collapse: partial randomization of the page offset
+        // inside each window, so the result never leaves its window
+        if profile.moves_stack() {
+            layout.stack_base = self.rerandomize_address(&arch, layout.stack_base, arch.stack_window, entropy.stack_bits);
+        }
+        if profile.moves_heap() {
+            layout.heap_base = self.rerandomize_address(&arch, layout.heap_base, arch.heap_window, entropy.heap_bits);
+        }
+        if profile.moves_mmap() {
+            layout.mmap_base = self.rerandomize_address(&arch, layout.mmap_base, arch.mmap_window, entropy.mmap_bits);
+        }
+        layout.vdso_offset = self.generate_vdso_offset(&arch, entropy.vdso_bits);
+        layout.layout_hash = self.generate_layout_hash(pid);
+        
+        self.record_layout(layout.clone(), trigger);
+        metrics::global().incr("qks_randomizer_regenerations_total", &[]);
         layout
     }
     
-    pub fn regenerate_layout(&mut self, pid: u32) -> MemoryLayout {
-        let mut layouts = self.layouts.write().unwrap();
+    // Where stack top, heap and the first shared library sit right now
+    fn current_bases(pid: u32) -> (Option<u64>, Option<u64>, Option<u64>) {
+        let maps = match ProcessMaps::read(pid) {
+            Ok(maps) => maps,
+            Err(_) => return (None, None, None),
+        };
         
-        if let Some(mut layout) = layouts.get_mut(&pid) {
-            layout.regeneration_count += 1;
-            
-            // Apply quantum collapse: partial randomization
-            layout.stack_base ^= self.rng.gen::<u64>() & 0x0000_FFFF_FFFF;
-            layout.heap_base ^= self.rng.gen::<u64>() & 0x0000_FFFF_FFFF;
-            layout.mmap_base ^= self.rng.gen::<u64>() & 0x0000_FFFF_FFFF;
-            layout.vdso_offset = self.rng.gen_range(0x1000..0xFFFF);
-            layout.layout_hash = self.generate_layout_hash(pid);
-            
-            layout.clone()
+        let mmap = maps.entries.iter()
+            .filter(|e| e.path.as_deref().map_or(false, |p| p.ends_with(".so") || p.contains(".so.")))
+            .map(|e| e.start)
+            .min();
+        
+        (maps.named("[stack]").map(|e| e.end), maps.named("[heap]").map(|e| e.start), mmap)
+    }
+    
+    fn random_bits(&mut self, bits: u32) -> u64 {
+        if bits == 0 {
+            return 0;
+        }
+        self.rng.next_u64() & (u64::MAX >> (64 - bits))
+    }
+    
+    fn profile_for_pid(&self, pid: u32) -> ArchProfile {
+        let profile = ArchProfile::for_pid(pid);
+        // An explicitly configured native profile wins over detection
+        if profile.arch == ArchProfile::detect().arch {
+            self.arch.clone()
         } else {
-            self.randomize_for_pid(pid)
+            profile
         }
     }
     
-    fn generate_random_address(&mut self, min: u64, max: u64) -> u64 {
-        let base = self.rng.gen_range(min..max);
-        // Align to 4KB pages
-        base & !0xFFF
+    fn generate_random_address(&mut self, arch: &ArchProfile, window: (u64, u64), bits: u32) -> u64 {
+        // Window starts are page aligned, so the result is too
+        window.0 + (self.random_bits(bits) << arch.page_shift)
+    }
+    
+    fn rerandomize_address(&mut self, arch: &ArchProfile, current: u64, window: (u64, u64), bits: u32) -> u64 {
+        let window_pages = (window.1 - window.0 + 1) >> arch.page_shift;
+        let offset = current.saturating_sub(window.0) >> arch.page_shift;
+        let flipped = (offset ^ self.random_bits(bits)) % window_pages;
+        
+        window.0 + (flipped << arch.page_shift)
+    }
+    
+    fn generate_vdso_offset(&mut self, arch: &ArchProfile, bits: u32) -> u64 {
+        // Never zero pages: the vDSO always moves at least one page
+        (1 + self.random_bits(bits)) << arch.page_shift
     }
     
     fn generate_layout_hash(&mut self, pid: u32) -> [u8; 32] {
         use ring::digest;
-        let seed: [u8; 16] = self.rng.gen();
+        let mut seed = [0u8; 16];
+        self.rng.fill(&mut seed);
         let mut context = digest::Context::new(&digest::SHA256);
         context.update(&seed);
         context.update(&pid.to_ne_bytes());
@@ -88,42 +607,304 @@ impl MemoryRandomizer {
         result
     }
     
-    pub fn apply_layout_to_process(&self, pid: u32) -> Result<(), String> {
-        // In production, this would use:
-        // 1. prctl(PR_SET_MM, ...)
-        // 2. personality(ADDR_NO_RANDOMIZE) manipulation
-        // 3. Custom ELF loader for randomized mappings
+    pub fn apply_layout_to_process(&mut self, pid: u32) -> Result<(), String> {
+        let started = Instant::now();
+        let result = self.try_apply_layout(pid);
+        let metrics = metrics::global();
         
-        let layouts = self.layouts.read().unwrap();
-        if let Some(layout) = layouts.get(&pid) {
-            // Generate /proc/[pid]/mem manipulation commands
-            println!("Applying memory layout to PID {}: {:?}", pid, layout);
+        metrics.observe("qks_randomizer_apply_seconds", &[], started.elapsed());
+        match &result {
+            Ok(()) => metrics.incr("qks_randomizer_apply_total", &[("result", "success")]),
+            Err((reason, _)) => metrics.incr("qks_randomizer_apply_total", &[("result", "failure"), ("reason", reason)]),
+        }
+        
+        result.map_err(|(_, message)| message)
+    }
+    
+    // Failures carry a short reason label for the metrics
+    fn try_apply_layout(&mut self, pid: u32) -> Result<(), (&'static str, String)> {
+        if !self.layouts.read().unwrap().contains_key(&pid) {
+            return Err(("no_layout", format!("No layout found for PID {}", pid)));
+        }
+        
+        let profile = self.profile_for(pid);
+        if profile == RandomizationProfile::Excluded {
+            tracing::info!(pid, "PID {} is excluded from randomization by policy", pid);
+            return Ok(());
+        }
+        
+        // Keep the target from allocating while its maps are read and
+        // rewritten; the guard resumes it on every return path below.
+        // SIGSTOP rather than the cgroup freezer, which ptrace can't step
+        let _frozen = freezer::global().hold_stopped(pid, "randomizer", Some(FREEZE_HOLD))
+            .map_err(|e| ("freeze", e.to_string()))?;
+        
+        // Never move onto something already mapped
+        let layout = self.resolve_collisions(pid).map_err(|e| ("collision", e))?;
+        tracing::debug!(pid, "Applying memory layout to PID {}: {:?}", pid, layout);
+        
+        let mut moved = Vec::new();
+        if profile.moves_heap() || profile.moves_stack() {
+            let (moves, patches) = self.remap_process_memory(pid, &layout).map_err(|e| ("remap", e))?;
+            if let Err(e) = Self::verify_layout(pid, &moves) {
+                self.roll_back(pid, &moves, &patches);
+                return Err(("verification", e.to_string()));
+            }
+            moved.extend(moves.iter().map(|mv| match mv.kind {
+                RegionKind::Stack => "stack",
+                _ => "heap",
+            }));
+        }
+        if profile.moves_vdso() && self.relocate_vdso(pid, &layout) {
+            moved.push("vdso");
+        }
+        self.enforce_wx(pid).map_err(|e| ("wx", e))?;
+        
+        // Heap refusal may have changed the recorded layout since
+        let applied = self.layouts.read().unwrap().get(&pid).cloned().unwrap_or(layout);
+        self.publish_layout_event(&applied, LayoutTrigger::Applied, moved);
+        Ok(())
+    }
+    
+    // vDSO moves are best effort: failure downgrades the process, it
+    // doesn't fail the layout
+    fn relocate_vdso(&mut self, pid: u32, layout: &MemoryLayout) -> bool {
+        if let Some(VdsoSupport::Unsupported { .. }) = self.vdso_support.get(&pid) {
+            return false;
+        }
+        
+        let support = match vdso_relocation::relocate_vdso(pid, layout.vdso_offset) {
+            Ok(base) => VdsoSupport::Relocated { base },
+            Err(e) => {
+                tracing::warn!(pid, "vDSO of PID {} stays in place: {}", pid, e);
+                metrics::global().incr("qks_randomizer_vdso_unsupported_total", &[]);
+                VdsoSupport::Unsupported { reason: e.to_string() }
+            }
+        };
+        let relocated = matches!(support, VdsoSupport::Relocated { .. });
+        self.vdso_support.insert(pid, support);
+        relocated
+    }
+    
+    pub fn vdso_support(&self, pid: u32) -> Option<&VdsoSupport> {
+        self.vdso_support.get(&pid)
+    }
+    
+    // Walk the target's mappings and remove W+X combinations
+    pub fn enforce_wx(&self, pid: u32) -> Result<(), String> {
+        let strip = match self.wx_policy {
+            WxPolicy::Disabled => return Ok(()),
+            WxPolicy::StripWrite => libc::PROT_WRITE,
+            WxPolicy::StripExec => libc::PROT_EXEC,
+        };
+        
+        let maps = ProcessMaps::read(pid)
+            .map_err(|e| format!("Cannot read mappings of PID {}: {}", pid, e))?;
+        
+        let changes: Vec<ProtectionChange> = maps.entries.iter()
+            .filter(|e| e.writable() && e.executable())
+            // The legacy vsyscall page can't be mprotect'ed
+            .filter(|e| e.path.as_deref() != Some("[vsyscall]"))
+            .map(|e| {
+                let mut prot = libc::PROT_WRITE | libc::PROT_EXEC;
+                if e.readable() {
+                    prot |= libc::PROT_READ;
+                }
+                ProtectionChange {
+                    start: e.start,
+                    len: e.len(),
+                    old_prot: prot as u64,
+                    new_prot: (prot & !strip) as u64,
+                }
+            })
+            .collect();
+        
+        if changes.is_empty() {
+            return Ok(());
+        }
+        
+        tracing::info!(pid, "Enforcing W^X on {} mappings of PID {}", changes.len(), pid);
+        RemapEngine::protect(pid, &changes).map_err(|e| e.to_string())
+    }
+    
+    // Re-enforce when the monitor sees a process ask for W+X again
+    pub fn on_mprotect(&self, event: &MprotectEvent) -> Result<(), String> {
+        if self.wx_policy == WxPolicy::Disabled || !event.is_write_exec() {
+            return Ok(());
+        }
+        if !self.layouts.read().unwrap().contains_key(&event.pid) {
+            return Ok(());
+        }
+        
+        tracing::warn!(
+            pid = event.pid,
+            "PID {} requested W+X on {:#x} (+{} bytes), re-enforcing",
+            event.pid, event.start, event.len
+        );
+        self.enforce_wx(event.pid)
+    }
+    
+    pub fn resolve_collisions(&mut self, pid: u32) -> Result<MemoryLayout, String> {
+        let maps = ProcessMaps::read(pid)
+            .map_err(|e| format!("Cannot read mappings of PID {}: {}", pid, e))?;
+        let min_addr = process_maps::mmap_min_addr();
+        let arch = self.profile_for_pid(pid);
+        let entropy = self.entropy.clamped_to(&arch);
+        let profile = self.profile_for(pid);
+        
+        // Moved regions keep their current size
+        let heap_len = maps.named("[heap]").map(|e| e.len()).unwrap_or(arch.page_size());
+        let stack_len = maps.named("[stack]").map(|e| e.len()).unwrap_or(arch.page_size());
+        
+        let mut layout = self.layouts.read().unwrap()
+            .get(&pid)
+            .cloned()
+            .ok_or_else(|| format!("No layout found for PID {}", pid))?;
+        
+        for attempt in 0..MAX_COLLISION_REDRAWS {
+            let heap = (layout.heap_base, layout.heap_base + heap_len);
+            // Stack base is the top; the region extends downward
+            let stack = (layout.stack_base.saturating_sub(stack_len), layout.stack_base);
+            let mmap = (layout.mmap_base, layout.mmap_base + arch.page_size());
+            
+            // Pinned regions sit on their own mapping, which is not a clash
+            let heap_ok = !profile.moves_heap() || Self::range_is_free(&maps, heap, min_addr);
+            let stack_ok = !profile.moves_stack() || (Self::range_is_free(&maps, stack, min_addr)
+                && !Self::ranges_overlap(stack, heap));
+            let mmap_ok = !profile.moves_mmap() || Self::range_is_free(&maps, mmap, min_addr);
             
-            // This is where you'd implement actual memory remapping
-            // using ptrace or process_vm_writev
-            unsafe {
-                Self::remap_process_memory(pid, layout);
+            if heap_ok && stack_ok && mmap_ok {
+                if attempt > 0 {
+                    tracing::debug!(pid, "PID {} layout conflict-free after {} redraws", pid, attempt);
+                    self.record_layout(layout.clone(), LayoutTrigger::Regeneration);
+                }
+                return Ok(layout);
             }
             
-            Ok(())
-        } else {
-            Err(format!("No layout found for PID {}", pid))
+            // Only redraw the regions that collided
+            if !heap_ok {
+                layout.heap_base = self.generate_random_address(&arch, arch.heap_window, entropy.heap_bits);
+            }
+            if !stack_ok {
+                layout.stack_base = self.generate_random_address(&arch, arch.stack_window, entropy.stack_bits);
+            }
+            if !mmap_ok {
+                layout.mmap_base = self.generate_random_address(&arch, arch.mmap_window, entropy.mmap_bits);
+            }
         }
+        
+        Err(format!(
+            "No conflict-free layout for PID {} after {} attempts",
+            pid, MAX_COLLISION_REDRAWS
+        ))
+    }
+    
+    fn range_is_free(maps: &ProcessMaps, range: (u64, u64), min_addr: u64) -> bool {
+        range.0 >= min_addr && range.0 < range.1 && maps.overlapping(range.0, range.1).is_empty()
     }
     
-    unsafe fn remap_process_memory(pid: u32, layout: &MemoryLayout) {
-        // WARNING: This is a conceptual implementation
-        // Real implementation would use Linux kernel APIs
+    fn ranges_overlap(a: (u64, u64), b: (u64, u64)) -> bool {
+        a.0 < b.1 && b.0 < a.1
+    }
+    
+    // Read the process back: a remap that "succeeded" but left the kernel's
+    // view elsewhere gives no protection at all
+    pub fn verify_layout(pid: u32, moves: &[RegionMove]) -> Result<(), LayoutVerificationError> {
+        let result = layout_verification::verify_moves(pid, moves);
+        if let Err(e) = &result {
+            tracing::warn!("Layout verification failed: {}", e);
+            if let LayoutVerificationError::Mismatch { mismatches, .. } = e {
+                for mismatch in mismatches {
+                    metrics::global().incr("qks_randomizer_verification_mismatches_total", &[("field", mismatch.field)]);
+                }
+            }
+        }
+        result
+    }
+    
+    // A layout that failed verification is undone instead of being left
+    // half applied, and the recorded layout goes back to the old bases
+    fn roll_back(&mut self, pid: u32, moves: &[RegionMove], patches: &[PointerPatch]) {
+        let undo: Vec<RegionMove> = moves.iter().rev()
+            .map(|mv| RegionMove { kind: mv.kind, old_start: mv.new_start, len: mv.len, new_start: mv.old_start })
+            .collect();
+        // Patched words live in the moved regions, at their post-move addresses
+        let restore: Vec<PointerPatch> = patches.iter()
+            .map(|p| {
+                let addr = moves.iter()
+                    .find(|mv| p.addr >= mv.new_start && p.addr < mv.new_start + mv.len)
+                    .map_or(p.addr, |mv| p.addr - mv.new_start + mv.old_start);
+                PointerPatch { addr, old_value: p.new_value, new_value: p.old_value }
+            })
+            .collect();
         
-        libc::syscall(
-            libc::SYS_prctl,
-            libc::PR_SET_MM,
-            libc::PR_SET_MM_START_BRK,
-            layout.heap_base,
-            0,
-            0
-        );
+        match RemapEngine::apply_moves(pid, &undo, &restore) {
+            Ok(()) => {
+                tracing::warn!(pid, "PID {}: rolled back {} moves after failed verification", pid, moves.len());
+                metrics::global().incr("qks_randomizer_rollbacks_total", &[("result", "success")]);
+                if let Some(recorded) = self.layouts.write().unwrap().get_mut(&pid) {
+                    for mv in moves {
+                        match mv.kind {
+                            RegionKind::Heap => recorded.heap_base = mv.old_start,
+                            RegionKind::Stack => recorded.stack_base = mv.old_start + mv.len,
+                            RegionKind::Library => {}
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!(pid, "PID {}: rollback after failed verification failed: {}", pid, e);
+                metrics::global().incr("qks_randomizer_rollbacks_total", &[("result", "failure")]);
+            }
+        }
+    }
+    
+    fn remap_process_memory(&mut self, pid: u32, layout: &MemoryLayout) -> Result<(Vec<RegionMove>, Vec<PointerPatch>), String> {
+        let mut moves = RemapEngine::plan(pid, layout, self.hugepage_policy).map_err(|e| e.to_string())?;
+        
+        // A heap move without its pointer fixups corrupts the process, so
+        // without a usable relocation table the heap stays where it is
+        let mut patches = Vec::new();
+        if let Some(i) = moves.iter().position(|mv| mv.kind == RegionKind::Heap) {
+            match self.heap_fixups.patches_for(pid, &moves[i]) {
+                Ok(heap_patches) => patches = heap_patches,
+                Err(e) => {
+                    tracing::warn!(pid, "Not moving heap of PID {}: {}", pid, e);
+                    metrics::global().incr("qks_randomizer_heap_moves_refused_total", &[]);
+                    let refused = moves.remove(i);
+                    if let Some(recorded) = self.layouts.write().unwrap().get_mut(&pid) {
+                        recorded.heap_base = refused.old_start;
+                    }
+                }
+            }
+        }
+        
+        // Nothing finds the stack's pointers yet (see STACK_MOVE_REFUSED)
+        if let Some(i) = moves.iter().position(|mv| mv.kind == RegionKind::Stack) {
+            tracing::debug!(pid, "Not moving stack of PID {}: {}", pid, STACK_MOVE_REFUSED);
+            metrics::global().incr("qks_randomizer_stack_moves_refused_total", &[]);
+            let refused = moves.remove(i);
+            if let Some(recorded) = self.layouts.write().unwrap().get_mut(&pid) {
+                recorded.stack_base = refused.old_start + refused.len;
+            }
+        }
+        
+        // Stops the target, moves heap/stack to the new bases, patches
+        // PR_SET_MM_* fields and heap pointers; any failure is rolled back
+        // before resuming
+        RemapEngine::apply_moves(pid, &moves, &patches).map_err(|e| e.to_string())?;
+        
+        for mv in &moves {
+            tracing::info!(
+                pid,
+                "PID {}: moved {:?} {:#x} -> {:#x} ({} bytes)",
+                pid, mv.kind, mv.old_start, mv.new_start, mv.len
+            );
+        }
+        if !patches.is_empty() {
+            tracing::info!(pid, "PID {}: rewrote {} heap pointers", pid, patches.len());
+        }
         
-        // More syscalls to remap stack, mmap regions, etc.
+        Ok((moves, patches))
     }
 }
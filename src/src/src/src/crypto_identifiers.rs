@@ -1,13 +1,31 @@
 // src/crypto_identifiers.rs
 use ring::{rand, signature, hmac};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use crate::clock::{Clock, SystemClock};
+use crate::constant_time;
+use crate::metrics;
+use dashmap::DashMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// Matches [crypto] token_lifetime_minutes = 60
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 3600;
+// Allowed disagreement between issuer and verifier clocks
+const DEFAULT_CLOCK_SKEW_SECS: u64 = 30;
 
 pub struct CryptoIdentifier {
     key_pair: signature::Ed25519KeyPair,
     rng: rand::SystemRandom,
+    token_lifetime_secs: u64,
+    // The host's one revocation list, shared with the status responder and
+    // the propagator, so a token revoked on a peer introspects as revoked
+    revoked: Arc<DashMap<[u8; 32], RevocationProof>>,
+    clock: Arc<dyn Clock>,
+    skew_tolerance_secs: u64,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct ProcessToken {
     pub pid: u32,
     pub parent_token: Option<Vec<u8>>,
@@ -17,7 +35,7 @@ pub struct ProcessToken {
     pub nonce: [u8; 16],
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub enum Capability {
     NetworkAccess,
     FilesystemAccess(String),  // Path prefix
@@ -29,9 +47,71 @@ impl CryptoIdentifier {
     pub fn new() -> Result<Self, ring::error::Unspecified> {
         let rng = rand::SystemRandom::new();
         let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng)?;
-        let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref())?;
+        Self::from_pkcs8(pkcs8_bytes.as_ref())
+    }
+    
+    // The same key across restarts: read from `path`, or generated and
+    // written there (0600) the first time
+    pub fn load_or_create(path: &Path) -> std::io::Result<Self> {
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), what));
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rand::SystemRandom::new())
+                    .map_err(|_| invalid("key generation failed"))?;
+                let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+                file.write_all(pkcs8.as_ref())?;
+                file.sync_all()?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        Self::from_pkcs8(&pkcs8).map_err(|_| invalid("not an Ed25519 PKCS#8 key"))
+    }
+    
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, ring::error::Unspecified> {
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8)?;
         
-        Ok(Self { key_pair, rng })
+        Ok(Self {
+            key_pair,
+            rng: rand::SystemRandom::new(),
+            token_lifetime_secs: DEFAULT_TOKEN_LIFETIME_SECS,
+            revoked: Arc::new(DashMap::new()),
+            clock: Arc::new(SystemClock::new()),
+            skew_tolerance_secs: DEFAULT_CLOCK_SKEW_SECS,
+        })
+    }
+    
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    
+    pub fn with_skew_tolerance(mut self, skew_secs: u64) -> Self {
+        self.skew_tolerance_secs = skew_secs;
+        self
+    }
+    
+    pub fn now(&self) -> u64 {
+        self.clock.now_secs()
+    }
+    
+    pub fn skew_tolerance(&self) -> u64 {
+        self.skew_tolerance_secs
+    }
+    
+    pub fn token_lifetime(&self) -> u64 {
+        self.token_lifetime_secs
+    }
+    
+    pub fn with_token_lifetime(mut self, lifetime_secs: u64) -> Self {
+        self.token_lifetime_secs = lifetime_secs;
+        self
+    }
+    
+    // Keyed by constant_time::lookup_key of the token signature
+    pub fn revocations(&self) -> Arc<DashMap<[u8; 32], RevocationProof>> {
+        self.revoked.clone()
     }
     
     pub fn generate_process_token(
@@ -40,10 +120,7 @@ impl CryptoIdentifier {
         parent_token: Option<&ProcessToken>,
         capabilities: &[Capability],
     ) -> Result<ProcessToken, ring::error::Unspecified> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = self.clock.now_secs();
         
         let mut nonce = [0u8; 16];
         rand::generate(&self.rng, &mut nonce)?;
@@ -65,6 +142,7 @@ impl CryptoIdentifier {
         
         // Sign the token
         let signature = self.key_pair.sign(&token_data).as_ref().to_vec();
+        metrics::global().incr("qks_tokens_issued_total", &[]);
         
         Ok(ProcessToken {
             pid,
@@ -103,6 +181,31 @@ impl CryptoIdentifier {
         Ok(true)
     }
     
+    pub fn verify_token_with_parent(
+        &self,
+        token: &ProcessToken,
+        parent: &ProcessToken,
+    ) -> Result<bool, ring::error::Unspecified> {
+        self.verify_token(token)?;
+        
+        // Parent linkage compares signature bytes, keep it constant-time
+        match token.parent_token {
+            Some(ref parent_sig) if constant_time::ct_eq(parent_sig, &parent.signature) => Ok(true),
+            _ => Err(ring::error::Unspecified),
+        }
+    }
+    
+    pub fn is_revoked(token: &ProcessToken, revocations: &[RevocationProof]) -> bool {
+        constant_time::ct_contains(
+            revocations.iter().map(|r| r.token_signature.as_slice()),
+            &token.signature,
+        )
+    }
+    
+    pub fn session_keys_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+        constant_time::ct_eq(a, b)
+    }
+    
     pub fn generate_session_key(&self, token: &ProcessToken) -> [u8; 32] {
         // Derive session key from token
         let key = hmac::Key::new(hmac::HMAC_SHA256, b"session_derivation");
@@ -115,26 +218,147 @@ impl CryptoIdentifier {
     
     pub fn revoke_token(&self, token: &ProcessToken) -> RevocationProof {
         // Create revocation proof (add to CRL)
-        let revocation_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let revocation_time = self.clock.now_secs();
+        metrics::global().incr("qks_tokens_revoked_total", &[]);
         
         let mut proof_data = Vec::new();
         proof_data.extend_from_slice(&token.signature);
         proof_data.extend_from_slice(&revocation_time.to_ne_bytes());
         
-        RevocationProof {
+        let proof = RevocationProof {
             token_signature: token.signature.clone(),
             revoked_at: revocation_time,
             proof: self.key_pair.sign(&proof_data).as_ref().to_vec(),
+        };
+        
+        self.revoked.insert(constant_time::lookup_key(&token.signature), proof.clone());
+        proof
+    }
+    
+    pub fn introspect(&self, token: &ProcessToken) -> TokenStatus {
+        let now = self.clock.now_secs();
+        let mut failures = Vec::new();
+        
+        if self.verify_token(token).is_err() {
+            failures.push(TokenCheckFailure {
+                check: TokenCheck::Signature,
+                detail: "signature does not verify against issuer key".to_string(),
+            });
         }
+        
+        // Skew tolerance applies in both directions
+        let expires_at = token.timestamp.saturating_add(self.token_lifetime_secs);
+        if now >= expires_at.saturating_add(self.skew_tolerance_secs) {
+            failures.push(TokenCheckFailure {
+                check: TokenCheck::Expiry,
+                detail: format!("expired at {} (now {})", expires_at, now),
+            });
+        } else if token.timestamp > now.saturating_add(self.skew_tolerance_secs) {
+            failures.push(TokenCheckFailure {
+                check: TokenCheck::Expiry,
+                detail: format!("issued in the future at {} (now {})", token.timestamp, now),
+            });
+        }
+        
+        if let Some(proof) = self.revoked.get(&constant_time::lookup_key(&token.signature)) {
+            failures.push(TokenCheckFailure {
+                check: TokenCheck::Revoked,
+                detail: format!("revoked at {}", proof.revoked_at),
+            });
+        }
+        
+        // A revoked parent invalidates every token derived from it
+        if let Some(ref parent_sig) = token.parent_token {
+            if let Some(proof) = self.revoked.get(&constant_time::lookup_key(parent_sig)) {
+                failures.push(TokenCheckFailure {
+                    check: TokenCheck::Chain,
+                    detail: format!("parent token revoked at {}", proof.revoked_at),
+                });
+            }
+        }
+        
+        // Token is bound to a pid; it means nothing once that process is gone
+        if !std::path::Path::new(&format!("/proc/{}", token.pid)).exists() {
+            failures.push(TokenCheckFailure {
+                check: TokenCheck::Binding,
+                detail: format!("bound PID {} is not running", token.pid),
+            });
+        }
+        
+        TokenStatus {
+            pid: token.pid,
+            valid: failures.is_empty(),
+            expires_at,
+            failures,
+        }
+    }
+    
+    pub fn public_key_bytes(&self) -> Vec<u8> {
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+    
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(data).as_ref().to_vec()
+    }
+    
+    pub fn verify_revocation(
+        proof: &RevocationProof,
+        issuer_public_key: &[u8],
+    ) -> Result<(), ring::error::Unspecified> {
+        let mut proof_data = Vec::new();
+        proof_data.extend_from_slice(&proof.token_signature);
+        proof_data.extend_from_slice(&proof.revoked_at.to_ne_bytes());
+        
+        signature::UnparsedPublicKey::new(&signature::ED25519, issuer_public_key)
+            .verify(&proof_data, &proof.proof)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct RevocationProof {
     pub token_signature: Vec<u8>,
     pub revoked_at: u64,
     pub proof: Vec<u8>,
 }
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct TokenStatus {
+    pub pid: u32,
+    pub valid: bool,
+    pub expires_at: u64,
+    pub failures: Vec<TokenCheckFailure>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct TokenCheckFailure {
+    pub check: TokenCheck,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub enum TokenCheck {
+    Signature,
+    Expiry,
+    Revoked,
+    Chain,
+    Binding,
+}
+
+impl TokenCheck {
+    // Stable codes for logs and API consumers
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenCheck::Signature => "TOKEN_BAD_SIGNATURE",
+            TokenCheck::Expiry => "TOKEN_EXPIRED",
+            TokenCheck::Revoked => "TOKEN_REVOKED",
+            TokenCheck::Chain => "TOKEN_CHAIN_BROKEN",
+            TokenCheck::Binding => "TOKEN_BINDING_MISMATCH",
+        }
+    }
+}
+
+impl TokenStatus {
+    pub fn codes(&self) -> Vec<&'static str> {
+        self.failures.iter().map(|f| f.check.code()).collect()
+    }
+}
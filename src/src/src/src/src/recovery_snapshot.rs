@@ -5,40 +5,124 @@ use std::io::{Write, Read};
 use std::path::PathBuf;
 use flate2::{Compression, write::GzEncoder};
 use ring::digest;
+use crate::constant_time;
+use crate::crypto_identifiers::{Capability, ProcessToken, RevocationProof};
+use crate::dm_verity::{self, VerityVolume};
+use crate::freezer;
+use crate::metrics;
+use crate::memory_randomizer::{LayoutRestoreMode, MemoryRandomizer};
+use crate::process_maps::{self, ProcessIdentity};
+use crate::randomization_policy::{RandomizationPolicy, RandomizationProfile};
+use std::collections::BTreeMap;
+
+// Longest a restore keeps its processes frozen
+const RESTORE_HOLD: std::time::Duration = std::time::Duration::from_secs(60);
+
+// What qksd keeps about the processes it manages, which is what a
+// snapshot records; their memory and registers are not part of it
+pub struct TrackedState {
+    pub layouts: Vec<MemoryLayoutSnapshot>,
+    pub tokens: Vec<ProcessToken>,
+    pub revocations: Vec<RevocationProof>,
+    pub policy: RandomizationPolicy,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KernelSnapshot {
     pub snapshot_id: String,
     pub timestamp: u64,
+    // Every process with a recorded layout or a token
     pub processes: Vec<ProcessSnapshot>,
     pub memory_layouts: Vec<MemoryLayoutSnapshot>,
-    pub syscall_state: SyscallStateSnapshot,
-    pub crypto_state: CryptoStateSnapshot,
+    pub tokens: Vec<ProcessToken>,
+    pub revocations: Vec<RevocationProof>,
+    pub randomization_policy: PolicySnapshot,
+    // dm-verity state of the protected volumes and every verity device
+    pub integrity: Vec<VerityVolume>,
+    // Why it was taken, e.g. "apt-kernel" from the APT hook
+    pub tag: Option<String>,
+    pub note: Option<String>,
     pub checksum: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProcessSnapshot {
     pub pid: u32,
-    pub state: ProcessState,
-    pub registers: Option<RegisterSet>,
-    pub memory_ranges: Vec<MemoryRange>,
-    pub open_files: Vec<FileDescriptor>,
-    pub children: Vec<u32>,
+    // None if it exited while the snapshot was taken
+    pub identity: Option<ProcessIdentity>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum ProcessState {
-    Running,
-    Frozen,
-    Collapsing,
-    Regenerating,
+// RandomizationPolicy with its binaries sorted, so that a snapshot read
+// back serializes to the bytes its checksum was taken over
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PolicySnapshot {
+    pub default: RandomizationProfile,
+    pub binaries: BTreeMap<String, RandomizationProfile>,
+}
+
+// See SnapshotManager::service_state
+#[derive(Debug, Clone)]
+pub struct ServiceState {
+    // Recorded processes of the executable
+    pub processes: usize,
+    // Every capability their tokens held
+    pub capabilities: Vec<Capability>,
+    pub profile: RandomizationProfile,
+}
+
+impl From<&RandomizationPolicy> for PolicySnapshot {
+    fn from(policy: &RandomizationPolicy) -> Self {
+        Self {
+            default: policy.default,
+            binaries: policy.binaries.iter().map(|(exe, profile)| (exe.clone(), *profile)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MemoryLayoutSnapshot {
+    pub pid: u32,
+    pub stack_base: u64,
+    pub heap_base: u64,
+    pub mmap_base: u64,
+    pub vdso_offset: u64,
+    pub layout_hash: [u8; 32],
+    pub regeneration_count: u32,
+    // The process the layout was taken from; a restore skips the PID when
+    // whatever holds it now is not that process
+    pub identity: Option<ProcessIdentity>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SnapshotSummary {
+    pub snapshot_id: String,
+    pub timestamp: u64,
+    pub processes: usize,
+    pub memory_layouts: usize,
+    pub tag: Option<String>,
+    pub note: Option<String>,
+}
+
+// PIDs that appear, vanish or were re-laid-out between two snapshots
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SnapshotDiff {
+    pub from: String,
+    pub to: String,
+    pub processes_added: Vec<u32>,
+    pub processes_removed: Vec<u32>,
+    pub layouts_changed: Vec<u32>,
+    pub layouts_added: Vec<u32>,
+    pub layouts_removed: Vec<u32>,
+    // Volumes whose verity state or root hash differ, or that appear in
+    // only one of the two
+    pub verity_changed: Vec<String>,
 }
 
 pub struct SnapshotManager {
     snapshot_dir: PathBuf,
     max_snapshots: usize,
     encryption_key: Option<[u8; 32]>,
+    verity_volumes: Vec<String>,
 }
 
 impl SnapshotManager {
@@ -50,30 +134,41 @@ impl SnapshotManager {
             snapshot_dir: dir,
             max_snapshots: 10,
             encryption_key: None,
+            verity_volumes: Vec::new(),
         }
     }
     
-    pub fn take_snapshot(&self, kernel_state: &QuantumKernel) -> Result<String, anyhow::Error> {
+    pub fn with_max_snapshots(mut self, max_snapshots: usize) -> Self {
+        self.max_snapshots = max_snapshots.max(1);
+        self
+    }
+    
+    // Volumes recorded as protected in each snapshot's integrity state
+    pub fn with_verity_volumes(mut self, volumes: Vec<String>) -> Self {
+        self.verity_volumes = volumes;
+        self
+    }
+    
+    pub fn take_snapshot(&self, state: TrackedState, tag: Option<&str>, note: Option<&str>) -> Result<SnapshotSummary, anyhow::Error> {
+        let started = std::time::Instant::now();
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_nanos();
         
         let snapshot_id = format!("snapshot_{:x}", timestamp);
         
-        // Capture process states
-        let processes = self.capture_processes(kernel_state)?;
-        
-        // Capture memory layouts
-        let memory_layouts = self.capture_memory_layouts(kernel_state)?;
-        
         // Create snapshot
         let snapshot = KernelSnapshot {
             snapshot_id: snapshot_id.clone(),
             timestamp: timestamp as u64,
-            processes,
-            memory_layouts,
-            syscall_state: kernel_state.syscall_state(),
-            crypto_state: kernel_state.crypto_state(),
+            processes: Self::capture_processes(&state),
+            memory_layouts: state.layouts,
+            tokens: state.tokens,
+            revocations: state.revocations,
+            randomization_policy: PolicySnapshot::from(&state.policy),
+            integrity: self.capture_integrity(),
+            tag: tag.map(str::to_string),
+            note: note.map(str::to_string),
             checksum: String::new(), // Will calculate below
         };
         
@@ -90,76 +185,184 @@ impl SnapshotManager {
         
         // Enforce max snapshots
         self.cleanup_old_snapshots();
+        metrics::global().observe("qks_snapshot_take_seconds", &[], started.elapsed());
+        tracing::info!(snapshot_id, "Took snapshot {} of {} layouts and {} tokens", snapshot_id, snapshot.memory_layouts.len(), snapshot.tokens.len());
         
-        Ok(snapshot_id)
+        Ok(SnapshotSummary {
+            snapshot_id,
+            timestamp: snapshot.timestamp,
+            processes: snapshot.processes.len(),
+            memory_layouts: snapshot.memory_layouts.len(),
+            tag: snapshot.tag,
+            note: snapshot.note,
+        })
     }
     
-    pub fn restore_snapshot(&self, snapshot_id: &str) -> Result<QuantumKernel, anyhow::Error> {
+    // Re-apply the layouts recorded in a snapshot to the live processes
+    pub fn restore_layouts(
+        &self,
+        snapshot_id: &str,
+        randomizer: &mut MemoryRandomizer,
+        mode: LayoutRestoreMode,
+    ) -> Result<Vec<u32>, anyhow::Error> {
+        let started = std::time::Instant::now();
         let snapshot = self.load_snapshot(snapshot_id)?;
-        
-        // Verify checksum
-        let snapshot_bytes = bincode::serialize(&snapshot)?;
-        let calculated = self.calculate_checksum(&snapshot_bytes);
-        
-        if calculated != snapshot.checksum {
-            return Err(anyhow::anyhow!("Snapshot checksum mismatch"));
+        // A reused PID would have an unrelated process's memory rewritten
+        let (current, gone): (Vec<_>, Vec<_>) = snapshot.memory_layouts.into_iter()
+            .partition(|layout| layout.identity.is_some() && layout.identity == process_maps::identity(layout.pid));
+        for layout in &gone {
+            tracing::info!(pid = layout.pid, snapshot_id, "PID {} is not the process snapshot {} recorded; layout skipped", layout.pid, snapshot_id);
         }
+        let identities: std::collections::HashMap<u32, Option<ProcessIdentity>> = current.iter()
+            .map(|layout| (layout.pid, layout.identity.clone()))
+            .collect();
+        let pids = randomizer.import_layouts(&current, mode);
         
-        // Restore kernel state
-        let mut kernel = QuantumKernel::new();
+        // Hold every process until all are restored, so none runs on a
+        // restored layout while its peers are still on the old one
+        let _frozen: Vec<_> = pids.iter()
+            .filter_map(|pid| freezer::global().hold(*pid, "snapshot", Some(RESTORE_HOLD)).ok())
+            .collect();
         
-        // Restore processes
-        for proc_snapshot in &snapshot.processes {
-            self.restore_process(&mut kernel, proc_snapshot)?;
+        let mut applied = Vec::new();
+        for pid in pids {
+            // Checked again now that it is frozen: it may have exited since
+            if identities.get(&pid).cloned().flatten() != process_maps::identity(pid) {
+                tracing::info!(pid, snapshot_id, "PID {} exited during the restore; layout skipped", pid);
+                continue;
+            }
+            match randomizer.apply_layout_to_process(pid) {
+                Ok(()) => applied.push(pid),
+                Err(e) => tracing::warn!(pid, snapshot_id, "Layout restore for PID {} failed: {}", pid, e),
+            }
         }
+        tracing::info!(snapshot_id, "Restored {} layouts from snapshot {}", applied.len(), snapshot_id);
+        metrics::global().observe("qks_snapshot_restore_seconds", &[], started.elapsed());
         
-        // Restore memory layouts
-        for layout_snapshot in &snapshot.memory_layouts {
-            kernel.restore_memory_layout(layout_snapshot);
+        Ok(applied)
+    }
+    
+    // What a snapshot recorded for the processes of one executable, for
+    // starting a replacement the way they ran
+    pub fn service_state(&self, snapshot_id: &str, exe: &str) -> Result<ServiceState, anyhow::Error> {
+        let snapshot = self.load_snapshot(snapshot_id)?;
+        let pids: std::collections::BTreeSet<u32> = snapshot.processes.iter()
+            .filter(|process| process.identity.as_ref().is_some_and(|identity| identity.exe == exe))
+            .map(|process| process.pid)
+            .collect();
+        let mut capabilities: Vec<Capability> = Vec::new();
+        for token in snapshot.tokens.iter().filter(|token| pids.contains(&token.pid)) {
+            for capability in &token.capabilities {
+                if !capabilities.contains(capability) {
+                    capabilities.push(capability.clone());
+                }
+            }
         }
-        
-        // Restore syscall state
-        kernel.restore_syscall_state(&snapshot.syscall_state);
-        
-        // Restore crypto state
-        kernel.restore_crypto_state(&snapshot.crypto_state);
-        
-        Ok(kernel)
+        let policy = RandomizationPolicy {
+            default: snapshot.randomization_policy.default,
+            binaries: snapshot.randomization_policy.binaries.into_iter().collect(),
+        };
+        Ok(ServiceState { processes: pids.len(), capabilities, profile: policy.profile_for_exe(exe) })
     }
     
-    fn capture_processes(&self, kernel: &QuantumKernel) -> Result<Vec<ProcessSnapshot>, anyhow::Error> {
-        let mut snapshots = Vec::new();
-        
-        for (pid, process) in &kernel.processes {
-            // Read process memory via /proc/[pid]/mem
-            let mem_path = format!("/proc/{}/mem", pid);
-            let memory_ranges = self.capture_memory_ranges(&mem_path)?;
-            
-            // Read registers via ptrace
-            let registers = self.capture_registers(*pid)?;
-            
-            // Capture open files via /proc/[pid]/fd
-            let open_files = self.capture_open_files(*pid)?;
-            
-            let snapshot = ProcessSnapshot {
-                pid: *pid,
-                state: ProcessState::Frozen, // Snapshots always from frozen state
-                registers: Some(registers),
-                memory_ranges,
-                open_files,
-                children: process.children.clone(),
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotSummary>, anyhow::Error> {
+        let mut summaries = Vec::new();
+        for entry in fs::read_dir(&self.snapshot_dir)?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("qks") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
             };
-            
-            snapshots.push(snapshot);
+            match self.load_snapshot(id) {
+                Ok(snapshot) => summaries.push(SnapshotSummary {
+                    snapshot_id: snapshot.snapshot_id,
+                    timestamp: snapshot.timestamp,
+                    processes: snapshot.processes.len(),
+                    memory_layouts: snapshot.memory_layouts.len(),
+                    tag: snapshot.tag,
+                    note: snapshot.note,
+                }),
+                Err(e) => tracing::warn!(snapshot_id = id, "Unreadable snapshot {}: {}", path.display(), e),
+            }
         }
         
-        Ok(snapshots)
+        summaries.sort_by_key(|s| s.timestamp);
+        Ok(summaries)
+    }
+    
+    // The newest snapshot whose tag starts with `prefix`
+    pub fn latest_tagged(&self, prefix: &str) -> Result<Option<SnapshotSummary>, anyhow::Error> {
+        Ok(self.list_snapshots()?
+            .into_iter()
+            .filter(|s| s.tag.as_deref().map_or(false, |tag| tag.starts_with(prefix)))
+            .last())
+    }
+    
+    // Recompute the checksum without restoring anything
+    pub fn verify_snapshot(&self, snapshot_id: &str) -> Result<bool, anyhow::Error> {
+        let mut snapshot = self.load_snapshot(snapshot_id)?;
+        let recorded = std::mem::take(&mut snapshot.checksum);
+        let calculated = self.calculate_checksum(&bincode::serialize(&snapshot)?);
+        Ok(constant_time::ct_eq_str(&calculated, &recorded))
+    }
+    
+    pub fn diff_snapshots(&self, from: &str, to: &str) -> Result<SnapshotDiff, anyhow::Error> {
+        let from = self.load_snapshot(from)?;
+        let to = self.load_snapshot(to)?;
+        
+        let pids = |s: &KernelSnapshot| s.processes.iter().map(|p| p.pid).collect::<std::collections::BTreeSet<u32>>();
+        let (before, after) = (pids(&from), pids(&to));
+        
+        let layouts = |s: &KernelSnapshot| {
+            s.memory_layouts.iter().map(|l| (l.pid, l.clone())).collect::<std::collections::BTreeMap<u32, MemoryLayoutSnapshot>>()
+        };
+        let (old_layouts, new_layouts) = (layouts(&from), layouts(&to));
+        
+        let volumes = |s: &KernelSnapshot| {
+            s.integrity.iter().map(|v| (v.volume.clone(), (v.state, v.root_hash.clone()))).collect::<std::collections::BTreeMap<_, _>>()
+        };
+        let (old_volumes, new_volumes) = (volumes(&from), volumes(&to));
+        
+        Ok(SnapshotDiff {
+            from: from.snapshot_id.clone(),
+            to: to.snapshot_id.clone(),
+            processes_added: after.difference(&before).copied().collect(),
+            processes_removed: before.difference(&after).copied().collect(),
+            layouts_changed: new_layouts.iter()
+                .filter(|(pid, layout)| old_layouts.get(pid).map_or(false, |old| old != *layout))
+                .map(|(pid, _)| *pid)
+                .collect(),
+            layouts_added: new_layouts.keys().filter(|pid| !old_layouts.contains_key(pid)).copied().collect(),
+            layouts_removed: old_layouts.keys().filter(|pid| !new_layouts.contains_key(pid)).copied().collect(),
+            verity_changed: old_volumes.keys().chain(new_volumes.keys())
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .filter(|volume| old_volumes.get(*volume) != new_volumes.get(*volume))
+                .cloned()
+                .collect(),
+        })
     }
     
-    fn capture_memory_ranges(&self, mem_path: &str) -> Result<Vec<MemoryRange>, anyhow::Error> {
-        // Parse /proc/[pid]/maps and read memory contents
-        // This is simplified - real implementation would read actual memory
-        Ok(Vec::new())
+    // A snapshot without it is still worth having
+    fn capture_integrity(&self) -> Vec<VerityVolume> {
+        dm_verity::inspect(&self.verity_volumes).unwrap_or_else(|e| {
+            tracing::warn!("Snapshot taken without dm-verity state: {}", e);
+            Vec::new()
+        })
+    }
+    
+    // The processes the layouts and tokens belong to; layouts carry the
+    // identity they were exported with
+    fn capture_processes(state: &TrackedState) -> Vec<ProcessSnapshot> {
+        let mut processes: BTreeMap<u32, Option<ProcessIdentity>> = state.layouts.iter()
+            .map(|layout| (layout.pid, layout.identity.clone()))
+            .collect();
+        for token in &state.tokens {
+            processes.entry(token.pid).or_insert_with(|| process_maps::identity(token.pid));
+        }
+        processes.into_iter().map(|(pid, identity)| ProcessSnapshot { pid, identity }).collect()
     }
     
     fn save_snapshot(&self, snapshot: &KernelSnapshot) -> Result<(), anyhow::Error> {
@@ -0,0 +1,121 @@
+// src/syscall_embedding.rs
+// Pre-trained syscall embeddings. Syscalls that do similar things (read /
+// pread64 / readv) sit close together, so a variant attack that swaps one
+// call for its sibling still lands near the original in feature space.
+//
+// File format (little endian):
+//   "QKSEMB01"  magic
+//   u32 rows    one row per syscall number; the last row is the unknown token
+//   u32 dim
+//   f32 * rows * dim
+//
+// The table ships next to the model it was trained with (embedding.bin)
+// and widens the model's input by `dim`.
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"QKSEMB01";
+const HEADER_LEN: usize = 16;
+// Anything bigger is not a syscall table
+const MAX_ROWS: u32 = 4096;
+const MAX_DIM: u32 = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmbeddingError {
+    #[error("embedding file I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not an embedding table: {0}")]
+    Format(String),
+    #[error("embedding dimension {table} does not match the model's {expected}")]
+    Dimension { table: usize, expected: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct SyscallEmbedding {
+    rows: usize,
+    dim: usize,
+    vectors: Vec<f32>,
+}
+
+impl SyscallEmbedding {
+    pub fn load(path: &Path) -> Result<Self, EmbeddingError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    // None when the model ships without an embedding table
+    pub fn load_sidecar(model_path: &Path) -> Result<Option<Self>, EmbeddingError> {
+        match Self::load(&Self::sidecar_path(model_path)) {
+            Ok(table) => Ok(Some(table)),
+            Err(EmbeddingError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn sidecar_path(model_path: &Path) -> PathBuf {
+        if model_path.is_dir() {
+            model_path.join("embedding.bin")
+        } else {
+            model_path.with_extension("embedding.bin")
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, EmbeddingError> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err(EmbeddingError::Format("bad magic".into()));
+        }
+        let rows = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        let dim = u32::from_le_bytes(data[12..16].try_into().unwrap());
+        if rows < 2 || rows > MAX_ROWS || dim == 0 || dim > MAX_DIM {
+            return Err(EmbeddingError::Format(format!("implausible shape {}x{}", rows, dim)));
+        }
+
+        let (rows, dim) = (rows as usize, dim as usize);
+        let body = &data[HEADER_LEN..];
+        if body.len() != rows * dim * 4 {
+            return Err(EmbeddingError::Format(format!(
+                "{} bytes of vectors for a {}x{} table", body.len(), rows, dim
+            )));
+        }
+
+        let vectors: Vec<f32> = body.chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        if vectors.iter().any(|v| !v.is_finite()) {
+            return Err(EmbeddingError::Format("non-finite values".into()));
+        }
+
+        Ok(Self { rows, dim, vectors })
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn validate_dim(&self, expected: usize) -> Result<(), EmbeddingError> {
+        if self.dim != expected {
+            return Err(EmbeddingError::Dimension { table: self.dim, expected });
+        }
+        Ok(())
+    }
+
+    // Syscalls beyond the table share the unknown-token row
+    pub fn lookup(&self, syscall: u32) -> &[f32] {
+        let row = (syscall as usize).min(self.rows - 1);
+        &self.vectors[row * self.dim..(row + 1) * self.dim]
+    }
+
+    // Mean embedding of a sequence: where in "syscall space" the window sits
+    pub fn embed_sequence(&self, sequence: &[u32]) -> Vec<f32> {
+        let mut mean = vec![0.0; self.dim];
+        if sequence.is_empty() {
+            return mean;
+        }
+        for &syscall in sequence {
+            for (m, v) in mean.iter_mut().zip(self.lookup(syscall)) {
+                *m += v;
+            }
+        }
+        let n = sequence.len() as f32;
+        mean.iter_mut().for_each(|m| *m /= n);
+        mean
+    }
+}
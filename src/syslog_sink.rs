@@ -0,0 +1,435 @@
+// src/syslog_sink.rs
+// RFC 5424 records for sites that centralize logs through rsyslog. A
+// record's MSGID is its event type, the last segment of the tracing target
+// ("anomaly" for qks::anomaly), and its typed fields go in one
+// structured-data element. Facility and severity come from the longest
+// matching route, else the configured facility and the level's own
+// severity. Each event type has its own token bucket so a storm of one kind
+// can't drown the others; drops are counted and reported once the bucket
+// refills. Records are sent from a dedicated thread and dropped, never
+// waited on, when its queue is full.
+//
+// With `event_format` set, SecurityEvents of `event_kinds` are forwarded
+// too, rendered by siem_format.rs as the MSG with the kind as MSGID, so a
+// SIEM reading the syslog stream gets them as CEF, LEEF or ECS. They have
+// their own writer and are routed as target qks::<kind>.
+use crate::config::SyslogSection;
+use crate::events::EventBus;
+use crate::siem_format;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+// The private enterprise number RFC 5424 reserves for examples
+const SD_ID: &str = "qks@32473";
+const QUEUE_DEPTH: usize = 4096;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const NILVALUE: &str = "-";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyslogError {
+    #[error("invalid syslog address {0:?} (expected unix:PATH, udp:HOST:PORT or tcp:HOST:PORT)")]
+    Address(String),
+    #[error("cannot start the syslog writer: {0}")]
+    Spawn(std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Severity {
+    fn from_level(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => Severity::Err,
+            Level::WARN => Severity::Warning,
+            Level::INFO => Severity::Info,
+            _ => Severity::Debug,
+        }
+    }
+
+    // From the 0-10 scale CEF and LEEF use
+    fn from_siem(severity: u8) -> Self {
+        match severity {
+            9.. => Severity::Crit,
+            7..=8 => Severity::Err,
+            5..=6 => Severity::Warning,
+            3..=4 => Severity::Notice,
+            _ => Severity::Info,
+        }
+    }
+}
+
+// Events whose target starts with `target` get this facility and, if set,
+// this severity instead of the level's
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogRoute {
+    pub target: String,
+    pub facility: Option<Facility>,
+    pub severity: Option<Severity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddress {
+    Unix(PathBuf),
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for SyslogAddress {
+    type Err = SyslogError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SyslogError::Address(s.to_string());
+        let (scheme, rest) = s.split_once(':').ok_or_else(invalid)?;
+        match scheme {
+            "unix" if rest.starts_with('/') => Ok(SyslogAddress::Unix(PathBuf::from(rest))),
+            "udp" if rest.rsplit_once(':').is_some() => Ok(SyslogAddress::Udp(rest.to_string())),
+            "tcp" if rest.rsplit_once(':').is_some() => Ok(SyslogAddress::Tcp(rest.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+enum Transport {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    fn connect(address: &SyslogAddress) -> std::io::Result<Self> {
+        Ok(match address {
+            SyslogAddress::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Transport::Unix(socket)
+            }
+            SyslogAddress::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr.as_str())?;
+                Transport::Udp(socket)
+            }
+            SyslogAddress::Tcp(addr) => {
+                let stream = TcpStream::connect(addr.as_str())?;
+                stream.set_write_timeout(Some(RECONNECT_INTERVAL))?;
+                Transport::Tcp(stream)
+            }
+        })
+    }
+
+    fn send(&mut self, record: &str) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(socket) => socket.send(record.as_bytes()).map(|_| ()),
+            Transport::Udp(socket) => socket.send(record.as_bytes()).map(|_| ()),
+            // Octet-counting framing (RFC 6587), so records may contain newlines
+            Transport::Tcp(stream) => stream.write_all(format!("{} {}", record.len(), record).as_bytes()),
+        }
+    }
+}
+
+// Owns the connection; reconnects lazily, at most every RECONNECT_INTERVAL.
+// Must never log through tracing, which would feed back into this sink.
+fn run_writer(address: SyslogAddress, records: Receiver<String>) {
+    let mut transport: Option<Transport> = None;
+    let mut last_attempt: Option<Instant> = None;
+    for record in records {
+        for _ in 0..2 {
+            if transport.is_none() {
+                if last_attempt.map_or(false, |t| t.elapsed() < RECONNECT_INTERVAL) {
+                    break;
+                }
+                last_attempt = Some(Instant::now());
+                transport = Transport::connect(&address).ok();
+            }
+            match transport.as_mut().map(|t| t.send(&record)) {
+                Some(Ok(())) => break,
+                Some(Err(_)) => transport = None,
+                None => break,
+            }
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    suppressed: u64,
+}
+
+pub struct SyslogLayer {
+    routes: Vec<SyslogRoute>,
+    facility: Facility,
+    app_name: String,
+    hostname: String,
+    rate_per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    queue: SyncSender<String>,
+}
+
+impl SyslogLayer {
+    pub fn new(section: &SyslogSection) -> Result<Self, SyslogError> {
+        let address: SyslogAddress = section.address.parse()?;
+        let (queue, records) = sync_channel(QUEUE_DEPTH);
+        std::thread::Builder::new()
+            .name("qks-syslog".to_string())
+            .spawn(move || run_writer(address, records))
+            .map_err(SyslogError::Spawn)?;
+
+        Ok(Self {
+            routes: section.routes.clone(),
+            facility: section.facility,
+            app_name: header_field(&section.app_name, 48),
+            hostname: header_field(&hostname(), 255),
+            rate_per_sec: section.rate_per_sec as f64,
+            burst: section.burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+            queue,
+        })
+    }
+
+    fn route(&self, target: &str) -> (Facility, Option<Severity>) {
+        route(&self.routes, self.facility, target)
+    }
+
+    // Some(n) admits the event, n being how many of its type were dropped
+    // since the last one admitted
+    fn admit(&self, msgid: &str) -> Option<u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let bucket = buckets
+            .entry(msgid.to_string())
+            .or_insert(Bucket { tokens: self.burst, refilled: now, suppressed: 0 });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate_per_sec).min(self.burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Some(std::mem::take(&mut bucket.suppressed))
+        } else {
+            bucket.suppressed += 1;
+            None
+        }
+    }
+
+    fn enqueue(&self, facility: Facility, severity: Severity, msgid: &str, fields: &[(String, String)], message: &str) {
+        let record = record(facility, severity, &self.hostname, &self.app_name, msgid, &structured_data(fields), message);
+        // A full queue means the collector is down or slow; drop
+        let _ = self.queue.try_send(record);
+    }
+}
+
+fn route(routes: &[SyslogRoute], facility: Facility, target: &str) -> (Facility, Option<Severity>) {
+    routes
+        .iter()
+        .filter(|r| target.starts_with(&r.target))
+        .max_by_key(|r| r.target.len())
+        .map_or((facility, None), |r| (r.facility.unwrap_or(facility), r.severity))
+}
+
+fn record(facility: Facility, severity: Severity, hostname: &str, app_name: &str, msgid: &str, sd: &str, message: &str) -> String {
+    let pri = (facility as u8) * 8 + severity as u8;
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        pri,
+        timestamp(SystemTime::now()),
+        hostname,
+        app_name,
+        std::process::id(),
+        msgid,
+        sd,
+        message
+    )
+}
+
+// Forwards bus events until the bus goes away; None when `event_format` is
+// unset. Not rate limited: the kinds are chosen, and syscalls are left out
+// by default.
+pub fn forward_events(section: &SyslogSection, bus: &EventBus) -> Result<Option<tokio::task::JoinHandle<()>>, SyslogError> {
+    let Some(format) = section.event_format else {
+        return Ok(None);
+    };
+    let address: SyslogAddress = section.address.parse()?;
+    let (queue, records) = sync_channel(QUEUE_DEPTH);
+    std::thread::Builder::new()
+        .name("qks-syslog-events".to_string())
+        .spawn(move || run_writer(address, records))
+        .map_err(SyslogError::Spawn)?;
+
+    let section = section.clone();
+    let mut events = bus.subscribe_to(&section.event_kinds).with_name("syslog");
+    Ok(Some(tokio::spawn(async move {
+        let (host, app_name) = (hostname(), header_field(&section.app_name, 48));
+        let header_host = header_field(&host, 255);
+        while let Some(event) = events.recv().await {
+            let kind = event.kind().as_str();
+            let (facility, severity) = route(&section.routes, section.facility, &format!("qks::{}", kind));
+            let severity = severity.unwrap_or_else(|| Severity::from_siem(siem_format::severity(&event)));
+            let message = siem_format::render(format, &event, &host);
+            let _ = queue.try_send(record(facility, severity, &header_host, &app_name, kind, NILVALUE, &message));
+        }
+    })))
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let msgid = header_field(metadata.target().rsplit("::").next().unwrap_or_default(), 32);
+        let Some(suppressed) = self.admit(&msgid) else {
+            return;
+        };
+        let (facility, severity) = self.route(metadata.target());
+        let severity = severity.unwrap_or_else(|| Severity::from_level(metadata.level()));
+
+        if suppressed > 0 {
+            let fields = [("suppressed".to_string(), suppressed.to_string())];
+            let message = format!("rate limit: {} {} records suppressed", suppressed, msgid);
+            self.enqueue(facility, Severity::Warning, &msgid, &fields, &message);
+        }
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        self.enqueue(facility, severity, &msgid, &visitor.fields, &visitor.message);
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+}
+
+// `[qks@32473 pid="1234" score="0.91"]`, or the nil value without fields
+fn structured_data(fields: &[(String, String)]) -> String {
+    if fields.is_empty() {
+        return NILVALUE.to_string();
+    }
+    let mut sd = format!("[{}", SD_ID);
+    for (name, value) in fields {
+        let name = header_field(&name.replace(['=', ']', '"'], "_"), 32);
+        let _ = write!(sd, " {}=\"", name);
+        for c in value.chars() {
+            if matches!(c, '"' | '\\' | ']') {
+                sd.push('\\');
+            }
+            sd.push(c);
+        }
+        sd.push('"');
+    }
+    sd.push(']');
+    sd
+}
+
+// Header fields are printable US-ASCII without spaces, bounded in length
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if field.is_empty() {
+        NILVALUE.to_string()
+    } else {
+        field
+    }
+}
+
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return NILVALUE.to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// RFC 3339 in UTC with microseconds, e.g. 2024-05-01T12:00:00.000000Z
+pub(crate) fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+        since_epoch.subsec_micros()
+    )
+}
@@ -0,0 +1,140 @@
+// src/systemd.rs
+// Behaving like a first-class Debian service: READY=1 once every subsystem
+// has been brought up (degraded ones included), a STATUS= line with the
+// health summary, WATCHDOG=1 only while the supervision loop is alive and no
+// critical subsystem has failed, and the control socket taken from
+// qksd.socket when systemd passes one. All of it is a no-op outside systemd.
+use crate::daemon::SubsystemHealth;
+use dashmap::DashMap;
+use sd_notify::NotifyState;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Without these qksd is not doing its job, however alive it looks
+const CRITICAL_SUBSYSTEMS: &[&str] = &["tokens", "control"];
+// Supervisor heartbeats older than this mean the runtime is wedged
+const HEARTBEAT_STALE_SECS: u64 = 30;
+
+fn notify(states: &[NotifyState]) {
+    // Keep NOTIFY_SOCKET set: the watchdog and STOPPING=1 need it later
+    if let Err(e) = sd_notify::notify(false, states) {
+        tracing::debug!("sd_notify failed: {}", e);
+    }
+}
+
+pub fn notify_ready(health: &DashMap<&'static str, SubsystemHealth>) {
+    let status = status_line(health);
+    notify(&[NotifyState::Ready, NotifyState::Status(&status)]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// "5 running, 1 degraded (monitor)"
+pub fn status_line(health: &DashMap<&'static str, SubsystemHealth>) -> String {
+    let mut running = 0;
+    let mut degraded = Vec::new();
+    let mut failed = Vec::new();
+    for entry in health.iter() {
+        match entry.value() {
+            SubsystemHealth::Running => running += 1,
+            SubsystemHealth::Degraded { .. } => degraded.push(*entry.key()),
+            SubsystemHealth::Failed { .. } => failed.push(*entry.key()),
+            SubsystemHealth::Starting | SubsystemHealth::Stopped => {}
+        }
+    }
+    degraded.sort_unstable();
+    failed.sort_unstable();
+
+    let mut status = format!("{} running", running);
+    if !degraded.is_empty() {
+        status.push_str(&format!(", {} degraded ({})", degraded.len(), degraded.join(", ")));
+    }
+    if !failed.is_empty() {
+        status.push_str(&format!(", {} failed ({})", failed.len(), failed.join(", ")));
+    }
+    status
+}
+
+// Why a ping is being withheld, if it is
+fn unhealthy(health: &DashMap<&'static str, SubsystemHealth>, heartbeat: &AtomicU64) -> Option<String> {
+    let age = now_secs().saturating_sub(heartbeat.load(Ordering::Relaxed));
+    if age > HEARTBEAT_STALE_SECS {
+        return Some(format!("supervisor last ran {}s ago", age));
+    }
+    CRITICAL_SUBSYSTEMS.iter().find_map(|subsystem| match health.get(subsystem).as_deref() {
+        Some(SubsystemHealth::Failed { reason }) => Some(format!("{} failed: {}", subsystem, reason)),
+        _ => None,
+    })
+}
+
+// Pings at half of WatchdogSec while healthy; None when the unit has no
+// watchdog. A withheld ping lets systemd restart qksd.
+pub fn start_watchdog(
+    health: Arc<DashMap<&'static str, SubsystemHealth>>,
+    heartbeat: Arc<AtomicU64>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return None;
+    }
+    let period = Duration::from_micros(usec / 2);
+    tracing::info!("systemd watchdog enabled; pinging every {:?}", period);
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        let mut last_status = String::new();
+        loop {
+            interval.tick().await;
+            let status = status_line(&health);
+            match unhealthy(&health, &heartbeat) {
+                None if status != last_status => notify(&[NotifyState::Watchdog, NotifyState::Status(&status)]),
+                None => notify(&[NotifyState::Watchdog]),
+                Some(reason) => {
+                    tracing::error!("Withholding watchdog ping: {}", reason);
+                    notify(&[NotifyState::Status(&format!("unhealthy: {}", reason))]);
+                }
+            }
+            last_status = status;
+        }
+    }))
+}
+
+// The first Unix socket passed by socket activation, if any
+pub fn activated_control_listener() -> Option<UnixListener> {
+    let fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(e) => {
+            tracing::debug!("No sockets from systemd: {}", e);
+            return None;
+        }
+    };
+    for fd in fds {
+        if !is_unix_socket(fd) {
+            tracing::warn!("Ignoring non-Unix socket fd {} passed by systemd", fd);
+            continue;
+        }
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        if let Err(e) = listener.set_nonblocking(true) {
+            tracing::warn!("Activated control socket unusable: {}", e);
+            continue;
+        }
+        return Some(listener);
+    }
+    None
+}
+
+fn is_unix_socket(fd: RawFd) -> bool {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ok = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } == 0;
+    ok && addr.ss_family as libc::c_int == libc::AF_UNIX
+}
@@ -0,0 +1,162 @@
+// src/threshold_calibration.rs
+// Alert thresholds from a false-positive budget. Recorded benign traffic
+// (JSON lines of {"timestamp": secs, "features": [...]}) is replayed through
+// the installed model; the thresholds are the scores that only the allowed
+// number of benign rows per hour exceed. The result is saved next to the
+// model as thresholds.json / <model>.thresholds.json, which the model's
+// signature covers: sign the model again after calibrating it.
+use crate::inference_backend::InferenceError;
+use crate::ml_detector::{FeatureVector, MLAnomalyDetector, ScoringMode};
+use serde::{Deserialize, Serialize};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REPLAY_BATCH: usize = 256;
+// Shorter recordings make per-hour budgets meaningless
+const MIN_RECORDING_SECS: u64 = 3600;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CalibrationError {
+    #[error("calibration needs a trained model; the detector is still on its baseline")]
+    NoModel,
+    #[error("recording covers {0}s; at least {MIN_RECORDING_SECS}s of traffic is needed")]
+    InsufficientData(u64),
+    #[error("recording line {line}: {source}")]
+    Record { line: usize, source: serde_json::Error },
+    #[error("thresholds format: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("calibration I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Inference(#[from] InferenceError),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordedSample {
+    pub timestamp: u64,
+    pub features: FeatureVector,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FalsePositiveBudget {
+    pub alerts_per_hour: f64,
+    pub warnings_per_hour: f64,
+}
+
+impl Default for FalsePositiveBudget {
+    fn default() -> Self {
+        Self { alerts_per_hour: 1.0, warnings_per_hour: 10.0 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibratedThresholds {
+    pub alert: f32,
+    pub warning: f32,
+    pub budget: FalsePositiveBudget,
+    pub model_version: u64,
+    pub samples: usize,
+    pub recording_secs: u64,
+    // Benign rows over each threshold in the recording, per hour
+    pub observed_alerts_per_hour: f64,
+    pub observed_warnings_per_hour: f64,
+    pub calibrated_at: u64,
+}
+
+impl CalibratedThresholds {
+    pub fn sidecar_path(model_path: &Path) -> PathBuf {
+        if model_path.is_dir() {
+            model_path.join("thresholds.json")
+        } else {
+            model_path.with_extension("thresholds.json")
+        }
+    }
+
+    pub fn save(&self, model_path: &Path) -> Result<(), CalibrationError> {
+        std::fs::write(Self::sidecar_path(model_path), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(model_path: &Path) -> Result<Option<Self>, CalibrationError> {
+        match std::fs::read(Self::sidecar_path(model_path)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedSample>, CalibrationError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut samples = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        samples.push(serde_json::from_str(&line).map_err(|source| CalibrationError::Record { line: i + 1, source })?);
+    }
+    Ok(samples)
+}
+
+pub fn calibrate(
+    detector: &mut MLAnomalyDetector,
+    recording: &[RecordedSample],
+    budget: FalsePositiveBudget,
+) -> Result<CalibratedThresholds, CalibrationError> {
+    if detector.scoring_mode() != ScoringMode::Model {
+        return Err(CalibrationError::NoModel);
+    }
+
+    let first = recording.iter().map(|s| s.timestamp).min().unwrap_or(0);
+    let last = recording.iter().map(|s| s.timestamp).max().unwrap_or(0);
+    let recording_secs = last - first;
+    if recording_secs < MIN_RECORDING_SECS {
+        return Err(CalibrationError::InsufficientData(recording_secs));
+    }
+    let hours = recording_secs as f64 / 3600.0;
+
+    let mut scores = Vec::with_capacity(recording.len());
+    for chunk in recording.chunks(REPLAY_BATCH) {
+        let rows: Vec<FeatureVector> = chunk.iter().map(|s| s.features.clone()).collect();
+        scores.extend(detector.detect_anomalies_batch(&rows)?.into_iter().map(|r| r.score));
+    }
+    scores.sort_by(|a, b| b.total_cmp(a));
+
+    let (alert, alerts) = threshold_for(&scores, budget.alerts_per_hour * hours);
+    let (warning, warnings) = threshold_for(&scores, budget.warnings_per_hour * hours);
+
+    let thresholds = CalibratedThresholds {
+        alert,
+        // A looser budget can never produce a stricter warning level
+        warning: warning.min(alert),
+        budget,
+        model_version: detector.model_version(),
+        samples: scores.len(),
+        recording_secs,
+        observed_alerts_per_hour: alerts as f64 / hours,
+        observed_warnings_per_hour: warnings as f64 / hours,
+        calibrated_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    };
+    tracing::info!(
+        "Calibrated model {}: alert > {:.4} ({:.2}/h), warning > {:.4} ({:.2}/h) over {} benign samples",
+        thresholds.model_version, thresholds.alert, thresholds.observed_alerts_per_hour,
+        thresholds.warning, thresholds.observed_warnings_per_hour, thresholds.samples
+    );
+    Ok(thresholds)
+}
+
+// Lowest threshold that at most `allowed` of the (descending) scores exceed,
+// and how many do
+fn threshold_for(descending: &[f32], allowed: f64) -> (f32, usize) {
+    if descending.is_empty() {
+        return (1.0, 0);
+    }
+    let allowed = (allowed.floor() as usize).min(descending.len() - 1);
+
+    // Alerts fire on score > threshold, so ties at the cut all stay quiet
+    let threshold = descending[allowed];
+    let exceeding = descending.partition_point(|&s| s > threshold);
+    (threshold, exceeding)
+}
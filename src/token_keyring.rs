@@ -0,0 +1,268 @@
+// src/token_keyring.rs
+use crate::crypto_identifiers::ProcessToken;
+use crate::process_maps;
+use std::ffi::CString;
+use std::io;
+
+// Key permission bits from <linux/keyctl.h> (not exported by libc)
+const KEY_POS_VIEW: u32 = 0x0100_0000;
+const KEY_POS_READ: u32 = 0x0200_0000;
+const KEY_POS_WRITE: u32 = 0x0400_0000;
+const KEY_POS_SEARCH: u32 = 0x0800_0000;
+const KEY_POS_SETATTR: u32 = 0x2000_0000;
+const KEY_USR_VIEW: u32 = 0x0001_0000;
+const KEY_USR_READ: u32 = 0x0002_0000;
+
+const KEY_TYPE: &str = "user";
+const KEYRING_TYPE: &str = "keyring";
+const KEY_DESCRIPTION: &str = "qks:token";
+// Per-target keyrings are "qks:tokens:<pid>:<start time>"
+const KEYRING_PREFIX: &str = "qks:tokens";
+
+pub type KeySerial = i32;
+
+// The daemon keyring that holds one keyring per target process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyringScope {
+    Thread,
+    Process,
+    Session,
+}
+
+// Each token sits in a keyring of its own process, named by PID and start
+// time so a later process given the PID finds nothing; sweep() revokes
+// those of processes that have exited
+pub struct TokenKeyring {
+    scope: KeyringScope,
+}
+
+impl KeyringScope {
+    fn special_id(self) -> i32 {
+        match self {
+            KeyringScope::Thread => libc::KEY_SPEC_THREAD_KEYRING,
+            KeyringScope::Process => libc::KEY_SPEC_PROCESS_KEYRING,
+            KeyringScope::Session => libc::KEY_SPEC_SESSION_KEYRING,
+        }
+    }
+}
+
+impl TokenKeyring {
+    pub fn new(scope: KeyringScope) -> Self {
+        Self { scope }
+    }
+
+    pub fn store(&self, token: &ProcessToken) -> Result<KeySerial, io::Error> {
+        let start_time = process_maps::start_time(token.pid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("PID {} is not running", token.pid)))?;
+        let payload = serde_json::to_vec(token)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Returns the existing keyring if there is one
+        let target = Self::add_key(KEYRING_TYPE, &Self::keyring_description(token.pid, start_time), &[], self.scope.special_id())?;
+        let perm = KEY_POS_VIEW | KEY_POS_READ | KEY_POS_WRITE | KEY_POS_SEARCH | KEY_POS_SETATTR | KEY_USR_VIEW;
+        Self::keyctl(libc::KEYCTL_SETPERM, target as u64, perm as u64, 0)?;
+
+        // add_key replaces the payload if a key with this description exists
+        let serial = Self::add_key(KEY_TYPE, KEY_DESCRIPTION, &payload, target)?;
+
+        // Owner-only: possessor may read/search, owning uid may only view
+        let perm = KEY_POS_VIEW | KEY_POS_READ | KEY_POS_SEARCH | KEY_POS_SETATTR
+            | KEY_USR_VIEW | KEY_USR_READ;
+        Self::keyctl(libc::KEYCTL_SETPERM, serial as u64, perm as u64, 0)?;
+
+        Ok(serial)
+    }
+
+    pub fn find(&self, pid: u32) -> Result<Option<KeySerial>, io::Error> {
+        let Some(target) = self.target(pid)? else {
+            return Ok(None);
+        };
+        Self::search(target, KEY_TYPE, KEY_DESCRIPTION)
+    }
+
+    pub fn load(&self, pid: u32) -> Result<Option<ProcessToken>, io::Error> {
+        let serial = match self.find(pid)? {
+            Some(serial) => serial,
+            None => return Ok(None),
+        };
+
+        let payload = Self::read_key(serial)?;
+        let token = serde_json::from_slice(&payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Some(token))
+    }
+
+    pub fn revoke(&self, pid: u32) -> Result<bool, io::Error> {
+        let Some(target) = self.target(pid)? else {
+            return Ok(false);
+        };
+        self.drop_target(target)?;
+        Ok(true)
+    }
+
+    // The tokens of every running process that has one
+    pub fn list(&self) -> Result<Vec<ProcessToken>, io::Error> {
+        let mut tokens = Vec::new();
+        for serial in Self::read_keyring(self.scope.special_id())? {
+            let Some(description) = Self::describe(serial, KEYRING_TYPE) else {
+                continue;
+            };
+            let Some((pid, start_time)) = Self::parse_keyring_description(&description) else {
+                continue;
+            };
+            if process_maps::start_time(pid) != Some(start_time) {
+                continue;
+            }
+            let Some(key) = Self::search(serial, KEY_TYPE, KEY_DESCRIPTION)? else {
+                continue;
+            };
+            match serde_json::from_slice(&Self::read_key(key)?) {
+                Ok(token) => tokens.push(token),
+                Err(e) => tracing::warn!(pid, "Token of PID {} in the keyring is unreadable: {}", pid, e),
+            }
+        }
+        Ok(tokens)
+    }
+
+    // Revokes the keyrings of processes that have exited (or whose PID
+    // now belongs to another process); returns how many
+    pub fn sweep(&self) -> Result<usize, io::Error> {
+        let mut revoked = 0;
+        for serial in Self::read_keyring(self.scope.special_id())? {
+            let Some(description) = Self::describe(serial, KEYRING_TYPE) else {
+                continue;
+            };
+            let Some((pid, start_time)) = Self::parse_keyring_description(&description) else {
+                continue;
+            };
+            if process_maps::start_time(pid) == Some(start_time) {
+                continue;
+            }
+            match self.drop_target(serial) {
+                Ok(()) => revoked += 1,
+                Err(e) => tracing::warn!(pid, "Token keyring of exited PID {} not revoked: {}", pid, e),
+            }
+        }
+        Ok(revoked)
+    }
+
+    // The keyring of the process now running as `pid`, if it has one
+    fn target(&self, pid: u32) -> Result<Option<KeySerial>, io::Error> {
+        let Some(start_time) = process_maps::start_time(pid) else {
+            return Ok(None);
+        };
+        Self::search(self.scope.special_id(), KEYRING_TYPE, &Self::keyring_description(pid, start_time))
+    }
+
+    // Revoke first so any other holder of the serial loses access to the
+    // keyring and the token in it, then drop it from ours
+    fn drop_target(&self, target: KeySerial) -> Result<(), io::Error> {
+        Self::keyctl(libc::KEYCTL_REVOKE, target as u64, 0, 0)?;
+        Self::keyctl(libc::KEYCTL_UNLINK, target as u64, self.scope.special_id() as u64, 0)?;
+        Ok(())
+    }
+
+    fn add_key(key_type: &str, description: &str, payload: &[u8], keyring: i32) -> Result<KeySerial, io::Error> {
+        let key_type = CString::new(key_type).unwrap();
+        let desc = CString::new(description).unwrap();
+        // A keyring takes no payload
+        let data = if payload.is_empty() { std::ptr::null() } else { payload.as_ptr() };
+
+        // SAFETY: the strings are NUL-terminated and `data` is null or
+        // valid for payload.len() bytes
+        let serial = unsafe {
+            libc::syscall(libc::SYS_add_key, key_type.as_ptr(), desc.as_ptr(), data, payload.len(), keyring)
+        };
+        if serial < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(serial as KeySerial)
+    }
+
+    fn search(keyring: i32, key_type: &str, description: &str) -> Result<Option<KeySerial>, io::Error> {
+        let key_type = CString::new(key_type).unwrap();
+        let desc = CString::new(description).unwrap();
+
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_SEARCH,
+                keyring,
+                key_type.as_ptr(),
+                desc.as_ptr(),
+                0,
+            )
+        };
+
+        if serial < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOKEY) | Some(libc::EKEYREVOKED) | Some(libc::EKEYEXPIRED) => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        Ok(Some(serial as KeySerial))
+    }
+
+    // The serials linked into a keyring
+    fn read_keyring(keyring: i32) -> Result<Vec<KeySerial>, io::Error> {
+        let payload = Self::read_key(keyring)?;
+        Ok(payload
+            .chunks_exact(std::mem::size_of::<KeySerial>())
+            .map(|chunk| KeySerial::from_ne_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    // The description of `serial` if it is of `key_type`; KEYCTL_DESCRIBE
+    // gives "type;uid;gid;perm;description"
+    fn describe(serial: KeySerial, key_type: &str) -> Option<String> {
+        let len = Self::keyctl(libc::KEYCTL_DESCRIBE, serial as u64, 0, 0).ok()?;
+        let mut buffer = vec![0u8; len as usize];
+        let read = Self::keyctl(libc::KEYCTL_DESCRIBE, serial as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64).ok()?;
+        buffer.truncate((read as usize).min(buffer.len()));
+        let text = String::from_utf8(buffer).ok()?;
+        let mut fields = text.trim_end_matches('\0').splitn(5, ';');
+        if fields.next()? != key_type {
+            return None;
+        }
+        fields.nth(3).map(str::to_string)
+    }
+
+    fn read_key(serial: KeySerial) -> Result<Vec<u8>, io::Error> {
+        // First call with no buffer returns the payload size
+        let len = Self::keyctl(libc::KEYCTL_READ, serial as u64, 0, 0)?;
+        let mut buffer = vec![0u8; len as usize];
+
+        let read = Self::keyctl(
+            libc::KEYCTL_READ,
+            serial as u64,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+        )?;
+        buffer.truncate(read as usize);
+
+        Ok(buffer)
+    }
+
+    fn keyctl(op: u32, arg2: u64, arg3: u64, arg4: u64) -> Result<i64, io::Error> {
+        let ret = unsafe { libc::syscall(libc::SYS_keyctl, op, arg2, arg3, arg4, 0u64) };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as i64)
+        }
+    }
+
+    fn keyring_description(pid: u32, start_time: u64) -> String {
+        format!("{}:{}:{}", KEYRING_PREFIX, pid, start_time)
+    }
+
+    fn parse_keyring_description(description: &str) -> Option<(u32, u64)> {
+        let rest = description.strip_prefix(KEYRING_PREFIX)?.strip_prefix(':')?;
+        let (pid, start_time) = rest.split_once(':')?;
+        Some((pid.parse().ok()?, start_time.parse().ok()?))
+    }
+}
@@ -0,0 +1,167 @@
+// src/token_status.rs
+use crate::clock::Clock;
+use crate::constant_time;
+use crate::crypto_identifiers::{CryptoIdentifier, ProcessToken, RevocationProof};
+use dashmap::DashMap;
+use ring::signature;
+use std::sync::Arc;
+
+// How long a verifier may cache a response before asking again
+const DEFAULT_RESPONSE_VALIDITY_SECS: u64 = 300;
+
+pub struct TokenStatusResponder {
+    identity: Arc<CryptoIdentifier>,
+    revoked: Arc<DashMap<[u8; 32], RevocationProof>>,
+    response_validity_secs: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StatusRequest {
+    pub token: ProcessToken,
+    // Echoed back so a verifier can reject replayed responses
+    pub nonce: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum OnlineStatus {
+    Good { expires_at: u64 },
+    Expired { expired_at: u64 },
+    Revoked { revoked_at: u64 },
+    // Not issued by this responder, signature does not verify, or issued
+    // further in the future than the skew tolerance allows
+    Unknown,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StatusResponse {
+    pub token_digest: [u8; 32],
+    pub status: OnlineStatus,
+    pub produced_at: u64,
+    pub next_update: u64,
+    pub nonce: Option<[u8; 16]>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusVerifyError {
+    #[error("response signature is invalid")]
+    BadSignature,
+    #[error("response is for a different token")]
+    WrongToken,
+    #[error("response nonce does not match the request")]
+    NonceMismatch,
+    #[error("response is stale (next update was {0})")]
+    Stale(u64),
+}
+
+impl TokenStatusResponder {
+    pub fn new(identity: Arc<CryptoIdentifier>) -> Self {
+        Self {
+            revoked: identity.revocations(),
+            identity,
+            response_validity_secs: DEFAULT_RESPONSE_VALIDITY_SECS,
+        }
+    }
+
+    pub fn with_response_validity(mut self, response_validity_secs: u64) -> Self {
+        self.response_validity_secs = response_validity_secs;
+        self
+    }
+
+    pub fn respond(&self, request: &StatusRequest) -> StatusResponse {
+        let now = self.identity.now();
+        let token = &request.token;
+        let token_digest = constant_time::lookup_key(&token.signature);
+
+        // Skew tolerance applies in both directions, as in introspect
+        let skew = self.identity.skew_tolerance();
+        let status = if self.identity.verify_token(token).is_err() || token.timestamp > now.saturating_add(skew) {
+            OnlineStatus::Unknown
+        } else if let Some(revoked_at) = self.revoked_at(token) {
+            OnlineStatus::Revoked { revoked_at }
+        } else {
+            let expires_at = token.timestamp.saturating_add(self.identity.token_lifetime());
+            if now >= expires_at.saturating_add(skew) {
+                OnlineStatus::Expired { expired_at: expires_at }
+            } else {
+                OnlineStatus::Good { expires_at }
+            }
+        };
+
+        // A good token must be rechecked no later than its own expiry
+        let next_update = match status {
+            OnlineStatus::Good { expires_at } => (now + self.response_validity_secs).min(expires_at.saturating_add(skew)),
+            _ => now + self.response_validity_secs,
+        };
+
+        let mut response = StatusResponse {
+            token_digest,
+            status,
+            produced_at: now,
+            next_update,
+            nonce: request.nonce,
+            signature: Vec::new(),
+        };
+        response.signature = self.identity.sign(&Self::response_bytes(&response));
+
+        response
+    }
+
+    // The token's own revocation, else its parent's, which invalidates
+    // every token derived from it as in introspect
+    fn revoked_at(&self, token: &ProcessToken) -> Option<u64> {
+        std::iter::once(&token.signature)
+            .chain(token.parent_token.as_ref())
+            .find_map(|signature| self.revoked.get(&constant_time::lookup_key(signature)).map(|proof| proof.revoked_at))
+    }
+
+    pub fn start_serving(
+        self: Arc<Self>,
+        mut requests: tokio::sync::mpsc::Receiver<(StatusRequest, tokio::sync::oneshot::Sender<StatusResponse>)>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some((request, reply)) = requests.recv().await {
+                let _ = reply.send(self.respond(&request));
+            }
+        })
+    }
+
+    // Verifier side: needs only the responder's public key, not the CRL
+    pub fn verify_response(
+        response: &StatusResponse,
+        request: &StatusRequest,
+        responder_public_key: &[u8],
+        clock: &dyn Clock,
+    ) -> Result<OnlineStatus, StatusVerifyError> {
+        signature::UnparsedPublicKey::new(&signature::ED25519, responder_public_key)
+            .verify(&Self::response_bytes(response), &response.signature)
+            .map_err(|_| StatusVerifyError::BadSignature)?;
+
+        let expected_digest = constant_time::lookup_key(&request.token.signature);
+        if !constant_time::ct_eq(&response.token_digest, &expected_digest) {
+            return Err(StatusVerifyError::WrongToken);
+        }
+
+        if request.nonce.is_some() && response.nonce != request.nonce {
+            return Err(StatusVerifyError::NonceMismatch);
+        }
+
+        if clock.now_secs() > response.next_update {
+            return Err(StatusVerifyError::Stale(response.next_update));
+        }
+
+        Ok(response.status)
+    }
+
+    fn response_bytes(response: &StatusResponse) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&response.token_digest);
+        data.extend_from_slice(&serde_json::to_vec(&response.status).unwrap());
+        data.extend_from_slice(&response.produced_at.to_be_bytes());
+        data.extend_from_slice(&response.next_update.to_be_bytes());
+        if let Some(nonce) = response.nonce {
+            data.extend_from_slice(&nonce);
+        }
+        data
+    }
+}
@@ -0,0 +1,508 @@
+// src/tpm.rs
+// TPM 2.0 attestation of what qksd did, with the `tpm` feature
+// (tss-esapi). Security-relevant events (the configuration loaded or
+// changed, a model loaded, a snapshot restored) are hashed and extended
+// into one PCR, and each is appended to an event log in the state
+// directory so a verifier can replay it. The PCR starts from zeros at
+// boot, so the log is per boot: entries from an earlier boot are dropped
+// when the log is opened.
+//
+// A quote is the TPM's signature, with an attestation key, over the PCR's
+// current value and a caller's nonce. The AK is a restricted ECDSA P-256
+// primary key in the owner hierarchy: its template is fixed, so the TPM
+// derives the same key on every start and a controller can enroll it once
+// (trusted_aks). Linking the AK to the TPM's endorsement key is left to
+// enrollment. A controller verifies a peer's quote without a TPM of its
+// own: the AK must be trusted, the signature valid, the nonce its own, and
+// the event log must replay to the quoted PCR digest.
+use crate::audit_log::hex;
+use crate::config::TpmSection;
+use crate::model_signing::decode_hex;
+use crate::systemd;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::mpsc;
+
+// TPM_GENERATED_VALUE and TPM_ST_ATTEST_QUOTE
+const TPM_GENERATED: u32 = 0xff54_4347;
+const ST_ATTEST_QUOTE: u16 = 0x8018;
+const ALG_SHA256: u16 = 0x000b;
+pub const MAX_NONCE_BYTES: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TpmError {
+    #[error("{0} support not compiled in")]
+    Unsupported(&'static str),
+    #[error("TPM: {0}")]
+    Device(String),
+    #[error("{}: {source}", .path.display())]
+    Log { path: PathBuf, source: std::io::Error },
+    #[error("{}: {reason}", .path.display())]
+    Key { path: PathBuf, reason: String },
+    #[error("malformed quote: {0}")]
+    Malformed(String),
+    #[error("quote is signed by an attestation key that is not trusted")]
+    UntrustedKey,
+    #[error("quote signature does not verify")]
+    BadSignature,
+    #[error("quote is not for this nonce")]
+    NonceMismatch,
+    #[error("event log does not replay to the quoted PCR value")]
+    LogMismatch,
+}
+
+// One measured event, as extended and as logged
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasuredEvent {
+    pub boot_id: String,
+    pub seq: u64,
+    // config_load, config_change, model_load, snapshot_restore
+    pub kind: String,
+    pub detail: String,
+    pub timestamp: u64,
+    // SHA-256 of kind, NUL, detail: what was extended
+    pub digest: String,
+}
+
+impl MeasuredEvent {
+    fn digest_of(kind: &str, detail: &str) -> [u8; 32] {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(kind.as_bytes());
+        context.update(&[0]);
+        context.update(detail.as_bytes());
+        context.finish().as_ref().try_into().unwrap_or([0; 32])
+    }
+}
+
+// PCR value after extending each event in turn from zeros
+pub fn replay(events: &[MeasuredEvent]) -> [u8; 32] {
+    events.iter().fold([0u8; 32], |pcr, event| {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&pcr);
+        context.update(&MeasuredEvent::digest_of(&event.kind, &event.detail));
+        context.finish().as_ref().try_into().unwrap_or([0; 32])
+    })
+}
+
+// What a host hands a verifier; byte fields are hex
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quote {
+    pub host: String,
+    pub pcr: u8,
+    pub nonce: String,
+    // The marshalled TPMS_ATTEST the TPM signed
+    pub attest: String,
+    // ECDSA r || s, 32 bytes each
+    pub signature: String,
+    // Uncompressed P-256 point of the AK
+    pub ak_public: String,
+    pub events: Vec<MeasuredEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifiedQuote {
+    pub host: String,
+    pub pcr: u8,
+    pub ak_public: String,
+    pub events: usize,
+    // TPM clock, and reboots and resumes since the TPM was cleared
+    pub clock_ms: u64,
+    pub reset_count: u32,
+    pub restart_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TpmStatus {
+    pub pcr: u8,
+    pub ak_public: String,
+    pub events: Vec<MeasuredEvent>,
+}
+
+fn boot_id() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id").map(|id| id.trim().to_string()).unwrap_or_default()
+}
+
+pub fn file_digest(path: &Path) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+    Some(format!("sha256:{}", hex(digest::digest(&digest::SHA256, &bytes).as_ref())))
+}
+
+// Events waiting to be extended, once the TPM is started
+static QUEUE: OnceLock<mpsc::UnboundedSender<(String, String)>> = OnceLock::new();
+
+// Queues an event for the PCR; nothing happens without a TPM
+pub fn measure(kind: &str, detail: impl Into<String>) {
+    if let Some(queue) = QUEUE.get() {
+        let _ = queue.send((kind.to_string(), detail.into()));
+    }
+}
+
+// A value by the digest of its JSON, e.g. a whole configuration
+pub fn measure_json<T: Serialize>(kind: &str, value: &T) {
+    if QUEUE.get().is_some() {
+        let json = serde_json::to_vec(value).unwrap_or_default();
+        measure(kind, format!("sha256:{}", hex(digest::digest(&digest::SHA256, &json).as_ref())));
+    }
+}
+
+// The file's digest, with its path, so the log says what was loaded
+pub fn measure_file(kind: &str, path: &Path) {
+    if QUEUE.get().is_some() {
+        let digest = file_digest(path).unwrap_or_else(|| "unreadable".to_string());
+        measure(kind, format!("{} {}", path.display(), digest));
+    }
+}
+
+pub struct Tpm {
+    pcr: u8,
+    log_path: PathBuf,
+    events: Mutex<Vec<MeasuredEvent>>,
+    ak_public: Vec<u8>,
+    #[cfg(feature = "tpm")]
+    device: Mutex<device::Device>,
+}
+
+impl Tpm {
+    #[cfg(feature = "tpm")]
+    pub fn open(section: &TpmSection, log_path: PathBuf) -> Result<Self, TpmError> {
+        let (device, ak_public) = device::Device::open(&section.tcti)?;
+        let events = Self::read_log(&log_path)?;
+        Ok(Self { pcr: section.pcr, log_path, events: Mutex::new(events), ak_public, device: Mutex::new(device) })
+    }
+
+    #[cfg(not(feature = "tpm"))]
+    pub fn open(_section: &TpmSection, _log_path: PathBuf) -> Result<Self, TpmError> {
+        Err(TpmError::Unsupported("tpm"))
+    }
+
+    // This boot's entries; the file is rewritten without earlier boots'
+    #[cfg_attr(not(feature = "tpm"), allow(dead_code))]
+    fn read_log(path: &Path) -> Result<Vec<MeasuredEvent>, TpmError> {
+        let io = |source| TpmError::Log { path: path.to_path_buf(), source };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(io(e)),
+        };
+        let boot = boot_id();
+        let logged: Vec<MeasuredEvent> = text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+        let events: Vec<MeasuredEvent> = logged.iter().filter(|event| event.boot_id == boot).cloned().collect();
+        if events.len() != logged.len() {
+            let mut rewritten = String::new();
+            for event in &events {
+                rewritten.push_str(&serde_json::to_string(event).unwrap_or_default());
+                rewritten.push('\n');
+            }
+            std::fs::write(path, rewritten).map_err(io)?;
+        }
+        Ok(events)
+    }
+
+    pub fn status(&self) -> TpmStatus {
+        TpmStatus { pcr: self.pcr, ak_public: hex(&self.ak_public), events: self.events.lock().unwrap().clone() }
+    }
+
+    // Logged before it is extended: a crash in between leaves an entry
+    // the PCR lacks, which a verifier sees as a mismatch rather than an
+    // extension nobody can explain
+    pub fn extend(&self, kind: &str, detail: &str) -> Result<(), TpmError> {
+        let mut events = self.events.lock().unwrap();
+        let digest = MeasuredEvent::digest_of(kind, detail);
+        let event = MeasuredEvent {
+            boot_id: boot_id(),
+            seq: events.last().map_or(0, |last| last.seq + 1),
+            kind: kind.to_string(),
+            detail: detail.to_string(),
+            timestamp: systemd::now_secs(),
+            digest: hex(&digest),
+        };
+        let io = |source| TpmError::Log { path: self.log_path.clone(), source };
+        let mut log = std::fs::OpenOptions::new().create(true).append(true).open(&self.log_path).map_err(io)?;
+        writeln!(log, "{}", serde_json::to_string(&event).unwrap_or_default()).map_err(io)?;
+        log.sync_data().map_err(io)?;
+        self.extend_pcr(&digest)?;
+        tracing::info!("Measured {} into PCR {}: {}", kind, self.pcr, detail);
+        events.push(event);
+        Ok(())
+    }
+
+    #[cfg(feature = "tpm")]
+    fn extend_pcr(&self, digest: &[u8; 32]) -> Result<(), TpmError> {
+        self.device.lock().unwrap().extend(self.pcr, digest)
+    }
+
+    #[cfg(not(feature = "tpm"))]
+    fn extend_pcr(&self, _digest: &[u8; 32]) -> Result<(), TpmError> {
+        Err(TpmError::Unsupported("tpm"))
+    }
+
+    pub fn quote(&self, nonce: &[u8]) -> Result<Quote, TpmError> {
+        if nonce.len() > MAX_NONCE_BYTES {
+            return Err(TpmError::Malformed(format!("nonce longer than {} bytes", MAX_NONCE_BYTES)));
+        }
+        // Held so no extension lands between the log and the quote
+        let events = self.events.lock().unwrap();
+        let (attest, signature) = self.sign_quote(nonce)?;
+        Ok(Quote {
+            host: crate::syslog_sink::hostname(),
+            pcr: self.pcr,
+            nonce: hex(nonce),
+            attest: hex(&attest),
+            signature: hex(&signature),
+            ak_public: hex(&self.ak_public),
+            events: events.clone(),
+        })
+    }
+
+    // A round trip to the device, for diagnostics
+    #[cfg(feature = "tpm")]
+    pub fn ping(&self) -> Result<(), TpmError> {
+        self.device.lock().unwrap().ping()
+    }
+
+    #[cfg(not(feature = "tpm"))]
+    pub fn ping(&self) -> Result<(), TpmError> {
+        Err(TpmError::Unsupported("tpm"))
+    }
+
+    #[cfg(feature = "tpm")]
+    fn sign_quote(&self, nonce: &[u8]) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+        self.device.lock().unwrap().quote(self.pcr, nonce)
+    }
+
+    #[cfg(not(feature = "tpm"))]
+    fn sign_quote(&self, _nonce: &[u8]) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+        Err(TpmError::Unsupported("tpm"))
+    }
+}
+
+// Extends queued events one at a time, off the async workers
+pub fn start(tpm: std::sync::Arc<Tpm>) -> tokio::task::JoinHandle<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if QUEUE.set(tx).is_err() {
+        tracing::warn!("TPM measurement queue already started");
+    }
+    tokio::task::spawn_blocking(move || {
+        while let Some((kind, detail)) = rx.blocking_recv() {
+            if let Err(e) = tpm.extend(&kind, &detail) {
+                tracing::error!("{} not measured into the TPM: {}", kind, e);
+            }
+        }
+    })
+}
+
+// Attestation keys a controller accepts quotes from
+pub struct QuoteVerifier {
+    trusted: Vec<Vec<u8>>,
+}
+
+impl QuoteVerifier {
+    // Each file holds an AK's public point in hex, as `qksctl tpm status`
+    // prints it
+    pub fn from_key_files(paths: &[PathBuf]) -> Result<Self, TpmError> {
+        let mut trusted = Vec::new();
+        for path in paths {
+            let text = std::fs::read_to_string(path).map_err(|e| TpmError::Key { path: path.clone(), reason: e.to_string() })?;
+            match decode_hex(text.trim()) {
+                Some(key) if key.len() == 65 && key[0] == 0x04 => trusted.push(key),
+                _ => return Err(TpmError::Key { path: path.clone(), reason: "not an uncompressed P-256 point in hex".to_string() }),
+            }
+        }
+        Ok(Self { trusted })
+    }
+
+    pub fn verify(&self, quote: &Quote, nonce: &[u8]) -> Result<VerifiedQuote, TpmError> {
+        let bytes = |field: &str, value: &str| decode_hex(value).ok_or_else(|| TpmError::Malformed(format!("{} is not hex", field)));
+        let ak = bytes("ak_public", &quote.ak_public)?;
+        if !self.trusted.contains(&ak) {
+            return Err(TpmError::UntrustedKey);
+        }
+        let attest = bytes("attest", &quote.attest)?;
+        let signature = bytes("signature", &quote.signature)?;
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ECDSA_P256_SHA256_FIXED, &ak)
+            .verify(&attest, &signature)
+            .map_err(|_| TpmError::BadSignature)?;
+
+        // Only the signed bytes count from here on, not the JSON around them
+        let info = QuoteInfo::parse(&attest)?;
+        if info.extra_data != nonce {
+            return Err(TpmError::NonceMismatch);
+        }
+        if info.selection != [(ALG_SHA256, 1u32.checked_shl(quote.pcr as u32).unwrap_or(0))] {
+            return Err(TpmError::Malformed(format!("quote does not cover exactly SHA-256 PCR {}", quote.pcr)));
+        }
+        for event in &quote.events {
+            if event.digest != hex(&MeasuredEvent::digest_of(&event.kind, &event.detail)) {
+                return Err(TpmError::LogMismatch);
+            }
+        }
+        // The quoted digest is over the selected PCR values
+        let pcr = replay(&quote.events);
+        if info.pcr_digest != digest::digest(&digest::SHA256, &pcr).as_ref() {
+            return Err(TpmError::LogMismatch);
+        }
+        Ok(VerifiedQuote {
+            host: quote.host.clone(),
+            pcr: quote.pcr,
+            ak_public: quote.ak_public.clone(),
+            events: quote.events.len(),
+            clock_ms: info.clock,
+            reset_count: info.reset_count,
+            restart_count: info.restart_count,
+        })
+    }
+}
+
+// The parts of a TPMS_ATTEST quote a verifier checks
+struct QuoteInfo {
+    extra_data: Vec<u8>,
+    clock: u64,
+    reset_count: u32,
+    restart_count: u32,
+    // (hash algorithm, PCR bitmap) per bank
+    selection: Vec<(u16, u32)>,
+    pcr_digest: Vec<u8>,
+}
+
+impl QuoteInfo {
+    fn parse(attest: &[u8]) -> Result<Self, TpmError> {
+        let mut reader = Reader { bytes: attest, at: 0 };
+        if reader.u32()? != TPM_GENERATED {
+            return Err(TpmError::Malformed("not generated by a TPM".to_string()));
+        }
+        if reader.u16()? != ST_ATTEST_QUOTE {
+            return Err(TpmError::Malformed("not a quote".to_string()));
+        }
+        let _qualified_signer = reader.sized()?;
+        let extra_data = reader.sized()?.to_vec();
+        let clock = reader.u64()?;
+        let reset_count = reader.u32()?;
+        let restart_count = reader.u32()?;
+        let _safe = reader.take(1)?;
+        let _firmware_version = reader.u64()?;
+        let mut selection = Vec::new();
+        for _ in 0..reader.u32()? {
+            let hash = reader.u16()?;
+            let size = reader.take(1)?[0] as usize;
+            let bitmap = reader.take(size)?.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (8 * i));
+            selection.push((hash, bitmap));
+        }
+        let pcr_digest = reader.sized()?.to_vec();
+        Ok(Self { extra_data, clock, reset_count, restart_count, selection, pcr_digest })
+    }
+}
+
+// Big-endian, as the TPM marshals
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TpmError> {
+        let end = self.at.checked_add(n).filter(|end| *end <= self.bytes.len()).ok_or_else(|| TpmError::Malformed("truncated attestation".to_string()))?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, TpmError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap_or_default()))
+    }
+
+    fn u32(&mut self) -> Result<u32, TpmError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap_or_default()))
+    }
+
+    fn u64(&mut self) -> Result<u64, TpmError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap_or_default()))
+    }
+
+    // TPM2B: a u16 size, then that many bytes
+    fn sized(&mut self) -> Result<&'a [u8], TpmError> {
+        let size = self.u16()? as usize;
+        self.take(size)
+    }
+}
+
+#[cfg(feature = "tpm")]
+mod device {
+    use super::TpmError;
+    use std::str::FromStr;
+    use tss_esapi::handles::{KeyHandle, PcrHandle};
+    use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+    use tss_esapi::interface_types::ecc::EccCurve;
+    use tss_esapi::interface_types::resource_handles::Hierarchy;
+    use tss_esapi::structures::{Data, Digest, DigestValues, EccScheme, PcrSelectionListBuilder, PcrSlot, Public, Signature, SignatureScheme};
+    use tss_esapi::tcti_ldr::TctiNameConf;
+    use tss_esapi::traits::Marshall;
+    use tss_esapi::Context;
+
+    pub(super) struct Device {
+        context: Context,
+        ak: KeyHandle,
+    }
+
+    fn tss(e: tss_esapi::Error) -> TpmError {
+        TpmError::Device(e.to_string())
+    }
+
+    // P-256 coordinates are 32 bytes; the TPM drops leading zeros
+    fn padded(value: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; 32usize.saturating_sub(value.len())];
+        out.extend_from_slice(value);
+        out
+    }
+
+    impl Device {
+        // Opens the TPM and derives the attestation key; returns its point
+        pub(super) fn open(tcti: &str) -> Result<(Self, Vec<u8>), TpmError> {
+            let tcti = TctiNameConf::from_str(tcti).map_err(tss)?;
+            let mut context = Context::new(tcti).map_err(tss)?;
+            let scheme = EccScheme::create(tss_esapi::interface_types::algorithm::EccSchemeAlgorithm::EcDsa, Some(HashingAlgorithm::Sha256), None).map_err(tss)?;
+            let template = tss_esapi::utils::create_restricted_signing_ecc_public(scheme, EccCurve::NistP256).map_err(tss)?;
+            let primary = context
+                .execute_with_nullauth_session(|ctx| ctx.create_primary(Hierarchy::Owner, template, None, None, None, None))
+                .map_err(tss)?;
+            let Public::Ecc { unique, .. } = &primary.out_public else {
+                return Err(TpmError::Device("attestation key is not an ECC key".to_string()));
+            };
+            let mut point = vec![0x04];
+            point.extend(padded(unique.x().value()));
+            point.extend(padded(unique.y().value()));
+            Ok((Self { context, ak: primary.key_handle }, point))
+        }
+
+        pub(super) fn extend(&mut self, pcr: u8, digest: &[u8; 32]) -> Result<(), TpmError> {
+            let handle = PcrHandle::try_from(pcr as u32).map_err(tss)?;
+            let mut values = DigestValues::new();
+            values.set(HashingAlgorithm::Sha256, Digest::try_from(digest.to_vec()).map_err(tss)?);
+            self.context.execute_with_nullauth_session(|ctx| ctx.pcr_extend(handle, values)).map_err(tss)
+        }
+
+        pub(super) fn ping(&mut self) -> Result<(), TpmError> {
+            self.context.get_random(8).map(|_| ()).map_err(tss)
+        }
+
+        // The marshalled TPMS_ATTEST and the signature as r || s
+        pub(super) fn quote(&mut self, pcr: u8, nonce: &[u8]) -> Result<(Vec<u8>, Vec<u8>), TpmError> {
+            let slot = PcrSlot::try_from(1u32 << pcr).map_err(tss)?;
+            let selection = PcrSelectionListBuilder::new().with_selection(HashingAlgorithm::Sha256, &[slot]).build().map_err(tss)?;
+            let nonce = Data::try_from(nonce.to_vec()).map_err(tss)?;
+            let ak = self.ak;
+            let (attest, signature) = self
+                .context
+                .execute_with_nullauth_session(|ctx| ctx.quote(ak, nonce, SignatureScheme::Null, selection))
+                .map_err(tss)?;
+            let Signature::EcDsa(signature) = signature else {
+                return Err(TpmError::Device("quote signature is not ECDSA".to_string()));
+            };
+            let mut raw = padded(signature.signature_r().value());
+            raw.extend(padded(signature.signature_s().value()));
+            Ok((attest.marshall().map_err(tss)?, raw))
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// src/training_export.rs
+// Scored production rows plus analyst labels, written out for offline
+// retraining. CSV always; Parquet with the `parquet-export` feature. Each
+// file carries the extractor's FeatureSchema version and column names so
+// rows from an older extractor are never mixed into a newer model's
+// training set. Unreviewed rows are
+// exported with an empty label.
+use crate::drift_monitor::ModelDrift;
+use crate::feature_pipeline::ScoredProcess;
+use crate::ml_detector::{FeatureSchema, FeatureVector};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Rows held between exports; the oldest go first past this
+const MAX_PENDING: usize = 200_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("export I/O: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parquet support not compiled in (enable the parquet-export feature)")]
+    ParquetUnavailable,
+    #[error("parquet: {0}")]
+    Parquet(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    Benign,
+    Suspicious,
+    Confirmed,
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Label::Benign => "benign",
+            Label::Suspicious => "suspicious",
+            Label::Confirmed => "confirmed",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug, Clone)]
+pub struct LabeledSample {
+    pub timestamp: u64,
+    pub pid: u32,
+    pub exe: Option<String>,
+    pub model_version: u64,
+    pub score: f32,
+    pub features: FeatureVector,
+    pub label: Option<Label>,
+}
+
+#[derive(Clone)]
+pub struct TrainingExporter {
+    dir: PathBuf,
+    format: ExportFormat,
+    // What the extractor produces now: feature set plus embedding width
+    schema: Arc<Mutex<FeatureSchema>>,
+    pending: Arc<Mutex<VecDeque<LabeledSample>>>,
+    // Labels arrive after the row was scored; keyed by (pid, timestamp)
+    labels: Arc<DashMap<(u32, u64), Label>>,
+    collecting: Arc<AtomicBool>,
+}
+
+impl TrainingExporter {
+    pub fn new(dir: &Path, format: ExportFormat, schema: FeatureSchema) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            format,
+            schema: Arc::new(Mutex::new(schema)),
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            labels: Arc::new(DashMap::new()),
+            collecting: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn set_collecting(&self, collecting: bool) {
+        self.collecting.store(collecting, Ordering::SeqCst);
+    }
+
+    pub fn is_collecting(&self) -> bool {
+        self.collecting.load(Ordering::SeqCst)
+    }
+
+    // Called when the detector's feature set or model embedding changes;
+    // rows extracted under the old schema are written out first
+    pub fn set_schema(&self, schema: FeatureSchema) -> Result<Option<PathBuf>, ExportError> {
+        if *self.schema.lock().unwrap() == schema {
+            return Ok(None);
+        }
+        let written = self.export()?;
+        *self.schema.lock().unwrap() = schema;
+        Ok(written)
+    }
+
+    pub fn record(&self, scored: &ScoredProcess) {
+        if !self.is_collecting() || scored.features.len() != self.schema.lock().unwrap().features.len() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.pop_front();
+        }
+        pending.push_back(LabeledSample {
+            timestamp: scored.timestamp,
+            pid: scored.pid,
+            exe: scored.exe.clone(),
+            model_version: scored.model_version,
+            score: scored.score,
+            features: scored.features.clone(),
+            label: None,
+        });
+    }
+
+    pub fn label(&self, pid: u32, timestamp: u64, label: Label) {
+        self.labels.insert((pid, timestamp), label);
+    }
+
+    // A drift event asking for retraining turns collection back on
+    pub fn on_drift(&self, drift: &ModelDrift) {
+        if drift.retrain_requested && !self.is_collecting() {
+            tracing::info!("Model {} drifted; collecting training data", drift.model_version);
+            self.set_collecting(true);
+        }
+    }
+
+    // Write everything pending to a new file and clear it; None when there
+    // was nothing to write
+    pub fn export(&self) -> Result<Option<PathBuf>, ExportError> {
+        if self.format == ExportFormat::Parquet && cfg!(not(feature = "parquet-export")) {
+            return Err(ExportError::ParquetUnavailable);
+        }
+        let mut samples: Vec<LabeledSample> = std::mem::take(&mut *self.pending.lock().unwrap()).into();
+        if samples.is_empty() {
+            return Ok(None);
+        }
+        for sample in &mut samples {
+            sample.label = self.labels.remove(&(sample.pid, sample.timestamp)).map(|(_, label)| label);
+        }
+
+        let schema = self.schema.lock().unwrap().clone();
+        std::fs::create_dir_all(&self.dir)?;
+        let first = samples.first().map_or(0, |s| s.timestamp);
+        let extension = match self.format {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        };
+        let path = self.dir.join(format!("training-v{}-{}.{}", schema.version, first, extension));
+
+        match self.format {
+            ExportFormat::Csv => write_csv(&path, &schema, &samples)?,
+            ExportFormat::Parquet => write_parquet(&path, &schema, &samples)?,
+        }
+        tracing::info!("Exported {} training rows to {}", samples.len(), path.display());
+        Ok(Some(path))
+    }
+
+    pub fn start(
+        &self,
+        mut scored: mpsc::UnboundedReceiver<ScoredProcess>,
+        mut drift: mpsc::UnboundedReceiver<ModelDrift>,
+        interval_secs: u64,
+    ) -> tokio::task::JoinHandle<()> {
+        let exporter = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval_secs));
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    result = scored.recv() => match result {
+                        Some(result) => exporter.record(&result),
+                        None => break,
+                    },
+                    Some(event) = drift.recv() => exporter.on_drift(&event),
+                    _ = interval.tick() => {
+                        let exporter = exporter.clone();
+                        match tokio::task::spawn_blocking(move || exporter.export()).await {
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => tracing::warn!("Training export failed: {}", e),
+                            Err(e) => tracing::warn!("Training export task failed: {}", e),
+                        }
+                    }
+                }
+            }
+
+            // Stream closed: write out what is left
+            if let Err(e) = exporter.export() {
+                tracing::warn!("Final training export failed: {}", e);
+            }
+        })
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(path: &Path, schema: &FeatureSchema, samples: &[LabeledSample]) -> Result<(), ExportError> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+    let mut header = vec!["schema_version", "timestamp", "pid", "exe", "model_version", "score", "label"];
+    header.extend(schema.features.iter().map(String::as_str));
+    writeln!(out, "{}", header.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","))?;
+
+    for sample in samples {
+        let mut row = vec![
+            schema.version.to_string(),
+            sample.timestamp.to_string(),
+            sample.pid.to_string(),
+            csv_field(sample.exe.as_deref().unwrap_or("")),
+            sample.model_version.to_string(),
+            sample.score.to_string(),
+            sample.label.map(|l| l.to_string()).unwrap_or_default(),
+        ];
+        row.extend(sample.features.iter().map(|f| f.to_string()));
+        writeln!(out, "{}", row.join(","))?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "parquet-export")]
+fn write_parquet(path: &Path, schema: &FeatureSchema, samples: &[LabeledSample]) -> Result<(), ExportError> {
+    use arrow::array::{ArrayRef, Float32Array, StringArray, UInt32Array, UInt64Array};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::ArrowWriter;
+
+    let mut fields = vec![
+        Field::new("schema_version", DataType::UInt32, false),
+        Field::new("timestamp", DataType::UInt64, false),
+        Field::new("pid", DataType::UInt32, false),
+        Field::new("exe", DataType::Utf8, true),
+        Field::new("model_version", DataType::UInt64, false),
+        Field::new("score", DataType::Float32, false),
+        Field::new("label", DataType::Utf8, true),
+    ];
+    fields.extend(schema.features.iter().map(|name| Field::new(name.as_str(), DataType::Float32, false)));
+
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from(vec![schema.version; samples.len()])),
+        Arc::new(UInt64Array::from_iter_values(samples.iter().map(|s| s.timestamp))),
+        Arc::new(UInt32Array::from_iter_values(samples.iter().map(|s| s.pid))),
+        Arc::new(StringArray::from_iter(samples.iter().map(|s| s.exe.clone()))),
+        Arc::new(UInt64Array::from_iter_values(samples.iter().map(|s| s.model_version))),
+        Arc::new(Float32Array::from_iter_values(samples.iter().map(|s| s.score))),
+        Arc::new(StringArray::from_iter(samples.iter().map(|s| s.label.map(|l| l.to_string())))),
+    ];
+    for i in 0..schema.features.len() {
+        columns.push(Arc::new(Float32Array::from_iter_values(samples.iter().map(|s| s.features[i]))));
+    }
+
+    let parquet_error = |e: &dyn std::fmt::Display| ExportError::Parquet(e.to_string());
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).map_err(|e| parquet_error(&e))?;
+    let mut writer = ArrowWriter::try_new(std::fs::File::create(path)?, batch.schema(), None)
+        .map_err(|e| parquet_error(&e))?;
+    writer.write(&batch).map_err(|e| parquet_error(&e))?;
+    writer.close().map_err(|e| parquet_error(&e))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet-export"))]
+fn write_parquet(_path: &Path, _schema: &FeatureSchema, _samples: &[LabeledSample]) -> Result<(), ExportError> {
+    Err(ExportError::ParquetUnavailable)
+}
@@ -0,0 +1,182 @@
+// src/vdso_relocation.rs
+use crate::arch_profile::ArchProfile;
+use crate::privsep::{self, ProcFile};
+use crate::process_maps::{self, MapEntry, ProcessMaps};
+use crate::remap_engine::{PointerPatch, RegionKind, RegionMove, RemapEngine, RemapError};
+use goblin::elf::{program_header, Elf};
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+
+// Special mappings that must keep their relative placement: the vDSO code
+// addresses its data pages at a fixed offset
+const VDSO_MAPPINGS: [&str; 4] = ["[vvar]", "[vvar_vclock]", "[vdso]", "[vdso_data]"];
+
+const AT_NULL: u64 = 0;
+const AT_SYSINFO_EHDR: u64 = 33;
+const MAX_AUXV_ENTRIES: usize = 64;
+// Bound on argc and envp entries when walking the initial stack
+const MAX_STACK_WORDS: u64 = 1 << 20;
+const RTLD_GLOBAL_RO: &str = "_rtld_global_ro";
+
+// Whether the vDSO of a process could actually be moved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VdsoSupport {
+    Relocated { base: u64 },
+    Unsupported { reason: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VdsoError {
+    #[error("vDSO relocation unsupported: {0}")]
+    Unsupported(String),
+    #[error("vDSO ended up at {actual:#x}, expected {expected:#x}")]
+    Verification { expected: u64, actual: u64 },
+    #[error(transparent)]
+    Remap(#[from] RemapError),
+}
+
+pub fn relocate_vdso(pid: u32, offset: u64) -> Result<u64, VdsoError> {
+    let maps = ProcessMaps::read(pid).map_err(|e| VdsoError::Unsupported(e.to_string()))?;
+    let arch = ArchProfile::for_pid(pid);
+
+    let special: Vec<&MapEntry> = maps.entries.iter()
+        .filter(|e| e.path.as_deref().map_or(false, |p| VDSO_MAPPINGS.contains(&p)))
+        .collect();
+    let vdso = maps.named("[vdso]")
+        .ok_or_else(|| VdsoError::Unsupported("process has no [vdso] mapping".into()))?;
+
+    let group_start = special.iter().map(|e| e.start).min().unwrap_or(vdso.start);
+    let group_end = special.iter().map(|e| e.end).max().unwrap_or(vdso.end);
+
+    // Move the whole group down by `offset` pages, staying page aligned
+    let new_group_start = arch.align(group_start.saturating_sub(offset));
+    let delta = new_group_start.wrapping_sub(group_start);
+    let new_group_end = group_end.wrapping_add(delta);
+
+    if new_group_start < process_maps::mmap_min_addr() {
+        return Err(VdsoError::Unsupported("target below mmap_min_addr".into()));
+    }
+    let clashes = maps.overlapping(new_group_start, new_group_end).into_iter()
+        .any(|e| e.start < group_start || e.end > group_end);
+    if clashes {
+        return Err(VdsoError::Unsupported(format!("{:#x} is already mapped", new_group_start)));
+    }
+
+    let moves: Vec<RegionMove> = special.iter()
+        .map(|e| RegionMove {
+            kind: RegionKind::Library,
+            old_start: e.start,
+            len: e.len(),
+            new_start: e.start.wrapping_add(delta),
+        })
+        .collect();
+
+    // getauxval(AT_SYSINFO_EHDR) and ld.so's cached vDSO pointers; without
+    // them the next clock_gettime() jumps into unmapped memory
+    let mem = privsep::open_proc(pid, ProcFile::Mem).map_err(|e| VdsoError::Unsupported(e.to_string()))?;
+    let mut patches = vec![auxv_patch(&mem, pid, vdso.start, delta)?];
+    patches.extend(rtld_global_ro_patches(&mem, &maps, group_start, group_end, delta)?);
+
+    RemapEngine::relocate(pid, &moves, &patches)?;
+    verify(pid, &mem, &special, delta, &patches)?;
+
+    let expected = vdso.start.wrapping_add(delta);
+    tracing::info!(
+        pid,
+        "PID {}: vDSO moved {:#x} -> {:#x} ({} cached pointers patched)",
+        pid, vdso.start, expected, patches.len()
+    );
+    Ok(expected)
+}
+
+fn read_word(mem: &File, addr: u64) -> Result<u64, VdsoError> {
+    let mut word = [0u8; 8];
+    mem.read_exact_at(&mut word, addr).map_err(|e| VdsoError::Unsupported(format!("reading {:#x}: {}", addr, e)))?;
+    Ok(u64::from_ne_bytes(word))
+}
+
+// The AT_SYSINFO_EHDR entry of the auxiliary vector on the initial stack,
+// which getauxval() reads: past argc, argv[] and envp[] with their NULLs
+fn auxv_patch(mem: &File, pid: u32, vdso_start: u64, delta: u64) -> Result<PointerPatch, VdsoError> {
+    let stat = process_maps::mm_stat(pid).map_err(|e| VdsoError::Unsupported(e.to_string()))?;
+    let argc = read_word(mem, stat.start_stack)?;
+    if argc > MAX_STACK_WORDS {
+        return Err(VdsoError::Unsupported(format!("implausible argc {} on the stack", argc)));
+    }
+    let mut addr = stat.start_stack + 8 * (argc + 2);
+    let mut envp = 0;
+    while read_word(mem, addr)? != 0 {
+        addr += 8;
+        envp += 1;
+        if envp > MAX_STACK_WORDS {
+            return Err(VdsoError::Unsupported("no end to envp on the stack".into()));
+        }
+    }
+    addr += 8;
+
+    for _ in 0..MAX_AUXV_ENTRIES {
+        match read_word(mem, addr)? {
+            AT_NULL => break,
+            AT_SYSINFO_EHDR => {
+                let value = read_word(mem, addr + 8)?;
+                if value != vdso_start {
+                    return Err(VdsoError::Unsupported(format!("AT_SYSINFO_EHDR is {:#x}, not the vDSO", value)));
+                }
+                return Ok(PointerPatch { addr: addr + 8, old_value: value, new_value: value.wrapping_add(delta) });
+            }
+            _ => addr += 16,
+        }
+    }
+    Err(VdsoError::Unsupported("no AT_SYSINFO_EHDR in the auxiliary vector".into()))
+}
+
+// ld.so's _rtld_global_ro (in RELRO) holds the vDSO's ELF header address
+// and the vDSO functions it resolved at startup. Only that struct is
+// searched; a static binary keeps them somewhere unnamed and is refused.
+fn rtld_global_ro_patches(mem: &File, maps: &ProcessMaps, start: u64, end: u64, delta: u64) -> Result<Vec<PointerPatch>, VdsoError> {
+    let ld = maps.entries.iter()
+        .find(|e| e.offset == 0 && e.path.as_deref().map_or(false, |p| p.rsplit('/').next().unwrap_or(p).starts_with("ld-linux")))
+        .ok_or_else(|| VdsoError::Unsupported("no dynamic linker mapped".into()))?;
+    let path = ld.path.as_deref().unwrap_or_default();
+    let bytes = fs::read(path).map_err(|e| VdsoError::Unsupported(format!("{}: {}", path, e)))?;
+    let elf = Elf::parse(&bytes).map_err(|e| VdsoError::Unsupported(format!("{}: {}", path, e)))?;
+
+    let first_vaddr = elf.program_headers.iter()
+        .filter(|ph| ph.p_type == program_header::PT_LOAD)
+        .map(|ph| ph.p_vaddr & !0xFFF)
+        .min()
+        .unwrap_or(0);
+    let symbol = elf.dynsyms.iter()
+        .find(|sym| elf.dynstrtab.get_at(sym.st_name) == Some(RTLD_GLOBAL_RO))
+        .ok_or_else(|| VdsoError::Unsupported(format!("{} does not export {}", path, RTLD_GLOBAL_RO)))?;
+    let base = ld.start.wrapping_sub(first_vaddr).wrapping_add(symbol.st_value);
+
+    let mut buffer = vec![0u8; symbol.st_size as usize];
+    mem.read_exact_at(&mut buffer, base).map_err(|e| VdsoError::Unsupported(format!("reading {}: {}", RTLD_GLOBAL_RO, e)))?;
+    Ok(buffer.chunks_exact(8)
+        .enumerate()
+        .map(|(i, word)| (base + i as u64 * 8, u64::from_ne_bytes(word.try_into().unwrap())))
+        .filter(|(_, value)| *value >= start && *value < end)
+        .map(|(addr, value)| PointerPatch { addr, old_value: value, new_value: value.wrapping_add(delta) })
+        .collect())
+}
+
+// The kernel may silently refuse to track a moved vvar/vdso pair, so every
+// mapping of the group and every patched word is read back
+fn verify(pid: u32, mem: &File, special: &[&MapEntry], delta: u64, patches: &[PointerPatch]) -> Result<(), VdsoError> {
+    let maps = ProcessMaps::read(pid).map_err(|e| VdsoError::Unsupported(e.to_string()))?;
+    for entry in special {
+        let expected = entry.start.wrapping_add(delta);
+        let actual = entry.path.as_deref().and_then(|name| maps.named(name)).map(|e| e.start).unwrap_or(0);
+        if actual != expected {
+            return Err(VdsoError::Verification { expected, actual });
+        }
+    }
+    for patch in patches {
+        let actual = read_word(mem, patch.addr)?;
+        if actual != patch.new_value {
+            return Err(VdsoError::Verification { expected: patch.new_value, actual });
+        }
+    }
+    Ok(())
+}